@@ -43,7 +43,7 @@ cargo build -q --release --all-targets
 OBNAM_SERVER_LOG=error cargo run -q --release --bin obnam-server -- "$TMP/server.yaml" >/dev/null &
 pid="$!"
 
-cargo run -q --release --bin obnam -- --config "$TMP/client.yaml" init --insecure-passphrase=hunter2
+cargo run -q --release --bin obnam -- --config "$TMP/client.yaml" init
 if true; then
 	/usr/bin/time cargo run -q --release --bin obnam -- --config "$TMP/client.yaml" backup >/dev/null
 else
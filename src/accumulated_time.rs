@@ -11,6 +11,11 @@ use std::time::Instant;
 /// `AccumulatedTime` accumulates time for each possible clock.
 /// Conceptually, every type of clock exists. If a type of clock
 /// doesn't ever get created, it measures at 0 accumulated time.
+///
+/// A clock may be started again while it's already running, on the
+/// same thread or another one, for example by concurrent tasks doing
+/// the same kind of work. Time is then counted once, for as long as
+/// at least one start hasn't been matched by a stop.
 #[derive(Debug)]
 pub struct AccumulatedTime<T> {
     accumulated: Mutex<HashMap<T, ClockTime>>,
@@ -20,6 +25,7 @@ pub struct AccumulatedTime<T> {
 struct ClockTime {
     nanos: u128,
     started: Option<Instant>,
+    running: usize,
 }
 
 impl<T: Eq + PartialEq + Hash + Copy> AccumulatedTime<T> {
@@ -35,27 +41,44 @@ impl<T: Eq + PartialEq + Hash + Copy> AccumulatedTime<T> {
     ///
     /// The clock's measured time is added to the accumulator when the
     /// clock is stopped.
-    pub fn start(&mut self, clock: T) {
+    pub fn start(&self, clock: T) {
         let mut map = self.accumulated.lock().unwrap();
         let ct = map.entry(clock).or_insert_with(ClockTime::default);
-        assert!(ct.started.is_none());
-        ct.started = Some(Instant::now());
+        if ct.running == 0 {
+            ct.started = Some(Instant::now());
+        }
+        ct.running += 1;
     }
 
     /// Stop a running clock.
     ///
-    /// Its run time is added to the accumulated time for that kind of clock.
-    pub fn stop(&mut self, clock: T) {
+    /// Once every start of the clock has been stopped, its run time
+    /// is added to the accumulated time for that kind of clock.
+    /// Stopping a clock that isn't running does nothing.
+    pub fn stop(&self, clock: T) {
         let mut map = self.accumulated.lock().unwrap();
-        if let Some(mut ct) = map.get_mut(&clock) {
-            assert!(ct.started.is_some());
-            if let Some(started) = ct.started.take() {
-                ct.nanos += started.elapsed().as_nanos();
-                ct.started = None;
+        if let Some(ct) = map.get_mut(&clock) {
+            if ct.running == 0 {
+                return;
+            }
+            ct.running -= 1;
+            if ct.running == 0 {
+                if let Some(started) = ct.started.take() {
+                    ct.nanos += started.elapsed().as_nanos();
+                }
             }
         }
     }
 
+    /// Start a clock, and stop it when the returned guard is dropped.
+    ///
+    /// This keeps the clock from being left running if the code it
+    /// measures returns early, for example with an error.
+    pub fn guard(&self, clock: T) -> ClockGuard<'_, T> {
+        self.start(clock);
+        ClockGuard { time: self, clock }
+    }
+
     /// Return the accumulated time for a type of clock, as whole seconds.
     pub fn secs(&self, clock: T) -> u128 {
         self.nanos(clock) / 1_000_000_000u128
@@ -77,3 +100,81 @@ impl<T: Eq + PartialEq + Hash + Copy> AccumulatedTime<T> {
         }
     }
 }
+
+/// A running clock, which is stopped when this is dropped.
+///
+/// Created with [`AccumulatedTime::guard`].
+#[derive(Debug)]
+pub struct ClockGuard<'a, T: Eq + PartialEq + Hash + Copy> {
+    time: &'a AccumulatedTime<T>,
+    clock: T,
+}
+
+impl<'a, T: Eq + PartialEq + Hash + Copy> Drop for ClockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.time.stop(self.clock);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AccumulatedTime;
+    use std::sync::{Arc, Barrier};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    const MS: u128 = 1_000_000;
+
+    #[test]
+    fn guard_stops_clock_when_dropped() {
+        let time = AccumulatedTime::new();
+        {
+            let _guard = time.guard("work");
+            sleep(Duration::from_millis(10));
+        }
+        let nanos = time.nanos("work");
+        sleep(Duration::from_millis(10));
+        assert!(nanos >= 10 * MS);
+        assert_eq!(time.nanos("work"), nanos);
+    }
+
+    #[test]
+    fn nested_starts_run_until_all_are_stopped() {
+        let time = AccumulatedTime::new();
+        time.start("work");
+        time.start("work");
+        time.stop("work");
+        let nanos = time.nanos("work");
+        sleep(Duration::from_millis(10));
+        assert!(time.nanos("work") >= nanos + 10 * MS);
+        time.stop("work");
+        time.stop("work");
+        let total = time.nanos("work");
+        sleep(Duration::from_millis(10));
+        assert_eq!(time.nanos("work"), total);
+    }
+
+    #[test]
+    fn clocks_overlap_across_threads() {
+        let time = Arc::new(AccumulatedTime::new());
+        let barrier = Arc::new(Barrier::new(4));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let time = time.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let _guard = time.guard("work");
+                    barrier.wait();
+                    sleep(Duration::from_millis(10));
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let total = time.nanos("work");
+        assert!(total >= 10 * MS);
+        sleep(Duration::from_millis(10));
+        assert_eq!(time.nanos("work"), total);
+    }
+}
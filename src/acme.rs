@@ -0,0 +1,772 @@
+//! Obtain TLS certificates automatically with ACME.
+//!
+//! [ACME][] is the protocol Let's Encrypt, and other certificate
+//! authorities, use to issue certificates without manual steps. The
+//! server proves it controls the domain names of the certificate by
+//! answering plain HTTP requests for challenge tokens, which the
+//! certificate authority makes to port 80 of each domain ("http-01"
+//! challenges).
+//!
+//! The account key, the certificate, and the certificate's key are
+//! kept in a state directory, so that the certificate only needs to be
+//! obtained again when it's about to expire.
+//!
+//! [ACME]: https://www.rfc-editor.org/rfc/rfc8555
+
+use log::{debug, info};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNumContext;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509Req, X509};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use warp::Filter;
+
+/// URL of the ACME directory of Let's Encrypt.
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// A certificate is obtained again when it expires in fewer days than
+/// this.
+pub const RENEW_DAYS: u32 = 30;
+
+const ACCOUNT_KEY_FILE: &str = "account.key";
+const KEY_FILE: &str = "tls.key";
+const CERT_FILE: &str = "tls.pem";
+
+// How often, and how many times, to ask the certificate authority if
+// it's done validating or issuing.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: usize = 60;
+
+/// Configuration for obtaining TLS certificates with ACME.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    /// Domain names the certificate is for. The first one is the
+    /// subject of the certificate.
+    pub domains: Vec<String>,
+    /// Email address the certificate authority can use to contact
+    /// the server operator.
+    pub contact: Option<String>,
+    /// URL of the ACME directory of the certificate authority.
+    #[serde(default = "default_directory")]
+    pub directory: String,
+    /// Directory where the account key, the certificate, and its key
+    /// are kept.
+    pub state: PathBuf,
+    /// Address where challenges are answered over plain HTTP. The
+    /// certificate authority connects to port 80 of each domain.
+    #[serde(default = "default_challenge_address")]
+    pub challenge_address: String,
+    /// Agree to the terms of service of the certificate authority.
+    #[serde(default)]
+    pub accept_terms: bool,
+}
+
+fn default_directory() -> String {
+    LETS_ENCRYPT_DIRECTORY.to_string()
+}
+
+fn default_challenge_address() -> String {
+    "0.0.0.0:80".to_string()
+}
+
+/// Possible errors from obtaining a certificate with ACME.
+#[derive(Debug, thiserror::Error)]
+pub enum AcmeError {
+    /// No domain names are configured.
+    #[error("ACME needs at least one domain name")]
+    NoDomains,
+
+    /// The terms of service haven't been agreed to.
+    #[error("the certificate authority's terms of service must be agreed to with accept_terms")]
+    TermsNotAccepted,
+
+    /// The state directory doesn't exist.
+    #[error("ACME state directory {0} does not exist")]
+    StateDirNotFound(PathBuf),
+
+    /// The address for answering challenges is wrong.
+    #[error("ACME challenge address {0} can't be resolved")]
+    BadChallengeAddress(String),
+
+    /// Couldn't listen for challenge requests.
+    #[error("can't answer ACME challenges on {0}: {1}")]
+    ChallengeListen(SocketAddr, warp::Error),
+
+    /// An HTTP request to the certificate authority failed.
+    #[error("ACME request to {0} failed: {1}")]
+    Request(String, reqwest::Error),
+
+    /// The certificate authority responded with an error.
+    #[error("ACME server responded to {0} with status {1}: {2}")]
+    Status(String, reqwest::StatusCode, String),
+
+    /// A response from the certificate authority lacks something it
+    /// must have.
+    #[error("ACME response from {0} lacks {1}")]
+    Missing(String, &'static str),
+
+    /// The certificate authority doesn't offer an http-01 challenge.
+    #[error("ACME server offers no http-01 challenge for {0}")]
+    NoHttpChallenge(String),
+
+    /// The certificate authority didn't accept that the server
+    /// controls a domain.
+    #[error("ACME authorization for {0} failed: {1}")]
+    AuthorizationFailed(String, String),
+
+    /// The certificate authority didn't issue the certificate.
+    #[error("ACME order failed: {0}")]
+    OrderFailed(String),
+
+    /// The certificate authority took too long.
+    #[error("ACME server didn't finish with {0} in time")]
+    Timeout(String),
+
+    /// Error from OpenSSL.
+    #[error(transparent)]
+    OpenSsl(#[from] openssl::error::ErrorStack),
+
+    /// Error reading a file.
+    #[error("failed to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+
+    /// Error writing a file.
+    #[error("failed to write {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+}
+
+impl AcmeConfig {
+    /// Check the configuration.
+    pub fn check(&self) -> Result<(), AcmeError> {
+        if self.domains.is_empty() {
+            return Err(AcmeError::NoDomains);
+        }
+        if !self.accept_terms {
+            return Err(AcmeError::TermsNotAccepted);
+        }
+        if !self.state.is_dir() {
+            return Err(AcmeError::StateDirNotFound(self.state.clone()));
+        }
+        Ok(())
+    }
+
+    /// Path to the key of the obtained certificate.
+    pub fn key_path(&self) -> PathBuf {
+        self.state.join(KEY_FILE)
+    }
+
+    /// Path to the obtained certificate.
+    pub fn cert_path(&self) -> PathBuf {
+        self.state.join(CERT_FILE)
+    }
+
+    fn account_key_path(&self) -> PathBuf {
+        self.state.join(ACCOUNT_KEY_FILE)
+    }
+
+    /// Does a certificate need to be obtained? It does if there isn't
+    /// one yet, if its key is missing or isn't the one it was issued
+    /// for, if it doesn't cover all the domain names, or if it expires
+    /// within [`RENEW_DAYS`] days.
+    pub fn needs_certificate(&self) -> Result<bool, AcmeError> {
+        let filename = self.cert_path();
+        let key_filename = self.key_path();
+        if !filename.exists() || !key_filename.exists() {
+            return Ok(true);
+        }
+        let pem = std::fs::read(&filename).map_err(|err| AcmeError::Read(filename, err))?;
+        let cert = X509::from_pem(&pem)?;
+
+        // The key is written before the certificate, so if writing
+        // the certificate failed, the key is for a certificate that
+        // was never stored.
+        let pem = std::fs::read(&key_filename).map_err(|err| AcmeError::Read(key_filename, err))?;
+        let key = PKey::private_key_from_pem(&pem)?;
+        if !cert.public_key()?.public_eq(&key) {
+            return Ok(true);
+        }
+
+        let names: Vec<String> = cert
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| name.dnsname().map(|s| s.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if !self.domains.iter().all(|domain| names.contains(domain)) {
+            return Ok(true);
+        }
+        let renew = Asn1Time::days_from_now(RENEW_DAYS)?;
+        Ok(cert.not_after() < renew)
+    }
+}
+
+/// Obtain a new certificate for the configured domain names, and
+/// store it and its key in the state directory.
+pub async fn obtain_certificate(config: &AcmeConfig) -> Result<(), AcmeError> {
+    info!("obtaining TLS certificate for {:?}", config.domains);
+    let account_key = load_or_create_key(&config.account_key_path())?;
+    let mut session = Session::new(&config.directory, account_key).await?;
+    session.register(config.contact.as_deref()).await?;
+    let (order_url, order) = session.new_order(&config.domains).await?;
+
+    let responder = ChallengeResponder::start(&config.challenge_address)?;
+    for url in order.authorizations.iter() {
+        session.authorize(url, &responder.tokens).await?;
+    }
+    responder.stop();
+
+    let key = new_key()?;
+    let csr = certificate_request(&key, &config.domains)?;
+    let cert_url = session.finalize(&order_url, &order, &csr).await?;
+    let cert = session.post(&cert_url, None).await?;
+    let cert = cert
+        .bytes()
+        .await
+        .map_err(|err| AcmeError::Request(cert_url.clone(), err))?;
+
+    // Write the key first. If the certificate isn't written after
+    // it, the old certificate doesn't match the key, and a new one is
+    // obtained when the server next starts or checks for renewal.
+    write_private(&config.key_path(), &key.private_key_to_pem_pkcs8()?)?;
+    write_private(&config.cert_path(), &cert)?;
+    info!("obtained TLS certificate for {:?}", config.domains);
+    Ok(())
+}
+
+fn new_key() -> Result<PKey<Private>, AcmeError> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    Ok(PKey::from_ec_key(EcKey::generate(&group)?)?)
+}
+
+fn load_or_create_key(filename: &Path) -> Result<PKey<Private>, AcmeError> {
+    if filename.exists() {
+        let pem = std::fs::read(filename).map_err(|err| AcmeError::Read(filename.into(), err))?;
+        Ok(PKey::private_key_from_pem(&pem)?)
+    } else {
+        debug!("creating ACME account key {}", filename.display());
+        let key = new_key()?;
+        write_private(filename, &key.private_key_to_pem_pkcs8()?)?;
+        Ok(key)
+    }
+}
+
+// Replace a file atomically with one only its owner can read.
+fn write_private(filename: &Path, data: &[u8]) -> Result<(), AcmeError> {
+    let err = |err| AcmeError::Write(filename.to_path_buf(), err);
+    let dir = filename.parent().unwrap_or_else(|| Path::new("."));
+    let mut temp = tempfile::NamedTempFile::new_in(dir).map_err(err)?;
+    temp.as_file()
+        .set_permissions(std::fs::Permissions::from_mode(0o600))
+        .map_err(err)?;
+    temp.write_all(data).map_err(err)?;
+    temp.as_file().sync_all().map_err(err)?;
+    temp.persist(filename).map_err(|e| err(e.error))?;
+    Ok(())
+}
+
+// A certificate signing request for the domain names, in DER form.
+fn certificate_request(key: &PKey<Private>, domains: &[String]) -> Result<Vec<u8>, AcmeError> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, &domains[0])?;
+    let name = name.build();
+
+    let mut req = X509Req::builder()?;
+    req.set_subject_name(&name)?;
+    req.set_pubkey(key)?;
+    let mut san = SubjectAlternativeName::new();
+    for domain in domains.iter() {
+        san.dns(domain);
+    }
+    let mut extensions = Stack::new()?;
+    extensions.push(san.build(&req.x509v3_context(None))?)?;
+    req.add_extensions(&extensions)?;
+    req.sign(key, MessageDigest::sha256())?;
+    Ok(req.build().to_der()?)
+}
+
+// Base64 encoding as JWS uses it.
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+// The public part of an account key as a JSON Web Key, with members
+// in the order and form its RFC 7638 thumbprint needs.
+fn jwk(key: &PKey<Private>) -> Result<String, AcmeError> {
+    let key = key.ec_key()?;
+    let mut ctx = BigNumContext::new()?;
+    let mut x = openssl::bn::BigNum::new()?;
+    let mut y = openssl::bn::BigNum::new()?;
+    key.public_key()
+        .affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut ctx)?;
+    Ok(format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        b64(&x.to_vec_padded(32)?),
+        b64(&y.to_vec_padded(32)?)
+    ))
+}
+
+// The key authorization for a challenge token.
+fn key_authorization(token: &str, jwk: &str) -> String {
+    format!("{}.{}", token, b64(&Sha256::digest(jwk.as_bytes())))
+}
+
+// An ES256 signature, as the fixed size concatenation of r and s that
+// JWS uses.
+fn sign(key: &PKey<Private>, data: &[u8]) -> Result<Vec<u8>, AcmeError> {
+    let sig = EcdsaSig::sign(&Sha256::digest(data), &*key.ec_key()?)?;
+    let mut bytes = sig.r().to_vec_padded(32)?;
+    bytes.extend(sig.s().to_vec_padded(32)?);
+    Ok(bytes)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Identifier {
+    #[serde(rename = "type")]
+    kind: String,
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: Option<String>,
+    error: Option<serde_json::Value>,
+}
+
+// Conversation with the certificate authority, with requests signed
+// by the account key.
+struct Session {
+    client: reqwest::Client,
+    key: PKey<Private>,
+    jwk: String,
+    directory: Directory,
+    nonce: Option<String>,
+    kid: Option<String>,
+}
+
+impl Session {
+    async fn new(url: &str, key: PKey<Private>) -> Result<Self, AcmeError> {
+        let client = reqwest::Client::new();
+        let err = |err| AcmeError::Request(url.to_string(), err);
+        let directory = client
+            .get(url)
+            .send()
+            .await
+            .map_err(err)?
+            .json()
+            .await
+            .map_err(err)?;
+        Ok(Self {
+            client,
+            jwk: jwk(&key)?,
+            key,
+            directory,
+            nonce: None,
+            kid: None,
+        })
+    }
+
+    async fn nonce(&mut self) -> Result<String, AcmeError> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let url = &self.directory.new_nonce;
+        let res = self
+            .client
+            .head(url)
+            .send()
+            .await
+            .map_err(|err| AcmeError::Request(url.clone(), err))?;
+        replay_nonce(&res).ok_or_else(|| AcmeError::Missing(url.clone(), "Replay-Nonce"))
+    }
+
+    // Make a signed request. Without a payload, this is a
+    // "POST-as-GET" request to fetch a resource.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&serde_json::Value>,
+    ) -> Result<reqwest::Response, AcmeError> {
+        let payload = match payload {
+            None => String::new(),
+            Some(payload) => b64(payload.to_string().as_bytes()),
+        };
+
+        // The certificate authority may reject a nonce at any time,
+        // and then the request should be made again with a new one.
+        let mut retried = false;
+        loop {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.nonce().await?,
+                "url": url,
+            });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = serde_json::from_str(&self.jwk).unwrap(),
+            }
+            let protected = b64(protected.to_string().as_bytes());
+            let signature = sign(&self.key, format!("{}.{}", protected, payload).as_bytes())?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": b64(&signature),
+            });
+
+            let res = self
+                .client
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await
+                .map_err(|err| AcmeError::Request(url.to_string(), err))?;
+            self.nonce = replay_nonce(&res);
+            if res.status().is_success() {
+                return Ok(res);
+            }
+
+            let status = res.status();
+            let problem = res.text().await.unwrap_or_default();
+            if !retried && problem.contains("urn:ietf:params:acme:error:badNonce") {
+                debug!("ACME server rejected nonce, retrying");
+                retried = true;
+                continue;
+            }
+            return Err(AcmeError::Status(url.to_string(), status, problem));
+        }
+    }
+
+    // Make a signed request and parse the JSON response. Also return
+    // the Location header, if any, as that's where a newly created
+    // resource is.
+    async fn post_json<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        payload: Option<&serde_json::Value>,
+    ) -> Result<(Option<String>, T), AcmeError> {
+        let res = self.post(url, payload).await?;
+        let location = location(&res);
+        let value = res
+            .json()
+            .await
+            .map_err(|err| AcmeError::Request(url.to_string(), err))?;
+        Ok((location, value))
+    }
+
+    async fn register(&mut self, contact: Option<&str>) -> Result<(), AcmeError> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(contact) = contact {
+            payload["contact"] = json!([format!("mailto:{}", contact)]);
+        }
+        let url = self.directory.new_account.clone();
+        let res = self.post(&url, Some(&payload)).await?;
+        let kid = location(&res).ok_or(AcmeError::Missing(url, "Location"))?;
+        debug!("ACME account {}", kid);
+        self.kid = Some(kid);
+        Ok(())
+    }
+
+    async fn new_order(&mut self, domains: &[String]) -> Result<(String, Order), AcmeError> {
+        let identifiers: Vec<Identifier> = domains
+            .iter()
+            .map(|domain| Identifier {
+                kind: "dns".to_string(),
+                value: domain.clone(),
+            })
+            .collect();
+        let url = self.directory.new_order.clone();
+        let (order_url, order): (_, Order) = self
+            .post_json(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = order_url.ok_or(AcmeError::Missing(url, "Location"))?;
+        Ok((order_url, order))
+    }
+
+    // Prove control of the domain of an authorization, unless that's
+    // already been done.
+    async fn authorize(
+        &mut self,
+        url: &str,
+        tokens: &Arc<Mutex<HashMap<String, String>>>,
+    ) -> Result<(), AcmeError> {
+        let (_, authz): (_, Authorization) = self.post_json(url, None).await?;
+        let domain = authz.identifier.value.clone();
+        if authz.status == "valid" {
+            return Ok(());
+        }
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.kind == "http-01")
+            .ok_or_else(|| AcmeError::NoHttpChallenge(domain.clone()))?;
+        let token = challenge
+            .token
+            .as_ref()
+            .ok_or_else(|| AcmeError::Missing(url.to_string(), "challenge token"))?;
+        tokens
+            .lock()
+            .unwrap()
+            .insert(token.clone(), key_authorization(token, &self.jwk));
+
+        debug!("answering ACME challenge for {}", domain);
+        let challenge_url = challenge.url.clone();
+        self.post(&challenge_url, Some(&json!({}))).await?;
+
+        for _ in 0..MAX_POLLS {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let (_, authz): (_, Authorization) = self.post_json(url, None).await?;
+            match authz.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" => (),
+                _ => {
+                    let problem = authz
+                        .challenges
+                        .iter()
+                        .filter_map(|c| c.error.as_ref())
+                        .map(|e| e.to_string())
+                        .next()
+                        .unwrap_or(authz.status);
+                    return Err(AcmeError::AuthorizationFailed(domain, problem));
+                }
+            }
+        }
+        Err(AcmeError::Timeout(domain))
+    }
+
+    // Ask for the certificate to be issued, wait for it, and return
+    // its URL.
+    async fn finalize(
+        &mut self,
+        order_url: &str,
+        order: &Order,
+        csr: &[u8],
+    ) -> Result<String, AcmeError> {
+        let (_, mut order): (_, Order) = self
+            .post_json(&order.finalize, Some(&json!({ "csr": b64(csr) })))
+            .await?;
+        for _ in 0..MAX_POLLS {
+            match order.status.as_str() {
+                "valid" => {
+                    return order
+                        .certificate
+                        .ok_or_else(|| AcmeError::Missing(order_url.to_string(), "certificate"));
+                }
+                "invalid" => {
+                    let problem = order.error.map(|e| e.to_string()).unwrap_or(order.status);
+                    return Err(AcmeError::OrderFailed(problem));
+                }
+                _ => (),
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+            order = self.post_json(order_url, None).await?.1;
+        }
+        Err(AcmeError::Timeout(order_url.to_string()))
+    }
+}
+
+fn replay_nonce(res: &reqwest::Response) -> Option<String> {
+    header(res, "Replay-Nonce")
+}
+
+fn location(res: &reqwest::Response) -> Option<String> {
+    header(res, "Location")
+}
+
+fn header(res: &reqwest::Response, name: &str) -> Option<String> {
+    res.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+// A plain HTTP server answering challenge requests, while a
+// certificate is being obtained.
+struct ChallengeResponder {
+    tokens: Arc<Mutex<HashMap<String, String>>>,
+    stop: oneshot::Sender<()>,
+}
+
+impl ChallengeResponder {
+    fn start(address: &str) -> Result<Self, AcmeError> {
+        let addr = address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| AcmeError::BadChallengeAddress(address.to_string()))?;
+
+        let tokens: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
+        let shared = Arc::clone(&tokens);
+        let route = warp::get()
+            .and(warp::path(".well-known"))
+            .and(warp::path("acme-challenge"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .map(move |token: String| {
+                let answer = shared.lock().unwrap().get(&token).cloned();
+                match answer {
+                    Some(answer) => {
+                        debug!("answered ACME challenge {}", token);
+                        warp::reply::with_status(answer, warp::http::StatusCode::OK)
+                    }
+                    None => {
+                        warp::reply::with_status(String::new(), warp::http::StatusCode::NOT_FOUND)
+                    }
+                }
+            });
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let (_, server) = warp::serve(route)
+            .try_bind_with_graceful_shutdown(addr, async {
+                stopped.await.ok();
+            })
+            .map_err(|err| AcmeError::ChallengeListen(addr, err))?;
+        tokio::spawn(server);
+        Ok(Self { tokens, stop })
+    }
+
+    fn stop(self) {
+        self.stop.send(()).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{certificate_request, jwk, key_authorization, new_key, sign, AcmeConfig};
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::hash::MessageDigest;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Req, X509};
+    use sha2::{Digest, Sha256};
+    use tempfile::tempdir;
+
+    #[test]
+    fn signature_verifies() {
+        let key = new_key().unwrap();
+        let sig = sign(&key, b"hello").unwrap();
+        assert_eq!(sig.len(), 64);
+        let r = BigNum::from_slice(&sig[..32]).unwrap();
+        let s = BigNum::from_slice(&sig[32..]).unwrap();
+        let sig = EcdsaSig::from_private_components(r, s).unwrap();
+        let digest = Sha256::digest(b"hello");
+        assert!(sig.verify(&digest, &key.ec_key().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn key_authorization_uses_jwk_thumbprint() {
+        let key = new_key().unwrap();
+        let jwk = jwk(&key).unwrap();
+        assert!(jwk.starts_with(r#"{"crv":"P-256","kty":"EC","x":""#));
+        let auth = key_authorization("token", &jwk);
+        let (token, thumbprint) = auth.split_once('.').unwrap();
+        assert_eq!(token, "token");
+        assert_eq!(thumbprint.len(), 43);
+    }
+
+    #[test]
+    fn certificate_request_names_all_domains() {
+        let key = new_key().unwrap();
+        let domains = vec!["a.example".to_string(), "b.example".to_string()];
+        let der = certificate_request(&key, &domains).unwrap();
+        let req = X509Req::from_der(&der).unwrap();
+        assert!(req.verify(&key).unwrap());
+        let text = String::from_utf8(req.to_text().unwrap()).unwrap();
+        assert!(text.contains("DNS:a.example, DNS:b.example"));
+    }
+
+    #[test]
+    fn needs_certificate_until_valid_one_exists() {
+        let dir = tempdir().unwrap();
+        let config = AcmeConfig {
+            domains: vec!["a.example".to_string()],
+            contact: None,
+            directory: super::default_directory(),
+            state: dir.path().to_path_buf(),
+            challenge_address: super::default_challenge_address(),
+            accept_terms: true,
+        };
+        assert!(config.check().is_ok());
+        assert!(config.needs_certificate().unwrap());
+
+        let write_cert = |days: u32| {
+            let key = new_key().unwrap();
+            let mut cert = X509::builder().unwrap();
+            cert.set_pubkey(&key).unwrap();
+            cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+                .unwrap();
+            cert.set_not_after(&Asn1Time::days_from_now(days).unwrap())
+                .unwrap();
+            let san = SubjectAlternativeName::new()
+                .dns("a.example")
+                .build(&cert.x509v3_context(None, None))
+                .unwrap();
+            cert.append_extension(san).unwrap();
+            cert.sign(&key, MessageDigest::sha256()).unwrap();
+            let pem = cert.build().to_pem().unwrap();
+            std::fs::write(config.cert_path(), pem).unwrap();
+            let pem = key.private_key_to_pem_pkcs8().unwrap();
+            std::fs::write(config.key_path(), pem).unwrap();
+        };
+
+        write_cert(90);
+        assert!(!config.needs_certificate().unwrap());
+        write_cert(10);
+        assert!(config.needs_certificate().unwrap());
+
+        // A new key without its certificate.
+        write_cert(90);
+        let pem = new_key().unwrap().private_key_to_pem_pkcs8().unwrap();
+        std::fs::write(config.key_path(), pem).unwrap();
+        assert!(config.needs_certificate().unwrap());
+    }
+}
@@ -0,0 +1,519 @@
+//! High-level API for making and restoring backups.
+//!
+//! This module is for other Rust programs that want to embed Obnam,
+//! instead of running the `obnam` command line program. The functions
+//! here don't print anything or draw progress bars. Instead, they
+//! report progress and warnings via callbacks, and return a report of
+//! what was done.
+//!
+//! Like the command line program, a backup or restore holds the lock
+//! for the client while it runs, so that it can't run at the same time
+//! as another one for the same client. If another run holds the lock,
+//! it fails with [`RunLockError::Locked`](crate::runlock::RunLockError).
+//!
+//! ```no_run
+//! use obnam::api::{Backup, Restore};
+//! use obnam::config::ClientConfig;
+//! use std::path::Path;
+//!
+//! let config = ClientConfig::read(Path::new("obnam.yaml")).unwrap();
+//! let report = Backup::run(&config).unwrap();
+//! println!("made backup {}", report.generation_id);
+//! Restore::run(&config, "latest", Path::new("/tmp/restored")).unwrap();
+//! ```
+
+use crate::backup_run::{current_timestamp, BackupEstimate, BackupRun};
+use crate::chunk::ClientTrust;
+use crate::client::BackupClient;
+use crate::cmd::restore::{restore, OwnerMap, OwnerPolicy, RestoreOptions};
+use crate::config::ClientConfig;
+use crate::dbgen::{schema_version, FileId, DEFAULT_SCHEMA_MAJOR};
+use crate::error::ObnamError;
+use crate::generation::{GenId, LocalGeneration};
+use crate::performance::{Clock, Performance, ProgressCounter};
+use crate::problem::Problem;
+use crate::progress_sink::{ProgressEvent, ProgressSink, WarningEvents, WarningSink};
+use crate::refcount::register_generation;
+use crate::reposettings::RepositorySettings;
+use crate::runlock::RunLock;
+use crate::sampling::verify_sample;
+use crate::schema::{SchemaVersion, VersionComponent};
+use log::{info, warn};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tempfile::tempdir;
+use tokio::runtime::Runtime;
+
+pub use tokio_util::sync::CancellationToken;
+
+/// Ignore progress and warnings.
+pub struct Quiet;
+
+impl ProgressSink for Quiet {
+    fn event(&self, _event: &ProgressEvent) {}
+}
+
+impl WarningSink for Quiet {
+    fn warning(&self, _message: &str) {}
+}
+
+/// What happened during a backup.
+#[derive(Debug)]
+pub struct BackupReport {
+    /// Identifier of the new backup.
+    pub generation_id: GenId,
+    /// Was the backup incremental, based on a previous one?
+    pub is_incremental: bool,
+    /// Number of files in the backup.
+    pub file_count: FileId,
+    /// Problems that didn't stop the backup.
+    pub warnings: Vec<String>,
+    /// The same problems, with codes for what kind of problem each
+    /// one is.
+    pub problems: Vec<Problem>,
+    /// CACHEDIR.TAG files that aren't in the previous backup.
+    pub new_cachedir_tags: Vec<PathBuf>,
+    /// Number of uploaded chunks that were fetched again and
+    /// verified, as the `verify_sample_percent` setting says.
+    pub verified_chunks: usize,
+}
+
+/// Make a backup.
+pub struct Backup;
+
+impl Backup {
+    /// Make a backup, without reporting progress or warnings.
+    pub fn run(config: &ClientConfig) -> Result<BackupReport, ObnamError> {
+        Self::run_with(config, &Quiet, &Quiet, CancellationToken::new())
+    }
+
+    /// Make a backup, reporting progress and warnings as they happen.
+    ///
+    /// The backup stops with an error, without recording a new
+    /// backup, if `cancel` is cancelled.
+    ///
+    /// This starts an asynchronous runtime of its own. From
+    /// asynchronous code, use [`Backup::run_async`] instead.
+    pub fn run_with(
+        config: &ClientConfig,
+        progress: &dyn ProgressSink,
+        warnings: &dyn WarningSink,
+        cancel: CancellationToken,
+    ) -> Result<BackupReport, ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(Self::run_async(config, progress, warnings, cancel))
+    }
+
+    /// Make a backup, from asynchronous code.
+    pub async fn run_async(
+        config: &ClientConfig,
+        progress: &dyn ProgressSink,
+        warnings: &dyn WarningSink,
+        cancel: CancellationToken,
+    ) -> Result<BackupReport, ObnamError> {
+        let options = BackupOptions {
+            progress_bars: false,
+            cancel,
+            ..BackupOptions::default()
+        };
+        let sinks: Vec<Box<dyn ProgressSink + '_>> =
+            vec![Box::new(progress), Box::new(WarningEvents(warnings))];
+        let mut perf = Performance::default();
+        backup(config, &options, sinks, &mut perf).await
+    }
+}
+
+// How to make a backup. The command line program has more choices
+// than the library API.
+pub(crate) struct BackupOptions {
+    pub(crate) full: bool,
+    // Backup schema major version, if not the repository's default.
+    pub(crate) schema_major: Option<VersionComponent>,
+    pub(crate) progress_bars: bool,
+    pub(crate) cancel: CancellationToken,
+    // Back up the standard input as a stream with this name, instead
+    // of the backup roots.
+    pub(crate) stream: Option<PathBuf>,
+    // Tag to record in the new generation's metadata.
+    pub(crate) tag: Option<String>,
+    // Wait for another run for the same client to finish, instead of
+    // failing.
+    pub(crate) wait: bool,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            full: false,
+            schema_major: None,
+            progress_bars: true,
+            cancel: CancellationToken::new(),
+            stream: None,
+            tag: None,
+            wait: false,
+        }
+    }
+}
+
+// Make a backup, and record it as the latest one for the client.
+pub(crate) async fn backup<'a>(
+    config: &ClientConfig,
+    options: &BackupOptions,
+    sinks: Vec<Box<dyn ProgressSink + 'a>>,
+    perf: &mut Performance,
+) -> Result<BackupReport, ObnamError> {
+    let _lock =
+        RunLock::acquire_async(&config.lock_filename(), options.wait, &options.cancel).await?;
+    let mut client = BackupClient::new(config).await?;
+    backup_with(&mut client, config, options, sinks, perf).await
+}
+
+// Make a backup with a given client.
+pub(crate) async fn backup_with<'a>(
+    client: &mut BackupClient,
+    config: &ClientConfig,
+    options: &BackupOptions,
+    sinks: Vec<Box<dyn ProgressSink + 'a>>,
+    perf: &mut Performance,
+) -> Result<BackupReport, ObnamError> {
+    let base = BackupBase::find(client, options).await?;
+    let is_incremental = base.old_id.is_some();
+
+    let temp = tempdir()?;
+    let oldtemp = temp.path().join("old.db");
+    let newtemp = temp.path().join("new.db");
+
+    let counter = ProgressCounter::default();
+    let outcome = {
+        let (mut run, old) = start_run(
+            config, client, &base, options, sinks, &counter, &oldtemp, perf,
+        )
+        .await?;
+        match &options.stream {
+            None => {
+                run.backup_roots(config, &old, &newtemp, base.schema, perf)
+                    .await?
+            }
+            Some(name) => {
+                let stdin = std::io::stdin();
+                run.backup_stream(&old, &newtemp, base.schema, name, stdin.lock(), perf)
+                    .await?
+            }
+        }
+    };
+
+    perf.count_progress(&counter);
+
+    let clock = perf.clock(Clock::GenerationUpload);
+    let mut trust = base.trust;
+    trust.append_backup(outcome.gen_id.as_chunk_id());
+    trust.finalize(current_timestamp());
+    let trust_id = client.update_client_trust(&trust).await?;
+    drop(clock);
+    info!("uploaded new client-trust {}", trust_id);
+
+    // Failing to tell the server which chunks the new generation uses
+    // only makes forgetting generations slower, so it's not an error.
+    if let Err(err) = register_generation(client, &outcome.gen_id).await {
+        warn!(
+            "couldn't register generation {} with server: {}",
+            outcome.gen_id, err
+        );
+    }
+
+    // The backup is recorded even if some of its chunks turn out to
+    // be damaged, so that it can be repaired.
+    let mut verified_chunks = 0;
+    if config.verify_sample_percent > 0.0 {
+        let verification = verify_sample(client, config.verify_sample_percent).await?;
+        if !verification.damaged.is_empty() {
+            return Err(ObnamError::DamagedUploads(
+                outcome.gen_id,
+                verification.damaged.len(),
+                verification.checked,
+            ));
+        }
+        verified_chunks = verification.checked;
+    }
+
+    perf.network(client.network_stats().counts());
+
+    Ok(BackupReport {
+        generation_id: outcome.gen_id,
+        is_incremental,
+        file_count: outcome.files_count,
+        warnings: outcome.problems.iter().map(|p| p.to_string()).collect(),
+        problems: outcome.problems,
+        new_cachedir_tags: outcome.new_cachedir_tags,
+        verified_chunks,
+    })
+}
+
+// What a dry run of a backup found.
+pub(crate) struct DryRunReport {
+    pub(crate) is_incremental: bool,
+    pub(crate) file_count: FileId,
+    pub(crate) problems: Vec<Problem>,
+    pub(crate) new_cachedir_tags: Vec<PathBuf>,
+}
+
+// Go through the backup roots as for a backup, but don't upload
+// anything. What would have been uploaded is counted in `perf`.
+pub(crate) async fn dry_run<'a>(
+    config: &ClientConfig,
+    options: &BackupOptions,
+    sinks: Vec<Box<dyn ProgressSink + 'a>>,
+    perf: &mut Performance,
+) -> Result<DryRunReport, ObnamError> {
+    let _lock =
+        RunLock::acquire_async(&config.lock_filename(), options.wait, &options.cancel).await?;
+    let mut client = BackupClient::new(config).await?;
+    let base = BackupBase::find(&client, options).await?;
+    let is_incremental = base.old_id.is_some();
+
+    let temp = tempdir()?;
+    let oldtemp = temp.path().join("old.db");
+
+    let counter = ProgressCounter::default();
+    let outcome = {
+        let (mut run, old) = start_run(
+            config,
+            &mut client,
+            &base,
+            options,
+            sinks,
+            &counter,
+            &oldtemp,
+            perf,
+        )
+        .await?;
+        run.dry_run_roots(config, &old, base.schema).await?
+    };
+
+    perf.count_progress(&counter);
+    perf.network(client.network_stats().counts());
+
+    Ok(DryRunReport {
+        is_incremental,
+        file_count: outcome.files_count,
+        problems: outcome.problems,
+        new_cachedir_tags: outcome.new_cachedir_tags,
+    })
+}
+
+// Estimate quickly how much a backup would upload, by comparing the
+// metadata of files with the previous backup.
+pub(crate) async fn estimate<'a>(
+    config: &ClientConfig,
+    options: &BackupOptions,
+    sinks: Vec<Box<dyn ProgressSink + 'a>>,
+    perf: &mut Performance,
+) -> Result<BackupEstimate, ObnamError> {
+    let _lock =
+        RunLock::acquire_async(&config.lock_filename(), options.wait, &options.cancel).await?;
+    let mut client = BackupClient::new(config).await?;
+    let base = BackupBase::find(&client, options).await?;
+
+    let temp = tempdir()?;
+    let oldtemp = temp.path().join("old.db");
+
+    let counter = ProgressCounter::default();
+    let estimate = {
+        let (mut run, old) = start_run(
+            config,
+            &mut client,
+            &base,
+            options,
+            sinks,
+            &counter,
+            &oldtemp,
+            perf,
+        )
+        .await?;
+        run.estimate_roots(config, &old)?
+    };
+
+    perf.count_progress(&counter);
+    perf.network(client.network_stats().counts());
+    Ok(estimate)
+}
+
+// What a new backup is based on.
+struct BackupBase {
+    settings: Option<RepositorySettings>,
+    schema: SchemaVersion,
+    trust: ClientTrust,
+    // The previous backup, unless a full backup is wanted.
+    old_id: Option<GenId>,
+}
+
+impl BackupBase {
+    async fn find(client: &BackupClient, options: &BackupOptions) -> Result<Self, ObnamError> {
+        let settings = client.get_repository_settings().await?;
+        let schema_major = options
+            .schema_major
+            .or_else(|| settings.as_ref().map(|s| s.schema_major()))
+            .unwrap_or(DEFAULT_SCHEMA_MAJOR);
+        let schema = schema_version(schema_major)?;
+
+        let trust = client.get_client_trust().await?;
+        let genlist = client.list_generations(&trust);
+
+        let old_id = if options.full {
+            None
+        } else {
+            genlist.resolve("latest").ok()
+        };
+
+        Ok(Self {
+            settings,
+            schema,
+            trust,
+            old_id,
+        })
+    }
+}
+
+// Create and start a backup run. The previous backup, if any, is
+// downloaded into `oldtemp`. Progress is reported to the sinks, and
+// counted by `counter`.
+#[allow(clippy::too_many_arguments)]
+async fn start_run<'a>(
+    config: &ClientConfig,
+    client: &'a mut BackupClient,
+    base: &BackupBase,
+    options: &BackupOptions,
+    sinks: Vec<Box<dyn ProgressSink + 'a>>,
+    counter: &'a ProgressCounter,
+    oldtemp: &Path,
+    perf: &mut Performance,
+) -> Result<(BackupRun<'a>, LocalGeneration), ObnamError> {
+    let mut run = if let Some(old_id) = &base.old_id {
+        info!("incremental backup based on {}", old_id);
+        BackupRun::incremental(config, client, options.cancel.clone())?
+    } else {
+        info!("fresh backup without a previous generation");
+        BackupRun::initial(config, client, options.cancel.clone())?
+    };
+    match &base.settings {
+        Some(settings) => run.use_repository_settings(settings),
+        None => info!("repository has no settings, using client configuration"),
+    }
+    if !options.progress_bars {
+        run.hide_progress_bars();
+    }
+    if let Some(tag) = &options.tag {
+        run.set_tag(tag);
+    }
+    if config.exclude_obnam_files {
+        // The temporary directory holds the old and new generation
+        // databases while the backup runs.
+        if let Some(dir) = oldtemp.parent() {
+            run.exclude(dir);
+        }
+    }
+    for sink in sinks {
+        run.add_progress_sink(sink);
+    }
+    run.add_progress_sink(Box::new(counter));
+    let old = run.start(base.old_id.as_ref(), oldtemp, perf).await?;
+    Ok((run, old))
+}
+
+/// What happened during a restore.
+#[derive(Debug)]
+pub struct RestoreReport {
+    /// Identifier of the restored backup.
+    pub generation_id: GenId,
+    /// Number of files restored.
+    pub file_count: FileId,
+    /// Number of files that weren't in the target directory yet.
+    pub created: FileId,
+    /// Number of files that replaced something different in the
+    /// target directory.
+    pub overwritten: FileId,
+    /// Number of files that were already in the target directory,
+    /// with the right content, and weren't written again.
+    pub skipped: FileId,
+    /// Features used by the backup that were ignored, because this
+    /// version of Obnam doesn't support them.
+    pub ignored_features: Vec<String>,
+    /// Parts of files that couldn't be restored, because their chunks
+    /// couldn't be fetched. This is only ever non-empty if the
+    /// restore was told to keep going after such errors.
+    pub damaged: Vec<DamagedRegion>,
+}
+
+/// A part of a restored file whose content couldn't be fetched.
+///
+/// The region is filled with zeros in the restored file.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct DamagedRegion {
+    /// The file, as it was named in the backup.
+    pub path: PathBuf,
+    /// Where in the file the region starts, in bytes.
+    pub offset: u64,
+    /// Length of the region, in bytes.
+    pub length: u64,
+    /// Why the content couldn't be fetched.
+    pub error: String,
+}
+
+/// Restore a backup.
+pub struct Restore;
+
+impl Restore {
+    /// Restore a backup into a directory, without reporting progress
+    /// or warnings.
+    ///
+    /// The backup is identified by anything `obnam restore` accepts,
+    /// such as `latest`. Restored files are owned by the users and
+    /// groups with the same names as in the backup, if they exist.
+    pub fn run(config: &ClientConfig, gen: &str, to: &Path) -> Result<RestoreReport, ObnamError> {
+        Self::run_with(config, gen, to, &Quiet, &Quiet, CancellationToken::new())
+    }
+
+    /// Restore a backup, reporting progress and warnings as they
+    /// happen.
+    ///
+    /// The restore stops with an error if `cancel` is cancelled. The
+    /// file being restored at that moment is removed, but files
+    /// restored before it are left in place.
+    ///
+    /// This starts an asynchronous runtime of its own. From
+    /// asynchronous code, use [`Restore::run_async`] instead.
+    pub fn run_with(
+        config: &ClientConfig,
+        gen: &str,
+        to: &Path,
+        progress: &dyn ProgressSink,
+        warnings: &dyn WarningSink,
+        cancel: CancellationToken,
+    ) -> Result<RestoreReport, ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(Self::run_async(config, gen, to, progress, warnings, cancel))
+    }
+
+    /// Restore a backup, from asynchronous code.
+    pub async fn run_async(
+        config: &ClientConfig,
+        gen: &str,
+        to: &Path,
+        progress: &dyn ProgressSink,
+        warnings: &dyn WarningSink,
+        cancel: CancellationToken,
+    ) -> Result<RestoreReport, ObnamError> {
+        let options = RestoreOptions {
+            owners: OwnerMap::new(OwnerPolicy::ByName, None, None)?,
+            progress_bar: false,
+            progress,
+            warnings,
+            cancel,
+            jobs: config.jobs,
+            keep_going: false,
+            from_dir: None,
+            wait: false,
+        };
+        restore(config, gen, to, &options).await
+    }
+}
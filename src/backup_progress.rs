@@ -1,48 +1,57 @@
 //! Progress bars for Obnam.
 
 use crate::generation::GenId;
-use indicatif::{ProgressBar, ProgressStyle};
+use crate::progress_sink::{ProgressEvent, ProgressSink};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Instant;
 
 const SHOW_PROGRESS: bool = true;
 
 /// A progress bar abstraction specific to backups.
 ///
 /// The progress bar is different for initial and incremental backups,
-/// and for different phases of making a backup.
+/// and for different phases of making a backup. During the backup
+/// of files, there are two bars: one for files, and one for bytes.
 pub struct BackupProgress {
     progress: ProgressBar,
+    bytes: Option<ByteProgress>,
+    drawer: Mutex<Option<JoinHandle<std::io::Result<()>>>>,
 }
 
+// Progress in bytes of file content.
+struct ByteProgress {
+    bar: ProgressBar,
+    uploaded: AtomicU64,
+    reused: AtomicU64,
+    // When the upload speed was last measured, how much had been
+    // uploaded then, and the speed.
+    speed: Mutex<(Instant, u64, u64)>,
+}
+
+// How often the upload speed is measured, in seconds.
+const SPEED_INTERVAL: f64 = 1.0;
+
 impl BackupProgress {
     /// Create a progress bar for an initial backup.
     pub fn initial() -> Self {
-        let progress = if SHOW_PROGRESS {
-            ProgressBar::new(0)
-        } else {
-            ProgressBar::hidden()
-        };
-        let parts = vec![
+        let files = vec![
             "initial backup",
             "elapsed: {elapsed}",
             "files: {pos}",
             "current: {wide_msg}",
             "{spinner}",
         ];
-        progress.set_style(ProgressStyle::default_bar().template(&parts.join("\n")));
-        progress.enable_steady_tick(100);
-
-        Self { progress }
+        let bytes = vec!["bytes: {bytes} ({binary_bytes_per_sec})", "{msg}"];
+        Self::with_bytes(&files, &bytes)
     }
 
     /// Create a progress bar for an incremental backup.
     pub fn incremental() -> Self {
-        let progress = if SHOW_PROGRESS {
-            ProgressBar::new(0)
-        } else {
-            ProgressBar::hidden()
-        };
-        let parts = vec![
+        let files = vec![
             "incremental backup",
             "{wide_bar}",
             "elapsed: {elapsed}",
@@ -50,10 +59,46 @@ impl BackupProgress {
             "current: {wide_msg}",
             "{spinner}",
         ];
-        progress.set_style(ProgressStyle::default_bar().template(&parts.join("\n")));
+        let bytes = vec![
+            "{wide_bar}",
+            "bytes: {bytes}/{total_bytes} ({binary_bytes_per_sec}), eta: {eta}",
+            "{msg}",
+        ];
+        Self::with_bytes(&files, &bytes)
+    }
+
+    // Create a files bar and a bytes bar, shown together.
+    fn with_bytes(files: &[&str], bytes: &[&str]) -> Self {
+        let multi = if SHOW_PROGRESS {
+            MultiProgress::new()
+        } else {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        };
+
+        let progress = multi.add(ProgressBar::new(0));
+        progress.set_style(ProgressStyle::default_bar().template(&files.join("\n")));
         progress.enable_steady_tick(100);
 
-        Self { progress }
+        let bar = multi.add(ProgressBar::new(0));
+        bar.set_style(ProgressStyle::default_bar().template(&bytes.join("\n")));
+
+        // The bars are only drawn while someone waits for them to
+        // finish.
+        let drawer = std::thread::spawn(move || multi.join_and_clear());
+
+        let bytes = ByteProgress {
+            bar,
+            uploaded: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+            speed: Mutex::new((Instant::now(), 0, 0)),
+        };
+        bytes.update_message();
+
+        Self {
+            progress,
+            bytes: Some(bytes),
+            drawer: Mutex::new(Some(drawer)),
+        }
     }
 
     /// Create a progress bar for uploading a new generation's metadata.
@@ -67,7 +112,16 @@ impl BackupProgress {
         progress.set_style(ProgressStyle::default_bar().template(&parts.join("\n")));
         progress.enable_steady_tick(100);
 
-        Self { progress }
+        Self::single(progress)
+    }
+
+    // Wrap a single progress bar.
+    fn single(progress: ProgressBar) -> Self {
+        Self {
+            progress,
+            bytes: None,
+            drawer: Mutex::new(None),
+        }
     }
 
     /// Create a progress bar for downloading an existing generation's
@@ -82,7 +136,7 @@ impl BackupProgress {
             gen_id
         ));
 
-        Self { progress }
+        Self::single(progress)
     }
 
     /// Set the number of files that were in the previous generation.
@@ -94,6 +148,42 @@ impl BackupProgress {
         self.progress.set_length(count);
     }
 
+    /// Set the number of bytes of file content that were in the
+    /// previous generation.
+    ///
+    /// This is used to estimate how long the backup will take.
+    pub fn bytes_in_previous_generation(&self, bytes: u64) {
+        if let Some(b) = &self.bytes {
+            b.bar.set_length(bytes);
+        }
+    }
+
+    /// Update progress bar about a chunk of file content that was
+    /// uploaded.
+    pub fn uploaded_chunk(&self, bytes: u64) {
+        if let Some(b) = &self.bytes {
+            b.uploaded.fetch_add(bytes, Ordering::Relaxed);
+            b.advance(bytes);
+        }
+    }
+
+    /// Update progress bar about a chunk of file content that was
+    /// already on the server.
+    pub fn reused_chunk(&self, bytes: u64) {
+        if let Some(b) = &self.bytes {
+            b.reused.fetch_add(bytes, Ordering::Relaxed);
+            b.advance(bytes);
+        }
+    }
+
+    /// Update progress bar about file content that didn't need to be
+    /// backed up.
+    pub fn unchanged_bytes(&self, bytes: u64) {
+        if let Some(b) = &self.bytes {
+            b.advance(bytes);
+        }
+    }
+
     /// Update progress bar about number of problems found during a backup.
     pub fn found_problem(&self) {
         self.progress.inc(1);
@@ -115,5 +205,63 @@ impl BackupProgress {
     pub fn finish(&self) {
         self.progress.set_length(self.progress.position());
         self.progress.finish_and_clear();
+        if let Some(b) = &self.bytes {
+            b.bar.finish_and_clear();
+        }
+        if let Some(drawer) = self.drawer.lock().unwrap().take() {
+            drawer.join().ok();
+        }
+    }
+}
+
+impl ProgressSink for BackupProgress {
+    fn event(&self, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::FileStarted { path } => self.found_live_file(Path::new(path)),
+            ProgressEvent::FileUnchanged { bytes, .. } => self.unchanged_bytes(*bytes),
+            ProgressEvent::ChunkUploaded {
+                bytes,
+                reused: true,
+                ..
+            } => self.reused_chunk(*bytes),
+            ProgressEvent::ChunkUploaded {
+                bytes,
+                reused: false,
+                ..
+            } => self.uploaded_chunk(*bytes),
+            ProgressEvent::Warning { .. } => self.found_problem(),
+            ProgressEvent::Finished { .. } => (),
+        }
+    }
+}
+
+impl ByteProgress {
+    fn advance(&self, bytes: u64) {
+        self.bar.inc(bytes);
+        if self.bar.length() < self.bar.position() {
+            self.bar.set_length(self.bar.position());
+        }
+        self.update_message();
+    }
+
+    fn update_message(&self) {
+        let uploaded = self.uploaded.load(Ordering::Relaxed);
+        let reused = self.reused.load(Ordering::Relaxed);
+        let speed = {
+            let mut speed = self.speed.lock().unwrap();
+            let (then, then_uploaded, _) = *speed;
+            let secs = then.elapsed().as_secs_f64();
+            if secs >= SPEED_INTERVAL {
+                let rate = ((uploaded - then_uploaded) as f64 / secs) as u64;
+                *speed = (Instant::now(), uploaded, rate);
+            }
+            speed.2
+        };
+        self.bar.set_message(format!(
+            "uploaded: {} ({}/s), deduplicated: {}",
+            HumanBytes(uploaded),
+            HumanBytes(speed),
+            HumanBytes(reused)
+        ));
     }
 }
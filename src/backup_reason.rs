@@ -3,19 +3,29 @@
 use rusqlite::types::ToSqlOutput;
 use rusqlite::ToSql;
 use std::fmt;
+use std::path::PathBuf;
 
 /// Represent the reason a file is in a backup.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub enum Reason {
     /// File was skipped due to policy, but carried over without
     /// changes.
     Skipped,
+    /// File is excluded from backups by policy, because of its size
+    /// or kind. It's not in the backup at all.
+    PolicyExcluded,
     /// File is new, compared to previous backup.
     IsNew,
     /// File has been changed, compared to previous backup,
     Changed,
+    /// File content has been changed, compared to previous backup,
+    /// even though its metadata hasn't.
+    ContentChanged,
     /// File has not been changed, compared to previous backup,
     Unchanged,
+    /// File is new, but has the same content as a file with the given
+    /// name in the previous backup, which is no longer there.
+    Renamed(PathBuf),
     /// There was an error looking up the file in the previous backup.
     ///
     /// File has been carried over without changes.
@@ -37,14 +47,36 @@ impl Reason {
     pub fn from(text: &str) -> Reason {
         match text {
             "skipped" => Reason::Skipped,
+            "policyexcluded" => Reason::PolicyExcluded,
             "new" => Reason::IsNew,
             "changed" => Reason::Changed,
+            "contentchanged" => Reason::ContentChanged,
             "unchanged" => Reason::Unchanged,
             "genlookuperror" => Reason::GenerationLookupError,
             "fileerror" => Reason::FileError,
             _ => Reason::Unknown,
         }
     }
+
+    /// Create a Reason from a string representation, and the name the
+    /// file had before it was renamed, if it was.
+    ///
+    /// A file can only be renamed from some name: without one, the
+    /// reason is unknown.
+    pub fn with_renamed_from(text: &str, renamed_from: Option<PathBuf>) -> Reason {
+        match (text, renamed_from) {
+            ("renamed", Some(from)) => Reason::Renamed(from),
+            (text, _) => Reason::from(text),
+        }
+    }
+
+    /// The name the file had before it was renamed, if it was.
+    pub fn renamed_from(&self) -> Option<&PathBuf> {
+        match self {
+            Reason::Renamed(from) => Some(from),
+            _ => None,
+        }
+    }
 }
 
 impl ToSql for Reason {
@@ -62,9 +94,12 @@ impl fmt::Display for Reason {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let reason = match self {
             Reason::Skipped => "skipped",
+            Reason::PolicyExcluded => "policyexcluded",
             Reason::IsNew => "new",
             Reason::Changed => "changed",
+            Reason::ContentChanged => "contentchanged",
             Reason::Unchanged => "unchanged",
+            Reason::Renamed(_) => "renamed",
             Reason::GenerationLookupError => "genlookuperror",
             Reason::FileError => "fileerror",
             Reason::Unknown => "unknown",
@@ -2,39 +2,103 @@
 
 use crate::backup_progress::BackupProgress;
 use crate::backup_reason::Reason;
-use crate::chunk::{GenerationChunk, GenerationChunkError};
+use crate::chunk::{DataChunk, GenerationChunk, GenerationChunkError};
 use crate::chunker::{ChunkerError, FileChunks};
 use crate::chunkid::ChunkId;
+use crate::chunkmeta::ChunkMeta;
+use crate::cipher::Padding;
 use crate::client::{BackupClient, ClientError};
-use crate::config::ClientConfig;
+use crate::config::{absolute, ClientConfig, GenerationUpload};
 use crate::db::DatabaseError;
-use crate::dbgen::{schema_version, FileId, DEFAULT_SCHEMA_MAJOR};
+use crate::dbgen::{schema_version, FileId, DEFAULT_SCHEMA_MAJOR, INCREMENTAL_SCHEMA_MAJOR};
 use crate::error::ObnamError;
-use crate::fsentry::{FilesystemEntry, FilesystemKind};
+use crate::fsentry::{EntryBuilder, FilesystemEntry, FilesystemKind, FsEntryError};
 use crate::fsiter::{AnnotatedFsEntry, FsIterError, FsIterator};
 use crate::generation::{
-    GenId, LocalGeneration, LocalGenerationError, NascentError, NascentGeneration,
+    GenId, LocalGeneration, LocalGenerationError, NascentError, NascentGeneration, MAX_CHAIN_LENGTH,
 };
-use crate::label::LabelChecksumKind;
+use crate::genmeta::{self, Feature};
+use crate::label::{LabelChecksumKind, Labeler};
 use crate::performance::{Clock, Performance};
-use crate::policy::BackupPolicy;
+use crate::policy::{BackupPolicy, ErrorPolicy};
+use crate::problem::{Problem, ProblemCode};
+use crate::progress_sink::{ProgressEvent, ProgressSink};
+use crate::reposettings::RepositorySettings;
 use crate::schema::SchemaVersion;
+use crate::snapshot::{Snapshot, SnapshotError};
 
 use bytesize::MIB;
 use chrono::{DateTime, Local};
+use futures::stream::{FuturesOrdered, StreamExt};
 use log::{debug, error, info, warn};
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use users::UsersCache;
 
 const DEFAULT_CHECKSUM_KIND: LabelChecksumKind = LabelChecksumKind::Sha256;
 const SQLITE_CHUNK_SIZE: usize = MIB as usize;
 
+// Size of the segments in which the metadata of a backup is uploaded,
+// when only changes from the previous backup are uploaded. This is a
+// multiple of any SQLite page size, so that a changed page changes
+// only one segment.
+const DELTA_SEGMENT_SIZE: usize = 64 * 1024;
+
+// How many chunks to read ahead, to ask the server in one request
+// which of them it already has.
+const EXISTS_BATCH: usize = 16;
+
 /// A running backup.
 pub struct BackupRun<'a> {
     checksum_kind: Option<LabelChecksumKind>,
     client: &'a mut BackupClient,
     policy: BackupPolicy,
     buffer_size: usize,
+    progress_bars: bool,
     progress: Option<BackupProgress>,
+    sinks: Vec<Box<dyn ProgressSink + 'a>>,
+    started: String,
+    tag: Option<String>,
+    max_uploads: usize,
+    cancel: CancellationToken,
+    generation_upload: GenerationUpload,
+    padding: Padding,
+    // The generation this backup is based on, if any.
+    previous: Option<GenId>,
+    // Chunks of the previous backup's metadata, by label, for
+    // uploading only changed parts of the new backup's metadata.
+    previous_segments: HashMap<String, ChunkId>,
+    // Set in a dry run, which uploads nothing.
+    dry_run: Option<DryRun>,
+    // Obnam's own files and directories, which are never backed up.
+    excluded: Vec<PathBuf>,
+}
+
+// The chunks a dry run would have uploaded, by label, so that
+// identical chunks count as new only once.
+#[derive(Default)]
+struct DryRun {
+    new_chunks: Mutex<HashMap<String, ChunkId>>,
+}
+
+impl DryRun {
+    fn chunk(&self, label: &str) -> Option<ChunkId> {
+        self.new_chunks.lock().unwrap().get(label).cloned()
+    }
+
+    // Pretend to upload a chunk. It gets an identifier that's only
+    // used in the new generation, which is never uploaded.
+    fn upload(&self, chunk: &DataChunk) -> ChunkId {
+        let chunk_id = ChunkId::new();
+        self.new_chunks
+            .lock()
+            .unwrap()
+            .insert(chunk.meta().label().to_string(), chunk_id.clone());
+        chunk_id
+    }
 }
 
 /// Possible errors that can occur during a backup.
@@ -67,6 +131,78 @@ pub enum BackupError {
     /// A error splitting backup metadata into chunks.
     #[error(transparent)]
     GenerationChunkError(#[from] GenerationChunkError),
+
+    /// The backup was cancelled while backing up a file.
+    #[error("backup was cancelled")]
+    Cancelled,
+
+    /// The backup was cancelled, after some files had been backed up.
+    #[error("backup cancelled after {0} files; no new backup was made")]
+    CancelledAfter(FileId),
+
+    /// An error removing a snapshot of a backup root.
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+
+    /// A stream name can't be restored safely.
+    #[error("stream name {0} must not be empty or contain \"..\"")]
+    BadStreamName(PathBuf),
+
+    /// An error describing a stream.
+    #[error(transparent)]
+    FsEntryError(#[from] FsEntryError),
+
+    /// An error under a path where policy says errors stop the
+    /// backup.
+    #[error("backup stopped, because policy doesn't allow errors for {0}: {1}")]
+    Aborted(PathBuf, Box<BackupError>),
+}
+
+impl BackupError {
+    /// Describe the error as a problem that didn't stop a backup.
+    pub fn problem(&self) -> Problem {
+        let (code, path) = match self {
+            Self::ClientError(ClientError::FileOpen(path, _)) => {
+                (ProblemCode::UnreadableFile, Some(path.clone()))
+            }
+            Self::ClientError(_) => (ProblemCode::Server, None),
+            Self::FsIterError(err) => fsiter_problem(err),
+            Self::FsEntryError(err) => fsentry_problem(err),
+            Self::ChunkerError(ChunkerError::FileRead(path, _)) => {
+                (ProblemCode::UnreadableFile, Some(path.clone()))
+            }
+            Self::NascentError(_)
+            | Self::LocalGenerationError(_)
+            | Self::Database(_)
+            | Self::GenerationChunkError(_) => (ProblemCode::Database, None),
+            Self::Snapshot(_) => (ProblemCode::Snapshot, None),
+            Self::Cancelled | Self::CancelledAfter(_) | Self::BadStreamName(_) => {
+                (ProblemCode::Other, None)
+            }
+            Self::Aborted(path, _) => (ProblemCode::Other, Some(path.clone())),
+        };
+        Problem::new(code, path, &self.to_string())
+    }
+}
+
+fn fsiter_problem(err: &FsIterError) -> (ProblemCode, Option<PathBuf>) {
+    match err {
+        FsIterError::WalkDir(err) => (
+            ProblemCode::UnreadableDirectory,
+            err.path().map(|path| path.to_path_buf()),
+        ),
+        FsIterError::Metadata(path, _) => (ProblemCode::UnreadableMetadata, Some(path.clone())),
+        FsIterError::OtherFileSystem(path) => (ProblemCode::OtherFileSystem, Some(path.clone())),
+        FsIterError::AlreadyVisited(path) => (ProblemCode::AlreadyVisited, Some(path.clone())),
+        FsIterError::FsEntryError(err) => fsentry_problem(err),
+    }
+}
+
+fn fsentry_problem(err: &FsEntryError) -> (ProblemCode, Option<PathBuf>) {
+    match err {
+        FsEntryError::ReadLink(path, _) => (ProblemCode::UnreadableMetadata, Some(path.clone())),
+        FsEntryError::UnknownFileKindCode(_) => (ProblemCode::UnreadableMetadata, None),
+    }
 }
 
 /// The outcome of backing up a file system entry.
@@ -76,10 +212,22 @@ pub struct FsEntryBackupOutcome {
     pub entry: FilesystemEntry,
     /// The chunk identifiers for the file's content.
     pub ids: Vec<ChunkId>,
+    /// The lengths of the chunks, in the same order as their ids, or
+    /// empty if they're not known.
+    pub lengths: Vec<u64>,
+    /// The labels of the chunks, in the same order as their ids, or
+    /// empty if they're not known.
+    pub labels: Vec<String>,
     /// Why this entry is added to the new backup.
     pub reason: Reason,
     /// Does this entry represent a cache directory?
     pub is_cachedir_tag: bool,
+    /// An error backing up the entry's content, if any. The entry is
+    /// then recorded without content.
+    pub error: Option<BackupError>,
+    /// Why the entry's content couldn't be backed up, in this backup
+    /// or an earlier one, as recorded in the new backup.
+    pub error_message: Option<String>,
 }
 
 /// The outcome of backing up a backup root.
@@ -89,6 +237,74 @@ struct OneRootBackupOutcome {
     pub warnings: Vec<BackupError>,
     /// New cache directories in this root.
     pub new_cachedir_tags: Vec<PathBuf>,
+    /// Was the backup cancelled before the whole root was backed up?
+    pub cancelled: bool,
+}
+
+/// The outcome of a dry run of a backup.
+#[derive(Debug)]
+pub struct DryRunOutcome {
+    /// The number of files that would have been backed up.
+    pub files_count: FileId,
+    /// The problems encountered while going through files.
+    pub problems: Vec<Problem>,
+    /// CACHEDIR.TAG files that aren't present in in a previous generation.
+    pub new_cachedir_tags: Vec<PathBuf>,
+}
+
+/// A quick estimate of what a backup would do.
+///
+/// Files are compared with the previous backup only by their
+/// metadata. Their content isn't read.
+#[derive(Debug, Default)]
+pub struct BackupEstimate {
+    /// Files found in the backup roots.
+    pub live_files: FileId,
+    /// Files that aren't in the previous backup.
+    pub new_files: FileId,
+    /// Size of the content of new files.
+    pub new_bytes: u64,
+    /// Files that have changed since the previous backup.
+    pub changed_files: FileId,
+    /// Size of the content of changed files.
+    pub changed_bytes: u64,
+    /// Files that haven't changed, or are skipped by policy.
+    pub unchanged_files: FileId,
+    /// Files that policy excludes from backups, by size or kind.
+    pub excluded_files: FileId,
+    /// The problems encountered while going through files.
+    pub problems: Vec<Problem>,
+}
+
+impl BackupEstimate {
+    // Count a file that needs backing up for some reason.
+    fn count(&mut self, entry: &FilesystemEntry, reason: Reason) {
+        let bytes = if entry.kind().has_content() {
+            entry.len()
+        } else {
+            0
+        };
+        self.live_files += 1;
+        match reason {
+            Reason::IsNew | Reason::Renamed(_) => {
+                self.new_files += 1;
+                self.new_bytes += bytes;
+            }
+            Reason::Changed
+            | Reason::ContentChanged
+            | Reason::GenerationLookupError
+            | Reason::Unknown => {
+                self.changed_files += 1;
+                self.changed_bytes += bytes;
+            }
+            Reason::Skipped | Reason::Unchanged | Reason::FileError => {
+                self.unchanged_files += 1;
+            }
+            Reason::PolicyExcluded => {
+                self.excluded_files += 1;
+            }
+        }
+    }
 }
 
 /// The outcome of a backup run.
@@ -96,8 +312,8 @@ struct OneRootBackupOutcome {
 pub struct RootsBackupOutcome {
     /// The number of backed up files.
     pub files_count: FileId,
-    /// The errors encountered while backing up files.
-    pub warnings: Vec<BackupError>,
+    /// The problems encountered while backing up files.
+    pub problems: Vec<Problem>,
     /// CACHEDIR.TAG files that aren't present in in a previous generation.
     pub new_cachedir_tags: Vec<PathBuf>,
     /// Id of new generation.
@@ -106,33 +322,103 @@ pub struct RootsBackupOutcome {
 
 impl<'a> BackupRun<'a> {
     /// Create a new run for an initial backup.
+    ///
+    /// The run stops early, with an error, if `cancel` is cancelled.
     pub fn initial(
         config: &ClientConfig,
         client: &'a mut BackupClient,
+        cancel: CancellationToken,
     ) -> Result<Self, BackupError> {
         Ok(Self {
             checksum_kind: Some(DEFAULT_CHECKSUM_KIND),
             client,
-            policy: BackupPolicy::default(),
+            policy: BackupPolicy::new(&config.policy),
             buffer_size: config.chunk_size,
-            progress: Some(BackupProgress::initial()),
+            progress_bars: true,
+            progress: None,
+            sinks: vec![],
+            started: current_timestamp(),
+            tag: None,
+            max_uploads: config.max_concurrent_uploads,
+            cancel,
+            generation_upload: config.generation_upload,
+            padding: config.padding,
+            previous: None,
+            previous_segments: HashMap::new(),
+            dry_run: None,
+            excluded: config.obnam_files(),
         })
     }
 
     /// Create a new run for an incremental backup.
+    ///
+    /// The run stops early, with an error, if `cancel` is cancelled.
     pub fn incremental(
         config: &ClientConfig,
         client: &'a mut BackupClient,
+        cancel: CancellationToken,
     ) -> Result<Self, BackupError> {
         Ok(Self {
             checksum_kind: None,
             client,
-            policy: BackupPolicy::default(),
+            policy: BackupPolicy::new(&config.policy),
             buffer_size: config.chunk_size,
+            progress_bars: true,
             progress: None,
+            sinks: vec![],
+            started: current_timestamp(),
+            tag: None,
+            max_uploads: config.max_concurrent_uploads,
+            cancel,
+            generation_upload: config.generation_upload,
+            padding: config.padding,
+            previous: None,
+            previous_segments: HashMap::new(),
+            dry_run: None,
+            excluded: config.obnam_files(),
         })
     }
 
+    /// Leave a file or directory out of the backup, like Obnam's own
+    /// files.
+    pub fn exclude(&mut self, path: &Path) {
+        self.excluded.push(absolute(path));
+    }
+
+    /// Report progress events to a sink, in addition to the progress
+    /// bars on the terminal.
+    pub fn add_progress_sink(&mut self, sink: Box<dyn ProgressSink + 'a>) {
+        self.sinks.push(sink);
+    }
+
+    /// Split files into chunks, and label them, as the repository
+    /// settings say, instead of as the client's configuration says.
+    ///
+    /// An incremental backup keeps using the checksum kind of the
+    /// backup it's based on, so that its chunks are found. This must
+    /// be called before the run is started.
+    pub fn use_repository_settings(&mut self, settings: &RepositorySettings) {
+        self.buffer_size = settings.chunk_size();
+        if self.checksum_kind.is_some() {
+            self.checksum_kind = Some(settings.checksum_kind());
+        }
+    }
+
+    /// Record a tag in the new generation's metadata, to tell it
+    /// apart from others when listing them.
+    ///
+    /// This must be called before the run is started.
+    pub fn set_tag(&mut self, tag: &str) {
+        self.tag = Some(tag.to_string());
+    }
+
+    /// Don't draw progress bars on the terminal.
+    ///
+    /// This must be called before the run is started.
+    pub fn hide_progress_bars(&mut self) {
+        self.progress_bars = false;
+    }
+
     /// Start the backup run.
     pub async fn start(
         &mut self,
@@ -146,30 +432,73 @@ impl<'a> BackupRun<'a> {
                 let schema = schema_version(DEFAULT_SCHEMA_MAJOR).unwrap();
                 NascentGeneration::create(oldname, schema, self.checksum_kind.unwrap())?.close()?;
 
+                if self.progress_bars {
+                    self.progress = Some(BackupProgress::initial());
+                }
+
                 // Open the newly created empty generation.
                 Ok(LocalGeneration::open(oldname)?)
             }
             Some(genid) => {
-                perf.start(Clock::GenerationDownload);
+                let clock = perf.clock(Clock::GenerationDownload);
                 let old = self.fetch_previous_generation(genid, oldname).await?;
-                perf.stop(Clock::GenerationDownload);
+                drop(clock);
+                self.previous = Some(genid.clone());
 
                 let meta = old.meta()?;
                 if let Some(v) = meta.get("checksum_kind") {
                     self.checksum_kind = Some(LabelChecksumKind::from(v)?);
                 }
 
-                let progress = BackupProgress::incremental();
-                progress.files_in_previous_generation(old.file_count()? as u64);
-                self.progress = Some(progress);
+                if self.generation_upload == GenerationUpload::Delta {
+                    match self.previous_segments(genid, oldname).await {
+                        Ok(segments) => self.previous_segments = segments,
+                        Err(err) => warn!(
+                            "can't upload only changes to backup metadata, uploading all: {}",
+                            err
+                        ),
+                    }
+                }
+
+                if self.progress_bars {
+                    let progress = BackupProgress::incremental();
+                    progress.files_in_previous_generation(old.file_count()? as u64);
+                    progress.bytes_in_previous_generation(old.stats()?.total().bytes());
+                    self.progress = Some(progress);
+                }
 
                 Ok(old)
             }
         }
     }
 
-    fn checksum_kind(&self) -> LabelChecksumKind {
-        self.checksum_kind.unwrap_or(LabelChecksumKind::Sha256)
+    // Map labels of the segments of the previous backup's metadata to
+    // their chunk ids. The segments are found by splitting the
+    // metadata the same way the new backup's metadata will be split.
+    // If the previous backup's metadata wasn't uploaded that way,
+    // the map is empty.
+    async fn previous_segments(
+        &self,
+        genid: &GenId,
+        oldname: &Path,
+    ) -> Result<HashMap<String, ChunkId>, BackupError> {
+        let ids = self.client.generation_chunk_ids(genid).await?;
+        let file = std::fs::File::open(oldname)
+            .map_err(|err| ClientError::FileOpen(oldname.to_path_buf(), err))?;
+        let mut labels = vec![];
+        for chunk in FileChunks::new(DELTA_SEGMENT_SIZE, file, oldname, self.labeler()) {
+            labels.push(chunk?.meta().label().to_string());
+        }
+        if labels.len() != ids.len() {
+            info!("previous backup metadata was not uploaded in segments");
+            return Ok(HashMap::new());
+        }
+        Ok(labels.into_iter().zip(ids).collect())
+    }
+
+    fn labeler(&self) -> Labeler {
+        self.client
+            .labeler(self.checksum_kind.unwrap_or(LabelChecksumKind::Sha256))
     }
 
     async fn fetch_previous_generation(
@@ -177,9 +506,16 @@ impl<'a> BackupRun<'a> {
         genid: &GenId,
         oldname: &Path,
     ) -> Result<LocalGeneration, ObnamError> {
-        let progress = BackupProgress::download_generation(genid);
-        let old = self.client.fetch_generation(genid, oldname).await?;
-        progress.finish();
+        let progress = self
+            .progress_bars
+            .then(|| BackupProgress::download_generation(genid));
+        let old = self
+            .client
+            .fetch_generation(genid, oldname, &self.cancel)
+            .await?;
+        if let Some(progress) = progress {
+            progress.finish();
+        }
         Ok(old)
     }
 
@@ -199,99 +535,450 @@ impl<'a> BackupRun<'a> {
         schema: SchemaVersion,
         perf: &mut Performance,
     ) -> Result<RootsBackupOutcome, ObnamError> {
-        let mut warnings: Vec<BackupError> = vec![];
-        let mut new_cachedir_tags = vec![];
-        let files_count = {
-            let mut new = NascentGeneration::create(newpath, schema, self.checksum_kind.unwrap())?;
-            for root in &config.roots {
-                match self.backup_one_root(config, old, &mut new, root).await {
-                    Ok(mut o) => {
-                        new_cachedir_tags.append(&mut o.new_cachedir_tags);
-                        if !o.warnings.is_empty() {
-                            for err in o.warnings.iter() {
-                                debug!("ignoring backup error {}", err);
-                                self.found_problem();
-                            }
-                            warnings.append(&mut o.warnings);
-                        }
+        let new = self.create_nascent(old, Some(newpath), schema)?;
+        let (new, problems, new_cachedir_tags) = self.scan_roots(config, old, new).await?;
+        let files_count = new.file_count();
+        new.close()?;
+        self.finish();
+        let gen_id = self
+            .upload_new_generation(newpath, files_count, problems.len(), perf)
+            .await?;
+        Ok(RootsBackupOutcome {
+            files_count,
+            problems,
+            new_cachedir_tags,
+            gen_id,
+        })
+    }
+
+    /// Go through all the roots for this run as for a backup, but
+    /// upload nothing.
+    ///
+    /// The server is still asked which chunks it already has, and
+    /// the chunks it doesn't have are reported as progress as if they
+    /// were uploaded. The new generation is only built in memory.
+    pub async fn dry_run_roots(
+        &mut self,
+        config: &ClientConfig,
+        old: &LocalGeneration,
+        schema: SchemaVersion,
+    ) -> Result<DryRunOutcome, ObnamError> {
+        self.dry_run = Some(DryRun::default());
+        let new = self.create_nascent(old, None, schema)?;
+        let (new, problems, new_cachedir_tags) = self.scan_roots(config, old, new).await?;
+        let files_count = new.file_count();
+        new.finish()?;
+        self.finish();
+        Ok(DryRunOutcome {
+            files_count,
+            problems,
+            new_cachedir_tags,
+        })
+    }
+
+    /// Estimate how much a backup of all the roots for this run would
+    /// upload, without reading any file content.
+    ///
+    /// Backup roots are read directly, even if snapshots are
+    /// configured. Nothing is uploaded or downloaded.
+    pub fn estimate_roots(
+        &mut self,
+        config: &ClientConfig,
+        old: &LocalGeneration,
+    ) -> Result<BackupEstimate, ObnamError> {
+        let mut estimate = BackupEstimate::default();
+        for root in &config.roots {
+            let iter = FsIterator::new(
+                root,
+                config.cachedir_tags,
+                &config.cachedir_tag_allowlist,
+                &self.excluded,
+                config.one_file_system,
+                config.follow_symlinks,
+            );
+            for (i, entry) in iter.enumerate() {
+                if self.cancel.is_cancelled() {
+                    self.finish();
+                    return Err(BackupError::Cancelled.into());
+                }
+                match entry {
+                    // Only the backup root itself failing is an error.
+                    Err(err) if i == 0 => {
+                        self.finish();
+                        return Err(NascentError::BackupRootFailed(root.to_path_buf(), err).into());
                     }
                     Err(err) => {
-                        self.found_problem();
-                        return Err(err.into());
+                        let problem = self.found_problem(&err.into());
+                        estimate.problems.push(problem);
+                    }
+                    Ok(entry) => {
+                        self.found_live_file(&entry.inner.pathbuf());
+                        let reason = self.policy.needs_backup(old, &entry.inner);
+                        estimate.count(&entry.inner, reason);
                     }
                 }
             }
+        }
+        self.finish();
+        Ok(estimate)
+    }
+
+    // Back up all the roots into a new generation, and record its
+    // metadata. Return the generation, any problems, and any new
+    // CACHEDIR.TAG files.
+    async fn scan_roots(
+        &mut self,
+        config: &ClientConfig,
+        old: &LocalGeneration,
+        mut new: NascentGeneration,
+    ) -> Result<(NascentGeneration, Vec<Problem>, Vec<PathBuf>), ObnamError> {
+        let mut problems = vec![];
+        let mut new_cachedir_tags = vec![];
+        for root in &config.roots {
+            match self.backup_one_root(config, old, &mut new, root).await {
+                Ok(o) if o.cancelled => {
+                    // Leave the new generation in a consistent
+                    // state, even though it won't be uploaded.
+                    info!("backup cancelled while backing up {}", root.display());
+                    let count = new.file_count();
+                    new.close()?;
+                    self.finish();
+                    return Err(BackupError::CancelledAfter(count).into());
+                }
+                Ok(mut o) => {
+                    new_cachedir_tags.append(&mut o.new_cachedir_tags);
+                    for err in o.warnings.iter() {
+                        debug!("ignoring backup error {}", err);
+                        problems.push(self.found_problem(err));
+                    }
+                }
+                Err(err) => {
+                    self.found_problem(&err);
+                    return Err(err.into());
+                }
+            }
+        }
+        // Streams aren't in the file system, but they're not
+        // deleted either.
+        new.keep_from(old, |e| e.kind() == FilesystemKind::Stream)?;
+        if config.detect_renames {
+            let renamed = new.detect_renames(old)?;
+            info!("{} files were renamed since the previous backup", renamed);
+        }
+        new.record_deletions(old)?;
+        self.record_meta(&mut new, problems.len())?;
+        Ok((new, problems, new_cachedir_tags))
+    }
+
+    /// Back up data read from `reader` as a stream with a given
+    /// name. The new generation also has all the files of the
+    /// previous one, except one with the same name.
+    pub async fn backup_stream<R: Read>(
+        &mut self,
+        old: &LocalGeneration,
+        newpath: &Path,
+        schema: SchemaVersion,
+        name: &Path,
+        reader: R,
+        perf: &mut Performance,
+    ) -> Result<RootsBackupOutcome, ObnamError> {
+        // The name is used as a path when restoring, so it mustn't
+        // point outside the directory restored to.
+        if name.as_os_str().is_empty() || name.components().any(|c| c == Component::ParentDir) {
+            return Err(BackupError::BadStreamName(name.to_path_buf()).into());
+        }
+
+        info!("backup stream: {}", name.display());
+        self.found_live_file(name);
+        let chunker = FileChunks::new(self.buffer_size, reader, name, self.labeler());
+        let (ids, lengths, labels) = self.upload_chunks(chunker).await?;
+        let len = lengths.iter().sum();
+
+        let now = Local::now();
+        let mut cache = UsersCache::new();
+        let entry = EntryBuilder::new(FilesystemKind::Stream)
+            .path(name.to_path_buf())
+            .len(len)
+            .mode(0o600)
+            .mtime(now.timestamp(), now.timestamp_subsec_nanos().into())
+            .atime(now.timestamp(), now.timestamp_subsec_nanos().into())
+            .user(users::get_current_uid(), &mut cache)
+            .map_err(BackupError::from)?
+            .group(users::get_current_gid(), &mut cache)
+            .map_err(BackupError::from)?
+            .build();
+
+        let files_count = {
+            let mut new = self.create_nascent(old, Some(newpath), schema)?;
+            new.insert_with_error(entry, &ids, &lengths, &labels, Reason::IsNew, false, None)?;
+            new.keep_from(old, |_| true)?;
             let count = new.file_count();
+            self.record_meta(&mut new, 0)?;
             new.close()?;
             count
         };
         self.finish();
-        perf.start(Clock::GenerationUpload);
-        let gen_id = self.upload_nascent_generation(newpath).await?;
-        perf.stop(Clock::GenerationUpload);
-        let gen_id = GenId::from_chunk_id(gen_id);
+        let gen_id = self
+            .upload_new_generation(newpath, files_count, 0, perf)
+            .await?;
         Ok(RootsBackupOutcome {
             files_count,
-            warnings,
-            new_cachedir_tags,
+            problems: vec![],
+            new_cachedir_tags: vec![],
             gen_id,
         })
     }
 
+    // Create the new generation, in a file, or in memory if there's
+    // no file name. If the schema allows, only changes since the
+    // previous generation are stored.
+    fn create_nascent(
+        &self,
+        old: &LocalGeneration,
+        newpath: Option<&Path>,
+        schema: SchemaVersion,
+    ) -> Result<NascentGeneration, NascentError> {
+        let checksum_kind = self.checksum_kind.unwrap();
+        match &self.previous {
+            Some(parent_id)
+                if schema.major == INCREMENTAL_SCHEMA_MAJOR
+                    && old.chain_length() < MAX_CHAIN_LENGTH =>
+            {
+                info!("storing only changes since generation {}", parent_id);
+                match newpath {
+                    Some(newpath) => NascentGeneration::create_incremental(
+                        newpath,
+                        schema,
+                        checksum_kind,
+                        parent_id,
+                        old,
+                    ),
+                    None => NascentGeneration::in_memory_incremental(
+                        schema,
+                        checksum_kind,
+                        parent_id,
+                        old,
+                    ),
+                }
+            }
+            _ => match newpath {
+                Some(newpath) => NascentGeneration::create(newpath, schema, checksum_kind),
+                None => NascentGeneration::in_memory(schema, checksum_kind),
+            },
+        }
+    }
+
+    // Upload the new generation, and report that the backup has
+    // finished.
+    async fn upload_new_generation(
+        &mut self,
+        newpath: &Path,
+        files_count: FileId,
+        warning_count: usize,
+        perf: &mut Performance,
+    ) -> Result<GenId, ObnamError> {
+        let clock = perf.clock(Clock::GenerationUpload);
+        let gen_id = self.upload_nascent_generation(newpath).await?;
+        drop(clock);
+        if let Some(uploads) = self.client.upload_concurrency() {
+            perf.upload_concurrency(&uploads);
+        }
+        let gen_id = GenId::from_chunk_id(gen_id);
+        self.client.cache_generation(&gen_id, newpath);
+        self.emit(&ProgressEvent::Finished {
+            generation_id: gen_id.to_string(),
+            files: files_count as u64,
+            warnings: warning_count as u64,
+        });
+        Ok(gen_id)
+    }
+
+    fn record_meta(
+        &self,
+        new: &mut NascentGeneration,
+        warning_count: usize,
+    ) -> Result<(), NascentError> {
+        new.set_meta(genmeta::HOSTNAME, &hostname())?;
+        if let Some(username) = users::get_current_username() {
+            new.set_meta(genmeta::USERNAME, &username.to_string_lossy())?;
+        }
+        new.set_meta(genmeta::CLIENT_VERSION, env!("CARGO_PKG_VERSION"))?;
+        new.set_meta(genmeta::STARTED, &self.started)?;
+        new.set_meta(genmeta::ENDED, &current_timestamp())?;
+        if let Some(tag) = &self.tag {
+            new.set_meta(genmeta::TAG, tag)?;
+        }
+        new.set_meta(genmeta::FILE_COUNT, &format!("{}", new.file_count()))?;
+        new.set_meta(genmeta::FILE_BYTES, &format!("{}", new.file_bytes()))?;
+        new.set_meta(genmeta::WARNING_COUNT, &format!("{}", warning_count))?;
+        let mut features = Feature::in_use();
+        if new.has_streams() {
+            features.push(Feature::Streams);
+        }
+        if self.padding == Padding::Padme {
+            features.push(Feature::Padme);
+        }
+        match self.checksum_kind {
+            Some(LabelChecksumKind::Blake3) => features.push(Feature::Blake3),
+            Some(LabelChecksumKind::Keyed) => {
+                features.push(Feature::Blake3);
+                features.push(Feature::KeyedLabels);
+            }
+            _ => (),
+        }
+        if new.schema().major == INCREMENTAL_SCHEMA_MAJOR {
+            features.push(Feature::IncrementalSchema);
+        }
+        new.set_meta(genmeta::FEATURES, &Feature::serialize_list(&features))?;
+        Ok(())
+    }
+
     async fn backup_one_root(
         &mut self,
         config: &ClientConfig,
         old: &LocalGeneration,
         new: &mut NascentGeneration,
         root: &Path,
-    ) -> Result<OneRootBackupOutcome, NascentError> {
+    ) -> Result<OneRootBackupOutcome, BackupError> {
         let mut warnings: Vec<BackupError> = vec![];
+        let mut aborted = None;
         let mut new_cachedir_tags = vec![];
-        let iter = FsIterator::new(root, config.exclude_cache_tag_directories);
+
+        // If configured, back up from a snapshot of the root, but
+        // record files using their original paths.
+        let snapshot = match &config.snapshot {
+            None => None,
+            Some(snapshot_config) => Some(
+                Snapshot::create(snapshot_config, root)
+                    .map_err(|err| NascentError::Snapshot(root.to_path_buf(), err))?,
+            ),
+        };
+        let (live_root, cachedir_allowlist, excluded) = match &snapshot {
+            None => (
+                root.to_path_buf(),
+                config.cachedir_tag_allowlist.clone(),
+                self.excluded.clone(),
+            ),
+            Some(snapshot) => (
+                snapshot.path().to_path_buf(),
+                config
+                    .cachedir_tag_allowlist
+                    .iter()
+                    .map(|path| snapshot.live(path))
+                    .collect(),
+                self.excluded
+                    .iter()
+                    .map(|path| snapshot.live(path))
+                    .collect(),
+            ),
+        };
+
+        let iter = FsIterator::new(
+            &live_root,
+            config.cachedir_tags,
+            &cachedir_allowlist,
+            &excluded,
+            config.one_file_system,
+            config.follow_symlinks,
+        );
         let mut first_entry = true;
         for entry in iter {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            let mut errors: Vec<BackupError> = vec![];
             match entry {
                 Err(err) => {
                     if first_entry {
                         // Only the first entry (the backup root)
                         // failing is an error. Everything else is a
                         // warning.
-                        return Err(NascentError::BackupRootFailed(root.to_path_buf(), err));
+                        return Err(NascentError::BackupRootFailed(root.to_path_buf(), err).into());
                     }
-                    warnings.push(err.into());
+                    errors.push(err.into());
                 }
-                Ok(entry) => {
+                Ok(mut entry) => {
+                    let live = entry.inner.pathbuf();
+                    if let Some(snapshot) = &snapshot {
+                        entry.inner = entry.inner.with_path(&snapshot.original(&live));
+                    }
                     let path = entry.inner.pathbuf();
                     if entry.is_cachedir_tag && !old.is_cachedir_tag(&path)? {
                         new_cachedir_tags.push(path);
                     }
-                    match self.backup_if_needed(entry, old).await {
+                    match self.backup_if_needed(entry, &live, old).await {
                         Err(err) => {
-                            warnings.push(err);
+                            errors.push(err);
                         }
                         Ok(None) => (),
-                        Ok(Some(o)) => {
-                            if let Err(err) =
-                                new.insert(o.entry, &o.ids, o.reason, o.is_cachedir_tag)
-                            {
-                                warnings.push(err.into());
+                        Ok(Some(mut o)) => {
+                            if let Some(err) = o.error.take() {
+                                errors.push(err);
+                            }
+                            if let Err(err) = new.insert_or_keep(
+                                old,
+                                o.entry,
+                                &o.ids,
+                                &o.lengths,
+                                &o.labels,
+                                o.reason,
+                                o.is_cachedir_tag,
+                                o.error_message.as_deref(),
+                            ) {
+                                errors.push(err.into());
                             }
                         }
                     }
                 }
             }
             first_entry = false;
+
+            // Policy says which errors matter, based on the file's
+            // original path, not its path in a snapshot.
+            for err in errors {
+                let path = err.problem().path.map(|path| match &snapshot {
+                    Some(snapshot) => snapshot.original(&path),
+                    None => path,
+                });
+                match path.as_ref().map(|path| self.policy.on_error(path)) {
+                    Some(ErrorPolicy::Skip) => info!("skipping error, as policy allows: {}", err),
+                    Some(ErrorPolicy::Abort) => {
+                        aborted = path.map(|path| BackupError::Aborted(path, Box::new(err)));
+                        break;
+                    }
+                    Some(ErrorPolicy::Warn) | None => warnings.push(err),
+                }
+            }
+            if aborted.is_some() {
+                break;
+            }
+        }
+
+        // The last file may have been cut short, even if there are no
+        // more files.
+        let cancelled = self.cancel.is_cancelled();
+
+        if let Some(snapshot) = snapshot {
+            if let Err(err) = snapshot.remove() {
+                warnings.push(err.into());
+            }
+        }
+
+        if let Some(err) = aborted {
+            return Err(err);
         }
 
         Ok(OneRootBackupOutcome {
             warnings,
             new_cachedir_tags,
+            cancelled,
         })
     }
 
     async fn backup_if_needed(
         &mut self,
         entry: AnnotatedFsEntry,
+        live: &Path,
         old: &LocalGeneration,
     ) -> Result<Option<FsEntryBackupOutcome>, BackupError> {
         let path = &entry.inner.pathbuf();
@@ -299,76 +986,156 @@ impl<'a> BackupRun<'a> {
         self.found_live_file(path);
         let reason = self.policy.needs_backup(old, &entry.inner);
         match reason {
-            Reason::IsNew | Reason::Changed | Reason::GenerationLookupError | Reason::Unknown => {
-                Ok(Some(self.backup_one_entry(&entry, path, reason).await))
+            Reason::IsNew
+            | Reason::Renamed(_)
+            | Reason::Changed
+            | Reason::ContentChanged
+            | Reason::GenerationLookupError
+            | Reason::Unknown => Ok(Some(self.backup_one_entry(&entry, live, reason).await)),
+            Reason::Skipped => {
+                self.unchanged_bytes(&entry.inner);
+                Ok(None)
+            }
+            Reason::PolicyExcluded => {
+                info!("excluded by policy: {}", path.display());
+                Ok(None)
             }
-            Reason::Skipped => Ok(None),
             Reason::Unchanged | Reason::FileError => {
                 let fileno = old.get_fileno(&entry.inner.pathbuf())?;
-                let ids = if let Some(fileno) = fileno {
-                    let mut ids = vec![];
-                    for id in old.chunkids(fileno)?.iter()? {
-                        ids.push(id?);
-                    }
-                    ids
+                let (ids, lengths, labels) = if let Some(fileno) = fileno {
+                    let (ids, lengths) = old.chunk_ids_and_lengths(fileno)?;
+                    (ids, lengths, old.chunk_labels(fileno)?)
                 } else {
-                    vec![]
+                    (vec![], vec![], vec![])
                 };
+                if matches!(reason, Reason::Unchanged)
+                    && entry.inner.kind() == FilesystemKind::Regular
+                    && self.policy.check_content(path)
+                    && self.content_has_changed(live, &ids, &labels)
+                {
+                    info!("content has changed: {}", path.display());
+                    return Ok(Some(
+                        self.backup_one_entry(&entry, live, Reason::ContentChanged)
+                            .await,
+                    ));
+                }
+                let error_message = match reason {
+                    Reason::FileError => old.file_error(path)?,
+                    _ => None,
+                };
+                self.unchanged_bytes(&entry.inner);
                 Ok(Some(FsEntryBackupOutcome {
                     entry: entry.inner,
                     ids,
+                    lengths,
+                    labels,
                     reason,
                     is_cachedir_tag: entry.is_cachedir_tag,
+                    error: None,
+                    error_message,
                 }))
             }
         }
     }
 
+    // Does the content of a file differ from the chunks it had in the
+    // previous backup? The file is split into chunks again, and their
+    // labels are compared to those recorded in the previous backup.
+    // The labels aren't recorded by older versions of Obnam, and then
+    // the file is assumed to have changed, as it is if the check
+    // fails. Backing it up again only uploads chunks that have
+    // actually changed.
+    fn content_has_changed(&self, path: &Path, old_ids: &[ChunkId], old_labels: &[String]) -> bool {
+        if old_labels.len() != old_ids.len() {
+            return true;
+        }
+        match self.live_chunk_labels(path) {
+            Ok(labels) => labels != old_labels,
+            Err(err) => {
+                warn!("failed to check content of {}: {}", path.display(), err);
+                true
+            }
+        }
+    }
+
+    // Labels of the chunks of a file's current content.
+    fn live_chunk_labels(&self, path: &Path) -> Result<Vec<String>, BackupError> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| ClientError::FileOpen(path.to_path_buf(), err))?;
+        let chunker = FileChunks::new(self.buffer_size, file, path, self.labeler());
+        let mut labels = vec![];
+        for item in chunker {
+            labels.push(item?.meta().label().to_string());
+        }
+        Ok(labels)
+    }
+
     async fn backup_one_entry(
         &mut self,
         entry: &AnnotatedFsEntry,
-        path: &Path,
+        live: &Path,
         reason: Reason,
     ) -> FsEntryBackupOutcome {
-        let ids = self
-            .upload_filesystem_entry(&entry.inner, self.buffer_size)
+        let chunks = self
+            .upload_entry_from(&entry.inner, live, self.buffer_size)
             .await;
-        match ids {
+        match chunks {
             Err(err) => {
-                warn!("error backing up {}, skipping it: {}", path.display(), err);
+                warn!("error backing up {}, skipping it: {}", live.display(), err);
                 FsEntryBackupOutcome {
                     entry: entry.inner.clone(),
                     ids: vec![],
+                    lengths: vec![],
+                    labels: vec![],
                     reason: Reason::FileError,
                     is_cachedir_tag: entry.is_cachedir_tag,
+                    error_message: Some(err.to_string()),
+                    error: Some(err),
                 }
             }
-            Ok(ids) => FsEntryBackupOutcome {
+            Ok((ids, lengths, labels)) => FsEntryBackupOutcome {
                 entry: entry.inner.clone(),
                 ids,
+                lengths,
+                labels,
                 reason,
                 is_cachedir_tag: entry.is_cachedir_tag,
+                error: None,
+                error_message: None,
             },
         }
     }
 
-    /// Upload any file content for a file system entry.
+    /// Upload any file content for a file system entry. Return the
+    /// ids of its chunks, and their lengths and labels.
     pub async fn upload_filesystem_entry(
         &mut self,
         e: &FilesystemEntry,
         size: usize,
-    ) -> Result<Vec<ChunkId>, BackupError> {
-        let path = e.pathbuf();
+    ) -> Result<(Vec<ChunkId>, Vec<u64>, Vec<String>), BackupError> {
+        self.upload_entry_from(e, &e.pathbuf(), size).await
+    }
+
+    // Upload any file content for a file system entry, reading it
+    // from a given path, which may be in a snapshot.
+    async fn upload_entry_from(
+        &mut self,
+        e: &FilesystemEntry,
+        path: &Path,
+        size: usize,
+    ) -> Result<(Vec<ChunkId>, Vec<u64>, Vec<String>), BackupError> {
         info!("uploading {:?}", path);
-        let ids = match e.kind() {
-            FilesystemKind::Regular => self.upload_regular_file(&path, size).await?,
-            FilesystemKind::Directory => vec![],
-            FilesystemKind::Symlink => vec![],
-            FilesystemKind::Socket => vec![],
-            FilesystemKind::Fifo => vec![],
+        let chunks = match e.kind() {
+            FilesystemKind::Regular => self.upload_regular_file(path, size).await?,
+            FilesystemKind::Directory => (vec![], vec![], vec![]),
+            FilesystemKind::Symlink => (vec![], vec![], vec![]),
+            FilesystemKind::Socket => (vec![], vec![], vec![]),
+            FilesystemKind::Fifo => (vec![], vec![], vec![]),
+            // A stream can't be read again from the file system.
+            FilesystemKind::Stream => (vec![], vec![], vec![]),
         };
         info!("upload OK for {:?}", path);
-        Ok(ids)
+        Ok(chunks)
     }
 
     /// Upload the metadata for the backup of this run.
@@ -378,7 +1145,7 @@ impl<'a> BackupRun<'a> {
         size: usize,
     ) -> Result<ChunkId, BackupError> {
         info!("upload SQLite {}", filename.display());
-        let ids = self.upload_regular_file(filename, size).await?;
+        let (ids, _, _) = self.upload_regular_file(filename, size).await?;
         let gen = GenerationChunk::new(ids);
         let data = gen.to_data_chunk()?;
         let gen_id = self.client.upload_chunk(data).await?;
@@ -390,44 +1157,182 @@ impl<'a> BackupRun<'a> {
         &mut self,
         filename: &Path,
         size: usize,
-    ) -> Result<Vec<ChunkId>, BackupError> {
+    ) -> Result<(Vec<ChunkId>, Vec<u64>, Vec<String>), BackupError> {
         info!("upload file {}", filename.display());
-        let mut chunk_ids = vec![];
         let file = std::fs::File::open(filename)
             .map_err(|err| ClientError::FileOpen(filename.to_path_buf(), err))?;
-        let chunker = FileChunks::new(size, file, filename, self.checksum_kind());
-        for item in chunker {
-            let chunk = item?;
-            if let Some(chunk_id) = self.client.has_chunk(chunk.meta()).await? {
-                chunk_ids.push(chunk_id.clone());
-                info!("reusing existing chunk {}", chunk_id);
-            } else {
-                let chunk_id = self.client.upload_chunk(chunk).await?;
-                chunk_ids.push(chunk_id.clone());
-                info!("created new chunk {}", chunk_id);
+        let chunker = FileChunks::new(size, file, filename, self.labeler());
+        self.upload_chunks(chunker).await
+    }
+
+    // Upload all the chunks from a chunker. Return their ids, the
+    // number of bytes in each, and their labels.
+    async fn upload_chunks<R: Read>(
+        &mut self,
+        mut chunker: FileChunks<R>,
+    ) -> Result<(Vec<ChunkId>, Vec<u64>, Vec<String>), BackupError> {
+        let mut chunk_ids = vec![];
+        let mut lengths = vec![];
+        let mut chunk_labels = vec![];
+
+        // Upload chunks concurrently, but keep their order. The
+        // client lets the number of uploads in flight adapt to the
+        // network, up to the number of chunks handled at once here.
+        // Identical chunks are not uploaded concurrently, so that the
+        // later one can re-use the earlier one. Chunks are read in
+        // batches, so that the server can be asked about a whole batch
+        // at once if it has them already.
+        let client = &*self.client;
+        let dry_run = self.dry_run.as_ref();
+        let sinks = progress_sinks(&self.progress, &self.sinks);
+        let mut pending = FuturesOrdered::new();
+        let mut labels = VecDeque::new();
+        loop {
+            let mut batch = vec![];
+            for item in chunker.by_ref().take(EXISTS_BATCH) {
+                batch.push(item?);
+            }
+            if batch.is_empty() {
+                break;
+            }
+            let metas: Vec<&ChunkMeta> = batch.iter().map(|chunk| chunk.meta()).collect();
+            client.chunks_exist(&metas).await?;
+
+            for chunk in batch {
+                if self.cancel.is_cancelled() {
+                    return Err(BackupError::Cancelled);
+                }
+                lengths.push(chunk.data().len() as u64);
+                let label = chunk.meta().label().to_string();
+                chunk_labels.push(label.clone());
+                while labels.contains(&label) || pending.len() >= self.max_uploads {
+                    if let Some(result) = pending.next().await {
+                        labels.pop_front();
+                        chunk_ids.push(result?);
+                    }
+                }
+                let known = self
+                    .previous_segments
+                    .get(&label)
+                    .cloned()
+                    .or_else(|| dry_run.and_then(|dry_run| dry_run.chunk(&label)));
+                labels.push_back(label);
+                pending.push_back(upload_chunk(client, &sinks, chunk, known, dry_run));
             }
         }
-        Ok(chunk_ids)
+        while let Some(result) = pending.next().await {
+            chunk_ids.push(result?);
+        }
+        Ok((chunk_ids, lengths, chunk_labels))
     }
 
     async fn upload_nascent_generation(&mut self, filename: &Path) -> Result<ChunkId, ObnamError> {
-        let progress = BackupProgress::upload_generation();
-        let gen_id = self.upload_generation(filename, SQLITE_CHUNK_SIZE).await?;
-        progress.finish();
+        let progress = self.progress_bars.then(BackupProgress::upload_generation);
+        let size = match self.generation_upload {
+            GenerationUpload::Whole => SQLITE_CHUNK_SIZE,
+            GenerationUpload::Delta => DELTA_SEGMENT_SIZE,
+        };
+        let gen_id = self.upload_generation(filename, size).await?;
+        if let Some(progress) = progress {
+            progress.finish();
+        }
         Ok(gen_id)
     }
 
-    fn found_live_file(&self, path: &Path) {
-        if let Some(progress) = &self.progress {
-            progress.found_live_file(path);
+    fn emit(&self, event: &ProgressEvent) {
+        for sink in progress_sinks(&self.progress, &self.sinks) {
+            sink.event(event);
         }
     }
 
-    fn found_problem(&self) {
-        if let Some(progress) = &self.progress {
-            progress.found_problem();
+    fn found_live_file(&self, path: &Path) {
+        self.emit(&ProgressEvent::file_started(path));
+    }
+
+    fn unchanged_bytes(&self, e: &FilesystemEntry) {
+        let bytes = if e.kind().has_content() { e.len() } else { 0 };
+        self.emit(&ProgressEvent::file_unchanged(&e.pathbuf(), bytes));
+    }
+
+    // Report a problem that doesn't stop the backup, and return it.
+    fn found_problem(&self, err: &BackupError) -> Problem {
+        let problem = err.problem();
+        self.emit(&ProgressEvent::warning(&problem));
+        problem
+    }
+}
+
+// All the places progress is reported to.
+fn progress_sinks<'a, 'b>(
+    progress: &'a Option<BackupProgress>,
+    sinks: &'a [Box<dyn ProgressSink + 'b>],
+) -> Vec<&'a dyn ProgressSink> {
+    let mut all: Vec<&dyn ProgressSink> = vec![];
+    if let Some(progress) = progress {
+        all.push(progress);
+    }
+    all.extend(sinks.iter().map(|s| s.as_ref()));
+    all
+}
+
+// Upload a chunk, unless the server already has it. If the chunk is
+// already known to be on the server, the server isn't even asked. In
+// a dry run, nothing is uploaded.
+async fn upload_chunk(
+    client: &BackupClient,
+    sinks: &[&dyn ProgressSink],
+    chunk: DataChunk,
+    known: Option<ChunkId>,
+    dry_run: Option<&DryRun>,
+) -> Result<ChunkId, ClientError> {
+    let size = chunk.data().len() as u64;
+    let found = match known {
+        Some(chunk_id) => Ok(Some(chunk_id)),
+        None => client.has_chunk(chunk.meta()).await,
+    };
+    match found {
+        Ok(Some(chunk_id)) => {
+            info!("reusing existing chunk {}", chunk_id);
+            chunk_uploaded(sinks, &chunk_id, size, true);
+            Ok(chunk_id)
         }
+        Ok(None) => match dry_run {
+            Some(dry_run) => {
+                let chunk_id = dry_run.upload(&chunk);
+                info!("would create new chunk {}", chunk.meta().label());
+                chunk_uploaded(sinks, &chunk_id, size, false);
+                Ok(chunk_id)
+            }
+            None => client.upload_chunk(chunk).await.map(|chunk_id| {
+                info!("created new chunk {}", chunk_id);
+                chunk_uploaded(sinks, &chunk_id, size, false);
+                chunk_id
+            }),
+        },
+        Err(err) => Err(err),
+    }
+}
+
+fn chunk_uploaded(sinks: &[&dyn ProgressSink], chunk_id: &ChunkId, bytes: u64, reused: bool) {
+    let event = ProgressEvent::ChunkUploaded {
+        id: chunk_id.to_string(),
+        bytes,
+        reused,
+    };
+    for sink in sinks {
+        sink.event(&event);
+    }
+}
+
+// Name of the host we're running on.
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret == -1 {
+        return "".to_string();
     }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
 }
 
 /// Current timestamp as an ISO 8601 string.
@@ -435,3 +1340,142 @@ pub fn current_timestamp() -> String {
     let now: DateTime<Local> = Local::now();
     format!("{}", now.format("%Y-%m-%d %H:%M:%S.%f %z"))
 }
+
+#[cfg(test)]
+mod test {
+    use crate::api::BackupReport;
+    use crate::error::{ErrorKind, ObnamError, EXIT_CANCELLED};
+    use crate::generation::GenId;
+    use crate::genmeta::Feature;
+    use crate::progress_sink::{ProgressEvent, ProgressSink};
+    use crate::testing::TestRepo;
+    use std::collections::HashSet;
+    use tempfile::{tempdir, NamedTempFile};
+    use tokio_util::sync::CancellationToken;
+
+    // Cancel the backup when it starts backing up a file.
+    struct CancelOnFile(CancellationToken);
+
+    impl ProgressSink for CancelOnFile {
+        fn event(&self, event: &ProgressEvent) {
+            if let ProgressEvent::FileStarted { .. } = event {
+                self.0.cancel();
+            }
+        }
+    }
+
+    async fn generations(repo: &TestRepo) -> Vec<GenId> {
+        let client = repo.client();
+        let trust = client.get_client_trust().await.unwrap();
+        client
+            .list_generations(&trust)
+            .iter()
+            .map(|gen| gen.id().clone())
+            .collect()
+    }
+
+    fn assert_cancelled(result: Result<BackupReport, ObnamError>) {
+        let err = result.unwrap_err();
+        assert!(err.is_cancelled(), "not cancelled: {}", err);
+        assert_eq!(err.kind(), ErrorKind::Cancelled);
+        assert_eq!(err.kind().exit_code(), EXIT_CANCELLED);
+        assert_eq!(EXIT_CANCELLED, 130);
+    }
+
+    #[tokio::test]
+    async fn backup_cancelled_before_it_starts_does_nothing() {
+        let repo = TestRepo::new();
+        std::fs::write(repo.live().join("data"), "hello").unwrap();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        assert_cancelled(repo.backup_with(cancel, vec![]).await);
+        assert!(generations(&repo).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn backup_cancelled_while_running_leaves_no_generation() {
+        let repo = TestRepo::new();
+        std::fs::write(repo.live().join("data"), "hello").unwrap();
+        let first = repo.backup().await.unwrap();
+
+        std::fs::write(repo.live().join("more"), "world").unwrap();
+        let cancel = CancellationToken::new();
+        let sink = Box::new(CancelOnFile(cancel.clone()));
+        assert_cancelled(repo.backup_with(cancel, vec![sink]).await);
+        assert_eq!(generations(&repo).await, vec![first.generation_id]);
+    }
+
+    #[tokio::test]
+    async fn records_features_in_use() {
+        let repo = TestRepo::with_settings("padding: padme\n");
+        std::fs::write(repo.live().join("data"), "hello").unwrap();
+        let backup = repo.backup().await.unwrap();
+
+        let temp = NamedTempFile::new().unwrap();
+        let gen = repo
+            .client()
+            .fetch_generation(
+                &backup.generation_id,
+                temp.path(),
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let features = gen.meta().unwrap().features();
+        assert!(features.contains(&Feature::IdBoundChunks));
+        assert!(features.contains(&Feature::Padme));
+        assert!(!features.contains(&Feature::KeyedLabels));
+        assert!(gen.check_features().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn delta_upload_reuses_unchanged_segments() {
+        let repo = TestRepo::with_settings("generation_upload: delta\n");
+        let live = repo.live();
+        for i in 0..1000 {
+            let name = format!("file-with-a-fairly-long-name-{:04}", i);
+            std::fs::write(live.join(name), format!("content {}", i)).unwrap();
+        }
+        // The first backup records every file as new, and the second
+        // as unchanged, so compare the second and third backups.
+        repo.backup().await.unwrap();
+        let first = repo.backup().await.unwrap();
+        std::fs::write(live.join("file-with-a-fairly-long-name-0500"), "changed").unwrap();
+        let second = repo.backup().await.unwrap();
+        assert!(second.is_incremental);
+
+        let client = repo.client();
+        let old: HashSet<_> = client
+            .generation_chunk_ids(&first.generation_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+        let new = client
+            .generation_chunk_ids(&second.generation_id)
+            .await
+            .unwrap();
+        let reused = new.iter().filter(|id| old.contains(id)).count();
+        assert!(new.len() > 4);
+        assert!(
+            reused * 2 > new.len(),
+            "only {} of {} segments reused",
+            reused,
+            new.len()
+        );
+
+        let restored = tempdir().unwrap();
+        repo.restore("latest", restored.path(), false)
+            .await
+            .unwrap();
+        let root = restored.path().join(live.strip_prefix("/").unwrap());
+        assert_eq!(
+            std::fs::read(root.join("file-with-a-fairly-long-name-0500")).unwrap(),
+            b"changed"
+        );
+        assert_eq!(
+            std::fs::read(root.join("file-with-a-fairly-long-name-0999")).unwrap(),
+            b"content 999"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Measure how fast a generation-like database is written and read.
+//!
+//! This creates a database with a table like the files table of a
+//! backup generation, inserts rows for many files into it, and then
+//! looks up each of them, with both SQLite's default settings and the
+//! tuning Obnam uses.
+
+use clap::Parser;
+use obnam::db::{Column, Database, DbInt, Query, Table, Tuning, Value};
+use std::time::Instant;
+use tempfile::tempdir;
+
+#[derive(Debug, Parser)]
+struct Opt {
+    /// Number of files to insert.
+    #[clap(long, default_value = "1000000")]
+    files: DbInt,
+
+    /// Number of files to look up after inserting.
+    #[clap(long, default_value = "10000")]
+    lookups: DbInt,
+}
+
+fn main() -> anyhow::Result<()> {
+    let opt = Opt::parse();
+    println!("files: {}", opt.files);
+    println!("lookups: {}", opt.lookups);
+    for (name, create, open) in [
+        ("sqlite-defaults", Tuning::default(), Tuning::default()),
+        ("obnam", Tuning::bulk_insert(), Tuning::read_only()),
+    ] {
+        let (insert, lookup) = benchmark(&opt, &create, &open)?;
+        println!("{}: insert {:.3} s, lookup {:.3} s", name, insert, lookup);
+    }
+    Ok(())
+}
+
+fn benchmark(opt: &Opt, create: &Tuning, open: &Tuning) -> anyhow::Result<(f64, f64)> {
+    let tmp = tempdir()?;
+    let filename = tmp.path().join("gen.db");
+    let table = Table::new("files")
+        .column(Column::primary_key("fileno"))
+        .column(Column::blob("filename"))
+        .column(Column::text("json"))
+        .column(Column::text("reason"))
+        .column(Column::bool("is_cachedir_tag"))
+        .build();
+
+    let started = Instant::now();
+    let mut db = Database::create_with(&filename, create)?;
+    db.create_table(&table)?;
+    db.create_index("filenames", &table, "filename")?;
+    for fileno in 0..opt.files {
+        let name = path(fileno);
+        let json = format!(r#"{{"kind":"Regular","len":{},"mode":33188}}"#, fileno);
+        db.insert(
+            &table,
+            &[
+                Value::primary_key("fileno", fileno),
+                Value::blob("filename", name.as_bytes()),
+                Value::text("json", &json),
+                Value::text("reason", "new"),
+                Value::bool("is_cachedir_tag", false),
+            ],
+        )?;
+    }
+    db.close()?;
+    let insert = started.elapsed().as_secs_f64();
+
+    let started = Instant::now();
+    let db = Database::open_with(&filename, open)?;
+    let step = (opt.files / opt.lookups.max(1)).max(1);
+    for fileno in (0..opt.files).step_by(step as usize) {
+        let name = path(fileno);
+        let query = Query::new().with(Value::blob("filename", name.as_bytes()));
+        let mut rows = db.query_rows(&table, &query, &get_fileno)?;
+        assert_eq!(rows.iter()?.count(), 1);
+    }
+    let lookup = started.elapsed().as_secs_f64();
+
+    Ok((insert, lookup))
+}
+
+fn path(fileno: DbInt) -> String {
+    format!("/home/user/dir{}/file{}", fileno / 1000, fileno)
+}
+
+fn get_fileno(row: &rusqlite::Row) -> Result<DbInt, rusqlite::Error> {
+    row.get("fileno")
+}
@@ -1,48 +1,535 @@
 use anyhow::Context;
 use clap::Parser;
-use log::{debug, error, info};
+use futures::future::BoxFuture;
+use futures::{FutureExt, Stream, StreamExt};
+use indicatif::HumanBytes;
+use log::{debug, error, info, LevelFilter};
+use obnam::acme::{self, AcmeConfig};
 use obnam::chunkid::ChunkId;
 use obnam::chunkmeta::ChunkMeta;
-use obnam::chunkstore::ChunkStore;
+use obnam::chunkstore::{self, ChunkStore, Durability, Sharding, StoreError};
 use obnam::label::Label;
-use obnam::server::{ServerConfig, ServerConfigError};
+use obnam::logging;
+use obnam::server::{
+    ByteRange, ContentRange, ExistsBitmap, ExistsQuery, FreedChunks, FreedQuery, GenerationChunks,
+    ServerConfig, ServerConfigError, MAX_EXISTS_LABELS,
+};
 use serde::Serialize;
 use std::collections::HashMap;
 use std::default::Default;
+use std::future::Future;
+use std::io::Write;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::task::{self, ready, Poll};
+use std::time::Duration;
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls;
 use warp::http::StatusCode;
-use warp::hyper::body::Bytes;
+use warp::hyper::body::Buf;
 use warp::Filter;
 
+// Environment variable that sets how much the server logs.
+const LOG_VAR: &str = "OBNAM_SERVER_LOG";
+
+// How long to wait before accepting connections again, after failing
+// to accept one.
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Largest request body for a query of which chunks exist. Labels are
+// short, so this is plenty for the maximum number of them.
+const MAX_EXISTS_BODY: u64 = 1024 * 1024;
+
+// Largest request body for registering the chunks of a generation.
+// Each chunk id takes about 40 bytes, so this allows for millions of
+// chunks.
+const MAX_GENERATION_BODY: u64 = 256 * 1024 * 1024;
+
 #[derive(Debug, Parser)]
 #[clap(name = "obnam2-server", about = "Backup server")]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Opt {
-    config: PathBuf,
+    #[clap(required = true)]
+    config: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Rebuild the chunk index from the chunk files, when the server
+    /// isn't running.
+    Reindex { config: PathBuf },
+
+    /// Move chunk files to where the configured sharding scheme puts
+    /// them. This can be done while the server is running.
+    MigrateShards { config: PathBuf },
+
+    /// Permanently delete chunks that have been in the trash longer
+    /// than the configured number of days, or all of them if the
+    /// configuration doesn't set it. This can be done while the
+    /// server is running.
+    PurgeTrash { config: PathBuf },
+
+    /// Move chunks from the trash back into use, when the server
+    /// isn't running. Without chunk ids, all chunks in the trash are
+    /// restored.
+    RestoreTrash {
+        config: PathBuf,
+        chunk_ids: Vec<String>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    pretty_env_logger::init_custom_env("OBNAM_SERVER_LOG");
-
     let opt = Opt::parse();
-    let config = load_config(&opt.config)?;
+    let filename = match &opt.cmd {
+        Some(Command::Reindex { config })
+        | Some(Command::MigrateShards { config })
+        | Some(Command::PurgeTrash { config })
+        | Some(Command::RestoreTrash { config, .. }) => config,
+        None => opt.config.as_ref().unwrap(),
+    };
+    let mut config = load_config(filename)?;
+    setup_logging(&config)?;
 
-    let addresses: Vec<SocketAddr> = config.address.to_socket_addrs()?.collect();
-    if addresses.is_empty() {
-        error!("specified address is empty set: {:?}", addresses);
-        eprintln!("ERROR: server address is empty: {:?}", addresses);
-        return Err(ServerConfigError::BadServerAddress.into());
+    match &opt.cmd {
+        Some(Command::Reindex { .. }) => return reindex(&config),
+        Some(Command::MigrateShards { .. }) => return migrate_shards(&config),
+        Some(Command::PurgeTrash { .. }) => return purge_trash(&config),
+        Some(Command::RestoreTrash { chunk_ids, .. }) => return restore_trash(&config, chunk_ids),
+        None => (),
     }
 
-    let store = ChunkStore::local(&config.chunks)?;
-    let store = Arc::new(Mutex::new(store));
-    let store = warp::any().map(move || Arc::clone(&store));
-
     info!("Obnam server starting up");
     debug!("opt: {:#?}", opt);
+
+    // On SIGHUP, the configuration is read again, and a new server is
+    // started with it. The new server takes over the socket the old
+    // one listens on, if the address hasn't changed, and only then
+    // does the old one stop taking new requests and finish the ones in
+    // progress. That way no connection is refused while reloading. If
+    // the new configuration can't be used, the old server keeps
+    // running.
+    let (tx, mut events) = signal_events()?;
+    let (acme, acme_rx) = watch::channel(config.acme.clone());
+    renew_certificates(tx, acme_rx);
+    let mut listener = Listener::open(&config, None).await?;
+    let mut store = SharedStore::open(&config, None)?;
+    let mut running = serve(&config, &listener, &store).await?;
+    let mut draining: Vec<JoinHandle<()>> = vec![];
+    loop {
+        let event = tokio::select! {
+            _ = &mut running.task => return Err(ServerError::Stopped.into()),
+            event = events.recv() => event.unwrap_or(Event::Shutdown),
+        };
+        let new = match event {
+            Event::Reload => {
+                info!("reloading configuration");
+                match load_config(opt.config.as_ref().unwrap()) {
+                    Ok(new) => new,
+                    Err(err) => {
+                        error!("can't reload configuration, using old one: {:#}", err);
+                        continue;
+                    }
+                }
+            }
+            Event::Renew => {
+                info!("using renewed TLS certificate");
+                config.clone()
+            }
+            Event::Shutdown => {
+                running.stop().await;
+                for old in draining {
+                    old.await.ok();
+                }
+                listener.close();
+                info!("Obnam server shut down");
+                return Ok(());
+            }
+        };
+
+        match restart(&new, &listener, &store).await {
+            Ok((new_listener, new_store, new_running)) => {
+                let old = std::mem::replace(&mut running, new_running);
+                let old_listener = std::mem::replace(&mut listener, new_listener);
+                let handed_over = old_listener.is_same(&listener);
+                store = new_store;
+                config = new;
+                acme.send(config.acme.clone()).ok();
+                draining.retain(|old| !old.is_finished());
+                draining.push(tokio::spawn(async move {
+                    old.stop().await;
+                    if !handed_over {
+                        old_listener.close();
+                    }
+                }));
+            }
+            Err(err) => error!("can't use new configuration, using old one: {:#}", err),
+        }
+    }
+}
+
+// Start a server with a new configuration, taking over the socket and
+// chunk store of the running one where the configuration allows.
+async fn restart(
+    config: &ServerConfig,
+    listener: &Listener,
+    store: &SharedStore,
+) -> anyhow::Result<(Listener, SharedStore, Running)> {
+    let listener = Listener::open(config, Some(listener)).await?;
+    let store = SharedStore::open(config, Some(store))?;
+    let running = serve(config, &listener, &store).await?;
+    Ok((listener, store, running))
+}
+
+/// A server that is running in a task of its own.
+struct Running {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Running {
+    /// Stop taking new requests, and finish the ones in progress.
+    async fn stop(self) {
+        info!("finishing requests in progress");
+        self.stop.send(()).ok();
+        self.task.await.ok();
+    }
+}
+
+/// The socket the server listens on.
+///
+/// It's kept open when the server is reloaded, so that connections
+/// made meanwhile wait to be accepted by the new server, instead of
+/// being refused.
+#[derive(Clone)]
+enum Listener {
+    Tcp(SocketAddr, Arc<TcpListener>),
+    Unix(PathBuf, Arc<UnixListener>),
+}
+
+impl Listener {
+    /// Listen where the configuration says, reusing the current socket
+    /// if it's for the same address.
+    async fn open(config: &ServerConfig, current: Option<&Listener>) -> anyhow::Result<Self> {
+        if let Some(path) = config.unix_socket() {
+            if let Some(Self::Unix(old, listener)) = current {
+                if old == path {
+                    return Ok(Self::Unix(old.clone(), Arc::clone(listener)));
+                }
+            }
+            // A socket left behind by an earlier run would prevent
+            // binding.
+            if path.exists() {
+                std::fs::remove_file(path)
+                    .with_context(|| format!("failed to remove old socket {}", path.display()))?;
+            }
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("failed to listen on socket {}", path.display()))?;
+            info!("listening on {}", path.display());
+            Ok(Self::Unix(path.to_path_buf(), Arc::new(listener)))
+        } else {
+            let addresses: Vec<SocketAddr> = config.address.to_socket_addrs()?.collect();
+            if addresses.is_empty() {
+                error!("specified address is empty set: {:?}", addresses);
+                eprintln!("ERROR: server address is empty: {:?}", addresses);
+                return Err(ServerConfigError::BadServerAddress.into());
+            }
+            let addr = addresses[0];
+            if let Some(Self::Tcp(old, listener)) = current {
+                if *old == addr {
+                    return Ok(Self::Tcp(addr, Arc::clone(listener)));
+                }
+            }
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|_| ServerError::Bind(addr))?;
+            info!("listening on {}", listener.local_addr()?);
+            Ok(Self::Tcp(addr, Arc::new(listener)))
+        }
+    }
+
+    /// Is this the same socket as another?
+    fn is_same(&self, other: &Listener) -> bool {
+        match (self, other) {
+            (Self::Tcp(_, a), Self::Tcp(_, b)) => Arc::ptr_eq(a, b),
+            (Self::Unix(_, a), Self::Unix(_, b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    /// Stop listening, and remove the socket file, if any.
+    fn close(self) {
+        if let Self::Unix(path, _) = self {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// The chunk store the server uses.
+///
+/// It's kept when the server is reloaded, unless the settings it was
+/// opened with change, so that the old and the new server don't both
+/// write to the chunk index while the old one finishes its requests.
+#[derive(Clone)]
+struct SharedStore {
+    settings: (PathBuf, Durability, Sharding, bool),
+    store: Arc<Mutex<ChunkStore>>,
+}
+
+impl SharedStore {
+    /// Open the chunk store the configuration says, reusing the current
+    /// one if it's opened the same way.
+    fn open(config: &ServerConfig, current: Option<&SharedStore>) -> anyhow::Result<Self> {
+        let settings = (
+            config.chunks.clone(),
+            config.durability,
+            config.sharding,
+            config.trash_days.is_some(),
+        );
+        if let Some(current) = current {
+            if current.settings == settings {
+                return Ok(current.clone());
+            }
+        }
+        let mut store = ChunkStore::local(&config.chunks, config.durability, config.sharding)?;
+        if config.trash_days.is_some() {
+            store.use_trash();
+        }
+        Ok(Self {
+            settings,
+            store: Arc::new(Mutex::new(store)),
+        })
+    }
+}
+
+// Accept connections on a socket until the server stops. Errors, such
+// as running out of file descriptors, are logged, and accepting is
+// tried again after a pause, so that they don't stop the server.
+fn connections<L, C, F>(listener: Arc<L>, accept: F) -> impl Stream<Item = std::io::Result<C>>
+where
+    F: Fn(Arc<L>) -> BoxFuture<'static, std::io::Result<C>>,
+{
+    futures::stream::unfold((listener, accept), |(listener, accept)| async move {
+        loop {
+            match accept(Arc::clone(&listener)).await {
+                Ok(conn) => return Some((Ok(conn), (listener, accept))),
+                Err(err) => {
+                    error!("can't accept connection: {}", err);
+                    tokio::time::sleep(ACCEPT_RETRY_INTERVAL).await;
+                }
+            }
+        }
+    })
+}
+
+// Read the TLS key and certificate into a TLS configuration.
+fn tls_config(key: &Path, cert: &Path) -> anyhow::Result<rustls::ServerConfig> {
+    let read = |path: &Path| {
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+    };
+    let certs = rustls_pemfile::certs(&mut read(cert)?.as_slice())
+        .with_context(|| format!("failed to parse TLS certificate {}", cert.display()))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    let key_pem = read(key)?;
+    let key_parse_error = || format!("failed to parse TLS key {}", key.display());
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut key_pem.as_slice())
+        .with_context(key_parse_error)?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut key_pem.as_slice())
+            .with_context(key_parse_error)?;
+    }
+    let private_key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no private key in {}", key.display()))?;
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, rustls::PrivateKey(private_key))
+        .with_context(|| format!("TLS key {} can't be used", key.display()))?;
+    config.alpn_protocols = vec!["h2".into(), "http/1.1".into()];
+    Ok(config)
+}
+
+/// A TLS connection. The handshake is done when the connection is first
+/// used, so that a slow client doesn't keep the server from accepting
+/// other connections meanwhile.
+enum TlsConn {
+    Handshaking(Box<tokio_rustls::Accept<TcpStream>>),
+    Streaming(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+impl TlsConn {
+    fn poll_handshake(
+        &mut self,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<&mut tokio_rustls::server::TlsStream<TcpStream>>> {
+        if let Self::Handshaking(accept) = self {
+            let stream = ready!(Pin::new(accept).poll(cx))?;
+            *self = Self::Streaming(Box::new(stream));
+        }
+        match self {
+            Self::Streaming(stream) => Poll::Ready(Ok(stream)),
+            Self::Handshaking(_) => unreachable!(),
+        }
+    }
+}
+
+impl AsyncRead for TlsConn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_handshake(cx))?;
+        Pin::new(stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let stream = ready!(self.get_mut().poll_handshake(cx))?;
+        Pin::new(stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<std::io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_handshake(cx))?;
+        Pin::new(stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_handshake(cx))?;
+        Pin::new(stream).poll_shutdown(cx)
+    }
+}
+
+/// Why the server stopped serving requests.
+#[derive(Debug, Clone, Copy)]
+enum Event {
+    /// The configuration is to be read again.
+    Reload,
+    /// A TLS certificate has been obtained with ACME, to replace
+    /// one that is about to expire, and is to be used.
+    Renew,
+    /// The server is to exit.
+    Shutdown,
+}
+
+// How often to check if a certificate obtained with ACME needs to be
+// renewed.
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+// Turn signals into events for the server.
+fn signal_events() -> anyhow::Result<(UnboundedSender<Event>, UnboundedReceiver<Event>)> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let sender = tx.clone();
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                _ = hangup.recv() => Event::Reload,
+                _ = interrupt.recv() => Event::Shutdown,
+                _ = terminate.recv() => Event::Shutdown,
+            };
+            info!("got signal, {:?}", event);
+            if sender.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    Ok((tx, rx))
+}
+
+// Periodically renew the TLS certificate, if it's obtained with ACME
+// and is about to expire. This is done in a task of its own, so that
+// the server keeps serving requests meanwhile, and the server is told
+// to start using the new certificate only once it has been written.
+// The ACME settings come from the configuration in use, which may
+// change.
+fn renew_certificates(tx: UnboundedSender<Event>, acme: watch::Receiver<Option<AcmeConfig>>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RENEW_CHECK_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let current = acme.borrow().clone();
+            if let Some(current) = current {
+                if renew_certificate(&current).await && tx.send(Event::Renew).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+// Obtain a new TLS certificate with ACME, if one is needed. Return
+// true if one was obtained, and the server needs to start using it.
+async fn renew_certificate(acme: &AcmeConfig) -> bool {
+    match acme.needs_certificate() {
+        Ok(false) => false,
+        Ok(true) => match acme::obtain_certificate(acme).await {
+            Ok(()) => true,
+            Err(err) => {
+                error!(
+                    "failed to renew TLS certificate, will try again later: {}",
+                    err
+                );
+                false
+            }
+        },
+        Err(err) => {
+            error!("failed to check TLS certificate: {}", err);
+            false
+        }
+    }
+}
+
+// Serve requests with a given configuration until an event says to
+// stop. Requests in progress are finished, and the chunk index is
+// closed, before returning.
+async fn serve(
+    config: &ServerConfig,
+    listener: &Listener,
+    store: &SharedStore,
+) -> anyhow::Result<Running> {
+    if let Some(acme) = &config.acme {
+        if acme.needs_certificate()? {
+            acme::obtain_certificate(acme).await?;
+        }
+    }
+    let tls = match config.tls_files() {
+        Some((key, cert)) => Some(Arc::new(tls_config(&key, &cert)?)),
+        None => None,
+    };
+
+    let store = Arc::clone(&store.store);
+    let store = warp::any().map(move || Arc::clone(&store));
+
+    let shared_config = Arc::new(config.clone());
+    let shared_config = warp::any().map(move || Arc::clone(&shared_config));
+
     debug!("Configuration: {:#?}", config);
 
     let create = warp::post()
@@ -50,8 +537,11 @@ async fn main() -> anyhow::Result<()> {
         .and(warp::path("chunks"))
         .and(warp::path::end())
         .and(store.clone())
+        .and(shared_config.clone())
         .and(warp::header("chunk-meta"))
-        .and(warp::filters::body::bytes())
+        .and(warp::header::optional::<String>("chunk-id"))
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::filters::body::stream())
         .and_then(create_chunk);
 
     let fetch = warp::get()
@@ -60,8 +550,35 @@ async fn main() -> anyhow::Result<()> {
         .and(warp::path::param())
         .and(warp::path::end())
         .and(store.clone())
+        .and(warp::header::optional::<String>("range"))
         .and_then(fetch_chunk);
 
+    let exists = warp::head()
+        .and(warp::path("v1"))
+        .and(warp::path("chunks"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(store.clone())
+        .and_then(chunk_exists);
+
+    let delete = warp::delete()
+        .and(warp::path("v1"))
+        .and(warp::path("chunks"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(store.clone())
+        .and_then(delete_chunk);
+
+    let bulk_exists = warp::post()
+        .and(warp::path("v1"))
+        .and(warp::path("chunks"))
+        .and(warp::path("exists"))
+        .and(warp::path::end())
+        .and(store.clone())
+        .and(warp::body::content_length_limit(MAX_EXISTS_BODY))
+        .and(warp::body::json())
+        .and_then(chunks_exist);
+
     let search = warp::get()
         .and(warp::path("v1"))
         .and(warp::path("chunks"))
@@ -70,16 +587,188 @@ async fn main() -> anyhow::Result<()> {
         .and(store.clone())
         .and_then(search_chunks);
 
+    let generations = warp::get()
+        .and(warp::path("v1"))
+        .and(warp::path("generations"))
+        .and(warp::path::end())
+        .and(store.clone())
+        .and(shared_config.clone())
+        .and_then(list_generations);
+
+    let register = warp::put()
+        .and(warp::path("v1"))
+        .and(warp::path("generations"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(store.clone())
+        .and(shared_config.clone())
+        .and(warp::body::content_length_limit(MAX_GENERATION_BODY))
+        .and(warp::body::json())
+        .and_then(register_generation);
+
+    let freed = warp::post()
+        .and(warp::path("v1"))
+        .and(warp::path("generations"))
+        .and(warp::path("freed"))
+        .and(warp::path::end())
+        .and(store.clone())
+        .and(shared_config.clone())
+        .and(warp::body::content_length_limit(MAX_GENERATION_BODY))
+        .and(warp::body::json())
+        .and_then(freed_chunks);
+
+    let unregister = warp::delete()
+        .and(warp::path("v1"))
+        .and(warp::path("generations"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(store.clone())
+        .and(shared_config)
+        .and_then(unregister_generation);
+
     let log = warp::log("obnam");
-    let webroot = create.or(fetch).or(search).with(log);
+    let webroot = create
+        .or(fetch)
+        .or(exists)
+        .or(delete)
+        .or(bulk_exists)
+        .or(search)
+        .or(generations)
+        .or(register)
+        .or(freed)
+        .or(unregister)
+        .with(log);
 
     debug!("starting warp");
-    warp::serve(webroot)
-        .tls()
-        .key_path(config.tls_key)
-        .cert_path(config.tls_cert)
-        .run(addresses[0])
-        .await;
+    let (stop, stopped) = oneshot::channel::<()>();
+    let stopped = async {
+        stopped.await.ok();
+    };
+    let server = warp::serve(webroot);
+    let server: Pin<Box<dyn Future<Output = ()> + Send>> = match (listener, tls) {
+        (Listener::Unix(_, listener), _) => {
+            let incoming = connections(Arc::clone(listener), |listener| {
+                async move { listener.accept().await.map(|(conn, _)| conn) }.boxed()
+            });
+            Box::pin(server.serve_incoming_with_graceful_shutdown(Box::pin(incoming), stopped))
+        }
+        (Listener::Tcp(_, listener), Some(tls)) => {
+            let incoming = connections(Arc::clone(listener), move |listener| {
+                let acceptor = tokio_rustls::TlsAcceptor::from(Arc::clone(&tls));
+                async move {
+                    let (conn, addr) = listener.accept().await?;
+                    debug!("accepted connection from {}", addr);
+                    Ok(TlsConn::Handshaking(Box::new(acceptor.accept(conn))))
+                }
+                .boxed()
+            });
+            Box::pin(server.serve_incoming_with_graceful_shutdown(Box::pin(incoming), stopped))
+        }
+        (Listener::Tcp(_, listener), None) => {
+            let incoming = connections(Arc::clone(listener), |listener| {
+                async move {
+                    let (conn, addr) = listener.accept().await?;
+                    debug!("accepted connection from {}", addr);
+                    Ok(conn)
+                }
+                .boxed()
+            });
+            Box::pin(server.serve_incoming_with_graceful_shutdown(Box::pin(incoming), stopped))
+        }
+    };
+    let task = tokio::spawn(server);
+    Ok(Running { stop, task })
+}
+
+/// Possible errors from serving requests.
+#[derive(Debug, thiserror::Error)]
+enum ServerError {
+    /// Couldn't start listening.
+    #[error("can't listen on {0}")]
+    Bind(SocketAddr),
+
+    /// The server stopped unexpectedly.
+    #[error("server stopped unexpectedly")]
+    Stopped,
+}
+
+fn reindex(config: &ServerConfig) -> anyhow::Result<()> {
+    info!("rebuilding chunk index in {}", config.chunks.display());
+    let report = chunkstore::reindex(&config.chunks)?;
+    for filename in report.skipped.iter() {
+        eprintln!(
+            "WARNING: left out chunk without usable metadata: {}",
+            filename.display()
+        );
+    }
+    println!(
+        "index rebuilt with {} chunks ({} with metadata from the old index), {} chunk files left out",
+        report.chunks,
+        report.from_old_index,
+        report.skipped.len()
+    );
+    Ok(())
+}
+
+fn migrate_shards(config: &ServerConfig) -> anyhow::Result<()> {
+    info!(
+        "moving chunks in {} to {:?} sharding",
+        config.chunks.display(),
+        config.sharding
+    );
+    let report = chunkstore::migrate_shards(&config.chunks, config.sharding)?;
+    println!(
+        "moved {} chunks, {} were already in place",
+        report.moved, report.unchanged
+    );
+    Ok(())
+}
+
+fn purge_trash(config: &ServerConfig) -> anyhow::Result<()> {
+    info!(
+        "purging chunks older than {:?} days from trash in {}",
+        config.trash_days,
+        config.chunks.display()
+    );
+    let report = chunkstore::purge_trash(&config.chunks, config.trash_days)?;
+    println!(
+        "purged {} chunks ({}) from trash",
+        report.chunks,
+        HumanBytes(report.bytes)
+    );
+    Ok(())
+}
+
+fn restore_trash(config: &ServerConfig, chunk_ids: &[String]) -> anyhow::Result<()> {
+    info!("restoring chunks from trash in {}", config.chunks.display());
+    let ids: Vec<ChunkId> = chunk_ids.iter().map(|id| ChunkId::recreate(id)).collect();
+    let report = chunkstore::restore_trash(&config.chunks, config.sharding, &ids)?;
+    for (id, err) in report.failed.iter() {
+        eprintln!("WARNING: can't restore chunk {} from trash: {}", id, err);
+    }
+    println!(
+        "restored {} chunks ({}) from trash",
+        report.chunks,
+        HumanBytes(report.bytes)
+    );
+    Ok(())
+}
+
+// Log to the system log, if the configuration says so, and otherwise
+// to the standard error output. How much is logged is set by the
+// OBNAM_SERVER_LOG environment variable. For the system log, it's a
+// level, and by default info.
+fn setup_logging(config: &ServerConfig) -> anyhow::Result<()> {
+    match config.system_log {
+        None => pretty_env_logger::init_custom_env(LOG_VAR),
+        Some(kind) => {
+            let level = std::env::var(LOG_VAR)
+                .ok()
+                .and_then(|level| level.parse().ok())
+                .unwrap_or(LevelFilter::Info);
+            logging::setup_server(kind, level)?;
+        }
+    }
     Ok(())
 }
 
@@ -95,11 +784,12 @@ fn load_config(filename: &Path) -> Result<ServerConfig, anyhow::Error> {
 
 pub async fn create_chunk(
     store: Arc<Mutex<ChunkStore>>,
+    config: Arc<ServerConfig>,
     meta: String,
-    data: Bytes,
+    id: Option<String>,
+    length: Option<u64>,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let store = store.lock().await;
-
     let meta: ChunkMeta = match meta.parse() {
         Ok(s) => s,
         Err(e) => {
@@ -108,8 +798,53 @@ pub async fn create_chunk(
         }
     };
 
-    let id = match store.put(data.to_vec(), &meta).await {
+    if let Err(e) = Label::deserialize(meta.label()) {
+        error!("chunk-meta header has a bad label: {}", e);
+        return Ok(ChunkResult::BadRequest);
+    }
+
+    // The client may choose the id of the chunk, so that it can bind
+    // the encrypted chunk to it.
+    let id = id.map(|id| ChunkId::recreate(&id));
+    if let Some(id) = &id {
+        if !id.is_well_formed() {
+            error!("chunk-id header is bad: {:?}", id.to_string());
+            return Ok(ChunkResult::BadRequest);
+        }
+    }
+
+    if let Some(length) = length {
+        if length > config.max_chunk_size {
+            error!(
+                "chunk is too large: {} bytes, maximum is {}",
+                length, config.max_chunk_size
+            );
+            return Ok(ChunkResult::PayloadTooLarge);
+        }
+    }
+
+    let file = match receive_chunk(&config, body).await {
+        Ok(Some(file)) => file,
+        Ok(None) => {
+            error!(
+                "chunk is too large: more than maximum of {} bytes",
+                config.max_chunk_size
+            );
+            return Ok(ChunkResult::PayloadTooLarge);
+        }
+        Err(e) => {
+            error!("couldn't receive chunk: {}", e);
+            return Ok(ChunkResult::InternalServerError);
+        }
+    };
+
+    let store = store.lock().await;
+    let id = match store.put_file(file, &meta, id.as_ref()).await {
         Ok(id) => id,
+        Err(StoreError::ChunkExists(id)) => {
+            error!("chunk {} already exists", id);
+            return Ok(ChunkResult::Conflict);
+        }
         Err(e) => {
             error!("couldn't save: {}", e);
             return Ok(ChunkResult::InternalServerError);
@@ -120,12 +855,70 @@ pub async fn create_chunk(
     Ok(ChunkResult::Created(id))
 }
 
+// Write a request body to a temporary file in the chunk directory,
+// as it arrives, so that the server doesn't need to keep the whole
+// chunk in memory. Return None if the body is larger than allowed.
+async fn receive_chunk(
+    config: &ServerConfig,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+) -> anyhow::Result<Option<NamedTempFile>> {
+    let mut file = NamedTempFile::new_in(&config.chunks)?;
+    let mut size: u64 = 0;
+    futures::pin_mut!(body);
+    while let Some(buf) = body.next().await {
+        let mut buf = buf?;
+        while buf.has_remaining() {
+            let n = buf.chunk().len();
+            size += n as u64;
+            if size > config.max_chunk_size {
+                return Ok(None);
+            }
+            file.write_all(buf.chunk())?;
+            buf.advance(n);
+        }
+    }
+    file.flush()?;
+    Ok(Some(file))
+}
+
 pub async fn fetch_chunk(
     id: String,
     store: Arc<Mutex<ChunkStore>>,
+    range: Option<String>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let store = store.lock().await;
     let id: ChunkId = id.parse().unwrap();
+
+    // A Range header we don't understand is ignored, and the whole
+    // chunk is returned, as HTTP allows.
+    let range = range.and_then(|r| match ByteRange::parse(&r) {
+        Ok(range) => Some(range),
+        Err(e) => {
+            info!("ignoring range: {}", e);
+            None
+        }
+    });
+    if let Some(range) = range {
+        return match store.get_range(&id, &range).await {
+            Ok((data, meta, content_range)) => {
+                info!(
+                    "found chunk {}, returning {}",
+                    id,
+                    content_range.to_header()
+                );
+                Ok(ChunkResult::PartialContent(meta, data, content_range))
+            }
+            Err(StoreError::RangeNotSatisfiable(_)) => {
+                error!("range {} is not within chunk {}", range.to_header(), id);
+                Ok(ChunkResult::RangeNotSatisfiable)
+            }
+            Err(e) => {
+                error!("chunk not found: {}: {:?}", id, e);
+                Ok(ChunkResult::NotFound)
+            }
+        };
+    }
+
     match store.get(&id).await {
         Ok((data, meta)) => {
             info!("found chunk {}: {:?}", id, meta);
@@ -138,6 +931,80 @@ pub async fn fetch_chunk(
     }
 }
 
+pub async fn chunk_exists(
+    id: String,
+    store: Arc<Mutex<ChunkStore>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let store = store.lock().await;
+    let id: ChunkId = id.parse().unwrap();
+    match store.exists(&id).await {
+        Ok(true) => {
+            info!("chunk {} exists", id);
+            Ok(ChunkResult::Exists)
+        }
+        Ok(false) => {
+            info!("chunk {} does not exist", id);
+            Ok(ChunkResult::NotFound)
+        }
+        Err(e) => {
+            error!("couldn't check if chunk {} exists: {}", id, e);
+            Ok(ChunkResult::InternalServerError)
+        }
+    }
+}
+
+pub async fn delete_chunk(
+    id: String,
+    store: Arc<Mutex<ChunkStore>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let store = store.lock().await;
+    let id: ChunkId = id.parse().unwrap();
+    match store.delete(&id).await {
+        Ok(()) => {
+            info!("deleted chunk {}", id);
+            Ok(ChunkResult::Deleted)
+        }
+        Err(StoreError::NotFound(_)) => {
+            info!("chunk {} does not exist, can't delete it", id);
+            Ok(ChunkResult::NotFound)
+        }
+        Err(e) => {
+            error!("couldn't delete chunk {}: {}", id, e);
+            Ok(ChunkResult::InternalServerError)
+        }
+    }
+}
+
+pub async fn chunks_exist(
+    store: Arc<Mutex<ChunkStore>>,
+    query: ExistsQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if query.labels.len() > MAX_EXISTS_LABELS {
+        error!(
+            "query has too many labels: {}, maximum is {}",
+            query.labels.len(),
+            MAX_EXISTS_LABELS
+        );
+        return Ok(ChunkResult::BadRequest);
+    }
+
+    let store = store.lock().await;
+    match store.labels_exist(&query.labels).await {
+        Ok(exists) => {
+            info!(
+                "{} of {} labels exist",
+                exists.iter().filter(|e| **e).count(),
+                exists.len()
+            );
+            Ok(ChunkResult::ExistsBitmap(ExistsBitmap::new(&exists)))
+        }
+        Err(e) => {
+            error!("couldn't check which labels exist: {}", e);
+            Ok(ChunkResult::InternalServerError)
+        }
+    }
+}
+
 pub async fn search_chunks(
     query: HashMap<String, String>,
     store: Arc<Mutex<ChunkStore>>,
@@ -151,12 +1018,27 @@ pub async fn search_chunks(
             return Ok(ChunkResult::BadRequest);
         }
         if key == "label" {
-            let label = Label::deserialize(value).unwrap();
+            let label = match Label::deserialize(value) {
+                Ok(label) => label,
+                Err(err) => {
+                    error!("search label is bad: {}", err);
+                    return Ok(ChunkResult::BadRequest);
+                }
+            };
             let label = ChunkMeta::new(&label);
             store
                 .find_by_label(&label)
                 .await
                 .expect("SQL lookup failed")
+        } else if key == "id_prefix" {
+            if value.is_empty() {
+                error!("search id prefix is empty");
+                return Ok(ChunkResult::BadRequest);
+            }
+            store
+                .find_by_id_prefix(value)
+                .await
+                .expect("SQL lookup failed")
         } else {
             error!("unknown search key {:?}", key);
             return Ok(ChunkResult::BadRequest);
@@ -188,6 +1070,129 @@ pub async fn search_chunks(
     Ok(ChunkResult::Found(hits))
 }
 
+pub async fn list_generations(
+    store: Arc<Mutex<ChunkStore>>,
+    config: Arc<ServerConfig>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !config.refcounts {
+        info!("chunk references are not counted, no generations to list");
+        return Ok(ChunkResult::NotFound);
+    }
+
+    let store = store.lock().await;
+    match store.generations().await {
+        Ok(Some(gens)) => {
+            info!("{} generations are registered", gens.len());
+            Ok(ChunkResult::Generations(gens))
+        }
+        Ok(None) => Ok(ChunkResult::NotFound),
+        Err(e) => {
+            error!("couldn't list registered generations: {}", e);
+            Ok(ChunkResult::InternalServerError)
+        }
+    }
+}
+
+pub async fn register_generation(
+    id: String,
+    store: Arc<Mutex<ChunkStore>>,
+    config: Arc<ServerConfig>,
+    body: GenerationChunks,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !config.refcounts {
+        info!("chunk references are not counted, can't register generation");
+        return Ok(ChunkResult::NotFound);
+    }
+
+    let store = store.lock().await;
+    let id: ChunkId = id.parse().unwrap();
+    match store.exists(&id).await {
+        Ok(true) => (),
+        Ok(false) => {
+            error!("generation {} is not a chunk, can't register it", id);
+            return Ok(ChunkResult::NotFound);
+        }
+        Err(e) => {
+            error!("couldn't check if generation {} exists: {}", id, e);
+            return Ok(ChunkResult::InternalServerError);
+        }
+    }
+
+    match store.register_generation(&id, &body.chunks).await {
+        Ok(()) => {
+            info!(
+                "registered generation {} with {} chunks",
+                id,
+                body.chunks.len()
+            );
+            Ok(ChunkResult::Registered)
+        }
+        Err(e) => {
+            error!("couldn't register generation {}: {}", id, e);
+            Ok(ChunkResult::InternalServerError)
+        }
+    }
+}
+
+pub async fn freed_chunks(
+    store: Arc<Mutex<ChunkStore>>,
+    config: Arc<ServerConfig>,
+    query: FreedQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !config.refcounts {
+        info!("chunk references are not counted, can't find freed chunks");
+        return Ok(ChunkResult::NotFound);
+    }
+
+    let store = store.lock().await;
+    match store.freed_chunks(&query.generations).await {
+        Ok(freed) => {
+            info!(
+                "forgetting {} generations would free {} chunks",
+                query.generations.len(),
+                freed.chunks.len()
+            );
+            Ok(ChunkResult::Freed(freed))
+        }
+        Err(e) => {
+            error!("couldn't find freed chunks: {}", e);
+            Ok(ChunkResult::InternalServerError)
+        }
+    }
+}
+
+pub async fn unregister_generation(
+    id: String,
+    store: Arc<Mutex<ChunkStore>>,
+    config: Arc<ServerConfig>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !config.refcounts {
+        info!("chunk references are not counted, can't unregister generation");
+        return Ok(ChunkResult::NotFound);
+    }
+
+    let store = store.lock().await;
+    let id: ChunkId = id.parse().unwrap();
+    match store.unregister_generation(&id).await {
+        Ok(freed) => {
+            info!(
+                "unregistered generation {}, removed {} chunks",
+                id,
+                freed.chunks.len()
+            );
+            Ok(ChunkResult::Freed(freed))
+        }
+        Err(StoreError::NotFound(_)) => {
+            info!("generation {} is not registered", id);
+            Ok(ChunkResult::NotFound)
+        }
+        Err(e) => {
+            error!("couldn't unregister generation {}: {}", id, e);
+            Ok(ChunkResult::InternalServerError)
+        }
+    }
+}
+
 #[derive(Default, Clone, Serialize)]
 struct SearchHits {
     map: HashMap<String, ChunkMeta>,
@@ -210,9 +1215,19 @@ impl SearchHits {
 enum ChunkResult {
     Created(ChunkId),
     Fetched(ChunkMeta, Vec<u8>),
+    PartialContent(ChunkMeta, Vec<u8>, ContentRange),
     Found(SearchHits),
+    Exists,
+    ExistsBitmap(ExistsBitmap),
+    Generations(Vec<ChunkId>),
+    Freed(FreedChunks),
+    Registered,
+    Deleted,
     NotFound,
     BadRequest,
+    Conflict,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
     InternalServerError,
 }
 
@@ -237,6 +1252,7 @@ impl warp::Reply for ChunkResult {
                     "chunk-meta".to_string(),
                     serde_json::to_string(&meta).unwrap(),
                 );
+                headers.insert("accept-ranges".to_string(), "bytes".to_string());
                 into_response(
                     StatusCode::OK,
                     &chunk,
@@ -244,9 +1260,36 @@ impl warp::Reply for ChunkResult {
                     Some(headers),
                 )
             }
+            ChunkResult::PartialContent(meta, chunk, content_range) => {
+                let mut headers = HashMap::new();
+                headers.insert(
+                    "chunk-meta".to_string(),
+                    serde_json::to_string(&meta).unwrap(),
+                );
+                headers.insert("content-range".to_string(), content_range.to_header());
+                into_response(
+                    StatusCode::PARTIAL_CONTENT,
+                    &chunk,
+                    "application/octet-stream",
+                    Some(headers),
+                )
+            }
             ChunkResult::Found(hits) => json_response(StatusCode::OK, hits.to_json(), None),
+            ChunkResult::Exists => status_response(StatusCode::OK),
+            ChunkResult::ExistsBitmap(bitmap) => {
+                json_response(StatusCode::OK, bitmap.to_json(), None)
+            }
+            ChunkResult::Generations(gens) => {
+                json_response(StatusCode::OK, serde_json::to_string(&gens).unwrap(), None)
+            }
+            ChunkResult::Freed(freed) => json_response(StatusCode::OK, freed.to_json(), None),
+            ChunkResult::Registered => status_response(StatusCode::OK),
+            ChunkResult::Deleted => status_response(StatusCode::OK),
             ChunkResult::BadRequest => status_response(StatusCode::BAD_REQUEST),
             ChunkResult::NotFound => status_response(StatusCode::NOT_FOUND),
+            ChunkResult::Conflict => status_response(StatusCode::CONFLICT),
+            ChunkResult::PayloadTooLarge => status_response(StatusCode::PAYLOAD_TOO_LARGE),
+            ChunkResult::RangeNotSatisfiable => status_response(StatusCode::RANGE_NOT_SATISFIABLE),
             ChunkResult::InternalServerError => status_response(StatusCode::INTERNAL_SERVER_ERROR),
         }
     }
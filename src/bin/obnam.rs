@@ -1,25 +1,42 @@
 use clap::Parser;
 use directories_next::ProjectDirs;
-use log::{debug, error, info, LevelFilter};
-use log4rs::append::file::FileAppender;
-use log4rs::config::{Appender, Logger, Root};
+use log::{debug, error, info};
 use obnam::cmd::backup::Backup;
+use obnam::cmd::backup_stream::BackupStream;
 use obnam::cmd::chunk::{DecryptChunk, EncryptChunk};
 use obnam::cmd::chunkify::Chunkify;
+use obnam::cmd::clients::Clients;
+use obnam::cmd::du::Du;
+use obnam::cmd::export::{Export, Import};
+use obnam::cmd::forget::Forget;
 use obnam::cmd::gen_info::GenInfo;
 use obnam::cmd::get_chunk::GetChunk;
+use obnam::cmd::history::History;
 use obnam::cmd::init::Init;
 use obnam::cmd::inspect::Inspect;
+use obnam::cmd::key::Key;
 use obnam::cmd::list::List;
 use obnam::cmd::list_backup_versions::ListSchemaVersions;
 use obnam::cmd::list_files::ListFiles;
+use obnam::cmd::pin::{Pin, Unpin};
+use obnam::cmd::repair::Repair;
+use obnam::cmd::replicate::Replicate;
 use obnam::cmd::resolve::Resolve;
 use obnam::cmd::restore::Restore;
+use obnam::cmd::restore_test::RestoreTest;
+use obnam::cmd::search::Search;
 use obnam::cmd::show_config::ShowConfig;
 use obnam::cmd::show_gen::ShowGeneration;
-use obnam::config::ClientConfig;
+use obnam::cmd::GlobalOptions;
+use obnam::config::{ClientConfig, ClientConfigError};
+use obnam::error::{ErrorKind, ObnamError, EXIT_CANCELLED};
+use obnam::logging::{self, console_level, LoggingError};
+use obnam::passphrase::PassphraseError;
 use obnam::performance::{Clock, Performance};
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
+use tokio::runtime::Builder;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
 
 const QUALIFIER: &str = "";
 const ORG: &str = "";
@@ -29,9 +46,15 @@ fn main() {
     let mut perf = Performance::default();
     perf.start(Clock::RunTime);
     if let Err(err) = main_program(&mut perf) {
-        error!("{}", err);
-        eprintln!("ERROR: {}", err);
-        std::process::exit(1);
+        let kind = error_kind(&err);
+        if kind == ErrorKind::Cancelled {
+            info!("{}", err);
+            eprintln!("CANCELLED: {}", err);
+        } else {
+            error!("{}", err);
+            eprintln!("ERROR: {}", err);
+        }
+        std::process::exit(kind.exit_code());
     }
     perf.stop(Clock::RunTime);
     perf.log();
@@ -39,45 +62,105 @@ fn main() {
 
 fn main_program(perf: &mut Performance) -> anyhow::Result<()> {
     let opt = Opt::parse();
-    let config = ClientConfig::read(&config_filename(&opt))?;
-    setup_logging(&config.log)?;
+    let global = &opt.global;
+    let mut config = ClientConfig::read_profile(&config_filename(&opt), global.profile.as_deref())?;
+    global.apply(&mut config)?;
+    logging::setup(
+        &config,
+        global.log_level,
+        console_level(global.verbose, global.quiet),
+    )?;
 
     info!("client starts");
     debug!("{:?}", opt);
-    debug!("configuration: {:#?}", config);
+    debug!("configuration: {:#?}", config.redacted());
+
+    // Only commands that can be cancelled catch signals. Others are
+    // stopped by them right away, as usual.
+    let cancellable = || -> anyhow::Result<CancellationToken> {
+        let cancel = CancellationToken::new();
+        cancel_on_signals(cancel.clone())?;
+        Ok(cancel)
+    };
 
     match opt.cmd {
-        Command::Init(x) => x.run(&config),
-        Command::ListBackupVersions(x) => x.run(&config),
-        Command::Backup(x) => x.run(&config, perf),
-        Command::Inspect(x) => x.run(&config),
-        Command::Chunkify(x) => x.run(&config),
-        Command::List(x) => x.run(&config),
-        Command::ShowGeneration(x) => x.run(&config),
-        Command::ListFiles(x) => x.run(&config),
-        Command::Resolve(x) => x.run(&config),
-        Command::Restore(x) => x.run(&config),
-        Command::GenInfo(x) => x.run(&config),
-        Command::GetChunk(x) => x.run(&config),
-        Command::Config(x) => x.run(&config),
-        Command::EncryptChunk(x) => x.run(&config),
-        Command::DecryptChunk(x) => x.run(&config),
+        Command::Init(x) => x.run(&config, global),
+        Command::ListBackupVersions(x) => x.run(&config, global),
+        Command::Backup(x) => x.run(&config, global, perf, cancellable()?),
+        Command::BackupStream(x) => x.run(&config, global, perf, cancellable()?),
+        Command::Inspect(x) => x.run(&config, global),
+        Command::Chunkify(x) => x.run(&config, global),
+        Command::List(x) => x.run(&config, global),
+        Command::Clients(x) => x.run(&config, global),
+        Command::Key(x) => x.run(&config, global),
+        Command::Forget(x) => x.run(&config, global, cancellable()?),
+        Command::Pin(x) => x.run(&config, global),
+        Command::Unpin(x) => x.run(&config, global),
+        Command::ShowGeneration(x) => x.run(&config, global),
+        Command::ListFiles(x) => x.run(&config, global),
+        Command::Search(x) => x.run(&config, global),
+        Command::History(x) => x.run(&config, global),
+        Command::Du(x) => x.run(&config, global),
+        Command::Resolve(x) => x.run(&config, global),
+        Command::Restore(x) => x.run(&config, global, cancellable()?),
+        Command::RestoreTest(x) => x.run(&config, global),
+        Command::Repair(x) => x.run(&config, global),
+        Command::Replicate(x) => x.run(&config, global),
+        Command::GenInfo(x) => x.run(&config, global),
+        Command::GetChunk(x) => x.run(&config, global),
+        Command::Export(x) => x.run(&config, global),
+        Command::Import(x) => x.run(&config, global, cancellable()?),
+        Command::Config(x) => x.run(&config, global),
+        Command::EncryptChunk(x) => x.run(&config, global),
+        Command::DecryptChunk(x) => x.run(&config, global),
     }?;
 
     info!("client ends successfully");
     Ok(())
 }
 
-fn setup_logging(filename: &Path) -> anyhow::Result<()> {
-    let logfile = FileAppender::builder().build(filename)?;
-
-    let config = log4rs::Config::builder()
-        .appender(Appender::builder().build("obnam", Box::new(logfile)))
-        .logger(Logger::builder().build("obnam", LevelFilter::Debug))
-        .build(Root::builder().appender("obnam").build(LevelFilter::Debug))?;
-
-    log4rs::init_config(config)?;
+// What kind of failure an error is, for the exit code. Errors from
+// before a command is run are about the configuration or the command
+// line options.
+fn error_kind(err: &anyhow::Error) -> ErrorKind {
+    if let Some(err) = err.downcast_ref::<ObnamError>() {
+        err.kind()
+    } else if err.is::<ClientConfigError>()
+        || err.is::<LoggingError>()
+        || err.is::<PassphraseError>()
+    {
+        ErrorKind::Config
+    } else {
+        ErrorKind::Failure
+    }
+}
 
+// Cancel the current operation on SIGINT or SIGTERM, so that it can
+// clean up after itself. A second signal stops the program at once.
+fn cancel_on_signals(cancel: CancellationToken) -> anyhow::Result<()> {
+    let rt = Builder::new_current_thread().enable_all().build()?;
+    let (mut interrupt, mut terminate) = {
+        let _guard = rt.enter();
+        (
+            signal(SignalKind::interrupt())?,
+            signal(SignalKind::terminate())?,
+        )
+    };
+    std::thread::spawn(move || {
+        rt.block_on(async {
+            tokio::select! {
+                _ = interrupt.recv() => info!("got SIGINT, cancelling"),
+                _ = terminate.recv() => info!("got SIGTERM, cancelling"),
+            }
+            eprintln!("Cancelling, please wait. Send the signal again to stop at once.");
+            cancel.cancel();
+            tokio::select! {
+                _ = interrupt.recv() => (),
+                _ = terminate.recv() => (),
+            }
+            std::process::exit(EXIT_CANCELLED);
+        })
+    });
     Ok(())
 }
 
@@ -102,6 +185,9 @@ struct Opt {
     #[clap(long, short)]
     config: Option<PathBuf>,
 
+    #[clap(flatten)]
+    global: GlobalOptions,
+
     #[clap(subcommand)]
     cmd: Command,
 }
@@ -110,16 +196,30 @@ struct Opt {
 enum Command {
     Init(Init),
     Backup(Backup),
+    BackupStream(BackupStream),
     Inspect(Inspect),
     Chunkify(Chunkify),
     List(List),
+    Clients(Clients),
+    Key(Key),
+    Forget(Forget),
+    Pin(Pin),
+    Unpin(Unpin),
     ListBackupVersions(ListSchemaVersions),
     ListFiles(ListFiles),
+    Search(Search),
+    History(History),
+    Du(Du),
     Restore(Restore),
+    RestoreTest(RestoreTest),
+    Repair(Repair),
+    Replicate(Replicate),
     GenInfo(GenInfo),
     ShowGeneration(ShowGeneration),
     Resolve(Resolve),
     GetChunk(GetChunk),
+    Export(Export),
+    Import(Import),
     Config(ShowConfig),
     EncryptChunk(EncryptChunk),
     DecryptChunk(DecryptChunk),
@@ -4,6 +4,7 @@ use crate::chunkid::ChunkId;
 use crate::chunkmeta::ChunkMeta;
 use crate::label::Label;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::default::Default;
 
 /// An arbitrary chunk of arbitrary binary data.
@@ -108,12 +109,25 @@ impl GenerationChunk {
 /// This chunk contains all per-client backup information. As long as
 /// this chunk can be trusted, everything it links to can also be
 /// trusted, thanks to cryptographic signatures.
-#[derive(Debug, Serialize, Deserialize)]
+///
+/// Each update is uploaded as a new chunk, which names the version it
+/// was based on. If two clients update the trust root at the same
+/// time, there will be two versions based on the same one. They're
+/// merged with [`ClientTrust::merge`], and the merged version names
+/// all the versions it merged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientTrust {
     client_name: String,
     previous_version: Option<ChunkId>,
     timestamp: String,
     backups: Vec<ChunkId>,
+    // Other versions that were merged into this one, in addition to
+    // the previous version.
+    #[serde(default)]
+    merged: Vec<ChunkId>,
+    // Backups that must not be forgotten.
+    #[serde(default)]
+    pinned: Vec<ChunkId>,
 }
 
 /// All the errors that may be returned for `ClientTrust` operations.
@@ -145,6 +159,8 @@ impl ClientTrust {
             previous_version,
             timestamp,
             backups,
+            merged: vec![],
+            pinned: vec![],
         }
     }
 
@@ -158,6 +174,11 @@ impl ClientTrust {
         self.previous_version.clone()
     }
 
+    /// Return ids of other versions merged into this one.
+    pub fn merged(&self) -> &[ChunkId] {
+        &self.merged
+    }
+
     /// Return timestamp.
     pub fn timestamp(&self) -> &str {
         &self.timestamp
@@ -173,6 +194,63 @@ impl ClientTrust {
         self.backups.push(id.clone());
     }
 
+    /// Return list of pinned backup generations, which must not be
+    /// forgotten.
+    pub fn pinned(&self) -> &[ChunkId] {
+        &self.pinned
+    }
+
+    /// Is a backup generation pinned?
+    pub fn is_pinned(&self, id: &ChunkId) -> bool {
+        self.pinned.contains(id)
+    }
+
+    /// Pin a backup generation, so that it's not forgotten.
+    ///
+    /// Return false if the generation was pinned already.
+    pub fn pin(&mut self, id: &ChunkId) -> bool {
+        if self.is_pinned(id) {
+            false
+        } else {
+            self.pinned.push(id.clone());
+            true
+        }
+    }
+
+    /// Unpin a backup generation, so that it can be forgotten again.
+    ///
+    /// Return false if the generation wasn't pinned.
+    pub fn unpin(&mut self, id: &ChunkId) -> bool {
+        let len = self.pinned.len();
+        self.pinned.retain(|p| p != id);
+        self.pinned.len() != len
+    }
+
+    /// Remove a backup generation from the list.
+    ///
+    /// Return false if the generation wasn't in the list.
+    pub fn remove_backup(&mut self, id: &ChunkId) -> bool {
+        let len = self.backups.len();
+        self.backups.retain(|b| b != id);
+        self.backups.len() != len
+    }
+
+    /// Replace a backup generation in the list with another, keeping
+    /// its place. If the old generation was pinned, the new one is.
+    ///
+    /// Return false if the generation wasn't in the list.
+    pub fn replace_backup(&mut self, old: &ChunkId, new: &ChunkId) -> bool {
+        let mut replaced = false;
+        for b in self.backups.iter_mut().filter(|b| *b == old) {
+            *b = new.clone();
+            replaced = true;
+        }
+        for p in self.pinned.iter_mut().filter(|p| *p == old) {
+            *p = new.clone();
+        }
+        replaced
+    }
+
     /// Update for new upload.
     ///
     /// This needs to happen every time the chunk is updated so that
@@ -196,4 +274,358 @@ impl ClientTrust {
         let data = std::str::from_utf8(data)?;
         serde_json::from_str(data).map_err(ClientTrustError::JsonParse)
     }
+
+    /// Return the ids of the latest versions: those that no other
+    /// version is based on, or has merged.
+    ///
+    /// Older versions of Obnam didn't record what a version was based
+    /// on. Such a version is considered to be superseded if the newest
+    /// version has all of its backups, or if it's no newer than the
+    /// oldest version some recorded history goes back to: the first
+    /// version to record what it was based on was based on the state
+    /// of the versions before it.
+    pub fn heads(versions: &[(ChunkId, ClientTrust)]) -> Vec<ChunkId> {
+        let by_id: HashMap<&ChunkId, &ClientTrust> =
+            versions.iter().map(|(id, t)| (id, t)).collect();
+        let mut superseded: HashSet<&ChunkId> = versions
+            .iter()
+            .flat_map(|(_, t)| t.previous_version.iter().chain(t.merged.iter()))
+            .collect();
+
+        let mut history_start: Option<&str> = None;
+        for (_, t) in versions
+            .iter()
+            .filter(|(_, t)| t.previous_version.is_some())
+        {
+            let oldest = ancestors(&by_id, t)
+                .iter()
+                .map(|a| a.timestamp())
+                .min()
+                .unwrap_or_else(|| t.timestamp());
+            history_start = history_start.max(Some(oldest));
+        }
+        if let Some(start) = history_start {
+            for (id, t) in versions.iter() {
+                if t.previous_version.is_none() && t.merged.is_empty() && t.timestamp() <= start {
+                    superseded.insert(id);
+                }
+            }
+        }
+
+        if let Some((newest_id, newest)) = versions.iter().max_by_key(|(_, t)| t.timestamp()) {
+            let newest_backups: HashSet<&ChunkId> = newest.backups.iter().collect();
+            for (id, t) in versions.iter() {
+                if id != newest_id
+                    && t.previous_version.is_none()
+                    && t.merged.is_empty()
+                    && t.backups.iter().all(|b| newest_backups.contains(b))
+                {
+                    superseded.insert(id);
+                }
+            }
+        }
+        let mut heads: Vec<(&str, &ChunkId)> = versions
+            .iter()
+            .filter(|(id, _)| !superseded.contains(id))
+            .map(|(id, t)| (t.timestamp(), id))
+            .collect();
+        heads.sort_by_key(|(timestamp, id)| (*timestamp, id.to_string()));
+        heads.into_iter().map(|(_, id)| id.clone()).collect()
+    }
+
+    /// Merge all versions of a trust root into the next version.
+    ///
+    /// The newest of the latest versions is the base. For each other
+    /// latest version, the backups it added or removed since it forked
+    /// from the base are added or removed, and likewise for the
+    /// pinned backups. Only backups that remain in the list stay
+    /// pinned. The result is based on the newest version, and names
+    /// the others as merged, so that it can be uploaded as the next
+    /// version after adding to it.
+    ///
+    /// Return `None` if there are no versions.
+    pub fn merge(versions: &[(ChunkId, ClientTrust)]) -> Option<ClientTrust> {
+        let by_id: HashMap<&ChunkId, &ClientTrust> =
+            versions.iter().map(|(id, t)| (id, t)).collect();
+        let mut heads = Self::heads(versions);
+        let base_id = heads.pop()?;
+        let base = by_id[&base_id];
+
+        // Everything the base version was made from.
+        let mut ancestors = HashSet::new();
+        let mut todo = vec![&base_id];
+        while let Some(id) = todo.pop() {
+            if ancestors.insert(id) {
+                if let Some(t) = by_id.get(id) {
+                    todo.extend(t.previous_version.iter());
+                    todo.extend(t.merged.iter());
+                }
+            }
+        }
+
+        let mut backups = base.backups.clone();
+        let mut pinned = base.pinned.clone();
+        for id in heads.iter() {
+            let other = by_id[id];
+
+            // The newest version that both the base and the other
+            // version were made from.
+            let mut fork: Option<&ClientTrust> = None;
+            let mut seen = HashSet::new();
+            let mut prev = other.previous_version.as_ref();
+            while let Some(p) = prev {
+                if !seen.insert(p) {
+                    break;
+                }
+                if ancestors.contains(p) {
+                    fork = by_id.get(p).copied();
+                    break;
+                }
+                prev = by_id.get(p).and_then(|t| t.previous_version.as_ref());
+            }
+
+            merge_changes(
+                &mut backups,
+                fork.map(|t| &t.backups[..]).unwrap_or(&[]),
+                &other.backups,
+            );
+            merge_changes(
+                &mut pinned,
+                fork.map(|t| &t.pinned[..]).unwrap_or(&[]),
+                &other.pinned,
+            );
+        }
+        pinned.retain(|p| backups.contains(p));
+
+        Some(ClientTrust {
+            client_name: base.client_name.clone(),
+            previous_version: Some(base_id),
+            timestamp: base.timestamp.clone(),
+            backups,
+            merged: heads,
+            pinned,
+        })
+    }
+}
+
+// The known versions a version was made from, directly or indirectly.
+fn ancestors<'a>(
+    by_id: &HashMap<&ChunkId, &'a ClientTrust>,
+    trust: &'a ClientTrust,
+) -> Vec<&'a ClientTrust> {
+    let mut found = vec![];
+    let mut seen = HashSet::new();
+    let mut todo: Vec<&ChunkId> = trust
+        .previous_version
+        .iter()
+        .chain(trust.merged.iter())
+        .collect();
+    while let Some(id) = todo.pop() {
+        if seen.insert(id) {
+            if let Some(t) = by_id.get(id) {
+                found.push(*t);
+                todo.extend(t.previous_version.iter());
+                todo.extend(t.merged.iter());
+            }
+        }
+    }
+    found
+}
+
+// Apply to a list the changes another version made to it since the
+// version it forked from.
+fn merge_changes(list: &mut Vec<ChunkId>, fork: &[ChunkId], other: &[ChunkId]) {
+    list.retain(|id| !fork.contains(id) || other.contains(id));
+    for id in other.iter() {
+        if !fork.contains(id) && !list.contains(id) {
+            list.push(id.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ClientTrust;
+    use crate::chunkid::ChunkId;
+
+    fn ids(names: &[&str]) -> Vec<ChunkId> {
+        names.iter().map(|s| ChunkId::recreate(s)).collect()
+    }
+
+    fn version(
+        id: &str,
+        previous: Option<&str>,
+        timestamp: &str,
+        backups: &[&str],
+    ) -> (ChunkId, ClientTrust) {
+        (
+            ChunkId::recreate(id),
+            ClientTrust::new(
+                "test",
+                previous.map(ChunkId::recreate),
+                timestamp.to_string(),
+                ids(backups),
+            ),
+        )
+    }
+
+    #[test]
+    fn replaces_backup_in_place() {
+        let (_, mut trust) = version("t1", None, "1", &["a", "b", "c"]);
+        assert!(trust.replace_backup(&ChunkId::recreate("b"), &ChunkId::recreate("x")));
+        assert_eq!(trust.backups(), ids(&["a", "x", "c"]));
+        assert!(!trust.replace_backup(&ChunkId::recreate("b"), &ChunkId::recreate("y")));
+    }
+
+    #[test]
+    fn pins_and_unpins_backup() {
+        let (_, mut trust) = version("t1", None, "1", &["a", "b"]);
+        let a = ChunkId::recreate("a");
+        assert!(trust.pin(&a));
+        assert!(!trust.pin(&a));
+        assert!(trust.is_pinned(&a));
+        assert!(!trust.is_pinned(&ChunkId::recreate("b")));
+        assert!(trust.unpin(&a));
+        assert!(!trust.unpin(&a));
+        assert!(trust.pinned().is_empty());
+    }
+
+    #[test]
+    fn replacing_pinned_backup_pins_replacement() {
+        let (_, mut trust) = version("t1", None, "1", &["a", "b"]);
+        trust.pin(&ChunkId::recreate("a"));
+        trust.replace_backup(&ChunkId::recreate("a"), &ChunkId::recreate("x"));
+        assert_eq!(trust.pinned(), ids(&["x"]));
+    }
+
+    #[test]
+    fn reads_trust_without_pins() {
+        // Trust roots from before pinning have no list of pins.
+        let (_, trust) = version("t1", None, "1", &["a"]);
+        let mut json = serde_json::to_value(&trust).unwrap();
+        json.as_object_mut().unwrap().remove("pinned");
+        let trust: ClientTrust = serde_json::from_value(json).unwrap();
+        assert!(trust.pinned().is_empty());
+    }
+
+    #[test]
+    fn merges_concurrent_pins() {
+        let (id1, mut t1) = version("t1", None, "1", &["a", "b", "c"]);
+        t1.pin(&ChunkId::recreate("a"));
+        // One client pinned a backup, the other unpinned one.
+        let (id2, mut t2) = version("t2", Some("t1"), "2", &["a", "b", "c"]);
+        t2.pinned = ids(&["a", "b"]);
+        let (id3, t3) = version("t3", Some("t1"), "3", &["a", "b", "c"]);
+        let merged = ClientTrust::merge(&[(id1, t1), (id2, t2), (id3, t3)]).unwrap();
+        assert_eq!(merged.pinned(), ids(&["b"]));
+    }
+
+    #[test]
+    fn pin_of_concurrently_removed_backup_is_dropped() {
+        let (id1, t1) = version("t1", None, "1", &["a", "b"]);
+        let (id2, mut t2) = version("t2", Some("t1"), "2", &["a", "b"]);
+        t2.pin(&ChunkId::recreate("a"));
+        let (id3, t3) = version("t3", Some("t1"), "3", &["b"]);
+        let merged = ClientTrust::merge(&[(id1, t1), (id2, t2), (id3, t3)]).unwrap();
+        assert_eq!(merged.backups(), ids(&["b"]));
+        assert!(merged.pinned().is_empty());
+    }
+
+    #[test]
+    fn merge_of_nothing_is_nothing() {
+        assert!(ClientTrust::merge(&[]).is_none());
+    }
+
+    #[test]
+    fn merge_of_linear_history_is_latest() {
+        let versions = vec![
+            version("t1", None, "1", &["a"]),
+            version("t2", Some("t1"), "2", &["a", "b"]),
+        ];
+        assert_eq!(ClientTrust::heads(&versions), ids(&["t2"]));
+        let merged = ClientTrust::merge(&versions).unwrap();
+        assert_eq!(merged.previous_version(), Some(ChunkId::recreate("t2")));
+        assert!(merged.merged().is_empty());
+        assert_eq!(merged.backups(), ids(&["a", "b"]));
+    }
+
+    #[test]
+    fn merges_concurrent_updates() {
+        let versions = vec![
+            version("t1", None, "1", &["a", "b"]),
+            // One client removed a backup and added one, the other
+            // added one, both based on the same version.
+            version("t2", Some("t1"), "2", &["b", "c"]),
+            version("t3", Some("t1"), "3", &["a", "b", "d"]),
+        ];
+        assert_eq!(ClientTrust::heads(&versions), ids(&["t2", "t3"]));
+        let merged = ClientTrust::merge(&versions).unwrap();
+        assert_eq!(merged.previous_version(), Some(ChunkId::recreate("t3")));
+        assert_eq!(merged.merged(), ids(&["t2"]));
+        assert_eq!(merged.backups(), ids(&["b", "d", "c"]));
+    }
+
+    #[test]
+    fn merges_concurrent_first_versions() {
+        let versions = vec![
+            version("t1", None, "1", &["a"]),
+            version("t2", None, "2", &["b"]),
+        ];
+        let merged = ClientTrust::merge(&versions).unwrap();
+        assert_eq!(merged.previous_version(), Some(ChunkId::recreate("t2")));
+        assert_eq!(merged.merged(), ids(&["t1"]));
+        assert_eq!(merged.backups(), ids(&["b", "a"]));
+    }
+
+    #[test]
+    fn unlinked_old_versions_are_superseded() {
+        let versions = vec![
+            version("t1", None, "1", &["a"]),
+            version("t2", None, "2", &["a", "b"]),
+            version("t3", None, "3", &["a", "b", "c"]),
+        ];
+        assert_eq!(ClientTrust::heads(&versions), ids(&["t3"]));
+    }
+
+    #[test]
+    fn unlinked_versions_before_recorded_history_are_superseded() {
+        // The first version to record its previous version was based
+        // on t2, which already had all that t1 had. The backup it then
+        // removed mustn't come back from t1.
+        let versions = vec![
+            version("t1", None, "1", &["a"]),
+            version("t2", None, "2", &["a", "b"]),
+            version("t3", Some("t2"), "3", &["b"]),
+        ];
+        assert_eq!(ClientTrust::heads(&versions), ids(&["t3"]));
+        let merged = ClientTrust::merge(&versions).unwrap();
+        assert!(merged.merged().is_empty());
+        assert_eq!(merged.backups(), ids(&["b"]));
+    }
+
+    #[test]
+    fn unlinked_version_after_recorded_history_is_a_head() {
+        // An older client updated the trust root after t2, without
+        // recording what it was based on.
+        let versions = vec![
+            version("t1", None, "1", &["a"]),
+            version("t2", Some("t1"), "2", &["a", "b"]),
+            version("t3", None, "3", &["a", "c"]),
+            version("t4", Some("t2"), "4", &["a", "b", "d"]),
+        ];
+        assert_eq!(ClientTrust::heads(&versions), ids(&["t3", "t4"]));
+    }
+
+    #[test]
+    fn merged_version_is_not_a_head() {
+        let (id, mut t4) = version("t4", Some("t3"), "4", &["b", "d", "c"]);
+        t4.merged = ids(&["t2"]);
+        let versions = vec![
+            version("t1", None, "1", &["a", "b"]),
+            version("t2", Some("t1"), "2", &["b", "c"]),
+            version("t3", Some("t1"), "3", &["a", "b", "d"]),
+            (id, t4),
+        ];
+        assert_eq!(ClientTrust::heads(&versions), ids(&["t4"]));
+    }
 }
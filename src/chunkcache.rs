@@ -0,0 +1,328 @@
+//! Local cache of chunks fetched from the server.
+//!
+//! Restoring reads the same chunks again and again, when files share
+//! content, or when the same backup is restored more than once.
+//! Fetched chunks are kept in a local cache directory, and reused,
+//! instead of fetching them from the server again.
+//!
+//! The cache has a maximum size. When it's exceeded, the chunks used
+//! least recently are removed. Using a chunk in the cache updates its
+//! file's modification time, which is what tells how recently it was
+//! used.
+//!
+//! The cached chunks are backed up data, so they're encrypted with the
+//! client's encryption key, bound to their chunk identifier, so that a
+//! cached chunk can't be used in place of another.
+
+use crate::chunk::DataChunk;
+use crate::chunkid::ChunkId;
+use crate::cipher::{CipherEngine, CipherError};
+use log::debug;
+use std::ffi::CString;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+
+// Start of a cache file, to recognize the format.
+const MAGIC: &[u8] = b"obnam-chunk-cache-v1\n";
+
+// Suffix of the names of cache files.
+const SUFFIX: &str = ".chunk";
+
+/// A size-limited cache of chunks.
+#[derive(Debug, Clone)]
+pub struct ChunkCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    // Total size of the cache files, once it's known.
+    bytes: Arc<Mutex<Option<u64>>>,
+}
+
+/// Possible errors from using the chunk cache.
+#[derive(Debug, thiserror::Error)]
+pub enum ChunkCacheError {
+    /// Couldn't read or write a file.
+    #[error("chunk cache: failed to use {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    /// A cache file isn't in the expected format.
+    #[error("chunk cache: {0} is not a valid cache file")]
+    Malformed(PathBuf),
+
+    /// A cache file couldn't be encrypted or decrypted.
+    #[error("chunk cache: {0}: {1}")]
+    Cipher(PathBuf, CipherError),
+}
+
+impl ChunkCache {
+    /// Use a cache in a directory, holding at most `max_bytes` bytes
+    /// of cached chunks. The directory is created when something is
+    /// first put in the cache.
+    pub fn new(dir: &Path, max_bytes: u64) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            bytes: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Name of a chunk's cache file. Chunk ids come from the server,
+    // so only ones that are safe as file names are cached.
+    fn filename(&self, id: &ChunkId) -> Option<PathBuf> {
+        let id = id.to_string();
+        if !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            Some(self.dir.join(format!("{}{}", id, SUFFIX)))
+        } else {
+            None
+        }
+    }
+
+    /// Get a chunk from the cache, if it's there.
+    pub fn get(
+        &self,
+        cipher: &CipherEngine,
+        id: &ChunkId,
+    ) -> Result<Option<DataChunk>, ChunkCacheError> {
+        let filename = match self.filename(id) {
+            Some(filename) => filename,
+            None => return Ok(None),
+        };
+        let malformed = || ChunkCacheError::Malformed(filename.clone());
+
+        let data = match std::fs::read(&filename) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(ChunkCacheError::Io(filename, err)),
+        };
+        let rest = data.strip_prefix(MAGIC).ok_or_else(malformed)?;
+        if rest.len() < 8 {
+            return Err(malformed());
+        }
+        let (len, rest) = rest.split_at(8);
+        let len = u64::from_le_bytes(len.try_into().unwrap()) as usize;
+        if rest.len() < len {
+            return Err(malformed());
+        }
+        let (meta, ciphertext) = rest.split_at(len);
+        let chunk = cipher
+            .decrypt_chunk_with_id(ciphertext, meta, id)
+            .map_err(|err| ChunkCacheError::Cipher(filename.clone(), err))?;
+
+        touch(&filename).map_err(|err| ChunkCacheError::Io(filename.clone(), err))?;
+        debug!("using cached chunk {}", id);
+        Ok(Some(chunk))
+    }
+
+    /// Put a chunk into the cache, and remove the chunks used least
+    /// recently, if the cache is then too big.
+    pub fn put(
+        &self,
+        cipher: &CipherEngine,
+        id: &ChunkId,
+        chunk: &DataChunk,
+    ) -> Result<(), ChunkCacheError> {
+        let filename = match self.filename(id) {
+            Some(filename) => filename,
+            None => return Ok(()),
+        };
+        let io = |err| ChunkCacheError::Io(self.dir.clone(), err);
+        std::fs::create_dir_all(&self.dir).map_err(io)?;
+
+        let meta = chunk.meta().to_json_vec();
+        let enc = cipher
+            .encrypt_chunk_with_id(chunk, id)
+            .map_err(|err| ChunkCacheError::Cipher(filename.clone(), err))?;
+        let mut temp = NamedTempFile::new_in(&self.dir).map_err(io)?;
+        temp.write_all(MAGIC).map_err(io)?;
+        temp.write_all(&(meta.len() as u64).to_le_bytes())
+            .map_err(io)?;
+        temp.write_all(&meta).map_err(io)?;
+        temp.write_all(enc.ciphertext()).map_err(io)?;
+        let size = temp.as_file().metadata().map_err(io)?.len();
+        temp.persist(&filename)
+            .map_err(|err| ChunkCacheError::Io(filename.clone(), err.error))?;
+        debug!("cached chunk {} in {}", id, filename.display());
+
+        let mut bytes = self.bytes.lock().unwrap();
+        let total = match *bytes {
+            Some(total) => total + size,
+            None => self.size()?,
+        };
+        *bytes = Some(if total > self.max_bytes {
+            self.prune()?
+        } else {
+            total
+        });
+        Ok(())
+    }
+
+    /// Remove a chunk from the cache, if it's there.
+    pub fn remove(&self, id: &ChunkId) -> Result<(), ChunkCacheError> {
+        let filename = match self.filename(id) {
+            Some(filename) => filename,
+            None => return Ok(()),
+        };
+        match std::fs::remove_file(&filename) {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(ChunkCacheError::Io(filename, err)),
+        }
+    }
+
+    // The cache files, with their modification times and sizes.
+    fn cached(&self) -> Result<Vec<(std::time::SystemTime, u64, PathBuf)>, ChunkCacheError> {
+        let io = |err| ChunkCacheError::Io(self.dir.clone(), err);
+        let mut cached = vec![];
+        for entry in std::fs::read_dir(&self.dir).map_err(io)? {
+            let entry = entry.map_err(io)?;
+            if entry.file_name().to_string_lossy().ends_with(SUFFIX) {
+                // Another process may remove a file while this one
+                // looks at it.
+                if let Ok(meta) = entry.metadata() {
+                    cached.push((meta.modified().map_err(io)?, meta.len(), entry.path()));
+                }
+            }
+        }
+        Ok(cached)
+    }
+
+    // Total size of the cache files.
+    fn size(&self) -> Result<u64, ChunkCacheError> {
+        Ok(self.cached()?.iter().map(|(_, size, _)| size).sum())
+    }
+
+    // Remove the chunks used least recently, until the cache is no
+    // longer too big. Return the size of what remains.
+    fn prune(&self) -> Result<u64, ChunkCacheError> {
+        let mut cached = self.cached()?;
+        cached.sort();
+        let mut total: u64 = cached.iter().map(|(_, size, _)| size).sum();
+        for (_, size, filename) in cached {
+            if total <= self.max_bytes {
+                break;
+            }
+            debug!("removing cached chunk {}", filename.display());
+            match std::fs::remove_file(&filename) {
+                Ok(_) => (),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                Err(err) => return Err(ChunkCacheError::Io(filename, err)),
+            }
+            total -= size;
+        }
+        Ok(total)
+    }
+}
+
+// Set a file's modification time to now.
+fn touch(filename: &Path) -> std::io::Result<()> {
+    let path = CString::new(filename.as_os_str().as_bytes())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), std::ptr::null(), 0) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChunkCache, ChunkCacheError};
+    use crate::chunk::DataChunk;
+    use crate::chunkid::ChunkId;
+    use crate::chunkmeta::ChunkMeta;
+    use crate::cipher::CipherEngine;
+    use crate::label::Label;
+    use crate::passwords::Passwords;
+    use tempfile::tempdir;
+
+    fn chunk(data: &[u8]) -> DataChunk {
+        DataChunk::new(data.to_vec(), ChunkMeta::new(&Label::literal("x")))
+    }
+
+    #[test]
+    fn roundtrips_chunk() {
+        let tmp = tempdir().unwrap();
+        let cache = ChunkCache::new(&tmp.path().join("cache"), 1024 * 1024);
+        let cipher = CipherEngine::new(&Passwords::new("hunter2"));
+        let id = ChunkId::recreate("abc");
+
+        assert!(cache.get(&cipher, &id).unwrap().is_none());
+        cache.put(&cipher, &id, &chunk(b"hello")).unwrap();
+        let cached = cache.get(&cipher, &id).unwrap().unwrap();
+        assert_eq!(cached.data(), b"hello");
+        assert_eq!(cached.meta(), chunk(b"hello").meta());
+    }
+
+    #[test]
+    fn encrypts_chunks() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        let cache = ChunkCache::new(&dir, 1024 * 1024);
+        let cipher = CipherEngine::new(&Passwords::new("hunter2"));
+        cache
+            .put(&cipher, &ChunkId::recreate("abc"), &chunk(b"secret data"))
+            .unwrap();
+        let data = std::fs::read(dir.join("abc.chunk")).unwrap();
+        assert!(!data.windows(11).any(|w| w == b"secret data"));
+    }
+
+    #[test]
+    fn rejects_other_chunk() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        let cache = ChunkCache::new(&dir, 1024 * 1024);
+        let cipher = CipherEngine::new(&Passwords::new("hunter2"));
+        cache
+            .put(&cipher, &ChunkId::recreate("abc"), &chunk(b"hello"))
+            .unwrap();
+        std::fs::rename(dir.join("abc.chunk"), dir.join("def.chunk")).unwrap();
+
+        assert!(matches!(
+            cache.get(&cipher, &ChunkId::recreate("def")),
+            Err(ChunkCacheError::Cipher(_, _))
+        ));
+    }
+
+    #[test]
+    fn ignores_unsafe_chunk_ids() {
+        let tmp = tempdir().unwrap();
+        let cache = ChunkCache::new(&tmp.path().join("cache"), 1024 * 1024);
+        let cipher = CipherEngine::new(&Passwords::new("hunter2"));
+        let id = ChunkId::recreate("../abc");
+        cache.put(&cipher, &id, &chunk(b"hello")).unwrap();
+        assert!(cache.get(&cipher, &id).unwrap().is_none());
+        assert!(!tmp.path().join("abc.chunk").exists());
+    }
+
+    #[test]
+    fn removes_least_recently_used_chunks() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        let cipher = CipherEngine::new(&Passwords::new("hunter2"));
+        let data = vec![0; 1000];
+
+        // Find out how big one cached chunk is, and make room for
+        // two.
+        let probe = ChunkCache::new(&tmp.path().join("probe"), u64::MAX);
+        probe
+            .put(&cipher, &ChunkId::recreate("a"), &chunk(&data))
+            .unwrap();
+        let size = probe.size().unwrap();
+        let cache = ChunkCache::new(&dir, size * 2);
+
+        let id = |s: &str| ChunkId::recreate(s);
+        cache.put(&cipher, &id("a"), &chunk(&data)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        cache.put(&cipher, &id("b"), &chunk(&data)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(cache.get(&cipher, &id("a")).unwrap().is_some());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        cache.put(&cipher, &id("c"), &chunk(&data)).unwrap();
+
+        assert!(cache.get(&cipher, &id("a")).unwrap().is_some());
+        assert!(cache.get(&cipher, &id("b")).unwrap().is_none());
+        assert!(cache.get(&cipher, &id("c")).unwrap().is_some());
+        assert_eq!(cache.size().unwrap(), size * 2);
+    }
+}
@@ -2,17 +2,17 @@
 
 use crate::chunk::DataChunk;
 use crate::chunkmeta::ChunkMeta;
-use crate::label::{Label, LabelChecksumKind};
+use crate::label::Labeler;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
-/// Iterator over chunks in a file.
-pub struct FileChunks {
+/// Iterator over chunks in a file, or anything else that can be read.
+pub struct FileChunks<R = std::fs::File> {
     chunk_size: usize,
-    kind: LabelChecksumKind,
+    labeler: Labeler,
     buf: Vec<u8>,
     filename: PathBuf,
-    handle: std::fs::File,
+    handle: R,
 }
 
 /// Possible errors from data chunking.
@@ -23,19 +23,15 @@ pub enum ChunkerError {
     FileRead(PathBuf, std::io::Error),
 }
 
-impl FileChunks {
-    /// Create new iterator.
-    pub fn new(
-        chunk_size: usize,
-        handle: std::fs::File,
-        filename: &Path,
-        kind: LabelChecksumKind,
-    ) -> Self {
+impl<R: Read> FileChunks<R> {
+    /// Create new iterator. The filename is only used in error
+    /// messages.
+    pub fn new(chunk_size: usize, handle: R, filename: &Path, labeler: Labeler) -> Self {
         let mut buf = vec![];
         buf.resize(chunk_size, 0);
         Self {
             chunk_size,
-            kind,
+            labeler,
             buf,
             handle,
             filename: filename.to_path_buf(),
@@ -61,17 +57,14 @@ impl FileChunks {
         }
 
         let buffer = &self.buf.as_slice()[..used];
-        let hash = match self.kind {
-            LabelChecksumKind::Blake2 => Label::blake2(buffer),
-            LabelChecksumKind::Sha256 => Label::sha256(buffer),
-        };
+        let hash = self.labeler.label(buffer);
         let meta = ChunkMeta::new(&hash);
         let chunk = DataChunk::new(buffer.to_vec(), meta);
         Ok(Some(chunk))
     }
 }
 
-impl Iterator for FileChunks {
+impl<R: Read> Iterator for FileChunks<R> {
     type Item = Result<DataChunk, ChunkerError>;
 
     /// Return the next chunk, if any, or an error.
@@ -1,7 +1,8 @@
 //! The identifier for a chunk.
 //!
-//! Chunk identifiers are chosen by the server. Each chunk has a
-//! unique identifier, which isn't based on the contents of the chunk.
+//! Chunk identifiers are chosen by the server, or by the client when
+//! it uploads a chunk. Each chunk has a unique identifier, which isn't
+//! based on the contents of the chunk.
 
 use crate::label::Label;
 use rusqlite::types::ToSqlOutput;
@@ -47,6 +48,14 @@ impl ChunkId {
         ChunkId { id: s.to_string() }
     }
 
+    /// Is the identifier in the form of newly constructed ones?
+    ///
+    /// Identifiers chosen by clients must be, so that they can't be
+    /// used to, for example, construct unexpected file names.
+    pub fn is_well_formed(&self) -> bool {
+        Uuid::parse_str(&self.id).is_ok()
+    }
+
     /// Return the identifier as a slice of bytes.
     pub fn as_bytes(&self) -> &[u8] {
         self.id.as_bytes()
@@ -111,6 +120,12 @@ mod test {
         assert_ne!(id.to_string(), "")
     }
 
+    #[test]
+    fn new_is_well_formed() {
+        assert!(ChunkId::new().is_well_formed());
+        assert!(!ChunkId::recreate("../../etc/passwd").is_well_formed());
+    }
+
     #[test]
     fn never_the_same() {
         let id1 = ChunkId::new();
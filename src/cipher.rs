@@ -1,17 +1,95 @@
 //! Encryption cipher algorithms.
 
 use crate::chunk::DataChunk;
+use crate::chunkid::ChunkId;
 use crate::chunkmeta::ChunkMeta;
 use crate::passwords::Passwords;
 
 use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead, Payload};
 use aes_gcm::Aes256Gcm; // Or `Aes128Gcm`
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
 use std::str::FromStr;
 
 const CHUNK_V1: &[u8] = b"0001";
 
+// Like CHUNK_V1, but the cleartext is followed by padding, and the
+// length of the padding as a big endian u32, before it's encrypted.
+const CHUNK_V2: &[u8] = b"0002";
+
+// Like CHUNK_V2, but the associated data also includes the id of the
+// chunk, so that the server can't return one chunk in place of
+// another. The cleartext always has a padding length, even if there is
+// no padding.
+const CHUNK_V3: &[u8] = b"0003";
+
+// Separates the metadata and the chunk id in the associated data of a
+// CHUNK_V3 chunk. It can't occur in either.
+const AAD_SEPARATOR: u8 = 0;
+
+// Length of the padding length at the end of padded cleartext.
+const PADDING_LEN_SIZE: usize = 4;
+
+// Length of the authentication tag AES-GCM adds to the ciphertext.
+const TAG_SIZE: usize = 16;
+
+/// Return the largest an encrypted chunk can be, for cleartext of a
+/// given length, however it's padded.
+pub fn max_encrypted_len(len: usize) -> usize {
+    CHUNK_V1.len() + NONCE_SIZE + padme(len + PADDING_LEN_SIZE) + TAG_SIZE
+}
+
+/// How chunks are padded before they're encrypted.
+///
+/// Encryption hides the contents of a chunk, but not its length. As
+/// the last chunk of a file is usually shorter than the others, its
+/// length gives away the size of the file. Padding chunks to one of
+/// fewer possible lengths makes that harder to learn, at the cost of
+/// some storage on the server.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Padding {
+    /// Don't pad chunks.
+    None,
+
+    /// Pad chunks with the Padmé scheme. It wastes at most 12% of
+    /// the chunk size, and less for larger chunks, while leaving only
+    /// O(log log L) bits of information about the length L.
+    Padme,
+}
+
+impl Default for Padding {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl Padding {
+    // Return the length to pad data of some length to.
+    fn padded_len(self, len: usize) -> usize {
+        match self {
+            Self::None => len,
+            Self::Padme => padme(len),
+        }
+    }
+}
+
+// Padmé, from "Reducing Metadata Leakage from Encrypted Files and
+// Communication with PURBs", by Nikitin et al, 2019. The length is
+// rounded up so that only the highest bits of it, about as many as
+// there are bits in the exponent, may be non-zero.
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let e = usize::BITS - 1 - len.leading_zeros();
+    let s = u32::BITS - e.leading_zeros();
+    let last_bits = e - s;
+    let mask = (1usize << last_bits) - 1;
+    (len + mask) & !mask
+}
+
 /// An encrypted chunk.
 ///
 /// This consists of encrypted ciphertext, and un-encrypted (or
@@ -45,26 +123,66 @@ impl EncryptedChunk {
 /// An engine for encrypting and decrypting chunks.
 pub struct CipherEngine {
     cipher: Aes256Gcm,
+    padding: Padding,
 }
 
 impl CipherEngine {
     /// Create a new cipher engine using cleartext passwords.
     pub fn new(pass: &Passwords) -> Self {
+        Self::with_padding(pass, Padding::None)
+    }
+
+    /// Create a new cipher engine that pads chunks before encrypting
+    /// them.
+    ///
+    /// Padded chunks can be decrypted by any cipher engine, whatever
+    /// its padding.
+    pub fn with_padding(pass: &Passwords, padding: Padding) -> Self {
         let key = GenericArray::from_slice(pass.encryption_key());
         Self {
             cipher: Aes256Gcm::new(key),
+            padding,
         }
     }
 
     /// Encrypt a chunk.
     pub fn encrypt_chunk(&self, chunk: &DataChunk) -> Result<EncryptedChunk, CipherError> {
-        // Payload with metadata as associated data, to be encrypted.
+        let (version, padded) = match self.padding {
+            Padding::None => (CHUNK_V1, None),
+            Padding::Padme => (CHUNK_V2, Some(self.pad(chunk.data())?)),
+        };
+        let msg = padded.as_deref().unwrap_or_else(|| chunk.data());
+        self.encrypt(version, msg, chunk.meta(), None)
+    }
+
+    /// Encrypt a chunk that will be stored with a given id.
+    ///
+    /// The chunk can only be decrypted with
+    /// [`CipherEngine::decrypt_chunk_with_id`] and the same id.
+    pub fn encrypt_chunk_with_id(
+        &self,
+        chunk: &DataChunk,
+        id: &ChunkId,
+    ) -> Result<EncryptedChunk, CipherError> {
+        let padded = self.pad(chunk.data())?;
+        self.encrypt(CHUNK_V3, &padded, chunk.meta(), Some(id))
+    }
+
+    fn encrypt(
+        &self,
+        version: &[u8],
+        msg: &[u8],
+        meta: &ChunkMeta,
+        id: Option<&ChunkId>,
+    ) -> Result<EncryptedChunk, CipherError> {
+        // Payload with metadata, and the chunk id, if any, as
+        // associated data, to be encrypted.
         //
         // The metadata will be stored in cleartext after encryption.
-        let aad = chunk.meta().to_json_vec();
+        let aad = meta.to_json_vec();
         let payload = Payload {
-            msg: chunk.data(),
-            aad: &aad,
+            msg,
+            aad: &associated_data(&aad, id),
         };
 
         // Unique random key for each encryption.
@@ -79,7 +197,7 @@ impl CipherEngine {
 
         // Construct the blob to be stored on the server.
         let mut vec: Vec<u8> = vec![];
-        push_bytes(&mut vec, CHUNK_V1);
+        push_bytes(&mut vec, version);
         push_bytes(&mut vec, nonce.as_bytes());
         push_bytes(&mut vec, &ciphertext);
 
@@ -87,11 +205,62 @@ impl CipherEngine {
     }
 
     /// Decrypt a chunk.
+    ///
+    /// Chunks encrypted for a given id can't be decrypted with this.
     pub fn decrypt_chunk(&self, bytes: &[u8], meta: &[u8]) -> Result<DataChunk, CipherError> {
+        self.decrypt(bytes, meta, None, false)
+    }
+
+    /// Decrypt a chunk fetched with a given id.
+    ///
+    /// If the chunk was encrypted for a different id, decryption
+    /// fails. Chunks encrypted without an id, by older versions of
+    /// Obnam, are decrypted without checking the id.
+    pub fn decrypt_chunk_with_id(
+        &self,
+        bytes: &[u8],
+        meta: &[u8],
+        id: &ChunkId,
+    ) -> Result<DataChunk, CipherError> {
+        self.decrypt(bytes, meta, Some(id), false)
+    }
+
+    /// Decrypt a chunk fetched with a given id, which it must have
+    /// been encrypted for.
+    ///
+    /// Unlike [`CipherEngine::decrypt_chunk_with_id`], chunks
+    /// encrypted without an id are refused, as the server could
+    /// return any of them in place of any other.
+    pub fn decrypt_bound_chunk(
+        &self,
+        bytes: &[u8],
+        meta: &[u8],
+        id: &ChunkId,
+    ) -> Result<DataChunk, CipherError> {
+        self.decrypt(bytes, meta, Some(id), true)
+    }
+
+    fn decrypt(
+        &self,
+        bytes: &[u8],
+        meta: &[u8],
+        id: Option<&ChunkId>,
+        bound: bool,
+    ) -> Result<DataChunk, CipherError> {
+        if bound && !is_bound(bytes) {
+            return Err(CipherError::UnboundChunk);
+        }
+
         // Does encrypted chunk start with the right version?
-        if !bytes.starts_with(CHUNK_V1) {
+        let (padded, id) = if bytes.starts_with(CHUNK_V1) {
+            (false, None)
+        } else if bytes.starts_with(CHUNK_V2) {
+            (true, None)
+        } else if bytes.starts_with(CHUNK_V3) {
+            (true, Some(id.ok_or(CipherError::NoChunkId)?))
+        } else {
             return Err(CipherError::UnknownChunkVersion);
-        }
+        };
         let version_len = CHUNK_V1.len();
         let bytes = &bytes[version_len..];
 
@@ -102,22 +271,67 @@ impl CipherEngine {
 
         let payload = Payload {
             msg: ciphertext,
-            aad: meta,
+            aad: &associated_data(meta, id),
         };
 
         let payload = self
             .cipher
             .decrypt(nonce, payload)
             .map_err(CipherError::DecryptError)?;
-        let payload = Payload::from(payload.as_slice());
+        let data = if padded { unpad(&payload)? } else { &payload };
 
         let meta = std::str::from_utf8(meta)?;
         let meta = ChunkMeta::from_str(meta)?;
 
-        let chunk = DataChunk::new(payload.msg.to_vec(), meta);
+        let chunk = DataChunk::new(data.to_vec(), meta);
 
         Ok(chunk)
     }
+
+    // Append padding to data, and then the length of the padding.
+    fn pad(&self, data: &[u8]) -> Result<Vec<u8>, CipherError> {
+        let len = data.len() + PADDING_LEN_SIZE;
+        let padding = self.padding.padded_len(len) - len;
+        let padding_len = u32::try_from(padding).map_err(|_| CipherError::TooLong(data.len()))?;
+        let mut padded = Vec::with_capacity(len + padding);
+        padded.extend_from_slice(data);
+        padded.resize(data.len() + padding, 0);
+        padded.extend_from_slice(&padding_len.to_be_bytes());
+        Ok(padded)
+    }
+}
+
+/// Is an encrypted chunk bound to the id it was uploaded as?
+///
+/// Chunks encrypted by older versions of Obnam aren't.
+pub fn is_bound(bytes: &[u8]) -> bool {
+    bytes.starts_with(CHUNK_V3)
+}
+
+// Associated data for encrypting a chunk: the metadata, and the chunk
+// id, if the chunk is bound to one.
+fn associated_data(meta: &[u8], id: Option<&ChunkId>) -> Vec<u8> {
+    let mut aad = meta.to_vec();
+    if let Some(id) = id {
+        aad.push(AAD_SEPARATOR);
+        aad.extend_from_slice(id.as_bytes());
+    }
+    aad
+}
+
+// Remove padding added by CipherEngine::pad.
+fn unpad(padded: &[u8]) -> Result<&[u8], CipherError> {
+    let data_and_padding = padded
+        .len()
+        .checked_sub(PADDING_LEN_SIZE)
+        .ok_or(CipherError::BadPadding)?;
+    let mut padding_len = [0; PADDING_LEN_SIZE];
+    padding_len.copy_from_slice(&padded[data_and_padding..]);
+    let padding = u32::from_be_bytes(padding_len) as usize;
+    let data_len = data_and_padding
+        .checked_sub(padding)
+        .ok_or(CipherError::BadPadding)?;
+    Ok(&padded[..data_len])
 }
 
 fn push_bytes(vec: &mut Vec<u8>, bytes: &[u8]) {
@@ -147,6 +361,24 @@ pub enum CipherError {
     #[error("failed to decrypt with AES-GEM: {0}")]
     DecryptError(aes_gcm::Error),
 
+    /// The chunk is bound to a chunk id, but none was given for
+    /// decrypting it.
+    #[error("encrypted chunk can only be decrypted with its chunk id")]
+    NoChunkId,
+
+    /// The chunk isn't bound to a chunk id, but the repository
+    /// requires chunks to be.
+    #[error("encrypted chunk isn't bound to its chunk id, as the repository requires")]
+    UnboundChunk,
+
+    /// The chunk is too long to be padded.
+    #[error("chunk of {0} bytes is too long to pad")]
+    TooLong(usize),
+
+    /// The decrypted chunk's padding length is longer than the chunk.
+    #[error("decrypted chunk has malformed padding")]
+    BadPadding,
+
     /// The decryption succeeded, by data isn't valid YAML.
     #[error("failed to parse decrypted data as a DataChunk: {0}")]
     Parse(serde_yaml::Error),
@@ -192,8 +424,12 @@ impl Nonce {
 #[cfg(test)]
 mod test {
     use crate::chunk::DataChunk;
+    use crate::chunkid::ChunkId;
     use crate::chunkmeta::ChunkMeta;
-    use crate::cipher::{CipherEngine, CipherError, CHUNK_V1, NONCE_SIZE};
+    use crate::cipher::{
+        max_encrypted_len, padme, CipherEngine, CipherError, Padding, CHUNK_V1, CHUNK_V2,
+        NONCE_SIZE,
+    };
     use crate::label::Label;
     use crate::passwords::Passwords;
 
@@ -210,6 +446,20 @@ mod test {
         assert_eq!(meta_as_aad, enc.aad());
     }
 
+    #[test]
+    fn encrypted_chunk_is_at_most_max_len() {
+        let pass = Passwords::new("secret");
+        for padding in [Padding::None, Padding::Padme] {
+            let cipher = CipherEngine::with_padding(&pass, padding);
+            for len in [0, 1, 1000, 1024 * 1024] {
+                let meta = ChunkMeta::new(&Label::literal("dummy"));
+                let chunk = DataChunk::new(vec![0; len], meta);
+                let enc = cipher.encrypt_chunk(&chunk).unwrap();
+                assert!(enc.ciphertext().len() <= max_encrypted_len(len));
+            }
+        }
+    }
+
     #[test]
     fn round_trip() {
         let sum = Label::sha256(b"dummy data");
@@ -225,6 +475,98 @@ mod test {
         assert_eq!(chunk, dec);
     }
 
+    #[test]
+    fn padme_rounds_up_lengths() {
+        assert_eq!(padme(0), 0);
+        assert_eq!(padme(1), 1);
+        assert_eq!(padme(9), 10);
+        assert_eq!(padme(100), 104);
+        assert_eq!(padme(1000), 1024);
+        assert_eq!(padme(1024), 1024);
+        assert_eq!(padme(1025), 1088);
+        for len in 2..10_000 {
+            let padded = padme(len);
+            assert!(padded >= len);
+            assert!(padded - len <= len * 12 / 100 + 1);
+        }
+    }
+
+    #[test]
+    fn padded_round_trip_hides_length() {
+        let pass = Passwords::new("secret");
+        let cipher = CipherEngine::with_padding(&pass, Padding::Padme);
+        let plain = CipherEngine::new(&pass);
+
+        let mut lengths = vec![];
+        for len in [1000, 1010] {
+            let meta = ChunkMeta::new(&Label::sha256(b"dummy data"));
+            let chunk = DataChunk::new(vec![42; len], meta);
+            let enc = cipher.encrypt_chunk(&chunk).unwrap();
+            lengths.push(enc.ciphertext().len());
+
+            // Any cipher engine can decrypt padded chunks.
+            let dec = plain.decrypt_chunk(enc.ciphertext(), enc.aad()).unwrap();
+            assert_eq!(chunk, dec);
+        }
+        assert_eq!(lengths[0], lengths[1]);
+    }
+
+    #[test]
+    fn chunk_encrypted_with_id_only_decrypts_with_same_id() {
+        let meta = ChunkMeta::new(&Label::sha256(b"dummy data"));
+        let chunk = DataChunk::new("hello".as_bytes().to_vec(), meta);
+        let cipher = CipherEngine::new(&Passwords::new("secret"));
+        let id = ChunkId::new();
+
+        let enc = cipher.encrypt_chunk_with_id(&chunk, &id).unwrap();
+        let bytes = enc.ciphertext();
+        let dec = cipher.decrypt_chunk_with_id(bytes, enc.aad(), &id).unwrap();
+        assert_eq!(chunk, dec);
+
+        assert!(matches!(
+            cipher.decrypt_chunk_with_id(bytes, enc.aad(), &ChunkId::new()),
+            Err(CipherError::DecryptError(_))
+        ));
+        assert!(matches!(
+            cipher.decrypt_chunk(bytes, enc.aad()),
+            Err(CipherError::NoChunkId)
+        ));
+    }
+
+    #[test]
+    fn bound_decryption_refuses_chunk_without_id() {
+        let meta = ChunkMeta::new(&Label::sha256(b"dummy data"));
+        let chunk = DataChunk::new("hello".as_bytes().to_vec(), meta);
+        let cipher = CipherEngine::with_padding(&Passwords::new("secret"), Padding::Padme);
+
+        let enc = cipher.encrypt_chunk(&chunk).unwrap();
+        assert!(enc.ciphertext().starts_with(CHUNK_V2));
+        assert!(matches!(
+            cipher.decrypt_bound_chunk(enc.ciphertext(), enc.aad(), &ChunkId::new()),
+            Err(CipherError::UnboundChunk)
+        ));
+
+        let id = ChunkId::new();
+        let enc = cipher.encrypt_chunk_with_id(&chunk, &id).unwrap();
+        let dec = cipher
+            .decrypt_bound_chunk(enc.ciphertext(), enc.aad(), &id)
+            .unwrap();
+        assert_eq!(chunk, dec);
+    }
+
+    #[test]
+    fn decrypts_chunk_without_id_when_id_is_given() {
+        let meta = ChunkMeta::new(&Label::sha256(b"dummy data"));
+        let chunk = DataChunk::new("hello".as_bytes().to_vec(), meta);
+        let cipher = CipherEngine::new(&Passwords::new("secret"));
+
+        let enc = cipher.encrypt_chunk(&chunk).unwrap();
+        let dec = cipher
+            .decrypt_chunk_with_id(enc.ciphertext(), enc.aad(), &ChunkId::new())
+            .unwrap();
+        assert_eq!(chunk, dec);
+    }
+
     #[test]
     fn decrypt_errors_if_nonce_is_too_short() {
         let pass = Passwords::new("our little test secret");
@@ -1,25 +1,52 @@
 //! Client to the Obnam server HTTP API.
 
+use crate::backup_run::current_timestamp;
 use crate::chunk::{
     ClientTrust, ClientTrustError, DataChunk, GenerationChunk, GenerationChunkError,
 };
+use crate::chunkcache::ChunkCache;
 use crate::chunkid::ChunkId;
 use crate::chunkmeta::ChunkMeta;
 use crate::chunkstore::{ChunkStore, StoreError};
-use crate::cipher::{CipherEngine, CipherError};
-use crate::config::{ClientConfig, ClientConfigError};
+use crate::cipher::{is_bound, CipherEngine, CipherError};
+use crate::concurrency::AdaptiveConcurrency;
+use crate::config::{ClientConfig, ClientConfigError, DEFAULT_CLIENT_NAME};
+use crate::gencache::GenerationCache;
 use crate::generation::{FinishedGeneration, GenId, LocalGeneration, LocalGenerationError};
 use crate::genlist::GenerationList;
-use crate::label::Label;
-
-use log::{error, info};
+use crate::label::{Label, LabelChecksumKind, LabelKey, Labeler};
+use crate::network_stats::NetworkStats;
+use crate::reposettings::{RepositorySettings, RepositorySettingsError, REPOSITORY_SETTINGS_LABEL};
+use crate::server::{ByteRange, FreedChunks};
+
+use log::{debug, error, info, warn};
+use reqwest::StatusCode;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tempfile::tempdir;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+// How many times to merge concurrent updates of the client trust root
+// before giving up. Each merge makes a new version that other clients
+// will merge in turn, so this only needs to cover brief races.
+const MAX_TRUST_MERGES: usize = 5;
+
+// Versions of Obnam before clients had names used this name for every
+// client. Such client trust roots belong to the default client.
+const LEGACY_CLIENT_NAME: &str = "FIXME";
 
 /// Possible errors when using the server API.
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError {
+    /// The operation was cancelled.
+    #[error("cancelled")]
+    Cancelled,
+
     /// No chunk id for uploaded chunk.
     #[error("Server response claimed it had created a chunk, but lacked chunk id")]
     NoCreatedChunkId,
@@ -32,6 +59,10 @@ pub enum ClientError {
     #[error("Server does not have chunk {0}")]
     ChunkNotFound(ChunkId),
 
+    /// A chunk id prefix matches more than one chunk.
+    #[error("Chunk id prefix {0} is ambiguous: it matches more than one chunk")]
+    AmbiguousChunkId(String),
+
     /// Server does not have generation.
     #[error("Server does not have generation {0}")]
     GenerationNotFound(ChunkId),
@@ -60,6 +91,10 @@ pub enum ClientError {
     #[error(transparent)]
     ClientTrust(#[from] ClientTrustError),
 
+    /// An error regarding repository settings.
+    #[error(transparent)]
+    RepositorySettings(#[from] RepositorySettingsError),
+
     /// An error using a backup's local metadata.
     #[error(transparent)]
     LocalGenerationError(#[from] LocalGenerationError),
@@ -103,57 +138,448 @@ pub enum ClientError {
     /// Error from a chunk store.
     #[error(transparent)]
     ChunkStore(#[from] StoreError),
+
+    /// An incremental generation is, directly or indirectly, based
+    /// on itself.
+    #[error("Backup generation {0} is its own ancestor")]
+    GenerationCycle(GenId),
+
+    /// Error creating a temporary directory.
+    #[error("failed to create temporary directory: {0}")]
+    TempDir(std::io::Error),
+
+    /// No client of this name uses the server.
+    #[error("Server has no client named {0}")]
+    UnknownClient(String),
+}
+
+impl ClientError {
+    /// Did the operation fail because the server couldn't be reached,
+    /// or responded unexpectedly?
+    pub fn is_network(&self) -> bool {
+        match self {
+            Self::ReqwestError(_) | Self::ChunkExists(_) => true,
+            Self::ChunkStore(err) => err.is_network(),
+            _ => false,
+        }
+    }
+
+    /// Did the operation fail because of the configuration?
+    pub fn is_config(&self) -> bool {
+        match self {
+            Self::ClientConfigError(_) => true,
+            Self::ChunkStore(err) => err.is_config(),
+            _ => false,
+        }
+    }
+
+    /// Did the operation fail because a chunk is missing from the
+    /// server, or is corrupt?
+    pub fn is_damaged_chunk(&self) -> bool {
+        match self {
+            Self::ChunkNotFound(_) | Self::WrongChecksum(_, _, _) => true,
+            Self::ChunkStore(err) => err.is_missing_chunk(),
+            Self::CipherError(err) => matches!(
+                err,
+                CipherError::UnknownChunkVersion
+                    | CipherError::NoNonce
+                    | CipherError::DecryptError(_)
+                    | CipherError::BadPadding
+                    | CipherError::Parse(_)
+                    | CipherError::Utf8Error(_)
+                    | CipherError::JsonParse(_)
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// A client using the server, as seen from its trust root.
+#[derive(Debug)]
+pub struct ClientSummary {
+    /// Name of the client.
+    pub name: String,
+    /// Current trust root of the client.
+    pub trust: ClientTrust,
+    /// Ids of all the versions of the trust root on the server.
+    pub trust_chunks: Vec<ChunkId>,
 }
 
 /// Client for the Obnam server HTTP API.
 pub struct BackupClient {
+    client_name: String,
     store: ChunkStore,
-    cipher: CipherEngine,
+    cipher: Arc<CipherEngine>,
+    // Limits how many chunks are encrypted or decrypted at once.
+    jobs: Arc<Semaphore>,
+    label_key: LabelKey,
+    // Labels the server is known not to have chunks for, so that
+    // looking them up again isn't necessary.
+    missing: Mutex<HashSet<String>>,
+    // Does the server lack support for querying many labels at once?
+    no_bulk_exists: AtomicBool,
+    cache: Option<GenerationCache>,
+    chunk_cache: Option<ChunkCache>,
+    // Chunks uploaded by this client, in the order they were uploaded.
+    uploaded: Mutex<Vec<ChunkId>>,
+    // Do the repository settings require chunks to be bound to their
+    // ids? Not known until the first chunk that isn't is fetched.
+    bound_chunk_ids: Mutex<Option<bool>>,
 }
 
 impl BackupClient {
     /// Create a new backup client.
-    pub fn new(config: &ClientConfig) -> Result<Self, ClientError> {
-        info!("creating backup client with config: {:#?}", config);
+    pub async fn new(config: &ClientConfig) -> Result<Self, ClientError> {
+        info!(
+            "creating backup client with config: {:#?}",
+            config.redacted()
+        );
+        Self::with_store(config, ChunkStore::remote(config).await?)
+    }
+
+    /// Create a backup client that reads chunks directly from a
+    /// server's chunk directory, without the server.
+    ///
+    /// The directory is opened for reading only, so the client can
+    /// restore, but not make or change backups.
+    pub fn offline(config: &ClientConfig, dir: &Path) -> Result<Self, ClientError> {
+        info!(
+            "creating offline backup client for chunks in {}",
+            dir.display()
+        );
+        Self::with_store(config, ChunkStore::local_read_only(dir)?)
+    }
+
+    /// Create a backup client that uses a chunk directory directly,
+    /// for tests.
+    #[cfg(test)]
+    pub(crate) fn local(config: &ClientConfig, dir: &Path) -> Result<Self, ClientError> {
+        use crate::chunkstore::{Durability, Sharding};
+        Self::with_store(
+            config,
+            ChunkStore::local(dir, Durability::None, Sharding::Hash)?,
+        )
+    }
+
+    fn with_store(config: &ClientConfig, store: ChunkStore) -> Result<Self, ClientError> {
         let pass = config.passwords()?;
         Ok(Self {
-            store: ChunkStore::remote(config)?,
-            cipher: CipherEngine::new(&pass),
+            client_name: config.client_name.clone(),
+            store,
+            cipher: Arc::new(CipherEngine::with_padding(&pass, config.padding)),
+            jobs: Arc::new(Semaphore::new(config.jobs)),
+            label_key: pass.label_key(),
+            missing: Mutex::new(HashSet::new()),
+            no_bulk_exists: AtomicBool::new(false),
+            cache: config.generation_cache.as_deref().map(GenerationCache::new),
+            chunk_cache: config
+                .chunk_cache
+                .as_deref()
+                .map(|dir| ChunkCache::new(dir, config.chunk_cache_size)),
+            uploaded: Mutex::new(vec![]),
+            bound_chunk_ids: Mutex::new(None),
         })
     }
 
+    /// Return the counters for the HTTP traffic with the server.
+    pub fn network_stats(&self) -> NetworkStats {
+        self.store.network_stats()
+    }
+
+    /// Return how the limit on concurrent uploads to the server has
+    /// adapted so far, if there is one.
+    pub fn upload_concurrency(&self) -> Option<AdaptiveConcurrency> {
+        self.store.upload_concurrency()
+    }
+
+    /// Return a labeler for a kind of checksum, using this client's
+    /// key for keyed checksums.
+    pub fn labeler(&self, kind: LabelChecksumKind) -> Labeler {
+        Labeler::new(kind, self.label_key)
+    }
+
     /// Does the server have a chunk?
+    ///
+    /// If an earlier call to [`BackupClient::chunks_exist`] found
+    /// that the server doesn't have the chunk, the server isn't asked
+    /// again.
     pub async fn has_chunk(&self, meta: &ChunkMeta) -> Result<Option<ChunkId>, ClientError> {
+        if self.missing.lock().unwrap().contains(meta.label()) {
+            return Ok(None);
+        }
         let mut ids = self.store.find_by_label(meta).await?;
         Ok(ids.pop())
     }
 
+    /// Does the server have a chunk with a given id?
+    pub async fn chunk_exists(&self, chunk_id: &ChunkId) -> Result<bool, ClientError> {
+        Ok(self.store.exists(chunk_id).await?)
+    }
+
+    /// Find the chunk whose id is, or starts with, the given string.
+    ///
+    /// A prefix must match only one chunk on the server, so that a
+    /// chunk can be referred to without copying its whole id.
+    pub async fn resolve_chunk_id(&self, prefix: &str) -> Result<ChunkId, ClientError> {
+        let id = ChunkId::recreate(prefix);
+        if prefix.is_empty() {
+            return Err(ClientError::ChunkNotFound(id));
+        }
+        if self.store.exists(&id).await? {
+            return Ok(id);
+        }
+        let mut ids = self.store.find_by_id_prefix(prefix).await?;
+        match ids.len() {
+            0 => Err(ClientError::ChunkNotFound(id)),
+            1 => Ok(ids.remove(0)),
+            _ => Err(ClientError::AmbiguousChunkId(prefix.to_string())),
+        }
+    }
+
+    /// Find out which of many chunks the server has, with as few
+    /// requests as possible.
+    ///
+    /// The answers are remembered, so that [`BackupClient::has_chunk`]
+    /// can answer for missing chunks without asking the server. Return
+    /// None if the server doesn't support this.
+    pub async fn chunks_exist(
+        &self,
+        metas: &[&ChunkMeta],
+    ) -> Result<Option<Vec<bool>>, ClientError> {
+        if metas.is_empty() || self.no_bulk_exists.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let labels: Vec<String> = metas.iter().map(|m| m.label().to_string()).collect();
+        let exists = match self.store.labels_exist(&labels).await {
+            Ok(exists) => exists,
+            Err(StoreError::UnexpectedStatus(url, StatusCode::NOT_FOUND))
+            | Err(StoreError::UnexpectedStatus(url, StatusCode::METHOD_NOT_ALLOWED)) => {
+                warn!(
+                    "server doesn't support {}, looking up chunks one by one",
+                    url
+                );
+                self.no_bulk_exists.store(true, Ordering::Relaxed);
+                self.store.network_stats().retried();
+                return Ok(None);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let mut missing = self.missing.lock().unwrap();
+        for (label, exists) in labels.into_iter().zip(exists.iter()) {
+            if !exists {
+                missing.insert(label);
+            }
+        }
+        Ok(Some(exists))
+    }
+
     /// Upload a data chunk to the server.
-    pub async fn upload_chunk(&mut self, chunk: DataChunk) -> Result<ChunkId, ClientError> {
-        let enc = self.cipher.encrypt_chunk(&chunk)?;
-        let data = enc.ciphertext().to_vec();
-        let id = self.store.put(data, chunk.meta()).await?;
+    pub async fn upload_chunk(&self, chunk: DataChunk) -> Result<ChunkId, ClientError> {
+        // The client chooses the id, so that the encrypted chunk can
+        // be bound to it: the server can't then return the chunk in
+        // place of another.
+        let id = ChunkId::new();
+        let meta = chunk.meta().clone();
+        let data = {
+            let id = id.clone();
+            self.with_cipher(move |cipher| {
+                cipher
+                    .encrypt_chunk_with_id(&chunk, &id)
+                    .map(|enc| enc.ciphertext().to_vec())
+            })
+            .await?
+        };
+        let id = self.store.put(data, &meta, Some(&id)).await?;
+        self.missing.lock().unwrap().remove(meta.label());
+        self.uploaded.lock().unwrap().push(id.clone());
+        Ok(id)
+    }
+
+    /// Upload a chunk that's already encrypted, with the id it was
+    /// bound to when it was encrypted.
+    ///
+    /// This is for copying chunks between repositories that use the
+    /// same keys. If the repository already has a chunk with the id,
+    /// the result is [`StoreError::ChunkExists`].
+    pub async fn upload_encrypted_chunk(
+        &self,
+        chunk_id: &ChunkId,
+        data: Vec<u8>,
+        meta: &ChunkMeta,
+    ) -> Result<ChunkId, ClientError> {
+        let id = self.store.put(data, meta, Some(chunk_id)).await?;
+        self.missing.lock().unwrap().remove(meta.label());
+        self.uploaded.lock().unwrap().push(id.clone());
+        Ok(id)
+    }
+
+    /// Ids of the chunks this client has uploaded, oldest first.
+    pub fn uploaded_chunks(&self) -> Vec<ChunkId> {
+        self.uploaded.lock().unwrap().clone()
+    }
+
+    /// Get current client trust chunk from repository.
+    ///
+    /// If other runs of the client have updated the trust root at the
+    /// same time, their versions are merged. The result is based on
+    /// the versions it was made from, so that it can be updated and
+    /// uploaded with [`BackupClient::update_client_trust`]. If the
+    /// client has no trust root yet, return an empty one.
+    pub async fn get_client_trust(&self) -> Result<ClientTrust, ClientError> {
+        let versions = self.client_trust_versions().await?;
+        Ok(ClientTrust::merge(&versions)
+            .unwrap_or_else(|| ClientTrust::new(&self.client_name, None, "".to_string(), vec![])))
+    }
+
+    /// List all clients using the server, ordered by client name.
+    pub async fn list_clients(&self) -> Result<Vec<ClientSummary>, ClientError> {
+        let mut by_name: BTreeMap<String, Vec<(ChunkId, ClientTrust)>> = BTreeMap::new();
+        for (id, trust) in self.all_client_trust_versions().await? {
+            by_name
+                .entry(owner(&trust).to_string())
+                .or_default()
+                .push((id, trust));
+        }
+        Ok(by_name
+            .into_iter()
+            .filter_map(|(name, versions)| {
+                ClientTrust::merge(&versions).map(|trust| ClientSummary {
+                    name,
+                    trust,
+                    trust_chunks: versions.into_iter().map(|(id, _)| id).collect(),
+                })
+            })
+            .collect())
+    }
+
+    /// Remove all versions of the trust root of a client from the
+    /// server.
+    ///
+    /// The client's backups are no longer listed for anyone, but the
+    /// chunks they use remain on the server. Return the number of
+    /// chunks removed.
+    pub async fn remove_client(&self, name: &str) -> Result<usize, ClientError> {
+        let client = self
+            .list_clients()
+            .await?
+            .into_iter()
+            .find(|c| c.name == name)
+            .ok_or_else(|| ClientError::UnknownClient(name.to_string()))?;
+        for id in client.trust_chunks.iter() {
+            info!("removing client-trust {} of client {}", id, name);
+            self.store.delete(id).await?;
+        }
+        Ok(client.trust_chunks.len())
+    }
+
+    /// Upload a new version of the client trust root.
+    ///
+    /// If another client uploaded a version at the same time, based on
+    /// the same earlier version, upload a version that merges them, so
+    /// that neither client's backups are lost. Return the id of the
+    /// last version uploaded.
+    pub async fn update_client_trust(&self, trust: &ClientTrust) -> Result<ChunkId, ClientError> {
+        let mut id = self.upload_chunk(trust.to_data_chunk()?).await?;
+        for _ in 0..MAX_TRUST_MERGES {
+            let versions = self.client_trust_versions().await?;
+            if ClientTrust::heads(&versions).len() <= 1 {
+                break;
+            }
+            info!("client trust was updated concurrently, merging");
+            self.store.network_stats().retried();
+            if let Some(mut merged) = ClientTrust::merge(&versions) {
+                merged.finalize(current_timestamp());
+                id = self.upload_chunk(merged.to_data_chunk()?).await?;
+            }
+        }
         Ok(id)
     }
 
-    /// Get current client trust chunk from repository, if there is one.
-    pub async fn get_client_trust(&self) -> Result<Option<ClientTrust>, ClientError> {
-        let ids = self.find_client_trusts().await?;
-        let mut latest: Option<ClientTrust> = None;
-        for id in ids {
+    // Return all versions of the trust root of this client.
+    async fn client_trust_versions(&self) -> Result<Vec<(ChunkId, ClientTrust)>, ClientError> {
+        let mut versions = self.all_client_trust_versions().await?;
+        versions.retain(|(_, trust)| owner(trust) == self.client_name);
+        Ok(versions)
+    }
+
+    // Return all versions of the trust roots of all clients. The
+    // client name is encrypted, so all of them need to be fetched.
+    async fn all_client_trust_versions(&self) -> Result<Vec<(ChunkId, ClientTrust)>, ClientError> {
+        let mut versions = vec![];
+        for id in self.find_client_trusts().await? {
             let chunk = self.fetch_chunk(&id).await?;
-            let new = ClientTrust::from_data_chunk(&chunk)?;
-            if let Some(t) = &latest {
-                if new.timestamp() > t.timestamp() {
-                    latest = Some(new);
+            versions.push((id, ClientTrust::from_data_chunk(&chunk)?));
+        }
+        Ok(versions)
+    }
+
+    /// Get the settings of the repository, if it has any.
+    ///
+    /// Settings that can't be decrypted with this client's key are
+    /// ignored. If there are several, the newest one is used.
+    pub async fn get_repository_settings(&self) -> Result<Option<RepositorySettings>, ClientError> {
+        let latest = self.find_repository_settings().await?;
+        if let Some(settings) = &latest {
+            settings.check()?;
+        }
+        Ok(latest)
+    }
+
+    // Find the newest repository settings. They're what says whether
+    // chunks must be bound to their ids, so they're fetched without
+    // requiring that.
+    async fn find_repository_settings(&self) -> Result<Option<RepositorySettings>, ClientError> {
+        let meta = ChunkMeta::new(&Label::literal(REPOSITORY_SETTINGS_LABEL));
+        let mut latest: Option<RepositorySettings> = None;
+        for id in self.store.find_by_label(&meta).await? {
+            let (body, meta) = self.store.get(&id).await?;
+            let chunk = match self.decrypt_fetched(&id, body, meta, false).await {
+                Ok(chunk) => chunk,
+                Err(ClientError::CipherError(err)) => {
+                    debug!("ignoring repository settings {}: {}", id, err);
+                    continue;
                 }
-            } else {
-                latest = Some(new);
+                Err(err) => return Err(err),
+            };
+            let settings = RepositorySettings::from_data_chunk(&chunk)?;
+            if latest.as_ref().map(|s| s.created()) < Some(settings.created()) {
+                latest = Some(settings);
             }
         }
         Ok(latest)
     }
 
+    // Must chunks be bound to their ids, as the repository settings
+    // say? The answer is remembered, so the settings are only fetched
+    // once.
+    async fn requires_bound_chunks(&self) -> Result<bool, ClientError> {
+        if let Some(bound) = *self.bound_chunk_ids.lock().unwrap() {
+            return Ok(bound);
+        }
+        let bound = self
+            .find_repository_settings()
+            .await?
+            .map(|settings| settings.bound_chunk_ids())
+            .unwrap_or(false);
+        *self.bound_chunk_ids.lock().unwrap() = Some(bound);
+        Ok(bound)
+    }
+
+    /// Ids of all chunks with settings for the repository, including
+    /// ones this client can't decrypt.
+    pub async fn repository_settings_chunks(&self) -> Result<Vec<ChunkId>, ClientError> {
+        let meta = ChunkMeta::new(&Label::literal(REPOSITORY_SETTINGS_LABEL));
+        Ok(self.store.find_by_label(&meta).await?)
+    }
+
+    /// Upload settings for the repository.
+    pub async fn upload_repository_settings(
+        &self,
+        settings: &RepositorySettings,
+    ) -> Result<ChunkId, ClientError> {
+        self.upload_chunk(settings.to_data_chunk()?).await
+    }
+
     async fn find_client_trusts(&self) -> Result<Vec<ChunkId>, ClientError> {
         let label = Label::literal("client-trust");
         let meta = ChunkMeta::new(&label);
@@ -171,41 +597,335 @@ impl BackupClient {
         GenerationList::new(finished)
     }
 
+    /// Fetch a chunk from the server as it's stored there, without
+    /// decrypting it.
+    pub async fn fetch_encrypted_chunk(
+        &self,
+        chunk_id: &ChunkId,
+    ) -> Result<(Vec<u8>, ChunkMeta), ClientError> {
+        Ok(self.store.get(chunk_id).await?)
+    }
+
     /// Fetch a data chunk from the server, given the chunk identifier.
+    ///
+    /// If the repository settings say chunks are bound to their ids,
+    /// a chunk that isn't is refused, as the server could have
+    /// returned it in place of the one asked for.
     pub async fn fetch_chunk(&self, chunk_id: &ChunkId) -> Result<DataChunk, ClientError> {
         let (body, meta) = self.store.get(chunk_id).await?;
+        // Only chunks that aren't bound need the settings to tell if
+        // they're allowed, so a repository that only has bound chunks
+        // needn't look them up.
+        let bound = !is_bound(&body) && self.requires_bound_chunks().await?;
+        self.decrypt_fetched(chunk_id, body, meta, bound).await
+    }
+
+    // Decrypt a chunk fetched with a given id. If `bound` is true, the
+    // chunk must be bound to the id.
+    async fn decrypt_fetched(
+        &self,
+        chunk_id: &ChunkId,
+        body: Vec<u8>,
+        meta: ChunkMeta,
+        bound: bool,
+    ) -> Result<DataChunk, ClientError> {
         let meta_bytes = meta.to_json_vec();
-        let chunk = self.cipher.decrypt_chunk(&body, &meta_bytes)?;
+        let chunk_id = chunk_id.clone();
+        let chunk = self
+            .with_cipher(move |cipher| {
+                if bound {
+                    cipher.decrypt_bound_chunk(&body, &meta_bytes, &chunk_id)
+                } else {
+                    cipher.decrypt_chunk_with_id(&body, &meta_bytes, &chunk_id)
+                }
+            })
+            .await?;
 
         Ok(chunk)
     }
 
+    /// Fetch a data chunk, using the local chunk cache, if there is
+    /// one.
+    ///
+    /// This is for reading backed up data, when restoring. Chunks
+    /// that are fetched to check them, or to see if the server still
+    /// has them, should be fetched with [`BackupClient::fetch_chunk`].
+    pub async fn fetch_cached_chunk(&self, chunk_id: &ChunkId) -> Result<DataChunk, ClientError> {
+        let cache = match &self.chunk_cache {
+            Some(cache) => cache.clone(),
+            None => return self.fetch_chunk(chunk_id).await,
+        };
+
+        let id = chunk_id.clone();
+        let c = cache.clone();
+        match self.with_cipher(move |cipher| c.get(cipher, &id)).await {
+            Ok(Some(chunk)) => return Ok(chunk),
+            Ok(None) => (),
+            Err(err) => {
+                warn!("ignoring cached chunk {}: {}", chunk_id, err);
+                if let Err(err) = cache.remove(chunk_id) {
+                    warn!("{}", err);
+                }
+            }
+        }
+
+        // Failing to cache isn't an error: the chunk will just be
+        // fetched again when needed.
+        let chunk = self.fetch_chunk(chunk_id).await?;
+        let id = chunk_id.clone();
+        let copy = chunk.clone();
+        if let Err(err) = self
+            .with_cipher(move |cipher| cache.put(cipher, &id, &copy))
+            .await
+        {
+            warn!("failed to cache chunk {}: {}", chunk_id, err);
+        }
+        Ok(chunk)
+    }
+
+    // Encrypt or decrypt in a blocking background task, so that
+    // concurrent uploads and downloads can use several CPUs, but only
+    // as many at once as the configuration allows.
+    async fn with_cipher<F, T>(&self, func: F) -> T
+    where
+        F: FnOnce(&CipherEngine) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // The semaphore is never closed, so acquiring can't fail.
+        let _permit = self.jobs.acquire().await.unwrap();
+        let cipher = self.cipher.clone();
+        tokio::task::spawn_blocking(move || func(&cipher))
+            .await
+            .unwrap()
+    }
+
+    /// Size of a chunk, as stored on the server.
+    pub async fn chunk_size(&self, chunk_id: &ChunkId) -> Result<u64, ClientError> {
+        let (_, _, range) = self
+            .store
+            .get_range(chunk_id, &ByteRange::FromTo(0, 0))
+            .await?;
+        Ok(range.total)
+    }
+
+    /// Remove a chunk from the server.
+    pub async fn delete_chunk(&self, chunk_id: &ChunkId) -> Result<(), ClientError> {
+        self.store.delete(chunk_id).await?;
+        Ok(())
+    }
+
+    /// Backup generations registered with the server.
+    ///
+    /// Return None if the server doesn't keep count of which
+    /// generations use each chunk.
+    pub async fn registered_generations(&self) -> Result<Option<Vec<GenId>>, ClientError> {
+        let gens = self.store.generations().await?;
+        Ok(gens.map(|ids| ids.into_iter().map(GenId::from_chunk_id).collect()))
+    }
+
+    /// Tell the server which chunks a backup generation uses.
+    pub async fn register_generation(
+        &self,
+        gen_id: &GenId,
+        chunks: &[ChunkId],
+    ) -> Result<(), ClientError> {
+        self.store
+            .register_generation(gen_id.as_chunk_id(), chunks)
+            .await?;
+        Ok(())
+    }
+
+    /// Ask the server which chunks forgetting some registered backup
+    /// generations would free.
+    pub async fn freed_chunks(&self, gen_ids: &[GenId]) -> Result<FreedChunks, ClientError> {
+        let ids: Vec<ChunkId> = gen_ids.iter().map(|id| id.as_chunk_id().clone()).collect();
+        Ok(self.store.freed_chunks(&ids).await?)
+    }
+
+    /// Tell the server a registered backup generation is forgotten.
+    ///
+    /// The server removes the chunks no other registered generation
+    /// uses, and returns what it removed.
+    pub async fn unregister_generation(&self, gen_id: &GenId) -> Result<FreedChunks, ClientError> {
+        Ok(self
+            .store
+            .unregister_generation(gen_id.as_chunk_id())
+            .await?)
+    }
+
+    /// Ids of the chunks that make up a backup generation's metadata.
+    pub async fn generation_chunk_ids(&self, gen_id: &GenId) -> Result<Vec<ChunkId>, ClientError> {
+        let gen = self.fetch_generation_chunk(gen_id).await?;
+        Ok(gen.chunk_ids().cloned().collect())
+    }
+
     async fn fetch_generation_chunk(&self, gen_id: &GenId) -> Result<GenerationChunk, ClientError> {
         let chunk = self.fetch_chunk(gen_id.as_chunk_id()).await?;
         let gen = GenerationChunk::from_data_chunk(&chunk)?;
         Ok(gen)
     }
 
+    /// Return the parent of a backup generation, if it's incremental.
+    ///
+    /// Only the generation's own metadata is fetched, not that of its
+    /// parents.
+    pub async fn generation_parent(&self, gen_id: &GenId) -> Result<Option<GenId>, ClientError> {
+        let dir = tempdir().map_err(ClientError::TempDir)?;
+        let gen = self
+            .fetch_one_generation(
+                gen_id,
+                &dir.path().join("gen.db"),
+                &CancellationToken::new(),
+            )
+            .await?;
+        Ok(gen.missing_parent().cloned())
+    }
+
     /// Fetch a backup generation's metadata, given it's identifier.
+    ///
+    /// If the generation is incremental, the metadata of its parent
+    /// generations is fetched as well, into temporary files.
+    ///
+    /// If the generation is in the local cache, it's not downloaded.
+    /// If the operation is cancelled, the partly downloaded file is
+    /// removed.
     pub async fn fetch_generation(
         &self,
         gen_id: &GenId,
         dbname: &Path,
+        cancel: &CancellationToken,
+    ) -> Result<LocalGeneration, ClientError> {
+        let mut gen = self.fetch_one_generation(gen_id, dbname, cancel).await?;
+        let mut seen = vec![gen_id.to_string()];
+        while let Some(parent_id) = gen.missing_parent().cloned() {
+            if seen.contains(&parent_id.to_string()) {
+                return Err(ClientError::GenerationCycle(parent_id));
+            }
+            seen.push(parent_id.to_string());
+            debug!("fetching parent generation {}", parent_id);
+            let dir = tempdir().map_err(ClientError::TempDir)?;
+            let parent = self
+                .fetch_one_generation(&parent_id, &dir.path().join("parent.db"), cancel)
+                .await?;
+            gen.add_parent(parent, dir);
+        }
+        Ok(gen)
+    }
+
+    async fn fetch_one_generation(
+        &self,
+        gen_id: &GenId,
+        dbname: &Path,
+        cancel: &CancellationToken,
     ) -> Result<LocalGeneration, ClientError> {
+        if let Some(cache) = &self.cache {
+            match cache.get(&self.cipher, gen_id, dbname) {
+                Ok(true) => return Ok(LocalGeneration::open(dbname)?),
+                Ok(false) => (),
+                Err(err) => {
+                    warn!("ignoring cached generation {}: {}", gen_id, err);
+                    if let Err(err) = cache.remove(gen_id) {
+                        warn!("{}", err);
+                    }
+                }
+            }
+        }
+
         let gen = self.fetch_generation_chunk(gen_id).await?;
 
         // Fetch the SQLite file, storing it in the named file.
         let mut dbfile = File::create(dbname)
             .map_err(|err| ClientError::FileCreate(dbname.to_path_buf(), err))?;
         for id in gen.chunk_ids() {
-            let chunk = self.fetch_chunk(id).await?;
+            let chunk = tokio::select! {
+                _ = cancel.cancelled() => None,
+                chunk = self.fetch_chunk(id) => Some(chunk?),
+            };
+            let chunk = match chunk {
+                Some(chunk) => chunk,
+                None => {
+                    info!("cancelled download of generation {}", gen_id);
+                    drop(dbfile);
+                    std::fs::remove_file(dbname).ok();
+                    return Err(ClientError::Cancelled);
+                }
+            };
             dbfile
                 .write_all(chunk.data())
                 .map_err(|err| ClientError::FileWrite(dbname.to_path_buf(), err))?;
         }
         info!("downloaded generation to {}", dbname.display());
+        drop(dbfile);
+        self.cache_generation(gen_id, dbname);
 
         let gen = LocalGeneration::open(dbname)?;
         Ok(gen)
     }
+
+    /// Put a generation's metadata in the local cache, if there is one.
+    ///
+    /// Failing to cache isn't an error: the generation will just be
+    /// downloaded again when needed.
+    pub fn cache_generation(&self, gen_id: &GenId, dbname: &Path) {
+        if let Some(cache) = &self.cache {
+            if let Err(err) = cache.put(&self.cipher, gen_id, dbname) {
+                warn!("failed to cache generation {}: {}", gen_id, err);
+            }
+        }
+    }
+}
+
+// Return the name of the client a trust root belongs to.
+fn owner(trust: &ClientTrust) -> &str {
+    match trust.client_name() {
+        LEGACY_CLIENT_NAME => DEFAULT_CLIENT_NAME,
+        name => name,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ClientError;
+    use crate::backup_run::current_timestamp;
+    use crate::chunk::DataChunk;
+    use crate::chunkid::ChunkId;
+    use crate::chunkmeta::ChunkMeta;
+    use crate::cipher::{CipherEngine, CipherError, Padding};
+    use crate::label::{Label, LabelChecksumKind};
+    use crate::reposettings::RepositorySettings;
+    use crate::testing::TestRepo;
+
+    #[tokio::test]
+    async fn refuses_unbound_chunk_if_repository_binds_ids() {
+        let repo = TestRepo::new();
+        let client = repo.client();
+
+        // Store a chunk as older versions of Obnam did, without
+        // binding it to its id, as a server could in place of
+        // another chunk.
+        let meta = ChunkMeta::new(&Label::literal("old"));
+        let chunk = DataChunk::new(b"data".to_vec(), meta.clone());
+        let cipher = CipherEngine::with_padding(&repo.config.passwords().unwrap(), Padding::Padme);
+        let data = cipher.encrypt_chunk(&chunk).unwrap().ciphertext().to_vec();
+        let id = client
+            .store
+            .put(data, &meta, Some(&ChunkId::new()))
+            .await
+            .unwrap();
+
+        // Without settings, the repository may be an old one.
+        assert_eq!(client.fetch_chunk(&id).await.unwrap(), chunk);
+
+        let settings =
+            RepositorySettings::new(&repo.config, LabelChecksumKind::Sha256, current_timestamp());
+        assert!(settings.bound_chunk_ids());
+        client.upload_repository_settings(&settings).await.unwrap();
+
+        let client = repo.client();
+        assert!(matches!(
+            client.fetch_chunk(&id).await,
+            Err(ClientError::CipherError(CipherError::UnboundChunk))
+        ));
+        assert!(client.get_repository_settings().await.unwrap().is_some());
+    }
 }
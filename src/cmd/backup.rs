@@ -1,20 +1,23 @@
 //! The `backup` subcommand.
 
-use crate::backup_run::{current_timestamp, BackupRun};
-use crate::chunk::ClientTrust;
-use crate::client::BackupClient;
+use crate::api::{backup, dry_run, estimate, BackupOptions};
+use crate::cmd::{GlobalOptions, Summary};
 use crate::config::ClientConfig;
-use crate::dbgen::{schema_version, FileId, DEFAULT_SCHEMA_MAJOR};
+use crate::dbgen::FileId;
 use crate::error::ObnamError;
 use crate::generation::GenId;
-use crate::performance::{Clock, Performance};
+use crate::notify::{self, Notification, Operation};
+use crate::performance::Performance;
+use crate::problem::{summarize, Problem, ProblemCode};
+use crate::progress_sink::{JsonProgress, LogProgress, ProgressSink, PROGRESS_LOG_INTERVAL};
 use crate::schema::VersionComponent;
 
 use clap::Parser;
-use log::info;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
-use tempfile::tempdir;
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 
 /// Make a backup.
 #[derive(Debug, Parser)]
@@ -26,112 +29,314 @@ pub struct Backup {
     /// Backup schema major version to use.
     #[clap(long)]
     backup_version: Option<VersionComponent>,
+
+    /// Write progress events as JSON lines to this open file descriptor.
+    #[clap(long, conflicts_with = "progress_socket")]
+    progress_fd: Option<RawFd>,
+
+    /// Write progress events as JSON lines to this Unix domain socket.
+    #[clap(long)]
+    progress_socket: Option<PathBuf>,
+
+    /// If another Obnam run for the same client is in progress, wait
+    /// for it to finish, instead of failing.
+    #[clap(long)]
+    wait: bool,
+
+    /// Write performance measurements of the backup as JSON to this
+    /// file.
+    #[clap(long)]
+    performance_report: Option<PathBuf>,
+
+    /// Go through the files as for a backup, but don't upload
+    /// anything. Report how much new data would be uploaded.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Quickly estimate how much new data a backup would upload, by
+    /// comparing file metadata with the previous backup, without
+    /// reading any file content.
+    #[clap(long, conflicts_with = "dry_run")]
+    estimate: bool,
+
+    /// Fail if there were problems of any of these kinds, separated by
+    /// commas, or of any kind at all if none are given. The backup is
+    /// still made.
+    #[clap(long, value_name = "CODE", num_args = 0.., value_delimiter = ',')]
+    fail_on_warning: Option<Vec<ProblemCode>>,
+
+    /// Record this tag in the backup, to tell it apart from others
+    /// when listing generations.
+    #[clap(long)]
+    tag: Option<String>,
 }
 
 impl Backup {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig, perf: &mut Performance) -> Result<(), ObnamError> {
+    ///
+    /// The backup stops early if `cancel` is cancelled.
+    pub fn run(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+        perf: &mut Performance,
+        cancel: CancellationToken,
+    ) -> Result<(), ObnamError> {
         let rt = Runtime::new()?;
-        rt.block_on(self.run_async(config, perf))
+        let mut summary = Summary::default();
+        let result = rt.block_on(self.run_async(config, global, perf, cancel, &mut summary));
+        if !self.dry_run && !self.estimate {
+            notify::send(
+                &config.notify,
+                &Notification::new(
+                    &config.client_name,
+                    Operation::Backup,
+                    result.as_ref().err(),
+                    summary.to_json(),
+                ),
+            );
+        }
+        result
     }
 
     async fn run_async(
         &self,
         config: &ClientConfig,
+        global: &GlobalOptions,
         perf: &mut Performance,
+        cancel: CancellationToken,
+        summary: &mut Summary,
     ) -> Result<(), ObnamError> {
         let runtime = SystemTime::now();
 
-        let major = self.backup_version.unwrap_or(DEFAULT_SCHEMA_MAJOR);
-        let schema = schema_version(major)?;
+        let options = BackupOptions {
+            full: self.full,
+            schema_major: self.backup_version,
+            tag: self.tag.clone(),
+            progress_bars: global.progress_bars(),
+            cancel,
+            wait: self.wait,
+            ..BackupOptions::default()
+        };
+        let mut sinks: Vec<Box<dyn ProgressSink>> = self.progress_sink()?.into_iter().collect();
+        if !options.progress_bars {
+            sinks.push(Box::new(LogProgress::new(PROGRESS_LOG_INTERVAL)));
+        }
+        if self.dry_run {
+            return self.run_dry(config, global, &options, sinks, perf).await;
+        }
+        if self.estimate {
+            return self
+                .run_estimate(config, global, &options, sinks, perf)
+                .await;
+        }
+        let report = backup(config, &options, sinks, perf).await?;
+        let is_incremental = report.is_incremental;
 
-        let mut client = BackupClient::new(config)?;
-        let trust = client
-            .get_client_trust()
-            .await?
-            .or_else(|| Some(ClientTrust::new("FIXME", None, current_timestamp(), vec![])))
-            .unwrap();
-        let genlist = client.list_generations(&trust);
+        report_problems(global, summary, &report.problems)?;
+        let new_cachedir_tags = report_new_cachedir_tags(
+            config,
+            global,
+            summary,
+            is_incremental,
+            &report.new_cachedir_tags,
+        )?;
 
-        let temp = tempdir()?;
-        let oldtemp = temp.path().join("old.db");
-        let newtemp = temp.path().join("new.db");
+        add_stats(
+            summary,
+            &runtime,
+            report.file_count,
+            &report.generation_id,
+            report.problems.len(),
+        )?;
+        if report.verified_chunks > 0 {
+            summary.add("verified-chunks", report.verified_chunks);
+        }
+        summary.print(global)?;
 
-        let old_id = if self.full {
-            None
-        } else {
-            match genlist.resolve("latest") {
-                Err(_) => None,
-                Ok(old_id) => Some(old_id),
-            }
-        };
+        if let Some(filename) = &self.performance_report {
+            write_performance_report(filename, perf)?;
+        }
 
-        let (is_incremental, outcome) = if let Some(old_id) = old_id {
-            info!("incremental backup based on {}", old_id);
-            let mut run = BackupRun::incremental(config, &mut client)?;
-            let old = run.start(Some(&old_id), &oldtemp, perf).await?;
-            (
-                true,
-                run.backup_roots(config, &old, &newtemp, schema, perf)
-                    .await?,
-            )
-        } else {
-            info!("fresh backup without a previous generation");
-            let mut run = BackupRun::initial(config, &mut client)?;
-            let old = run.start(None, &oldtemp, perf).await?;
-            (
-                false,
-                run.backup_roots(config, &old, &newtemp, schema, perf)
-                    .await?,
-            )
-        };
+        if new_cachedir_tags {
+            return Err(ObnamError::NewCachedirTagsFound);
+        }
+        self.check_problems(&report.problems)
+    }
 
-        perf.start(Clock::GenerationUpload);
-        let mut trust = trust;
-        trust.append_backup(outcome.gen_id.as_chunk_id());
-        trust.finalize(current_timestamp());
-        let trust = trust.to_data_chunk()?;
-        let trust_id = client.upload_chunk(trust).await?;
-        perf.stop(Clock::GenerationUpload);
-        info!("uploaded new client-trust {}", trust_id);
-
-        for w in outcome.warnings.iter() {
-            println!("warning: {}", w);
+    async fn run_dry(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+        options: &BackupOptions,
+        sinks: Vec<Box<dyn ProgressSink>>,
+        perf: &mut Performance,
+    ) -> Result<(), ObnamError> {
+        let runtime = SystemTime::now();
+        let report = dry_run(config, options, sinks, perf).await?;
+
+        let mut summary = Summary::default();
+        report_problems(global, &mut summary, &report.problems)?;
+        let new_cachedir_tags = report_new_cachedir_tags(
+            config,
+            global,
+            &mut summary,
+            report.is_incremental,
+            &report.new_cachedir_tags,
+        )?;
+
+        let perf_report = perf.report();
+        summary.add("status", "OK (dry run, nothing was uploaded)");
+        summary.add("warnings", report.problems.len());
+        summary.add("duration", runtime.elapsed()?.as_secs());
+        summary.add("file-count", report.file_count);
+        summary.add("new-chunks", perf_report.counters.chunks_uploaded);
+        summary.add("new-bytes", perf_report.dedup.bytes_new);
+        summary.add("reused-bytes", perf_report.dedup.bytes_reused);
+        summary.print(global)?;
+
+        if let Some(filename) = &self.performance_report {
+            write_performance_report(filename, perf)?;
         }
 
-        if is_incremental && !outcome.new_cachedir_tags.is_empty() {
-            println!("New CACHEDIR.TAG files since the last backup:");
-            for t in &outcome.new_cachedir_tags {
-                println!("- {:?}", t);
-            }
-            println!("You can configure Obnam to ignore all such files by setting `exclude_cache_tag_directories` to `false`.");
+        if new_cachedir_tags {
+            return Err(ObnamError::NewCachedirTagsFound);
         }
+        self.check_problems(&report.problems)
+    }
 
-        report_stats(
-            &runtime,
-            outcome.files_count,
-            &outcome.gen_id,
-            outcome.warnings.len(),
-        )?;
+    async fn run_estimate(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+        options: &BackupOptions,
+        sinks: Vec<Box<dyn ProgressSink>>,
+        perf: &mut Performance,
+    ) -> Result<(), ObnamError> {
+        let runtime = SystemTime::now();
+        let estimate = estimate(config, options, sinks, perf).await?;
+
+        let mut summary = Summary::default();
+        report_problems(global, &mut summary, &estimate.problems)?;
+
+        summary.add("status", "OK (estimate, nothing was uploaded)");
+        summary.add("warnings", estimate.problems.len());
+        summary.add("duration", runtime.elapsed()?.as_secs());
+        summary.add("live-files", estimate.live_files);
+        summary.add("new-files", estimate.new_files);
+        summary.add("new-bytes", estimate.new_bytes);
+        summary.add("changed-files", estimate.changed_files);
+        summary.add("changed-bytes", estimate.changed_bytes);
+        summary.add("unchanged-files", estimate.unchanged_files);
+        summary.add("excluded-files", estimate.excluded_files);
+        summary.add(
+            "estimated-bytes",
+            estimate.new_bytes + estimate.changed_bytes,
+        );
+        summary.print(global)?;
+
+        if let Some(filename) = &self.performance_report {
+            write_performance_report(filename, perf)?;
+        }
+        self.check_problems(&estimate.problems)
+    }
+
+    // Fail if there were problems that --fail-on-warning says to
+    // fail on.
+    fn check_problems(&self, problems: &[Problem]) -> Result<(), ObnamError> {
+        if let Some(codes) = &self.fail_on_warning {
+            let count = problems
+                .iter()
+                .filter(|p| codes.is_empty() || codes.contains(&p.code))
+                .count();
+            if count > 0 {
+                return Err(ObnamError::FailOnWarning(count));
+            }
+        }
+        Ok(())
+    }
 
-        if is_incremental && !outcome.new_cachedir_tags.is_empty() {
-            Err(ObnamError::NewCachedirTagsFound)
+    fn progress_sink(&self) -> Result<Option<Box<dyn ProgressSink>>, ObnamError> {
+        if let Some(fd) = self.progress_fd {
+            Ok(Some(Box::new(JsonProgress::from_fd(fd)?)))
+        } else if let Some(path) = &self.progress_socket {
+            Ok(Some(Box::new(JsonProgress::connect(path)?)))
         } else {
-            Ok(())
+            Ok(None)
         }
     }
 }
 
-fn report_stats(
+// List CACHEDIR.TAG files that aren't in the previous backup. Return
+// true if the configuration says that fails the backup. With
+// `--json`, the files are listed in the summary instead.
+fn report_new_cachedir_tags(
+    config: &ClientConfig,
+    global: &GlobalOptions,
+    summary: &mut Summary,
+    is_incremental: bool,
+    tags: &[PathBuf],
+) -> Result<bool, ObnamError> {
+    if !is_incremental || tags.is_empty() {
+        return Ok(false);
+    }
+    if global.json {
+        summary.add_json("new-cachedir-tags", &tags)?;
+        return Ok(config.fail_on_new_cachedir_tags);
+    }
+    println!("New CACHEDIR.TAG files since the last backup:");
+    for t in tags {
+        println!("- {:?}", t);
+    }
+    if config.fail_on_new_cachedir_tags {
+        println!("You can configure Obnam to back up such directories anyway by setting `cachedir_tags` to `include-anyway`, or by listing them in `cachedir_tag_allowlist`.");
+        println!(
+            "To only list new tags, without failing, set `fail_on_new_cachedir_tags` to `false`."
+        );
+    }
+    Ok(config.fail_on_new_cachedir_tags)
+}
+
+// List the problems, and count them by kind. With `--json`, they're
+// listed in the summary instead.
+fn report_problems(
+    global: &GlobalOptions,
+    summary: &mut Summary,
+    problems: &[Problem],
+) -> Result<(), ObnamError> {
+    if global.json {
+        return summary.add_json("problems", &problems);
+    }
+    for p in problems {
+        println!("warning: {}: {}", p.code, p);
+    }
+    if !problems.is_empty() {
+        println!("Warnings by kind:");
+        for (code, count) in summarize(problems) {
+            println!("- {}: {}", code, count);
+        }
+    }
+    Ok(())
+}
+
+fn write_performance_report(filename: &Path, perf: &Performance) -> Result<(), ObnamError> {
+    let mut json = serde_json::to_string_pretty(&perf.report())?;
+    json.push('\n');
+    std::fs::write(filename, json)
+        .map_err(|err| ObnamError::PerformanceReport(filename.to_path_buf(), err))
+}
+
+pub(crate) fn add_stats(
+    summary: &mut Summary,
     runtime: &SystemTime,
     file_count: FileId,
     gen_id: &GenId,
     num_warnings: usize,
 ) -> Result<(), ObnamError> {
-    println!("status: OK");
-    println!("warnings: {}", num_warnings);
-    println!("duration: {}", runtime.elapsed()?.as_secs());
-    println!("file-count: {}", file_count);
-    println!("generation-id: {}", gen_id);
+    summary.add("status", "OK");
+    summary.add("warnings", num_warnings);
+    summary.add("duration", runtime.elapsed()?.as_secs());
+    summary.add("file-count", file_count);
+    summary.add("generation-id", gen_id.to_string());
     Ok(())
 }
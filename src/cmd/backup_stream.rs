@@ -0,0 +1,89 @@
+//! The `backup-stream` subcommand.
+
+use crate::api::{backup, BackupOptions};
+use crate::cmd::backup::add_stats;
+use crate::cmd::{GlobalOptions, Summary};
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use crate::performance::Performance;
+use crate::progress_sink::{LogProgress, ProgressSink, PROGRESS_LOG_INTERVAL};
+use crate::schema::VersionComponent;
+
+use clap::Parser;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+
+/// Make a backup of data read from the standard input.
+///
+/// The data is stored under a name, as if it were a file, in a new
+/// backup that also has all the files of the latest backup. This is
+/// meant for things like database dumps, which don't need to be
+/// written to a file first. Later backups of files keep the stream,
+/// until it's replaced by another stream with the same name.
+#[derive(Debug, Parser)]
+pub struct BackupStream {
+    /// Name of the stream. It's restored as a file with this name.
+    name: PathBuf,
+
+    /// Backup schema major version to use.
+    #[clap(long)]
+    backup_version: Option<VersionComponent>,
+
+    /// If another Obnam run for the same client is in progress, wait
+    /// for it to finish, instead of failing.
+    #[clap(long)]
+    wait: bool,
+}
+
+impl BackupStream {
+    /// Run the command.
+    ///
+    /// The backup stops early if `cancel` is cancelled.
+    pub fn run(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+        perf: &mut Performance,
+        cancel: CancellationToken,
+    ) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config, global, perf, cancel))
+    }
+
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+        perf: &mut Performance,
+        cancel: CancellationToken,
+    ) -> Result<(), ObnamError> {
+        let runtime = SystemTime::now();
+        let options = BackupOptions {
+            schema_major: self.backup_version,
+            cancel,
+            stream: Some(self.name.clone()),
+            progress_bars: global.progress_bars(),
+            wait: self.wait,
+            ..BackupOptions::default()
+        };
+        let mut sinks: Vec<Box<dyn ProgressSink>> = vec![];
+        if !options.progress_bars {
+            sinks.push(Box::new(LogProgress::new(PROGRESS_LOG_INTERVAL)));
+        }
+        let report = backup(config, &options, sinks, perf).await?;
+        let mut summary = Summary::default();
+        add_stats(
+            &mut summary,
+            &runtime,
+            report.file_count,
+            &report.generation_id,
+            0,
+        )?;
+        if report.verified_chunks > 0 {
+            summary.add("verified-chunks", report.verified_chunks);
+        }
+        summary.print(global)
+    }
+}
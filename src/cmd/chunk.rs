@@ -1,70 +1,207 @@
 //! The `encrypt-chunk` and `decrypt-chunk` subcommands.
+//!
+//! These work on chunks as the server stores them, without talking to
+//! the server: a chunk's encrypted data is in a `.data` file, and its
+//! cleartext metadata in a `.meta` file next to it, named after the
+//! chunk id. A copy of the server's chunk directory is enough to
+//! recover chunks.
 
 use crate::chunk::DataChunk;
+use crate::chunkid::ChunkId;
 use crate::chunkmeta::ChunkMeta;
 use crate::cipher::CipherEngine;
+use crate::cmd::GlobalOptions;
 use crate::config::ClientConfig;
 use crate::error::ObnamError;
+use crate::passwords::Passwords;
 use clap::Parser;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 /// Encrypt a chunk.
 #[derive(Debug, Parser)]
 pub struct EncryptChunk {
-    /// The name of the file containing the cleartext chunk.
+    /// The name of the file containing the cleartext chunk, or "-"
+    /// for the standard input.
     filename: PathBuf,
 
-    /// Name of file where to write the encrypted chunk.
+    /// Name of file where to write the encrypted chunk, or "-" for
+    /// the standard output.
     output: PathBuf,
 
     /// Chunk metadata as JSON.
     json: String,
+
+    /// Id of the chunk, to bind the encrypted chunk to.
+    #[clap(long)]
+    chunk_id: Option<String>,
+
+    /// Identifier of the key to encrypt with. By default, the
+    /// client's key is used.
+    #[clap(long)]
+    key_id: Option<String>,
+
+    /// Also write the chunk metadata to this file, the way the server
+    /// stores it.
+    #[clap(long)]
+    meta_output: Option<PathBuf>,
 }
 
 impl EncryptChunk {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
-        let pass = config.passwords()?;
-        let cipher = CipherEngine::new(&pass);
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
+        let pass = select_key(config, self.key_id.as_deref())?;
+        let cipher = CipherEngine::with_padding(&pass, config.padding);
 
         let meta = ChunkMeta::from_json(&self.json)?;
 
-        let cleartext = std::fs::read(&self.filename)?;
+        let cleartext = read_input(&self.filename)?;
         let chunk = DataChunk::new(cleartext, meta);
-        let encrypted = cipher.encrypt_chunk(&chunk)?;
+        let encrypted = match &self.chunk_id {
+            Some(id) => cipher.encrypt_chunk_with_id(&chunk, &ChunkId::recreate(id))?,
+            None => cipher.encrypt_chunk(&chunk)?,
+        };
 
-        std::fs::write(&self.output, encrypted.ciphertext())?;
+        write_output(&self.output, encrypted.ciphertext())?;
+        if let Some(filename) = &self.meta_output {
+            write_output(filename, chunk.meta().to_json().as_bytes())?;
+        }
 
         Ok(())
     }
 }
 
 /// Decrypt a chunk.
+///
+/// If the encrypted chunk is a `.data` file from the server's chunk
+/// directory, its metadata is read from the `.meta` file next to it,
+/// and its id is taken from the file name, unless they're given.
 #[derive(Debug, Parser)]
 pub struct DecryptChunk {
-    /// Name of file containing encrypted chunk.
+    /// Name of file containing encrypted chunk, or "-" for the
+    /// standard input.
     filename: PathBuf,
 
-    /// Name of file where to write the cleartext chunk.
+    /// Name of file where to write the cleartext chunk, or "-" for
+    /// the standard output.
     output: PathBuf,
 
     /// Chunk metadata as JSON.
-    json: String,
+    #[clap(conflicts_with = "meta")]
+    json: Option<String>,
+
+    /// Read the chunk metadata from this file, as the server stores
+    /// it.
+    #[clap(long)]
+    meta: Option<PathBuf>,
+
+    /// Id of the chunk, to bind the encrypted chunk to.
+    #[clap(long)]
+    chunk_id: Option<String>,
+
+    /// Identifier of the key to decrypt with. By default, the
+    /// client's key is used.
+    #[clap(long)]
+    key_id: Option<String>,
 }
 
 impl DecryptChunk {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
-        let pass = config.passwords()?;
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
+        let pass = select_key(config, self.key_id.as_deref())?;
         let cipher = CipherEngine::new(&pass);
 
-        let meta = ChunkMeta::from_json(&self.json)?;
+        let stored = self.stored_chunk();
+        let meta = match (&self.json, &self.meta, &stored) {
+            (Some(json), _, _) => json.to_string(),
+            (None, Some(filename), _) => read_meta(filename)?,
+            (None, None, Some(data)) => read_meta(&data.with_extension("meta"))?,
+            (None, None, None) => return Err(ChunkCommandError::NoMetadata.into()),
+        };
+        let meta = ChunkMeta::from_json(&meta)?;
+        let chunk_id = match (&self.chunk_id, &stored) {
+            (Some(id), _) => Some(ChunkId::recreate(id)),
+            (None, Some(data)) => data
+                .file_stem()
+                .map(|stem| ChunkId::recreate(&stem.to_string_lossy())),
+            (None, None) => None,
+        };
+
+        let encrypted = read_input(&self.filename)?;
+        let meta = meta.to_json_vec();
+        let chunk = match &chunk_id {
+            Some(id) => cipher.decrypt_chunk_with_id(&encrypted, &meta, id)?,
+            None => cipher.decrypt_chunk(&encrypted, &meta)?,
+        };
+
+        write_output(&self.output, chunk.data())?;
 
-        let encrypted = std::fs::read(&self.filename)?;
-        let chunk = cipher.decrypt_chunk(&encrypted, &meta.to_json_vec())?;
+        Ok(())
+    }
 
-        std::fs::write(&self.output, chunk.data())?;
+    // If the encrypted chunk is a data file in a server's chunk
+    // directory, return its name.
+    fn stored_chunk(&self) -> Option<PathBuf> {
+        if self.filename.extension() == Some("data".as_ref()) {
+            Some(self.filename.clone())
+        } else {
+            None
+        }
+    }
+}
 
-        Ok(())
+/// Possible errors from the chunk encryption commands.
+#[derive(Debug, thiserror::Error)]
+pub enum ChunkCommandError {
+    /// The client doesn't have the requested key.
+    #[error("no key with id {0}: the client's key has id {1}")]
+    UnknownKey(String, String),
+
+    /// The chunk metadata wasn't given, and can't be found.
+    #[error("chunk metadata must be given, unless the chunk is a .data file with a .meta file")]
+    NoMetadata,
+
+    /// Error reading a file.
+    #[error("failed to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+
+    /// Error writing a file.
+    #[error("failed to write {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+}
+
+// Find the key to use. There's only ever the client's own key, for
+// now, but it's checked, if a specific one is asked for.
+fn select_key(config: &ClientConfig, key_id: Option<&str>) -> Result<Passwords, ObnamError> {
+    let pass = config.passwords()?;
+    match key_id {
+        Some(id) if id != pass.key_id() => {
+            Err(ChunkCommandError::UnknownKey(id.to_string(), pass.key_id()).into())
+        }
+        _ => Ok(pass),
     }
 }
+
+fn read_meta(filename: &Path) -> Result<String, ChunkCommandError> {
+    std::fs::read_to_string(filename).map_err(|err| ChunkCommandError::Read(filename.into(), err))
+}
+
+fn read_input(filename: &Path) -> Result<Vec<u8>, ChunkCommandError> {
+    let mut data = vec![];
+    let result = if filename == Path::new("-") {
+        std::io::stdin().lock().read_to_end(&mut data)
+    } else {
+        std::fs::File::open(filename).and_then(|mut file| file.read_to_end(&mut data))
+    };
+    result.map_err(|err| ChunkCommandError::Read(filename.to_path_buf(), err))?;
+    Ok(data)
+}
+
+fn write_output(filename: &Path, data: &[u8]) -> Result<(), ChunkCommandError> {
+    let result = if filename == Path::new("-") {
+        std::io::stdout().lock().write_all(data)
+    } else {
+        std::fs::write(filename, data)
+    };
+    result.map_err(|err| ChunkCommandError::Write(filename.to_path_buf(), err))
+}
@@ -1,55 +1,84 @@
 //! The `chunkify` subcommand.
 
+use crate::chunkid::ChunkId;
+use crate::chunkmeta::ChunkMeta;
+use crate::client::BackupClient;
+use crate::cmd::GlobalOptions;
 use crate::config::ClientConfig;
 use crate::engine::Engine;
 use crate::error::ObnamError;
-use crate::workqueue::WorkQueue;
+use crate::label::{Label, Labeler};
+use crate::reposettings::{Chunking, DEFAULT_CHECKSUM_KIND};
+use crate::workqueue::{WorkQueue, WorkSender};
 use clap::Parser;
+use log::info;
 use serde::Serialize;
-use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use std::path::PathBuf;
+use tempfile::NamedTempFile;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, BufReader};
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc;
-
-// Size of queue with unprocessed chunks, and also queue of computed
-// checksums.
-const Q: usize = 8;
+use tokio_util::sync::CancellationToken;
 
 /// Split files into chunks and show their metadata.
+///
+/// Files are split and checksummed as the repository settings say,
+/// the same way a backup would do it. If the repository has no
+/// settings, the client configuration is used.
 #[derive(Debug, Parser)]
 pub struct Chunkify {
     /// Names of files to split into chunks.
     filenames: Vec<PathBuf>,
+
+    /// Report how many of the chunks are already used by this backup
+    /// generation, and so wouldn't need to be uploaded again.
+    #[clap(long)]
+    compare: Option<String>,
 }
 
 impl Chunkify {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
         let rt = Runtime::new()?;
         rt.block_on(self.run_async(config))
     }
 
     async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
-        let mut q = WorkQueue::new(Q);
+        let client = BackupClient::new(config).await?;
+        let (chunk_size, kind) = match client.get_repository_settings().await? {
+            Some(settings) => match settings.chunking() {
+                Chunking::FixedSize => (settings.chunk_size(), settings.checksum_kind()),
+            },
+            None => {
+                info!("repository has no settings, using client configuration");
+                (config.chunk_size, DEFAULT_CHECKSUM_KIND)
+            }
+        };
+        let labeler = client.labeler(kind);
+
+        // The queue of unprocessed chunks, and the number of chunks
+        // checksummed at once, are as large as the number of jobs.
+        let mut q = WorkQueue::new(config.jobs);
         for filename in self.filenames.iter() {
-            tokio::spawn(split_file(
-                filename.to_path_buf(),
-                config.chunk_size,
-                q.push(),
-            ));
+            tokio::spawn(split_file(filename.to_path_buf(), chunk_size, q.push()));
         }
         q.close();
 
-        let mut summer = Engine::new(q, just_hash);
+        let mut summer = Engine::new(q, move |chunk| label_chunk(labeler, chunk));
 
         let mut checksums = vec![];
         while let Some(sum) = summer.next().await {
             checksums.push(sum);
         }
 
-        println!("{}", serde_json::to_string_pretty(&checksums)?);
+        match &self.compare {
+            None => println!("{}", serde_json::to_string_pretty(&checksums)?),
+            Some(gen_ref) => {
+                let comparison = compare(&client, gen_ref, checksums).await?;
+                println!("{}", serde_json::to_string_pretty(&comparison)?);
+            }
+        }
 
         Ok(())
     }
@@ -68,16 +97,39 @@ struct Checksum {
     offset: u64,
     pub len: u64,
     checksum: String,
+    #[serde(skip)]
+    label: Label,
+}
+
+// How the chunks of the files compare to those of a backup
+// generation.
+#[derive(Debug, Serialize)]
+struct Comparison {
+    generation: String,
+    chunks: Vec<Checksum>,
+    total_chunks: u64,
+    total_bytes: u64,
+    dedup_chunks: u64,
+    dedup_bytes: u64,
 }
 
-async fn split_file(filename: PathBuf, chunk_size: usize, tx: mpsc::Sender<Chunk>) {
+async fn split_file(filename: PathBuf, chunk_size: usize, tx: WorkSender<Chunk>) {
     // println!("split_file {}", filename.display());
     let mut file = BufReader::new(File::open(&*filename).await.unwrap());
 
     let mut offset = 0;
     loop {
+        // Fill the chunk, like a backup does, even if reads return
+        // less.
         let mut data = vec![0; chunk_size];
-        let n = file.read(&mut data).await.unwrap();
+        let mut n = 0;
+        while n < chunk_size {
+            let m = file.read(&mut data[n..]).await.unwrap();
+            if m == 0 {
+                break;
+            }
+            n += m;
+        }
         if n == 0 {
             break;
         }
@@ -96,15 +148,57 @@ async fn split_file(filename: PathBuf, chunk_size: usize, tx: mpsc::Sender<Chunk
     // println!("split_file EOF at {}", offset);
 }
 
-fn just_hash(chunk: Chunk) -> Checksum {
-    let mut hasher = Sha256::new();
-    hasher.update(&chunk.data);
-    let hash = hasher.finalize();
-    let hash = format!("{:x}", hash);
+fn label_chunk(labeler: Labeler, chunk: Chunk) -> Checksum {
+    let label = labeler.label(&chunk.data);
     Checksum {
         filename: chunk.filename,
         offset: chunk.offset,
         len: chunk.data.len() as u64,
-        checksum: hash,
+        checksum: label.checksum().to_string(),
+        label,
+    }
+}
+
+// Count the chunks that a backup generation already uses. Those are
+// the chunks a backup of the files would de-duplicate against it.
+async fn compare(
+    client: &BackupClient,
+    gen_ref: &str,
+    chunks: Vec<Checksum>,
+) -> Result<Comparison, ObnamError> {
+    let temp = NamedTempFile::new()?;
+    let trust = client.get_client_trust().await?;
+    let gen_id = client.list_generations(&trust).resolve(gen_ref)?;
+    let gen = client
+        .fetch_generation(&gen_id, temp.path(), &CancellationToken::new())
+        .await?;
+
+    let mut used: HashSet<ChunkId> = HashSet::new();
+    for file in gen.files()?.iter()? {
+        let (fileno, _, _, _) = file?;
+        for id in gen.chunkids(fileno)?.iter()? {
+            used.insert(id?);
+        }
+    }
+
+    let mut comparison = Comparison {
+        generation: gen_id.to_string(),
+        chunks: vec![],
+        total_chunks: 0,
+        total_bytes: 0,
+        dedup_chunks: 0,
+        dedup_bytes: 0,
+    };
+    for chunk in chunks {
+        comparison.total_chunks += 1;
+        comparison.total_bytes += chunk.len;
+        if let Some(id) = client.has_chunk(&ChunkMeta::new(&chunk.label)).await? {
+            if used.contains(&id) {
+                comparison.dedup_chunks += 1;
+                comparison.dedup_bytes += chunk.len;
+            }
+        }
+        comparison.chunks.push(chunk);
     }
+    Ok(comparison)
 }
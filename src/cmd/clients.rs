@@ -0,0 +1,92 @@
+//! The `clients` subcommand.
+
+use crate::client::BackupClient;
+use crate::cmd::GlobalOptions;
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use clap::Parser;
+use serde::Serialize;
+use tokio::runtime::Runtime;
+
+/// List or remove the clients using the server.
+#[derive(Debug, Parser)]
+pub struct Clients {
+    /// Remove the client with this name, so that its backups are no
+    /// longer listed. This can't be undone. A client with pinned
+    /// backups can't be removed.
+    #[clap(long, value_name = "NAME")]
+    remove: Option<String>,
+
+    /// Allow removing the client that's running the command.
+    #[clap(long, requires = "remove")]
+    force: bool,
+}
+
+impl Clients {
+    /// Run the command.
+    pub fn run(&self, config: &ClientConfig, global: &GlobalOptions) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config, global))
+    }
+
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+    ) -> Result<(), ObnamError> {
+        let client = BackupClient::new(config).await?;
+
+        if let Some(name) = &self.remove {
+            if *name == config.client_name && !self.force {
+                return Err(ObnamError::RemoveOwnClient(name.to_string()));
+            }
+            let clients = client.list_clients().await?;
+            if let Some(c) = clients.iter().find(|c| c.name == *name) {
+                let pinned = c.trust.pinned().len();
+                if pinned > 0 {
+                    return Err(ObnamError::RemovePinnedClient(name.to_string(), pinned));
+                }
+            }
+            let removed = client.remove_client(name).await?;
+            println!("removed client {} ({} chunks)", name, removed);
+            return Ok(());
+        }
+
+        let mut listed = vec![];
+        for c in client.list_clients().await? {
+            let latest = c.trust.backups().last().map(|id| id.to_string());
+            if global.json {
+                listed.push(Listed {
+                    name: c.name.clone(),
+                    latest,
+                    timestamp: c.trust.timestamp().to_string(),
+                    generations: c.trust.backups().len(),
+                    chunks: c.trust_chunks.len(),
+                });
+            } else {
+                println!(
+                    "{} {} {} generations={} chunks={}",
+                    c.name,
+                    latest.as_deref().unwrap_or("-"),
+                    c.trust.timestamp(),
+                    c.trust.backups().len(),
+                    c.trust_chunks.len(),
+                );
+            }
+        }
+        if global.json {
+            global.print_json(&listed)?;
+        }
+        Ok(())
+    }
+}
+
+// A listed client, for `--json`.
+#[derive(Debug, Serialize)]
+struct Listed {
+    name: String,
+    latest: Option<String>,
+    timestamp: String,
+    generations: usize,
+    chunks: usize,
+}
@@ -0,0 +1,165 @@
+//! The `du` subcommand.
+
+use crate::chunkid::ChunkId;
+use crate::client::BackupClient;
+use crate::cmd::GlobalOptions;
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use clap::Parser;
+use futures::stream::{StreamExt, TryStreamExt};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+
+/// Show how much space the directories in a backup use.
+///
+/// For each directory, the logical size is the total size of the
+/// files in it and its subdirectories, and the stored size is the
+/// total size of the distinct chunks those files use. A directory
+/// whose stored size is much smaller than its logical size has files
+/// with identical content. The sizes of the chunks are recorded in
+/// the backup; for backups made before that, they're asked from the
+/// server, which can be slow.
+#[derive(Debug, Parser)]
+pub struct Du {
+    /// Reference to the backup.
+    gen_ref: String,
+
+    /// Only show this directory and the ones in it.
+    path: Option<PathBuf>,
+
+    /// Only show directories at most this many levels below the
+    /// starting directory.
+    #[clap(long, value_name = "N")]
+    max_depth: Option<usize>,
+}
+
+// Sizes of a directory tree.
+#[derive(Default)]
+struct Usage {
+    logical: u64,
+    chunks: HashSet<ChunkId>,
+}
+
+// A listed directory, for `--json`.
+#[derive(Serialize)]
+struct Listed {
+    directory: PathBuf,
+    logical: u64,
+    stored: u64,
+}
+
+impl Du {
+    /// Run the command.
+    pub fn run(&self, config: &ClientConfig, global: &GlobalOptions) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config, global))
+    }
+
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+    ) -> Result<(), ObnamError> {
+        let temp = NamedTempFile::new()?;
+        let client = BackupClient::new(config).await?;
+        let trust = client.get_client_trust().await?;
+        let gen_id = client.list_generations(&trust).resolve(&self.gen_ref)?;
+        let gen = client
+            .fetch_generation(&gen_id, temp.path(), &CancellationToken::new())
+            .await?;
+
+        // Add each file's size and chunks to every directory it's in,
+        // up to the starting directory.
+        let mut usage: BTreeMap<PathBuf, Usage> = BTreeMap::new();
+        let mut sizes: HashMap<ChunkId, u64> = HashMap::new();
+        for file in gen.files()?.iter()? {
+            let (fileno, entry, _, _) = file?;
+            let path = entry.pathbuf();
+            if !entry.kind().has_content() || !self.is_wanted(&path) {
+                continue;
+            }
+            let mut ids = vec![];
+            for chunk in gen.file_chunks(fileno)?.iter()? {
+                let chunk = chunk?;
+                if let Some(length) = chunk.length() {
+                    sizes.insert(chunk.id().clone(), length);
+                }
+                ids.push(chunk.id().clone());
+            }
+            for dir in path.ancestors().skip(1) {
+                if dir.as_os_str().is_empty() || !self.is_wanted(dir) {
+                    break;
+                }
+                let u = usage.entry(dir.to_path_buf()).or_default();
+                u.logical += entry.len();
+                u.chunks.extend(ids.iter().cloned());
+            }
+        }
+
+        // Ask the server for the sizes of chunks whose size isn't
+        // recorded.
+        let unknown: HashSet<&ChunkId> = usage
+            .values()
+            .flat_map(|u| u.chunks.iter())
+            .filter(|id| !sizes.contains_key(id))
+            .collect();
+        let client = &client;
+        let asked: Vec<(ChunkId, u64)> = futures::stream::iter(unknown)
+            .map(
+                |id| async move { Ok::<_, ObnamError>((id.clone(), client.chunk_size(id).await?)) },
+            )
+            .buffer_unordered(config.jobs)
+            .try_collect()
+            .await?;
+        sizes.extend(asked);
+
+        let mut listed = vec![];
+        if !global.json {
+            println!("logical stored directory");
+        }
+        for (dir, u) in usage.iter() {
+            if let Some(max) = self.max_depth {
+                if self.depth(dir) > max {
+                    continue;
+                }
+            }
+            let stored: u64 = u.chunks.iter().map(|id| sizes[id]).sum();
+            if global.json {
+                listed.push(Listed {
+                    directory: dir.clone(),
+                    logical: u.logical,
+                    stored,
+                });
+            } else {
+                println!("{} {} {}", u.logical, stored, dir.display());
+            }
+        }
+        if global.json {
+            global.print_json(&listed)?;
+        }
+        Ok(())
+    }
+
+    // Is a path in the starting directory?
+    fn is_wanted(&self, path: &Path) -> bool {
+        match &self.path {
+            Some(start) => path.starts_with(start),
+            None => true,
+        }
+    }
+
+    // How many levels below the starting directory is a directory?
+    // Without a starting directory, the top level directories are at
+    // level zero.
+    fn depth(&self, dir: &Path) -> usize {
+        let n = dir.components().count();
+        match &self.path {
+            Some(start) => n - start.components().count(),
+            None => n - 1,
+        }
+    }
+}
@@ -0,0 +1,328 @@
+//! The `export` and `import` subcommands.
+//!
+//! An export is a tar archive with everything needed to restore one
+//! backup generation: the generation chunk, the chunks of the
+//! generation's metadata and that of the generations it's based on,
+//! and the chunks of the files in it. The chunks are stored as the
+//! server stores them, encrypted, with their metadata in a `.meta`
+//! entry before the `.data` entry. The archive starts with an index
+//! that says which generation it is, and which chunks it contains.
+
+use crate::backup_run::current_timestamp;
+use crate::chunkid::ChunkId;
+use crate::chunkmeta::ChunkMeta;
+use crate::chunkstore::StoreError;
+use crate::client::{BackupClient, ClientError};
+use crate::cmd::GlobalOptions;
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use crate::generation::GenId;
+use crate::refcount::{generation_chunks, register_generation};
+use crate::runlock::RunLock;
+use clap::Parser;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+
+// Name of the index entry in the archive. It's always the first entry.
+const INDEX_NAME: &str = "obnam-export.json";
+
+// Directory in the archive for the chunks.
+const CHUNKS_DIR: &str = "chunks";
+
+// Version of the archive format.
+const FORMAT_VERSION: u32 = 1;
+
+/// Export a backup generation to a self-contained archive file.
+///
+/// The chunks stay encrypted, so the archive can only be used by a
+/// client with the same key.
+#[derive(Debug, Parser)]
+pub struct Export {
+    /// Reference to generation to export.
+    gen_ref: String,
+
+    /// Name of the archive file to write.
+    filename: PathBuf,
+}
+
+impl Export {
+    /// Run the command.
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config))
+    }
+
+    async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+        let client = BackupClient::new(config).await?;
+        let trust = client.get_client_trust().await?;
+        let gen_id = client.list_generations(&trust).resolve(&self.gen_ref)?;
+
+        let mut ids: Vec<ChunkId> = generation_chunks(&client, &gen_id)
+            .await?
+            .into_iter()
+            .collect();
+        ids.sort_by_key(|id| id.to_string());
+        let index = ExportIndex {
+            format: FORMAT_VERSION,
+            generation: gen_id.to_string(),
+            key_id: config.passwords()?.key_id(),
+            chunks: ids.iter().map(|id| id.to_string()).collect(),
+        };
+
+        // Write to a temporary file next to the archive, so that a
+        // failed export doesn't leave a partial archive behind.
+        let write_err = |err| ExportError::Write(self.filename.clone(), err);
+        let dir = match self.filename.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let temp = NamedTempFile::new_in(dir).map_err(write_err)?;
+        let mut archive = tar::Builder::new(BufWriter::new(temp));
+        append(
+            &mut archive,
+            INDEX_NAME,
+            &serde_json::to_vec_pretty(&index)?,
+        )
+        .map_err(write_err)?;
+        for id in ids.iter() {
+            let (data, meta) = client.fetch_encrypted_chunk(id).await?;
+            let name = format!("{}/{}", CHUNKS_DIR, id);
+            append(
+                &mut archive,
+                &format!("{}.meta", name),
+                meta.to_json().as_bytes(),
+            )
+            .map_err(write_err)?;
+            append(&mut archive, &format!("{}.data", name), &data).map_err(write_err)?;
+        }
+        let temp = archive
+            .into_inner()
+            .map_err(write_err)?
+            .into_inner()
+            .map_err(|err| write_err(err.into_error()))?;
+        temp.persist(&self.filename)?;
+
+        println!(
+            "exported generation {} with {} chunks to {}",
+            gen_id,
+            ids.len(),
+            self.filename.display()
+        );
+        Ok(())
+    }
+}
+
+/// Import a backup generation from an archive made by `obnam export`.
+///
+/// The chunks are uploaded with the ids they had, and the generation
+/// is added to the client's list of backups. Chunks the repository
+/// already has aren't uploaded again, so an interrupted import can be
+/// run again.
+#[derive(Debug, Parser)]
+pub struct Import {
+    /// Name of the archive file to read.
+    filename: PathBuf,
+
+    /// If another Obnam run for the same client is in progress, wait
+    /// for it to finish, instead of failing.
+    #[clap(long)]
+    wait: bool,
+}
+
+impl Import {
+    /// Run the command.
+    pub fn run(
+        &self,
+        config: &ClientConfig,
+        _global: &GlobalOptions,
+        cancel: CancellationToken,
+    ) -> Result<(), ObnamError> {
+        let _lock = RunLock::acquire(&config.lock_filename(), self.wait, &cancel)?;
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config, &cancel))
+    }
+
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        cancel: &CancellationToken,
+    ) -> Result<(), ObnamError> {
+        let client = BackupClient::new(config).await?;
+
+        let read_err = |err| ExportError::Read(self.filename.clone(), err);
+        let file = File::open(&self.filename).map_err(read_err)?;
+        let mut archive = tar::Archive::new(BufReader::new(file));
+        let mut entries = archive.entries().map_err(read_err)?;
+
+        let index: ExportIndex = match entries.next() {
+            Some(entry) => {
+                let mut entry = entry.map_err(read_err)?;
+                if entry.path().map_err(read_err)? != Path::new(INDEX_NAME) {
+                    return Err(ExportError::NotAnExport(self.filename.clone()).into());
+                }
+                let mut json = vec![];
+                entry.read_to_end(&mut json).map_err(read_err)?;
+                serde_json::from_slice(&json)
+                    .map_err(|err| ExportError::Index(self.filename.clone(), err))?
+            }
+            None => return Err(ExportError::NotAnExport(self.filename.clone()).into()),
+        };
+        if index.format != FORMAT_VERSION {
+            return Err(ExportError::Format(self.filename.clone(), index.format).into());
+        }
+        let key_id = config.passwords()?.key_id();
+        if index.key_id != key_id {
+            return Err(ExportError::WrongKey(index.key_id, key_id).into());
+        }
+
+        let mut wanted: HashSet<String> = index.chunks.iter().cloned().collect();
+        let mut metas: HashMap<String, ChunkMeta> = HashMap::new();
+        let mut uploaded = 0;
+        let mut present = 0;
+        for entry in entries {
+            // Stopping between chunks is safe, as the import can be
+            // run again.
+            if cancel.is_cancelled() {
+                return Err(ExportError::Cancelled.into());
+            }
+            let mut entry = entry.map_err(read_err)?;
+            let name = entry.path().map_err(read_err)?.to_path_buf();
+            let (id, ext) = match chunk_entry(&name) {
+                Some((id, ext)) if wanted.contains(&id) => (id, ext),
+                _ => return Err(ExportError::UnexpectedEntry(self.filename.clone(), name).into()),
+            };
+            let mut data = vec![];
+            entry.read_to_end(&mut data).map_err(read_err)?;
+
+            if ext == "meta" {
+                let json = String::from_utf8_lossy(&data);
+                metas.insert(id, ChunkMeta::from_json(&json)?);
+                continue;
+            }
+            let meta = metas
+                .remove(&id)
+                .ok_or_else(|| ExportError::NoMetadata(id.clone()))?;
+            match client
+                .upload_encrypted_chunk(&ChunkId::recreate(&id), data, &meta)
+                .await
+            {
+                Ok(_) => uploaded += 1,
+                Err(ClientError::ChunkStore(StoreError::ChunkExists(_))) => {
+                    info!("repository already has chunk {}", id);
+                    present += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+            wanted.remove(&id);
+        }
+        if !wanted.is_empty() {
+            return Err(ExportError::MissingChunks(self.filename.clone(), wanted.len()).into());
+        }
+
+        let gen_id = GenId::from_chunk_id(ChunkId::recreate(&index.generation));
+        let mut trust = client.get_client_trust().await?;
+        if trust.backups().contains(gen_id.as_chunk_id()) {
+            info!("generation {} is already in the list of backups", gen_id);
+        } else {
+            trust.append_backup(gen_id.as_chunk_id());
+            trust.finalize(current_timestamp());
+            client.update_client_trust(&trust).await?;
+        }
+        if let Err(err) = register_generation(&client, &gen_id).await {
+            warn!(
+                "couldn't register generation {} with server: {}",
+                gen_id, err
+            );
+        }
+
+        println!(
+            "imported generation {}: {} chunks uploaded, {} already present",
+            gen_id, uploaded, present
+        );
+        Ok(())
+    }
+}
+
+/// Possible errors from exporting or importing a generation.
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    /// Error writing the archive.
+    #[error("failed to write export to {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+
+    /// Error reading the archive.
+    #[error("failed to read export from {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+
+    /// The archive doesn't start with an index.
+    #[error("{0} isn't an exported Obnam generation")]
+    NotAnExport(PathBuf),
+
+    /// The index of the archive can't be parsed.
+    #[error("failed to parse index of export {0}: {1}")]
+    Index(PathBuf, serde_json::Error),
+
+    /// The archive is of a format this version doesn't know.
+    #[error("export {0} has unknown format version {1}")]
+    Format(PathBuf, u32),
+
+    /// The archive was made by a client with another key.
+    #[error("export was made with key {0}, but the client's key is {1}")]
+    WrongKey(String, String),
+
+    /// The archive has an entry that isn't in the index.
+    #[error("export {0} has an unexpected entry {1}")]
+    UnexpectedEntry(PathBuf, PathBuf),
+
+    /// A chunk's data came before its metadata.
+    #[error("export has no metadata for chunk {0}")]
+    NoMetadata(String),
+
+    /// Chunks in the index aren't in the archive.
+    #[error("export {0} is missing {1} chunks")]
+    MissingChunks(PathBuf, usize),
+
+    /// The import was cancelled.
+    #[error("import was cancelled")]
+    Cancelled,
+}
+
+// The index of an exported generation.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportIndex {
+    format: u32,
+    generation: String,
+    key_id: String,
+    chunks: Vec<String>,
+}
+
+fn append<W: std::io::Write>(
+    archive: &mut tar::Builder<W>,
+    name: &str,
+    data: &[u8],
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    archive.append_data(&mut header, name, data)
+}
+
+// Return the chunk id and extension of a chunk entry in the archive.
+fn chunk_entry(name: &Path) -> Option<(String, String)> {
+    if name.parent() != Some(Path::new(CHUNKS_DIR)) {
+        return None;
+    }
+    let id = name.file_stem()?.to_str()?;
+    match name.extension()?.to_str()? {
+        ext @ ("meta" | "data") => Some((id.to_string(), ext.to_string())),
+        _ => None,
+    }
+}
@@ -0,0 +1,239 @@
+//! The `forget` subcommand.
+
+use crate::backup_run::current_timestamp;
+use crate::chunk::ClientTrust;
+use crate::chunkid::ChunkId;
+use crate::chunkstore::StoreError;
+use crate::client::{BackupClient, ClientError, ClientSummary};
+use crate::cmd::{GlobalOptions, Summary};
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use crate::generation::GenId;
+use crate::refcount::{generation_chunks, ChunkRefs};
+use crate::runlock::RunLock;
+use crate::server::FreedChunks;
+use clap::Parser;
+use log::info;
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+
+/// Remove backup generations, and the chunks only they use.
+///
+/// Before anything is removed, an estimate of how many chunks and how
+/// much space would be freed is printed. Chunks used by any other
+/// generation, of this or any other client, are kept. Pinned
+/// generations can't be forgotten.
+#[derive(Debug, Parser)]
+pub struct Forget {
+    /// Generations to forget, by id or "latest".
+    #[clap(required = true)]
+    gens: Vec<String>,
+
+    /// Only print the estimate, don't remove anything.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// If another Obnam run for the same client is in progress, wait
+    /// for it to finish, instead of failing.
+    #[clap(long)]
+    wait: bool,
+}
+
+impl Forget {
+    /// Run the command.
+    pub fn run(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+        cancel: CancellationToken,
+    ) -> Result<(), ObnamError> {
+        let _lock = if self.dry_run {
+            None
+        } else {
+            Some(RunLock::acquire(
+                &config.lock_filename(),
+                self.wait,
+                &cancel,
+            )?)
+        };
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config, global))
+    }
+
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+    ) -> Result<(), ObnamError> {
+        let client = BackupClient::new(config).await?;
+        let trust = client.get_client_trust().await?;
+
+        let genlist = client.list_generations(&trust);
+        let mut forgotten: Vec<GenId> = vec![];
+        for genref in self.gens.iter() {
+            let gen_id = genlist.resolve(genref)?;
+            if trust.is_pinned(gen_id.as_chunk_id()) {
+                return Err(ObnamError::PinnedGeneration(gen_id));
+            }
+            if !forgotten.contains(&gen_id) {
+                forgotten.push(gen_id);
+            }
+        }
+
+        // If the server knows which chunks every generation of every
+        // client uses, it can find the chunks to remove by itself.
+        let clients = client.list_clients().await?;
+        let registered = client.registered_generations().await?;
+        let all_registered = match &registered {
+            None => false,
+            Some(registered) => clients
+                .iter()
+                .flat_map(|c| c.trust.backups())
+                .all(|id| registered.contains(&GenId::from_chunk_id(id.clone()))),
+        };
+
+        if all_registered {
+            self.forget_registered(&client, global, trust, &forgotten)
+                .await
+        } else {
+            self.forget_unregistered(
+                &client,
+                config,
+                global,
+                trust,
+                &forgotten,
+                &clients,
+                registered.as_deref(),
+            )
+            .await
+        }
+    }
+
+    async fn forget_registered(
+        &self,
+        client: &BackupClient,
+        global: &GlobalOptions,
+        trust: ClientTrust,
+        forgotten: &[GenId],
+    ) -> Result<(), ObnamError> {
+        let freed = client.freed_chunks(forgotten).await?;
+        let mut summary = Summary::default();
+        summary.add("generations to forget", forgotten.len());
+        summary.add("chunks to remove", freed.chunks.len());
+        summary.add_bytes("space freed", freed.bytes);
+        summary.print(global)?;
+
+        if self.dry_run {
+            return Ok(());
+        }
+
+        forget_generations(client, trust, forgotten).await?;
+        let mut removed = FreedChunks::default();
+        for gen_id in forgotten.iter() {
+            removed.extend(client.unregister_generation(gen_id).await?);
+        }
+        info!("server removed {} chunks", removed.chunks.len());
+        if !global.json {
+            println!("forgot {} generations", forgotten.len());
+        }
+
+        Ok(())
+    }
+
+    // Find the chunks to remove by going through every generation of
+    // every client. If the server counts chunk references, register
+    // the generations that are kept while at it, so that next time is
+    // cheaper, and unregister the forgotten ones.
+    #[allow(clippy::too_many_arguments)]
+    async fn forget_unregistered(
+        &self,
+        client: &BackupClient,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+        trust: ClientTrust,
+        forgotten: &[GenId],
+        clients: &[ClientSummary],
+        registered: Option<&[GenId]>,
+    ) -> Result<(), ObnamError> {
+        let mut forgotten_refs = ChunkRefs::default();
+        for gen_id in forgotten.iter() {
+            forgotten_refs.add(&generation_chunks(client, gen_id).await?);
+        }
+
+        let mut kept = vec![];
+        let mut kept_refs = ChunkRefs::default();
+        for c in clients.iter() {
+            for id in c.trust.backups() {
+                let gen_id = GenId::from_chunk_id(id.clone());
+                if c.name == config.client_name && forgotten.contains(&gen_id) {
+                    continue;
+                }
+                let chunks = generation_chunks(client, &gen_id).await?;
+                kept_refs.add(&chunks);
+                kept.push((gen_id, chunks));
+            }
+        }
+
+        let freed = forgotten_refs.not_in(&kept_refs);
+        let mut bytes = 0;
+        for id in freed.iter() {
+            bytes += client.chunk_size(id).await?;
+        }
+
+        let mut summary = Summary::default();
+        summary.add("generations to forget", forgotten.len());
+        summary.add("generations kept", kept_refs.generations());
+        summary.add("chunks used by forgotten generations", forgotten_refs.len());
+        summary.add("chunks to remove", freed.len());
+        summary.add_bytes("space freed", bytes);
+        summary.print(global)?;
+
+        if self.dry_run {
+            return Ok(());
+        }
+
+        // All kept generations must be registered before any
+        // forgotten one is unregistered, or the server might remove
+        // chunks they use.
+        if registered.is_some() {
+            for (gen_id, chunks) in kept {
+                let chunks: Vec<ChunkId> = chunks.into_iter().collect();
+                client.register_generation(&gen_id, &chunks).await?;
+            }
+        }
+
+        forget_generations(client, trust, forgotten).await?;
+        if let Some(registered) = registered {
+            for gen_id in forgotten.iter().filter(|id| registered.contains(id)) {
+                client.unregister_generation(gen_id).await?;
+            }
+        }
+        for id in freed.iter() {
+            info!("removing chunk {}", id);
+            match client.delete_chunk(id).await {
+                Ok(()) | Err(ClientError::ChunkStore(StoreError::NotFound(_))) => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if !global.json {
+            println!("forgot {} generations", forgotten.len());
+        }
+
+        Ok(())
+    }
+}
+
+// Remove generations from the client's list of backups.
+async fn forget_generations(
+    client: &BackupClient,
+    mut trust: ClientTrust,
+    forgotten: &[GenId],
+) -> Result<(), ObnamError> {
+    for gen_id in forgotten.iter() {
+        info!("forgetting generation {}", gen_id);
+        trust.remove_backup(gen_id.as_chunk_id());
+    }
+    trust.finalize(current_timestamp());
+    client.update_client_trust(&trust).await?;
+    Ok(())
+}
@@ -1,24 +1,37 @@
 //! The `gen-info` subcommand.
 
-use crate::chunk::ClientTrust;
-use crate::client::BackupClient;
+use crate::chunkid::ChunkId;
+use crate::client::{BackupClient, ClientError};
+use crate::cmd::GlobalOptions;
 use crate::config::ClientConfig;
 use crate::error::ObnamError;
+use crate::generation::LocalGeneration;
+use crate::genmeta::GenerationMeta;
+use crate::label::LabelChecksumKind;
 use clap::Parser;
-use log::info;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
 use tempfile::NamedTempFile;
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 
 /// Show metadata for a generation.
 #[derive(Debug, Parser)]
 pub struct GenInfo {
     /// Reference of the generation.
     gen_ref: String,
+
+    /// Also check that every chunk the generation refers to is on the
+    /// server and has the content it should. This downloads all the
+    /// chunks.
+    #[clap(long)]
+    verify_chunks: bool,
 }
 
 impl GenInfo {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
         let rt = Runtime::new()?;
         rt.block_on(self.run_async(config))
     }
@@ -26,22 +39,152 @@ impl GenInfo {
     async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
         let temp = NamedTempFile::new()?;
 
-        let client = BackupClient::new(config)?;
+        let client = BackupClient::new(config).await?;
 
-        let trust = client
-            .get_client_trust()
-            .await?
-            .or_else(|| Some(ClientTrust::new("FIXME", None, "".to_string(), vec![])))
-            .unwrap();
+        let trust = client.get_client_trust().await?;
 
         let genlist = client.list_generations(&trust);
         let gen_id = genlist.resolve(&self.gen_ref)?;
         info!("generation id is {}", gen_id.as_chunk_id());
 
-        let gen = client.fetch_generation(&gen_id, temp.path()).await?;
+        let gen = client
+            .fetch_generation(&gen_id, temp.path(), &CancellationToken::new())
+            .await?;
         let meta = gen.meta()?;
-        println!("{}", serde_json::to_string_pretty(&meta)?);
 
+        if !self.verify_chunks {
+            println!("{}", serde_json::to_string_pretty(&meta)?);
+            return Ok(());
+        }
+
+        let verification = verify_chunks(&client, &gen, &meta).await?;
+        let damaged = verification.damaged_files.len();
+        let report = Report {
+            meta: &meta,
+            verification,
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if damaged > 0 {
+            return Err(ObnamError::DamagedBackup(gen_id, damaged));
+        }
         Ok(())
     }
 }
+
+#[derive(Serialize)]
+struct Report<'a> {
+    meta: &'a GenerationMeta,
+    verification: Verification,
+}
+
+// Result of checking the chunks of a generation.
+#[derive(Debug, Default, Serialize)]
+struct Verification {
+    files: u64,
+    healthy_files: u64,
+    chunks: u64,
+    missing_chunks: u64,
+    corrupt_chunks: u64,
+    damaged_files: Vec<FileHealth>,
+}
+
+// A file that can't be restored correctly.
+#[derive(Debug, Serialize)]
+struct FileHealth {
+    path: String,
+    chunks: u64,
+    missing: u64,
+    corrupt: u64,
+}
+
+/// Is a chunk on the server, with the content it should have?
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum ChunkHealth {
+    Healthy,
+    Missing,
+    Corrupt,
+}
+
+// Check every chunk of every file in a generation. Each chunk is
+// fetched only once, even if many files refer to it.
+async fn verify_chunks(
+    client: &BackupClient,
+    gen: &LocalGeneration,
+    meta: &GenerationMeta,
+) -> Result<Verification, ObnamError> {
+    let kind = match meta.get("checksum_kind") {
+        Some(kind) => LabelChecksumKind::from(kind)?,
+        None => LabelChecksumKind::Sha256,
+    };
+    let mut checked: HashMap<String, ChunkHealth> = HashMap::new();
+    let mut v = Verification::default();
+    for file in gen.files()?.iter()? {
+        let (fileno, entry, _, _) = file?;
+        let mut health = FileHealth {
+            path: entry.pathbuf().display().to_string(),
+            chunks: 0,
+            missing: 0,
+            corrupt: 0,
+        };
+        for id in gen.chunkids(fileno)?.iter()? {
+            let id = id?;
+            let chunk_health = match checked.get(&id.to_string()) {
+                Some(h) => *h,
+                None => {
+                    let h = check_chunk(client, &id, kind).await?;
+                    checked.insert(id.to_string(), h);
+                    v.chunks += 1;
+                    match h {
+                        ChunkHealth::Healthy => (),
+                        ChunkHealth::Missing => v.missing_chunks += 1,
+                        ChunkHealth::Corrupt => v.corrupt_chunks += 1,
+                    }
+                    h
+                }
+            };
+            health.chunks += 1;
+            match chunk_health {
+                ChunkHealth::Healthy => (),
+                ChunkHealth::Missing => health.missing += 1,
+                ChunkHealth::Corrupt => health.corrupt += 1,
+            }
+        }
+        v.files += 1;
+        if health.missing > 0 || health.corrupt > 0 {
+            warn!(
+                "{} is damaged: {} missing and {} corrupt chunks",
+                health.path, health.missing, health.corrupt
+            );
+            v.damaged_files.push(health);
+        } else {
+            v.healthy_files += 1;
+        }
+    }
+    Ok(v)
+}
+
+// Fetch a chunk and check that its content matches its label.
+pub(crate) async fn check_chunk(
+    client: &BackupClient,
+    id: &ChunkId,
+    kind: LabelChecksumKind,
+) -> Result<ChunkHealth, ObnamError> {
+    let chunk = match client.fetch_chunk(id).await {
+        Ok(chunk) => chunk,
+        Err(ClientError::ChunkStore(err)) if err.is_missing_chunk() => {
+            info!("chunk {} is missing", id);
+            return Ok(ChunkHealth::Missing);
+        }
+        Err(ClientError::CipherError(err)) => {
+            info!("chunk {} can't be decrypted: {}", id, err);
+            return Ok(ChunkHealth::Corrupt);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let actual = client.labeler(kind).label(chunk.data());
+    if actual.serialize() != chunk.meta().label() {
+        info!("chunk {} doesn't match its label", id);
+        return Ok(ChunkHealth::Corrupt);
+    }
+    Ok(ChunkHealth::Healthy)
+}
@@ -2,33 +2,113 @@
 
 use crate::chunkid::ChunkId;
 use crate::client::BackupClient;
+use crate::cmd::GlobalOptions;
 use crate::config::ClientConfig;
 use crate::error::ObnamError;
+use crate::label::Label;
+use crate::reposettings::DEFAULT_CHECKSUM_KIND;
 use clap::Parser;
+use log::info;
 use std::io::{stdout, Write};
+use std::path::PathBuf;
 use tokio::runtime::Runtime;
 
 /// Fetch a chunk from the server.
+///
+/// By default, the chunk is written as it's stored on the server,
+/// encrypted. This is useful for finding out what happened to a chunk
+/// that can't be decrypted.
 #[derive(Debug, Parser)]
 pub struct GetChunk {
-    /// Identifier of chunk to fetch.
+    /// Identifier of chunk to fetch, or a prefix of it that no other
+    /// chunk's identifier starts with.
     chunk_id: String,
+
+    /// Decrypt the chunk with the client's keys, and write its
+    /// content.
+    #[clap(long)]
+    decrypt: bool,
+
+    /// Check that the decrypted content matches the chunk's label.
+    /// The chunk is written even if it doesn't match.
+    #[clap(long, requires = "decrypt")]
+    verify: bool,
+
+    /// Write the chunk to this file, instead of the standard output.
+    #[clap(long, short)]
+    output: Option<PathBuf>,
 }
 
 impl GetChunk {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
         let rt = Runtime::new()?;
         rt.block_on(self.run_async(config))
     }
 
     async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
-        let client = BackupClient::new(config)?;
-        let chunk_id: ChunkId = self.chunk_id.parse().unwrap();
-        let chunk = client.fetch_chunk(&chunk_id).await?;
-        let stdout = stdout();
-        let mut handle = stdout.lock();
-        handle.write_all(chunk.data())?;
-        Ok(())
+        let client = BackupClient::new(config).await?;
+        let chunk_id = client.resolve_chunk_id(&self.chunk_id).await?;
+
+        let (data, mismatch) = if self.decrypt {
+            let chunk = client.fetch_chunk(&chunk_id).await?;
+            let mismatch = if self.verify {
+                let kind = match client.get_repository_settings().await? {
+                    Some(settings) => settings.checksum_kind(),
+                    None => DEFAULT_CHECKSUM_KIND,
+                };
+                let actual = client.labeler(kind).label(chunk.data()).serialize();
+                let expected = chunk.meta().label();
+                if let Ok(Label::Literal(_)) = Label::deserialize(expected) {
+                    eprintln!(
+                        "chunk {} has a literal label, which can't be verified",
+                        chunk_id
+                    );
+                    None
+                } else if actual == expected {
+                    info!("chunk {} matches its label {}", chunk_id, expected);
+                    None
+                } else {
+                    Some(GetChunkError::LabelMismatch(
+                        chunk_id.clone(),
+                        expected.to_string(),
+                        actual,
+                    ))
+                }
+            } else {
+                None
+            };
+            (chunk.data().to_vec(), mismatch)
+        } else {
+            let (data, _) = client.fetch_encrypted_chunk(&chunk_id).await?;
+            (data, None)
+        };
+
+        match &self.output {
+            Some(filename) => std::fs::write(filename, &data)
+                .map_err(|err| GetChunkError::Write(filename.to_path_buf(), err))?,
+            None => {
+                let stdout = stdout();
+                let mut handle = stdout.lock();
+                handle.write_all(&data)?;
+            }
+        }
+
+        match mismatch {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
     }
 }
+
+/// Possible errors from fetching a chunk.
+#[derive(Debug, thiserror::Error)]
+pub enum GetChunkError {
+    /// The content of the chunk doesn't match its label.
+    #[error("chunk {0} has label {1}, but its content has label {2}")]
+    LabelMismatch(ChunkId, String, String),
+
+    /// Error writing the chunk to a file.
+    #[error("failed to write chunk to {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+}
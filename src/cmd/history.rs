@@ -0,0 +1,146 @@
+//! The `history` subcommand.
+
+use crate::client::BackupClient;
+use crate::cmd::GlobalOptions;
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use crate::fsentry::FilesystemEntry;
+use chrono::{Local, TimeZone};
+use clap::Parser;
+use serde::Serialize;
+use std::path::PathBuf;
+use tempfile::NamedTempFile;
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+
+/// Show the history of a file in the backups.
+///
+/// Every backup is looked at, oldest first, and the backups where the
+/// file appeared, changed, or disappeared are listed, with the file's
+/// size, modification time, and the reason it was backed up. This
+/// requires downloading the metadata of each backup, which can be
+/// slow.
+#[derive(Debug, Parser)]
+pub struct History {
+    /// The file, named as it was when backed up.
+    path: PathBuf,
+
+    /// Also list the backups where the file didn't change.
+    #[clap(long)]
+    all: bool,
+}
+
+impl History {
+    /// Run the command.
+    pub fn run(&self, config: &ClientConfig, global: &GlobalOptions) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config, global))
+    }
+
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+    ) -> Result<(), ObnamError> {
+        let client = BackupClient::new(config).await?;
+        let trust = client.get_client_trust().await?;
+        let genlist = client.list_generations(&trust);
+
+        let mut previous: Option<FilesystemEntry> = None;
+        let mut events = vec![];
+        for finished in genlist.iter() {
+            let temp = NamedTempFile::new()?;
+            let gen = client
+                .fetch_generation(finished.id(), temp.path(), &CancellationToken::new())
+                .await?;
+            let ended = gen.meta()?.ended().unwrap_or("-").to_string();
+            let file = gen.get_backed_up_file(&self.path)?;
+
+            let event = match (&previous, &file) {
+                (None, None) => None,
+                (Some(_), None) => Some("disappeared"),
+                (None, Some(_)) => Some("appeared"),
+                (Some(old), Some(file)) if is_changed(old, file.entry()) => Some("changed"),
+                (Some(_), Some(_)) if self.all => Some("unchanged"),
+                (Some(_), Some(_)) => None,
+            };
+            if let Some(event) = event {
+                let entry = file.as_ref().map(|file| file.entry());
+                let reason = file.as_ref().map(|file| file.reason());
+                if !global.json {
+                    let described = match entry {
+                        Some(entry) => format!(" {}", describe(entry)),
+                        None => "".to_string(),
+                    };
+                    let reason = match reason {
+                        Some(reason) => match reason.renamed_from() {
+                            Some(from) => format!(" ({} from {})", reason, from.display()),
+                            None => format!(" ({})", reason),
+                        },
+                        None => "".to_string(),
+                    };
+                    println!(
+                        "{} {} {}{}{}",
+                        finished.id(),
+                        ended,
+                        event,
+                        described,
+                        reason
+                    );
+                }
+                events.push(Event {
+                    id: finished.id().to_string(),
+                    ended,
+                    event,
+                    size: entry.map(|e| e.len()),
+                    mtime: entry.map(|e| e.mtime()),
+                    reason: reason.map(|r| r.to_string()),
+                    renamed_from: reason.and_then(|r| r.renamed_from().cloned()),
+                });
+            }
+            previous = file.map(|file| file.entry().clone());
+        }
+
+        if global.json {
+            global.print_json(&events)?;
+        } else if events.is_empty() {
+            println!("{} is not in any backup", self.path.display());
+        }
+        Ok(())
+    }
+}
+
+// A backup where the file appeared, changed, or disappeared, for
+// `--json`.
+#[derive(Serialize)]
+struct Event {
+    id: String,
+    ended: String,
+    event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mtime: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    renamed_from: Option<PathBuf>,
+}
+
+// Has a file changed between backups, as far as can be told from its
+// metadata?
+fn is_changed(old: &FilesystemEntry, new: &FilesystemEntry) -> bool {
+    old.kind() != new.kind()
+        || old.len() != new.len()
+        || old.mtime() != new.mtime()
+        || old.mtime_ns() != new.mtime_ns()
+        || old.symlink_target() != new.symlink_target()
+}
+
+fn describe(e: &FilesystemEntry) -> String {
+    let mtime = match Local.timestamp_opt(e.mtime(), 0).single() {
+        Some(t) => t.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => e.mtime().to_string(),
+    };
+    format!("size={} mtime={}", e.len(), mtime)
+}
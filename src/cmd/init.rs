@@ -1,33 +1,127 @@
 //! The `init` subcommand.
 
+use crate::backup_run::current_timestamp;
+use crate::chunkmeta::ChunkMeta;
+use crate::chunkstore::ChunkStore;
+use crate::client::BackupClient;
+use crate::cmd::key::new_passphrase;
+use crate::cmd::GlobalOptions;
 use crate::config::ClientConfig;
 use crate::error::ObnamError;
-use crate::passwords::{passwords_filename, Passwords};
+use crate::label::{Label, LabelChecksumKind};
+use crate::passwords::{MasterKey, Passwords, MASTER_KEY_LEN};
+use crate::recovery;
+use crate::reposettings::{RepositorySettings, DEFAULT_CHECKSUM_KIND, REPOSITORY_SETTINGS_LABEL};
 use clap::Parser;
+use log::info;
+use std::io::BufRead;
+use tokio::runtime::Runtime;
 
-const PROMPT: &str = "Obnam passphrase: ";
-
-/// Initialize client by setting passwords.
+/// Initialize client by generating its keys, and the repository by
+/// storing its settings, unless it already has them.
 #[derive(Debug, Parser)]
 pub struct Init {
-    /// Only for testing.
+    /// Print a recovery phrase for the new keys. Write it down and
+    /// keep it safe: it's needed to restore backups if the keys are
+    /// lost.
+    #[clap(long, conflicts_with = "recover")]
+    recovery_phrase: bool,
+
+    /// Recreate keys from a recovery phrase, read from the standard
+    /// input, instead of generating new ones.
+    #[clap(long)]
+    recover: bool,
+
+    /// Kind of checksum to label chunks with, if the repository is
+    /// new: sha256, blake2, blake3, or keyed. Blake3 is fastest. Keyed
+    /// is a Blake3 checksum keyed with a secret, so that the server
+    /// can't tell what's in chunks from their labels. This can't be
+    /// changed later.
+    #[clap(long, value_parser = LabelChecksumKind::from)]
+    checksum_kind: Option<LabelChecksumKind>,
+
+    /// Save the keys without encrypting them with a passphrase, so
+    /// that no passphrase is needed to use them. Anyone who can read
+    /// the keys file can then read the backups.
     #[clap(long)]
-    insecure_passphrase: Option<String>,
+    plaintext_keys: bool,
 }
 
 impl Init {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
-        let passphrase = match &self.insecure_passphrase {
-            Some(x) => x.to_string(),
-            None => rpassword::read_password_from_tty(Some(PROMPT)).unwrap(),
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(check_server(config))?;
+
+        let master = if self.recover {
+            read_recovery_phrase()?
+        } else {
+            Passwords::generate_master_key()
         };
 
-        let passwords = Passwords::new(&passphrase);
-        let filename = passwords_filename(&config.filename);
-        passwords
-            .save(&filename)
-            .map_err(|err| ObnamError::PasswordSave(filename, err))?;
-        Ok(())
+        let passwords = Passwords::from_master_key(&master);
+        let filename = config.passwords_filename();
+        if self.plaintext_keys {
+            passwords.save(&filename)
+        } else {
+            let passphrase = new_passphrase(config)?;
+            passwords.save_encrypted(&filename, &passphrase, &config.key_derivation)
+        }
+        .map_err(|err| ObnamError::PasswordSave(filename, err))?;
+
+        if self.recovery_phrase {
+            println!("{}", recovery::encode(&master));
+        }
+
+        rt.block_on(init_repository(config, self.checksum_kind))
+    }
+}
+
+// Make sure the server can be reached, before saving any keys.
+pub(crate) async fn check_server(config: &ClientConfig) -> Result<(), ObnamError> {
+    let store = ChunkStore::remote(config).await?;
+    let meta = ChunkMeta::new(&Label::literal(REPOSITORY_SETTINGS_LABEL));
+    store
+        .find_by_label(&meta)
+        .await
+        .map_err(|err| ObnamError::ServerUnreachable(config.server_url.clone(), err))?;
+    info!("server {} can be reached", config.server_url);
+    Ok(())
+}
+
+fn read_recovery_phrase() -> Result<MasterKey, ObnamError> {
+    let mut phrase = String::new();
+    std::io::stdin().lock().read_line(&mut phrase)?;
+    let key = recovery::decode(&phrase, MASTER_KEY_LEN)?;
+    let mut master = [0; MASTER_KEY_LEN];
+    master.copy_from_slice(&key);
+    Ok(master)
+}
+
+async fn init_repository(
+    config: &ClientConfig,
+    checksum_kind: Option<LabelChecksumKind>,
+) -> Result<(), ObnamError> {
+    let client = BackupClient::new(config).await?;
+    if let Some(settings) = client.get_repository_settings().await? {
+        info!(
+            "repository already has settings, created {}",
+            settings.created()
+        );
+        if let Some(kind) = checksum_kind {
+            if kind != settings.checksum_kind() {
+                eprintln!(
+                    "Repository already labels chunks with {}, not changing it to {}",
+                    settings.checksum_kind().serialize(),
+                    kind.serialize()
+                );
+            }
+        }
+        return Ok(());
     }
+    let kind = checksum_kind.unwrap_or(DEFAULT_CHECKSUM_KIND);
+    let settings = RepositorySettings::new(config, kind, current_timestamp());
+    let id = client.upload_repository_settings(&settings).await?;
+    info!("uploaded repository settings {}: {:?}", id, settings);
+    Ok(())
 }
@@ -1,15 +1,18 @@
 //! The `inspect` subcommand.
 
-use crate::backup_run::current_timestamp;
-use crate::chunk::ClientTrust;
 use crate::client::BackupClient;
+use crate::cmd::{GlobalOptions, Summary};
 use crate::config::ClientConfig;
+use crate::dbgen::Tally;
 use crate::error::ObnamError;
 
 use clap::Parser;
+use indicatif::HumanBytes;
 use log::info;
+use std::collections::BTreeMap;
 use tempfile::NamedTempFile;
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 
 /// Make a backup.
 #[derive(Debug, Parser)]
@@ -20,27 +23,58 @@ pub struct Inspect {
 
 impl Inspect {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+    pub fn run(&self, config: &ClientConfig, global: &GlobalOptions) -> Result<(), ObnamError> {
         let rt = Runtime::new()?;
-        rt.block_on(self.run_async(config))
+        rt.block_on(self.run_async(config, global))
     }
 
-    async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+    ) -> Result<(), ObnamError> {
         let temp = NamedTempFile::new()?;
-        let client = BackupClient::new(config)?;
-        let trust = client
-            .get_client_trust()
-            .await?
-            .or_else(|| Some(ClientTrust::new("FIXME", None, current_timestamp(), vec![])))
-            .unwrap();
+        let client = BackupClient::new(config).await?;
+        let trust = client.get_client_trust().await?;
         let genlist = client.list_generations(&trust);
         let gen_id = genlist.resolve(&self.gen_id)?;
         info!("generation id is {}", gen_id.as_chunk_id());
 
-        let gen = client.fetch_generation(&gen_id, temp.path()).await?;
+        let gen = client
+            .fetch_generation(&gen_id, temp.path(), &CancellationToken::new())
+            .await?;
         let meta = gen.meta()?;
-        println!("schema_version: {}", meta.schema_version());
+        let stats = gen.stats()?;
+        let mut summary = Summary::default();
+        summary.add("generation-id", gen_id.to_string());
+        summary.add("schema_version", meta.schema_version().to_string());
+        summary.add_json("total", &stats.total())?;
+        summary.add_json("by-reason", &stats.by_reason().collect::<BTreeMap<_, _>>())?;
+        summary.add_json("by-kind", &stats.by_kind().collect::<BTreeMap<_, _>>())?;
+        summary.print(global)?;
+        if global.json {
+            return Ok(());
+        }
+
+        show_tally("total", stats.total());
+        println!("by reason:");
+        for (reason, tally) in stats.by_reason() {
+            show_tally(&format!("  {}", reason), tally);
+        }
+        println!("by kind:");
+        for (kind, tally) in stats.by_kind() {
+            show_tally(&format!("  {}", kind), tally);
+        }
 
         Ok(())
     }
 }
+
+fn show_tally(what: &str, tally: Tally) {
+    println!(
+        "{}: {} entries, {}",
+        what,
+        tally.count(),
+        HumanBytes(tally.bytes())
+    );
+}
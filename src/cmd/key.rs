@@ -0,0 +1,147 @@
+//! The `key` subcommand.
+
+use crate::cmd::GlobalOptions;
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use crate::keyexport;
+use crate::passphrase::Passphrase;
+use clap::{Parser, Subcommand};
+use log::info;
+use std::io::Read;
+use std::path::PathBuf;
+
+const PROMPT: &str = "Passphrase for exported keys: ";
+const PROMPT_AGAIN: &str = "Passphrase again: ";
+
+/// Export or import the client's keys, protected by a passphrase, or
+/// encrypt or decrypt the saved keys.
+#[derive(Debug, Parser)]
+pub struct Key {
+    #[clap(subcommand)]
+    cmd: KeyCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum KeyCommand {
+    /// Write the keys as text, encrypted with a passphrase, for
+    /// printing or keeping somewhere safe.
+    Export {
+        /// Write to this file instead of the standard output.
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+
+        /// Only for testing.
+        #[clap(long)]
+        insecure_passphrase: Option<String>,
+    },
+
+    /// Read keys exported earlier, and save them as the client's keys.
+    Import {
+        /// Read from this file instead of the standard input.
+        filename: Option<PathBuf>,
+
+        /// Replace the client's existing keys.
+        #[clap(long)]
+        force: bool,
+
+        /// Only for testing.
+        #[clap(long)]
+        insecure_passphrase: Option<String>,
+    },
+
+    /// Encrypt the saved keys with a new passphrase. The passphrase is
+    /// then needed whenever the keys are used.
+    Encrypt,
+
+    /// Save the keys without encryption.
+    Decrypt,
+}
+
+impl Key {
+    /// Run the command.
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
+        match &self.cmd {
+            KeyCommand::Export {
+                output,
+                insecure_passphrase,
+            } => {
+                let passwords = config.passwords()?;
+                let passphrase = match insecure_passphrase {
+                    Some(x) => x.to_string(),
+                    None => ask_new_passphrase()?,
+                };
+                let armored = keyexport::export(&passwords, &passphrase, &config.key_derivation)?;
+                match output {
+                    Some(filename) => std::fs::write(filename, armored)
+                        .map_err(|err| ObnamError::KeyExportWrite(filename.clone(), err))?,
+                    None => print!("{}", armored),
+                }
+            }
+            KeyCommand::Import {
+                filename,
+                force,
+                insecure_passphrase,
+            } => {
+                let pwfile = config.passwords_filename();
+                if pwfile.exists() && !force {
+                    return Err(ObnamError::KeysExist(pwfile));
+                }
+                let armored = match filename {
+                    Some(filename) => std::fs::read_to_string(filename)
+                        .map_err(|err| ObnamError::KeyImportRead(filename.clone(), err))?,
+                    None => {
+                        let mut text = String::new();
+                        std::io::stdin().read_to_string(&mut text)?;
+                        text
+                    }
+                };
+                let passphrase = match insecure_passphrase {
+                    Some(x) => x.to_string(),
+                    None => rpassword::read_password_from_tty(Some(PROMPT))?,
+                };
+                let passwords = keyexport::import(&armored, &passphrase)?;
+                passwords
+                    .save(&pwfile)
+                    .map_err(|err| ObnamError::PasswordSave(pwfile.clone(), err))?;
+                info!("imported keys to {}", pwfile.display());
+            }
+            KeyCommand::Encrypt => {
+                let passwords = config.passwords()?;
+                let passphrase = new_passphrase(config)?;
+                let pwfile = config.passwords_filename();
+                passwords
+                    .save_encrypted(&pwfile, &passphrase, &config.key_derivation)
+                    .map_err(|err| ObnamError::PasswordSave(pwfile.clone(), err))?;
+                info!("encrypted keys in {}", pwfile.display());
+            }
+            KeyCommand::Decrypt => {
+                let passwords = config.passwords()?;
+                let pwfile = config.passwords_filename();
+                passwords
+                    .save(&pwfile)
+                    .map_err(|err| ObnamError::PasswordSave(pwfile.clone(), err))?;
+                info!("decrypted keys in {}", pwfile.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Ask for a new passphrase twice, to catch typos.
+fn ask_new_passphrase() -> Result<String, ObnamError> {
+    let first = rpassword::read_password_from_tty(Some(PROMPT))?;
+    let second = rpassword::read_password_from_tty(Some(PROMPT_AGAIN))?;
+    if first != second {
+        return Err(ObnamError::PassphraseMismatch);
+    }
+    Ok(first)
+}
+
+/// Get a new passphrase for encrypting the saved keys, from where the
+/// configuration says, or by asking for it twice.
+pub(crate) fn new_passphrase(config: &ClientConfig) -> Result<Passphrase, ObnamError> {
+    match config.passphrase.get()? {
+        Some(passphrase) => Ok(passphrase),
+        None => Ok(Passphrase::new(&ask_new_passphrase()?)),
+    }
+}
@@ -2,35 +2,164 @@
 
 use crate::chunk::ClientTrust;
 use crate::client::BackupClient;
+use crate::cmd::GlobalOptions;
 use crate::config::ClientConfig;
 use crate::error::ObnamError;
+use crate::generation::FinishedGeneration;
 use clap::Parser;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use tempfile::NamedTempFile;
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 
-/// List generations on the server.
+/// List generations on the server, oldest first.
+///
+/// Pinned generations are marked as such.
 #[derive(Debug, Parser)]
-pub struct List {}
+pub struct List {
+    /// Show metadata for each generation: when it ended, where it was
+    /// made, its tag, and how many files and bytes it has. This
+    /// requires downloading the metadata of each listed generation,
+    /// which can be slow.
+    #[clap(long, short)]
+    long: bool,
+
+    /// List only the latest N generations.
+    #[clap(long, value_name = "N")]
+    last: Option<usize>,
+
+    /// List the generations of all clients using the server, not just
+    /// this one. Each line starts with the name of the client.
+    #[clap(long)]
+    all_clients: bool,
+}
 
 impl List {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+    pub fn run(&self, config: &ClientConfig, global: &GlobalOptions) -> Result<(), ObnamError> {
         let rt = Runtime::new()?;
-        rt.block_on(self.run_async(config))
+        rt.block_on(self.run_async(config, global))
+    }
+
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+    ) -> Result<(), ObnamError> {
+        let client = BackupClient::new(config).await?;
+        let mut listed = vec![];
+        if self.all_clients {
+            for c in client.list_clients().await? {
+                let prefix = format!("{} ", c.name);
+                self.list(
+                    &client,
+                    &c.trust,
+                    Some(&c.name),
+                    &prefix,
+                    global,
+                    &mut listed,
+                )
+                .await?;
+            }
+        } else {
+            let trust = client.get_client_trust().await?;
+            self.list(&client, &trust, None, "", global, &mut listed)
+                .await?;
+        }
+        if global.json {
+            global.print_json(&listed)?;
+        }
+        Ok(())
     }
 
-    async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
-        let client = BackupClient::new(config)?;
-        let trust = client
-            .get_client_trust()
-            .await?
-            .or_else(|| Some(ClientTrust::new("FIXME", None, "".to_string(), vec![])))
-            .unwrap();
-
-        let generations = client.list_generations(&trust);
-        for finished in generations.iter() {
-            println!("{} {}", finished.id(), finished.ended());
+    // List the generations in a client trust. With `--json`, they're
+    // collected into `listed` instead of being written out.
+    async fn list(
+        &self,
+        client: &BackupClient,
+        trust: &ClientTrust,
+        client_name: Option<&str>,
+        prefix: &str,
+        global: &GlobalOptions,
+        listed: &mut Vec<Listed>,
+    ) -> Result<(), ObnamError> {
+        let generations = client.list_generations(trust);
+        let generations: Vec<&FinishedGeneration> = match self.last {
+            Some(n) => generations.last(n).iter().collect(),
+            None => generations.iter().collect(),
+        };
+        for finished in generations {
+            let is_pinned = trust.is_pinned(finished.id().as_chunk_id());
+            let pinned = if is_pinned { " pinned" } else { "" };
+            if self.long {
+                let temp = NamedTempFile::new()?;
+                let gen = client
+                    .fetch_generation(finished.id(), temp.path(), &CancellationToken::new())
+                    .await?;
+                let meta = gen.meta()?;
+                if global.json {
+                    listed.push(Listed {
+                        client: client_name.map(|s| s.to_string()),
+                        id: finished.id().to_string(),
+                        ended: meta.ended().unwrap_or(finished.ended()).to_string(),
+                        pinned: is_pinned,
+                        hostname: meta.hostname().map(|s| s.to_string()),
+                        tag: meta.tag().map(|s| s.to_string()),
+                        files: meta.file_count()?,
+                        bytes: meta.file_bytes()?,
+                    });
+                    continue;
+                }
+                println!(
+                    "{}{} {} {} tag={} files={} bytes={}{}",
+                    prefix,
+                    finished.id(),
+                    meta.ended().unwrap_or("-"),
+                    meta.hostname().unwrap_or("-"),
+                    meta.tag().unwrap_or("-"),
+                    show_count(meta.file_count()?),
+                    meta.file_bytes()?
+                        .map(|n| HumanBytes(n).to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                    pinned,
+                );
+            } else if global.json {
+                listed.push(Listed {
+                    client: client_name.map(|s| s.to_string()),
+                    id: finished.id().to_string(),
+                    ended: finished.ended().to_string(),
+                    pinned: is_pinned,
+                    ..Listed::default()
+                });
+            } else {
+                println!("{}{} {}{}", prefix, finished.id(), finished.ended(), pinned);
+            }
         }
 
         Ok(())
     }
 }
+
+// A listed generation, for `--json`. The metadata fields are only
+// set with `--long`.
+#[derive(Debug, Default, Serialize)]
+struct Listed {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<String>,
+    id: String,
+    ended: String,
+    pinned: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+}
+
+fn show_count(n: Option<u64>) -> String {
+    n.map(|n| n.to_string()).unwrap_or_else(|| "-".to_string())
+}
@@ -1,5 +1,6 @@
 //! The `backup` subcommand.
 
+use crate::cmd::GlobalOptions;
 use crate::config::ClientConfig;
 use crate::dbgen::{schema_version, DEFAULT_SCHEMA_MAJOR, SCHEMA_MAJORS};
 use crate::error::ObnamError;
@@ -16,7 +17,7 @@ pub struct ListSchemaVersions {
 
 impl ListSchemaVersions {
     /// Run the command.
-    pub fn run(&self, _config: &ClientConfig) -> Result<(), ObnamError> {
+    pub fn run(&self, _config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
         if self.default_only {
             let schema = schema_version(DEFAULT_SCHEMA_MAJOR)?;
             println!("{}", schema);
@@ -1,14 +1,17 @@
 //! The `list-files` subcommand.
 
 use crate::backup_reason::Reason;
-use crate::chunk::ClientTrust;
 use crate::client::BackupClient;
+use crate::cmd::GlobalOptions;
 use crate::config::ClientConfig;
 use crate::error::ObnamError;
 use crate::fsentry::{FilesystemEntry, FilesystemKind};
 use clap::Parser;
+use serde::Serialize;
+use std::path::PathBuf;
 use tempfile::NamedTempFile;
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 
 /// List files in a backup.
 #[derive(Debug, Parser)]
@@ -20,41 +23,83 @@ pub struct ListFiles {
 
 impl ListFiles {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+    pub fn run(&self, config: &ClientConfig, global: &GlobalOptions) -> Result<(), ObnamError> {
         let rt = Runtime::new()?;
-        rt.block_on(self.run_async(config))
+        rt.block_on(self.run_async(config, global))
     }
 
-    async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+    ) -> Result<(), ObnamError> {
         let temp = NamedTempFile::new()?;
 
-        let client = BackupClient::new(config)?;
-        let trust = client
-            .get_client_trust()
-            .await?
-            .or_else(|| Some(ClientTrust::new("FIXME", None, "".to_string(), vec![])))
-            .unwrap();
+        let client = BackupClient::new(config).await?;
+        let trust = client.get_client_trust().await?;
 
         let genlist = client.list_generations(&trust);
         let gen_id = genlist.resolve(&self.gen_id)?;
 
-        let gen = client.fetch_generation(&gen_id, temp.path()).await?;
+        let gen = client
+            .fetch_generation(&gen_id, temp.path(), &CancellationToken::new())
+            .await?;
+        let mut listed = vec![];
         for file in gen.files()?.iter()? {
             let (_, entry, reason, _) = file?;
-            println!("{}", format_entry(&entry, reason));
+            let error = match reason {
+                Reason::FileError => gen.file_error(&entry.pathbuf())?,
+                _ => None,
+            };
+            if global.json {
+                listed.push(Listed {
+                    path: entry.pathbuf(),
+                    kind: entry.kind(),
+                    reason: reason.to_string(),
+                    renamed_from: reason.renamed_from().cloned(),
+                    error,
+                });
+            } else {
+                println!("{}", format_entry(&entry, &reason, error.as_deref()));
+            }
         }
 
+        if global.json {
+            global.print_json(&listed)?;
+        }
         Ok(())
     }
 }
 
-fn format_entry(e: &FilesystemEntry, reason: Reason) -> String {
+// A listed file, for `--json`.
+#[derive(Serialize)]
+struct Listed {
+    path: PathBuf,
+    kind: FilesystemKind,
+    reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    renamed_from: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn format_entry(e: &FilesystemEntry, reason: &Reason, error: Option<&str>) -> String {
     let kind = match e.kind() {
-        FilesystemKind::Regular => "-",
+        FilesystemKind::Regular | FilesystemKind::Stream => "-",
         FilesystemKind::Directory => "d",
         FilesystemKind::Symlink => "l",
         FilesystemKind::Socket => "s",
         FilesystemKind::Fifo => "p",
     };
-    format!("{} {} ({})", kind, e.pathbuf().display(), reason)
+    match (error, reason.renamed_from()) {
+        (Some(error), _) => format!("{} {} ({}: {})", kind, e.pathbuf().display(), reason, error),
+        (None, Some(from)) => format!(
+            "{} {} ({} from {})",
+            kind,
+            e.pathbuf().display(),
+            reason,
+            from.display()
+        ),
+        (None, None) => format!("{} {} ({})", kind, e.pathbuf().display(), reason),
+    }
 }
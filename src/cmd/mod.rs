@@ -1,16 +1,175 @@
 //! Subcommand implementations.
+//!
+//! Every subcommand is given the [`GlobalOptions`], which can be
+//! given before or after the name of the subcommand on the command
+//! line.
+
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use crate::logging::LogLevel;
+use crate::passphrase::{read_fd, PassphraseError};
+use clap::builder::{RangedI64ValueParser, RangedU64ValueParser};
+use indicatif::HumanBytes;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::os::unix::io::RawFd;
 
 pub mod backup;
+pub mod backup_stream;
 pub mod chunk;
 pub mod chunkify;
+pub mod clients;
+pub mod du;
+pub mod export;
+pub mod forget;
 pub mod gen_info;
 pub mod get_chunk;
+pub mod history;
 pub mod init;
 pub mod inspect;
+pub mod key;
 pub mod list;
 pub mod list_backup_versions;
 pub mod list_files;
+pub mod pin;
+pub mod repair;
+pub mod replicate;
 pub mod resolve;
 pub mod restore;
+pub mod restore_test;
+pub mod search;
 pub mod show_config;
 pub mod show_gen;
+
+/// Options that all subcommands accept.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct GlobalOptions {
+    /// Use the settings of this profile in the configuration file.
+    #[clap(long, global = true)]
+    pub profile: Option<String>,
+
+    /// Read the passphrase for encrypted keys from this open file
+    /// descriptor.
+    #[clap(long, global = true, value_name = "FD", value_parser = RangedI64ValueParser::<RawFd>::new().range(0..=i64::from(RawFd::MAX)))]
+    pub passphrase_fd: Option<RawFd>,
+
+    /// Log more to the terminal: what the client does, and more
+    /// details when given more than once. By default, only warnings
+    /// are logged to the terminal.
+    #[clap(long, short, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Don't log anything to the terminal, not even warnings, and
+    /// don't show progress bars.
+    #[clap(long, short, global = true)]
+    pub quiet: bool,
+
+    /// How much to log to the log file, instead of what the
+    /// configuration says.
+    #[clap(long, global = true, value_enum, value_name = "LEVEL")]
+    pub log_level: Option<LogLevel>,
+
+    /// How many pieces of CPU heavy work to do at once, instead of
+    /// what the configuration says.
+    #[clap(long, global = true, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jobs: Option<usize>,
+
+    /// Write the output as JSON, for commands that list or summarize
+    /// things. Other commands write what they always write.
+    #[clap(long, global = true)]
+    pub json: bool,
+
+    /// Don't show progress bars, but log the progress every so often.
+    /// This is the default when the standard error output isn't a
+    /// terminal.
+    #[clap(long, global = true)]
+    pub no_progress: bool,
+}
+
+impl GlobalOptions {
+    /// Override settings in the configuration with the options.
+    pub fn apply(&self, config: &mut ClientConfig) -> Result<(), PassphraseError> {
+        if let Some(jobs) = self.jobs {
+            config.jobs = jobs;
+        }
+        if let Some(fd) = self.passphrase_fd {
+            config.passphrase.from_fd = Some(read_fd(fd)?);
+        }
+        Ok(())
+    }
+
+    /// Should progress bars be shown?
+    ///
+    /// They're drawn on the standard error output, so they're only
+    /// shown when that's a terminal, and not when run from cron, say.
+    pub fn progress_bars(&self) -> bool {
+        // SAFETY: isatty only looks at the file descriptor.
+        let is_terminal = unsafe { libc::isatty(libc::STDERR_FILENO) } == 1;
+        !self.no_progress && !self.quiet && is_terminal
+    }
+
+    /// Write a value to the standard output as JSON.
+    pub fn print_json<T: Serialize>(&self, value: &T) -> Result<(), ObnamError> {
+        println!("{}", serde_json::to_string_pretty(value)?);
+        Ok(())
+    }
+}
+
+/// Named values that summarize what a command did.
+///
+/// They're written one per line, as `name: value`, or with `--json`,
+/// as a JSON object, with any spaces in the names turned into dashes.
+#[derive(Debug, Default)]
+pub struct Summary {
+    fields: Vec<(&'static str, Value, Option<String>)>,
+}
+
+impl Summary {
+    /// Add a value.
+    pub fn add<T: Into<Value> + fmt::Display>(&mut self, name: &'static str, value: T) {
+        let text = value.to_string();
+        self.fields.push((name, value.into(), Some(text)));
+    }
+
+    /// Add a size in bytes. It's written for people to read, except
+    /// as JSON.
+    pub fn add_bytes(&mut self, name: &'static str, bytes: u64) {
+        let text = HumanBytes(bytes).to_string();
+        self.fields.push((name, bytes.into(), Some(text)));
+    }
+
+    /// Add a value that's only written as JSON. Without `--json`,
+    /// the command writes it in its own way.
+    pub fn add_json<T: Serialize>(
+        &mut self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), ObnamError> {
+        self.fields.push((name, serde_json::to_value(value)?, None));
+        Ok(())
+    }
+
+    /// The summary as a JSON object.
+    pub fn to_json(&self) -> Map<String, Value> {
+        let mut map = Map::new();
+        for (name, value, _) in self.fields.iter() {
+            map.insert(name.replace(' ', "-"), value.clone());
+        }
+        map
+    }
+
+    /// Write the summary to the standard output.
+    pub fn print(&self, global: &GlobalOptions) -> Result<(), ObnamError> {
+        if global.json {
+            global.print_json(&self.to_json())?;
+        } else {
+            for (name, _, text) in self.fields.iter() {
+                if let Some(text) = text {
+                    println!("{}: {}", name, text);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+//! The `pin` and `unpin` subcommands.
+
+use crate::backup_run::current_timestamp;
+use crate::client::BackupClient;
+use crate::cmd::GlobalOptions;
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use clap::Parser;
+use tokio::runtime::Runtime;
+
+/// Pin a backup generation, so that it can't be forgotten.
+///
+/// This is for backups that need to be kept indefinitely, such as one
+/// made before a migration, or a yearly archive. A pinned generation
+/// has to be unpinned before it can be forgotten.
+#[derive(Debug, Parser)]
+pub struct Pin {
+    /// Reference to generation to pin.
+    gen_ref: String,
+}
+
+impl Pin {
+    /// Run the command.
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config))
+    }
+
+    async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+        let client = BackupClient::new(config).await?;
+        let mut trust = client.get_client_trust().await?;
+        let gen_id = client.list_generations(&trust).resolve(&self.gen_ref)?;
+
+        if trust.pin(gen_id.as_chunk_id()) {
+            trust.finalize(current_timestamp());
+            client.update_client_trust(&trust).await?;
+            println!("pinned generation {}", gen_id);
+        } else {
+            println!("generation {} is already pinned", gen_id);
+        }
+        Ok(())
+    }
+}
+
+/// Unpin a backup generation, so that it can be forgotten again.
+#[derive(Debug, Parser)]
+pub struct Unpin {
+    /// Reference to generation to unpin.
+    gen_ref: String,
+}
+
+impl Unpin {
+    /// Run the command.
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config))
+    }
+
+    async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+        let client = BackupClient::new(config).await?;
+        let mut trust = client.get_client_trust().await?;
+        let gen_id = client.list_generations(&trust).resolve(&self.gen_ref)?;
+
+        if trust.unpin(gen_id.as_chunk_id()) {
+            trust.finalize(current_timestamp());
+            client.update_client_trust(&trust).await?;
+            println!("unpinned generation {}", gen_id);
+        } else {
+            println!("generation {} isn't pinned", gen_id);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,443 @@
+//! The `repair` subcommand.
+
+use crate::backup_reason::Reason;
+use crate::backup_run::current_timestamp;
+use crate::chunk::GenerationChunk;
+use crate::chunker::{ChunkerError, FileChunks};
+use crate::chunkid::ChunkId;
+use crate::client::{BackupClient, ClientError};
+use crate::cmd::gen_info::{check_chunk, ChunkHealth};
+use crate::cmd::GlobalOptions;
+use crate::config::ClientConfig;
+use crate::dbgen::FileId;
+use crate::error::ObnamError;
+use crate::fsentry::{FilesystemEntry, FilesystemKind, FsEntryError};
+use crate::generation::{GenId, LocalGeneration, NascentGeneration};
+use crate::genmeta;
+use crate::label::{LabelChecksumKind, Labeler};
+use crate::policy::file_has_changed;
+use crate::refcount::register_generation;
+use bytesize::MIB;
+use clap::Parser;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use tempfile::{tempdir, NamedTempFile};
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+use users::UsersCache;
+
+// Size of chunks when uploading the repaired generation's metadata.
+const SQLITE_CHUNK_SIZE: usize = MIB as usize;
+
+// Metadata that is copied from the damaged generation to the
+// repaired one. The rest is set anew.
+const COPIED_META: &[&str] = &[
+    genmeta::HOSTNAME,
+    genmeta::USERNAME,
+    genmeta::CLIENT_VERSION,
+    genmeta::STARTED,
+    genmeta::ENDED,
+    genmeta::TAG,
+    genmeta::WARNING_COUNT,
+    genmeta::FEATURES,
+];
+
+/// Repair a backup generation whose chunks are missing or corrupt.
+///
+/// Every chunk the generation uses is checked, as with `obnam
+/// gen-info --verify-chunks`. A damaged file whose metadata in the
+/// file system still matches the backup is read again, and its chunks
+/// uploaded. Other damaged files are marked as irrecoverable, and
+/// aren't restored. The repaired generation replaces the damaged one
+/// in the list of backups.
+///
+/// A generation that a later incremental generation is based on can't
+/// be repaired, since the later one only records what changed since
+/// it, and would lose the rest if it were replaced. Repair the later
+/// generations instead.
+#[derive(Debug, Parser)]
+pub struct Repair {
+    /// Reference to generation to repair.
+    #[clap(default_value = "latest")]
+    gen_ref: String,
+
+    /// Only report which files could be repaired, without changing
+    /// anything.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+impl Repair {
+    /// Run the command.
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config))
+    }
+
+    async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+        let temp = NamedTempFile::new()?;
+        let newtemp = tempdir()?;
+        let newpath = newtemp.path().join("repaired.db");
+
+        let client = BackupClient::new(config).await?;
+        let mut trust = client.get_client_trust().await?;
+
+        let genlist = client.list_generations(&trust);
+        let gen_id = genlist.resolve(&self.gen_ref)?;
+        info!("repairing generation {}", gen_id.as_chunk_id());
+        for other in genlist.iter().filter(|other| other.id() != &gen_id) {
+            if client.generation_parent(other.id()).await?.as_ref() == Some(&gen_id) {
+                return Err(RepairError::IsParent(gen_id, other.id().clone()).into());
+            }
+        }
+
+        let gen = client
+            .fetch_generation(&gen_id, temp.path(), &CancellationToken::new())
+            .await?;
+        let meta = gen.meta()?;
+        let kind = match meta.get("checksum_kind") {
+            Some(kind) => LabelChecksumKind::from(kind)?,
+            None => LabelChecksumKind::Sha256,
+        };
+
+        let mut checked = HashMap::new();
+        let damaged = damaged_files(&client, &gen, kind, &mut checked).await?;
+        if damaged.is_empty() {
+            println!("generation {} has no missing or corrupt chunks", gen_id);
+            return Ok(());
+        }
+
+        let mut new = if self.dry_run {
+            None
+        } else {
+            Some(NascentGeneration::create(
+                &newpath,
+                meta.schema_version(),
+                kind,
+            )?)
+        };
+        let labeler = client.labeler(kind);
+        let mut repaired = 0;
+        let mut irrecoverable = 0;
+        for file in gen.files()?.iter()? {
+            let (fileno, entry, mut reason, is_cachedir_tag) = file?;
+            let mut error = match reason {
+                Reason::FileError => gen.file_error(&entry.pathbuf())?,
+                _ => None,
+            };
+            let (mut ids, mut lengths) = gen.chunk_ids_and_lengths(fileno)?;
+            let mut labels = gen.chunk_labels(fileno)?;
+            if damaged.contains(&fileno) {
+                let path = entry.pathbuf();
+                let outcome = match check_live_file(&entry) {
+                    Ok(()) if self.dry_run => Ok((vec![], vec![], vec![])),
+                    Ok(()) => reupload(&client, config, &path, labeler, &mut checked).await,
+                    Err(err) => Err(err),
+                };
+                match outcome {
+                    Ok((new_ids, new_lengths, new_labels)) => {
+                        let verb = if self.dry_run {
+                            "can repair"
+                        } else {
+                            "repaired"
+                        };
+                        println!("{} {}", verb, path.display());
+                        repaired += 1;
+                        ids = new_ids;
+                        lengths = new_lengths;
+                        labels = new_labels;
+                    }
+                    Err(RepairError::ClientError(err)) => return Err(err.into()),
+                    Err(err) => {
+                        warn!("can't repair {}: {}", path.display(), err);
+                        println!("irrecoverable {}: {}", path.display(), err);
+                        irrecoverable += 1;
+                        ids = vec![];
+                        lengths = vec![];
+                        labels = vec![];
+                        reason = Reason::FileError;
+                        error = Some(err.to_string());
+                    }
+                }
+            }
+            if let Some(new) = new.as_mut() {
+                new.insert_with_error(
+                    entry,
+                    &ids,
+                    &lengths,
+                    &labels,
+                    reason,
+                    is_cachedir_tag,
+                    error.as_deref(),
+                )?;
+            }
+        }
+
+        println!("damaged files: {}", damaged.len());
+        println!("repaired files: {}", repaired);
+        println!("irrecoverable files: {}", irrecoverable);
+
+        let mut new = match new {
+            None => return Ok(()),
+            Some(new) => new,
+        };
+        for key in COPIED_META {
+            if let Some(value) = meta.get(key) {
+                new.set_meta(key, value)?;
+            }
+        }
+        new.set_meta(genmeta::FILE_COUNT, &format!("{}", new.file_count()))?;
+        new.set_meta(genmeta::FILE_BYTES, &format!("{}", new.file_bytes()))?;
+        new.set_meta(genmeta::REPAIRED, &current_timestamp())?;
+        new.close()?;
+
+        let new_id = upload_generation(&client, &newpath, labeler).await?;
+        trust.replace_backup(gen_id.as_chunk_id(), new_id.as_chunk_id());
+        trust.finalize(current_timestamp());
+        client.update_client_trust(&trust).await?;
+        println!("repaired generation {} as {}", gen_id, new_id);
+
+        // The damaged generation is no longer in use, so the server
+        // may remove the chunks only it used. That's only safe once
+        // the repaired generation is registered.
+        if let Some(registered) = client.registered_generations().await? {
+            match register_generation(&client, &new_id).await {
+                Ok(_) if registered.contains(&gen_id) => {
+                    client.unregister_generation(&gen_id).await?;
+                }
+                Ok(_) => (),
+                Err(err) => warn!(
+                    "couldn't register generation {} with server: {}",
+                    new_id, err
+                ),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Possible errors from repairing a generation.
+#[derive(Debug, thiserror::Error)]
+pub enum RepairError {
+    /// The damaged file didn't come from the file system, so it can't
+    /// be read again.
+    #[error("{0} is a stream, not a file")]
+    NotAFile(PathBuf),
+
+    /// The damaged file can't be found in the file system.
+    #[error("failed to get file system metadata for {0}: {1}")]
+    Metadata(PathBuf, std::io::Error),
+
+    /// The damaged file has changed since the backup.
+    #[error("{0} has changed since it was backed up")]
+    Changed(PathBuf),
+
+    /// The damaged file can't be opened.
+    #[error("failed to open file {0}: {1}")]
+    FileOpen(PathBuf, std::io::Error),
+
+    /// Error reading a file.
+    #[error(transparent)]
+    ChunkerError(#[from] ChunkerError),
+
+    /// Error describing a file.
+    #[error(transparent)]
+    FsEntryError(#[from] FsEntryError),
+
+    /// Error from the server.
+    #[error(transparent)]
+    ClientError(#[from] ClientError),
+
+    /// The generation is the parent of an incremental generation.
+    #[error("generation {0} can't be repaired, as incremental generation {1} is based on it")]
+    IsParent(GenId, GenId),
+}
+
+// Find the files that use missing or corrupt chunks. Each chunk is
+// checked only once, and what was found is remembered in `checked`.
+async fn damaged_files(
+    client: &BackupClient,
+    gen: &LocalGeneration,
+    kind: LabelChecksumKind,
+    checked: &mut HashMap<ChunkId, ChunkHealth>,
+) -> Result<HashSet<FileId>, ObnamError> {
+    let mut damaged = HashSet::new();
+    for file in gen.files()?.iter()? {
+        let (fileno, entry, reason, _) = file?;
+        if matches!(reason, Reason::FileError) {
+            continue;
+        }
+        for id in gen.chunkids(fileno)?.iter()? {
+            let id = id?;
+            let health = match checked.get(&id) {
+                Some(health) => *health,
+                None => {
+                    let health = check_chunk(client, &id, kind).await?;
+                    checked.insert(id, health);
+                    health
+                }
+            };
+            if health != ChunkHealth::Healthy {
+                info!("{} is damaged", entry.pathbuf().display());
+                damaged.insert(fileno);
+            }
+        }
+    }
+    Ok(damaged)
+}
+
+// Is a damaged file still in the file system, as it was when it was
+// backed up?
+fn check_live_file(entry: &FilesystemEntry) -> Result<(), RepairError> {
+    let path = entry.pathbuf();
+    if entry.kind() == FilesystemKind::Stream {
+        return Err(RepairError::NotAFile(path));
+    }
+    let meta =
+        std::fs::symlink_metadata(&path).map_err(|err| RepairError::Metadata(path.clone(), err))?;
+    let live = FilesystemEntry::from_metadata(&path, &meta, &mut UsersCache::new())?;
+    if file_has_changed(entry, &live) {
+        return Err(RepairError::Changed(path));
+    }
+    Ok(())
+}
+
+// Read a file again, and upload the chunks the server doesn't have
+// intact. Return the ids of all its chunks, and their lengths and
+// labels.
+async fn reupload(
+    client: &BackupClient,
+    config: &ClientConfig,
+    path: &Path,
+    labeler: Labeler,
+    checked: &mut HashMap<ChunkId, ChunkHealth>,
+) -> Result<(Vec<ChunkId>, Vec<u64>, Vec<String>), RepairError> {
+    let file = File::open(path).map_err(|err| RepairError::FileOpen(path.to_path_buf(), err))?;
+    let mut ids = vec![];
+    let mut lengths = vec![];
+    let mut labels = vec![];
+    for chunk in FileChunks::new(config.chunk_size, file, path, labeler) {
+        let chunk = chunk?;
+        lengths.push(chunk.data().len() as u64);
+        labels.push(chunk.meta().label().to_string());
+        // A chunk with the same label may be one of the damaged ones,
+        // so only re-use chunks known to be intact.
+        let id = match client.has_chunk(chunk.meta()).await? {
+            Some(id) if checked.get(&id) == Some(&ChunkHealth::Healthy) => id,
+            _ => {
+                let id = client.upload_chunk(chunk).await?;
+                checked.insert(id.clone(), ChunkHealth::Healthy);
+                id
+            }
+        };
+        ids.push(id);
+    }
+    Ok((ids, lengths, labels))
+}
+
+// Upload the metadata of a repaired generation, and return its id.
+async fn upload_generation(
+    client: &BackupClient,
+    filename: &Path,
+    labeler: Labeler,
+) -> Result<GenId, RepairError> {
+    let file =
+        File::open(filename).map_err(|err| RepairError::FileOpen(filename.to_path_buf(), err))?;
+    let mut ids = vec![];
+    for chunk in FileChunks::new(SQLITE_CHUNK_SIZE, file, filename, labeler) {
+        ids.push(client.upload_chunk(chunk?).await?);
+    }
+    let gen = GenerationChunk::new(ids);
+    let id = client
+        .upload_chunk(gen.to_data_chunk().map_err(ClientError::from)?)
+        .await?;
+    Ok(GenId::from_chunk_id(id))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_live_file, reupload, RepairError};
+    use crate::client::BackupClient;
+    use crate::cmd::gen_info::{check_chunk, ChunkHealth};
+    use crate::config::ClientConfig;
+    use crate::fsentry::FilesystemEntry;
+    use crate::label::LabelChecksumKind;
+    use crate::passwords::{passwords_filename, Passwords};
+    use std::collections::HashMap;
+    use std::path::Path;
+    use tempfile::tempdir;
+    use users::UsersCache;
+    use walkdir::WalkDir;
+
+    fn client(dir: &Path) -> (ClientConfig, BackupClient) {
+        let filename = dir.join("client.yaml");
+        std::fs::write(&filename, "server_url: https://localhost\nroots: [live]\n").unwrap();
+        Passwords::new("secret")
+            .save(&passwords_filename(&filename, None))
+            .unwrap();
+        let config = ClientConfig::read(&filename).unwrap();
+        let chunks = dir.join("chunks");
+        std::fs::create_dir(&chunks).unwrap();
+        let client = BackupClient::local(&config, &chunks).unwrap();
+        (config, client)
+    }
+
+    fn entry(path: &Path) -> FilesystemEntry {
+        let meta = std::fs::symlink_metadata(path).unwrap();
+        FilesystemEntry::from_metadata(path, &meta, &mut UsersCache::new()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reuploads_missing_chunk_of_unchanged_file() {
+        let dir = tempdir().unwrap();
+        let (config, client) = client(dir.path());
+        let kind = LabelChecksumKind::Sha256;
+        let labeler = client.labeler(kind);
+        let path = dir.path().join("data");
+        std::fs::write(&path, b"hello, world").unwrap();
+        let backed_up = entry(&path);
+
+        // Back up the file, and then lose its chunk.
+        let mut checked = HashMap::new();
+        let (ids, _, _) = reupload(&client, &config, &path, labeler, &mut checked)
+            .await
+            .unwrap();
+        let data = format!("{}.data", ids[0]);
+        for e in WalkDir::new(dir.path().join("chunks")) {
+            let e = e.unwrap();
+            if e.file_name().to_string_lossy() == data {
+                std::fs::remove_file(e.path()).unwrap();
+            }
+        }
+        checked.clear();
+        let health = check_chunk(&client, &ids[0], kind).await.unwrap();
+        assert_eq!(health, ChunkHealth::Missing);
+        checked.insert(ids[0].clone(), health);
+
+        check_live_file(&backed_up).unwrap();
+        let (new_ids, lengths, labels) = reupload(&client, &config, &path, labeler, &mut checked)
+            .await
+            .unwrap();
+        assert_eq!(lengths, vec![12]);
+        assert_eq!(labels.len(), 1);
+        let chunk = client.fetch_chunk(&new_ids[0]).await.unwrap();
+        assert_eq!(chunk.data(), b"hello, world");
+    }
+
+    #[test]
+    fn changed_file_is_irrecoverable() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, b"hello, world").unwrap();
+        let backed_up = entry(&path);
+
+        std::fs::write(&path, b"goodbye, cruel world").unwrap();
+        assert!(matches!(
+            check_live_file(&backed_up),
+            Err(RepairError::Changed(_))
+        ));
+    }
+}
@@ -0,0 +1,221 @@
+//! The `replicate` subcommand.
+
+use crate::chunkid::ChunkId;
+use crate::chunkstore::StoreError;
+use crate::client::{BackupClient, ClientError};
+use crate::cmd::{GlobalOptions, Summary};
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use crate::generation::GenId;
+use crate::refcount::generation_chunks;
+use clap::Parser;
+use log::{debug, info};
+use tokio::runtime::Runtime;
+
+/// Copy the backups on the server to another server.
+///
+/// All backup generations of all clients are copied, with the chunks
+/// they use, the clients' trust roots, and the repository settings.
+/// Chunks keep their ids, and stay encrypted, so the copy is used
+/// with the same key. Only what the other server doesn't have yet is
+/// copied, so replicating again later is quick.
+///
+/// The other server is reached with the same TLS settings as the
+/// configured one.
+#[derive(Debug, Parser)]
+pub struct Replicate {
+    /// URL of the server to copy to.
+    #[clap(long)]
+    to: String,
+}
+
+impl Replicate {
+    /// Run the command.
+    pub fn run(&self, config: &ClientConfig, global: &GlobalOptions) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config, global))
+    }
+
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+    ) -> Result<(), ObnamError> {
+        let source = BackupClient::new(config).await?;
+        let mut target_config = config.clone();
+        target_config.server_url = self.to.clone();
+        let target = BackupClient::new(&target_config).await?;
+
+        let mut stats = Stats::default();
+        replicate(&source, &target, &mut stats).await?;
+
+        let mut summary = Summary::default();
+        summary.add("clients", stats.clients);
+        summary.add("generations copied", stats.generations);
+        summary.add("generations already replicated", stats.replicated);
+        summary.add("chunks copied", stats.copied);
+        summary.add("bytes copied", stats.bytes);
+        summary.add("chunks already present", stats.present);
+        summary.print(global)
+    }
+}
+
+// Copy all backups from one server to another.
+async fn replicate(
+    source: &BackupClient,
+    target: &BackupClient,
+    stats: &mut Stats,
+) -> Result<(), ObnamError> {
+    let registered = target.registered_generations().await?;
+    let clients = source.list_clients().await?;
+    for client in clients.iter() {
+        info!("replicating backups of client {}", client.name);
+
+        // Generations are copied oldest first, so that parents are
+        // copied before the generations based on them. The generation
+        // chunk is copied last: if the other server has it, it has
+        // the whole generation. It may not have been registered yet,
+        // though, if an earlier run was interrupted.
+        for id in client.trust.backups() {
+            let gen_id = GenId::from_chunk_id(id.clone());
+            let copied = target.chunk_exists(id).await?;
+            let unregistered = match &registered {
+                Some(registered) => !registered.contains(&gen_id),
+                None => false,
+            };
+            if copied && !unregistered {
+                debug!("generation {} has already been replicated", gen_id);
+                stats.replicated += 1;
+                continue;
+            }
+
+            let mut chunks: Vec<ChunkId> = generation_chunks(source, &gen_id)
+                .await?
+                .into_iter()
+                .filter(|chunk_id| chunk_id != id)
+                .collect();
+            chunks.sort_by_key(|chunk_id| chunk_id.to_string());
+            if copied {
+                debug!("generation {} was copied, but not registered", gen_id);
+                stats.replicated += 1;
+            } else {
+                for chunk_id in chunks.iter() {
+                    copy_chunk(source, target, chunk_id, stats).await?;
+                }
+                copy_chunk(source, target, id, stats).await?;
+                stats.generations += 1;
+            }
+
+            if unregistered {
+                chunks.push(id.clone());
+                target.register_generation(&gen_id, &chunks).await?;
+            }
+        }
+
+        // The trust root makes the generations visible, so it's
+        // copied after them.
+        for id in client.trust_chunks.iter() {
+            copy_chunk(source, target, id, stats).await?;
+        }
+    }
+    for id in source.repository_settings_chunks().await? {
+        copy_chunk(source, target, &id, stats).await?;
+    }
+    stats.clients = clients.len();
+    Ok(())
+}
+
+// Counts of what was replicated.
+#[derive(Debug, Default)]
+struct Stats {
+    clients: usize,
+    generations: usize,
+    replicated: usize,
+    copied: usize,
+    bytes: u64,
+    present: usize,
+}
+
+// Copy a chunk as it's stored, unless the other server has it.
+async fn copy_chunk(
+    source: &BackupClient,
+    target: &BackupClient,
+    id: &ChunkId,
+    stats: &mut Stats,
+) -> Result<(), ObnamError> {
+    if target.chunk_exists(id).await? {
+        stats.present += 1;
+        return Ok(());
+    }
+    let (data, meta) = source.fetch_encrypted_chunk(id).await?;
+    let len = data.len() as u64;
+    match target.upload_encrypted_chunk(id, data, &meta).await {
+        Ok(_) => {
+            stats.copied += 1;
+            stats.bytes += len;
+        }
+        // Someone else copied it at the same time.
+        Err(ClientError::ChunkStore(StoreError::ChunkExists(_))) => stats.present += 1,
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{copy_chunk, replicate, Stats};
+    use crate::client::BackupClient;
+    use crate::refcount::generation_chunks;
+    use crate::testing::TestRepo;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn replicates_backups_once() {
+        let repo = TestRepo::new();
+        std::fs::write(repo.live().join("data"), "hello").unwrap();
+        let gen_id = repo.backup().await.unwrap().generation_id;
+        let dir = tempdir().unwrap();
+        let source = repo.client();
+        let target = BackupClient::local(&repo.config, dir.path()).unwrap();
+
+        let mut stats = Stats::default();
+        replicate(&source, &target, &mut stats).await.unwrap();
+        assert_eq!(stats.clients, 1);
+        assert_eq!(stats.generations, 1);
+        assert!(target.chunk_exists(gen_id.as_chunk_id()).await.unwrap());
+        let registered = target.registered_generations().await.unwrap().unwrap();
+        assert_eq!(registered, vec![gen_id]);
+
+        let mut stats = Stats::default();
+        replicate(&source, &target, &mut stats).await.unwrap();
+        assert_eq!(stats.generations, 0);
+        assert_eq!(stats.replicated, 1);
+        assert_eq!(stats.copied, 0);
+    }
+
+    #[tokio::test]
+    async fn registers_generation_copied_by_interrupted_run() {
+        let repo = TestRepo::new();
+        std::fs::write(repo.live().join("data"), "hello").unwrap();
+        let gen_id = repo.backup().await.unwrap().generation_id;
+        let dir = tempdir().unwrap();
+        let source = repo.client();
+        let target = BackupClient::local(&repo.config, dir.path()).unwrap();
+
+        // An earlier run copied the generation, but stopped before
+        // registering it.
+        let mut stats = Stats::default();
+        for id in generation_chunks(&source, &gen_id).await.unwrap() {
+            copy_chunk(&source, &target, &id, &mut stats).await.unwrap();
+        }
+        assert!(target.chunk_exists(gen_id.as_chunk_id()).await.unwrap());
+        assert_eq!(target.registered_generations().await.unwrap(), Some(vec![]));
+
+        let mut stats = Stats::default();
+        replicate(&source, &target, &mut stats).await.unwrap();
+        assert_eq!(stats.generations, 0);
+        assert_eq!(stats.replicated, 1);
+        let registered = target.registered_generations().await.unwrap().unwrap();
+        assert_eq!(registered, vec![gen_id]);
+    }
+}
@@ -1,7 +1,7 @@
 //! The `resolve` subcommand.
 
-use crate::chunk::ClientTrust;
 use crate::client::BackupClient;
+use crate::cmd::GlobalOptions;
 use crate::config::ClientConfig;
 use crate::error::ObnamError;
 use clap::Parser;
@@ -16,18 +16,14 @@ pub struct Resolve {
 
 impl Resolve {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
         let rt = Runtime::new()?;
         rt.block_on(self.run_async(config))
     }
 
     async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
-        let client = BackupClient::new(config)?;
-        let trust = client
-            .get_client_trust()
-            .await?
-            .or_else(|| Some(ClientTrust::new("FIXME", None, "".to_string(), vec![])))
-            .unwrap();
+        let client = BackupClient::new(config).await?;
+        let trust = client.get_client_trust().await?;
         let generations = client.list_generations(&trust);
 
         match generations.resolve(&self.generation) {
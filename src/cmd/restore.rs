@@ -1,28 +1,39 @@
 //! The `restore` subcommand.
 
+use crate::api::{DamagedRegion, Quiet, RestoreReport};
 use crate::backup_reason::Reason;
-use crate::chunk::ClientTrust;
+use crate::chunker::{ChunkerError, FileChunks};
 use crate::client::{BackupClient, ClientError};
+use crate::cmd::{GlobalOptions, Summary};
 use crate::config::ClientConfig;
 use crate::db::DatabaseError;
 use crate::dbgen::FileId;
 use crate::error::ObnamError;
 use crate::fsentry::{FilesystemEntry, FilesystemKind};
-use crate::generation::{LocalGeneration, LocalGenerationError};
+use crate::generation::{GenId, LocalGeneration, LocalGenerationError};
+use crate::label::{LabelChecksumKind, Labeler};
+use crate::notify::{self, Notification, Operation};
+use crate::progress_sink::{ProgressEvent, ProgressSink, WarningSink, PROGRESS_LOG_INTERVAL};
+use crate::runlock::RunLock;
 use clap::Parser;
-use indicatif::{ProgressBar, ProgressStyle};
-use libc::{chmod, mkfifo, timespec, utimensat, AT_FDCWD, AT_SYMLINK_NOFOLLOW};
-use log::{debug, error, info};
+use futures::stream::StreamExt;
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use libc::{chmod, lchown, mkfifo, timespec, utimensat, AT_FDCWD, AT_SYMLINK_NOFOLLOW};
+use log::{debug, error, info, warn};
 use std::ffi::CString;
 use std::io::prelude::*;
 use std::io::Error;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::symlink;
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt};
 use std::os::unix::net::UnixListener;
 use std::path::StripPrefixError;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
 use tempfile::NamedTempFile;
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+use users::{Groups, Users, UsersCache};
 
 /// Restore a backup.
 #[derive(Debug, Parser)]
@@ -31,50 +42,629 @@ pub struct Restore {
     gen_id: String,
 
     /// Path to directory where restored files are written.
-    to: PathBuf,
+    #[clap(required_unless_present = "to_tar")]
+    to: Option<PathBuf>,
+
+    /// Write the restored files as a tar archive to this file, or to
+    /// the standard output if it's "-", instead of into a directory.
+    /// Unix domain sockets can't be put in a tar archive and are left
+    /// out.
+    #[clap(long, conflicts_with = "to")]
+    to_tar: Option<PathBuf>,
+
+    /// How to choose the owner of restored files. Owners are only
+    /// restored when running as root.
+    #[clap(long, value_enum, default_value = "by-name")]
+    owner_policy: OwnerPolicy,
+
+    /// Use the stored numeric user and group ids, like tar does with
+    /// the same option. This is the same as "--owner-policy numeric".
+    #[clap(long)]
+    numeric_owner: bool,
+
+    /// User to own restored files, if the stored owner can't be
+    /// mapped by name, or if the owner policy is "default".
+    #[clap(long)]
+    default_owner: Option<String>,
+
+    /// Group to own restored files, if the stored group can't be
+    /// mapped by name, or if the owner policy is "default".
+    #[clap(long)]
+    default_group: Option<String>,
+
+    /// If another Obnam run for the same client is in progress, wait
+    /// for it to finish, instead of failing.
+    #[clap(long)]
+    wait: bool,
+
+    /// If a chunk can't be fetched, fill its part of the file with
+    /// zeros and carry on, instead of stopping the restore. The
+    /// restore still fails at the end, if anything was damaged.
+    #[clap(long)]
+    keep_going: bool,
+
+    /// Write the parts of files that couldn't be restored to this
+    /// file, as JSON.
+    #[clap(long, requires = "keep_going")]
+    damage_report: Option<PathBuf>,
+
+    /// Read chunks directly from this copy of the server's chunk
+    /// directory, instead of from the server. The directory isn't
+    /// changed.
+    #[clap(long)]
+    from_dir: Option<PathBuf>,
+}
+
+/// How to choose the owner of restored files.
+///
+/// The backup stores both the numeric user and group ids, and their
+/// names at the time of the backup. When restoring on a different
+/// machine, the numeric ids often refer to different users, or no
+/// user at all.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
+pub enum OwnerPolicy {
+    /// Map by user and group name, if the name exists locally.
+    /// Otherwise use the default owner, if set, or the stored numeric
+    /// id.
+    ByName,
+
+    /// Use the stored numeric ids.
+    Numeric,
+
+    /// Use the default owner and group for all files.
+    Default,
 }
 
 impl Restore {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+    ///
+    /// The restore stops early if `cancel` is cancelled.
+    pub fn run(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+        cancel: CancellationToken,
+    ) -> Result<(), ObnamError> {
         let rt = Runtime::new()?;
-        rt.block_on(self.run_async(config))
+        let mut stats = Summary::default();
+        let result = rt.block_on(self.run_async(config, global, cancel, &mut stats));
+        notify::send(
+            &config.notify,
+            &Notification::new(
+                &config.client_name,
+                Operation::Restore,
+                result.as_ref().err(),
+                stats.to_json(),
+            ),
+        );
+        result
     }
 
-    async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
-        let temp = NamedTempFile::new()?;
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+        cancel: CancellationToken,
+        stats: &mut Summary,
+    ) -> Result<(), ObnamError> {
+        let policy = if self.numeric_owner {
+            OwnerPolicy::Numeric
+        } else {
+            self.owner_policy
+        };
+        let owners = OwnerMap::new(
+            policy,
+            self.default_owner.as_deref(),
+            self.default_group.as_deref(),
+        )?;
+        let options = RestoreOptions {
+            owners,
+            progress_bar: global.progress_bars(),
+            progress: &Quiet,
+            warnings: &StderrWarnings,
+            cancel,
+            jobs: config.jobs,
+            keep_going: self.keep_going,
+            from_dir: self.from_dir.as_deref(),
+            wait: self.wait,
+        };
+        let report = if let Some(filename) = &self.to_tar {
+            self.run_tar(config, filename, &options).await?
+        } else {
+            let to = self.to.as_ref().unwrap();
+            restore(config, &self.gen_id, to, &options).await?
+        };
+        stats.add("generation-id", report.generation_id.to_string());
+        stats.add("file-count", report.file_count);
+        stats.add("created", report.created);
+        stats.add("overwritten", report.overwritten);
+        stats.add("already-correct", report.skipped);
+        stats.add("damaged", report.damaged.len());
+        // A tar archive may be written to the standard output, so the
+        // summary is only written for a restore to a directory.
+        if self.to_tar.is_none() {
+            stats.print(global)?;
+        }
+        self.report_damage(&report.damaged)
+    }
 
-        let client = BackupClient::new(config)?;
-        let trust = client
-            .get_client_trust()
-            .await?
-            .or_else(|| Some(ClientTrust::new("FIXME", None, "".to_string(), vec![])))
-            .unwrap();
+    // Tell the user what couldn't be restored, and fail if anything.
+    fn report_damage(&self, damaged: &[DamagedRegion]) -> Result<(), ObnamError> {
+        if let Some(filename) = &self.damage_report {
+            let file = std::fs::File::create(filename)
+                .map_err(|err| RestoreError::CreateFile(filename.to_path_buf(), err))?;
+            serde_json::to_writer_pretty(file, damaged)
+                .map_err(|err| RestoreError::DamageReport(filename.to_path_buf(), err))?;
+        }
+        if damaged.is_empty() {
+            return Ok(());
+        }
+        let mut files: Vec<&Path> = damaged.iter().map(|d| d.path.as_path()).collect();
+        files.dedup();
+        for path in files.iter() {
+            eprintln!("damaged: {}", path.display());
+        }
+        Err(RestoreError::Damaged(damaged.len(), files.len()).into())
+    }
+
+    async fn run_tar(
+        &self,
+        config: &ClientConfig,
+        filename: &Path,
+        options: &RestoreOptions<'_>,
+    ) -> Result<RestoreReport, ObnamError> {
+        if filename == Path::new("-") {
+            let stdout = std::io::stdout();
+            let output = std::io::BufWriter::new(stdout.lock());
+            return restore_to_tar(config, &self.gen_id, output, options).await;
+        }
+
+        let file = std::fs::File::create(filename)
+            .map_err(|err| RestoreError::CreateFile(filename.to_path_buf(), err))?;
+        let output = std::io::BufWriter::new(file);
+        match restore_to_tar(config, &self.gen_id, output, options).await {
+            Ok(report) => Ok(report),
+            Err(err) => {
+                // Don't leave a truncated archive behind.
+                std::fs::remove_file(filename)
+                    .map_err(|err| RestoreError::RemoveFile(filename.to_path_buf(), err))?;
+                Err(err)
+            }
+        }
+    }
+}
 
-        let genlist = client.list_generations(&trust);
-        let gen_id = genlist.resolve(&self.gen_id)?;
-        info!("generation id is {}", gen_id.as_chunk_id());
+// Show warnings to the user, for the command line program.
+struct StderrWarnings;
 
-        let gen = client.fetch_generation(&gen_id, temp.path()).await?;
-        info!("restoring {} files", gen.file_count()?);
-        let progress = create_progress_bar(gen.file_count()?, true);
+impl WarningSink for StderrWarnings {
+    fn warning(&self, message: &str) {
+        eprintln!("WARNING: {}", message);
+    }
+}
+
+// How to restore a backup, and where to report what happens.
+pub(crate) struct RestoreOptions<'a> {
+    pub(crate) owners: OwnerMap,
+    pub(crate) progress_bar: bool,
+    pub(crate) progress: &'a dyn ProgressSink,
+    pub(crate) warnings: &'a dyn WarningSink,
+    pub(crate) cancel: CancellationToken,
+    // How many chunks of a file to fetch at once.
+    pub(crate) jobs: usize,
+    // Fill in chunks that can't be fetched with zeros, instead of
+    // failing.
+    pub(crate) keep_going: bool,
+    // Read chunks from a server's chunk directory, instead of from
+    // the server.
+    pub(crate) from_dir: Option<&'a Path>,
+    // Wait for another run for the same client to finish, instead of
+    // failing.
+    pub(crate) wait: bool,
+}
+
+// Open the backup client to restore with.
+async fn open_client(
+    config: &ClientConfig,
+    options: &RestoreOptions<'_>,
+) -> Result<BackupClient, ClientError> {
+    match options.from_dir {
+        Some(dir) => BackupClient::offline(config, dir),
+        None => BackupClient::new(config).await,
+    }
+}
+
+// Restore a backup into a directory.
+//
+// If the restore is cancelled, the file being restored is removed,
+// so that a restore never leaves a partially written file behind.
+pub(crate) async fn restore(
+    config: &ClientConfig,
+    gen_ref: &str,
+    to: &Path,
+    options: &RestoreOptions<'_>,
+) -> Result<RestoreReport, ObnamError> {
+    let owners = &options.owners;
+    let cancel = &options.cancel;
+    let _lock = RunLock::acquire_async(&config.lock_filename(), options.wait, cancel).await?;
+    let temp = NamedTempFile::new()?;
+
+    let client = open_client(config, options).await?;
+    let (gen_id, gen, ignored_features) =
+        open_generation(&client, gen_ref, temp.path(), options).await?;
+    let settings = client.get_repository_settings().await?;
+    let check = ContentCheck {
+        chunk_size: settings.map_or(config.chunk_size, |s| s.chunk_size()),
+        labeler: client.labeler(match gen.meta()?.get("checksum_kind") {
+            Some(kind) => LabelChecksumKind::from(kind)?,
+            None => LabelChecksumKind::Sha256,
+        }),
+    };
+    let file_count = gen.file_count()?;
+    let bytes = bytes_to_restore(&gen)?;
+    info!("restoring {} files, {} bytes", file_count, bytes);
+    let progress = RestoreProgress::new(file_count, bytes, options.progress_bar);
+    let mut done = 0;
+    let mut created = 0;
+    let mut overwritten = 0;
+    let mut skipped = 0;
+    let mut damaged = vec![];
+    let restored: Result<(), ObnamError> = async {
         for file in gen.files()?.iter()? {
             let (fileno, entry, reason, _) = file?;
+            if cancel.is_cancelled() {
+                return Err(RestoreError::CancelledAfter(done, file_count).into());
+            }
             match reason {
-                Reason::FileError => (),
-                _ => restore_generation(&client, &gen, fileno, &entry, &self.to, &progress).await?,
+                Reason::FileError => progress.bytes(content_len(&entry)),
+                _ => {
+                    options
+                        .progress
+                        .event(&ProgressEvent::file_started(&entry.pathbuf()));
+                    progress.file_started(done, &entry.pathbuf());
+                    let outcome = restore_generation(
+                        &client,
+                        &gen,
+                        fileno,
+                        &entry,
+                        to,
+                        options,
+                        &check,
+                        &progress,
+                        &mut damaged,
+                    )
+                    .await;
+                    match outcome {
+                        Err(RestoreError::Cancelled) => {
+                            return Err(RestoreError::CancelledAfter(done, file_count).into())
+                        }
+                        Err(err) => return Err(err.into()),
+                        Ok(Outcome::Created) => created += 1,
+                        Ok(Outcome::Overwritten) => overwritten += 1,
+                        Ok(Outcome::Skipped) => {
+                            progress.bytes(content_len(&entry));
+                            skipped += 1;
+                        }
+                    }
+                }
             }
+            done += 1;
         }
         for file in gen.files()?.iter()? {
             let (_, entry, _, _) = file?;
             if entry.is_dir() {
-                restore_directory_metadata(&entry, &self.to)?;
+                restore_directory_metadata(&entry, to, owners)?;
             }
         }
-        progress.finish();
+        Ok(())
+    }
+    .await;
+    progress.finish();
+    restored?;
 
+    Ok(RestoreReport {
+        generation_id: gen_id,
+        file_count,
+        created,
+        overwritten,
+        skipped,
+        ignored_features,
+        damaged,
+    })
+}
+
+// Fetch the generation to restore, and warn about any features it
+// uses that this version doesn't support.
+async fn open_generation(
+    client: &BackupClient,
+    gen_ref: &str,
+    dbname: &Path,
+    options: &RestoreOptions<'_>,
+) -> Result<(GenId, LocalGeneration, Vec<String>), ObnamError> {
+    let trust = client.get_client_trust().await?;
+
+    let genlist = client.list_generations(&trust);
+    let gen_id = genlist.resolve(gen_ref)?;
+    info!("generation id is {}", gen_id.as_chunk_id());
+
+    let gen = client
+        .fetch_generation(&gen_id, dbname, &options.cancel)
+        .await?;
+    let mut ignored_features = vec![];
+    for feature in gen.check_features()? {
+        warn!("backup uses unsupported feature {}, ignoring it", feature);
+        options
+            .warnings
+            .warning(&format!("ignoring unsupported feature {}", feature));
+        ignored_features.push(feature.to_string());
+    }
+    Ok((gen_id, gen, ignored_features))
+}
+
+// Restore a backup as a tar archive, written to `output`.
+//
+// Owners are stored in the archive as they are in the backup, both as
+// numbers and names. The owner policy doesn't apply.
+pub(crate) async fn restore_to_tar<W: Write>(
+    config: &ClientConfig,
+    gen_ref: &str,
+    output: W,
+    options: &RestoreOptions<'_>,
+) -> Result<RestoreReport, ObnamError> {
+    let cancel = &options.cancel;
+    let _lock = RunLock::acquire_async(&config.lock_filename(), options.wait, cancel).await?;
+    let temp = NamedTempFile::new()?;
+
+    let client = open_client(config, options).await?;
+    let (gen_id, gen, ignored_features) =
+        open_generation(&client, gen_ref, temp.path(), options).await?;
+    let file_count = gen.file_count()?;
+    let bytes = bytes_to_restore(&gen)?;
+    info!(
+        "writing {} files, {} bytes to a tar archive",
+        file_count, bytes
+    );
+    let progress = RestoreProgress::new(file_count, bytes, options.progress_bar);
+    let mut archive = tar::Builder::new(output);
+    let mut done = 0;
+    let mut created = 0;
+    let mut damaged = vec![];
+    let restored: Result<(), ObnamError> = async {
+        for file in gen.files()?.iter()? {
+            let (fileno, entry, reason, _) = file?;
+            if cancel.is_cancelled() {
+                return Err(RestoreError::CancelledAfter(done, file_count).into());
+            }
+            if matches!(reason, Reason::FileError) {
+                progress.bytes(content_len(&entry));
+            } else {
+                options
+                    .progress
+                    .event(&ProgressEvent::file_started(&entry.pathbuf()));
+                progress.file_started(done, &entry.pathbuf());
+                let tarred = append_to_tar(
+                    &mut archive,
+                    &client,
+                    &gen,
+                    fileno,
+                    &entry,
+                    options,
+                    &progress,
+                    &mut damaged,
+                )
+                .await;
+                match tarred {
+                    Err(RestoreError::Cancelled) => {
+                        return Err(RestoreError::CancelledAfter(done, file_count).into())
+                    }
+                    Err(err) => return Err(err.into()),
+                    Ok(true) => created += 1,
+                    Ok(false) => (),
+                }
+            }
+            done += 1;
+        }
+        archive
+            .into_inner()
+            .and_then(|mut output| output.flush())
+            .map_err(RestoreError::WriteTar)?;
         Ok(())
     }
+    .await;
+    progress.finish();
+    restored?;
+
+    Ok(RestoreReport {
+        generation_id: gen_id,
+        file_count,
+        created,
+        overwritten: 0,
+        skipped: 0,
+        ignored_features,
+        damaged,
+    })
+}
+
+// Add one backed up file to a tar archive. Return false if the file
+// can't be represented in tar format.
+//
+// The content of a regular file is written to the archive one chunk
+// at a time, as chunks are fetched, so that large files don't need to
+// fit in memory.
+#[allow(clippy::too_many_arguments)]
+async fn append_to_tar<W: Write>(
+    archive: &mut tar::Builder<W>,
+    client: &BackupClient,
+    gen: &LocalGeneration,
+    fileid: FileId,
+    entry: &FilesystemEntry,
+    options: &RestoreOptions<'_>,
+    progress: &RestoreProgress,
+    damaged: &mut Vec<DamagedRegion>,
+) -> Result<bool, RestoreError> {
+    let path = entry.pathbuf();
+    let mut name = path.as_os_str().as_bytes();
+    while let Some(rest) = name.strip_prefix(b"/") {
+        name = rest;
+    }
+    if name.is_empty() {
+        name = b".";
+    }
+    let mut name = name.to_vec();
+
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(entry.mode() & 0o7777);
+    header.set_uid(entry.uid().into());
+    header.set_gid(entry.gid().into());
+    header.set_mtime(entry.mtime().max(0) as u64);
+    // Names that are too long for the header are left out. The
+    // numeric ids are still there.
+    header.set_username(entry.user()).ok();
+    header.set_groupname(entry.group()).ok();
+    header.set_size(0);
+    match entry.kind() {
+        FilesystemKind::Regular | FilesystemKind::Stream => {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(entry.len());
+        }
+        FilesystemKind::Directory => {
+            header.set_entry_type(tar::EntryType::Directory);
+            name.push(b'/');
+        }
+        FilesystemKind::Symlink => {
+            header.set_entry_type(tar::EntryType::Symlink);
+            let target = entry.symlink_target().unwrap();
+            let target = target.as_os_str().as_bytes();
+            let field = &mut header.as_old_mut().linkname;
+            if target.len() > field.len() {
+                append_long_name(archive, tar::EntryType::GNULongLink, target)?;
+            }
+            copy_truncated(field, target);
+        }
+        FilesystemKind::Fifo => header.set_entry_type(tar::EntryType::Fifo),
+        FilesystemKind::Socket => {
+            warn!(
+                "can't put Unix domain socket {} in a tar archive",
+                path.display()
+            );
+            return Ok(false);
+        }
+    }
+    if name.len() > header.as_old().name.len() {
+        append_long_name(archive, tar::EntryType::GNULongName, &name)?;
+    }
+    copy_truncated(&mut header.as_old_mut().name, &name);
+    header.set_cksum();
+    archive
+        .append(&header, std::io::empty())
+        .map_err(RestoreError::WriteTar)?;
+
+    if !entry.kind().has_content() {
+        return Ok(true);
+    }
+
+    // The size in the header is what the file was when the backup
+    // started. If the file changed while it was being backed up, the
+    // content may have a different length, but the archive must have
+    // exactly as many bytes as the header says.
+    let output = archive.get_mut();
+    let mut remaining = entry.len();
+    let (chunkids, lengths) = gen.chunk_ids_and_lengths(fileid)?;
+    let mut lost_rest = false;
+    for (i, chunkid) in chunkids.iter().enumerate() {
+        let chunk = tokio::select! {
+            _ = options.cancel.cancelled() => return Err(RestoreError::Cancelled),
+            chunk = client.fetch_cached_chunk(chunkid) => chunk,
+        };
+        let offset = entry.len() - remaining;
+        let n = match chunk {
+            Ok(chunk) => {
+                let data = chunk.data();
+                let n = remaining.min(data.len() as u64);
+                output
+                    .write_all(&data[..n as usize])
+                    .map_err(RestoreError::WriteTar)?;
+                n
+            }
+            Err(err) if options.keep_going && err.is_damaged_chunk() => match lengths.get(i) {
+                Some(length) => {
+                    let n = remaining.min(*length);
+                    damaged.push(damaged_region(&path, offset, n, &err, options));
+                    std::io::copy(&mut std::io::repeat(0).take(n), output)
+                        .map_err(RestoreError::WriteTar)?;
+                    n
+                }
+                None => {
+                    // Without the lengths of the chunks, it's not
+                    // known where the rest of them go. The rest of the
+                    // file is padded with zeros below.
+                    damaged.push(damaged_region(&path, offset, remaining, &err, options));
+                    lost_rest = true;
+                    break;
+                }
+            },
+            Err(err) => return Err(err.into()),
+        };
+        progress.bytes(n);
+        remaining -= n;
+    }
+    if remaining > 0 && !lost_rest {
+        warn!(
+            "{} is shorter in the backup than its size, padding it with zeros",
+            path.display()
+        );
+    }
+    progress.bytes(remaining);
+    let padding = (512 - entry.len() % 512) % 512;
+    std::io::copy(&mut std::io::repeat(0).take(remaining + padding), output)
+        .map_err(RestoreError::WriteTar)?;
+    Ok(true)
+}
+
+// Add a GNU extension entry for a name that doesn't fit in a tar
+// header.
+fn append_long_name<W: Write>(
+    archive: &mut tar::Builder<W>,
+    kind: tar::EntryType,
+    name: &[u8],
+) -> Result<(), RestoreError> {
+    let mut header = tar::Header::new_gnu();
+    copy_truncated(&mut header.as_old_mut().name, b"././@LongLink");
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_size(name.len() as u64 + 1);
+    header.set_entry_type(kind);
+    header.set_cksum();
+    archive
+        .append(&header, name.chain(&[0u8][..]))
+        .map_err(RestoreError::WriteTar)
+}
+
+fn copy_truncated(field: &mut [u8], value: &[u8]) {
+    let n = field.len().min(value.len());
+    field[..n].copy_from_slice(&value[..n]);
+}
+
+// What restoring a file did to the target directory.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Outcome {
+    // Nothing was in the way, so the file was created.
+    Created,
+    // Something different was in the way, and was replaced.
+    Overwritten,
+    // The file was already there, with the right content. Only its
+    // metadata was restored.
+    Skipped,
+}
+
+// How to check if an existing file already has the content of the
+// backed up one: split it into chunks the way a backup does, and
+// compare their labels to the ones recorded in the backup.
+struct ContentCheck {
+    chunk_size: usize,
+    labeler: Labeler,
 }
 
 /// Possible errors from restoring.
@@ -84,6 +674,14 @@ pub enum RestoreError {
     #[error(transparent)]
     Database(#[from] DatabaseError),
 
+    /// The restore was cancelled while restoring a file.
+    #[error("restore was cancelled")]
+    Cancelled,
+
+    /// The restore was cancelled, after some files had been restored.
+    #[error("restore cancelled after {0} of {1} files")]
+    CancelledAfter(FileId, FileId),
+
     /// Failed to create a name pipe.
     #[error("Could not create named pipe (FIFO) {0}")]
     NamedPipeCreationError(PathBuf),
@@ -112,6 +710,26 @@ pub enum RestoreError {
     #[error("failed to write file {0}: {1}")]
     WriteFile(PathBuf, std::io::Error),
 
+    /// Error looking at a file already in the target directory.
+    #[error("failed to look at existing file {0}: {1}")]
+    Inspect(PathBuf, std::io::Error),
+
+    /// Error reading a file already in the target directory.
+    #[error(transparent)]
+    ChunkerError(#[from] ChunkerError),
+
+    /// Error removing a file that's in the way of a restored one.
+    #[error("failed to remove existing file {0}: {1}")]
+    RemoveExisting(PathBuf, std::io::Error),
+
+    /// Error writing a tar archive.
+    #[error("failed to write tar archive: {0}")]
+    WriteTar(std::io::Error),
+
+    /// Error removing a partially restored file.
+    #[error("failed to remove partially restored file {0}: {1}")]
+    RemoveFile(PathBuf, std::io::Error),
+
     /// Error creating a symbolic link.
     #[error("failed to create symbolic link {0}: {1}")]
     Symlink(PathBuf, std::io::Error),
@@ -127,29 +745,226 @@ pub enum RestoreError {
     /// Error settting timestamp.
     #[error("failed to set timestamp for {0}: {1}")]
     SetTimestamp(PathBuf, std::io::Error),
+
+    /// Error setting owner and group.
+    #[error("failed to set owner for {0}: {1}")]
+    Chown(PathBuf, std::io::Error),
+
+    /// The default owner does not exist on this system.
+    #[error("default owner {0:?} does not exist on this system")]
+    UnknownDefaultOwner(String),
+
+    /// The default group does not exist on this system.
+    #[error("default group {0:?} does not exist on this system")]
+    UnknownDefaultGroup(String),
+
+    /// The owner policy requires a default owner, but none was given.
+    #[error("owner policy 'default' requires --default-owner and --default-group")]
+    NoDefaultOwner,
+
+    /// Error writing the report of damaged files.
+    #[error("failed to write damage report {0}: {1}")]
+    DamageReport(PathBuf, serde_json::Error),
+
+    /// Some files couldn't be restored completely.
+    #[error("restore finished, but {0} damaged regions in {1} files were filled with zeros")]
+    Damaged(usize, usize),
+}
+
+/// Map owners of backed up files to owners of restored files.
+///
+/// Only root can give files to other users, so owners are only
+/// restored when running as root. Otherwise restored files are owned
+/// by the user running the restore, like with tar.
+pub(crate) struct OwnerMap {
+    policy: OwnerPolicy,
+    is_root: bool,
+    cache: UsersCache,
+    default_uid: Option<u32>,
+    default_gid: Option<u32>,
+}
+
+impl OwnerMap {
+    pub(crate) fn new(
+        policy: OwnerPolicy,
+        default_owner: Option<&str>,
+        default_group: Option<&str>,
+    ) -> Result<Self, RestoreError> {
+        let cache = UsersCache::new();
+        let default_uid = match default_owner {
+            None => None,
+            Some(name) => match cache.get_user_by_name(name) {
+                Some(user) => Some(user.uid()),
+                None => return Err(RestoreError::UnknownDefaultOwner(name.to_string())),
+            },
+        };
+        let default_gid = match default_group {
+            None => None,
+            Some(name) => match cache.get_group_by_name(name) {
+                Some(group) => Some(group.gid()),
+                None => return Err(RestoreError::UnknownDefaultGroup(name.to_string())),
+            },
+        };
+        if policy == OwnerPolicy::Default && (default_uid.is_none() || default_gid.is_none()) {
+            return Err(RestoreError::NoDefaultOwner);
+        }
+        Ok(Self {
+            policy,
+            is_root: users::get_effective_uid() == 0,
+            cache,
+            default_uid,
+            default_gid,
+        })
+    }
+
+    /// Return the local user and group ids that should own a
+    /// restored file, or None if owners aren't restored.
+    fn owner(&self, entry: &FilesystemEntry) -> Option<(u32, u32)> {
+        if !self.is_root {
+            return None;
+        }
+        let owner = match self.policy {
+            OwnerPolicy::Numeric => (entry.uid(), entry.gid()),
+            OwnerPolicy::Default => (self.default_uid.unwrap(), self.default_gid.unwrap()),
+            OwnerPolicy::ByName => {
+                let uid = self
+                    .cache
+                    .get_user_by_name(entry.user())
+                    .map(|user| user.uid())
+                    .or(self.default_uid)
+                    .unwrap_or_else(|| entry.uid());
+                let gid = self
+                    .cache
+                    .get_group_by_name(entry.group())
+                    .map(|group| group.gid())
+                    .or(self.default_gid)
+                    .unwrap_or_else(|| entry.gid());
+                (uid, gid)
+            }
+        };
+        Some(owner)
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn restore_generation(
     client: &BackupClient,
     gen: &LocalGeneration,
     fileid: FileId,
     entry: &FilesystemEntry,
     to: &Path,
-    progress: &ProgressBar,
-) -> Result<(), RestoreError> {
+    options: &RestoreOptions<'_>,
+    check: &ContentCheck,
+    progress: &RestoreProgress,
+    damaged: &mut Vec<DamagedRegion>,
+) -> Result<Outcome, RestoreError> {
     info!("restoring {:?}", entry);
-    progress.set_message(format!("{}", entry.pathbuf().display()));
-    progress.inc(1);
+    let owners = &options.owners;
 
     let to = restored_path(entry, to)?;
+    let outcome = clear_the_way(gen, fileid, entry, &to, check)?;
+    if outcome == Outcome::Skipped {
+        debug!("{} is already restored", to.display());
+        if entry.kind() != FilesystemKind::Directory {
+            restore_metadata(&to, entry, owners)?;
+        }
+        return Ok(outcome);
+    }
+
     match entry.kind() {
-        FilesystemKind::Regular => restore_regular(client, gen, &to, fileid, entry).await?,
+        FilesystemKind::Regular | FilesystemKind::Stream => {
+            restore_regular(client, gen, &to, fileid, entry, options, progress, damaged).await?
+        }
         FilesystemKind::Directory => restore_directory(&to)?,
-        FilesystemKind::Symlink => restore_symlink(&to, entry)?,
-        FilesystemKind::Socket => restore_socket(&to, entry)?,
-        FilesystemKind::Fifo => restore_fifo(&to, entry)?,
+        FilesystemKind::Symlink => restore_symlink(&to, entry, owners)?,
+        FilesystemKind::Socket => restore_socket(&to, entry, owners)?,
+        FilesystemKind::Fifo => restore_fifo(&to, entry, owners)?,
     }
-    Ok(())
+    Ok(outcome)
+}
+
+// Look at what's already at the place a file is restored to. If it's
+// the same as the backed up file, leave it be. Otherwise, remove it,
+// so that it doesn't get in the way. An existing file is removed
+// rather than written over, so that a symbolic link or hard link
+// doesn't make restore change some other file.
+fn clear_the_way(
+    gen: &LocalGeneration,
+    fileid: FileId,
+    entry: &FilesystemEntry,
+    path: &Path,
+    check: &ContentCheck,
+) -> Result<Outcome, RestoreError> {
+    let existing = match std::fs::symlink_metadata(path) {
+        Ok(existing) => existing,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Outcome::Created),
+        Err(err) => return Err(RestoreError::Inspect(path.to_path_buf(), err)),
+    };
+
+    let ft = existing.file_type();
+    let same = match entry.kind() {
+        FilesystemKind::Directory => ft.is_dir(),
+        FilesystemKind::Regular | FilesystemKind::Stream => {
+            ft.is_file()
+                && existing.len() == entry.len()
+                && existing.mtime() == entry.mtime()
+                && existing.mtime_nsec() == entry.mtime_ns()
+                && has_same_content(gen, fileid, path, check)?
+        }
+        FilesystemKind::Symlink => {
+            ft.is_symlink()
+                && std::fs::read_link(path)
+                    .map_err(|err| RestoreError::Inspect(path.to_path_buf(), err))?
+                    == entry.symlink_target().unwrap()
+        }
+        FilesystemKind::Socket => ft.is_socket(),
+        FilesystemKind::Fifo => ft.is_fifo(),
+    };
+    if same {
+        return Ok(Outcome::Skipped);
+    }
+
+    debug!("removing {} that's in the way", path.display());
+    if ft.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+    .map_err(|err| RestoreError::RemoveExisting(path.to_path_buf(), err))?;
+    Ok(Outcome::Overwritten)
+}
+
+// Does an existing file have the same content as a backed up one?
+// The existing file is split into chunks, and their labels compared
+// to those recorded in the generation. If the generation doesn't
+// record labels, the file is assumed to differ.
+fn has_same_content(
+    gen: &LocalGeneration,
+    fileid: FileId,
+    path: &Path,
+    check: &ContentCheck,
+) -> Result<bool, RestoreError> {
+    let mut labels = vec![];
+    for chunk in gen.file_chunks(fileid)?.iter()? {
+        match chunk?.label() {
+            Some(label) => labels.push(label.to_string()),
+            None => return Ok(false),
+        }
+    }
+
+    let file =
+        std::fs::File::open(path).map_err(|err| RestoreError::Inspect(path.to_path_buf(), err))?;
+    let mut chunks = FileChunks::new(check.chunk_size, file, path, check.labeler);
+    for label in labels {
+        let chunk = match chunks.next() {
+            Some(chunk) => chunk?,
+            None => return Ok(false),
+        };
+        if chunk.meta().label() != label {
+            return Ok(false);
+        }
+    }
+    Ok(chunks.next().is_none())
 }
 
 fn restore_directory(path: &Path) -> Result<(), RestoreError> {
@@ -159,10 +974,14 @@ fn restore_directory(path: &Path) -> Result<(), RestoreError> {
     Ok(())
 }
 
-fn restore_directory_metadata(entry: &FilesystemEntry, to: &Path) -> Result<(), RestoreError> {
+fn restore_directory_metadata(
+    entry: &FilesystemEntry,
+    to: &Path,
+    owners: &OwnerMap,
+) -> Result<(), RestoreError> {
     let to = restored_path(entry, to)?;
     match entry.kind() {
-        FilesystemKind::Directory => restore_metadata(&to, entry)?,
+        FilesystemKind::Directory => restore_metadata(&to, entry, owners)?,
         _ => panic!(
             "restore_directory_metadata called with non-directory {:?}",
             entry,
@@ -181,12 +1000,22 @@ fn restored_path(entry: &FilesystemEntry, to: &Path) -> Result<PathBuf, RestoreE
     Ok(to.join(path))
 }
 
+// If a chunk is missing or corrupt, and the restore keeps going, the
+// part of the file it would've filled is left as a hole, which reads
+// as zeros. The size of the hole is the chunk's length, as recorded
+// in the generation. If the lengths aren't recorded, the rest of the
+// file is left as a hole, since it's not known where the remaining
+// chunks go.
+#[allow(clippy::too_many_arguments)]
 async fn restore_regular(
     client: &BackupClient,
     gen: &LocalGeneration,
     path: &Path,
     fileid: FileId,
     entry: &FilesystemEntry,
+    options: &RestoreOptions<'_>,
+    progress: &RestoreProgress,
+    damaged: &mut Vec<DamagedRegion>,
 ) -> Result<(), RestoreError> {
     debug!("restoring regular {}", path.display());
     let parent = path.parent().unwrap();
@@ -196,19 +1025,97 @@ async fn restore_regular(
     {
         let mut file = std::fs::File::create(path)
             .map_err(|err| RestoreError::CreateFile(path.to_path_buf(), err))?;
-        for chunkid in gen.chunkids(fileid)?.iter()? {
-            let chunkid = chunkid?;
-            let chunk = client.fetch_chunk(&chunkid).await?;
+        // Fetch several chunks at once, but write them in order.
+        let (chunkids, lengths) = gen.chunk_ids_and_lengths(fileid)?;
+        let mut chunks = futures::stream::iter(chunkids.iter())
+            .map(|chunkid| client.fetch_cached_chunk(chunkid))
+            .buffered(options.jobs)
+            .enumerate();
+        let mut offset = 0;
+        loop {
+            let chunk = tokio::select! {
+                _ = options.cancel.cancelled() => None,
+                chunk = chunks.next() => match chunk {
+                    Some((_, Ok(chunk))) => Some(chunk),
+                    Some((i, Err(err))) if options.keep_going && err.is_damaged_chunk() => {
+                        let n = match lengths.get(i) {
+                            Some(length) => *length,
+                            None => entry.len().saturating_sub(offset),
+                        };
+                        damaged.push(damaged_region(&entry.pathbuf(), offset, n, &err, options));
+                        progress.bytes(n);
+                        offset += n;
+                        if lengths.is_empty() {
+                            break;
+                        }
+                        file.seek(std::io::SeekFrom::Start(offset))
+                            .map_err(|err| RestoreError::WriteFile(path.to_path_buf(), err))?;
+                        continue;
+                    }
+                    Some((_, Err(err))) => return Err(err.into()),
+                    None => break,
+                },
+            };
+            let chunk = match chunk {
+                Some(chunk) => chunk,
+                None => {
+                    info!("cancelled restore of {}", path.display());
+                    drop(file);
+                    std::fs::remove_file(path)
+                        .map_err(|err| RestoreError::RemoveFile(path.to_path_buf(), err))?;
+                    return Err(RestoreError::Cancelled);
+                }
+            };
             file.write_all(chunk.data())
                 .map_err(|err| RestoreError::WriteFile(path.to_path_buf(), err))?;
+            offset += chunk.data().len() as u64;
+            progress.bytes(chunk.data().len() as u64);
         }
-        restore_metadata(path, entry)?;
+        // A hole at the end of the file doesn't make the file longer
+        // by itself.
+        file.set_len(offset)
+            .map_err(|err| RestoreError::WriteFile(path.to_path_buf(), err))?;
+        restore_metadata(path, entry, &options.owners)?;
     }
     debug!("restored regular {}", path.display());
     Ok(())
 }
 
-fn restore_symlink(path: &Path, entry: &FilesystemEntry) -> Result<(), RestoreError> {
+// Note that a part of a file couldn't be restored.
+fn damaged_region(
+    path: &Path,
+    offset: u64,
+    length: u64,
+    err: &ClientError,
+    options: &RestoreOptions<'_>,
+) -> DamagedRegion {
+    warn!(
+        "can't restore {} bytes at offset {} of {}: {}",
+        length,
+        offset,
+        path.display(),
+        err
+    );
+    options.warnings.warning(&format!(
+        "can't restore {} bytes at offset {} of {}: {}",
+        length,
+        offset,
+        path.display(),
+        err
+    ));
+    DamagedRegion {
+        path: path.to_path_buf(),
+        offset,
+        length,
+        error: err.to_string(),
+    }
+}
+
+fn restore_symlink(
+    path: &Path,
+    entry: &FilesystemEntry,
+    owners: &OwnerMap,
+) -> Result<(), RestoreError> {
     debug!("restoring symlink {}", path.display());
     let parent = path.parent().unwrap();
     debug!("  mkdir {}", parent.display());
@@ -218,31 +1125,43 @@ fn restore_symlink(path: &Path, entry: &FilesystemEntry) -> Result<(), RestoreEr
     }
     symlink(entry.symlink_target().unwrap(), path)
         .map_err(|err| RestoreError::Symlink(path.to_path_buf(), err))?;
-    restore_metadata(path, entry)?;
+    restore_metadata(path, entry, owners)?;
     debug!("restored symlink {}", path.display());
     Ok(())
 }
 
-fn restore_socket(path: &Path, entry: &FilesystemEntry) -> Result<(), RestoreError> {
+fn restore_socket(
+    path: &Path,
+    entry: &FilesystemEntry,
+    owners: &OwnerMap,
+) -> Result<(), RestoreError> {
     debug!("creating Unix domain socket {:?}", path);
     UnixListener::bind(path).map_err(|err| RestoreError::UnixBind(path.to_path_buf(), err))?;
-    restore_metadata(path, entry)?;
+    restore_metadata(path, entry, owners)?;
     Ok(())
 }
 
-fn restore_fifo(path: &Path, entry: &FilesystemEntry) -> Result<(), RestoreError> {
+fn restore_fifo(
+    path: &Path,
+    entry: &FilesystemEntry,
+    owners: &OwnerMap,
+) -> Result<(), RestoreError> {
     debug!("creating fifo {:?}", path);
     let filename = path_to_cstring(path);
     match unsafe { mkfifo(filename.as_ptr(), 0) } {
         -1 => {
             return Err(RestoreError::NamedPipeCreationError(path.to_path_buf()));
         }
-        _ => restore_metadata(path, entry)?,
+        _ => restore_metadata(path, entry, owners)?,
     }
     Ok(())
 }
 
-fn restore_metadata(path: &Path, entry: &FilesystemEntry) -> Result<(), RestoreError> {
+fn restore_metadata(
+    path: &Path,
+    entry: &FilesystemEntry,
+    owners: &OwnerMap,
+) -> Result<(), RestoreError> {
     debug!("restoring metadata for {}", entry.pathbuf().display());
 
     debug!("restoring metadata for {:?}", path);
@@ -258,12 +1177,28 @@ fn restore_metadata(path: &Path, entry: &FilesystemEntry) -> Result<(), RestoreE
     let times = [atime, mtime];
     let times: *const timespec = &times[0];
 
+    let owner = owners.owner(entry);
+
     let pathbuf = path.to_path_buf();
     let path = path_to_cstring(path);
 
     // We have to use unsafe here to be able call the libc functions
     // below.
     unsafe {
+        // Change owner before mode, as chown may clear the
+        // set-user-id and set-group-id bits. Use lchown so that a
+        // symlink itself gets the owner, not the file it points at.
+        // Even root may not be allowed to change owners, such as on
+        // an NFS mount, or in a user namespace. The file is still
+        // restored, just owned by whoever runs the restore.
+        if let Some((uid, gid)) = owner {
+            debug!("lchown {:?} to {}:{}", path, uid, gid);
+            if lchown(path.as_ptr(), uid, gid) == -1 {
+                let error = Error::last_os_error();
+                warn!("{}", RestoreError::Chown(pathbuf.clone(), error));
+            }
+        }
+
         if entry.kind() != FilesystemKind::Symlink {
             debug!("chmod {:?}", path);
             if chmod(path.as_ptr(), entry.mode() as libc::mode_t) == -1 {
@@ -293,19 +1228,283 @@ fn path_to_cstring(path: &Path) -> CString {
     CString::new(path).unwrap()
 }
 
-fn create_progress_bar(file_count: FileId, verbose: bool) -> ProgressBar {
-    let progress = if verbose {
-        ProgressBar::new(file_count as u64)
+// How many bytes of file content are there to restore? Backups
+// record this in their metadata, but older ones don't, so then the
+// sizes of files are summed up.
+fn bytes_to_restore(gen: &LocalGeneration) -> Result<u64, ObnamError> {
+    match gen.meta()?.file_bytes()? {
+        Some(bytes) => Ok(bytes),
+        None => Ok(gen.stats()?.total().bytes()),
+    }
+}
+
+// How many bytes of content a file has in the backup.
+fn content_len(entry: &FilesystemEntry) -> u64 {
+    if entry.kind().has_content() {
+        entry.len()
     } else {
-        ProgressBar::hidden()
-    };
-    let parts = vec![
-        "{wide_bar}",
-        "elapsed: {elapsed}",
-        "files: {pos}/{len}",
-        "current: {wide_msg}",
-        "{spinner}",
-    ];
-    progress.set_style(ProgressStyle::default_bar().template(&parts.join("\n")));
-    progress
+        0
+    }
+}
+
+// Progress of a restore. The bar measures bytes of file content
+// written, so that it can show throughput and how long the rest of
+// the restore will take. Files are counted alongside.
+struct RestoreProgress {
+    bar: ProgressBar,
+    file_count: FileId,
+    // Without a progress bar, when the progress was last logged, and
+    // how many files have been started.
+    logged: Option<Mutex<(Instant, FileId)>>,
+}
+
+impl RestoreProgress {
+    fn new(file_count: FileId, bytes: u64, verbose: bool) -> Self {
+        let (bar, logged) = if verbose {
+            (ProgressBar::new(bytes), None)
+        } else {
+            let bar = ProgressBar::with_draw_target(bytes, ProgressDrawTarget::hidden());
+            (bar, Some(Mutex::new((Instant::now(), 0))))
+        };
+        let parts = [
+            "{wide_bar}",
+            "elapsed: {elapsed}",
+            "files: {prefix}",
+            "bytes: {bytes}/{total_bytes} ({binary_bytes_per_sec}), eta: {eta}",
+            "current: {wide_msg}",
+            "{spinner}",
+        ];
+        bar.set_style(ProgressStyle::default_bar().template(&parts.join("\n")));
+        bar.set_prefix(format!("0/{}", file_count));
+        Self {
+            bar,
+            file_count,
+            logged,
+        }
+    }
+
+    // A file is being restored, after `done` other files.
+    fn file_started(&self, done: FileId, path: &Path) {
+        self.bar
+            .set_prefix(format!("{}/{}", done + 1, self.file_count));
+        self.bar.set_message(format!("{}", path.display()));
+        self.log(Some(done + 1));
+    }
+
+    // Some bytes of file content have been restored.
+    fn bytes(&self, n: u64) {
+        self.bar.inc(n);
+        self.log(None);
+    }
+
+    // Without a progress bar, log the progress every so often.
+    fn log(&self, started: Option<FileId>) {
+        if let Some(logged) = &self.logged {
+            let mut logged = logged.lock().unwrap();
+            if let Some(started) = started {
+                logged.1 = started;
+            }
+            if logged.0.elapsed() >= PROGRESS_LOG_INTERVAL {
+                info!(
+                    "restore progress: files: {}/{}, bytes: {}/{}",
+                    logged.1,
+                    self.file_count,
+                    HumanBytes(self.bar.position()),
+                    HumanBytes(self.bar.length()),
+                );
+                logged.0 = Instant::now();
+            }
+        }
+    }
+
+    fn finish(&self) {
+        self.bar.finish();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{restore_metadata, OwnerMap, OwnerPolicy, Restore, RestoreError};
+    use crate::error::ErrorKind;
+    use crate::fsentry::{EntryBuilder, FilesystemEntry, FilesystemKind};
+    use crate::testing::TestRepo;
+    use clap::Parser;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::PathBuf;
+    use tempfile::{tempdir, NamedTempFile, TempDir};
+    use tokio_util::sync::CancellationToken;
+    use users::UsersCache;
+
+    // An entry owned by ids and names that needn't match each other.
+    fn entry(uid: u32, user: &str, gid: u32, group: &str) -> FilesystemEntry {
+        entry_of_kind(FilesystemKind::Regular, uid, user, gid, group)
+    }
+
+    fn entry_of_kind(
+        kind: FilesystemKind,
+        uid: u32,
+        user: &str,
+        gid: u32,
+        group: &str,
+    ) -> FilesystemEntry {
+        let e = EntryBuilder::new(kind).build();
+        let mut json = serde_json::to_value(&e).unwrap();
+        json["uid"] = uid.into();
+        json["user"] = user.into();
+        json["gid"] = gid.into();
+        json["group"] = group.into();
+        serde_json::from_value(json).unwrap()
+    }
+
+    // An owner map as if running as root, or not.
+    fn owners(policy: OwnerPolicy, is_root: bool, default: Option<(u32, u32)>) -> OwnerMap {
+        OwnerMap {
+            policy,
+            is_root,
+            cache: UsersCache::new(),
+            default_uid: default.map(|(uid, _)| uid),
+            default_gid: default.map(|(_, gid)| gid),
+        }
+    }
+
+    #[test]
+    fn owners_are_not_restored_by_normal_user() {
+        let map = owners(OwnerPolicy::Numeric, false, None);
+        assert_eq!(map.owner(&entry(1234, "", 5678, "")), None);
+    }
+
+    #[test]
+    fn numeric_policy_uses_stored_ids() {
+        let map = owners(OwnerPolicy::Numeric, true, Some((1, 2)));
+        assert_eq!(
+            map.owner(&entry(1234, "root", 5678, "root")),
+            Some((1234, 5678))
+        );
+    }
+
+    #[test]
+    fn default_policy_uses_default_owner() {
+        let map = owners(OwnerPolicy::Default, true, Some((1, 2)));
+        assert_eq!(map.owner(&entry(1234, "root", 5678, "root")), Some((1, 2)));
+    }
+
+    #[test]
+    fn by_name_policy_maps_names_to_local_ids() {
+        let map = owners(OwnerPolicy::ByName, true, None);
+        assert_eq!(map.owner(&entry(1234, "root", 5678, "root")), Some((0, 0)));
+    }
+
+    #[test]
+    fn by_name_policy_falls_back_for_unknown_names() {
+        let unknown = entry(1234, "no-such-obnam-user", 5678, "no-such-obnam-group");
+        let map = owners(OwnerPolicy::ByName, true, Some((1, 2)));
+        assert_eq!(map.owner(&unknown), Some((1, 2)));
+        let map = owners(OwnerPolicy::ByName, true, None);
+        assert_eq!(map.owner(&unknown), Some((1234, 5678)));
+    }
+
+    // A symbolic link to a new file, in a temporary directory.
+    fn symlink_to_file() -> (TempDir, PathBuf, PathBuf) {
+        let dir = tempdir().unwrap();
+        let target = dir.path().join("target");
+        let link = dir.path().join("link");
+        std::fs::write(&target, "data").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        (dir, target, link)
+    }
+
+    #[test]
+    fn restores_mtime_of_symlink_not_its_target() {
+        let (_dir, target, link) = symlink_to_file();
+        let before = std::fs::metadata(&target).unwrap();
+
+        let mut json =
+            serde_json::to_value(entry_of_kind(FilesystemKind::Symlink, 0, "", 0, "")).unwrap();
+        json["mtime"] = 1_000_000_000.into();
+        let entry: FilesystemEntry = serde_json::from_value(json).unwrap();
+        let map = owners(OwnerPolicy::Numeric, false, None);
+        restore_metadata(&link, &entry, &map).unwrap();
+
+        let meta = std::fs::symlink_metadata(&link).unwrap();
+        assert_eq!(meta.mtime(), 1_000_000_000);
+        let after = std::fs::metadata(&target).unwrap();
+        assert_eq!(after.mtime(), before.mtime());
+        assert_eq!(after.mode(), before.mode());
+        assert_eq!((after.uid(), after.gid()), (before.uid(), before.gid()));
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn restores_owner_of_symlink_not_its_target() {
+        let (_dir, target, link) = symlink_to_file();
+
+        let entry = entry_of_kind(FilesystemKind::Symlink, 1234, "", 5678, "");
+        let map = owners(OwnerPolicy::Numeric, true, None);
+        restore_metadata(&link, &entry, &map).unwrap();
+
+        let meta = std::fs::symlink_metadata(&link).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (1234, 5678));
+        let meta = std::fs::metadata(&target).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (0, 0));
+    }
+
+    #[test]
+    fn default_policy_needs_default_owner() {
+        assert!(matches!(
+            OwnerMap::new(OwnerPolicy::Default, None, None),
+            Err(RestoreError::NoDefaultOwner)
+        ));
+        assert!(matches!(
+            OwnerMap::new(OwnerPolicy::ByName, Some("no-such-obnam-user"), None),
+            Err(RestoreError::UnknownDefaultOwner(_))
+        ));
+        assert!(OwnerMap::new(OwnerPolicy::Default, Some("root"), Some("root")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn keep_going_fills_lost_chunk_with_zeros() {
+        let repo = TestRepo::with_settings("chunk_size: 1024\n");
+        let path = repo.live().join("data");
+        let mut data = vec![1; 1024];
+        data.extend(vec![2; 1024]);
+        data.extend(vec![3; 1000]);
+        std::fs::write(&path, &data).unwrap();
+        let backup = repo.backup().await.unwrap();
+
+        // Lose the middle chunk of the file.
+        let client = repo.client();
+        let temp = NamedTempFile::new().unwrap();
+        let gen = client
+            .fetch_generation(
+                &backup.generation_id,
+                temp.path(),
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let fileno = gen.get_fileno(&path).unwrap().unwrap();
+        let (ids, _) = gen.chunk_ids_and_lengths(fileno).unwrap();
+        assert_eq!(ids.len(), 3);
+        repo.remove_chunk(&ids[1]);
+
+        let to = tempdir().unwrap();
+        assert!(repo.restore("latest", to.path(), false).await.is_err());
+        let report = repo.restore("latest", to.path(), true).await.unwrap();
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].path, path);
+        assert_eq!(report.damaged[0].offset, 1024);
+        assert_eq!(report.damaged[0].length, 1024);
+
+        let restored = std::fs::read(to.path().join(path.strip_prefix("/").unwrap())).unwrap();
+        assert_eq!(restored.len(), data.len());
+        assert_eq!(&restored[..1024], &data[..1024]);
+        assert!(restored[1024..2048].iter().all(|b| *b == 0));
+        assert_eq!(&restored[2048..], &data[2048..]);
+
+        // The command line program fails after such a restore.
+        let cmd = Restore::try_parse_from(["restore", "latest", "x", "--keep-going"]).unwrap();
+        let err = cmd.report_damage(&report.damaged).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Failure));
+        assert_ne!(err.kind().exit_code(), 0);
+    }
 }
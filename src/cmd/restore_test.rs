@@ -0,0 +1,291 @@
+//! The `restore-test` subcommand.
+
+use crate::backup_reason::Reason;
+use crate::client::{BackupClient, ClientError};
+use crate::cmd::{GlobalOptions, Summary};
+use crate::config::ClientConfig;
+use crate::dbgen::FileId;
+use crate::error::ObnamError;
+use crate::fsentry::{FilesystemEntry, FilesystemKind};
+use crate::generation::{GenId, LocalGeneration, LocalGenerationError};
+use crate::label::{Label, LabelChecksumKind, LabelError};
+use crate::notify::{self, Notification, Operation};
+use clap::Parser;
+use log::{debug, error, info, warn};
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use tempfile::{tempdir, NamedTempFile};
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+
+/// Check that files can actually be restored.
+///
+/// Restore a random sample of regular files from a backup generation
+/// into a temporary directory, verify their content against the
+/// checksums in the backup, and delete them again.
+#[derive(Debug, Parser)]
+pub struct RestoreTest {
+    /// Reference to generation to test.
+    #[clap(default_value = "latest")]
+    gen_id: String,
+
+    /// Maximum number of files to restore.
+    #[clap(long, default_value = "10")]
+    count: usize,
+
+    /// Maximum number of bytes of file content to restore.
+    #[clap(long)]
+    max_bytes: Option<u64>,
+}
+
+impl RestoreTest {
+    /// Run the command.
+    pub fn run(&self, config: &ClientConfig, global: &GlobalOptions) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        let mut stats = Summary::default();
+        let result = rt.block_on(self.run_async(config, global, &mut stats));
+        notify::send(
+            &config.notify,
+            &Notification::new(
+                &config.client_name,
+                Operation::RestoreTest,
+                result.as_ref().err(),
+                stats.to_json(),
+            ),
+        );
+        result
+    }
+
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+        stats: &mut Summary,
+    ) -> Result<(), ObnamError> {
+        let temp = NamedTempFile::new()?;
+
+        let client = BackupClient::new(config).await?;
+        let trust = client.get_client_trust().await?;
+
+        let genlist = client.list_generations(&trust);
+        let gen_id = genlist.resolve(&self.gen_id)?;
+        info!("testing restore of generation {}", gen_id.as_chunk_id());
+
+        let gen = client
+            .fetch_generation(&gen_id, temp.path(), &CancellationToken::new())
+            .await?;
+        for feature in gen.check_features()? {
+            warn!("backup uses unsupported feature {}, ignoring it", feature);
+            if !global.quiet {
+                eprintln!("WARNING: ignoring unsupported feature {}", feature);
+            }
+        }
+        let kind = match gen.meta()?.get("checksum_kind") {
+            Some(v) => LabelChecksumKind::from(v)?,
+            None => LabelChecksumKind::Sha256,
+        };
+
+        let sample = self.sample(&gen)?;
+        let dir = tempdir()?;
+
+        let mut failures = 0;
+        let mut bytes = 0;
+        let mut tested = vec![];
+        for (fileno, entry) in sample.iter() {
+            let filename = dir.path().join(format!("{}", fileno));
+            let result = restore_and_verify(&client, &gen, *fileno, entry, &filename, kind).await;
+            let error = match result {
+                Ok(()) => {
+                    bytes += entry.len();
+                    None
+                }
+                Err(err) => {
+                    error!(
+                        "restore test of {} failed: {}",
+                        entry.pathbuf().display(),
+                        err
+                    );
+                    failures += 1;
+                    Some(err.to_string())
+                }
+            };
+            tested.push(TestedFile {
+                path: entry.pathbuf(),
+                error,
+            });
+            // Don't let restored files accumulate on disk.
+            std::fs::remove_file(&filename).ok();
+        }
+
+        stats.add("generation-id", gen_id.to_string());
+        stats.add("file-count", sample.len());
+        stats.add_bytes("restored-bytes", bytes);
+        stats.add("failed", failures);
+        if global.json {
+            stats.add_json("files", &tested)?;
+        } else {
+            for file in tested.iter() {
+                match &file.error {
+                    None if global.quiet => (),
+                    None => println!("ok {}", file.path.display()),
+                    Some(err) => println!("FAILED {}: {}", file.path.display(), err),
+                }
+            }
+        }
+        stats.print(global)?;
+
+        if sample.is_empty() {
+            Err(RestoreTestError::NothingToTest(gen_id).into())
+        } else if failures > 0 {
+            Err(RestoreTestError::Failed(failures, sample.len()).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Pick a random sample of regular files, within the limits given
+    // by the user.
+    fn sample(
+        &self,
+        gen: &LocalGeneration,
+    ) -> Result<Vec<(FileId, FilesystemEntry)>, RestoreTestError> {
+        let mut candidates = vec![];
+        for file in gen.files()?.iter()? {
+            let (fileno, entry, reason, _) = file?;
+            if entry.kind() == FilesystemKind::Regular && !matches!(reason, Reason::FileError) {
+                candidates.push((fileno, entry));
+            }
+        }
+        candidates.shuffle(&mut rand::thread_rng());
+
+        let mut sample = vec![];
+        let mut bytes = 0;
+        for (fileno, entry) in candidates {
+            if sample.len() >= self.count {
+                break;
+            }
+            if let Some(max) = self.max_bytes {
+                if bytes + entry.len() > max {
+                    continue;
+                }
+            }
+            bytes += entry.len();
+            sample.push((fileno, entry));
+        }
+        Ok(sample)
+    }
+}
+
+// The outcome of testing one file.
+#[derive(Serialize)]
+struct TestedFile {
+    path: PathBuf,
+    // Why the file couldn't be restored correctly, if it couldn't.
+    error: Option<String>,
+}
+
+/// Possible errors from testing restores.
+#[derive(Debug, thiserror::Error)]
+pub enum RestoreTestError {
+    /// Some of the sampled files could not be restored correctly.
+    #[error("restore test failed for {0} of {1} files")]
+    Failed(usize, usize),
+
+    /// The generation has no regular files to test, within the limits
+    /// given.
+    #[error("generation {0} has no files to test")]
+    NothingToTest(GenId),
+
+    /// Restored file has the wrong size.
+    #[error("restored file {0} has {1} bytes, expected {2}")]
+    WrongSize(PathBuf, u64, u64),
+
+    /// Restored file content doesn't match checksum in backup.
+    #[error("restored file {0} doesn't match checksum of chunk at offset {1}")]
+    WrongChecksum(PathBuf, u64),
+
+    /// Error writing restored file.
+    #[error("failed to write file {0}: {1}")]
+    WriteFile(PathBuf, std::io::Error),
+
+    /// Error reading restored file back.
+    #[error("failed to read file {0}: {1}")]
+    ReadFile(PathBuf, std::io::Error),
+
+    /// Error from chunk label.
+    #[error(transparent)]
+    Label(#[from] LabelError),
+
+    /// Error from HTTP client.
+    #[error(transparent)]
+    ClientError(#[from] ClientError),
+
+    /// Error from local generation.
+    #[error(transparent)]
+    LocalGenerationError(#[from] LocalGenerationError),
+
+    /// Error from a Database.
+    #[error(transparent)]
+    Database(#[from] crate::db::DatabaseError),
+}
+
+// Restore one file and verify that what ended up on disk matches
+// what's in the backup: the file has the right size, and each chunk's
+// worth of data has the checksum recorded for that chunk.
+async fn restore_and_verify(
+    client: &BackupClient,
+    gen: &LocalGeneration,
+    fileno: FileId,
+    entry: &FilesystemEntry,
+    filename: &Path,
+    kind: LabelChecksumKind,
+) -> Result<(), RestoreTestError> {
+    debug!(
+        "restoring {} to {}",
+        entry.pathbuf().display(),
+        filename.display()
+    );
+
+    let mut chunks = vec![];
+    {
+        let mut file = File::create(filename)
+            .map_err(|err| RestoreTestError::WriteFile(filename.to_path_buf(), err))?;
+        for chunkid in gen.chunkids(fileno)?.iter()? {
+            let chunk = client.fetch_chunk(&chunkid?).await?;
+            file.write_all(chunk.data())
+                .map_err(|err| RestoreTestError::WriteFile(filename.to_path_buf(), err))?;
+            let label = Label::deserialize(chunk.meta().label())?;
+            chunks.push((chunk.data().len(), label.serialize()));
+        }
+    }
+
+    let size = std::fs::metadata(filename)
+        .map_err(|err| RestoreTestError::ReadFile(filename.to_path_buf(), err))?
+        .len();
+    if size != entry.len() {
+        return Err(RestoreTestError::WrongSize(
+            entry.pathbuf(),
+            size,
+            entry.len(),
+        ));
+    }
+
+    let mut file = File::open(filename)
+        .map_err(|err| RestoreTestError::ReadFile(filename.to_path_buf(), err))?;
+    let mut offset = 0;
+    for (len, expected) in chunks {
+        let mut buf = vec![0; len];
+        file.read_exact(&mut buf)
+            .map_err(|err| RestoreTestError::ReadFile(filename.to_path_buf(), err))?;
+        let actual = client.labeler(kind).label(&buf);
+        if actual.serialize() != expected {
+            return Err(RestoreTestError::WrongChecksum(entry.pathbuf(), offset));
+        }
+        offset += len as u64;
+    }
+
+    Ok(())
+}
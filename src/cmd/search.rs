@@ -0,0 +1,152 @@
+//! The `search` subcommand.
+
+use crate::client::BackupClient;
+use crate::cmd::GlobalOptions;
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use crate::generation::{FinishedGeneration, GenId};
+use crate::pathmatch::PathMatcher;
+use clap::Parser;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tempfile::NamedTempFile;
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+
+/// Search for backed up files by name.
+///
+/// By default, the latest backup is searched for files whose path
+/// contains the pattern, and their paths are listed. When more than
+/// one backup is searched, each matching path is followed by the
+/// backups that contain it, oldest first. This requires downloading
+/// the metadata of each searched backup, which can be slow.
+#[derive(Debug, Parser)]
+pub struct Search {
+    /// What to search for.
+    pattern: String,
+
+    /// The pattern is a shell-style glob. A glob without a slash is
+    /// matched against the last component of each path.
+    #[clap(long, conflicts_with = "regex")]
+    glob: bool,
+
+    /// The pattern is a regular expression, which may match anywhere
+    /// in a path.
+    #[clap(long)]
+    regex: bool,
+
+    /// Search this backup, instead of the latest one. Can be given
+    /// more than once.
+    #[clap(long = "generation", value_name = "GEN")]
+    generations: Vec<String>,
+
+    /// Search all backups.
+    #[clap(long, conflicts_with = "generations")]
+    all_generations: bool,
+}
+
+impl Search {
+    /// Run the command.
+    pub fn run(&self, config: &ClientConfig, global: &GlobalOptions) -> Result<(), ObnamError> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(config, global))
+    }
+
+    async fn run_async(
+        &self,
+        config: &ClientConfig,
+        global: &GlobalOptions,
+    ) -> Result<(), ObnamError> {
+        let matcher = if self.glob {
+            PathMatcher::glob(&self.pattern)?
+        } else if self.regex {
+            PathMatcher::regex(&self.pattern)?
+        } else {
+            PathMatcher::substring(&self.pattern)
+        };
+
+        let client = BackupClient::new(config).await?;
+        let trust = client.get_client_trust().await?;
+        let genlist = client.list_generations(&trust);
+        let searched: Vec<&FinishedGeneration> = if self.all_generations {
+            genlist.iter().collect()
+        } else {
+            let refs = if self.generations.is_empty() {
+                vec!["latest".to_string()]
+            } else {
+                self.generations.clone()
+            };
+            let mut ids = vec![];
+            for genref in refs.iter() {
+                ids.push(genlist.resolve(genref)?);
+            }
+            genlist
+                .iter()
+                .filter(|gen| ids.contains(gen.id()))
+                .collect()
+        };
+
+        // Matching paths, with the id and end time of each searched
+        // backup that contains them.
+        let mut found: BTreeMap<PathBuf, Vec<(GenId, String)>> = BTreeMap::new();
+        for finished in searched.iter() {
+            let temp = NamedTempFile::new()?;
+            let gen = client
+                .fetch_generation(finished.id(), temp.path(), &CancellationToken::new())
+                .await?;
+            let ended = gen.meta()?.ended().unwrap_or("-").to_string();
+            for file in gen.files()?.iter()? {
+                let (_, entry, _, _) = file?;
+                let path = entry.pathbuf();
+                if matcher.is_match(&path) {
+                    found
+                        .entry(path)
+                        .or_default()
+                        .push((finished.id().clone(), ended.clone()));
+                }
+            }
+        }
+
+        if global.json {
+            let listed: Vec<Found> = found
+                .into_iter()
+                .map(|(path, gens)| Found {
+                    path,
+                    generations: gens
+                        .into_iter()
+                        .map(|(id, ended)| FoundIn {
+                            id: id.to_string(),
+                            ended,
+                        })
+                        .collect(),
+                })
+                .collect();
+            return global.print_json(&listed);
+        }
+
+        for (path, gens) in found {
+            println!("{}", path.display());
+            if searched.len() > 1 {
+                for (id, ended) in gens {
+                    println!("  {} {}", id, ended);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A matching path, for `--json`, with the searched backups that
+// contain it.
+#[derive(Serialize)]
+struct Found {
+    path: PathBuf,
+    generations: Vec<FoundIn>,
+}
+
+#[derive(Serialize)]
+struct FoundIn {
+    id: String,
+    ended: String,
+}
@@ -1,17 +1,98 @@
 //! The `show-config` subcommand.
 
+use crate::cmd::init::check_server;
+use crate::cmd::GlobalOptions;
 use crate::config::ClientConfig;
 use crate::error::ObnamError;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tokio::runtime::Runtime;
 
 /// Show actual client configuration.
+///
+/// Any passwords in URLs are hidden.
 #[derive(Debug, Parser)]
-pub struct ShowConfig {}
+pub struct ShowConfig {
+    #[clap(subcommand)]
+    cmd: Option<ConfigCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Show the configuration, and check that it can be used: the
+    /// server can be reached, the client's keys are readable only by
+    /// its owner, and the backup roots can be read. Fail if there are
+    /// any problems.
+    Validate,
+}
 
 impl ShowConfig {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
-        println!("{}", serde_json::to_string_pretty(config)?);
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
+        println!("{}", serde_json::to_string_pretty(&config.redacted())?);
+        match self.cmd {
+            None => Ok(()),
+            Some(ConfigCommand::Validate) => validate(config),
+        }
+    }
+}
+
+// Check the configuration, and report each check.
+fn validate(config: &ClientConfig) -> Result<(), ObnamError> {
+    let rt = Runtime::new()?;
+    let mut problems = 0;
+    let mut report = |result: Result<String, String>| match result {
+        Ok(msg) => println!("ok: {}", msg),
+        Err(msg) => {
+            println!("problem: {}", msg);
+            problems += 1;
+        }
+    };
+
+    report(
+        rt.block_on(check_server(config))
+            .map(|_| format!("server {} can be reached", config.server_url))
+            .map_err(|err| err.to_string()),
+    );
+    report(check_passwords(config));
+    for root in config.roots.iter() {
+        report(check_root(root));
+    }
+
+    if problems > 0 {
+        Err(ObnamError::BadConfig(problems))
+    } else {
         Ok(())
     }
 }
+
+fn check_passwords(config: &ClientConfig) -> Result<String, String> {
+    let filename = config.passwords_filename();
+    let metadata = std::fs::metadata(&filename).map_err(|err| {
+        format!(
+            "can't read keys from {}: {}; you may need to run 'obnam init'",
+            filename.display(),
+            err
+        )
+    })?;
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return Err(format!(
+            "keys in {} can be read by others than their owner",
+            filename.display()
+        ));
+    }
+    config.passwords().map_err(|err| err.to_string())?;
+    Ok(format!("keys in {}", filename.display()))
+}
+
+fn check_root(root: &Path) -> Result<String, String> {
+    let readable = match std::fs::metadata(root) {
+        Ok(metadata) if metadata.is_dir() => std::fs::read_dir(root).map(|_| ()),
+        Ok(_) => std::fs::File::open(root).map(|_| ()),
+        Err(err) => Err(err),
+    };
+    readable
+        .map(|_| format!("backup root {} can be read", root.display()))
+        .map_err(|err| format!("can't read backup root {}: {}", root.display(), err))
+}
@@ -1,17 +1,18 @@
 //! The `show-generation` subcommand.
 
-use crate::chunk::ClientTrust;
+use crate::backup_reason::Reason;
 use crate::client::BackupClient;
+use crate::cmd::GlobalOptions;
 use crate::config::ClientConfig;
 use crate::db::DbInt;
 use crate::error::ObnamError;
-use crate::fsentry::FilesystemKind;
 use crate::generation::GenId;
 use clap::Parser;
 use indicatif::HumanBytes;
 use serde::Serialize;
 use tempfile::NamedTempFile;
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 
 /// Show information about a generation.
 #[derive(Debug, Parser)]
@@ -23,41 +24,42 @@ pub struct ShowGeneration {
 
 impl ShowGeneration {
     /// Run the command.
-    pub fn run(&self, config: &ClientConfig) -> Result<(), ObnamError> {
+    pub fn run(&self, config: &ClientConfig, _global: &GlobalOptions) -> Result<(), ObnamError> {
         let rt = Runtime::new()?;
         rt.block_on(self.run_async(config))
     }
 
     async fn run_async(&self, config: &ClientConfig) -> Result<(), ObnamError> {
         let temp = NamedTempFile::new()?;
-        let client = BackupClient::new(config)?;
-        let trust = client
-            .get_client_trust()
-            .await?
-            .or_else(|| Some(ClientTrust::new("FIXME", None, "".to_string(), vec![])))
-            .unwrap();
+        let client = BackupClient::new(config).await?;
+        let trust = client.get_client_trust().await?;
 
         let genlist = client.list_generations(&trust);
         let gen_id = genlist.resolve(&self.gen_id)?;
-        let gen = client.fetch_generation(&gen_id, temp.path()).await?;
-        let mut files = gen.files()?;
-        let mut files = files.iter()?;
-
-        let total_bytes = files.try_fold(0, |acc, file| {
-            file.map(|(_, e, _, _)| {
-                if e.kind() == FilesystemKind::Regular {
-                    acc + e.len()
-                } else {
-                    acc
-                }
-            })
-        });
-        let total_bytes = total_bytes?;
+        let gen = client
+            .fetch_generation(&gen_id, temp.path(), &CancellationToken::new())
+            .await?;
+        let mut total_bytes = 0;
+        let mut file_errors = vec![];
+        for file in gen.files()?.iter()? {
+            let (_, e, reason, _) = file?;
+            if e.kind().has_content() {
+                total_bytes += e.len();
+            }
+            if matches!(reason, Reason::FileError) {
+                let filename = e.pathbuf();
+                file_errors.push(FileError {
+                    error: gen.file_error(&filename)?,
+                    filename: filename.display().to_string(),
+                });
+            }
+        }
 
         let output = Output::new(gen_id)
             .db_bytes(temp.path().metadata()?.len())
             .file_count(gen.file_count()?)
-            .file_bytes(total_bytes);
+            .file_bytes(total_bytes)
+            .file_errors(file_errors);
         serde_json::to_writer_pretty(std::io::stdout(), &output)?;
 
         Ok(())
@@ -72,6 +74,15 @@ struct Output {
     file_bytes_raw: u64,
     db_bytes: String,
     db_bytes_raw: u64,
+    file_errors: Vec<FileError>,
+}
+
+// A file whose content couldn't be backed up, and why, if the
+// generation says.
+#[derive(Debug, Serialize)]
+struct FileError {
+    filename: String,
+    error: Option<String>,
 }
 
 impl Output {
@@ -98,4 +109,9 @@ impl Output {
         self.db_bytes = HumanBytes(n).to_string();
         self
     }
+
+    fn file_errors(mut self, errors: Vec<FileError>) -> Self {
+        self.file_errors = errors;
+        self
+    }
 }
@@ -0,0 +1,294 @@
+//! Adaptive limit on concurrent uploads.
+
+use log::debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+// How much slower than usual a request may be before we consider the
+// network congested.
+const CONGESTION_FACTOR: f64 = 2.0;
+
+// Weight of the newest latency measurement in the moving average.
+const LATENCY_WEIGHT: f64 = 0.1;
+
+/// Adjust the number of concurrent uploads to what the network can
+/// handle.
+///
+/// This uses additive increase, multiplicative decrease (AIMD), the
+/// same approach as TCP congestion control. Each completed request
+/// that isn't unusually slow allows the limit to grow, by about one
+/// for each full window of requests. A failed request, or one that
+/// takes much longer than the moving average, halves the limit. The
+/// limit stays within the bounds given at creation.
+///
+/// On a fast local network the limit grows until the configured
+/// maximum; on a slow or flaky link it stays low.
+#[derive(Debug, Clone)]
+pub struct AdaptiveConcurrency {
+    min: usize,
+    max: usize,
+    limit: f64,
+    average: Option<f64>,
+    since_decrease: usize,
+    peak: usize,
+    successes: u64,
+    failures: u64,
+    decreases: u64,
+}
+
+impl AdaptiveConcurrency {
+    /// Create a new controller with bounds for the limit.
+    ///
+    /// The limit starts at the minimum.
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        Self {
+            min,
+            max,
+            limit: min as f64,
+            average: None,
+            since_decrease: 0,
+            peak: min,
+            successes: 0,
+            failures: 0,
+            decreases: 0,
+        }
+    }
+
+    /// How many requests may be in flight at once now?
+    pub fn limit(&self) -> usize {
+        (self.limit as usize).clamp(self.min, self.max)
+    }
+
+    /// Record a successful request and how long it took.
+    pub fn succeeded(&mut self, latency: Duration) {
+        self.successes += 1;
+        self.since_decrease += 1;
+
+        let latency = latency.as_secs_f64();
+        let congested = match self.average {
+            Some(average) => latency > average * CONGESTION_FACTOR,
+            None => false,
+        };
+        self.average = Some(match self.average {
+            None => latency,
+            Some(average) => average * (1.0 - LATENCY_WEIGHT) + latency * LATENCY_WEIGHT,
+        });
+
+        if congested {
+            self.decrease();
+        } else {
+            self.limit = (self.limit + 1.0 / self.limit).min(self.max as f64);
+            self.peak = self.peak.max(self.limit());
+        }
+    }
+
+    /// Record a failed request.
+    pub fn failed(&mut self) {
+        self.failures += 1;
+        self.since_decrease += 1;
+        self.decrease();
+    }
+
+    // Halve the limit, but at most once per window of requests, as
+    // all the requests in flight are likely to suffer from the same
+    // congestion.
+    fn decrease(&mut self) {
+        if self.since_decrease >= self.limit() {
+            self.limit = (self.limit / 2.0).max(self.min as f64);
+            self.since_decrease = 0;
+            self.decreases += 1;
+            debug!("decreased upload concurrency to {}", self.limit());
+        }
+    }
+
+    /// Highest limit reached so far.
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+
+    /// Number of successful requests.
+    pub fn successes(&self) -> u64 {
+        self.successes
+    }
+
+    /// Number of failed requests.
+    pub fn failures(&self) -> u64 {
+        self.failures
+    }
+
+    /// Number of times the limit was decreased.
+    pub fn decreases(&self) -> u64 {
+        self.decreases
+    }
+}
+
+/// Limit the uploads in flight, with a shared adaptive limit.
+///
+/// Clones share the same limit. Each upload request takes a slot
+/// before it's sent, and reports how it went, which lets the limit
+/// adapt.
+#[derive(Debug, Clone)]
+pub struct UploadLimiter {
+    state: Arc<Mutex<LimiterState>>,
+    released: Arc<Notify>,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    concurrency: AdaptiveConcurrency,
+    in_flight: usize,
+}
+
+impl UploadLimiter {
+    /// Create a new limiter with bounds for the limit.
+    pub fn new(min: usize, max: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(LimiterState {
+                concurrency: AdaptiveConcurrency::new(min, max),
+                in_flight: 0,
+            })),
+            released: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Wait until another upload may be in flight, and take a slot
+    /// for it.
+    pub async fn acquire(&self) -> UploadSlot {
+        loop {
+            // Start listening before checking, so that a slot freed
+            // in between isn't missed.
+            let released = self.released.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.concurrency.limit() {
+                    state.in_flight += 1;
+                    return UploadSlot {
+                        limiter: self.clone(),
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+
+    /// How the limit has adapted so far.
+    pub fn concurrency(&self) -> AdaptiveConcurrency {
+        self.state.lock().unwrap().concurrency.clone()
+    }
+}
+
+/// A slot for one upload in flight.
+///
+/// The slot is freed when dropped.
+#[derive(Debug)]
+pub struct UploadSlot {
+    limiter: UploadLimiter,
+}
+
+impl UploadSlot {
+    /// Record that the upload succeeded, and how long it took.
+    pub fn succeeded(self, latency: Duration) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.concurrency.succeeded(latency);
+    }
+
+    /// Record that the upload failed.
+    pub fn failed(self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.concurrency.failed();
+    }
+}
+
+impl Drop for UploadSlot {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().in_flight -= 1;
+        self.limiter.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AdaptiveConcurrency, UploadLimiter};
+    use std::time::Duration;
+
+    const STEADY: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn starts_at_minimum() {
+        let c = AdaptiveConcurrency::new(2, 8);
+        assert_eq!(c.limit(), 2);
+    }
+
+    #[test]
+    fn grows_until_maximum_when_network_is_fast() {
+        let mut c = AdaptiveConcurrency::new(1, 8);
+        for _ in 0..1000 {
+            c.succeeded(STEADY);
+        }
+        assert_eq!(c.limit(), 8);
+        assert_eq!(c.peak(), 8);
+    }
+
+    #[test]
+    fn halves_on_failure() {
+        let mut c = AdaptiveConcurrency::new(1, 8);
+        for _ in 0..1000 {
+            c.succeeded(STEADY);
+        }
+        c.failed();
+        assert_eq!(c.limit(), 4);
+        assert_eq!(c.decreases(), 1);
+    }
+
+    #[test]
+    fn decreases_once_per_window() {
+        let mut c = AdaptiveConcurrency::new(1, 8);
+        for _ in 0..1000 {
+            c.succeeded(STEADY);
+        }
+        c.failed();
+        c.failed();
+        c.failed();
+        assert_eq!(c.limit(), 4);
+    }
+
+    #[test]
+    fn decreases_on_slow_request() {
+        let mut c = AdaptiveConcurrency::new(1, 8);
+        for _ in 0..1000 {
+            c.succeeded(STEADY);
+        }
+        c.succeeded(STEADY * 10);
+        assert_eq!(c.limit(), 4);
+    }
+
+    #[test]
+    fn never_goes_below_minimum() {
+        let mut c = AdaptiveConcurrency::new(2, 8);
+        for _ in 0..100 {
+            c.failed();
+        }
+        assert_eq!(c.limit(), 2);
+        assert_eq!(c.failures(), 100);
+    }
+
+    #[tokio::test]
+    async fn limiter_waits_for_a_free_slot() {
+        let limiter = UploadLimiter::new(1, 8);
+        let slot = limiter.acquire().await;
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await.succeeded(STEADY) }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        slot.failed();
+        waiting.await.unwrap();
+        let c = limiter.concurrency();
+        assert_eq!(c.failures(), 1);
+        assert_eq!(c.successes(), 1);
+    }
+}
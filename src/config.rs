@@ -1,24 +1,95 @@
 //! Client configuration.
 
-use crate::passwords::{passwords_filename, PasswordError, Passwords};
+use crate::cipher::Padding;
+use crate::fsiter::{CachedirTags, FollowSymlinks};
+use crate::keyexport::Kdf;
+use crate::logging::{LogFormat, LogLevel, LogRotation, SystemLog};
+use crate::notify::Notifier;
+use crate::passphrase::PassphraseSource;
+use crate::passwords::{all_passwords_filenames, passwords_filename, PasswordError, Passwords};
+use crate::policy::PolicyConfig;
+use crate::proxy::{parse_proxy, redact_password, ProxyConfig, ProxyError};
+use crate::runlock::lock_filename;
+use crate::snapshot::SnapshotConfig;
 
 use bytesize::MIB;
+use directories_next::ProjectDirs;
 use log::{error, trace};
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 use std::path::{Path, PathBuf};
 
 const DEFAULT_CHUNK_SIZE: usize = MIB as usize;
 const DEVNULL: &str = "/dev/null";
+const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 8;
+const DEFAULT_CHUNK_CACHE_SIZE: u64 = 1024 * MIB;
+const PROFILES: &str = "profiles";
+
+/// Name of the client, if the configuration doesn't set one.
+pub const DEFAULT_CLIENT_NAME: &str = "default";
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 struct TentativeClientConfig {
     server_url: String,
+    client_name: Option<String>,
     verify_tls_cert: Option<bool>,
+    server_cert_fingerprint: Option<String>,
+    ca_cert_path: Option<PathBuf>,
+    https_proxy: Option<String>,
+    http_proxy: Option<String>,
+    no_proxy: Option<Vec<String>>,
     chunk_size: Option<usize>,
     roots: Vec<PathBuf>,
     log: Option<PathBuf>,
+    log_level: Option<LogLevel>,
+    log_format: Option<LogFormat>,
+    log_rotation: Option<LogRotation>,
+    system_log: Option<SystemLog>,
     exclude_cache_tag_directories: Option<bool>,
+    exclude_obnam_files: Option<bool>,
+    cachedir_tags: Option<CachedirTags>,
+    cachedir_tag_allowlist: Option<Vec<PathBuf>>,
+    fail_on_new_cachedir_tags: Option<bool>,
+    one_file_system: Option<bool>,
+    max_concurrent_uploads: Option<usize>,
+    jobs: Option<usize>,
+    follow_symlinks: Option<FollowSymlinks>,
+    snapshot: Option<SnapshotConfig>,
+    policy: Option<PolicyConfig>,
+    cache_generations: Option<bool>,
+    generation_cache_dir: Option<PathBuf>,
+    generation_upload: Option<GenerationUpload>,
+    padding: Option<Padding>,
+    verify_sample_percent: Option<f64>,
+    detect_renames: Option<bool>,
+    cache_chunks: Option<bool>,
+    chunk_cache_dir: Option<PathBuf>,
+    chunk_cache_size: Option<u64>,
+    password_command: Option<String>,
+    key_derivation: Option<Kdf>,
+    notify: Option<Vec<Notifier>>,
+}
+
+/// How the metadata of a new backup is uploaded.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GenerationUpload {
+    /// Upload the metadata in large chunks. Chunks that are identical
+    /// to ones already on the server aren't uploaded again, but with
+    /// large chunks that rarely happens.
+    Whole,
+
+    /// Upload the metadata in small segments, and only those that
+    /// differ from the previous backup's metadata. This saves time
+    /// and space when only a few files have changed.
+    Delta,
+}
+
+impl Default for GenerationUpload {
+    fn default() -> Self {
+        Self::Whole
+    }
 }
 
 /// Configuration for the Obnam client.
@@ -26,29 +97,121 @@ struct TentativeClientConfig {
 pub struct ClientConfig {
     /// Name of configuration file.
     pub filename: PathBuf,
+    /// Name of the profile in the configuration file whose settings
+    /// are used, if any.
+    pub profile: Option<String>,
     /// URL of Obnam server.
     pub server_url: String,
+    /// Name of the client. Several clients can share a server, and
+    /// each has its own backups.
+    pub client_name: String,
     /// Should server's TLS certificate be verified using CA
     /// signatures? Set to false, for self-signed certificates.
     pub verify_tls_cert: bool,
+    /// SHA-256 fingerprint of the server's TLS certificate, as
+    /// lower case hexadecimal. If set, only a server with exactly
+    /// that certificate is accepted. The certificate must be
+    /// self-signed.
+    pub server_cert_fingerprint: Option<String>,
+    /// Path to the certificate of the CA that signs the server's TLS
+    /// certificate. If set, only certificates it signs are accepted.
+    pub ca_cert_path: Option<PathBuf>,
+    /// Which HTTP proxy to use to reach the server, if any.
+    pub proxy: ProxyConfig,
     /// Size of chunks when splitting files for backup.
     pub chunk_size: usize,
     /// Backup root directories.
     pub roots: Vec<PathBuf>,
     /// File where logs should be written.
     pub log: PathBuf,
-    /// Should cache directories be excluded? Cache directories
+    /// How much to write to the log file.
+    pub log_level: LogLevel,
+    /// Format of the log file.
+    pub log_format: LogFormat,
+    /// When the log file is rotated, if at all.
+    pub log_rotation: Option<LogRotation>,
+    /// System log to also log to, if any.
+    pub system_log: Option<SystemLog>,
+    /// What to do with cache directories? Cache directories
     /// contain a specially formatted CACHEDIR.TAG file.
-    pub exclude_cache_tag_directories: bool,
+    pub cachedir_tags: CachedirTags,
+    /// Cache directories under these paths are backed up like any
+    /// other directory.
+    pub cachedir_tag_allowlist: Vec<PathBuf>,
+    /// Should an incremental backup fail if it finds CACHEDIR.TAG
+    /// files that aren't in the previous backup?
+    pub fail_on_new_cachedir_tags: bool,
+    /// Should Obnam's own files, such as its log file, be left out of
+    /// backups?
+    pub exclude_obnam_files: bool,
+    /// Should backups stay on the file system of each backup root?
+    /// The contents of mount points for other file systems are
+    /// skipped.
+    pub one_file_system: bool,
+    /// Maximum number of chunks to upload concurrently. The actual
+    /// number adapts to how well the network copes.
+    pub max_concurrent_uploads: usize,
+    /// How many pieces of CPU heavy work, such as encrypting or
+    /// checksumming chunks, are done at once. By default, this is the
+    /// number of CPU cores.
+    pub jobs: usize,
+    /// When should symbolic links be followed?
+    pub follow_symlinks: FollowSymlinks,
+    /// How to make snapshots of backup roots, if at all.
+    pub snapshot: Option<SnapshotConfig>,
+    /// Policy for what gets backed up.
+    pub policy: PolicyConfig,
+    /// Directory where downloaded backup metadata is cached, if at
+    /// all.
+    pub generation_cache: Option<PathBuf>,
+    /// How the metadata of a new backup is uploaded.
+    pub generation_upload: GenerationUpload,
+    /// How chunks are padded before encryption, to hide their exact
+    /// lengths from the server.
+    pub padding: Padding,
+    /// Percentage of the chunks uploaded by a backup that are fetched
+    /// again and verified after the backup. Zero means none.
+    pub verify_sample_percent: f64,
+    /// Should new files that have the same content as files deleted
+    /// since the previous backup be recorded as renamed?
+    pub detect_renames: bool,
+    /// Directory where chunks fetched when restoring are cached, if
+    /// at all.
+    pub chunk_cache: Option<PathBuf>,
+    /// Maximum size of the chunk cache, in bytes.
+    pub chunk_cache_size: u64,
+    /// Where to get the passphrase for encrypted keys.
+    pub passphrase: PassphraseSource,
+    /// How the key that encrypts saved or exported keys is derived
+    /// from the passphrase.
+    pub key_derivation: Kdf,
+    /// How to tell someone that a backup, restore, or restore test
+    /// has finished.
+    pub notify: Vec<Notifier>,
 }
 
 impl ClientConfig {
     /// Read a client configuration from a file.
     pub fn read(filename: &Path) -> Result<Self, ClientConfigError> {
-        trace!("read_config: filename={:?}", filename);
+        Self::read_profile(filename, None)
+    }
+
+    /// Read a client configuration from a file, using the settings of
+    /// a named profile in it.
+    ///
+    /// The file may have a `profiles` map, from profile names to
+    /// settings. A profile's settings replace the top level settings
+    /// with the same names; the other top level settings are used as
+    /// they are. Without a profile, only the top level settings are
+    /// used.
+    pub fn read_profile(filename: &Path, profile: Option<&str>) -> Result<Self, ClientConfigError> {
+        trace!("read_config: filename={:?} profile={:?}", filename, profile);
         let config = std::fs::read_to_string(filename)
             .map_err(|err| ClientConfigError::Read(filename.to_path_buf(), err))?;
-        let tentative: TentativeClientConfig = serde_yaml::from_str(&config)
+        let settings: Value = serde_yaml::from_str(&config)
+            .map_err(|err| ClientConfigError::YamlParse(filename.to_path_buf(), err))?;
+        let settings = select_profile(filename, settings, profile)?;
+        let tentative: TentativeClientConfig = serde_yaml::from_value(settings)
             .map_err(|err| ClientConfigError::YamlParse(filename.to_path_buf(), err))?;
         let roots = tentative
             .roots
@@ -59,16 +222,94 @@ impl ClientConfig {
             .log
             .map(|path| expand_tilde(&path))
             .unwrap_or_else(|| PathBuf::from(DEVNULL));
-        let exclude_cache_tag_directories = tentative.exclude_cache_tag_directories.unwrap_or(true);
+        let cachedir_tags = match (
+            tentative.exclude_cache_tag_directories,
+            tentative.cachedir_tags,
+        ) {
+            (Some(_), Some(_)) => return Err(ClientConfigError::CachedirTagsTwice),
+            (Some(true), None) => CachedirTags::ExcludeContents,
+            (Some(false), None) => CachedirTags::IncludeAnyway,
+            (None, mode) => mode.unwrap_or_default(),
+        };
+        let cachedir_tag_allowlist = tentative
+            .cachedir_tag_allowlist
+            .unwrap_or_default()
+            .iter()
+            .map(|path| expand_tilde(path))
+            .collect();
+        let mut policy = tentative.policy.unwrap_or_default();
+        for p in policy.paths.iter_mut() {
+            p.path = expand_tilde(&p.path);
+        }
+
+        let generation_cache = if tentative.cache_generations.unwrap_or(true) {
+            tentative
+                .generation_cache_dir
+                .map(|path| expand_tilde(&path))
+                .or_else(default_generation_cache)
+        } else {
+            None
+        };
+        let chunk_cache = if tentative.cache_chunks.unwrap_or(false) {
+            tentative
+                .chunk_cache_dir
+                .map(|path| expand_tilde(&path))
+                .or_else(default_chunk_cache)
+        } else {
+            None
+        };
 
         let config = Self {
             chunk_size: tentative.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
             filename: filename.to_path_buf(),
+            profile: profile.map(|name| name.to_string()),
             roots,
             server_url: tentative.server_url,
+            client_name: tentative
+                .client_name
+                .unwrap_or_else(|| DEFAULT_CLIENT_NAME.to_string()),
             verify_tls_cert: tentative.verify_tls_cert.unwrap_or(false),
+            server_cert_fingerprint: tentative
+                .server_cert_fingerprint
+                .map(|fp| fp.replace(':', "").to_lowercase()),
+            ca_cert_path: tentative.ca_cert_path.map(|path| expand_tilde(&path)),
+            proxy: ProxyConfig {
+                https_proxy: tentative.https_proxy,
+                http_proxy: tentative.http_proxy,
+                no_proxy: tentative.no_proxy,
+            },
             log,
-            exclude_cache_tag_directories,
+            log_level: tentative.log_level.unwrap_or_default(),
+            log_format: tentative.log_format.unwrap_or_default(),
+            log_rotation: tentative.log_rotation,
+            system_log: tentative.system_log,
+            cachedir_tags,
+            cachedir_tag_allowlist,
+            fail_on_new_cachedir_tags: tentative.fail_on_new_cachedir_tags.unwrap_or(true),
+            exclude_obnam_files: tentative.exclude_obnam_files.unwrap_or(true),
+            one_file_system: tentative.one_file_system.unwrap_or(false),
+            max_concurrent_uploads: tentative
+                .max_concurrent_uploads
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_UPLOADS),
+            jobs: tentative.jobs.unwrap_or_else(num_cpus::get_physical),
+            follow_symlinks: tentative.follow_symlinks.unwrap_or_default(),
+            snapshot: tentative.snapshot,
+            policy,
+            generation_cache,
+            generation_upload: tentative.generation_upload.unwrap_or_default(),
+            padding: tentative.padding.unwrap_or_default(),
+            verify_sample_percent: tentative.verify_sample_percent.unwrap_or(0.0),
+            detect_renames: tentative.detect_renames.unwrap_or(false),
+            chunk_cache,
+            chunk_cache_size: tentative
+                .chunk_cache_size
+                .unwrap_or(DEFAULT_CHUNK_CACHE_SIZE),
+            passphrase: PassphraseSource {
+                from_fd: None,
+                command: tentative.password_command,
+            },
+            key_derivation: tentative.key_derivation.unwrap_or_default(),
+            notify: tentative.notify.unwrap_or_default(),
         };
 
         config.check()?;
@@ -82,18 +323,110 @@ impl ClientConfig {
         if !self.server_url.starts_with("https://") {
             return Err(ClientConfigError::NotHttps(self.server_url.to_string()));
         }
+        if let Some(fp) = &self.server_cert_fingerprint {
+            if fp.len() != 64 || !fp.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(ClientConfigError::BadFingerprint(fp.to_string()));
+            }
+            if self.ca_cert_path.is_some() {
+                return Err(ClientConfigError::FingerprintAndCaCert);
+            }
+        }
+        for proxy in [&self.proxy.https_proxy, &self.proxy.http_proxy]
+            .iter()
+            .filter_map(|p| p.as_deref())
+            .filter(|p| !p.is_empty())
+        {
+            parse_proxy(proxy).map_err(ClientConfigError::BadProxy)?;
+        }
+        if self.client_name.is_empty() {
+            return Err(ClientConfigError::ClientNameIsEmpty);
+        }
         if self.roots.is_empty() {
             return Err(ClientConfigError::NoBackupRoot);
         }
+        if self.max_concurrent_uploads == 0 {
+            return Err(ClientConfigError::NoConcurrentUploads);
+        }
+        if self.jobs == 0 {
+            return Err(ClientConfigError::NoJobs);
+        }
+        if let Some(f) = self.policy.bad_content_sample() {
+            return Err(ClientConfigError::BadContentSample(f));
+        }
+        if !self.key_derivation.is_within_limits() {
+            return Err(ClientConfigError::KdfTooLarge);
+        }
+        if !(0.0..=100.0).contains(&self.verify_sample_percent) {
+            return Err(ClientConfigError::BadVerifySample(
+                self.verify_sample_percent,
+            ));
+        }
         Ok(())
     }
 
+    /// Return the configuration with any passwords in URLs hidden, so
+    /// that it can be shown.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        config.server_url = redact_password(&self.server_url);
+        config.proxy = self.proxy.redacted();
+        for notifier in config.notify.iter_mut() {
+            if let Notifier::Webhook(url) = notifier {
+                *url = redact_password(url);
+            }
+        }
+        config
+    }
+
+    /// Name of the file with the encryption passwords.
+    pub fn passwords_filename(&self) -> PathBuf {
+        passwords_filename(&self.filename, self.profile.as_deref())
+    }
+
+    /// Name of the lock file that prevents concurrent runs.
+    pub fn lock_filename(&self) -> PathBuf {
+        lock_filename(&self.filename, self.profile.as_deref())
+    }
+
+    /// Files and directories of Obnam's own that are left out of
+    /// backups: the log file, the passwords files of all profiles, the
+    /// lock file, and the caches. The paths are absolute. The list is
+    /// empty if `exclude_obnam_files` is false.
+    pub fn obnam_files(&self) -> Vec<PathBuf> {
+        if !self.exclude_obnam_files {
+            return vec![];
+        }
+        let mut files = vec![];
+        if self.log != Path::new(DEVNULL) {
+            files.push(self.log.clone());
+            if let Some(rotation) = &self.log_rotation {
+                for i in 1..=rotation.keep {
+                    let mut rotated = self.log.clone().into_os_string();
+                    rotated.push(format!(".{}", i));
+                    files.push(PathBuf::from(rotated));
+                }
+            }
+        }
+        files.extend(all_passwords_filenames(&self.filename));
+        files.push(self.lock_filename());
+        files.extend(self.generation_cache.iter().cloned());
+        files.extend(self.chunk_cache.iter().cloned());
+        files.iter().map(|path| absolute(path)).collect()
+    }
+
     /// Read encryption passwords from a file.
     ///
     /// The password file is expected to be next to the configuration file.
     pub fn passwords(&self) -> Result<Passwords, ClientConfigError> {
-        Passwords::load(&passwords_filename(&self.filename))
-            .map_err(ClientConfigError::PasswordsMissing)
+        match Passwords::load(
+            &self.passwords_filename(),
+            &self.passphrase,
+            &self.key_derivation,
+        ) {
+            Ok(passwords) => Ok(passwords),
+            Err(err @ PasswordError::Read(_, _)) => Err(ClientConfigError::PasswordsMissing(err)),
+            Err(err) => Err(ClientConfigError::Passwords(err)),
+        }
     }
 }
 
@@ -104,18 +437,66 @@ pub enum ClientConfigError {
     #[error("server_url is empty")]
     ServerUrlIsEmpty,
 
+    /// The configuration specifies the client name as an empty string.
+    #[error("client_name is empty")]
+    ClientNameIsEmpty,
+
     /// The configuration does not specify any backup root directories.
     #[error("No backup roots in config; at least one is needed")]
     NoBackupRoot,
 
+    /// The configuration doesn't allow any uploads at all.
+    #[error("max_concurrent_uploads must be at least 1")]
+    NoConcurrentUploads,
+
+    /// The configuration doesn't allow any work to be done at once.
+    #[error("jobs must be at least 1")]
+    NoJobs,
+
+    /// A policy content sample fraction is out of range.
+    #[error("policy content_sample must be between 0.0 and 1.0, not {0}")]
+    BadContentSample(f64),
+
+    /// The percentage of chunks to verify after a backup is out of
+    /// range.
+    #[error("verify_sample_percent must be between 0 and 100, not {0}")]
+    BadVerifySample(f64),
+
+    /// The key derivation parameters are larger than Obnam accepts.
+    #[error(
+        "key_derivation parameters are too large: keys encrypted with them couldn't be read back"
+    )]
+    KdfTooLarge,
+
+    /// Both the old and the new setting for cache directories are
+    /// used.
+    #[error("only one of exclude_cache_tag_directories and cachedir_tags can be set")]
+    CachedirTagsTwice,
+
     /// The server URL is not an https: one.
     #[error("server URL doesn't use https: {0}")]
     NotHttps(String),
 
+    /// The server certificate fingerprint isn't a SHA-256 digest.
+    #[error("server_cert_fingerprint must be 64 hexadecimal digits: {0}")]
+    BadFingerprint(String),
+
+    /// Both ways of pinning the server certificate are used.
+    #[error("only one of server_cert_fingerprint and ca_cert_path can be set")]
+    FingerprintAndCaCert,
+
+    /// A proxy setting is wrong.
+    #[error(transparent)]
+    BadProxy(ProxyError),
+
     /// There are no passwords stored.
     #[error("No passwords are set: you may need to run 'obnam init': {0}")]
     PasswordsMissing(PasswordError),
 
+    /// The stored passwords can't be used.
+    #[error(transparent)]
+    Passwords(PasswordError),
+
     /// Error reading a configuation file.
     #[error("failed to read configuration file {0}: {1}")]
     Read(PathBuf, std::io::Error),
@@ -123,6 +504,73 @@ pub enum ClientConfigError {
     /// Error parsing configuration file as YAML.
     #[error("failed to parse configuration file {0} as YAML: {1}")]
     YamlParse(PathBuf, serde_yaml::Error),
+
+    /// The chosen profile isn't in the configuration file.
+    #[error("configuration file {0} has no profile {1}")]
+    NoSuchProfile(PathBuf, String),
+
+    /// A profile in the configuration file isn't a map of settings.
+    #[error("profile {1} in configuration file {0} is not a map of settings")]
+    BadProfile(PathBuf, String),
+}
+
+// Remove the profiles from the settings in a configuration file, and
+// if a profile is chosen, replace top level settings with its
+// settings.
+fn select_profile(
+    filename: &Path,
+    settings: Value,
+    profile: Option<&str>,
+) -> Result<Value, ClientConfigError> {
+    let mut settings = match settings {
+        Value::Mapping(settings) => settings,
+        // Let parsing the settings report what's wrong.
+        _ => return Ok(settings),
+    };
+    let profiles = settings.remove(&Value::from(PROFILES));
+    if let Some(name) = profile {
+        match profiles.as_ref().and_then(|profiles| profiles.get(name)) {
+            Some(Value::Mapping(chosen)) => {
+                for (key, value) in chosen.iter() {
+                    settings.insert(key.clone(), value.clone());
+                }
+            }
+            Some(_) => {
+                return Err(ClientConfigError::BadProfile(
+                    filename.to_path_buf(),
+                    name.to_string(),
+                ))
+            }
+            None => {
+                return Err(ClientConfigError::NoSuchProfile(
+                    filename.to_path_buf(),
+                    name.to_string(),
+                ))
+            }
+        }
+    }
+    Ok(Value::Mapping(settings))
+}
+
+fn default_generation_cache() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "obnam").map(|dirs| dirs.cache_dir().join("generations"))
+}
+
+fn default_chunk_cache() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "obnam").map(|dirs| dirs.cache_dir().join("chunks"))
+}
+
+/// Make a relative path absolute, by joining it to the current
+/// directory. Unlike canonicalizing, this doesn't require the path to
+/// exist.
+pub fn absolute(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+    match std::env::current_dir() {
+        Ok(cwd) => cwd.join(path),
+        Err(_) => path.to_path_buf(),
+    }
 }
 
 fn expand_tilde(path: &Path) -> PathBuf {
@@ -16,32 +16,83 @@ use std::path::{Path, PathBuf};
 /// A database.
 pub struct Database {
     conn: Connection,
+    // Is there a transaction that needs to be committed?
+    transaction: bool,
 }
 
 impl Database {
     /// Create a new database file for an empty database.
     ///
-    /// The database can be written to.
+    /// The database can be written to. It's tuned for inserting many
+    /// rows at once, with [`Tuning::bulk_insert`].
     pub fn create<P: AsRef<Path>>(filename: P) -> Result<Self, DatabaseError> {
+        Self::create_with(filename, &Tuning::bulk_insert())
+    }
+
+    /// Create a new database file for an empty database, tuned in a
+    /// given way.
+    pub fn create_with<P: AsRef<Path>>(
+        filename: P,
+        tuning: &Tuning,
+    ) -> Result<Self, DatabaseError> {
         if filename.as_ref().exists() {
             return Err(DatabaseError::Exists(filename.as_ref().to_path_buf()));
         }
         let flags = OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_READ_WRITE;
         let conn = Connection::open_with_flags(filename, flags)?;
+        tuning.apply(&conn, true)?;
+        Self::begin(conn)
+    }
+
+    /// Create a new, empty database that only exists in memory.
+    ///
+    /// The database can be written to, like one made with
+    /// [`Database::create`], but nothing is ever written to disk, and
+    /// the database is gone when it's closed or dropped.
+    pub fn memory() -> Result<Self, DatabaseError> {
+        let conn = Connection::open_in_memory()?;
+        Self::begin(conn)
+    }
+
+    fn begin(conn: Connection) -> Result<Self, DatabaseError> {
         conn.execute("BEGIN", params![])?;
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            transaction: true,
+        })
     }
 
     /// Open an existing database file in read only mode.
+    ///
+    /// It's tuned for reading, with [`Tuning::read_only`].
     pub fn open<P: AsRef<Path>>(filename: P) -> Result<Self, DatabaseError> {
+        Self::open_with(filename, &Tuning::read_only())
+    }
+
+    /// Open an existing database file in read only mode, tuned in a
+    /// given way.
+    pub fn open_with<P: AsRef<Path>>(filename: P, tuning: &Tuning) -> Result<Self, DatabaseError> {
         let flags = OpenFlags::SQLITE_OPEN_READ_ONLY;
         let conn = Connection::open_with_flags(filename, flags)?;
-        Ok(Self { conn })
+        tuning.apply(&conn, false)?;
+        Ok(Self {
+            conn,
+            transaction: false,
+        })
+    }
+
+    /// Commit any changes, but keep the database open for reading.
+    pub fn commit(&mut self) -> Result<(), DatabaseError> {
+        if self.transaction {
+            self.conn.execute("COMMIT", params![])?;
+            self.transaction = false;
+        }
+        Ok(())
     }
 
     /// Close an open database, committing any changes to disk.
-    pub fn close(self) -> Result<(), DatabaseError> {
-        self.conn.execute("COMMIT", params![])?;
+    pub fn close(mut self) -> Result<(), DatabaseError> {
+        self.commit()?;
         self.conn
             .close()
             .map_err(|(_, err)| DatabaseError::Rusqlite(err))?;
@@ -81,6 +132,43 @@ impl Database {
         Ok(())
     }
 
+    /// Set columns to new values in rows that have a given value in a
+    /// given column.
+    ///
+    /// Return the number of rows changed.
+    pub fn update(
+        &mut self,
+        table: &Table,
+        values: &[Value],
+        predicate: &Value,
+    ) -> Result<usize, DatabaseError> {
+        assert!(!values.is_empty());
+        assert!(table.has_columns(values));
+        assert!(table.has_column(predicate));
+        let names: Vec<&str> = values.iter().map(|v| v.name()).collect();
+        let sql = sql_statement::update(table, &names, predicate.name());
+        let mut stmt = self.conn.prepare_cached(&sql)?;
+        let n = stmt.execute(rusqlite::params_from_iter(
+            values.iter().chain(std::iter::once(predicate)),
+        ))?;
+        Ok(n)
+    }
+
+    /// Remove rows that have a given value in a given column.
+    ///
+    /// Return the number of rows removed.
+    pub fn delete_rows(
+        &mut self,
+        table: &Table,
+        predicate: &Value,
+    ) -> Result<usize, DatabaseError> {
+        assert!(table.has_column(predicate));
+        let sql = sql_statement::delete_rows(table, predicate.name());
+        let mut stmt = self.conn.prepare_cached(&sql)?;
+        let n = stmt.execute(params![predicate])?;
+        Ok(n)
+    }
+
     /// Return an iterator for all rows in a table.
     pub fn all_rows<T>(
         &self,
@@ -91,7 +179,7 @@ impl Database {
         SqlResults::new(
             &self.conn,
             &sql,
-            None,
+            vec![],
             Box::new(|stmt, _| {
                 let iter = stmt.query_map(params![], |row| rowfunc(row))?;
                 let iter = iter.map(|x| match x {
@@ -104,24 +192,55 @@ impl Database {
     }
 
     /// Return rows that have a given value in a given column.
-    ///
-    /// This is simplistic, but for Obnam, it provides all the SQL
-    /// SELECT ... WHERE that's needed, and there's no point in making
-    /// this more generic than is needed.
     pub fn some_rows<T>(
         &self,
         table: &Table,
         value: &Value,
         rowfunc: &'static dyn Fn(&Row) -> Result<T, rusqlite::Error>,
     ) -> Result<SqlResults<T>, DatabaseError> {
-        assert!(table.has_column(value));
-        let sql = sql_statement::select_some_rows(table, value.name());
+        self.query_rows(table, &Query::new().with(*value), rowfunc)
+    }
+
+    /// Return the rows of an SQL query that aggregates rows, such as
+    /// with `GROUP BY`.
+    ///
+    /// The result is expected to be small, so it's returned all at
+    /// once.
+    pub fn aggregate_rows<T>(
+        &self,
+        sql: &str,
+        rowfunc: &dyn Fn(&Row) -> Result<T, rusqlite::Error>,
+    ) -> Result<Vec<T>, DatabaseError> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let iter = stmt.query_map(params![], |row| rowfunc(row))?;
+        let mut rows = vec![];
+        for row in iter {
+            rows.push(row?);
+        }
+        Ok(rows)
+    }
+
+    /// Return the rows a query matches, in the order it asks for.
+    pub fn query_rows<T>(
+        &self,
+        table: &Table,
+        query: &Query,
+        rowfunc: &'static dyn Fn(&Row) -> Result<T, rusqlite::Error>,
+    ) -> Result<SqlResults<T>, DatabaseError> {
+        assert!(table.has_columns(&query.predicates));
+        assert!(query
+            .order
+            .iter()
+            .all(|(column, _)| table.column_names.contains(column)));
+        let sql = sql_statement::select_query(table, query);
         SqlResults::new(
             &self.conn,
             &sql,
-            Some(OwnedValue::from(value)),
-            Box::new(|stmt, value| {
-                let iter = stmt.query_map(params![value], |row| rowfunc(row))?;
+            query.predicates.iter().map(OwnedValue::from).collect(),
+            Box::new(|stmt, values| {
+                let iter = stmt.query_map(rusqlite::params_from_iter(values.iter()), |row| {
+                    rowfunc(row)
+                })?;
                 let iter = iter.map(|x| match x {
                     Ok(t) => Ok(t),
                     Err(e) => Err(DatabaseError::Rusqlite(e)),
@@ -132,6 +251,111 @@ impl Database {
     }
 }
 
+/// How SQLite is tuned for a database.
+///
+/// Settings that are None are left at SQLite's defaults. The journal
+/// mode and page size only matter when a database is created, and
+/// are ignored when one is opened read only.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Tuning {
+    /// How SQLite keeps track of changes in a transaction.
+    pub journal_mode: Option<JournalMode>,
+    /// How hard SQLite tries to get changes onto disk.
+    pub synchronous: Option<Synchronous>,
+    /// Size of database pages, in bytes. This must be a power of two
+    /// from 512 to 65536.
+    pub page_size: Option<u32>,
+    /// Size of the cache of database pages, in KiB.
+    pub cache_size: Option<u32>,
+    /// How much of the database file to access via memory mapping,
+    /// in bytes.
+    pub mmap_size: Option<u64>,
+}
+
+/// The SQLite journal modes.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum JournalMode {
+    /// A rollback journal file, which is deleted at the end of each
+    /// transaction.
+    Delete,
+    /// A write-ahead log.
+    Wal,
+    /// A rollback journal kept in memory.
+    Memory,
+    /// No journal. A transaction can't be rolled back.
+    Off,
+}
+
+/// The SQLite levels of making sure data gets to disk.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Synchronous {
+    /// Leave it to the operating system.
+    Off,
+    /// Flush at critical moments.
+    Normal,
+    /// Flush at every transaction.
+    Full,
+}
+
+impl Tuning {
+    /// Tuning for creating a database and inserting many rows.
+    ///
+    /// The database is written in one transaction, and is of no use
+    /// if the program crashes before it's finished, so there is no
+    /// point in guarding it against crashes.
+    pub fn bulk_insert() -> Self {
+        Self {
+            journal_mode: Some(JournalMode::Memory),
+            synchronous: Some(Synchronous::Off),
+            page_size: Some(8192),
+            cache_size: Some(64 * 1024),
+            mmap_size: None,
+        }
+    }
+
+    /// Tuning for reading a database that doesn't change.
+    pub fn read_only() -> Self {
+        Self {
+            cache_size: Some(64 * 1024),
+            mmap_size: Some(256 * 1024 * 1024),
+            ..Self::default()
+        }
+    }
+
+    fn apply(&self, conn: &Connection, writable: bool) -> Result<(), rusqlite::Error> {
+        if writable {
+            if let Some(size) = self.page_size {
+                conn.pragma_update(None, "page_size", size)?;
+            }
+            if let Some(mode) = self.journal_mode {
+                let mode = match mode {
+                    JournalMode::Delete => "DELETE",
+                    JournalMode::Wal => "WAL",
+                    JournalMode::Memory => "MEMORY",
+                    JournalMode::Off => "OFF",
+                };
+                conn.pragma_update_and_check(None, "journal_mode", mode, |_| Ok(()))?;
+            }
+            if let Some(sync) = self.synchronous {
+                let sync = match sync {
+                    Synchronous::Off => "OFF",
+                    Synchronous::Normal => "NORMAL",
+                    Synchronous::Full => "FULL",
+                };
+                conn.pragma_update(None, "synchronous", sync)?;
+            }
+        }
+        if let Some(kib) = self.cache_size {
+            // A negative cache size is in KiB, rather than in pages.
+            conn.pragma_update(None, "cache_size", -i64::from(kib))?;
+        }
+        if let Some(size) = self.mmap_size {
+            conn.pragma_update_and_check(None, "mmap_size", size, |_| Ok(()))?;
+        }
+        Ok(())
+    }
+}
+
 /// Possible errors from a database.
 #[derive(Debug, thiserror::Error)]
 pub enum DatabaseError {
@@ -174,14 +398,14 @@ type SqlResultsIterator<'stmt, T> = Box<dyn Iterator<Item = Result<T, DatabaseEr
 type CreateIterFn<'conn, ItemT> = Box<
     dyn for<'stmt> Fn(
         &'stmt mut CachedStatement<'conn>,
-        &Option<OwnedValue>,
+        &[OwnedValue],
     ) -> Result<SqlResultsIterator<'stmt, ItemT>, DatabaseError>,
 >;
 
 /// An iterator over rows from a query.
 pub struct SqlResults<'conn, ItemT> {
     stmt: CachedStatement<'conn>,
-    value: Option<OwnedValue>,
+    values: Vec<OwnedValue>,
     create_iter: CreateIterFn<'conn, ItemT>,
 }
 
@@ -189,23 +413,62 @@ impl<'conn, ItemT> SqlResults<'conn, ItemT> {
     fn new(
         conn: &'conn Connection,
         statement: &str,
-        value: Option<OwnedValue>,
+        values: Vec<OwnedValue>,
         create_iter: CreateIterFn<'conn, ItemT>,
     ) -> Result<Self, DatabaseError> {
         let stmt = conn.prepare_cached(statement)?;
         Ok(Self {
             stmt,
-            value,
+            values,
             create_iter,
         })
     }
 
     /// Create an iterator over results.
     pub fn iter(&'_ mut self) -> Result<SqlResultsIterator<'_, ItemT>, DatabaseError> {
-        (self.create_iter)(&mut self.stmt, &self.value)
+        (self.create_iter)(&mut self.stmt, &self.values)
     }
 }
 
+/// Which rows to return from a table, and in what order.
+///
+/// A row matches if it has all the values given with
+/// [`Query::with`]. A query without values matches all rows.
+#[derive(Debug, Default)]
+pub struct Query<'a> {
+    predicates: Vec<Value<'a>>,
+    order: Vec<(String, Order)>,
+}
+
+impl<'a> Query<'a> {
+    /// Create a query that matches all rows, in any order.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match rows that have a value in its column.
+    pub fn with(mut self, value: Value<'a>) -> Self {
+        self.predicates.push(value);
+        self
+    }
+
+    /// Order rows by a column. Rows that are equal in the columns
+    /// given earlier are ordered by this one.
+    pub fn order_by(mut self, column: &str, order: Order) -> Self {
+        self.order.push((column.to_string(), order));
+        self
+    }
+}
+
+/// The direction to order rows by a column.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Order {
+    /// Smallest value first.
+    Ascending,
+    /// Largest value first.
+    Descending,
+}
+
 /// Describe a table in a row.
 pub struct Table {
     table: String,
@@ -357,7 +620,7 @@ impl Column {
 pub type DbInt = i64;
 
 /// A value in a named column.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Value<'a> {
     /// An integer primary key.
     PrimaryKey(&'a str, DbInt),
@@ -499,7 +762,7 @@ impl rusqlite::types::ToSql for FilesystemEntry {
 }
 
 mod sql_statement {
-    use super::Table;
+    use super::{Order, Query, Table};
 
     pub fn create_table(table: &Table) -> String {
         format!(
@@ -526,8 +789,44 @@ mod sql_statement {
         format!("SELECT * FROM {}", table.name())
     }
 
-    pub fn select_some_rows(table: &Table, column: &str) -> String {
-        format!("SELECT * FROM {} WHERE {} = ?", table.name(), column)
+    pub fn select_query(table: &Table, query: &Query) -> String {
+        let mut sql = format!("SELECT * FROM {}", table.name());
+        let predicates: Vec<String> = query
+            .predicates
+            .iter()
+            .map(|v| format!("{} = ?", v.name()))
+            .collect();
+        if !predicates.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&predicates.join(" AND "));
+        }
+        let order: Vec<String> = query
+            .order
+            .iter()
+            .map(|(column, order)| match order {
+                Order::Ascending => format!("{} ASC", column),
+                Order::Descending => format!("{} DESC", column),
+            })
+            .collect();
+        if !order.is_empty() {
+            sql.push_str(" ORDER BY ");
+            sql.push_str(&order.join(","));
+        }
+        sql
+    }
+
+    pub fn update(table: &Table, columns: &[&str], column: &str) -> String {
+        let assignments: Vec<String> = columns.iter().map(|c| format!("{} = ?", c)).collect();
+        format!(
+            "UPDATE {} SET {} WHERE {} = ?",
+            table.name(),
+            assignments.join(","),
+            column
+        )
+    }
+
+    pub fn delete_rows(table: &Table, column: &str) -> String {
+        format!("DELETE FROM {} WHERE {} = ?", table.name(), column)
     }
 
     fn column_names(table: &Table) -> String {
@@ -609,6 +908,16 @@ mod test {
         assert_eq!(values, vec![42]);
     }
 
+    #[test]
+    fn inserts_row_in_memory() {
+        let mut db = Database::memory().unwrap();
+        db.create_table(&table()).unwrap();
+        insert(&mut db, 42);
+        db.commit().unwrap();
+        db.commit().unwrap();
+        assert_eq!(values(db), vec![42]);
+    }
+
     #[test]
     fn inserts_many_rows() {
         const N: DbInt = 1000;
@@ -631,6 +940,114 @@ mod test {
         }
         assert_eq!(values, expected);
     }
+
+    #[test]
+    fn updates_matching_rows() {
+        let tmp = tempdir().unwrap();
+        let filename = tmp.path().join("test.db");
+        let mut db = create_db(&filename);
+        for i in [1, 2, 1] {
+            insert(&mut db, i);
+        }
+        let n = db
+            .update(&table(), &[Value::int("bar", 3)], &Value::int("bar", 1))
+            .unwrap();
+        assert_eq!(n, 2);
+        db.close().unwrap();
+
+        let db = open_db(&filename);
+        assert_eq!(values(db), vec![3, 2, 3]);
+    }
+
+    #[test]
+    fn deletes_matching_rows() {
+        let tmp = tempdir().unwrap();
+        let filename = tmp.path().join("test.db");
+        let mut db = create_db(&filename);
+        for i in [1, 2, 1] {
+            insert(&mut db, i);
+        }
+        assert_eq!(db.delete_rows(&table(), &Value::int("bar", 1)).unwrap(), 2);
+        assert_eq!(db.delete_rows(&table(), &Value::int("bar", 7)).unwrap(), 0);
+        db.close().unwrap();
+
+        let db = open_db(&filename);
+        assert_eq!(values(db), vec![2]);
+    }
+
+    #[test]
+    fn queries_with_several_values_and_order() {
+        fn get_pair(row: &rusqlite::Row) -> Result<(DbInt, DbInt), rusqlite::Error> {
+            Ok((row.get("a")?, row.get("b")?))
+        }
+
+        let tmp = tempdir().unwrap();
+        let table = Table::new("pairs")
+            .column(Column::int("a"))
+            .column(Column::int("b"))
+            .column(Column::bool("c"))
+            .build();
+        let mut db = Database::create(tmp.path().join("test.db")).unwrap();
+        db.create_table(&table).unwrap();
+        for (a, b, c) in [
+            (1, 1, true),
+            (1, 3, true),
+            (1, 2, true),
+            (1, 4, false),
+            (2, 5, true),
+        ] {
+            db.insert(
+                &table,
+                &[Value::int("a", a), Value::int("b", b), Value::bool("c", c)],
+            )
+            .unwrap();
+        }
+
+        let query = Query::new()
+            .with(Value::int("a", 1))
+            .with(Value::bool("c", true))
+            .order_by("b", Order::Descending);
+        let mut rows = db.query_rows(&table, &query, &get_pair).unwrap();
+        let pairs: Vec<(DbInt, DbInt)> = rows.iter().unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(pairs, vec![(1, 3), (1, 2), (1, 1)]);
+
+        let query = Query::new()
+            .order_by("a", Order::Descending)
+            .order_by("b", Order::Ascending);
+        let mut rows = db.query_rows(&table, &query, &get_pair).unwrap();
+        let pairs: Vec<(DbInt, DbInt)> = rows.iter().unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(pairs, vec![(2, 5), (1, 1), (1, 2), (1, 3), (1, 4)]);
+    }
+
+    #[test]
+    fn applies_tuning() {
+        let tmp = tempdir().unwrap();
+        let filename = tmp.path().join("test.db");
+        let tuning = Tuning {
+            journal_mode: Some(JournalMode::Wal),
+            synchronous: Some(Synchronous::Normal),
+            page_size: Some(16384),
+            ..Tuning::default()
+        };
+        let mut db = Database::create_with(&filename, &tuning).unwrap();
+        db.create_table(&table()).unwrap();
+        insert(&mut db, 42);
+        let page_size: i64 = db
+            .conn
+            .pragma_query_value(None, "page_size", |row| row.get(0))
+            .unwrap();
+        let mode: String = db
+            .conn
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))
+            .unwrap();
+        assert_eq!(page_size, 16384);
+        assert_eq!(mode, "wal");
+        db.close().unwrap();
+
+        let db = open_db(&filename);
+        assert_eq!(values(db), vec![42]);
+    }
+
     #[test]
     fn round_trips_int_max() {
         let tmp = tempdir().unwrap();
@@ -3,21 +3,24 @@
 use crate::backup_reason::Reason;
 use crate::chunkid::ChunkId;
 use crate::db::{Column, Database, DatabaseError, DbInt, SqlResults, Table, Value};
-use crate::fsentry::FilesystemEntry;
+use crate::fsentry::{FilesystemEntry, FilesystemKind};
 use crate::genmeta::{GenerationMeta, GenerationMetaError};
 use crate::label::LabelChecksumKind;
 use crate::schema::{SchemaVersion, VersionComponent};
 use log::error;
-use std::collections::HashMap;
-use std::os::unix::ffi::OsStrExt;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
 
 /// Return latest supported schema version for a supported major
 /// version.
 pub fn schema_version(major: VersionComponent) -> Result<SchemaVersion, GenerationDbError> {
     match major {
-        0 => Ok(SchemaVersion::new(0, 0)),
-        1 => Ok(SchemaVersion::new(1, 0)),
+        0 => Ok(SchemaVersion::new(V0_0::MAJOR, V0_0::MINOR)),
+        1 => Ok(SchemaVersion::new(V1_0::MAJOR, V1_0::MINOR)),
+        2 => Ok(SchemaVersion::new(V2_0::MAJOR, V2_0::MINOR)),
         _ => Err(GenerationDbError::Unsupported(major)),
     }
 }
@@ -26,11 +29,49 @@ pub fn schema_version(major: VersionComponent) -> Result<SchemaVersion, Generati
 pub const DEFAULT_SCHEMA_MAJOR: VersionComponent = V0_0::MAJOR;
 
 /// Major schema versions supported by this version of Obnam.
-pub const SCHEMA_MAJORS: &[VersionComponent] = &[0, 1];
+pub const SCHEMA_MAJORS: &[VersionComponent] = &[0, 1, 2];
+
+/// Major schema version in which a generation may store only the
+/// files that changed since its parent generation.
+pub const INCREMENTAL_SCHEMA_MAJOR: VersionComponent = V2_0::MAJOR;
 
 /// An integer identifier for a file in a generation.
 pub type FileId = DbInt;
 
+/// A chunk of a file's content, and where it is in the file.
+///
+/// The offset, length, and label are of the chunk's plaintext, and are
+/// only known for chunks recorded by a version of Obnam that stores
+/// them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChunk {
+    id: ChunkId,
+    span: Option<(u64, u64)>,
+    label: Option<String>,
+}
+
+impl FileChunk {
+    /// The chunk's identifier.
+    pub fn id(&self) -> &ChunkId {
+        &self.id
+    }
+
+    /// Where in the file the chunk starts, if known.
+    pub fn offset(&self) -> Option<u64> {
+        self.span.map(|(offset, _)| offset)
+    }
+
+    /// The length of the chunk, if known.
+    pub fn length(&self) -> Option<u64> {
+        self.span.map(|(_, len)| len)
+    }
+
+    /// The chunk's label, if known.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+}
+
 /// Possible errors from using generation databases.
 #[derive(Debug, thiserror::Error)]
 pub enum GenerationDbError {
@@ -59,6 +100,10 @@ pub enum GenerationDbError {
     #[error("Backup is not compatible with this version of Obnam: {0}.{1}")]
     Incompatible(VersionComponent, VersionComponent),
 
+    /// Deleted files can only be recorded in an incremental schema.
+    #[error("Backup schema {0} can't record deleted files")]
+    NotIncremental(VersionComponent),
+
     /// Error from a database
     #[error(transparent)]
     Database(#[from] DatabaseError),
@@ -84,6 +129,7 @@ pub struct GenerationDb {
 enum GenerationDbVariant {
     V0_0(V0_0),
     V1_0(V1_0),
+    V2_0(V2_0),
 }
 
 impl GenerationDb {
@@ -92,14 +138,38 @@ impl GenerationDb {
         filename: P,
         schema: SchemaVersion,
         checksum_kind: LabelChecksumKind,
+    ) -> Result<Self, GenerationDbError> {
+        let db = Database::create(filename.as_ref())?;
+        Self::create_in(db, schema, checksum_kind)
+    }
+
+    /// Create a new generation database in memory, in read/write
+    /// mode.
+    ///
+    /// Nothing is written to disk. The database is gone once it's
+    /// closed or dropped.
+    pub fn memory(
+        schema: SchemaVersion,
+        checksum_kind: LabelChecksumKind,
+    ) -> Result<Self, GenerationDbError> {
+        Self::create_in(Database::memory()?, schema, checksum_kind)
+    }
+
+    fn create_in(
+        db: Database,
+        schema: SchemaVersion,
+        checksum_kind: LabelChecksumKind,
     ) -> Result<Self, GenerationDbError> {
         let meta_table = Self::meta_table();
         let variant = match schema.version() {
             (V0_0::MAJOR, V0_0::MINOR) => {
-                GenerationDbVariant::V0_0(V0_0::create(filename, meta_table, checksum_kind)?)
+                GenerationDbVariant::V0_0(V0_0::create(db, meta_table, checksum_kind)?)
             }
             (V1_0::MAJOR, V1_0::MINOR) => {
-                GenerationDbVariant::V1_0(V1_0::create(filename, meta_table, checksum_kind)?)
+                GenerationDbVariant::V1_0(V1_0::create(db, meta_table, checksum_kind)?)
+            }
+            (V2_0::MAJOR, V2_0::MINOR) => {
+                GenerationDbVariant::V2_0(V2_0::create(db, meta_table, checksum_kind)?)
             }
             (major, minor) => return Err(GenerationDbError::Incompatible(major, minor)),
         };
@@ -115,13 +185,18 @@ impl GenerationDb {
             let rows = Self::meta_rows(&plain_db, &meta_table)?;
             GenerationMeta::from(rows)?.schema_version()
         };
+        // A database with an older minor version of a schema can be
+        // read as if it had the latest one.
         let variant = match schema.version() {
-            (V0_0::MAJOR, V0_0::MINOR) => {
+            (V0_0::MAJOR, minor) if minor <= V0_0::MINOR => {
                 GenerationDbVariant::V0_0(V0_0::open(filename, meta_table)?)
             }
-            (V1_0::MAJOR, V1_0::MINOR) => {
+            (V1_0::MAJOR, minor) if minor <= V1_0::MINOR => {
                 GenerationDbVariant::V1_0(V1_0::open(filename, meta_table)?)
             }
+            (V2_0::MAJOR, minor) if minor <= V2_0::MINOR => {
+                GenerationDbVariant::V2_0(V2_0::open(filename, meta_table)?)
+            }
             (major, minor) => return Err(GenerationDbError::Incompatible(major, minor)),
         };
         Ok(Self { variant })
@@ -147,11 +222,24 @@ impl GenerationDb {
         Ok(map)
     }
 
+    /// Commit any changes, but keep the database open for reading.
+    ///
+    /// This finishes a new database without closing it, which is the
+    /// only way to read one that's in memory.
+    pub fn commit(&mut self) -> Result<(), GenerationDbError> {
+        match &mut self.variant {
+            GenerationDbVariant::V0_0(v) => v.commit(),
+            GenerationDbVariant::V1_0(v) => v.commit(),
+            GenerationDbVariant::V2_0(v) => v.commit(),
+        }
+    }
+
     /// Close a database, commit any changes.
     pub fn close(self) -> Result<(), GenerationDbError> {
         match self.variant {
             GenerationDbVariant::V0_0(v) => v.close(),
             GenerationDbVariant::V1_0(v) => v.close(),
+            GenerationDbVariant::V2_0(v) => v.close(),
         }
     }
 
@@ -160,21 +248,68 @@ impl GenerationDb {
         match &self.variant {
             GenerationDbVariant::V0_0(v) => v.meta(),
             GenerationDbVariant::V1_0(v) => v.meta(),
+            GenerationDbVariant::V2_0(v) => v.v1.meta(),
+        }
+    }
+
+    /// Add a key/value pair to the "meta" table.
+    pub fn set_meta(&mut self, key: &str, value: &str) -> Result<(), GenerationDbError> {
+        match &mut self.variant {
+            GenerationDbVariant::V0_0(v) => v.set_meta(key, value),
+            GenerationDbVariant::V1_0(v) => v.set_meta(key, value),
+            GenerationDbVariant::V2_0(v) => v.v1.set_meta(key, value),
         }
     }
 
     /// Insert a file system entry into the database.
+    ///
+    /// The lengths and labels are those of the chunks, in the same
+    /// order as their ids. If they aren't known, they can be left
+    /// empty. The error is why the entry's content couldn't be backed
+    /// up, if it couldn't.
+    #[allow(clippy::too_many_arguments)]
     pub fn insert(
         &mut self,
         e: FilesystemEntry,
         fileid: FileId,
         ids: &[ChunkId],
+        lengths: &[u64],
+        labels: &[String],
         reason: Reason,
         is_cachedir_tag: bool,
+        error: Option<&str>,
     ) -> Result<(), GenerationDbError> {
         match &mut self.variant {
-            GenerationDbVariant::V0_0(v) => v.insert(e, fileid, ids, reason, is_cachedir_tag),
-            GenerationDbVariant::V1_0(v) => v.insert(e, fileid, ids, reason, is_cachedir_tag),
+            GenerationDbVariant::V0_0(v) => v.insert(
+                e,
+                fileid,
+                ids,
+                lengths,
+                labels,
+                reason,
+                is_cachedir_tag,
+                error,
+            ),
+            GenerationDbVariant::V1_0(v) => v.insert(
+                e,
+                fileid,
+                ids,
+                lengths,
+                labels,
+                reason,
+                is_cachedir_tag,
+                error,
+            ),
+            GenerationDbVariant::V2_0(v) => v.v1.insert(
+                e,
+                fileid,
+                ids,
+                lengths,
+                labels,
+                reason,
+                is_cachedir_tag,
+                error,
+            ),
         }
     }
 
@@ -183,6 +318,7 @@ impl GenerationDb {
         match &self.variant {
             GenerationDbVariant::V0_0(v) => v.file_count(),
             GenerationDbVariant::V1_0(v) => v.file_count(),
+            GenerationDbVariant::V2_0(v) => v.v1.file_count(),
         }
     }
 
@@ -191,6 +327,27 @@ impl GenerationDb {
         match &self.variant {
             GenerationDbVariant::V0_0(v) => v.is_cachedir_tag(filename),
             GenerationDbVariant::V1_0(v) => v.is_cachedir_tag(filename),
+            GenerationDbVariant::V2_0(v) => v.v1.is_cachedir_tag(filename),
+        }
+    }
+
+    /// Why couldn't a file's content be backed up? This is None for
+    /// files that were backed up, and for files in generations made
+    /// before the reason was recorded.
+    pub fn file_error(&self, filename: &Path) -> Result<Option<String>, GenerationDbError> {
+        match &self.variant {
+            GenerationDbVariant::V0_0(v) => v.file_error(filename),
+            GenerationDbVariant::V1_0(v) => v.file_error(filename),
+            GenerationDbVariant::V2_0(v) => v.v1.file_error(filename),
+        }
+    }
+
+    /// Change why a file is in the generation.
+    pub fn set_reason(&mut self, fileid: FileId, reason: &Reason) -> Result<(), GenerationDbError> {
+        match &mut self.variant {
+            GenerationDbVariant::V0_0(v) => v.set_reason(fileid, reason),
+            GenerationDbVariant::V1_0(v) => v.set_reason(fileid, reason),
+            GenerationDbVariant::V2_0(v) => v.v1.set_reason(fileid, reason),
         }
     }
 
@@ -199,6 +356,19 @@ impl GenerationDb {
         match &self.variant {
             GenerationDbVariant::V0_0(v) => v.chunkids(fileid),
             GenerationDbVariant::V1_0(v) => v.chunkids(fileid),
+            GenerationDbVariant::V2_0(v) => v.v1.chunkids(fileid),
+        }
+    }
+
+    /// Return a file's chunks, with their offsets and lengths.
+    pub fn file_chunks(
+        &self,
+        fileid: FileId,
+    ) -> Result<SqlResults<'_, FileChunk>, GenerationDbError> {
+        match &self.variant {
+            GenerationDbVariant::V0_0(v) => v.file_chunks(fileid),
+            GenerationDbVariant::V1_0(v) => v.file_chunks(fileid),
+            GenerationDbVariant::V2_0(v) => v.v1.file_chunks(fileid),
         }
     }
 
@@ -209,6 +379,7 @@ impl GenerationDb {
         match &self.variant {
             GenerationDbVariant::V0_0(v) => v.files(),
             GenerationDbVariant::V1_0(v) => v.files(),
+            GenerationDbVariant::V2_0(v) => v.v1.files(),
         }
     }
 
@@ -217,6 +388,20 @@ impl GenerationDb {
         match &self.variant {
             GenerationDbVariant::V0_0(v) => v.get_file(filename),
             GenerationDbVariant::V1_0(v) => v.get_file(filename),
+            GenerationDbVariant::V2_0(v) => v.v1.get_file(filename),
+        }
+    }
+
+    /// Get a file's id, information, and the reason it was backed
+    /// up, given its path.
+    pub fn get_file_row(
+        &self,
+        filename: &Path,
+    ) -> Result<Option<(FileId, FilesystemEntry, Reason)>, GenerationDbError> {
+        match &self.variant {
+            GenerationDbVariant::V0_0(v) => v.get_file_row(filename),
+            GenerationDbVariant::V1_0(v) => v.get_file_row(filename),
+            GenerationDbVariant::V2_0(v) => v.v1.get_file_row(filename),
         }
     }
 
@@ -225,10 +410,131 @@ impl GenerationDb {
         match &self.variant {
             GenerationDbVariant::V0_0(v) => v.get_fileno(filename),
             GenerationDbVariant::V1_0(v) => v.get_fileno(filename),
+            GenerationDbVariant::V2_0(v) => v.v1.get_fileno(filename),
+        }
+    }
+
+    /// Record that a file has been deleted since the parent
+    /// generation.
+    pub fn delete(&mut self, filename: &Path) -> Result<(), GenerationDbError> {
+        match &mut self.variant {
+            GenerationDbVariant::V0_0(_) => Err(GenerationDbError::NotIncremental(V0_0::MAJOR)),
+            GenerationDbVariant::V1_0(_) => Err(GenerationDbError::NotIncremental(V1_0::MAJOR)),
+            GenerationDbVariant::V2_0(v) => v.delete(filename),
+        }
+    }
+
+    /// Has a file been deleted since the parent generation?
+    pub fn is_deleted(&self, filename: &Path) -> Result<bool, GenerationDbError> {
+        match &self.variant {
+            GenerationDbVariant::V0_0(_) | GenerationDbVariant::V1_0(_) => Ok(false),
+            GenerationDbVariant::V2_0(v) => v.is_deleted(filename),
+        }
+    }
+
+    /// Aggregate file system entries by reason and by kind.
+    pub fn stats(&self) -> Result<GenerationStats, GenerationDbError> {
+        match &self.variant {
+            GenerationDbVariant::V0_0(v) => files_stats(&v.db, &v.files),
+            GenerationDbVariant::V1_0(v) => files_stats(&v.db, &v.files),
+            GenerationDbVariant::V2_0(v) => files_stats(&v.v1.db, &v.v1.files),
+        }
+    }
+}
+
+/// Number of file system entries, and bytes of regular file content.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct Tally {
+    count: u64,
+    bytes: u64,
+}
+
+impl Tally {
+    /// Number of entries.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Bytes of content in regular files.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    fn add(&mut self, kind: FilesystemKind, count: u64, bytes: u64) {
+        self.count += count;
+        if kind.has_content() {
+            self.bytes += bytes;
         }
     }
 }
 
+/// Statistics about the file system entries in a generation.
+#[derive(Debug, Default)]
+pub struct GenerationStats {
+    total: Tally,
+    by_reason: BTreeMap<String, Tally>,
+    by_kind: BTreeMap<String, Tally>,
+}
+
+impl GenerationStats {
+    /// Totals over all entries.
+    pub fn total(&self) -> Tally {
+        self.total
+    }
+
+    /// Totals for each reason an entry is in the generation.
+    pub fn by_reason(&self) -> impl Iterator<Item = (&str, Tally)> {
+        self.by_reason.iter().map(|(k, v)| (k.as_str(), *v))
+    }
+
+    /// Totals for each kind of file system entry.
+    pub fn by_kind(&self) -> impl Iterator<Item = (&str, Tally)> {
+        self.by_kind.iter().map(|(k, v)| (k.as_str(), *v))
+    }
+
+    pub(crate) fn add(&mut self, entry: &FilesystemEntry, reason: Reason) {
+        self.add_group(&reason.to_string(), entry.kind(), 1, entry.len());
+    }
+
+    // Add a group of entries of the same kind, with the same reason,
+    // and `bytes` in total.
+    fn add_group(&mut self, reason: &str, kind: FilesystemKind, count: u64, bytes: u64) {
+        self.total.add(kind, count, bytes);
+        self.by_reason
+            .entry(reason.to_string())
+            .or_default()
+            .add(kind, count, bytes);
+        self.by_kind
+            .entry(kind.name().to_string())
+            .or_default()
+            .add(kind, count, bytes);
+    }
+}
+
+// Aggregate the entries in a files table with SQL, rather than by
+// decoding every row. The kind and length of an entry are in its JSON.
+fn files_stats(db: &Database, files: &Table) -> Result<GenerationStats, GenerationDbError> {
+    let sql = format!(
+        "SELECT reason, json_extract(json, '$.kind') AS kind, COUNT(*), \
+         SUM(json_extract(json, '$.len')) FROM {} GROUP BY reason, kind",
+        files.name()
+    );
+    let mut stats = GenerationStats::default();
+    for (reason, kind, count, bytes) in db.aggregate_rows(&sql, &row_to_group)? {
+        let kind: FilesystemKind = serde_json::from_value(serde_json::Value::String(kind))?;
+        stats.add_group(&reason, kind, count, bytes);
+    }
+    Ok(stats)
+}
+
+fn row_to_group(row: &rusqlite::Row) -> rusqlite::Result<(String, String, u64, u64)> {
+    let reason = row.get(0)?;
+    let kind = row.get(1)?;
+    let count: i64 = row.get(2)?;
+    let bytes: Option<i64> = row.get(3)?;
+    Ok((reason, kind, count as u64, bytes.unwrap_or(0) as u64))
+}
+
 struct V0_0 {
     created: bool,
     db: Database,
@@ -239,15 +545,17 @@ struct V0_0 {
 
 impl V0_0 {
     const MAJOR: VersionComponent = 0;
-    const MINOR: VersionComponent = 0;
+    // Minor version 1 adds the inode change time and birth time to
+    // the JSON of each file.
+    const MINOR: VersionComponent = 1;
+    const FILEID: &'static str = "fileno";
 
     /// Create a new generation database in read/write mode.
-    pub fn create<P: AsRef<Path>>(
-        filename: P,
+    pub fn create(
+        db: Database,
         meta: Table,
         checksum_kind: LabelChecksumKind,
     ) -> Result<Self, GenerationDbError> {
-        let db = Database::create(filename.as_ref())?;
         let mut moi = Self::new(db, meta);
         moi.created = true;
         moi.create_tables(checksum_kind)?;
@@ -267,10 +575,15 @@ impl V0_0 {
             .column(Column::text("json"))
             .column(Column::text("reason"))
             .column(Column::bool("is_cachedir_tag"))
+            .column(Column::text("error"))
+            .column(Column::blob("renamed_from"))
             .build();
         let chunks = Table::new("chunks")
             .column(Column::int("fileno"))
             .column(Column::text("chunkid"))
+            .column(Column::int("offset"))
+            .column(Column::int("length"))
+            .column(Column::text("label"))
             .build();
 
         Self {
@@ -312,13 +625,20 @@ impl V0_0 {
         Ok(())
     }
 
-    /// Close a database, commit any changes.
-    pub fn close(self) -> Result<(), GenerationDbError> {
+    /// Commit any changes, but keep the database open for reading.
+    pub fn commit(&mut self) -> Result<(), GenerationDbError> {
         if self.created {
             self.db
                 .create_index("filenames_idx", &self.files, "filename")?;
             self.db.create_index("fileid_idx", &self.chunks, "fileno")?;
+            self.created = false;
         }
+        self.db.commit().map_err(GenerationDbError::Database)
+    }
+
+    /// Close a database, commit any changes.
+    pub fn close(mut self) -> Result<(), GenerationDbError> {
+        self.commit()?;
         self.db.close().map_err(GenerationDbError::Database)
     }
 
@@ -333,14 +653,27 @@ impl V0_0 {
         Ok(map)
     }
 
+    /// Add a key/value pair to the "meta" table.
+    pub fn set_meta(&mut self, key: &str, value: &str) -> Result<(), GenerationDbError> {
+        self.db.insert(
+            &self.meta,
+            &[Value::text("key", key), Value::text("value", value)],
+        )?;
+        Ok(())
+    }
+
     /// Insert a file system entry into the database.
+    #[allow(clippy::too_many_arguments)]
     pub fn insert(
         &mut self,
         e: FilesystemEntry,
         fileid: FileId,
         ids: &[ChunkId],
+        lengths: &[u64],
+        labels: &[String],
         reason: Reason,
         is_cachedir_tag: bool,
+        error: Option<&str>,
     ) -> Result<(), GenerationDbError> {
         let json = serde_json::to_string(&e)?;
         self.db.insert(
@@ -351,14 +684,21 @@ impl V0_0 {
                 Value::text("json", &json),
                 Value::text("reason", &format!("{}", reason)),
                 Value::bool("is_cachedir_tag", is_cachedir_tag),
+                Value::text("error", error.unwrap_or("")),
+                Value::blob("renamed_from", &renamed_from_blob(&reason)),
             ],
         )?;
-        for id in ids {
+        let spans = chunk_spans(ids, lengths);
+        for ((id, (offset, length)), label) in ids.iter().zip(spans).zip(chunk_labels(ids, labels))
+        {
             self.db.insert(
                 &self.chunks,
                 &[
                     Value::int("fileno", fileid),
                     Value::text("chunkid", &format!("{}", id)),
+                    Value::int("offset", offset),
+                    Value::int("length", length),
+                    Value::text("label", label),
                 ],
             )?;
         }
@@ -403,12 +743,48 @@ impl V0_0 {
         }
     }
 
+    /// Why couldn't a file's content be backed up?
+    pub fn file_error(&self, filename: &Path) -> Result<Option<String>, GenerationDbError> {
+        let filename_vec = path_into_blob(filename);
+        let value = Value::blob("filename", &filename_vec);
+        let mut rows = self.db.some_rows(&self.files, &value, &row_to_error)?;
+        let mut iter = rows.iter()?;
+        match iter.next() {
+            Some(row) => Ok(row?),
+            None => Ok(None),
+        }
+    }
+
+    /// Change why a file is in the generation.
+    pub fn set_reason(&mut self, fileid: FileId, reason: &Reason) -> Result<(), GenerationDbError> {
+        self.db.update(
+            &self.files,
+            &[
+                Value::text("reason", &format!("{}", reason)),
+                Value::blob("renamed_from", &renamed_from_blob(reason)),
+            ],
+            &Value::primary_key(Self::FILEID, fileid),
+        )?;
+        Ok(())
+    }
+
     /// Return all chunk ids in database.
     pub fn chunkids(&self, fileid: FileId) -> Result<SqlResults<ChunkId>, GenerationDbError> {
         let fileid = Value::int("fileno", fileid);
         Ok(self.db.some_rows(&self.chunks, &fileid, &row_to_chunkid)?)
     }
 
+    /// Return a file's chunks, with their offsets and lengths.
+    pub fn file_chunks(
+        &self,
+        fileid: FileId,
+    ) -> Result<SqlResults<'_, FileChunk>, GenerationDbError> {
+        let fileid = Value::int("fileno", fileid);
+        Ok(self
+            .db
+            .some_rows(&self.chunks, &fileid, &row_to_file_chunk)?)
+    }
+
     /// Return all file descriptions in database.
     pub fn files(
         &self,
@@ -418,7 +794,7 @@ impl V0_0 {
 
     /// Get a file's information given its path.
     pub fn get_file(&self, filename: &Path) -> Result<Option<FilesystemEntry>, GenerationDbError> {
-        match self.get_file_and_fileno(filename)? {
+        match self.get_file_row(filename)? {
             None => Ok(None),
             Some((_, e, _)) => Ok(Some(e)),
         }
@@ -426,21 +802,23 @@ impl V0_0 {
 
     /// Get a file's information given its id in the database.
     pub fn get_fileno(&self, filename: &Path) -> Result<Option<FileId>, GenerationDbError> {
-        match self.get_file_and_fileno(filename)? {
+        match self.get_file_row(filename)? {
             None => Ok(None),
             Some((id, _, _)) => Ok(Some(id)),
         }
     }
 
-    fn get_file_and_fileno(
+    /// Get a file's id, information, and the reason it was backed
+    /// up, given its path.
+    pub fn get_file_row(
         &self,
         filename: &Path,
-    ) -> Result<Option<(FileId, FilesystemEntry, String)>, GenerationDbError> {
+    ) -> Result<Option<(FileId, FilesystemEntry, Reason)>, GenerationDbError> {
         let filename_bytes = path_into_blob(filename);
         let value = Value::blob("filename", &filename_bytes);
         let mut rows = self
             .db
-            .some_rows(&self.files, &value, &Self::row_to_entry)?;
+            .some_rows(&self.files, &value, &Self::row_to_fsentry)?;
         let mut iter = rows.iter()?;
 
         if let Some(row) = iter.next() {
@@ -451,9 +829,8 @@ impl V0_0 {
                 error!("too many files in file lookup");
                 Err(GenerationDbError::TooManyFiles(filename.to_path_buf()))
             } else {
-                let (fileid, ref json, ref reason, _) = row?;
-                let entry = serde_json::from_str(json)?;
-                Ok(Some((fileid, entry, reason.to_string())))
+                let (fileid, entry, reason, _) = row?;
+                Ok(Some((fileid, entry, reason)))
             }
         } else {
             Ok(None)
@@ -477,7 +854,7 @@ impl V0_0 {
             rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err))
         })?;
         let reason: String = row.get("reason")?;
-        let reason = Reason::from(&reason);
+        let reason = Reason::with_renamed_from(&reason, row_to_renamed_from(row)?);
         let is_cachedir_tag: bool = row.get("is_cachedir_tag")?;
         Ok((fileno, entry, reason, is_cachedir_tag))
     }
@@ -493,18 +870,20 @@ struct V1_0 {
 
 impl V1_0 {
     const MAJOR: VersionComponent = 1;
-    const MINOR: VersionComponent = 0;
+    // Minor version 1 adds the inode change time and birth time to
+    // the JSON of each file.
+    const MINOR: VersionComponent = 1;
+    const FILEID: &'static str = "fileid";
 
     /// Create a new generation database in read/write mode.
-    pub fn create<P: AsRef<Path>>(
-        filename: P,
+    pub fn create(
+        db: Database,
         meta: Table,
         checksum_kind: LabelChecksumKind,
     ) -> Result<Self, GenerationDbError> {
-        let db = Database::create(filename.as_ref())?;
         let mut moi = Self::new(db, meta);
         moi.created = true;
-        moi.create_tables(checksum_kind)?;
+        moi.create_tables(checksum_kind, Self::MAJOR, Self::MINOR)?;
         Ok(moi)
     }
 
@@ -521,10 +900,15 @@ impl V1_0 {
             .column(Column::text("json"))
             .column(Column::text("reason"))
             .column(Column::bool("is_cachedir_tag"))
+            .column(Column::text("error"))
+            .column(Column::blob("renamed_from"))
             .build();
         let chunks = Table::new("chunks")
             .column(Column::int("fileid"))
             .column(Column::text("chunkid"))
+            .column(Column::int("offset"))
+            .column(Column::int("length"))
+            .column(Column::text("label"))
             .build();
 
         Self {
@@ -536,7 +920,12 @@ impl V1_0 {
         }
     }
 
-    fn create_tables(&mut self, checksum_kind: LabelChecksumKind) -> Result<(), GenerationDbError> {
+    fn create_tables(
+        &mut self,
+        checksum_kind: LabelChecksumKind,
+        major: VersionComponent,
+        minor: VersionComponent,
+    ) -> Result<(), GenerationDbError> {
         self.db.create_table(&self.meta)?;
         self.db.create_table(&self.files)?;
         self.db.create_table(&self.chunks)?;
@@ -545,14 +934,14 @@ impl V1_0 {
             &self.meta,
             &[
                 Value::text("key", "schema_version_major"),
-                Value::text("value", &format!("{}", Self::MAJOR)),
+                Value::text("value", &format!("{}", major)),
             ],
         )?;
         self.db.insert(
             &self.meta,
             &[
                 Value::text("key", "schema_version_minor"),
-                Value::text("value", &format!("{}", Self::MINOR)),
+                Value::text("value", &format!("{}", minor)),
             ],
         )?;
         self.db.insert(
@@ -566,13 +955,20 @@ impl V1_0 {
         Ok(())
     }
 
-    /// Close a database, commit any changes.
-    pub fn close(self) -> Result<(), GenerationDbError> {
+    /// Commit any changes, but keep the database open for reading.
+    pub fn commit(&mut self) -> Result<(), GenerationDbError> {
         if self.created {
             self.db
                 .create_index("filenames_idx", &self.files, "filename")?;
             self.db.create_index("fileid_idx", &self.chunks, "fileid")?;
+            self.created = false;
         }
+        self.db.commit().map_err(GenerationDbError::Database)
+    }
+
+    /// Close a database, commit any changes.
+    pub fn close(mut self) -> Result<(), GenerationDbError> {
+        self.commit()?;
         self.db.close().map_err(GenerationDbError::Database)
     }
 
@@ -587,14 +983,27 @@ impl V1_0 {
         Ok(map)
     }
 
+    /// Add a key/value pair to the "meta" table.
+    pub fn set_meta(&mut self, key: &str, value: &str) -> Result<(), GenerationDbError> {
+        self.db.insert(
+            &self.meta,
+            &[Value::text("key", key), Value::text("value", value)],
+        )?;
+        Ok(())
+    }
+
     /// Insert a file system entry into the database.
+    #[allow(clippy::too_many_arguments)]
     pub fn insert(
         &mut self,
         e: FilesystemEntry,
         fileid: FileId,
         ids: &[ChunkId],
+        lengths: &[u64],
+        labels: &[String],
         reason: Reason,
         is_cachedir_tag: bool,
+        error: Option<&str>,
     ) -> Result<(), GenerationDbError> {
         let json = serde_json::to_string(&e)?;
         self.db.insert(
@@ -605,14 +1014,21 @@ impl V1_0 {
                 Value::text("json", &json),
                 Value::text("reason", &format!("{}", reason)),
                 Value::bool("is_cachedir_tag", is_cachedir_tag),
+                Value::text("error", error.unwrap_or("")),
+                Value::blob("renamed_from", &renamed_from_blob(&reason)),
             ],
         )?;
-        for id in ids {
+        let spans = chunk_spans(ids, lengths);
+        for ((id, (offset, length)), label) in ids.iter().zip(spans).zip(chunk_labels(ids, labels))
+        {
             self.db.insert(
                 &self.chunks,
                 &[
                     Value::int("fileid", fileid),
                     Value::text("chunkid", &format!("{}", id)),
+                    Value::int("offset", offset),
+                    Value::int("length", length),
+                    Value::text("label", label),
                 ],
             )?;
         }
@@ -657,12 +1073,48 @@ impl V1_0 {
         }
     }
 
+    /// Why couldn't a file's content be backed up?
+    pub fn file_error(&self, filename: &Path) -> Result<Option<String>, GenerationDbError> {
+        let filename_vec = path_into_blob(filename);
+        let value = Value::blob("filename", &filename_vec);
+        let mut rows = self.db.some_rows(&self.files, &value, &row_to_error)?;
+        let mut iter = rows.iter()?;
+        match iter.next() {
+            Some(row) => Ok(row?),
+            None => Ok(None),
+        }
+    }
+
+    /// Change why a file is in the generation.
+    pub fn set_reason(&mut self, fileid: FileId, reason: &Reason) -> Result<(), GenerationDbError> {
+        self.db.update(
+            &self.files,
+            &[
+                Value::text("reason", &format!("{}", reason)),
+                Value::blob("renamed_from", &renamed_from_blob(reason)),
+            ],
+            &Value::primary_key(Self::FILEID, fileid),
+        )?;
+        Ok(())
+    }
+
     /// Return all chunk ids in database.
     pub fn chunkids(&self, fileid: FileId) -> Result<SqlResults<ChunkId>, GenerationDbError> {
         let fileid = Value::int("fileid", fileid);
         Ok(self.db.some_rows(&self.chunks, &fileid, &row_to_chunkid)?)
     }
 
+    /// Return a file's chunks, with their offsets and lengths.
+    pub fn file_chunks(
+        &self,
+        fileid: FileId,
+    ) -> Result<SqlResults<'_, FileChunk>, GenerationDbError> {
+        let fileid = Value::int("fileid", fileid);
+        Ok(self
+            .db
+            .some_rows(&self.chunks, &fileid, &row_to_file_chunk)?)
+    }
+
     /// Return all file descriptions in database.
     pub fn files(
         &self,
@@ -672,7 +1124,7 @@ impl V1_0 {
 
     /// Get a file's information given its path.
     pub fn get_file(&self, filename: &Path) -> Result<Option<FilesystemEntry>, GenerationDbError> {
-        match self.get_file_and_fileno(filename)? {
+        match self.get_file_row(filename)? {
             None => Ok(None),
             Some((_, e, _)) => Ok(Some(e)),
         }
@@ -680,21 +1132,23 @@ impl V1_0 {
 
     /// Get a file's information given its id in the database.
     pub fn get_fileno(&self, filename: &Path) -> Result<Option<FileId>, GenerationDbError> {
-        match self.get_file_and_fileno(filename)? {
+        match self.get_file_row(filename)? {
             None => Ok(None),
             Some((id, _, _)) => Ok(Some(id)),
         }
     }
 
-    fn get_file_and_fileno(
+    /// Get a file's id, information, and the reason it was backed
+    /// up, given its path.
+    pub fn get_file_row(
         &self,
         filename: &Path,
-    ) -> Result<Option<(FileId, FilesystemEntry, String)>, GenerationDbError> {
+    ) -> Result<Option<(FileId, FilesystemEntry, Reason)>, GenerationDbError> {
         let filename_bytes = path_into_blob(filename);
         let value = Value::blob("filename", &filename_bytes);
         let mut rows = self
             .db
-            .some_rows(&self.files, &value, &Self::row_to_entry)?;
+            .some_rows(&self.files, &value, &Self::row_to_fsentry)?;
         let mut iter = rows.iter()?;
 
         if let Some(row) = iter.next() {
@@ -705,9 +1159,8 @@ impl V1_0 {
                 error!("too many files in file lookup");
                 Err(GenerationDbError::TooManyFiles(filename.to_path_buf()))
             } else {
-                let (fileid, ref json, ref reason, _) = row?;
-                let entry = serde_json::from_str(json)?;
-                Ok(Some((fileid, entry, reason.to_string())))
+                let (fileid, entry, reason, _) = row?;
+                Ok(Some((fileid, entry, reason)))
             }
         } else {
             Ok(None)
@@ -731,31 +1184,200 @@ impl V1_0 {
             rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err))
         })?;
         let reason: String = row.get("reason")?;
-        let reason = Reason::from(&reason);
+        let reason = Reason::with_renamed_from(&reason, row_to_renamed_from(row)?);
         let is_cachedir_tag: bool = row.get("is_cachedir_tag")?;
         Ok((fileno, entry, reason, is_cachedir_tag))
     }
 }
 
+// Schema version 2 has the same tables as version 1, and additionally
+// records which files have been deleted since the parent generation.
+// A generation using it may store only the files that have changed
+// since its parent; looking up files through the chain of parents is
+// done by `LocalGeneration`.
+struct V2_0 {
+    v1: V1_0,
+    deleted: Table,
+}
+
+impl V2_0 {
+    const MAJOR: VersionComponent = 2;
+    // Minor version 1 adds the inode change time and birth time to
+    // the JSON of each file.
+    const MINOR: VersionComponent = 1;
+
+    /// Create a new generation database in read/write mode.
+    pub fn create(
+        db: Database,
+        meta: Table,
+        checksum_kind: LabelChecksumKind,
+    ) -> Result<Self, GenerationDbError> {
+        let mut v1 = V1_0::new(db, meta);
+        v1.created = true;
+        v1.create_tables(checksum_kind, Self::MAJOR, Self::MINOR)?;
+        let moi = Self::new(v1);
+        moi.v1.db.create_table(&moi.deleted)?;
+        Ok(moi)
+    }
+
+    /// Open an existing generation database in read-only mode.
+    pub fn open<P: AsRef<Path>>(filename: P, meta: Table) -> Result<Self, GenerationDbError> {
+        Ok(Self::new(V1_0::open(filename, meta)?))
+    }
+
+    fn new(v1: V1_0) -> Self {
+        let deleted = Table::new("deleted")
+            .column(Column::blob("filename"))
+            .build();
+        Self { v1, deleted }
+    }
+
+    /// Commit any changes, but keep the database open for reading.
+    pub fn commit(&mut self) -> Result<(), GenerationDbError> {
+        if self.v1.created {
+            self.v1
+                .db
+                .create_index("deleted_idx", &self.deleted, "filename")?;
+        }
+        self.v1.commit()
+    }
+
+    /// Close a database, commit any changes.
+    pub fn close(mut self) -> Result<(), GenerationDbError> {
+        self.commit()?;
+        self.v1.close()
+    }
+
+    /// Record that a file has been deleted since the parent
+    /// generation.
+    pub fn delete(&mut self, filename: &Path) -> Result<(), GenerationDbError> {
+        self.v1.db.insert(
+            &self.deleted,
+            &[Value::blob("filename", &path_into_blob(filename))],
+        )?;
+        Ok(())
+    }
+
+    /// Has a file been deleted since the parent generation?
+    pub fn is_deleted(&self, filename: &Path) -> Result<bool, GenerationDbError> {
+        let filename_bytes = path_into_blob(filename);
+        let value = Value::blob("filename", &filename_bytes);
+        let mut rows = self.v1.db.some_rows(&self.deleted, &value, &row_to_blob)?;
+        let found = rows.iter()?.next().is_some();
+        Ok(found)
+    }
+}
+
 fn row_to_kv(row: &rusqlite::Row) -> rusqlite::Result<(String, String)> {
     let k = row.get("key")?;
     let v = row.get("value")?;
     Ok((k, v))
 }
 
+// The error and renamed_from columns were added to the files table
+// without changing the schema version: they're only informational, and
+// an older version of Obnam ignores them. Generations made before then
+// don't have them, and files without an error, or that weren't
+// renamed, have them empty.
+fn row_to_error(row: &rusqlite::Row) -> rusqlite::Result<Option<String>> {
+    match row.get::<_, Option<String>>("error") {
+        Ok(error) => Ok(error.filter(|error| !error.is_empty())),
+        Err(rusqlite::Error::InvalidColumnName(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn row_to_renamed_from(row: &rusqlite::Row) -> rusqlite::Result<Option<PathBuf>> {
+    match row.get::<_, Option<Vec<u8>>>("renamed_from") {
+        Ok(Some(bytes)) if !bytes.is_empty() => Ok(Some(blob_into_path(bytes))),
+        Ok(_) | Err(rusqlite::Error::InvalidColumnName(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn renamed_from_blob(reason: &Reason) -> Vec<u8> {
+    reason
+        .renamed_from()
+        .map(|from| path_into_blob(from))
+        .unwrap_or_default()
+}
+
+fn row_to_blob(row: &rusqlite::Row) -> rusqlite::Result<Vec<u8>> {
+    row.get("filename")
+}
+
 fn path_into_blob(path: &Path) -> Vec<u8> {
     path.as_os_str().as_bytes().to_vec()
 }
 
+fn blob_into_path(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
 fn row_to_chunkid(row: &rusqlite::Row) -> rusqlite::Result<ChunkId> {
     let chunkid: String = row.get("chunkid")?;
     let chunkid = ChunkId::recreate(&chunkid);
     Ok(chunkid)
 }
 
+// The offset, length, and label columns were added to the chunks
+// table the same way as the error column to the files table. A chunk
+// is never empty, so a zero length means the length isn't known, and
+// neither is an empty label.
+fn row_to_file_chunk(row: &rusqlite::Row) -> rusqlite::Result<FileChunk> {
+    let id = row_to_chunkid(row)?;
+    let span = match (optional_int(row, "offset")?, optional_int(row, "length")?) {
+        (Some(offset), Some(length)) if length > 0 => Some((offset as u64, length as u64)),
+        _ => None,
+    };
+    let label = match row.get::<_, Option<String>>("label") {
+        Ok(label) => label.filter(|label| !label.is_empty()),
+        Err(rusqlite::Error::InvalidColumnName(_)) => None,
+        Err(err) => return Err(err),
+    };
+    Ok(FileChunk { id, span, label })
+}
+
+fn optional_int(row: &rusqlite::Row, column: &str) -> rusqlite::Result<Option<DbInt>> {
+    match row.get::<_, Option<DbInt>>(column) {
+        Ok(value) => Ok(value),
+        Err(rusqlite::Error::InvalidColumnName(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// The offset and length to record for each chunk of a file. If the
+// lengths aren't known for all chunks, none are recorded.
+fn chunk_spans(ids: &[ChunkId], lengths: &[u64]) -> Vec<(DbInt, DbInt)> {
+    if lengths.len() != ids.len() {
+        return vec![(0, 0); ids.len()];
+    }
+    let mut offset = 0;
+    let mut spans = vec![];
+    for length in lengths {
+        spans.push((offset as DbInt, *length as DbInt));
+        offset += length;
+    }
+    spans
+}
+
+// The label to record for each chunk of a file. Like lengths, labels
+// are only recorded if they're known for all chunks.
+fn chunk_labels<'a>(ids: &[ChunkId], labels: &'a [String]) -> Vec<&'a str> {
+    if labels.len() != ids.len() {
+        return vec![""; ids.len()];
+    }
+    labels.iter().map(|label| label.as_str()).collect()
+}
+
 #[cfg(test)]
 mod test {
-    use super::Database;
+    use super::{Database, FileId, GenerationDb, Reason};
+    use crate::chunkid::ChunkId;
+    use crate::fsentry::{EntryBuilder, FilesystemKind};
+    use crate::label::LabelChecksumKind;
+    use crate::schema::SchemaVersion;
+    use std::path::{Path, PathBuf};
     use tempfile::tempdir;
 
     #[test]
@@ -765,4 +1387,278 @@ mod test {
         Database::create(&filename).unwrap();
         assert!(Database::open(&filename).is_ok());
     }
+
+    #[test]
+    fn aggregates_stats_by_reason_and_kind() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("test.db");
+        let schema = SchemaVersion::new(0, 1);
+        let mut db = GenerationDb::create(&filename, schema, LabelChecksumKind::Sha256).unwrap();
+        let files = [
+            ("/", FilesystemKind::Directory, 4096, Reason::IsNew),
+            ("/a", FilesystemKind::Regular, 10, Reason::IsNew),
+            ("/b", FilesystemKind::Regular, 20, Reason::Unchanged),
+            ("/c", FilesystemKind::Regular, 40, Reason::FileError),
+        ];
+        for (fileno, (path, kind, len, reason)) in files.iter().enumerate() {
+            let e = EntryBuilder::new(*kind)
+                .path(PathBuf::from(path))
+                .len(*len)
+                .build();
+            db.insert(
+                e,
+                fileno as i64 + 1,
+                &[],
+                &[],
+                &[],
+                reason.clone(),
+                false,
+                None,
+            )
+            .unwrap();
+        }
+        db.close().unwrap();
+
+        let db = GenerationDb::open(&filename).unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.total().count(), 4);
+        assert_eq!(stats.total().bytes(), 70);
+
+        let by_reason: Vec<(&str, u64, u64)> = stats
+            .by_reason()
+            .map(|(r, t)| (r, t.count(), t.bytes()))
+            .collect();
+        assert_eq!(
+            by_reason,
+            vec![("fileerror", 1, 40), ("new", 2, 10), ("unchanged", 1, 20)]
+        );
+
+        let by_kind: Vec<(&str, u64, u64)> = stats
+            .by_kind()
+            .map(|(k, t)| (k, t.count(), t.bytes()))
+            .collect();
+        assert_eq!(by_kind, vec![("directory", 1, 0), ("regular", 3, 70)]);
+    }
+
+    fn create_with_error(filename: &Path) {
+        let schema = SchemaVersion::new(0, 1);
+        let mut db = GenerationDb::create(filename, schema, LabelChecksumKind::Sha256).unwrap();
+        let ok = EntryBuilder::new(FilesystemKind::Regular)
+            .path(PathBuf::from("/ok"))
+            .build();
+        let bad = EntryBuilder::new(FilesystemKind::Regular)
+            .path(PathBuf::from("/bad"))
+            .build();
+        db.insert(ok, 1, &[], &[], &[], Reason::IsNew, false, None)
+            .unwrap();
+        db.insert(
+            bad,
+            2,
+            &[],
+            &[],
+            &[],
+            Reason::FileError,
+            false,
+            Some("denied"),
+        )
+        .unwrap();
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn opens_older_minor_version() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("test.db");
+        create_with_error(&filename);
+        let conn = rusqlite::Connection::open(&filename).unwrap();
+        conn.execute(
+            "UPDATE meta SET value = '0' WHERE key = 'schema_version_minor'",
+            [],
+        )
+        .unwrap();
+        drop(conn);
+
+        let db = GenerationDb::open(&filename).unwrap();
+        assert_eq!(db.file_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn records_file_errors() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("test.db");
+        create_with_error(&filename);
+
+        let db = GenerationDb::open(&filename).unwrap();
+        assert_eq!(db.file_error(Path::new("/ok")).unwrap(), None);
+        assert_eq!(
+            db.file_error(Path::new("/bad")).unwrap(),
+            Some("denied".to_string())
+        );
+        assert_eq!(db.file_error(Path::new("/missing")).unwrap(), None);
+    }
+
+    #[test]
+    fn records_renamed_files() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("test.db");
+        let schema = SchemaVersion::new(1, 1);
+        let mut db = GenerationDb::create(&filename, schema, LabelChecksumKind::Sha256).unwrap();
+        for (fileno, path) in ["/new", "/other"].iter().enumerate() {
+            let e = EntryBuilder::new(FilesystemKind::Regular)
+                .path(PathBuf::from(path))
+                .build();
+            db.insert(
+                e,
+                fileno as i64 + 1,
+                &[],
+                &[],
+                &[],
+                Reason::IsNew,
+                false,
+                None,
+            )
+            .unwrap();
+        }
+        db.set_reason(1, &Reason::Renamed(PathBuf::from("/old")))
+            .unwrap();
+        db.close().unwrap();
+
+        let db = GenerationDb::open(&filename).unwrap();
+        let mut reasons = vec![];
+        for file in db.files().unwrap().iter().unwrap() {
+            let (_, _, reason, _) = file.unwrap();
+            reasons.push((reason.to_string(), reason.renamed_from().cloned()));
+        }
+        assert_eq!(
+            reasons,
+            vec![
+                ("renamed".to_string(), Some(PathBuf::from("/old"))),
+                ("new".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_generation_without_file_errors() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("test.db");
+        create_with_error(&filename);
+        let conn = rusqlite::Connection::open(&filename).unwrap();
+        conn.execute("ALTER TABLE files DROP COLUMN error", [])
+            .unwrap();
+        conn.close().unwrap();
+
+        let db = GenerationDb::open(&filename).unwrap();
+        assert_eq!(db.file_error(Path::new("/bad")).unwrap(), None);
+        assert_eq!(db.file_count().unwrap(), 2);
+    }
+
+    fn create_with_chunks(filename: &Path) {
+        let schema = SchemaVersion::new(1, 1);
+        let mut db = GenerationDb::create(filename, schema, LabelChecksumKind::Sha256).unwrap();
+        let ids = [ChunkId::recreate("c1"), ChunkId::recreate("c2")];
+        let labels = vec!["l1".to_string(), "l2".to_string()];
+        for (fileno, (path, lengths, labels)) in [
+            ("/sized", vec![10, 3], labels),
+            ("/unsized", vec![], vec![]),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let e = EntryBuilder::new(FilesystemKind::Regular)
+                .path(PathBuf::from(path))
+                .build();
+            db.insert(
+                e,
+                fileno as i64 + 1,
+                &ids,
+                lengths,
+                labels,
+                Reason::IsNew,
+                false,
+                None,
+            )
+            .unwrap();
+        }
+        db.close().unwrap();
+    }
+
+    fn spans(db: &GenerationDb, fileid: FileId) -> Vec<(String, Option<u64>, Option<u64>)> {
+        let mut spans = vec![];
+        for chunk in db.file_chunks(fileid).unwrap().iter().unwrap() {
+            let chunk = chunk.unwrap();
+            spans.push((chunk.id().to_string(), chunk.offset(), chunk.length()));
+        }
+        spans
+    }
+
+    fn labels(db: &GenerationDb, fileid: FileId) -> Vec<Option<String>> {
+        let mut labels = vec![];
+        for chunk in db.file_chunks(fileid).unwrap().iter().unwrap() {
+            labels.push(chunk.unwrap().label().map(|label| label.to_string()));
+        }
+        labels
+    }
+
+    #[test]
+    fn records_chunk_offsets_and_lengths() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("test.db");
+        create_with_chunks(&filename);
+
+        let db = GenerationDb::open(&filename).unwrap();
+        assert_eq!(
+            spans(&db, 1),
+            vec![
+                ("c1".to_string(), Some(0), Some(10)),
+                ("c2".to_string(), Some(10), Some(3)),
+            ]
+        );
+        assert_eq!(
+            spans(&db, 2),
+            vec![
+                ("c1".to_string(), None, None),
+                ("c2".to_string(), None, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn records_chunk_labels() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("test.db");
+        create_with_chunks(&filename);
+
+        let db = GenerationDb::open(&filename).unwrap();
+        assert_eq!(
+            labels(&db, 1),
+            vec![Some("l1".to_string()), Some("l2".to_string())]
+        );
+        assert_eq!(labels(&db, 2), vec![None, None]);
+    }
+
+    #[test]
+    fn reads_generation_without_chunk_lengths() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("test.db");
+        create_with_chunks(&filename);
+        let conn = rusqlite::Connection::open(&filename).unwrap();
+        conn.execute("ALTER TABLE chunks DROP COLUMN offset", [])
+            .unwrap();
+        conn.execute("ALTER TABLE chunks DROP COLUMN length", [])
+            .unwrap();
+        conn.execute("ALTER TABLE chunks DROP COLUMN label", [])
+            .unwrap();
+        conn.close().unwrap();
+
+        let db = GenerationDb::open(&filename).unwrap();
+        assert_eq!(
+            spans(&db, 1),
+            vec![
+                ("c1".to_string(), None, None),
+                ("c2".to_string(), None, None)
+            ]
+        );
+        assert_eq!(labels(&db, 1), vec![None, None]);
+    }
 }
@@ -2,20 +2,106 @@
 
 use crate::backup_run::BackupError;
 use crate::chunk::ClientTrustError;
+use crate::chunkstore::StoreError;
 use crate::cipher::CipherError;
 use crate::client::ClientError;
+use crate::cmd::chunk::ChunkCommandError;
+use crate::cmd::export::ExportError;
+use crate::cmd::get_chunk::GetChunkError;
+use crate::cmd::repair::RepairError;
 use crate::cmd::restore::RestoreError;
+use crate::cmd::restore_test::RestoreTestError;
 use crate::config::ClientConfigError;
 use crate::db::DatabaseError;
 use crate::dbgen::GenerationDbError;
-use crate::generation::{LocalGenerationError, NascentError};
+use crate::generation::{GenId, LocalGenerationError, NascentError};
 use crate::genlist::GenerationListError;
+use crate::genmeta::GenerationMetaError;
+use crate::keyexport::KeyExportError;
 use crate::label::LabelError;
+use crate::passphrase::PassphraseError;
 use crate::passwords::PasswordError;
+use crate::pathmatch::PathMatchError;
+use crate::progress_sink::ProgressSinkError;
+use crate::recovery::RecoveryError;
+use crate::refcount::RefCountError;
+use crate::runlock::RunLockError;
 use std::path::PathBuf;
 use std::time::SystemTimeError;
 use tempfile::PersistError;
 
+/// Exit code for a failure that isn't of any of the other kinds.
+pub const EXIT_FAILURE: i32 = 1;
+
+/// Exit code for a configuration that can't be used, including keys
+/// that can't be read.
+pub const EXIT_CONFIG: i32 = 3;
+
+/// Exit code for when the server can't be reached, or responds
+/// unexpectedly.
+pub const EXIT_NETWORK: i32 = 4;
+
+/// Exit code for a backup that was made, but with problems that
+/// `--fail-on-warning` makes fatal.
+pub const EXIT_PARTIAL: i32 = 5;
+
+/// Exit code for a backup or chunk that turned out to be damaged when
+/// checked.
+pub const EXIT_VERIFICATION: i32 = 6;
+
+/// Exit code for when there's nothing to do, such as when a restore
+/// test finds no files to test.
+pub const EXIT_NOTHING_TO_DO: i32 = 7;
+
+/// Exit code for when another Obnam run for the same client is already
+/// running.
+pub const EXIT_LOCKED: i32 = 8;
+
+/// Exit code for an operation cancelled by a signal. This is what
+/// shells use for a program killed by SIGINT.
+pub const EXIT_CANCELLED: i32 = 130;
+
+/// What kind of failure an error is.
+///
+/// Each kind has its own exit code, so that scripts running Obnam,
+/// such as from cron, can tell them apart. Errors in the command line
+/// are reported by the command line parser, with exit code 2.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// Any other failure.
+    Failure,
+    /// The configuration can't be used.
+    Config,
+    /// The server can't be reached.
+    Network,
+    /// A backup was made, but with problems.
+    Partial,
+    /// Something was found damaged.
+    Verification,
+    /// There was nothing to do.
+    NothingToDo,
+    /// Another run for the same client holds the lock.
+    Locked,
+    /// The operation was cancelled.
+    Cancelled,
+}
+
+impl ErrorKind {
+    /// The exit code for this kind of failure.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Failure => EXIT_FAILURE,
+            Self::Config => EXIT_CONFIG,
+            Self::Network => EXIT_NETWORK,
+            Self::Partial => EXIT_PARTIAL,
+            Self::Verification => EXIT_VERIFICATION,
+            Self::NothingToDo => EXIT_NOTHING_TO_DO,
+            Self::Locked => EXIT_LOCKED,
+            Self::Cancelled => EXIT_CANCELLED,
+        }
+    }
+}
+
 /// Define all the kinds of errors that functions corresponding to
 /// subcommands of the main program can return.
 ///
@@ -43,6 +129,50 @@ pub enum ObnamError {
     #[error(transparent)]
     ClientError(#[from] ClientError),
 
+    /// Error from a chunk store.
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    /// Error counting chunk references.
+    #[error(transparent)]
+    RefCount(#[from] RefCountError),
+
+    /// The server can't be reached.
+    #[error("can't reach server {0}: {1}")]
+    ServerUnreachable(String, StoreError),
+
+    /// Error reading a recovery phrase.
+    #[error(transparent)]
+    Recovery(#[from] RecoveryError),
+
+    /// Error exporting or importing keys.
+    #[error(transparent)]
+    KeyExport(#[from] KeyExportError),
+
+    /// Error writing exported keys.
+    #[error("couldn't write exported keys to {0}: {1}")]
+    KeyExportWrite(PathBuf, std::io::Error),
+
+    /// Error reading keys to import.
+    #[error("couldn't read keys to import from {0}: {1}")]
+    KeyImportRead(PathBuf, std::io::Error),
+
+    /// Refusing to replace existing keys on import.
+    #[error("client already has keys in {0}; use --force to replace them")]
+    KeysExist(PathBuf),
+
+    /// Error writing the performance report.
+    #[error("couldn't write performance report to {0}: {1}")]
+    PerformanceReport(PathBuf, std::io::Error),
+
+    /// Error getting a passphrase.
+    #[error(transparent)]
+    Passphrase(#[from] PassphraseError),
+
+    /// The two passphrases typed in didn't match.
+    #[error("passphrases don't match")]
+    PassphraseMismatch,
+
     /// Error in client configuration.
     #[error(transparent)]
     ClientConfigError(#[from] ClientConfigError),
@@ -51,6 +181,10 @@ pub enum ObnamError {
     #[error(transparent)]
     BackupError(#[from] BackupError),
 
+    /// Error locking, to prevent concurrent runs.
+    #[error(transparent)]
+    RunLock(#[from] RunLockError),
+
     /// Error making a new backup generation.
     #[error(transparent)]
     NascentError(#[from] NascentError),
@@ -67,6 +201,10 @@ pub enum ObnamError {
     #[error(transparent)]
     GenerationDb(#[from] GenerationDbError),
 
+    /// Error from generation metadata.
+    #[error(transparent)]
+    GenerationMeta(#[from] GenerationMetaError),
+
     /// Error using a Database.
     #[error(transparent)]
     Database(#[from] DatabaseError),
@@ -75,6 +213,34 @@ pub enum ObnamError {
     #[error(transparent)]
     RestoreError(#[from] RestoreError),
 
+    /// Error testing restores.
+    #[error(transparent)]
+    RestoreTestError(#[from] RestoreTestError),
+
+    /// Error repairing a generation.
+    #[error(transparent)]
+    RepairError(#[from] RepairError),
+
+    /// Error fetching a chunk.
+    #[error(transparent)]
+    GetChunkError(#[from] GetChunkError),
+
+    /// Error exporting or importing a generation.
+    #[error(transparent)]
+    ExportError(#[from] ExportError),
+
+    /// Error encrypting or decrypting a chunk locally.
+    #[error(transparent)]
+    ChunkCommandError(#[from] ChunkCommandError),
+
+    /// Error parsing a search pattern.
+    #[error(transparent)]
+    PathMatch(#[from] PathMatchError),
+
+    /// Error setting up progress reporting.
+    #[error(transparent)]
+    ProgressSinkError(#[from] ProgressSinkError),
+
     /// Error making temporary file persistent.
     #[error(transparent)]
     PersistError(#[from] PersistError),
@@ -91,9 +257,111 @@ pub enum ObnamError {
     #[error(transparent)]
     SerdeJsonError(#[from] serde_json::Error),
 
+    /// Some files in a backup can't be restored, because chunks are
+    /// missing or corrupt.
+    #[error("backup {0} has {1} damaged files")]
+    DamagedBackup(GenId, usize),
+
+    /// Chunks uploaded by a backup were missing or damaged when they
+    /// were fetched again.
+    #[error("backup {0} was made, but {1} of {2} chunks fetched again were missing or damaged; run `obnam repair` on it")]
+    DamagedUploads(GenId, usize, usize),
+
+    /// Refusing to remove the client that's running.
+    #[error(
+        "refusing to remove client {0}, which is this client; use --force to remove it anyway"
+    )]
+    RemoveOwnClient(String),
+
+    /// Refusing to remove a client with pinned backups.
+    #[error("refusing to remove client {0}, which has {1} pinned generations; unpin them first")]
+    RemovePinnedClient(String, usize),
+
+    /// Refusing to forget a pinned generation.
+    #[error("generation {0} is pinned; unpin it with `obnam unpin` to forget it")]
+    PinnedGeneration(GenId),
+
     /// Unexpected cache directories found.
     #[error(
         "found CACHEDIR.TAG files that aren't present in the previous backup, might be an attack"
     )]
     NewCachedirTagsFound,
+
+    /// Validating the client configuration found problems.
+    #[error("found {0} problems in the configuration")]
+    BadConfig(usize),
+
+    /// There were problems that --fail-on-warning makes fatal.
+    #[error("there were {0} problems that --fail-on-warning makes fatal")]
+    FailOnWarning(usize),
+}
+
+impl ObnamError {
+    /// Did the operation fail because it was cancelled?
+    pub fn is_cancelled(&self) -> bool {
+        matches!(
+            self,
+            Self::ClientError(ClientError::Cancelled)
+                | Self::BackupError(BackupError::Cancelled)
+                | Self::BackupError(BackupError::CancelledAfter(_))
+                | Self::BackupError(BackupError::ClientError(ClientError::Cancelled))
+                | Self::ExportError(ExportError::Cancelled)
+                | Self::RestoreError(RestoreError::Cancelled)
+                | Self::RestoreError(RestoreError::CancelledAfter(_, _))
+                | Self::RestoreError(RestoreError::ClientError(ClientError::Cancelled))
+        )
+    }
+
+    /// What kind of failure is this?
+    pub fn kind(&self) -> ErrorKind {
+        if self.is_cancelled() {
+            return ErrorKind::Cancelled;
+        }
+        match self {
+            Self::ClientConfigError(_)
+            | Self::BadConfig(_)
+            | Self::Passphrase(_)
+            | Self::PathMatch(_) => ErrorKind::Config,
+            Self::ServerUnreachable(_, err) if err.is_config() => ErrorKind::Config,
+            Self::ServerUnreachable(_, _) => ErrorKind::Network,
+            Self::Store(err) => store_error_kind(err),
+            Self::ClientError(err)
+            | Self::BackupError(BackupError::ClientError(err))
+            | Self::RestoreError(RestoreError::ClientError(err))
+            | Self::RestoreTestError(RestoreTestError::ClientError(err)) => client_error_kind(err),
+            Self::FailOnWarning(_) => ErrorKind::Partial,
+            Self::DamagedBackup(_, _)
+            | Self::DamagedUploads(_, _, _)
+            | Self::RestoreTestError(RestoreTestError::Failed(_, _))
+            | Self::RestoreTestError(RestoreTestError::WrongSize(_, _, _))
+            | Self::RestoreTestError(RestoreTestError::WrongChecksum(_, _)) => {
+                ErrorKind::Verification
+            }
+            Self::RestoreTestError(RestoreTestError::NothingToTest(_)) => ErrorKind::NothingToDo,
+            Self::RunLock(RunLockError::Locked(_, _)) => ErrorKind::Locked,
+            _ => ErrorKind::Failure,
+        }
+    }
+}
+
+fn client_error_kind(err: &ClientError) -> ErrorKind {
+    if err.is_config() {
+        ErrorKind::Config
+    } else if err.is_network() {
+        ErrorKind::Network
+    } else if let ClientError::WrongChecksum(_, _, _) = err {
+        ErrorKind::Verification
+    } else {
+        ErrorKind::Failure
+    }
+}
+
+fn store_error_kind(err: &StoreError) -> ErrorKind {
+    if err.is_config() {
+        ErrorKind::Config
+    } else if err.is_network() {
+        ErrorKind::Network
+    } else {
+        ErrorKind::Failure
+    }
 }
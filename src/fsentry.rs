@@ -5,9 +5,10 @@ use serde::{Deserialize, Serialize};
 use std::ffi::OsString;
 use std::fs::read_link;
 use std::fs::{FileType, Metadata};
-use std::os::unix::ffi::OsStringExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::FileTypeExt;
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 use users::{Groups, Users, UsersCache};
 
 #[cfg(target_os = "linux")]
@@ -25,7 +26,7 @@ use std::os::macos::fs::MetadataExt;
 ///
 /// This is everything Obnam cares about each file system object, when
 /// making a backup.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct FilesystemEntry {
     kind: FilesystemKind,
     path: Vec<u8>,
@@ -43,6 +44,14 @@ pub struct FilesystemEntry {
     atime: i64,
     atime_ns: i64,
 
+    // The inode change time and the birth time. Older versions of
+    // Obnam didn't store them, and not every file system has a birth
+    // time, so they may be missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ctime: Option<(i64, i64)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    btime: Option<(i64, i64)>,
+
     // The target of a symbolic link, if any.
     symlink_target: Option<PathBuf>,
 
@@ -82,12 +91,21 @@ impl FilesystemEntry {
             .mode(meta.st_mode())
             .mtime(meta.st_mtime(), meta.st_mtime_nsec())
             .atime(meta.st_atime(), meta.st_atime_nsec())
+            .ctime(meta.st_ctime(), meta.st_ctime_nsec())
+            .btime(birth_time(meta))
             .user(meta.st_uid(), cache)?
-            .group(meta.st_uid(), cache)?
+            .group(meta.st_gid(), cache)?
             .symlink_target()?
             .build())
     }
 
+    /// Return a copy of the entry with a different path.
+    pub fn with_path(&self, path: &Path) -> Self {
+        let mut e = self.clone();
+        e.path = path.as_os_str().as_bytes().to_vec();
+        e
+    }
+
     /// Return the kind of file the entry refers to.
     pub fn kind(&self) -> FilesystemKind {
         self.kind
@@ -129,6 +147,18 @@ impl FilesystemEntry {
         self.mtime_ns
     }
 
+    /// Return the entry's inode change time, as whole seconds and
+    /// nanoseconds since the last full second, if known.
+    pub fn ctime(&self) -> Option<(i64, i64)> {
+        self.ctime
+    }
+
+    /// Return the entry's birth time, as whole seconds and
+    /// nanoseconds since the last full second, if known.
+    pub fn btime(&self) -> Option<(i64, i64)> {
+        self.btime
+    }
+
     /// Does the entry represent a directory?
     pub fn is_dir(&self) -> bool {
         self.kind() == FilesystemKind::Directory
@@ -138,6 +168,30 @@ impl FilesystemEntry {
     pub fn symlink_target(&self) -> Option<PathBuf> {
         self.symlink_target.clone()
     }
+
+    /// Return numeric id of user owning the entry.
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// Return numeric id of group owning the entry.
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// Return name of user owning the entry, at the time of the backup.
+    ///
+    /// This is the empty string if the user id had no name.
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Return name of group owning the entry, at the time of the backup.
+    ///
+    /// This is the empty string if the group id had no name.
+    pub fn group(&self) -> &str {
+        &self.group
+    }
 }
 
 #[derive(Debug)]
@@ -157,6 +211,8 @@ pub(crate) struct EntryBuilder {
     mtime_ns: i64,
     atime: i64,
     atime_ns: i64,
+    ctime: Option<(i64, i64)>,
+    btime: Option<(i64, i64)>,
 
     // The target of a symbolic link, if any.
     symlink_target: Option<PathBuf>,
@@ -181,6 +237,8 @@ impl EntryBuilder {
             mtime_ns: 0,
             atime: 0,
             atime_ns: 0,
+            ctime: None,
+            btime: None,
             symlink_target: None,
             uid: 0,
             user: "".to_string(),
@@ -199,6 +257,8 @@ impl EntryBuilder {
             mtime_ns: self.mtime_ns,
             atime: self.atime,
             atime_ns: self.atime_ns,
+            ctime: self.ctime,
+            btime: self.btime,
             symlink_target: self.symlink_target,
             uid: self.uid,
             user: self.user,
@@ -234,6 +294,16 @@ impl EntryBuilder {
         self
     }
 
+    pub(crate) fn ctime(mut self, secs: i64, nsec: i64) -> Self {
+        self.ctime = Some((secs, nsec));
+        self
+    }
+
+    pub(crate) fn btime(mut self, time: Option<(i64, i64)>) -> Self {
+        self.btime = time;
+        self
+    }
+
     pub(crate) fn symlink_target(mut self) -> Result<Self, FsEntryError> {
         self.symlink_target = if self.kind == FilesystemKind::Symlink {
             debug!("reading symlink target for {:?}", self.path);
@@ -267,6 +337,25 @@ impl EntryBuilder {
     }
 }
 
+// The birth time of a file, if the file system records it. On Linux,
+// the standard library gets it with statx.
+fn birth_time(meta: &Metadata) -> Option<(i64, i64)> {
+    let created = meta.created().ok()?;
+    match created.duration_since(UNIX_EPOCH) {
+        Ok(after) => Some((after.as_secs() as i64, i64::from(after.subsec_nanos()))),
+        Err(err) => {
+            // Before the epoch: round the seconds down, so that the
+            // nanoseconds are positive, like in a stat timestamp.
+            let before = err.duration();
+            let secs = -(before.as_secs() as i64);
+            match before.subsec_nanos() {
+                0 => Some((secs, 0)),
+                nsec => Some((secs - 1, 1_000_000_000 - i64::from(nsec))),
+            }
+        }
+    }
+}
+
 /// Different types of file system entries.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum FilesystemKind {
@@ -280,6 +369,9 @@ pub enum FilesystemKind {
     Socket,
     /// A UNIX named pipe.
     Fifo,
+    /// Data read from a pipe, such as a database dump, rather than
+    /// from a file. It's restored as a regular file.
+    Stream,
 }
 
 impl FilesystemKind {
@@ -308,9 +400,27 @@ impl FilesystemKind {
             FilesystemKind::Symlink => 2,
             FilesystemKind::Socket => 3,
             FilesystemKind::Fifo => 4,
+            FilesystemKind::Stream => 5,
         }
     }
 
+    /// Return a short name for the kind, for humans.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilesystemKind::Regular => "regular",
+            FilesystemKind::Directory => "directory",
+            FilesystemKind::Symlink => "symlink",
+            FilesystemKind::Socket => "socket",
+            FilesystemKind::Fifo => "fifo",
+            FilesystemKind::Stream => "stream",
+        }
+    }
+
+    /// Does an entry of this kind have content stored in chunks?
+    pub fn has_content(&self) -> bool {
+        matches!(self, FilesystemKind::Regular | FilesystemKind::Stream)
+    }
+
     /// Create a kind from a numeric code.
     pub fn from_code(code: u8) -> Result<Self, FsEntryError> {
         match code {
@@ -319,6 +429,7 @@ impl FilesystemKind {
             2 => Ok(FilesystemKind::Symlink),
             3 => Ok(FilesystemKind::Socket),
             4 => Ok(FilesystemKind::Fifo),
+            5 => Ok(FilesystemKind::Stream),
             _ => Err(FsEntryError::UnknownFileKindCode(code)),
         }
     }
@@ -326,7 +437,8 @@ impl FilesystemKind {
 
 #[cfg(test)]
 mod test {
-    use super::FilesystemKind;
+    use super::{FilesystemEntry, FilesystemKind};
+    use users::UsersCache;
 
     #[test]
     fn file_kind_regular_round_trips() {
@@ -335,9 +447,46 @@ mod test {
         one_file_kind_round_trip(FilesystemKind::Symlink);
         one_file_kind_round_trip(FilesystemKind::Socket);
         one_file_kind_round_trip(FilesystemKind::Fifo);
+        one_file_kind_round_trip(FilesystemKind::Stream);
     }
 
     fn one_file_kind_round_trip(kind: FilesystemKind) {
         assert_eq!(kind, FilesystemKind::from_code(kind.as_code()).unwrap());
     }
+
+    #[test]
+    fn records_change_and_birth_time() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        std::fs::write(&path, b"").unwrap();
+        let meta = std::fs::symlink_metadata(&path).unwrap();
+        let e = FilesystemEntry::from_metadata(&path, &meta, &mut UsersCache::new()).unwrap();
+        let (secs, nsec) = e.ctime().unwrap();
+        assert!(secs > 0);
+        assert!((0..1_000_000_000).contains(&nsec));
+        if let Ok(created) = meta.created() {
+            let since = created.duration_since(std::time::UNIX_EPOCH).unwrap();
+            assert_eq!(
+                e.btime(),
+                Some((since.as_secs() as i64, since.subsec_nanos() as i64))
+            );
+        } else {
+            assert_eq!(e.btime(), None);
+        }
+    }
+
+    #[test]
+    fn reads_entry_without_change_or_birth_time() {
+        let tmp = tempfile::tempdir().unwrap();
+        let meta = std::fs::symlink_metadata(tmp.path()).unwrap();
+        let e = FilesystemEntry::from_metadata(tmp.path(), &meta, &mut UsersCache::new()).unwrap();
+        let mut json = serde_json::to_value(&e).unwrap();
+        let map = json.as_object_mut().unwrap();
+        assert!(map.remove("ctime").is_some());
+        map.remove("btime");
+        let old: FilesystemEntry = serde_json::from_value(json).unwrap();
+        assert_eq!(old.ctime(), None);
+        assert_eq!(old.btime(), None);
+        assert_eq!(old.mtime(), e.mtime());
+    }
 }
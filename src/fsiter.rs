@@ -1,7 +1,12 @@
 //! Iterate over directory tree.
 
+use crate::config::absolute;
 use crate::fsentry::{FilesystemEntry, FsEntryError};
-use log::warn;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use users::UsersCache;
 use walkdir::{DirEntry, IntoIter, WalkDir};
@@ -14,6 +19,52 @@ pub struct AnnotatedFsEntry {
     pub is_cachedir_tag: bool,
 }
 
+/// When should symbolic links be followed?
+///
+/// A symbolic link that is followed is backed up as what it points
+/// at, rather than as a link. If it points at a directory, the
+/// directory's contents are backed up as well.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FollowSymlinks {
+    /// Never follow symbolic links, not even backup roots.
+    Never,
+
+    /// Follow backup roots that are symbolic links, but not links
+    /// inside them.
+    RootsOnly,
+
+    /// Follow all symbolic links.
+    Always,
+}
+
+impl Default for FollowSymlinks {
+    fn default() -> Self {
+        Self::Never
+    }
+}
+
+/// What to do with directories that contain a CACHEDIR.TAG file?
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CachedirTags {
+    /// Back up the directory and the tag, but nothing else in the
+    /// directory. After a restore, the directory is still tagged.
+    ExcludeContents,
+
+    /// Don't back up the directory at all, not even the tag.
+    ExcludeEntirely,
+
+    /// Back up the directory like any other.
+    IncludeAnyway,
+}
+
+impl Default for CachedirTags {
+    fn default() -> Self {
+        Self::ExcludeContents
+    }
+}
+
 /// Iterator over file system entries in a directory tree.
 pub struct FsIterator {
     iter: SkipCachedirs,
@@ -30,6 +81,16 @@ pub enum FsIterError {
     #[error("failed to get file system metadata for {0}: {1}")]
     Metadata(PathBuf, std::io::Error),
 
+    /// A directory is a mount point for a different file system, and
+    /// its contents were skipped.
+    #[error("skipped contents of {0}: it is on a different file system")]
+    OtherFileSystem(PathBuf),
+
+    /// A directory was reached a second time via symbolic links, and
+    /// was skipped.
+    #[error("skipped {0}: directory has already been backed up via another path")]
+    AlreadyVisited(PathBuf),
+
     /// Error related to file system entries.
     #[error(transparent)]
     FsEntryError(#[from] FsEntryError),
@@ -37,14 +98,45 @@ pub enum FsIterError {
 
 impl FsIterator {
     /// Create a new iterator.
-    pub fn new(root: &Path, exclude_cache_tag_directories: bool) -> Self {
+    ///
+    /// Directories with a CACHEDIR.TAG file are treated as
+    /// `cachedir_tags` says, unless they are under one of the paths
+    /// in `cachedir_allowlist`: those are backed up like any other.
+    ///
+    /// The files and directories in `excluded`, given as absolute
+    /// paths, are skipped entirely, without a trace.
+    ///
+    /// If `one_file_system` is true, the contents of directories on a
+    /// different file system than `root` are skipped, and reported as
+    /// errors. The mount point directories themselves are included.
+    pub fn new(
+        root: &Path,
+        cachedir_tags: CachedirTags,
+        cachedir_allowlist: &[PathBuf],
+        excluded: &[PathBuf],
+        one_file_system: bool,
+        follow_symlinks: FollowSymlinks,
+    ) -> Self {
+        let walkdir = WalkDir::new(root).follow_links(follow_symlinks == FollowSymlinks::Always);
         Self {
             iter: SkipCachedirs::new(
-                WalkDir::new(root).into_iter(),
-                exclude_cache_tag_directories,
+                walkdir.into_iter(),
+                cachedir_tags,
+                cachedir_allowlist.to_vec(),
+                excluded.to_vec(),
+                one_file_system,
+                follow_symlinks,
             ),
         }
     }
+
+    // Use a different way of finding the device of a file, to fake
+    // mount points.
+    #[cfg(test)]
+    fn with_device(mut self, device: fn(&Path, &Metadata) -> u64) -> Self {
+        self.iter.device = device;
+        self
+    }
 }
 
 impl Iterator for FsIterator {
@@ -59,31 +151,107 @@ impl Iterator for FsIterator {
 struct SkipCachedirs {
     cache: UsersCache,
     iter: IntoIter,
-    exclude_cache_tag_directories: bool,
-    // This is the last tag we've found. `next()` will yield it before asking `iter` for more
-    // entries.
-    cachedir_tag: Option<Result<AnnotatedFsEntry, FsIterError>>,
+    cachedir_tags: CachedirTags,
+    cachedir_allowlist: Vec<PathBuf>,
+    excluded: Vec<PathBuf>,
+    one_file_system: bool,
+    follow_symlinks: FollowSymlinks,
+    // Device of the root directory, once we've seen it.
+    root_dev: Option<u64>,
+    // How to find the device a file is on.
+    device: fn(&Path, &Metadata) -> u64,
+    // Device and inode numbers of directories we've seen, when
+    // following symbolic links, to avoid loops.
+    visited: HashSet<(u64, u64)>,
+    // This is the last tag we've found, or the error for the last
+    // mount point we've skipped the contents of. `next()` will yield
+    // it before asking `iter` for more entries.
+    pending: Option<Result<AnnotatedFsEntry, FsIterError>>,
 }
 
 impl SkipCachedirs {
-    fn new(iter: IntoIter, exclude_cache_tag_directories: bool) -> Self {
+    fn new(
+        iter: IntoIter,
+        cachedir_tags: CachedirTags,
+        cachedir_allowlist: Vec<PathBuf>,
+        excluded: Vec<PathBuf>,
+        one_file_system: bool,
+        follow_symlinks: FollowSymlinks,
+    ) -> Self {
         Self {
             cache: UsersCache::new(),
             iter,
-            exclude_cache_tag_directories,
-            cachedir_tag: None,
+            cachedir_tags,
+            cachedir_allowlist,
+            excluded,
+            one_file_system,
+            follow_symlinks,
+            root_dev: None,
+            device: |_, meta| meta.dev(),
+            visited: HashSet::new(),
+            pending: None,
         }
     }
 
-    fn try_enqueue_cachedir_tag(&mut self, entry: &DirEntry) {
-        if !self.exclude_cache_tag_directories {
-            return;
+    // Is the entry one of the excluded files or directories?
+    fn is_excluded(&self, entry: &DirEntry) -> bool {
+        !self.excluded.is_empty() && self.excluded.contains(&absolute(entry.path()))
+    }
+
+    // Should a symbolic link be backed up as what it points at?
+    fn follows(&self, entry: &DirEntry) -> bool {
+        entry.path_is_symlink()
+            && match self.follow_symlinks {
+                FollowSymlinks::Never => false,
+                FollowSymlinks::RootsOnly => entry.depth() == 0,
+                FollowSymlinks::Always => true,
+            }
+    }
+
+    // Is the directory on a different file system than the root? If
+    // so, we skip its contents.
+    fn is_other_file_system(&mut self, path: &Path, meta: &Metadata) -> bool {
+        if !self.one_file_system {
+            return false;
+        }
+        let dev = (self.device)(path, meta);
+        match self.root_dev {
+            None => {
+                self.root_dev = Some(dev);
+                false
+            }
+            Some(root_dev) => root_dev != dev,
+        }
+    }
+
+    // Have we already seen this directory? This can only happen when
+    // following symbolic links.
+    fn is_visited(&mut self, meta: &Metadata) -> bool {
+        if self.follow_symlinks == FollowSymlinks::Never {
+            return false;
+        }
+        !self.visited.insert((meta.dev(), meta.ino()))
+    }
+
+    // If the entry is a cache directory whose contents we should
+    // skip, return the path to its tag.
+    fn cachedir_tag_path(&self, entry: &DirEntry, meta: &Metadata) -> Option<PathBuf> {
+        if self.cachedir_tags == CachedirTags::IncludeAnyway {
+            return None;
         }
 
         // If this entry is not a directory, it means we already processed its
         // parent dir and decided that it's not cached.
-        if !entry.file_type().is_dir() {
-            return;
+        if !meta.is_dir() {
+            return None;
+        }
+
+        if self
+            .cachedir_allowlist
+            .iter()
+            .any(|allowed| entry.path().starts_with(allowed))
+        {
+            return None;
         }
 
         let mut tag_path = entry.path().to_owned();
@@ -91,7 +259,7 @@ impl SkipCachedirs {
 
         // Tags are required to be regular files -- not even symlinks are allowed.
         if !tag_path.is_file() {
-            return;
+            return None;
         };
 
         const CACHEDIR_TAG: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
@@ -100,19 +268,20 @@ impl SkipCachedirs {
         let mut file = if let Ok(file) = std::fs::File::open(&tag_path) {
             file
         } else {
-            return;
+            return None;
         };
 
         use std::io::Read;
         match file.read_exact(&mut content) {
             Ok(_) => (),
             // If we can't read the tag file, proceed as if's not there
-            Err(_) => return,
+            Err(_) => return None,
         }
 
         if content == CACHEDIR_TAG {
-            self.iter.skip_current_dir();
-            self.cachedir_tag = Some(new_entry(&tag_path, true, &mut self.cache));
+            Some(tag_path)
+        } else {
+            None
         }
     }
 }
@@ -121,37 +290,348 @@ impl Iterator for SkipCachedirs {
     type Item = Result<AnnotatedFsEntry, FsIterError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.cachedir_tag.take().or_else(|| {
-            let next = self.iter.next();
-            match next {
-                None => None,
-                Some(Err(err)) => Some(Err(FsIterError::WalkDir(err))),
-                Some(Ok(entry)) => {
-                    self.try_enqueue_cachedir_tag(&entry);
-                    Some(new_entry(entry.path(), false, &mut self.cache))
+        if let Some(pending) = self.pending.take() {
+            return Some(pending);
+        }
+        loop {
+            let entry = match self.iter.next()? {
+                Err(err) => return Some(Err(FsIterError::WalkDir(err))),
+                Ok(entry) => entry,
+            };
+            if self.is_excluded(&entry) {
+                info!("skipping Obnam's own file {}", entry.path().display());
+                if entry.file_type().is_dir() {
+                    self.iter.skip_current_dir();
                 }
+                continue;
             }
-        })
+            let meta = match entry_metadata(entry.path(), self.follows(&entry)) {
+                Ok(meta) => meta,
+                Err(err) => return Some(Err(err)),
+            };
+            if meta.is_dir() {
+                if self.is_other_file_system(entry.path(), &meta) {
+                    info!(
+                        "skipping contents of mount point {}",
+                        entry.path().display()
+                    );
+                    self.iter.skip_current_dir();
+                    self.pending = Some(Err(FsIterError::OtherFileSystem(
+                        entry.path().to_path_buf(),
+                    )));
+                    return Some(annotate(entry.path(), &meta, false, &mut self.cache));
+                }
+                if self.is_visited(&meta) {
+                    info!("skipping already visited {}", entry.path().display());
+                    self.iter.skip_current_dir();
+                    return Some(Err(FsIterError::AlreadyVisited(entry.path().to_path_buf())));
+                }
+            }
+            if entry.depth() == 0 && entry.path_is_symlink() && !self.follows(&entry) {
+                // The walkdir crate always descends into a
+                // root that is a symbolic link to a directory.
+                self.iter.skip_current_dir();
+            }
+            if let Some(tag_path) = self.cachedir_tag_path(&entry, &meta) {
+                self.iter.skip_current_dir();
+                if self.cachedir_tags == CachedirTags::ExcludeEntirely {
+                    info!("skipping cache directory {}", entry.path().display());
+                    continue;
+                }
+                self.pending = Some(new_entry(&tag_path, true, &mut self.cache));
+            }
+            return Some(annotate(entry.path(), &meta, false, &mut self.cache));
+        }
     }
 }
 
+// Get metadata for a path, for the link itself, or for what it points
+// at.
+fn entry_metadata(path: &Path, follow: bool) -> Result<Metadata, FsIterError> {
+    let meta = if follow {
+        std::fs::metadata(path)
+    } else {
+        std::fs::symlink_metadata(path)
+    };
+    meta.map_err(|err| {
+        warn!("failed to get metadata for {}: {}", path.display(), err);
+        FsIterError::Metadata(path.to_path_buf(), err)
+    })
+}
+
 fn new_entry(
     path: &Path,
     is_cachedir_tag: bool,
     cache: &mut UsersCache,
 ) -> Result<AnnotatedFsEntry, FsIterError> {
-    let meta = std::fs::symlink_metadata(path);
-    let meta = match meta {
-        Ok(meta) => meta,
-        Err(err) => {
-            warn!("failed to get metadata for {}: {}", path.display(), err);
-            return Err(FsIterError::Metadata(path.to_path_buf(), err));
-        }
-    };
-    let entry = FilesystemEntry::from_metadata(path, &meta, cache)?;
+    let meta = entry_metadata(path, false)?;
+    annotate(path, &meta, is_cachedir_tag, cache)
+}
+
+fn annotate(
+    path: &Path,
+    meta: &Metadata,
+    is_cachedir_tag: bool,
+    cache: &mut UsersCache,
+) -> Result<AnnotatedFsEntry, FsIterError> {
+    let entry = FilesystemEntry::from_metadata(path, meta, cache)?;
     let annotated = AnnotatedFsEntry {
         inner: entry,
         is_cachedir_tag,
     };
     Ok(annotated)
 }
+
+#[cfg(test)]
+mod test {
+    use super::{CachedirTags, FollowSymlinks, FsIterError, FsIterator};
+    use crate::fsentry::FilesystemKind;
+    use std::fs::Metadata;
+    use std::os::unix::fs::{symlink, MetadataExt};
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    const CACHEDIR_TAG: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+    // Return kinds of entries found, and number of errors.
+    fn walk(root: &Path, follow: FollowSymlinks) -> (Vec<FilesystemKind>, usize) {
+        let mut kinds = vec![];
+        let mut errors = 0;
+        for e in FsIterator::new(root, CachedirTags::IncludeAnyway, &[], &[], false, follow) {
+            match e {
+                Ok(e) => kinds.push(e.inner.kind()),
+                Err(_) => errors += 1,
+            }
+        }
+        (kinds, errors)
+    }
+
+    #[test]
+    fn does_not_follow_root_symlink_by_default() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("dir");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let link = tmp.path().join("link");
+        symlink(&dir, &link).unwrap();
+
+        let (kinds, errors) = walk(&link, FollowSymlinks::Never);
+        assert_eq!(kinds, vec![FilesystemKind::Symlink]);
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn follows_root_symlink_only() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("dir");
+        std::fs::create_dir(&dir).unwrap();
+        symlink(&dir, dir.join("inner")).unwrap();
+        let link = tmp.path().join("link");
+        symlink(&dir, &link).unwrap();
+
+        let (kinds, errors) = walk(&link, FollowSymlinks::RootsOnly);
+        assert_eq!(
+            kinds,
+            vec![FilesystemKind::Directory, FilesystemKind::Symlink]
+        );
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn follows_all_symlinks_without_looping() {
+        let tmp = tempdir().unwrap();
+        let a = tmp.path().join("a");
+        let b = tmp.path().join("b");
+        std::fs::create_dir(&a).unwrap();
+        std::fs::create_dir(&b).unwrap();
+        std::fs::write(b.join("file"), b"").unwrap();
+        symlink(&b, a.join("to_b")).unwrap();
+        symlink(&b, a.join("also_to_b")).unwrap();
+        symlink(&a, b.join("back_to_a")).unwrap();
+
+        let (kinds, errors) = walk(&a, FollowSymlinks::Always);
+        let dirs = kinds
+            .iter()
+            .filter(|k| **k == FilesystemKind::Directory)
+            .count();
+        assert_eq!(dirs, 2);
+        assert_eq!(errors, 2);
+    }
+
+    // Return paths of entries found, relative to the root, and
+    // which of them are tags.
+    fn walk_cachedirs(
+        root: &Path,
+        mode: CachedirTags,
+        allowlist: &[PathBuf],
+    ) -> Vec<(PathBuf, bool)> {
+        let mut found: Vec<_> =
+            FsIterator::new(root, mode, allowlist, &[], false, FollowSymlinks::Never)
+                .map(|e| {
+                    let e = e.unwrap();
+                    let path = e.inner.pathbuf();
+                    (
+                        path.strip_prefix(root).unwrap().to_path_buf(),
+                        e.is_cachedir_tag,
+                    )
+                })
+                .collect();
+        found.sort();
+        found
+    }
+
+    fn cachedirs() -> tempfile::TempDir {
+        let tmp = tempdir().unwrap();
+        for dir in ["a", "b"] {
+            let dir = tmp.path().join(dir);
+            std::fs::create_dir(&dir).unwrap();
+            std::fs::write(dir.join("CACHEDIR.TAG"), CACHEDIR_TAG).unwrap();
+            std::fs::write(dir.join("data"), b"").unwrap();
+        }
+        tmp
+    }
+
+    #[test]
+    fn excludes_cachedir_contents_by_default() {
+        let tmp = cachedirs();
+        let found = walk_cachedirs(tmp.path(), CachedirTags::default(), &[]);
+        assert_eq!(
+            found,
+            vec![
+                (PathBuf::from(""), false),
+                (PathBuf::from("a"), false),
+                (PathBuf::from("a/CACHEDIR.TAG"), true),
+                (PathBuf::from("b"), false),
+                (PathBuf::from("b/CACHEDIR.TAG"), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn excludes_cachedirs_entirely() {
+        let tmp = cachedirs();
+        let found = walk_cachedirs(tmp.path(), CachedirTags::ExcludeEntirely, &[]);
+        assert_eq!(found, vec![(PathBuf::from(""), false)]);
+    }
+
+    #[test]
+    fn includes_cachedirs_anyway() {
+        let tmp = cachedirs();
+        let found = walk_cachedirs(tmp.path(), CachedirTags::IncludeAnyway, &[]);
+        assert_eq!(found.len(), 7);
+        assert!(found.iter().all(|(_, is_tag)| !is_tag));
+    }
+
+    #[test]
+    fn includes_allowed_cachedirs() {
+        let tmp = cachedirs();
+        let allowed = vec![tmp.path().join("b")];
+        let found = walk_cachedirs(tmp.path(), CachedirTags::ExcludeEntirely, &allowed);
+        assert_eq!(
+            found,
+            vec![
+                (PathBuf::from(""), false),
+                (PathBuf::from("b"), false),
+                (PathBuf::from("b/CACHEDIR.TAG"), false),
+                (PathBuf::from("b/data"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_excluded_files_and_directories() {
+        let tmp = cachedirs();
+        let excluded = vec![tmp.path().join("a"), tmp.path().join("b/data")];
+        let mut found: Vec<_> = FsIterator::new(
+            tmp.path(),
+            CachedirTags::IncludeAnyway,
+            &[],
+            &excluded,
+            false,
+            FollowSymlinks::Never,
+        )
+        .map(|e| {
+            let path = e.unwrap().inner.pathbuf();
+            path.strip_prefix(tmp.path()).unwrap().to_path_buf()
+        })
+        .collect();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                PathBuf::from(""),
+                PathBuf::from("b"),
+                PathBuf::from("b/CACHEDIR.TAG"),
+            ]
+        );
+    }
+
+    // Pretend that anything under a directory called "mnt" is on
+    // another file system.
+    fn fake_device(path: &Path, meta: &Metadata) -> u64 {
+        if path.components().any(|c| c.as_os_str() == "mnt") {
+            meta.dev() + 1
+        } else {
+            meta.dev()
+        }
+    }
+
+    #[test]
+    fn lists_mount_point_but_not_its_contents() {
+        let tmp = tempdir().unwrap();
+        std::fs::write(tmp.path().join("data"), b"").unwrap();
+        let mnt = tmp.path().join("mnt");
+        std::fs::create_dir_all(mnt.join("dir")).unwrap();
+        std::fs::write(mnt.join("dir/data"), b"").unwrap();
+
+        let mut found = vec![];
+        let mut skipped = vec![];
+        let iter = FsIterator::new(
+            tmp.path(),
+            CachedirTags::IncludeAnyway,
+            &[],
+            &[],
+            true,
+            FollowSymlinks::Never,
+        )
+        .with_device(fake_device);
+        for e in iter {
+            match e {
+                Ok(e) => {
+                    let path = e.inner.pathbuf();
+                    found.push(path.strip_prefix(tmp.path()).unwrap().to_path_buf());
+                }
+                Err(FsIterError::OtherFileSystem(path)) => skipped.push(path),
+                Err(err) => panic!("unexpected error: {}", err),
+            }
+        }
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                PathBuf::from(""),
+                PathBuf::from("data"),
+                PathBuf::from("mnt")
+            ]
+        );
+        assert_eq!(skipped, vec![mnt]);
+    }
+
+    #[test]
+    fn crosses_mount_points_by_default() {
+        let tmp = tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("mnt")).unwrap();
+        std::fs::write(tmp.path().join("mnt/data"), b"").unwrap();
+        let iter = FsIterator::new(
+            tmp.path(),
+            CachedirTags::IncludeAnyway,
+            &[],
+            &[],
+            false,
+            FollowSymlinks::Never,
+        )
+        .with_device(fake_device);
+        let found: Result<Vec<_>, _> = iter.collect();
+        assert_eq!(found.unwrap().len(), 3);
+    }
+}
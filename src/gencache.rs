@@ -0,0 +1,291 @@
+//! Local cache of backup generation metadata.
+//!
+//! The metadata of a backup generation is an SQLite database, which
+//! is uploaded to the server in chunks. For a large backup, the
+//! database is large, and downloading it every time the previous
+//! backup is needed is slow. Instead, downloaded databases are kept in
+//! a local cache directory, and reused if the same generation is
+//! needed again.
+//!
+//! The cached databases contain the names and metadata of all backed
+//! up files, so they're encrypted with the client's encryption key,
+//! the same way chunks are before they're uploaded.
+
+use crate::chunk::DataChunk;
+use crate::chunkmeta::ChunkMeta;
+use crate::cipher::{max_encrypted_len, CipherEngine, CipherError};
+use crate::generation::GenId;
+use crate::label::Label;
+use log::{debug, info};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+// Start of a cache file, to recognize the format.
+const MAGIC: &[u8] = b"obnam-generation-cache-v1\n";
+
+// The database is encrypted in pieces of this size, so that it doesn't
+// need to be in memory all at once.
+const PIECE_SIZE: usize = 1024 * 1024;
+
+// How many generations are kept in the cache. When more are added,
+// the oldest ones are removed.
+const MAX_CACHED_GENERATIONS: usize = 10;
+
+/// A cache of generation databases.
+#[derive(Debug, Clone)]
+pub struct GenerationCache {
+    dir: PathBuf,
+}
+
+/// Possible errors from using the generation cache.
+#[derive(Debug, thiserror::Error)]
+pub enum GenerationCacheError {
+    /// Couldn't read or write a file.
+    #[error("generation cache: failed to use {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    /// A cache file isn't in the expected format.
+    #[error("generation cache: {0} is not a valid cache file")]
+    Malformed(PathBuf),
+
+    /// A cache file couldn't be encrypted or decrypted.
+    #[error("generation cache: {0}: {1}")]
+    Cipher(PathBuf, CipherError),
+}
+
+impl GenerationCache {
+    /// Use a cache in a directory. The directory is created when
+    /// something is first put in the cache.
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn filename(&self, gen_id: &GenId) -> PathBuf {
+        self.dir.join(format!("{}.db.enc", gen_id))
+    }
+
+    /// Copy a generation's database from the cache into a file.
+    ///
+    /// Return false, without creating the file, if the generation
+    /// isn't in the cache.
+    pub fn get(
+        &self,
+        cipher: &CipherEngine,
+        gen_id: &GenId,
+        dbname: &Path,
+    ) -> Result<bool, GenerationCacheError> {
+        let filename = self.filename(gen_id);
+        let io = |err| GenerationCacheError::Io(filename.clone(), err);
+        let malformed = || GenerationCacheError::Malformed(filename.clone());
+
+        let file = match File::open(&filename) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(io(err)),
+        };
+        let mut file = BufReader::new(file);
+
+        let mut magic = vec![0; MAGIC.len()];
+        file.read_exact(&mut magic).map_err(|_| malformed())?;
+        if magic != MAGIC {
+            return Err(malformed());
+        }
+        let count = read_u64(&mut file).map_err(|_| malformed())?;
+
+        let mut output = File::create(dbname)
+            .map_err(|err| GenerationCacheError::Io(dbname.to_path_buf(), err))?;
+        for i in 0..count {
+            // A corrupt length mustn't make us allocate a huge buffer.
+            let len = read_u64(&mut file).map_err(|_| malformed())?;
+            if len > max_encrypted_len(PIECE_SIZE) as u64 {
+                return Err(malformed());
+            }
+            let mut ciphertext = vec![0; len as usize];
+            file.read_exact(&mut ciphertext).map_err(|_| malformed())?;
+            let meta = piece_meta(gen_id, i, count).to_json_vec();
+            let piece = cipher
+                .decrypt_chunk(&ciphertext, &meta)
+                .map_err(|err| GenerationCacheError::Cipher(filename.clone(), err))?;
+            output
+                .write_all(piece.data())
+                .map_err(|err| GenerationCacheError::Io(dbname.to_path_buf(), err))?;
+        }
+
+        info!(
+            "using cached generation {} from {}",
+            gen_id,
+            filename.display()
+        );
+        Ok(true)
+    }
+
+    /// Put a generation's database into the cache.
+    pub fn put(
+        &self,
+        cipher: &CipherEngine,
+        gen_id: &GenId,
+        dbname: &Path,
+    ) -> Result<(), GenerationCacheError> {
+        let io = |err| GenerationCacheError::Io(self.dir.clone(), err);
+        std::fs::create_dir_all(&self.dir).map_err(io)?;
+
+        let db_io = |err| GenerationCacheError::Io(dbname.to_path_buf(), err);
+        let size = std::fs::metadata(dbname).map_err(db_io)?.len();
+        let count = (size + PIECE_SIZE as u64 - 1) / PIECE_SIZE as u64;
+        let mut input = File::open(dbname).map_err(db_io)?;
+
+        let temp = NamedTempFile::new_in(&self.dir).map_err(io)?;
+        let mut output = BufWriter::new(temp);
+        output.write_all(MAGIC).map_err(io)?;
+        output.write_all(&count.to_le_bytes()).map_err(io)?;
+        for i in 0..count {
+            let mut data = vec![];
+            (&mut input)
+                .take(PIECE_SIZE as u64)
+                .read_to_end(&mut data)
+                .map_err(db_io)?;
+            let chunk = DataChunk::new(data, piece_meta(gen_id, i, count));
+            let enc = cipher
+                .encrypt_chunk(&chunk)
+                .map_err(|err| GenerationCacheError::Cipher(dbname.to_path_buf(), err))?;
+            let ciphertext = enc.ciphertext();
+            output
+                .write_all(&(ciphertext.len() as u64).to_le_bytes())
+                .map_err(io)?;
+            output.write_all(ciphertext).map_err(io)?;
+        }
+
+        let temp = output.into_inner().map_err(|err| io(err.into_error()))?;
+        let filename = self.filename(gen_id);
+        temp.persist(&filename)
+            .map_err(|err| GenerationCacheError::Io(filename.clone(), err.error))?;
+        debug!("cached generation {} in {}", gen_id, filename.display());
+
+        self.prune()
+    }
+
+    /// Remove a generation from the cache, if it's there.
+    pub fn remove(&self, gen_id: &GenId) -> Result<(), GenerationCacheError> {
+        let filename = self.filename(gen_id);
+        match std::fs::remove_file(&filename) {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(GenerationCacheError::Io(filename, err)),
+        }
+    }
+
+    // Remove the oldest cached generations, if there are too many.
+    fn prune(&self) -> Result<(), GenerationCacheError> {
+        let io = |err| GenerationCacheError::Io(self.dir.clone(), err);
+        let mut cached = vec![];
+        for entry in std::fs::read_dir(&self.dir).map_err(io)? {
+            let entry = entry.map_err(io)?;
+            let name = entry.file_name();
+            if name.to_string_lossy().ends_with(".db.enc") {
+                let modified = entry.metadata().and_then(|m| m.modified()).map_err(io)?;
+                cached.push((modified, entry.path()));
+            }
+        }
+        cached.sort();
+        while cached.len() > MAX_CACHED_GENERATIONS {
+            let (_, filename) = cached.remove(0);
+            debug!("removing old cached generation {}", filename.display());
+            std::fs::remove_file(&filename)
+                .map_err(|err| GenerationCacheError::Io(filename, err))?;
+        }
+        Ok(())
+    }
+}
+
+// Metadata used as associated data when encrypting a piece of a
+// cached database. It ties the piece to its place in the database of a
+// specific generation, so pieces can't be swapped or left out.
+fn piece_meta(gen_id: &GenId, i: u64, count: u64) -> ChunkMeta {
+    ChunkMeta::new(&Label::literal(&format!(
+        "generation-cache:{}:{}/{}",
+        gen_id, i, count
+    )))
+}
+
+fn read_u64(file: &mut dyn Read) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    file.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{GenerationCache, GenerationCacheError, MAGIC};
+    use crate::chunkid::ChunkId;
+    use crate::cipher::CipherEngine;
+    use crate::generation::GenId;
+    use crate::passwords::Passwords;
+    use tempfile::tempdir;
+
+    fn gen_id(id: &str) -> GenId {
+        GenId::from_chunk_id(ChunkId::recreate(id))
+    }
+
+    #[test]
+    fn roundtrips_database() {
+        let tmp = tempdir().unwrap();
+        let cache = GenerationCache::new(&tmp.path().join("cache"));
+        let cipher = CipherEngine::new(&Passwords::new("hunter2"));
+        let db = tmp.path().join("gen.db");
+        let data: Vec<u8> = (0..3_000_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&db, &data).unwrap();
+
+        let out = tmp.path().join("out.db");
+        assert!(!cache.get(&cipher, &gen_id("gen1"), &out).unwrap());
+        cache.put(&cipher, &gen_id("gen1"), &db).unwrap();
+        assert!(cache.get(&cipher, &gen_id("gen1"), &out).unwrap());
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_other_generation() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        let cache = GenerationCache::new(&dir);
+        let cipher = CipherEngine::new(&Passwords::new("hunter2"));
+        let db = tmp.path().join("gen.db");
+        std::fs::write(&db, b"hello").unwrap();
+        cache.put(&cipher, &gen_id("gen1"), &db).unwrap();
+        std::fs::rename(dir.join("gen1.db.enc"), dir.join("gen2.db.enc")).unwrap();
+
+        let out = tmp.path().join("out.db");
+        assert!(matches!(
+            cache.get(&cipher, &gen_id("gen2"), &out),
+            Err(GenerationCacheError::Cipher(_, _))
+        ));
+    }
+
+    #[test]
+    fn rejects_huge_piece_length() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        let cache = GenerationCache::new(&dir);
+        let cipher = CipherEngine::new(&Passwords::new("hunter2"));
+        let db = tmp.path().join("gen.db");
+        std::fs::write(&db, b"hello").unwrap();
+        cache.put(&cipher, &gen_id("gen1"), &db).unwrap();
+
+        // Replace the length of the first piece, after the magic and
+        // the piece count.
+        let filename = dir.join("gen1.db.enc");
+        let mut bytes = std::fs::read(&filename).unwrap();
+        let offset = MAGIC.len() + 8;
+        bytes[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&filename, bytes).unwrap();
+
+        let out = tmp.path().join("out.db");
+        assert!(matches!(
+            cache.get(&cipher, &gen_id("gen1"), &out),
+            Err(GenerationCacheError::Malformed(_))
+        ));
+    }
+}
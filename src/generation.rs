@@ -3,17 +3,28 @@
 use crate::backup_reason::Reason;
 use crate::chunkid::ChunkId;
 use crate::db::{DatabaseError, SqlResults};
-use crate::dbgen::{FileId, GenerationDb, GenerationDbError};
-use crate::fsentry::FilesystemEntry;
-use crate::genmeta::{GenerationMeta, GenerationMetaError};
+use crate::dbgen::{
+    FileChunk, FileId, GenerationDb, GenerationDbError, GenerationStats, INCREMENTAL_SCHEMA_MAJOR,
+};
+use crate::fsentry::{FilesystemEntry, FilesystemKind};
+use crate::genmeta::{self, Feature, GenerationMeta, GenerationMetaError};
 use crate::label::LabelChecksumKind;
 use crate::schema::{SchemaVersion, VersionComponent};
+use log::debug;
 use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Longest chain of incremental generations that a new incremental
+/// generation is based on. When the previous generation's chain is
+/// this long, the new generation stores all files instead, so that
+/// looking up files doesn't get slower and slower.
+pub const MAX_CHAIN_LENGTH: usize = 10;
 
 /// An identifier for a generation.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct GenId {
     id: ChunkId,
 }
@@ -46,7 +57,15 @@ impl fmt::Display for GenId {
 /// of its generation chunk.
 pub struct NascentGeneration {
     db: GenerationDb,
+    schema: SchemaVersion,
     fileno: FileId,
+    count: FileId,
+    file_bytes: u64,
+    has_streams: bool,
+    // For an incremental generation, the files stored in it or carried
+    // over from the parent, for finding files that have been deleted
+    // since the parent.
+    seen: Option<HashSet<PathBuf>>,
 }
 
 /// Possible errors from nascent backup generations.
@@ -60,6 +79,10 @@ pub enum NascentError {
     #[error(transparent)]
     LocalGenerationError(#[from] LocalGenerationError),
 
+    /// Error making a snapshot of a backup root.
+    #[error("Could not make a snapshot of backup root {0}: {1}")]
+    Snapshot(PathBuf, crate::snapshot::SnapshotError),
+
     /// Error from a GenerationDb.
     #[error(transparent)]
     GenerationDb(#[from] GenerationDbError),
@@ -88,17 +111,134 @@ impl NascentGeneration {
         P: AsRef<Path>,
     {
         let db = GenerationDb::create(filename.as_ref(), schema, checksum_kind)?;
-        Ok(Self { db, fileno: 0 })
+        Ok(Self::new(db, schema))
+    }
+
+    /// Create a new nascent generation that only exists in memory.
+    ///
+    /// Nothing is written to disk. Use
+    /// [`NascentGeneration::finish`] to look at the result.
+    pub fn in_memory(
+        schema: SchemaVersion,
+        checksum_kind: LabelChecksumKind,
+    ) -> Result<Self, NascentError> {
+        let db = GenerationDb::memory(schema, checksum_kind)?;
+        Ok(Self::new(db, schema))
+    }
+
+    fn new(db: GenerationDb, schema: SchemaVersion) -> Self {
+        Self {
+            db,
+            schema,
+            fileno: 0,
+            count: 0,
+            file_bytes: 0,
+            has_streams: false,
+            seen: None,
+        }
+    }
+
+    /// Create a new nascent generation that only stores files that
+    /// have changed since a parent generation.
+    ///
+    /// The schema must be one that supports incremental generations.
+    pub fn create_incremental<P>(
+        filename: P,
+        schema: SchemaVersion,
+        checksum_kind: LabelChecksumKind,
+        parent_id: &GenId,
+        parent: &LocalGeneration,
+    ) -> Result<Self, NascentError>
+    where
+        P: AsRef<Path>,
+    {
+        Self::check_incremental(schema)?;
+        Self::create(filename, schema, checksum_kind)?.incremental(parent_id, parent)
+    }
+
+    /// Create a new incremental nascent generation that only exists
+    /// in memory.
+    pub fn in_memory_incremental(
+        schema: SchemaVersion,
+        checksum_kind: LabelChecksumKind,
+        parent_id: &GenId,
+        parent: &LocalGeneration,
+    ) -> Result<Self, NascentError> {
+        Self::check_incremental(schema)?;
+        Self::in_memory(schema, checksum_kind)?.incremental(parent_id, parent)
+    }
+
+    fn check_incremental(schema: SchemaVersion) -> Result<(), NascentError> {
+        if schema.major != INCREMENTAL_SCHEMA_MAJOR {
+            return Err(GenerationDbError::NotIncremental(schema.major).into());
+        }
+        Ok(())
+    }
+
+    fn incremental(
+        mut self,
+        parent_id: &GenId,
+        parent: &LocalGeneration,
+    ) -> Result<Self, NascentError> {
+        let first = parent.next_fileno()?;
+        self.set_meta(genmeta::PARENT, &parent_id.to_string())?;
+        self.set_meta(genmeta::FIRST_FILENO, &format!("{}", first))?;
+        self.fileno = first - 1;
+        self.seen = Some(HashSet::new());
+        Ok(self)
     }
 
     /// Commit any changes, and close the database.
-    pub fn close(self) -> Result<(), NascentError> {
+    pub fn close(mut self) -> Result<(), NascentError> {
+        self.record_last_fileno()?;
         self.db.close().map_err(NascentError::GenerationDb)
     }
 
+    /// Commit any changes, and turn the nascent generation into a
+    /// local one, without closing the database.
+    ///
+    /// This is how a generation created with
+    /// [`NascentGeneration::in_memory`] can be read.
+    pub fn finish(mut self) -> Result<LocalGeneration, NascentError> {
+        self.record_last_fileno()?;
+        self.db.commit()?;
+        Ok(LocalGeneration::new(self.db)?)
+    }
+
+    fn record_last_fileno(&mut self) -> Result<(), NascentError> {
+        if self.schema.major == INCREMENTAL_SCHEMA_MAJOR {
+            self.db
+                .set_meta(genmeta::LAST_FILENO, &format!("{}", self.fileno))?;
+        }
+        Ok(())
+    }
+
     /// How many files are there now in the nascent generation?
     pub fn file_count(&self) -> FileId {
-        self.fileno
+        self.count
+    }
+
+    /// How many bytes of regular file content are there now in the
+    /// nascent generation?
+    pub fn file_bytes(&self) -> u64 {
+        self.file_bytes
+    }
+
+    /// The schema of the nascent generation.
+    pub fn schema(&self) -> SchemaVersion {
+        self.schema
+    }
+
+    /// Are there any streams in the nascent generation, including
+    /// ones kept from the parent generation?
+    pub fn has_streams(&self) -> bool {
+        self.has_streams
+    }
+
+    /// Add a key/value pair to the generation's metadata.
+    pub fn set_meta(&mut self, key: &str, value: &str) -> Result<(), NascentError> {
+        self.db.set_meta(key, value)?;
+        Ok(())
     }
 
     /// Insert a new file system entry into a nascent generation.
@@ -108,12 +248,209 @@ impl NascentGeneration {
         ids: &[ChunkId],
         reason: Reason,
         is_cachedir_tag: bool,
+    ) -> Result<(), NascentError> {
+        self.insert_with_error(e, ids, &[], &[], reason, is_cachedir_tag, None)
+    }
+
+    /// Insert a new file system entry into a nascent generation,
+    /// with the lengths and labels of its chunks, if known, and why
+    /// its content couldn't be backed up, if it couldn't.
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert_with_error(
+        &mut self,
+        e: FilesystemEntry,
+        ids: &[ChunkId],
+        lengths: &[u64],
+        labels: &[String],
+        reason: Reason,
+        is_cachedir_tag: bool,
+        error: Option<&str>,
     ) -> Result<(), NascentError> {
         self.fileno += 1;
-        self.db
-            .insert(e, self.fileno, ids, reason, is_cachedir_tag)?;
+        self.count(&e);
+        self.db.insert(
+            e,
+            self.fileno,
+            ids,
+            lengths,
+            labels,
+            reason,
+            is_cachedir_tag,
+            error,
+        )?;
+        Ok(())
+    }
+
+    /// Insert a file system entry, unless this is an incremental
+    /// generation and the entry is unchanged since the parent
+    /// generation. An unchanged entry is only counted.
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert_or_keep(
+        &mut self,
+        parent: &LocalGeneration,
+        e: FilesystemEntry,
+        ids: &[ChunkId],
+        lengths: &[u64],
+        labels: &[String],
+        reason: Reason,
+        is_cachedir_tag: bool,
+        error: Option<&str>,
+    ) -> Result<(), NascentError> {
+        if self.seen.is_some() && matches!(reason, Reason::Unchanged) {
+            let path = e.pathbuf();
+            if parent.get_file(&path)?.as_ref() == Some(&e)
+                && parent.is_cachedir_tag(&path)? == is_cachedir_tag
+            {
+                self.count(&e);
+                return Ok(());
+            }
+        }
+        self.insert_with_error(e, ids, lengths, labels, reason, is_cachedir_tag, error)
+    }
+
+    /// Record which files in the parent generation are no longer in
+    /// an incremental generation. This must be done after all files
+    /// have been inserted. For other generations, it does nothing.
+    pub fn record_deletions(&mut self, parent: &LocalGeneration) -> Result<(), NascentError> {
+        if let Some(seen) = &self.seen {
+            for file in parent.files()?.iter()? {
+                let (_, e, _, _) = file?;
+                let path = e.pathbuf();
+                if !seen.contains(&path) {
+                    self.db.delete(&path)?;
+                }
+            }
+        }
         Ok(())
     }
+
+    /// Mark new regular files that have the same content as a file
+    /// that's in the parent generation, but not in this one, as
+    /// renamed from that file. This must be done after all files have
+    /// been inserted. Return the number of renamed files.
+    ///
+    /// Files are compared by the labels of their chunks, which
+    /// depend only on their content, so a file matches even if its
+    /// chunks have been uploaded again. Files whose labels aren't
+    /// recorded are never matched. If more than one deleted file has
+    /// the same content as a new file, one with the same file name is
+    /// preferred, so that the files of a moved directory are matched
+    /// with their old selves.
+    pub fn detect_renames(&mut self, parent: &LocalGeneration) -> Result<usize, NascentError> {
+        let mut new_files = vec![];
+        for file in self
+            .db
+            .files()?
+            .iter()
+            .map_err(LocalGenerationError::from)?
+        {
+            let (fileno, e, reason, _) = file.map_err(LocalGenerationError::from)?;
+            if matches!(reason, Reason::IsNew) && e.kind() == FilesystemKind::Regular {
+                new_files.push((fileno, e.pathbuf()));
+            }
+        }
+        if new_files.is_empty() {
+            return Ok(0);
+        }
+
+        let mut deleted: HashMap<Vec<String>, Vec<PathBuf>> = HashMap::new();
+        for file in parent.files()?.iter()? {
+            let (fileno, e, _, _) = file?;
+            let path = e.pathbuf();
+            if e.kind() != FilesystemKind::Regular || self.contains(&path)? {
+                continue;
+            }
+            let labels = parent.chunk_labels(fileno)?;
+            if !labels.is_empty() {
+                deleted.entry(labels).or_default().push(path);
+            }
+        }
+
+        let mut renamed = 0;
+        for (fileno, path) in new_files {
+            let labels = collect_labels(self.db.file_chunks(fileno)?)?;
+            let candidates = match deleted.get_mut(&labels) {
+                Some(candidates) if !labels.is_empty() && !candidates.is_empty() => candidates,
+                _ => continue,
+            };
+            let i = candidates
+                .iter()
+                .position(|from| from.file_name() == path.file_name())
+                .unwrap_or(0);
+            let from = candidates.remove(i);
+            debug!("{} was renamed from {}", path.display(), from.display());
+            self.db.set_reason(fileno, &Reason::Renamed(from))?;
+            renamed += 1;
+        }
+        Ok(renamed)
+    }
+
+    // Is there a file with this name in the generation?
+    fn contains(&self, path: &Path) -> Result<bool, NascentError> {
+        match &self.seen {
+            Some(seen) => Ok(seen.contains(path)),
+            None => Ok(self.db.get_file(path)?.is_some()),
+        }
+    }
+
+    /// Keep the entries of a parent generation that `wanted` accepts,
+    /// unless an entry with the same name has already been inserted.
+    ///
+    /// Streams aren't in the file system, so a backup of files keeps
+    /// them this way. In an incremental generation, the entries are
+    /// already there via the parent, so they're only counted.
+    pub fn keep_from<F>(&mut self, parent: &LocalGeneration, wanted: F) -> Result<(), NascentError>
+    where
+        F: Fn(&FilesystemEntry) -> bool,
+    {
+        for file in parent.files()?.iter()? {
+            let (fileno, e, reason, is_cachedir_tag) = file?;
+            if !wanted(&e) {
+                continue;
+            }
+            let path = e.pathbuf();
+            match &self.seen {
+                Some(seen) => {
+                    if !seen.contains(&path) {
+                        self.count(&e);
+                    }
+                }
+                None => {
+                    if self.db.get_file(&path)?.is_none() {
+                        let (ids, lengths) = parent.chunk_ids_and_lengths(fileno)?;
+                        let labels = parent.chunk_labels(fileno)?;
+                        let (reason, error) = match reason {
+                            Reason::FileError => (Reason::FileError, parent.file_error(&path)?),
+                            _ => (Reason::Unchanged, None),
+                        };
+                        self.insert_with_error(
+                            e,
+                            &ids,
+                            &lengths,
+                            &labels,
+                            reason,
+                            is_cachedir_tag,
+                            error.as_deref(),
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn count(&mut self, e: &FilesystemEntry) {
+        self.count += 1;
+        if e.kind().has_content() {
+            self.file_bytes += e.len();
+        }
+        if e.kind() == FilesystemKind::Stream {
+            self.has_streams = true;
+        }
+        if let Some(seen) = &mut self.seen {
+            seen.insert(e.pathbuf());
+        }
+    }
 }
 
 /// A finished generation on the server.
@@ -151,8 +488,39 @@ impl FinishedGeneration {
 ///
 /// This is for querying an existing generation, and other read-only
 /// operations.
+///
+/// An incremental generation only stores the files that changed since
+/// its parent generation. Once its parents have been added with
+/// [`LocalGeneration::add_parent`], lookups go through the whole chain
+/// of generations, so that it looks like a generation with all files.
 pub struct LocalGeneration {
+    // The generation itself first, then its parent, and so on.
+    layers: Vec<Layer>,
+}
+
+struct Layer {
     db: GenerationDb,
+    parent: Option<GenId>,
+    first_fileno: FileId,
+    // Directory holding the database, if it needs to be removed
+    // with the generation. This is dropped after the database.
+    _dir: Option<TempDir>,
+}
+
+impl Layer {
+    fn new(db: GenerationDb) -> Result<Self, LocalGenerationError> {
+        let meta = GenerationMeta::from(db.meta()?)?;
+        let parent = meta
+            .parent()
+            .map(|id| GenId::from_chunk_id(ChunkId::recreate(id)));
+        let first_fileno = meta.first_fileno()?.unwrap_or(1) as FileId;
+        Ok(Self {
+            db,
+            parent,
+            first_fileno,
+            _dir: None,
+        })
+    }
 }
 
 /// Possible errors from using local generations.
@@ -224,14 +592,16 @@ impl BackedUpFile {
     }
 
     /// Return reason why file is in its local generation.
-    pub fn reason(&self) -> Reason {
-        self.reason
+    pub fn reason(&self) -> &Reason {
+        &self.reason
     }
 }
 
 impl LocalGeneration {
-    fn new(db: GenerationDb) -> Self {
-        Self { db }
+    fn new(db: GenerationDb) -> Result<Self, LocalGenerationError> {
+        Ok(Self {
+            layers: vec![Layer::new(db)?],
+        })
     }
 
     /// Open a local file as a local generation.
@@ -240,74 +610,733 @@ impl LocalGeneration {
         P: AsRef<Path>,
     {
         let db = GenerationDb::open(filename.as_ref())?;
-        let gen = Self::new(db);
-        Ok(gen)
+        Self::new(db)
+    }
+
+    /// The parent generation that needs to be added with
+    /// [`LocalGeneration::add_parent`] before all files can be looked
+    /// up, if any.
+    pub fn missing_parent(&self) -> Option<&GenId> {
+        self.layers.last().and_then(|layer| layer.parent.as_ref())
+    }
+
+    /// Add the missing parent generation to the chain.
+    ///
+    /// The parent's database is in `dir`, which is removed when this
+    /// generation is dropped.
+    pub fn add_parent(&mut self, parent: LocalGeneration, dir: TempDir) {
+        let mut layers = parent.layers.into_iter();
+        if let Some(mut layer) = layers.next() {
+            layer._dir = Some(dir);
+            self.layers.push(layer);
+        }
+        self.layers.extend(layers);
+    }
+
+    /// Ids of the parent generations that have been added to the
+    /// chain, nearest first.
+    pub fn parents(&self) -> Vec<GenId> {
+        let n = self.layers.len();
+        self.layers[..n - 1]
+            .iter()
+            .filter_map(|layer| layer.parent.clone())
+            .collect()
+    }
+
+    /// How many generations are in the chain of this generation and
+    /// its parents?
+    pub fn chain_length(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// The file id for the first file in a new generation based on
+    /// this one.
+    pub fn next_fileno(&self) -> Result<FileId, LocalGenerationError> {
+        let top = &self.layers[0];
+        let last = match self.meta()?.last_fileno()? {
+            Some(last) => last as FileId,
+            None => top.db.file_count()?,
+        };
+        Ok(last + 1)
     }
 
     /// Return generation metadata for local generation.
     pub fn meta(&self) -> Result<GenerationMeta, LocalGenerationError> {
-        let map = self.db.meta()?;
+        let map = self.layers[0].db.meta()?;
         GenerationMeta::from(map).map_err(LocalGenerationError::GenerationMeta)
     }
 
+    /// Check that this version of Obnam can restore the generation.
+    ///
+    /// Return unsupported features that can be safely ignored.
+    pub fn check_features(&self) -> Result<Vec<Feature>, LocalGenerationError> {
+        let mut ignored = vec![];
+        for layer in self.layers.iter() {
+            let meta = GenerationMeta::from(layer.db.meta()?)?;
+            for feature in meta.check_features()? {
+                if !ignored.contains(&feature) {
+                    ignored.push(feature);
+                }
+            }
+        }
+        Ok(ignored)
+    }
+
     /// How many files are there in the local generation?
     pub fn file_count(&self) -> Result<FileId, LocalGenerationError> {
-        Ok(self.db.file_count()?)
+        if self.layers.len() == 1 {
+            return Ok(self.layers[0].db.file_count()?);
+        }
+        let mut count = 0;
+        for file in self.files()?.iter()? {
+            file?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Return statistics about the files in the local generation.
+    pub fn stats(&self) -> Result<GenerationStats, LocalGenerationError> {
+        if self.layers.len() == 1 {
+            return Ok(self.layers[0].db.stats()?);
+        }
+        let mut stats = GenerationStats::default();
+        for file in self.files()?.iter()? {
+            let (_, entry, reason, _) = file?;
+            stats.add(&entry, reason);
+        }
+        Ok(stats)
     }
 
     /// Return all files in the local generation.
-    pub fn files(
-        &self,
-    ) -> Result<SqlResults<(FileId, FilesystemEntry, Reason, bool)>, LocalGenerationError> {
-        self.db.files().map_err(LocalGenerationError::GenerationDb)
+    pub fn files(&self) -> Result<GenerationFiles<'_>, LocalGenerationError> {
+        let mut layers = vec![];
+        for layer in self.layers.iter() {
+            layers.push(layer.db.files()?);
+        }
+        Ok(GenerationFiles { gen: self, layers })
     }
 
     /// Return ids for all chunks in local generation.
     pub fn chunkids(&self, fileid: FileId) -> Result<SqlResults<ChunkId>, LocalGenerationError> {
-        self.db
+        self.layer_of(fileid)
+            .db
             .chunkids(fileid)
             .map_err(LocalGenerationError::GenerationDb)
     }
 
+    // The layer of the chain that a file id belongs to.
+    fn layer_of(&self, fileid: FileId) -> &Layer {
+        self.layers
+            .iter()
+            .find(|layer| layer.first_fileno <= fileid)
+            .unwrap_or(&self.layers[self.layers.len() - 1])
+    }
+
+    /// Return a file's chunks, with their offsets and lengths, if
+    /// known.
+    pub fn file_chunks(
+        &self,
+        fileid: FileId,
+    ) -> Result<SqlResults<'_, FileChunk>, LocalGenerationError> {
+        self.layer_of(fileid)
+            .db
+            .file_chunks(fileid)
+            .map_err(LocalGenerationError::GenerationDb)
+    }
+
+    /// Return the ids of a file's chunks, and their lengths, in the
+    /// form a new generation takes them. The lengths are empty unless
+    /// they're known for all chunks.
+    pub fn chunk_ids_and_lengths(
+        &self,
+        fileid: FileId,
+    ) -> Result<(Vec<ChunkId>, Vec<u64>), LocalGenerationError> {
+        let mut ids = vec![];
+        let mut lengths = vec![];
+        for chunk in self.file_chunks(fileid)?.iter()? {
+            let chunk = chunk?;
+            if let Some(length) = chunk.length() {
+                lengths.push(length);
+            }
+            ids.push(chunk.id().clone());
+        }
+        if lengths.len() != ids.len() {
+            lengths.clear();
+        }
+        Ok((ids, lengths))
+    }
+
+    /// Return the labels of a file's chunks, in the order they are in
+    /// the file. The labels are empty unless they're known for all
+    /// chunks.
+    pub fn chunk_labels(&self, fileid: FileId) -> Result<Vec<String>, LocalGenerationError> {
+        collect_labels(self.file_chunks(fileid)?)
+    }
+
     /// Return entry for a file, given its pathname.
     pub fn get_file(
         &self,
         filename: &Path,
     ) -> Result<Option<FilesystemEntry>, LocalGenerationError> {
-        self.db
-            .get_file(filename)
-            .map_err(LocalGenerationError::GenerationDb)
+        for layer in self.layers.iter() {
+            if let Some(entry) = layer.db.get_file(filename)? {
+                return Ok(Some(entry));
+            }
+            if layer.db.is_deleted(filename)? {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Return a file's id, entry, and the reason it was backed up,
+    /// given its pathname.
+    pub fn get_backed_up_file(
+        &self,
+        filename: &Path,
+    ) -> Result<Option<BackedUpFile>, LocalGenerationError> {
+        for layer in self.layers.iter() {
+            if let Some((fileno, entry, reason)) = layer.db.get_file_row(filename)? {
+                return Ok(Some(BackedUpFile::new(fileno, entry, reason)));
+            }
+            if layer.db.is_deleted(filename)? {
+                break;
+            }
+        }
+        Ok(None)
     }
 
     /// Get the id in the local generation of a file, given its pathname.
     pub fn get_fileno(&self, filename: &Path) -> Result<Option<FileId>, LocalGenerationError> {
-        self.db
-            .get_fileno(filename)
-            .map_err(LocalGenerationError::GenerationDb)
+        for layer in self.layers.iter() {
+            if let Some(fileno) = layer.db.get_fileno(filename)? {
+                return Ok(Some(fileno));
+            }
+            if layer.db.is_deleted(filename)? {
+                break;
+            }
+        }
+        Ok(None)
     }
 
     /// Does a pathname refer to a cache directory?
     pub fn is_cachedir_tag(&self, filename: &Path) -> Result<bool, LocalGenerationError> {
-        self.db
-            .is_cachedir_tag(filename)
-            .map_err(LocalGenerationError::GenerationDb)
+        for layer in self.layers.iter() {
+            if layer.db.get_fileno(filename)?.is_some() {
+                return Ok(layer.db.is_cachedir_tag(filename)?);
+            }
+            if layer.db.is_deleted(filename)? {
+                break;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Why couldn't the content of a file be backed up, given its
+    /// pathname? This is None if it could, or if the generation
+    /// doesn't say.
+    pub fn file_error(&self, filename: &Path) -> Result<Option<String>, LocalGenerationError> {
+        for layer in self.layers.iter() {
+            if layer.db.get_fileno(filename)?.is_some() {
+                return Ok(layer.db.file_error(filename)?);
+            }
+            if layer.db.is_deleted(filename)? {
+                break;
+            }
+        }
+        Ok(None)
     }
+
+    // Is a file in the chain at a given position replaced or deleted
+    // by a later generation?
+    fn is_hidden(&self, i: usize, entry: &FilesystemEntry) -> Result<bool, LocalGenerationError> {
+        if i == 0 {
+            return Ok(false);
+        }
+        let filename = entry.pathbuf();
+        for layer in self.layers[..i].iter() {
+            if layer.db.get_fileno(&filename)?.is_some() || layer.db.is_deleted(&filename)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// A file in a local generation: its id, metadata, why it's in the
+/// generation, and whether it's a cache directory tag.
+pub type BackedUpRow = (FileId, FilesystemEntry, Reason, bool);
+
+/// All files in a local generation.
+pub struct GenerationFiles<'a> {
+    gen: &'a LocalGeneration,
+    layers: Vec<SqlResults<'a, BackedUpRow>>,
+}
+
+impl<'a> GenerationFiles<'a> {
+    /// Create an iterator over the files.
+    ///
+    /// Files in a parent generation that have been changed or deleted
+    /// in a later generation are left out.
+    pub fn iter(
+        &mut self,
+    ) -> Result<
+        impl Iterator<Item = Result<BackedUpRow, LocalGenerationError>> + '_,
+        LocalGenerationError,
+    > {
+        let gen = self.gen;
+        let mut iters = vec![];
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            iters.push(layer.iter()?.map(move |row| (i, row)));
+        }
+        Ok(iters
+            .into_iter()
+            .flatten()
+            .filter_map(move |(i, row)| match row {
+                Err(err) => Some(Err(err.into())),
+                Ok(row) => match gen.is_hidden(i, &row.1) {
+                    Ok(true) => None,
+                    Ok(false) => Some(Ok(row)),
+                    Err(err) => Some(Err(err)),
+                },
+            }))
+    }
+}
+
+// The chunk labels of a file, in order, or none unless they're known
+// for all chunks.
+fn collect_labels(mut chunks: SqlResults<FileChunk>) -> Result<Vec<String>, LocalGenerationError> {
+    let mut labels = vec![];
+    for chunk in chunks.iter()? {
+        match chunk?.label() {
+            Some(label) => labels.push(label.to_string()),
+            None => return Ok(vec![]),
+        }
+    }
+    Ok(labels)
 }
 
 #[cfg(test)]
 mod test {
-    use super::{LabelChecksumKind, LocalGeneration, NascentGeneration, Reason, SchemaVersion};
+    use super::{
+        GenId, LabelChecksumKind, LocalGeneration, NascentGeneration, Reason, SchemaVersion,
+    };
+    use crate::chunkid::ChunkId;
     use crate::fsentry::EntryBuilder;
     use crate::fsentry::FilesystemKind;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     use tempfile::{tempdir, NamedTempFile};
 
+    fn regular(path: &str, len: u64) -> crate::fsentry::FilesystemEntry {
+        EntryBuilder::new(FilesystemKind::Regular)
+            .path(PathBuf::from(path))
+            .len(len)
+            .build()
+    }
+
+    fn chunk_ids(gen: &LocalGeneration, path: &str) -> Vec<String> {
+        let fileno = gen.get_fileno(Path::new(path)).unwrap().unwrap();
+        let mut ids = vec![];
+        for id in gen.chunkids(fileno).unwrap().iter().unwrap() {
+            ids.push(id.unwrap().to_string());
+        }
+        ids
+    }
+
+    #[test]
+    fn incremental_generation_resolves_through_parent() {
+        let tmp = tempdir().unwrap();
+        let schema = SchemaVersion::new(2, 1);
+        let parent_db = tmp.path().join("parent.db");
+        let child_db = tmp.path().join("child.db");
+        let id = |s: &str| ChunkId::recreate(s);
+
+        let mut parent =
+            NascentGeneration::create(&parent_db, schema, LabelChecksumKind::Sha256).unwrap();
+        parent
+            .insert(regular("/a", 1), &[id("a1")], Reason::IsNew, false)
+            .unwrap();
+        parent
+            .insert(regular("/b", 2), &[id("b1")], Reason::IsNew, false)
+            .unwrap();
+        parent
+            .insert(regular("/c", 3), &[id("c1")], Reason::IsNew, false)
+            .unwrap();
+        parent.close().unwrap();
+
+        let old = LocalGeneration::open(&parent_db).unwrap();
+        let parent_id = GenId::from_chunk_id(id("parent"));
+        let mut child = NascentGeneration::create_incremental(
+            &child_db,
+            schema,
+            LabelChecksumKind::Sha256,
+            &parent_id,
+            &old,
+        )
+        .unwrap();
+        child
+            .insert_or_keep(
+                &old,
+                regular("/a", 1),
+                &[id("a1")],
+                &[],
+                &[],
+                Reason::Unchanged,
+                false,
+                None,
+            )
+            .unwrap();
+        child
+            .insert_or_keep(
+                &old,
+                regular("/b", 20),
+                &[id("b2")],
+                &[],
+                &[],
+                Reason::Changed,
+                false,
+                None,
+            )
+            .unwrap();
+        child
+            .insert_or_keep(
+                &old,
+                regular("/d", 4),
+                &[id("d1")],
+                &[],
+                &[],
+                Reason::IsNew,
+                false,
+                None,
+            )
+            .unwrap();
+        child.record_deletions(&old).unwrap();
+        assert_eq!(child.file_count(), 3);
+        child.close().unwrap();
+
+        let mut gen = LocalGeneration::open(&child_db).unwrap();
+        assert_eq!(gen.missing_parent().unwrap().to_string(), "parent");
+        assert_eq!(gen.layers[0].db.file_count().unwrap(), 2);
+        let dir = tempdir().unwrap();
+        gen.add_parent(LocalGeneration::open(&parent_db).unwrap(), dir);
+        assert!(gen.missing_parent().is_none());
+
+        let mut names = vec![];
+        for file in gen.files().unwrap().iter().unwrap() {
+            let (_, e, _, _) = file.unwrap();
+            names.push((e.pathbuf(), e.len()));
+        }
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                (PathBuf::from("/a"), 1),
+                (PathBuf::from("/b"), 20),
+                (PathBuf::from("/d"), 4),
+            ]
+        );
+        assert_eq!(gen.file_count().unwrap(), 3);
+        assert!(gen.get_file(Path::new("/c")).unwrap().is_none());
+        assert_eq!(chunk_ids(&gen, "/a"), vec!["a1"]);
+        assert_eq!(chunk_ids(&gen, "/b"), vec!["b2"]);
+        assert_eq!(chunk_ids(&gen, "/d"), vec!["d1"]);
+        assert_eq!(gen.next_fileno().unwrap(), 6);
+    }
+
+    #[test]
+    fn builds_generations_in_memory() {
+        let schema = SchemaVersion::new(2, 1);
+        let id = |s: &str| ChunkId::recreate(s);
+
+        let mut parent = NascentGeneration::in_memory(schema, LabelChecksumKind::Sha256).unwrap();
+        parent
+            .insert(regular("/a", 1), &[id("a1")], Reason::IsNew, false)
+            .unwrap();
+        parent
+            .insert(regular("/b", 2), &[id("b1")], Reason::IsNew, false)
+            .unwrap();
+        let parent = parent.finish().unwrap();
+        assert_eq!(parent.file_count().unwrap(), 2);
+        assert_eq!(chunk_ids(&parent, "/b"), vec!["b1"]);
+
+        let parent_id = GenId::from_chunk_id(id("parent"));
+        let mut child = NascentGeneration::in_memory_incremental(
+            schema,
+            LabelChecksumKind::Sha256,
+            &parent_id,
+            &parent,
+        )
+        .unwrap();
+        child
+            .insert_or_keep(
+                &parent,
+                regular("/a", 10),
+                &[id("a2")],
+                &[],
+                &[],
+                Reason::Changed,
+                false,
+                None,
+            )
+            .unwrap();
+        child.record_deletions(&parent).unwrap();
+        let mut gen = child.finish().unwrap();
+        gen.add_parent(parent, tempdir().unwrap());
+
+        assert_eq!(gen.file_count().unwrap(), 1);
+        assert_eq!(chunk_ids(&gen, "/a"), vec!["a2"]);
+        assert!(gen.get_file(Path::new("/b")).unwrap().is_none());
+    }
+
+    #[test]
+    fn finds_file_error_through_parent() {
+        let schema = SchemaVersion::new(2, 1);
+        let id = |s: &str| ChunkId::recreate(s);
+
+        let mut parent = NascentGeneration::in_memory(schema, LabelChecksumKind::Sha256).unwrap();
+        parent
+            .insert(regular("/a", 1), &[id("a1")], Reason::IsNew, false)
+            .unwrap();
+        parent
+            .insert_with_error(
+                regular("/b", 2),
+                &[],
+                &[],
+                &[],
+                Reason::FileError,
+                false,
+                Some("denied"),
+            )
+            .unwrap();
+        let parent = parent.finish().unwrap();
+
+        let parent_id = GenId::from_chunk_id(id("parent"));
+        let mut child = NascentGeneration::in_memory_incremental(
+            schema,
+            LabelChecksumKind::Sha256,
+            &parent_id,
+            &parent,
+        )
+        .unwrap();
+        child
+            .insert_or_keep(
+                &parent,
+                regular("/a", 10),
+                &[id("a2")],
+                &[],
+                &[],
+                Reason::Changed,
+                false,
+                None,
+            )
+            .unwrap();
+        let mut gen = child.finish().unwrap();
+        gen.add_parent(parent, tempdir().unwrap());
+
+        assert_eq!(gen.file_error(Path::new("/a")).unwrap(), None);
+        assert_eq!(
+            gen.file_error(Path::new("/b")).unwrap(),
+            Some("denied".to_string())
+        );
+    }
+
+    #[test]
+    fn looks_up_files_through_parent() {
+        let schema = SchemaVersion::new(2, 1);
+        let id = |s: &str| ChunkId::recreate(s);
+
+        let mut parent = NascentGeneration::in_memory(schema, LabelChecksumKind::Sha256).unwrap();
+        parent
+            .insert(regular("/a", 1), &[id("a1")], Reason::IsNew, false)
+            .unwrap();
+        parent
+            .insert(regular("/b", 2), &[id("b1")], Reason::IsNew, false)
+            .unwrap();
+        let parent = parent.finish().unwrap();
+
+        let parent_id = GenId::from_chunk_id(id("parent"));
+        let mut child = NascentGeneration::in_memory_incremental(
+            schema,
+            LabelChecksumKind::Sha256,
+            &parent_id,
+            &parent,
+        )
+        .unwrap();
+        child
+            .insert_or_keep(
+                &parent,
+                regular("/a", 10),
+                &[id("a2")],
+                &[],
+                &[],
+                Reason::Changed,
+                false,
+                None,
+            )
+            .unwrap();
+        child.record_deletions(&parent).unwrap();
+        let mut gen = child.finish().unwrap();
+        gen.add_parent(parent, tempdir().unwrap());
+
+        let a = gen.get_backed_up_file(Path::new("/a")).unwrap().unwrap();
+        assert_eq!(a.entry().len(), 10);
+        assert!(matches!(a.reason(), Reason::Changed));
+        assert!(gen.get_backed_up_file(Path::new("/b")).unwrap().is_none());
+        assert!(gen.get_backed_up_file(Path::new("/c")).unwrap().is_none());
+    }
+
+    #[test]
+    fn detects_renamed_files() {
+        let schema = SchemaVersion::new(0, 1);
+
+        // The chunks of the new files have other ids than those of the
+        // deleted ones, as if they'd been uploaded again, but the same
+        // labels.
+        let insert = |gen: &mut NascentGeneration, prefix: &str, path, labels: &[&str], reason| {
+            let ids: Vec<ChunkId> = labels
+                .iter()
+                .map(|label| ChunkId::recreate(&format!("{}{}", prefix, label)))
+                .collect();
+            let labels: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+            gen.insert_with_error(regular(path, 1), &ids, &[], &labels, reason, false, None)
+                .unwrap();
+        };
+
+        let mut parent = NascentGeneration::in_memory(schema, LabelChecksumKind::Sha256).unwrap();
+        for (path, labels) in [
+            ("/old/a", vec!["a1", "a2"]),
+            ("/same", vec!["s1"]),
+            ("/one/misc", vec!["d1"]),
+            ("/two/data", vec!["d1"]),
+            ("/empty", vec![]),
+        ] {
+            insert(&mut parent, "old-", path, &labels, Reason::IsNew);
+        }
+        let parent = parent.finish().unwrap();
+
+        let mut child = NascentGeneration::in_memory(schema, LabelChecksumKind::Sha256).unwrap();
+        for (path, labels, reason) in [
+            ("/same", vec!["s1"], Reason::Unchanged),
+            ("/new/a", vec!["a1", "a2"], Reason::IsNew),
+            ("/three/data", vec!["d1"], Reason::IsNew),
+            ("/other", vec!["a2", "a1"], Reason::IsNew),
+            ("/empty2", vec![], Reason::IsNew),
+        ] {
+            insert(&mut child, "new-", path, &labels, reason);
+        }
+        assert_eq!(child.detect_renames(&parent).unwrap(), 2);
+        let gen = child.finish().unwrap();
+
+        let mut reasons = vec![];
+        for file in gen.files().unwrap().iter().unwrap() {
+            let (_, e, reason, _) = file.unwrap();
+            reasons.push((
+                e.pathbuf(),
+                reason.to_string(),
+                reason.renamed_from().cloned(),
+            ));
+        }
+        let renamed = |path: &str, from: &str| {
+            (
+                PathBuf::from(path),
+                "renamed".to_string(),
+                Some(PathBuf::from(from)),
+            )
+        };
+        let not_renamed =
+            |path: &str, reason: &str| (PathBuf::from(path), reason.to_string(), None);
+        assert_eq!(
+            reasons,
+            vec![
+                not_renamed("/same", "unchanged"),
+                renamed("/new/a", "/old/a"),
+                renamed("/three/data", "/two/data"),
+                not_renamed("/other", "new"),
+                not_renamed("/empty2", "new"),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_in_memory_incremental_with_old_schema() {
+        let parent =
+            NascentGeneration::in_memory(SchemaVersion::new(1, 1), LabelChecksumKind::Sha256)
+                .unwrap()
+                .finish()
+                .unwrap();
+        let parent_id = GenId::from_chunk_id(ChunkId::recreate("parent"));
+        assert!(NascentGeneration::in_memory_incremental(
+            SchemaVersion::new(1, 1),
+            LabelChecksumKind::Sha256,
+            &parent_id,
+            &parent,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn keeps_streams_from_parent() {
+        let tmp = tempdir().unwrap();
+        let schema = SchemaVersion::new(0, 1);
+        let parent_db = tmp.path().join("parent.db");
+        let child_db = tmp.path().join("child.db");
+        let id = |s: &str| ChunkId::recreate(s);
+        let stream = EntryBuilder::new(FilesystemKind::Stream)
+            .path(PathBuf::from("db.sql"))
+            .len(5)
+            .build();
+
+        let mut parent =
+            NascentGeneration::create(&parent_db, schema, LabelChecksumKind::Sha256).unwrap();
+        parent
+            .insert(regular("/a", 1), &[id("a1")], Reason::IsNew, false)
+            .unwrap();
+        parent
+            .insert_with_error(
+                stream,
+                &[id("s1")],
+                &[5],
+                &["l1".to_string()],
+                Reason::IsNew,
+                false,
+                None,
+            )
+            .unwrap();
+        assert!(parent.has_streams());
+        parent.close().unwrap();
+
+        let old = LocalGeneration::open(&parent_db).unwrap();
+        let mut child =
+            NascentGeneration::create(&child_db, schema, LabelChecksumKind::Sha256).unwrap();
+        child
+            .insert(regular("/a", 2), &[id("a2")], Reason::Changed, false)
+            .unwrap();
+        assert!(!child.has_streams());
+        child
+            .keep_from(&old, |e| e.kind() == FilesystemKind::Stream)
+            .unwrap();
+        assert!(child.has_streams());
+        assert_eq!(child.file_count(), 2);
+        child.close().unwrap();
+
+        let gen = LocalGeneration::open(&child_db).unwrap();
+        assert_eq!(chunk_ids(&gen, "/a"), vec!["a2"]);
+        assert_eq!(chunk_ids(&gen, "db.sql"), vec!["s1"]);
+        let fileno = gen.get_fileno(Path::new("db.sql")).unwrap().unwrap();
+        assert_eq!(
+            gen.chunk_ids_and_lengths(fileno).unwrap(),
+            (vec![id("s1")], vec![5])
+        );
+        assert_eq!(gen.chunk_labels(fileno).unwrap(), vec!["l1"]);
+    }
+
     #[test]
     fn round_trips_u64_max() {
         let tmp = tempdir().unwrap();
         let filename = tmp.path().join("test.db");
         let path = PathBuf::from("/");
-        let schema = SchemaVersion::new(0, 0);
+        let schema = SchemaVersion::new(0, 1);
         {
             let e = EntryBuilder::new(FilesystemKind::Directory)
                 .path(path.clone())
@@ -327,7 +1356,7 @@ mod test {
     #[test]
     fn empty() {
         let filename = NamedTempFile::new().unwrap().path().to_path_buf();
-        let schema = SchemaVersion::new(0, 0);
+        let schema = SchemaVersion::new(0, 1);
         {
             let mut _gen =
                 NascentGeneration::create(&filename, schema, LabelChecksumKind::Sha256).unwrap();
@@ -359,7 +1388,7 @@ mod test {
         let tag_path1 = Path::new("/a_tag");
         let tag_path2 = Path::new("/another_dir/a_tag");
 
-        let schema = SchemaVersion::new(0, 0);
+        let schema = SchemaVersion::new(0, 1);
         let mut gen =
             NascentGeneration::create(&dbfile, schema, LabelChecksumKind::Sha256).unwrap();
         let mut cache = users::UsersCache::new();
@@ -383,14 +1412,22 @@ mod test {
             FsEntryBackupOutcome {
                 entry: FilesystemEntry::from_metadata(nontag_path2, &metadata, &mut cache).unwrap(),
                 ids: vec![],
+                lengths: vec![],
+                labels: vec![],
                 reason: Reason::IsNew,
                 is_cachedir_tag: false,
+                error: None,
+                error_message: None,
             },
             FsEntryBackupOutcome {
                 entry: FilesystemEntry::from_metadata(tag_path2, &metadata, &mut cache).unwrap(),
                 ids: vec![],
+                lengths: vec![],
+                labels: vec![],
                 reason: Reason::IsNew,
                 is_cachedir_tag: true,
+                error: None,
+                error_message: None,
             },
         ];
 
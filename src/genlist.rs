@@ -14,6 +14,10 @@ pub enum GenerationListError {
     /// Server doesn't know about a generation.
     #[error("Unknown generation: {0}")]
     UnknownGeneration(ChunkId),
+
+    /// A generation id prefix matches more than one generation.
+    #[error("Generation id prefix {0} is ambiguous: it matches {1} generations")]
+    AmbiguousGeneration(String, usize),
 }
 
 impl GenerationList {
@@ -29,11 +33,18 @@ impl GenerationList {
         self.list.iter()
     }
 
+    /// Return the latest `n` generations, oldest first, or all of
+    /// them if there are fewer.
+    pub fn last(&self, n: usize) -> &[FinishedGeneration] {
+        &self.list[self.list.len().saturating_sub(n)..]
+    }
+
     /// Resolve a symbolic name of a generation into its identifier.
     ///
     /// For example, "latest" refers to the latest backup, but needs
     /// to be resolved into an actual, immutable id to actually be
-    /// restored.
+    /// restored. A generation may also be referred to by a prefix of
+    /// its id, if no other generation's id starts with it.
     pub fn resolve(&self, genref: &str) -> Result<GenId, GenerationListError> {
         let gen = if self.list.is_empty() {
             None
@@ -41,17 +52,26 @@ impl GenerationList {
             let i = self.list.len() - 1;
             Some(self.list[i].clone())
         } else {
-            let genref = GenId::from_chunk_id(genref.parse().unwrap());
-            let hits: Vec<FinishedGeneration> = self
+            let exact: Vec<FinishedGeneration> = self
                 .iter()
-                .filter(|gen| gen.id().as_chunk_id() == genref.as_chunk_id())
+                .filter(|gen| gen.id().to_string() == genref)
                 .cloned()
                 .collect();
-            if hits.len() == 1 {
-                Some(hits[0].clone())
+            let hits: Vec<FinishedGeneration> = if exact.is_empty() && !genref.is_empty() {
+                self.iter()
+                    .filter(|gen| gen.id().to_string().starts_with(genref))
+                    .cloned()
+                    .collect()
             } else {
-                None
+                exact
+            };
+            if hits.len() > 1 {
+                return Err(GenerationListError::AmbiguousGeneration(
+                    genref.to_string(),
+                    hits.len(),
+                ));
             }
+            hits.first().cloned()
         };
         match gen {
             None => Err(GenerationListError::UnknownGeneration(ChunkId::recreate(
@@ -61,3 +81,65 @@ impl GenerationList {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{GenerationList, GenerationListError};
+    use crate::generation::FinishedGeneration;
+
+    fn list() -> GenerationList {
+        GenerationList::new(vec![
+            FinishedGeneration::new("b", "2022-01-02"),
+            FinishedGeneration::new("c", "2022-01-03"),
+            FinishedGeneration::new("a", "2022-01-01"),
+        ])
+    }
+
+    fn ids(gens: &[FinishedGeneration]) -> Vec<String> {
+        gens.iter().map(|gen| gen.id().to_string()).collect()
+    }
+
+    #[test]
+    fn sorts_by_end_time() {
+        let list = list();
+        let gens: Vec<FinishedGeneration> = list.iter().cloned().collect();
+        assert_eq!(ids(&gens), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn resolves_latest() {
+        assert_eq!(list().resolve("latest").unwrap().to_string(), "c");
+    }
+
+    #[test]
+    fn resolves_unique_prefix() {
+        let list = GenerationList::new(vec![
+            FinishedGeneration::new("abc1", "2022-01-01"),
+            FinishedGeneration::new("abd2", "2022-01-02"),
+            FinishedGeneration::new("abd", "2022-01-03"),
+        ]);
+        assert_eq!(list.resolve("abc").unwrap().to_string(), "abc1");
+        assert_eq!(list.resolve("abd").unwrap().to_string(), "abd");
+        assert_eq!(list.resolve("abd2").unwrap().to_string(), "abd2");
+        assert!(matches!(
+            list.resolve("ab"),
+            Err(GenerationListError::AmbiguousGeneration(_, 3))
+        ));
+        assert!(matches!(
+            list.resolve("x"),
+            Err(GenerationListError::UnknownGeneration(_))
+        ));
+        assert!(list.resolve("").is_err());
+    }
+
+    #[test]
+    fn returns_latest_generations() {
+        assert_eq!(ids(list().last(2)), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn returns_all_generations_if_there_are_fewer() {
+        assert_eq!(ids(list().last(5)), vec!["a", "b", "c"]);
+        assert!(list().last(0).is_empty());
+    }
+}
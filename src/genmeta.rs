@@ -3,6 +3,7 @@
 use crate::schema::{SchemaVersion, VersionComponent};
 use serde::Serialize;
 use std::collections::HashMap;
+use std::fmt;
 
 /// Metadata about the local generation.
 #[derive(Debug, Serialize)]
@@ -31,6 +32,291 @@ impl GenerationMeta {
     pub fn get(&self, key: &str) -> Option<&String> {
         self.extras.get(key)
     }
+
+    /// Return name of host where the backup was made, if known.
+    pub fn hostname(&self) -> Option<&str> {
+        self.get(HOSTNAME).map(|s| s.as_str())
+    }
+
+    /// Return name of user who made the backup, if known.
+    pub fn username(&self) -> Option<&str> {
+        self.get(USERNAME).map(|s| s.as_str())
+    }
+
+    /// Return version of Obnam that made the backup, if known.
+    pub fn client_version(&self) -> Option<&str> {
+        self.get(CLIENT_VERSION).map(|s| s.as_str())
+    }
+
+    /// Return timestamp of when the backup started, if known.
+    pub fn started(&self) -> Option<&str> {
+        self.get(STARTED).map(|s| s.as_str())
+    }
+
+    /// Return timestamp of when the backup ended, if known.
+    pub fn ended(&self) -> Option<&str> {
+        self.get(ENDED).map(|s| s.as_str())
+    }
+
+    /// Return the tag given to the backup when it was made, if any.
+    pub fn tag(&self) -> Option<&str> {
+        self.get(TAG).map(|s| s.as_str())
+    }
+
+    /// Return number of files in the backup, if known.
+    pub fn file_count(&self) -> Result<Option<u64>, GenerationMetaError> {
+        self.optional_int(FILE_COUNT)
+    }
+
+    /// Return number of bytes of file content in the backup, if known.
+    pub fn file_bytes(&self) -> Result<Option<u64>, GenerationMetaError> {
+        self.optional_int(FILE_BYTES)
+    }
+
+    /// Return number of warnings from making the backup, if known.
+    pub fn warning_count(&self) -> Result<Option<u64>, GenerationMetaError> {
+        self.optional_int(WARNING_COUNT)
+    }
+
+    /// Return the generation this one is based on, if it's an
+    /// incremental generation.
+    pub fn parent(&self) -> Option<&str> {
+        self.get(PARENT).map(|s| s.as_str())
+    }
+
+    /// Return the smallest file id used by an incremental generation,
+    /// if known.
+    pub fn first_fileno(&self) -> Result<Option<u64>, GenerationMetaError> {
+        self.optional_int(FIRST_FILENO)
+    }
+
+    /// Return the largest file id used by a generation and its
+    /// parents, if known.
+    pub fn last_fileno(&self) -> Result<Option<u64>, GenerationMetaError> {
+        self.optional_int(LAST_FILENO)
+    }
+
+    /// Return the optional features that were used when making the
+    /// backup.
+    ///
+    /// Backups made before features were recorded have none.
+    pub fn features(&self) -> Vec<Feature> {
+        match self.get(FEATURES) {
+            None => vec![],
+            Some(v) => v
+                .split(',')
+                .filter(|s| !s.is_empty())
+                .map(Feature::parse)
+                .collect(),
+        }
+    }
+
+    /// Check that this version of Obnam can restore the backup.
+    ///
+    /// Return the features used by the backup that this version of
+    /// Obnam doesn't support, but which can be safely ignored. If the
+    /// backup uses an unsupported feature that affects the content of
+    /// files, return an error, as restoring would produce wrong data.
+    pub fn check_features(&self) -> Result<Vec<Feature>, GenerationMetaError> {
+        let mut ignored = vec![];
+        for feature in self.features() {
+            if !feature.is_supported() {
+                if feature.affects_content() {
+                    return Err(GenerationMetaError::UnsupportedFeature(feature));
+                }
+                ignored.push(feature);
+            }
+        }
+        Ok(ignored)
+    }
+
+    fn optional_int(&self, key: &str) -> Result<Option<u64>, GenerationMetaError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(v) => v
+                .parse()
+                .map(Some)
+                .map_err(|err| GenerationMetaError::BadMetaInteger(key.to_string(), err)),
+        }
+    }
+}
+
+/// Key in the meta table for the name of the host making the backup.
+pub const HOSTNAME: &str = "hostname";
+
+/// Key in the meta table for the name of the user making the backup.
+pub const USERNAME: &str = "username";
+
+/// Key in the meta table for the version of Obnam making the backup.
+pub const CLIENT_VERSION: &str = "client_version";
+
+/// Key in the meta table for the time the backup started.
+pub const STARTED: &str = "started";
+
+/// Key in the meta table for the time the backup ended.
+pub const ENDED: &str = "ended";
+
+/// Key in the meta table for the tag given to the backup by the user.
+pub const TAG: &str = "tag";
+
+/// Key in the meta table for the number of files in the backup.
+pub const FILE_COUNT: &str = "file_count";
+
+/// Key in the meta table for the number of bytes of file content.
+pub const FILE_BYTES: &str = "file_bytes";
+
+/// Key in the meta table for the number of warnings.
+pub const WARNING_COUNT: &str = "warning_count";
+
+/// Key in the meta table for the optional features used by the backup.
+pub const FEATURES: &str = "features";
+
+/// Key in the meta table for the time a damaged generation was
+/// repaired, if it was.
+pub const REPAIRED: &str = "repaired";
+
+/// Key in the meta table for the generation an incremental generation
+/// is based on.
+pub const PARENT: &str = "parent";
+
+/// Key in the meta table for the smallest file id used in an
+/// incremental generation. Files with smaller ids are in parent
+/// generations.
+pub const FIRST_FILENO: &str = "first_fileno";
+
+/// Key in the meta table for the largest file id used in a generation
+/// and its parents.
+pub const LAST_FILENO: &str = "last_fileno";
+
+/// An optional feature that may have been used when making a backup.
+///
+/// Features are recorded in the generation's meta table so that
+/// restoring can tell if it knows how to handle the backup correctly.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Feature {
+    /// Extended attributes of files were backed up.
+    Xattrs,
+
+    /// Checksums of whole files are stored.
+    FileHashes,
+
+    /// Several small files may be packed into one chunk.
+    Packing,
+
+    /// Chunk data is compressed with the named algorithm.
+    Compression(String),
+
+    /// Some files are streams of data, read from a pipe.
+    Streams,
+
+    /// Chunks are padded with the Padmé scheme before they're
+    /// encrypted.
+    Padme,
+
+    /// Chunk labels are BLAKE3 checksums.
+    Blake3,
+
+    /// Chunk labels are keyed checksums, which only the client can
+    /// compute.
+    KeyedLabels,
+
+    /// Encrypted chunks are bound to their chunk ids.
+    IdBoundChunks,
+
+    /// The generation uses schema 2, and may store only the changes
+    /// since its parent generation.
+    IncrementalSchema,
+
+    /// A feature this version of Obnam doesn't know about.
+    Unknown(String),
+}
+
+impl Feature {
+    /// Features this version of Obnam uses when making backups.
+    pub fn in_use() -> Vec<Feature> {
+        vec![Self::IdBoundChunks]
+    }
+
+    /// Features this version of Obnam can restore. Some are only
+    /// used when making some backups, not all.
+    pub fn supported() -> Vec<Feature> {
+        let mut features = Self::in_use();
+        features.extend([
+            Self::Streams,
+            Self::Padme,
+            Self::Blake3,
+            Self::KeyedLabels,
+            Self::IncrementalSchema,
+        ]);
+        features
+    }
+
+    /// Parse a feature from its representation in the meta table.
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "xattrs" => Self::Xattrs,
+            "file_hashes" => Self::FileHashes,
+            "packing" => Self::Packing,
+            "streams" => Self::Streams,
+            "padme" => Self::Padme,
+            "blake3" => Self::Blake3,
+            "keyed_labels" => Self::KeyedLabels,
+            "id_bound_chunks" => Self::IdBoundChunks,
+            "incremental_schema" => Self::IncrementalSchema,
+            _ => match s.strip_prefix("compression=") {
+                Some(algo) => Self::Compression(algo.to_string()),
+                None => Self::Unknown(s.to_string()),
+            },
+        }
+    }
+
+    /// Does this version of Obnam support the feature?
+    pub fn is_supported(&self) -> bool {
+        Self::supported().contains(self)
+    }
+
+    /// Is the feature needed to restore the content of files
+    /// correctly?
+    ///
+    /// Features we don't know about are assumed to be needed.
+    pub fn affects_content(&self) -> bool {
+        match self {
+            Self::Xattrs | Self::FileHashes => false,
+            Self::Packing
+            | Self::Compression(_)
+            | Self::Streams
+            | Self::Padme
+            | Self::Blake3
+            | Self::KeyedLabels
+            | Self::IdBoundChunks
+            | Self::IncrementalSchema
+            | Self::Unknown(_) => true,
+        }
+    }
+
+    /// Represent a list of features for the meta table.
+    pub fn serialize_list(features: &[Feature]) -> String {
+        let features: Vec<String> = features.iter().map(|f| f.to_string()).collect();
+        features.join(",")
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Xattrs => write!(f, "xattrs"),
+            Self::FileHashes => write!(f, "file_hashes"),
+            Self::Packing => write!(f, "packing"),
+            Self::Streams => write!(f, "streams"),
+            Self::Padme => write!(f, "padme"),
+            Self::Blake3 => write!(f, "blake3"),
+            Self::KeyedLabels => write!(f, "keyed_labels"),
+            Self::IdBoundChunks => write!(f, "id_bound_chunks"),
+            Self::IncrementalSchema => write!(f, "incremental_schema"),
+            Self::Compression(algo) => write!(f, "compression={}", algo),
+            Self::Unknown(s) => write!(f, "{}", s),
+        }
+    }
 }
 
 fn metastr(map: &mut HashMap<String, String>, key: &str) -> Result<String, GenerationMetaError> {
@@ -59,4 +345,98 @@ pub enum GenerationMetaError {
     /// Bad data in 'meta' table.
     #[error("Generation 'meta' row {0} has badly formed integer: {1}")]
     BadMetaInteger(String, std::num::ParseIntError),
+
+    /// Backup uses a feature needed for restoring that this version
+    /// of Obnam doesn't support.
+    #[error("Backup uses feature {0} which this version of Obnam doesn't support")]
+    UnsupportedFeature(Feature),
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Feature, GenerationMeta, FEATURES, FILE_COUNT, HOSTNAME};
+    use std::collections::HashMap;
+
+    fn meta(pairs: &[(&str, &str)]) -> GenerationMeta {
+        let mut map = HashMap::new();
+        map.insert("schema_version_major".to_string(), "0".to_string());
+        map.insert("schema_version_minor".to_string(), "0".to_string());
+        for (k, v) in pairs {
+            map.insert(k.to_string(), v.to_string());
+        }
+        GenerationMeta::from(map).unwrap()
+    }
+
+    #[test]
+    fn old_generation_lacks_extra_metadata() {
+        let meta = meta(&[]);
+        assert_eq!(meta.hostname(), None);
+        assert_eq!(meta.file_count().unwrap(), None);
+    }
+
+    #[test]
+    fn returns_extra_metadata() {
+        let meta = meta(&[(HOSTNAME, "exolobe1"), (FILE_COUNT, "42")]);
+        assert_eq!(meta.hostname(), Some("exolobe1"));
+        assert_eq!(meta.file_count().unwrap(), Some(42));
+    }
+
+    #[test]
+    fn rejects_bad_integer() {
+        let meta = meta(&[(FILE_COUNT, "many")]);
+        assert!(meta.file_count().is_err());
+    }
+
+    #[test]
+    fn old_generation_has_no_features() {
+        let meta = meta(&[]);
+        assert_eq!(meta.features(), vec![]);
+        assert!(meta.check_features().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_features() {
+        let meta = meta(&[(FEATURES, "xattrs,compression=zstd,frobnicate")]);
+        assert_eq!(
+            meta.features(),
+            vec![
+                Feature::Xattrs,
+                Feature::Compression("zstd".to_string()),
+                Feature::Unknown("frobnicate".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn feature_list_round_trips() {
+        let features = vec![Feature::Packing, Feature::Compression("zstd".to_string())];
+        let meta = meta(&[(FEATURES, &Feature::serialize_list(&features))]);
+        assert_eq!(meta.features(), features);
+    }
+
+    #[test]
+    fn new_features_round_trip_and_are_supported() {
+        let features = vec![
+            Feature::Padme,
+            Feature::Blake3,
+            Feature::KeyedLabels,
+            Feature::IdBoundChunks,
+            Feature::IncrementalSchema,
+        ];
+        let meta = meta(&[(FEATURES, &Feature::serialize_list(&features))]);
+        assert_eq!(meta.features(), features);
+        assert!(meta.check_features().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ignores_unsupported_metadata_feature() {
+        let meta = meta(&[(FEATURES, "xattrs")]);
+        assert_eq!(meta.check_features().unwrap(), vec![Feature::Xattrs]);
+    }
+
+    #[test]
+    fn rejects_unsupported_content_feature() {
+        let meta = meta(&[(FEATURES, "xattrs,packing")]);
+        assert!(meta.check_features().is_err());
+    }
 }
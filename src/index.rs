@@ -4,7 +4,44 @@ use crate::chunkid::ChunkId;
 use crate::chunkmeta::ChunkMeta;
 use crate::label::Label;
 use rusqlite::Connection;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const INDEX_FILENAME: &str = "meta.db";
+
+/// What the index knows about a chunk, besides its metadata.
+///
+/// Chunks indexed before this was recorded have no size or creation
+/// time.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ChunkStats {
+    /// Size of the chunk file, in bytes.
+    pub size: Option<u64>,
+    /// When the chunk was stored, in seconds since the Unix epoch.
+    pub created: Option<i64>,
+    /// The last garbage collection epoch in which the chunk was
+    /// found to be in use.
+    pub epoch: Option<i64>,
+}
+
+impl ChunkStats {
+    /// Statistics for a chunk stored right now.
+    pub fn new(size: u64) -> Self {
+        Self {
+            size: Some(size),
+            created: Some(unix_now()),
+            epoch: None,
+        }
+    }
+}
+
+// Current time in seconds since the Unix epoch.
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
 
 /// A chunk index stored on the disk.
 ///
@@ -26,6 +63,14 @@ pub enum IndexError {
     #[error("The repository index duplicates chunk {0}")]
     DuplicateChunk(ChunkId),
 
+    /// There is no index file.
+    #[error("chunk index {0} does not exist")]
+    NoIndex(PathBuf),
+
+    /// Couldn't replace the index file with a rebuilt one.
+    #[error("failed to replace index file {0}: {1}")]
+    Replace(PathBuf, std::io::Error),
+
     /// An error from SQLite.
     #[error(transparent)]
     SqlError(#[from] rusqlite::Error),
@@ -34,7 +79,7 @@ pub enum IndexError {
 impl Index {
     /// Create a new index.
     pub fn new<P: AsRef<Path>>(dirname: P) -> Result<Self, IndexError> {
-        let filename = dirname.as_ref().join("meta.db");
+        let filename = dirname.as_ref().join(INDEX_FILENAME);
         let conn = if filename.exists() {
             sql::open_db(&filename)?
         } else {
@@ -43,10 +88,72 @@ impl Index {
         Ok(Self { conn })
     }
 
-    /// Insert metadata for a new chunk into index.
-    pub fn insert_meta(&mut self, id: ChunkId, meta: ChunkMeta) -> Result<(), IndexError> {
+    /// Open an existing index for reading only.
+    ///
+    /// Nothing is written to the directory, and an index with an older
+    /// schema isn't upgraded, so that the index of a server that's not
+    /// running can be used, without changing it.
+    pub fn open_read_only<P: AsRef<Path>>(dirname: P) -> Result<Self, IndexError> {
+        let filename = dirname.as_ref().join(INDEX_FILENAME);
+        if !filename.exists() {
+            return Err(IndexError::NoIndex(filename));
+        }
+        Ok(Self {
+            conn: sql::open_db_read_only(&filename)?,
+        })
+    }
+
+    /// Replace the index in a directory with a new one that has the
+    /// given chunks, and records the given uses of chunks by
+    /// generations, as pairs of generation and chunk.
+    ///
+    /// The new index is written to a separate file first, and only
+    /// replaces the old one once it's complete. If anything fails
+    /// before that, the old index is left as it was. Nothing else may
+    /// use the index meanwhile.
+    pub fn rebuild<P: AsRef<Path>>(
+        dirname: P,
+        chunks: &[(ChunkId, ChunkMeta, ChunkStats)],
+        refs: &[(ChunkId, ChunkId)],
+    ) -> Result<(), IndexError> {
+        let filename = dirname.as_ref().join(INDEX_FILENAME);
+        let new = filename.with_extension("db.new");
+        for stale in [new.clone(), sidecar(&new, "-wal"), sidecar(&new, "-shm")] {
+            remove_if_exists(&stale)?;
+        }
+
+        let mut conn = sql::create_db(&new)?;
+        let t = conn.transaction()?;
+        for (id, meta, stats) in chunks {
+            sql::insert(&t, id, meta, stats)?;
+        }
+        for (gen, chunk) in refs {
+            sql::insert_ref(&t, gen, chunk)?;
+        }
+        t.commit()?;
+        // Leave write-ahead logging, so that the new index is
+        // entirely in one file that can be renamed into place.
+        conn.pragma_update(None, "journal_mode", "DELETE")?;
+        drop(conn);
+
+        // The old index's write-ahead log must not be applied to the
+        // new index.
+        for stale in [sidecar(&filename, "-wal"), sidecar(&filename, "-shm")] {
+            remove_if_exists(&stale)?;
+        }
+        std::fs::rename(&new, &filename).map_err(|err| IndexError::Replace(filename, err))?;
+        Ok(())
+    }
+
+    /// Insert metadata for a new chunk of a given size into index.
+    pub fn insert_meta(
+        &mut self,
+        id: ChunkId,
+        meta: ChunkMeta,
+        size: u64,
+    ) -> Result<(), IndexError> {
         let t = self.conn.transaction()?;
-        sql::insert(&t, &id, &meta)?;
+        sql::insert(&t, &id, &meta, &ChunkStats::new(size))?;
         t.commit()?;
         Ok(())
     }
@@ -56,6 +163,16 @@ impl Index {
         sql::lookup(&self.conn, id)
     }
 
+    /// Look up size and age of a chunk, given its id.
+    pub fn get_stats(&self, id: &ChunkId) -> Result<ChunkStats, IndexError> {
+        sql::lookup_stats(&self.conn, id)
+    }
+
+    /// Total size of all chunks whose size is known, in bytes.
+    pub fn total_size(&self) -> Result<u64, IndexError> {
+        sql::total_size(&self.conn)
+    }
+
     /// Remove a chunk's metadata.
     pub fn remove_meta(&mut self, id: &ChunkId) -> Result<(), IndexError> {
         sql::remove(&self.conn, id)
@@ -66,17 +183,105 @@ impl Index {
         sql::find_by_label(&self.conn, label)
     }
 
+    /// Find chunks whose id starts with a prefix.
+    ///
+    /// At most two are returned: enough to tell if the prefix matches
+    /// no chunk, one, or more.
+    pub fn find_by_id_prefix(&self, prefix: &str) -> Result<Vec<ChunkId>, IndexError> {
+        sql::find_by_id_prefix(&self.conn, prefix)
+    }
+
     /// Find all chunks.
     pub fn all_chunks(&self) -> Result<Vec<ChunkId>, IndexError> {
         sql::find_chunk_ids(&self.conn)
     }
+
+    /// Record which chunks a backup generation uses.
+    ///
+    /// This replaces anything recorded for the generation earlier, so
+    /// registering a generation again doesn't count its references
+    /// twice.
+    pub fn register_generation(
+        &mut self,
+        gen: &ChunkId,
+        chunks: &[ChunkId],
+    ) -> Result<(), IndexError> {
+        let t = self.conn.transaction()?;
+        sql::remove_refs(&t, gen)?;
+        for chunk in chunks {
+            sql::insert_ref(&t, gen, chunk)?;
+        }
+        t.commit()?;
+        Ok(())
+    }
+
+    /// Forget which chunks a backup generation uses.
+    ///
+    /// Return the chunks that no registered generation uses anymore.
+    pub fn unregister_generation(&mut self, gen: &ChunkId) -> Result<Vec<ChunkId>, IndexError> {
+        let unused = self.unshared_chunks(std::slice::from_ref(gen))?;
+        let t = self.conn.transaction()?;
+        sql::remove_refs(&t, gen)?;
+        t.commit()?;
+        Ok(unused)
+    }
+
+    /// Find all recorded uses of chunks by registered generations, as
+    /// pairs of generation and chunk.
+    pub fn all_refs(&self) -> Result<Vec<(ChunkId, ChunkId)>, IndexError> {
+        sql::find_all_refs(&self.conn)
+    }
+
+    /// Find all registered backup generations.
+    pub fn generations(&self) -> Result<Vec<ChunkId>, IndexError> {
+        sql::find_generations(&self.conn)
+    }
+
+    /// How many registered generations use a chunk?
+    pub fn ref_count(&self, chunk: &ChunkId) -> Result<usize, IndexError> {
+        Ok(sql::find_referrers(&self.conn, chunk)?.len())
+    }
+
+    /// Find the chunks used by some of the registered generations,
+    /// but none of the others.
+    pub fn unshared_chunks(&self, gens: &[ChunkId]) -> Result<Vec<ChunkId>, IndexError> {
+        let mut unshared = vec![];
+        for gen in gens {
+            for chunk in sql::find_refs(&self.conn, gen)? {
+                if unshared.contains(&chunk) {
+                    continue;
+                }
+                let referrers = sql::find_referrers(&self.conn, &chunk)?;
+                if referrers.iter().all(|r| gens.contains(r)) {
+                    unshared.push(chunk);
+                }
+            }
+        }
+        Ok(unshared)
+    }
+}
+
+// Name of a file SQLite keeps next to a database file.
+fn sidecar(filename: &Path, suffix: &str) -> PathBuf {
+    let mut name = filename.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn remove_if_exists(filename: &Path) -> Result<(), IndexError> {
+    match std::fs::remove_file(filename) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(IndexError::Replace(filename.to_path_buf(), err)),
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::Label;
 
-    use super::{ChunkId, ChunkMeta, Index};
+    use super::{ChunkId, ChunkMeta, ChunkStats, Index};
+    use rusqlite::{params, Connection};
     use std::path::Path;
     use tempfile::tempdir;
 
@@ -91,7 +296,7 @@ mod test {
         let meta = ChunkMeta::new(&sum);
         let dir = tempdir().unwrap();
         let mut idx = new_index(dir.path());
-        idx.insert_meta(id.clone(), meta.clone()).unwrap();
+        idx.insert_meta(id.clone(), meta.clone(), 3).unwrap();
         assert_eq!(idx.get_meta(&id).unwrap(), meta);
         let ids = idx.find_by_label(&sum.serialize()).unwrap();
         assert_eq!(ids, vec![id]);
@@ -104,10 +309,49 @@ mod test {
         let meta = ChunkMeta::new(&sum);
         let dir = tempdir().unwrap();
         let mut idx = new_index(dir.path());
-        idx.insert_meta(id, meta).unwrap();
+        idx.insert_meta(id, meta, 3).unwrap();
         assert_eq!(idx.find_by_label("def").unwrap().len(), 0)
     }
 
+    #[test]
+    fn finds_by_id_prefix() {
+        let meta = ChunkMeta::new(&Label::sha256(b"abc"));
+        let dir = tempdir().unwrap();
+        let mut idx = new_index(dir.path());
+        for id in ["abc1", "abc2", "abd3", "x%_1"] {
+            idx.insert_meta(id.parse().unwrap(), meta.clone(), 3)
+                .unwrap();
+        }
+        let mut ids: Vec<String> = idx
+            .find_by_id_prefix("abc")
+            .unwrap()
+            .iter()
+            .map(|id| id.to_string())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec!["abc1", "abc2"]);
+        assert_eq!(idx.find_by_id_prefix("a").unwrap().len(), 2);
+        assert_eq!(idx.find_by_id_prefix("abd3").unwrap().len(), 1);
+        assert_eq!(idx.find_by_id_prefix("x%").unwrap().len(), 1);
+        assert_eq!(idx.find_by_id_prefix("%").unwrap().len(), 0);
+        assert_eq!(idx.find_by_id_prefix("abe").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn id_prefix_lookup_uses_index() {
+        let dir = tempdir().unwrap();
+        let idx = new_index(dir.path());
+        let plan: String = idx
+            .conn
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT id FROM chunks WHERE id >= ?1 AND id < ?2 LIMIT 2",
+                rusqlite::params!["abc", "abd"],
+                |row| row.get(3),
+            )
+            .unwrap();
+        assert!(plan.starts_with("SEARCH"), "{}", plan);
+    }
+
     #[test]
     fn removes_inserted() {
         let id: ChunkId = "id001".parse().unwrap();
@@ -115,18 +359,148 @@ mod test {
         let meta = ChunkMeta::new(&sum);
         let dir = tempdir().unwrap();
         let mut idx = new_index(dir.path());
-        idx.insert_meta(id.clone(), meta).unwrap();
+        idx.insert_meta(id.clone(), meta, 3).unwrap();
         idx.remove_meta(&id).unwrap();
         let ids: Vec<ChunkId> = idx.find_by_label(&sum.serialize()).unwrap();
         assert_eq!(ids, vec![]);
     }
+
+    #[test]
+    fn rebuild_replaces_index() {
+        let old: ChunkId = "id001".parse().unwrap();
+        let new: ChunkId = "id002".parse().unwrap();
+        let sum = Label::sha256(b"abc");
+        let meta = ChunkMeta::new(&sum);
+        let dir = tempdir().unwrap();
+        {
+            let mut idx = new_index(dir.path());
+            idx.insert_meta(old.clone(), meta.clone(), 3).unwrap();
+        }
+        let stats = ChunkStats::new(3);
+        let gen: ChunkId = "gen001".parse().unwrap();
+        Index::rebuild(
+            dir.path(),
+            &[(new.clone(), meta.clone(), stats)],
+            &[(gen.clone(), new.clone())],
+        )
+        .unwrap();
+        let idx = new_index(dir.path());
+        assert!(idx.get_meta(&old).is_err());
+        assert_eq!(idx.get_meta(&new).unwrap(), meta);
+        assert_eq!(idx.get_stats(&new).unwrap(), stats);
+        assert_eq!(idx.all_refs().unwrap(), vec![(gen, new)]);
+    }
+
+    #[test]
+    fn remembers_size() {
+        let id: ChunkId = "id001".parse().unwrap();
+        let meta = ChunkMeta::new(&Label::sha256(b"abc"));
+        let dir = tempdir().unwrap();
+        let mut idx = new_index(dir.path());
+        idx.insert_meta(id.clone(), meta, 42).unwrap();
+        let stats = idx.get_stats(&id).unwrap();
+        assert_eq!(stats.size, Some(42));
+        assert!(stats.created.is_some());
+        assert_eq!(stats.epoch, None);
+        assert_eq!(idx.total_size().unwrap(), 42);
+    }
+
+    #[test]
+    fn counts_references() {
+        let gen1: ChunkId = "gen1".parse().unwrap();
+        let gen2: ChunkId = "gen2".parse().unwrap();
+        let a: ChunkId = "a".parse().unwrap();
+        let b: ChunkId = "b".parse().unwrap();
+        let dir = tempdir().unwrap();
+        let mut idx = new_index(dir.path());
+        idx.register_generation(&gen1, &[a.clone(), b.clone()])
+            .unwrap();
+        idx.register_generation(&gen2, std::slice::from_ref(&b))
+            .unwrap();
+        idx.register_generation(&gen2, std::slice::from_ref(&b))
+            .unwrap();
+        assert_eq!(idx.ref_count(&a).unwrap(), 1);
+        assert_eq!(idx.ref_count(&b).unwrap(), 2);
+        let mut gens = idx.generations().unwrap();
+        gens.sort_by_key(|id| id.to_string());
+        assert_eq!(gens, vec![gen1.clone(), gen2.clone()]);
+    }
+
+    #[test]
+    fn finds_unshared_chunks() {
+        let gen1: ChunkId = "gen1".parse().unwrap();
+        let gen2: ChunkId = "gen2".parse().unwrap();
+        let gen3: ChunkId = "gen3".parse().unwrap();
+        let a: ChunkId = "a".parse().unwrap();
+        let b: ChunkId = "b".parse().unwrap();
+        let c: ChunkId = "c".parse().unwrap();
+        let dir = tempdir().unwrap();
+        let mut idx = new_index(dir.path());
+        idx.register_generation(&gen1, &[a.clone(), b.clone()])
+            .unwrap();
+        idx.register_generation(&gen2, &[b.clone(), c.clone()])
+            .unwrap();
+        idx.register_generation(&gen3, std::slice::from_ref(&c))
+            .unwrap();
+        assert_eq!(
+            idx.unshared_chunks(std::slice::from_ref(&gen1)).unwrap(),
+            vec![a.clone()]
+        );
+        assert_eq!(
+            idx.unshared_chunks(&[gen1.clone(), gen2.clone()]).unwrap(),
+            vec![a.clone(), b.clone()]
+        );
+    }
+
+    #[test]
+    fn unregistering_returns_unused_chunks() {
+        let gen1: ChunkId = "gen1".parse().unwrap();
+        let gen2: ChunkId = "gen2".parse().unwrap();
+        let a: ChunkId = "a".parse().unwrap();
+        let b: ChunkId = "b".parse().unwrap();
+        let dir = tempdir().unwrap();
+        let mut idx = new_index(dir.path());
+        idx.register_generation(&gen1, &[a.clone(), b.clone()])
+            .unwrap();
+        idx.register_generation(&gen2, std::slice::from_ref(&b))
+            .unwrap();
+        assert_eq!(idx.unregister_generation(&gen1).unwrap(), vec![a.clone()]);
+        assert_eq!(idx.ref_count(&a).unwrap(), 0);
+        assert_eq!(idx.ref_count(&b).unwrap(), 1);
+        assert_eq!(idx.generations().unwrap(), vec![gen2]);
+    }
+
+    #[test]
+    fn migrates_old_schema() {
+        let id: ChunkId = "id001".parse().unwrap();
+        let sum = Label::sha256(b"abc");
+        let dir = tempdir().unwrap();
+        {
+            let conn = Connection::open(dir.path().join("meta.db")).unwrap();
+            conn.execute(
+                "CREATE TABLE chunks (id TEXT PRIMARY KEY, label TEXT)",
+                params![],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO chunks (id, label) VALUES (?1, ?2)",
+                params![id, sum.serialize()],
+            )
+            .unwrap();
+        }
+        let idx = new_index(dir.path());
+        assert_eq!(idx.get_meta(&id).unwrap(), ChunkMeta::new(&sum));
+        assert_eq!(idx.get_stats(&id).unwrap(), ChunkStats::default());
+        assert_eq!(idx.total_size().unwrap(), 0);
+        assert_eq!(idx.generations().unwrap(), vec![]);
+    }
 }
 
 mod sql {
-    use super::{IndexError, Label};
+    use super::{ChunkStats, IndexError, Label};
     use crate::chunkid::ChunkId;
     use crate::chunkmeta::ChunkMeta;
-    use log::error;
+    use log::{error, info};
     use rusqlite::{params, Connection, OpenFlags, Row, Transaction};
     use std::path::Path;
 
@@ -135,29 +509,99 @@ mod sql {
         let flags = OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_READ_WRITE;
         let conn = Connection::open_with_flags(filename, flags)?;
         conn.execute(
-            "CREATE TABLE chunks (id TEXT PRIMARY KEY, label TEXT)",
+            "CREATE TABLE chunks (id TEXT PRIMARY KEY, label TEXT, size INTEGER, created INTEGER, epoch INTEGER)",
             params![],
         )?;
         conn.execute("CREATE INDEX label_idx ON chunks (label)", params![])?;
+        conn.execute(
+            "CREATE TABLE refs (generation TEXT, chunk TEXT, PRIMARY KEY (generation, chunk))",
+            params![],
+        )?;
+        conn.execute("CREATE INDEX refs_chunk_idx ON refs (chunk)", params![])?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
         conn.pragma_update(None, "journal_mode", "WAL")?;
         Ok(conn)
     }
 
     /// Open an existing database in a file.
+    ///
+    /// A database with an older schema is upgraded.
     pub fn open_db(filename: &Path) -> Result<Connection, IndexError> {
         let flags = OpenFlags::SQLITE_OPEN_READ_WRITE;
         let conn = Connection::open_with_flags(filename, flags)?;
         conn.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&conn)?;
         Ok(conn)
     }
 
+    /// Open an existing database in a file, for reading only.
+    ///
+    /// Even reading a database in write-ahead logging mode creates
+    /// files next to it, unless it's opened as immutable. That's only
+    /// safe if there's no log left over from a server that stopped
+    /// abruptly, as the log would then be ignored.
+    pub fn open_db_read_only(filename: &Path) -> Result<Connection, IndexError> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY;
+        if super::sidecar(filename, "-wal").exists() {
+            return Ok(Connection::open_with_flags(filename, flags)?);
+        }
+        let mut uri = String::from("file:");
+        for c in filename.to_string_lossy().chars() {
+            match c {
+                '%' | '?' | '#' => uri.push_str(&format!("%{:02X}", c as u32)),
+                _ => uri.push(c),
+            }
+        }
+        uri.push_str("?immutable=1");
+        Ok(Connection::open_with_flags(
+            uri,
+            flags | OpenFlags::SQLITE_OPEN_URI,
+        )?)
+    }
+
+    // Version of the database schema, stored in SQLite's user_version.
+    // Version 0 only has the id and label columns. Version 1 has no
+    // refs table.
+    const SCHEMA_VERSION: i64 = 2;
+
+    fn migrate(conn: &Connection) -> Result<(), IndexError> {
+        let version: i64 = conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        if version < 1 {
+            info!("upgrading chunk index schema from version {}", version);
+            conn.execute_batch(
+                "BEGIN;
+                 ALTER TABLE chunks ADD COLUMN size INTEGER;
+                 ALTER TABLE chunks ADD COLUMN created INTEGER;
+                 ALTER TABLE chunks ADD COLUMN epoch INTEGER;
+                 PRAGMA user_version = 1;
+                 COMMIT;",
+            )?;
+        }
+        if version < 2 {
+            info!("adding chunk references to chunk index");
+            conn.execute_batch(
+                "BEGIN;
+                 CREATE TABLE refs (generation TEXT, chunk TEXT, PRIMARY KEY (generation, chunk));
+                 CREATE INDEX refs_chunk_idx ON refs (chunk);
+                 PRAGMA user_version = 2;
+                 COMMIT;",
+            )?;
+        }
+        Ok(())
+    }
+
     /// Insert a new chunk's metadata into database.
-    pub fn insert(t: &Transaction, chunkid: &ChunkId, meta: &ChunkMeta) -> Result<(), IndexError> {
+    pub fn insert(
+        t: &Transaction,
+        chunkid: &ChunkId,
+        meta: &ChunkMeta,
+        stats: &ChunkStats,
+    ) -> Result<(), IndexError> {
         let chunkid = format!("{}", chunkid);
         let label = meta.label();
         t.execute(
-            "INSERT INTO chunks (id, label) VALUES (?1, ?2)",
-            params![chunkid, label],
+            "INSERT INTO chunks (id, label, size, created, epoch) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![chunkid, label, stats.size, stats.created, stats.epoch],
         )?;
         Ok(())
     }
@@ -190,6 +634,28 @@ mod sql {
         Ok(r)
     }
 
+    /// Look up size and age of a chunk using its id.
+    pub fn lookup_stats(conn: &Connection, id: &ChunkId) -> Result<ChunkStats, IndexError> {
+        let mut stmt = conn.prepare("SELECT size, created, epoch FROM chunks WHERE id IS ?1")?;
+        let mut rows = stmt.query_map(params![id], row_to_stats)?;
+        match (rows.next(), rows.next()) {
+            (None, _) => Err(IndexError::MissingChunk(id.clone())),
+            (Some(stats), None) => Ok(stats?),
+            (Some(_), Some(_)) => {
+                let err = IndexError::DuplicateChunk(id.clone());
+                error!("{}", err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Sum the known sizes of all chunks.
+    pub fn total_size(conn: &Connection) -> Result<u64, IndexError> {
+        let total: Option<u64> =
+            conn.query_row("SELECT SUM(size) FROM chunks", params![], |row| row.get(0))?;
+        Ok(total.unwrap_or(0))
+    }
+
     /// Find chunks with a given checksum.
     pub fn find_by_label(conn: &Connection, label: &str) -> Result<Vec<ChunkId>, IndexError> {
         let mut stmt = conn.prepare("SELECT id FROM chunks WHERE label IS ?1")?;
@@ -202,6 +668,46 @@ mod sql {
         Ok(ids)
     }
 
+    /// Find at most two chunks whose id starts with a prefix.
+    ///
+    /// The ids starting with the prefix are a range of the primary
+    /// key, so the lookup uses its index.
+    pub fn find_by_id_prefix(conn: &Connection, prefix: &str) -> Result<Vec<ChunkId>, IndexError> {
+        let mut stmt;
+        let iter = match prefix_end(prefix) {
+            Some(end) => {
+                stmt = conn.prepare("SELECT id FROM chunks WHERE id >= ?1 AND id < ?2 LIMIT 2")?;
+                stmt.query_map(params![prefix, end], row_to_id)?
+            }
+            None => {
+                stmt = conn.prepare("SELECT id FROM chunks WHERE id >= ?1 LIMIT 2")?;
+                stmt.query_map(params![prefix], row_to_id)?
+            }
+        };
+        let mut ids = vec![];
+        for x in iter {
+            let x = x?;
+            ids.push(x);
+        }
+        Ok(ids)
+    }
+
+    // The smallest string that's larger than every string starting with
+    // a prefix, or None if there isn't one. Text is compared byte by
+    // byte, and UTF-8 sorts like code points, so it's the prefix with
+    // its last character replaced by the next one.
+    fn prefix_end(prefix: &str) -> Option<String> {
+        let mut chars: Vec<char> = prefix.chars().collect();
+        while let Some(last) = chars.pop() {
+            let next = (u32::from(last) + 1..=u32::from(char::MAX)).find_map(char::from_u32);
+            if let Some(next) = next {
+                chars.push(next);
+                return Some(chars.into_iter().collect());
+            }
+        }
+        None
+    }
+
     /// Find ids of all chunks.
     pub fn find_chunk_ids(conn: &Connection) -> Result<Vec<ChunkId>, IndexError> {
         let mut stmt = conn.prepare("SELECT id FROM chunks")?;
@@ -214,14 +720,88 @@ mod sql {
         Ok(ids)
     }
 
+    /// Record that a generation uses a chunk.
+    pub fn insert_ref(t: &Transaction, gen: &ChunkId, chunk: &ChunkId) -> Result<(), IndexError> {
+        t.execute(
+            "INSERT OR IGNORE INTO refs (generation, chunk) VALUES (?1, ?2)",
+            params![gen, chunk],
+        )?;
+        Ok(())
+    }
+
+    /// Remove all records of chunks a generation uses.
+    pub fn remove_refs(t: &Transaction, gen: &ChunkId) -> Result<(), IndexError> {
+        t.execute("DELETE FROM refs WHERE generation IS ?1", params![gen])?;
+        Ok(())
+    }
+
+    /// Find the chunks a generation uses.
+    pub fn find_refs(conn: &Connection, gen: &ChunkId) -> Result<Vec<ChunkId>, IndexError> {
+        let mut stmt = conn.prepare("SELECT chunk FROM refs WHERE generation IS ?1")?;
+        let iter = stmt.query_map(params![gen], |row| row_to_ref(row, "chunk"))?;
+        let mut ids = vec![];
+        for x in iter {
+            ids.push(x?);
+        }
+        Ok(ids)
+    }
+
+    /// Find the generations that use a chunk.
+    pub fn find_referrers(conn: &Connection, chunk: &ChunkId) -> Result<Vec<ChunkId>, IndexError> {
+        let mut stmt = conn.prepare("SELECT generation FROM refs WHERE chunk IS ?1")?;
+        let iter = stmt.query_map(params![chunk], |row| row_to_ref(row, "generation"))?;
+        let mut ids = vec![];
+        for x in iter {
+            ids.push(x?);
+        }
+        Ok(ids)
+    }
+
+    /// Find all uses of chunks by generations.
+    pub fn find_all_refs(conn: &Connection) -> Result<Vec<(ChunkId, ChunkId)>, IndexError> {
+        let mut stmt = conn.prepare("SELECT generation, chunk FROM refs")?;
+        let iter = stmt.query_map(params![], |row| {
+            Ok((row_to_ref(row, "generation")?, row_to_ref(row, "chunk")?))
+        })?;
+        let mut refs = vec![];
+        for x in iter {
+            refs.push(x?);
+        }
+        Ok(refs)
+    }
+
+    /// Find all generations whose chunks have been recorded.
+    pub fn find_generations(conn: &Connection) -> Result<Vec<ChunkId>, IndexError> {
+        let mut stmt = conn.prepare("SELECT DISTINCT generation FROM refs")?;
+        let iter = stmt.query_map(params![], |row| row_to_ref(row, "generation"))?;
+        let mut ids = vec![];
+        for x in iter {
+            ids.push(x?);
+        }
+        Ok(ids)
+    }
+
     fn row_to_meta(row: &Row) -> rusqlite::Result<ChunkMeta> {
         let hash: String = row.get("label")?;
         let sha256 = Label::deserialize(&hash).expect("deserialize checksum from database");
         Ok(ChunkMeta::new(&sha256))
     }
 
+    fn row_to_stats(row: &Row) -> rusqlite::Result<ChunkStats> {
+        Ok(ChunkStats {
+            size: row.get("size")?,
+            created: row.get("created")?,
+            epoch: row.get("epoch")?,
+        })
+    }
+
     fn row_to_id(row: &Row) -> rusqlite::Result<ChunkId> {
         let id: String = row.get("id")?;
         Ok(ChunkId::recreate(&id))
     }
+
+    fn row_to_ref(row: &Row, column: &str) -> rusqlite::Result<ChunkId> {
+        let id: String = row.get(column)?;
+        Ok(ChunkId::recreate(&id))
+    }
 }
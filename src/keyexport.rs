@@ -0,0 +1,493 @@
+//! Export and import of keys, protected by a passphrase.
+//!
+//! Without the keys in the passwords file, backups can't be restored.
+//! To keep a copy somewhere safe, such as on paper, the keys can be
+//! exported as text. The text is encrypted with a key derived from a
+//! passphrase, and armored so that it's easy to print and type in.
+//!
+//! The key is derived with Argon2id, unless configured otherwise. The
+//! function and its parameters are stored in the export, so that
+//! exports made with other parameters, or with PBKDF2 by older versions
+//! of Obnam, can still be imported.
+
+use crate::passwords::Passwords;
+use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use pbkdf2::{
+    password_hash::{PasswordHasher, SaltString},
+    Params, Pbkdf2,
+};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+const BEGIN: &str = "-----BEGIN OBNAM KEYS-----";
+const END: &str = "-----END OBNAM KEYS-----";
+
+// Start of an exported blob, before it's armored, in the format of
+// older versions of Obnam, which always used PBKDF2. It's also used as
+// associated data when encrypting, so it can't be changed.
+const EXPORT_V1: &[u8] = b"OBNAMKEY1";
+
+// Start of an exported blob in the current format. It's followed by
+// the key derivation function and its parameters, and all of that is
+// used as associated data when encrypting.
+const EXPORT_V2: &[u8] = b"OBNAMKEY2";
+
+// Identifiers of key derivation functions in an export.
+const KDF_PBKDF2: u8 = 1;
+const KDF_ARGON2ID: u8 = 2;
+
+// Length of armored lines.
+const LINE_LEN: usize = 64;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+// Default parameters for key derivation. They're stored in the
+// export, so they can be raised later. The Argon2id ones are those
+// recommended by OWASP.
+const DEFAULT_ROUNDS: u32 = 100_000;
+const DEFAULT_MEMORY_KIB: u32 = 19 * 1024;
+const DEFAULT_ITERATIONS: u32 = 2;
+const DEFAULT_LANES: u32 = 1;
+
+// Largest parameters accepted for key derivation, so that a damaged or
+// hostile export can't make importing it take hours, or all memory.
+const MAX_ROUNDS: u32 = 10_000_000;
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ITERATIONS: u32 = 100;
+const MAX_LANES: u32 = 64;
+
+/// How the key that encrypts exported keys is derived from the
+/// passphrase.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Kdf {
+    /// PBKDF2 with HMAC-SHA256, as used by older versions of Obnam.
+    Pbkdf2 {
+        /// Number of rounds.
+        #[serde(default = "default_rounds")]
+        rounds: u32,
+    },
+
+    /// Argon2id. It needs a lot of memory, which makes guessing the
+    /// passphrase with special hardware expensive.
+    Argon2id {
+        /// Memory to use, in KiB.
+        #[serde(default = "default_memory_kib")]
+        memory_kib: u32,
+        /// Number of passes over the memory.
+        #[serde(default = "default_iterations")]
+        iterations: u32,
+        /// Degree of parallelism.
+        #[serde(default = "default_lanes")]
+        lanes: u32,
+    },
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Self::Argon2id {
+            memory_kib: DEFAULT_MEMORY_KIB,
+            iterations: DEFAULT_ITERATIONS,
+            lanes: DEFAULT_LANES,
+        }
+    }
+}
+
+fn default_rounds() -> u32 {
+    DEFAULT_ROUNDS
+}
+
+fn default_memory_kib() -> u32 {
+    DEFAULT_MEMORY_KIB
+}
+
+fn default_iterations() -> u32 {
+    DEFAULT_ITERATIONS
+}
+
+fn default_lanes() -> u32 {
+    DEFAULT_LANES
+}
+
+impl Kdf {
+    /// Are the parameters within what Obnam accepts when importing
+    /// keys? Keys exported with larger ones can't be imported.
+    pub fn is_within_limits(&self) -> bool {
+        match *self {
+            Self::Pbkdf2 { rounds } => rounds <= MAX_ROUNDS,
+            Self::Argon2id {
+                memory_kib,
+                iterations,
+                lanes,
+            } => memory_kib <= MAX_MEMORY_KIB && iterations <= MAX_ITERATIONS && lanes <= MAX_LANES,
+        }
+    }
+
+    // The function and its parameters, as stored in an export.
+    fn encode(&self) -> Vec<u8> {
+        let (kind, params) = match *self {
+            Self::Pbkdf2 { rounds } => (KDF_PBKDF2, vec![rounds]),
+            Self::Argon2id {
+                memory_kib,
+                iterations,
+                lanes,
+            } => (KDF_ARGON2ID, vec![memory_kib, iterations, lanes]),
+        };
+        let mut bytes = vec![kind];
+        for param in params {
+            bytes.extend_from_slice(&param.to_be_bytes());
+        }
+        bytes
+    }
+
+    // Parse the function and its parameters at the start of the data,
+    // and return them and the rest of the data.
+    fn decode(data: &[u8]) -> Result<(Self, &[u8]), KeyExportError> {
+        let (kind, rest) = data.split_first().ok_or(KeyExportError::Malformed)?;
+        let count = match *kind {
+            KDF_PBKDF2 => 1,
+            KDF_ARGON2ID => 3,
+            _ => return Err(KeyExportError::Malformed),
+        };
+        if rest.len() < count * 4 {
+            return Err(KeyExportError::Malformed);
+        }
+        let (params, rest) = rest.split_at(count * 4);
+        let params: Vec<u32> = params
+            .chunks(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        let kdf = match *kind {
+            KDF_PBKDF2 => Self::Pbkdf2 { rounds: params[0] },
+            _ => Self::Argon2id {
+                memory_kib: params[0],
+                iterations: params[1],
+                lanes: params[2],
+            },
+        };
+        Ok((kdf, rest))
+    }
+}
+
+/// Possible errors from exporting or importing keys.
+#[derive(Debug, thiserror::Error)]
+pub enum KeyExportError {
+    /// Couldn't turn the keys into YAML.
+    #[error("failed to serialize keys: {0}")]
+    Serialize(serde_yaml::Error),
+
+    /// Couldn't parse the imported keys.
+    #[error("failed to parse imported keys: {0}")]
+    Parse(serde_yaml::Error),
+
+    /// Couldn't derive a key from the passphrase.
+    #[error("failed to derive key from passphrase: {0}")]
+    Kdf(pbkdf2::password_hash::Error),
+
+    /// Couldn't derive a key from the passphrase with Argon2id.
+    #[error("failed to derive key from passphrase with Argon2id: {0}")]
+    Argon2(argon2::Error),
+
+    /// Couldn't encrypt the keys.
+    #[error("failed to encrypt keys")]
+    Encrypt,
+
+    /// The text doesn't look like exported keys.
+    #[error("not exported Obnam keys: missing or malformed {BEGIN} and {END} lines")]
+    NotArmored,
+
+    /// The armored text isn't valid base64.
+    #[error("exported keys are damaged: {0}")]
+    Base64(base64::DecodeError),
+
+    /// The exported data is too short or of an unknown version.
+    #[error("exported keys are damaged or in an unknown format")]
+    Malformed,
+
+    /// The keys couldn't be decrypted.
+    #[error("failed to decrypt keys: wrong passphrase, or damaged export")]
+    Decrypt,
+}
+
+/// Export keys as armored text, encrypted with a key derived from a
+/// passphrase.
+pub fn export(
+    passwords: &Passwords,
+    passphrase: &str,
+    kdf: &Kdf,
+) -> Result<String, KeyExportError> {
+    let yaml = serde_yaml::to_string(passwords).map_err(KeyExportError::Serialize)?;
+
+    let mut salt = [0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut header = EXPORT_V2.to_vec();
+    header.extend_from_slice(&kdf.encode());
+
+    let cipher = cipher(passphrase, &salt, kdf)?;
+    let payload = Payload {
+        msg: yaml.as_bytes(),
+        aad: &header,
+    };
+    let ciphertext = cipher
+        .encrypt(GenericArray::from_slice(&nonce), payload)
+        .map_err(|_| KeyExportError::Encrypt)?;
+
+    let mut blob = header;
+    blob.extend_from_slice(&salt);
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&ciphertext);
+
+    let encoded = base64::encode(&blob);
+    let mut armored = format!("{}\n", BEGIN);
+    for line in encoded.as_bytes().chunks(LINE_LEN) {
+        armored.push_str(&String::from_utf8_lossy(line));
+        armored.push('\n');
+    }
+    armored.push_str(END);
+    armored.push('\n');
+    Ok(armored)
+}
+
+/// Does text look like armored keys?
+pub fn is_armored(text: &str) -> bool {
+    text.lines().any(|line| line.trim() == BEGIN)
+}
+
+/// Import keys from armored text, decrypting them with a passphrase.
+///
+/// Text before the first line and after the last line of the armor is
+/// ignored, as is whitespace inside it.
+pub fn import(armored: &str, passphrase: &str) -> Result<Passwords, KeyExportError> {
+    let lines: Vec<&str> = armored.lines().map(|line| line.trim()).collect();
+    let begin = lines.iter().position(|line| *line == BEGIN);
+    let end = lines.iter().position(|line| *line == END);
+    let encoded: String = match (begin, end) {
+        (Some(begin), Some(end)) if begin < end => lines[begin + 1..end]
+            .iter()
+            .flat_map(|line| line.split_whitespace())
+            .collect(),
+        _ => return Err(KeyExportError::NotArmored),
+    };
+    let blob = base64::decode(&encoded).map_err(KeyExportError::Base64)?;
+
+    let (kdf, rest, header) = if let Some(rest) = blob.strip_prefix(EXPORT_V2) {
+        let (kdf, rest) = Kdf::decode(rest)?;
+        (kdf, rest, &blob[..blob.len() - rest.len()])
+    } else if let Some(rest) = blob.strip_prefix(EXPORT_V1) {
+        if rest.len() < 4 {
+            return Err(KeyExportError::Malformed);
+        }
+        let (rounds, rest) = rest.split_at(4);
+        let rounds = u32::from_be_bytes([rounds[0], rounds[1], rounds[2], rounds[3]]);
+        (Kdf::Pbkdf2 { rounds }, rest, EXPORT_V1)
+    } else {
+        return Err(KeyExportError::Malformed);
+    };
+    if !kdf.is_within_limits() {
+        return Err(KeyExportError::Malformed);
+    }
+    if rest.len() < SALT_LEN + NONCE_LEN {
+        return Err(KeyExportError::Malformed);
+    }
+    let (salt, rest) = rest.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let cipher = cipher(passphrase, salt, &kdf)?;
+    let payload = Payload {
+        msg: ciphertext,
+        aad: header,
+    };
+    let yaml = cipher
+        .decrypt(GenericArray::from_slice(nonce), payload)
+        .map_err(|_| KeyExportError::Decrypt)?;
+    serde_yaml::from_slice(&yaml).map_err(KeyExportError::Parse)
+}
+
+// Derive an encryption key from a passphrase.
+fn cipher(passphrase: &str, salt: &[u8], kdf: &Kdf) -> Result<Aes256Gcm, KeyExportError> {
+    let key = match *kdf {
+        Kdf::Pbkdf2 { rounds } => pbkdf2(passphrase, salt, rounds)?,
+        Kdf::Argon2id {
+            memory_kib,
+            iterations,
+            lanes,
+        } => {
+            let config = argon2::Config {
+                variant: argon2::Variant::Argon2id,
+                version: argon2::Version::Version13,
+                mem_cost: memory_kib,
+                time_cost: iterations,
+                lanes,
+                hash_length: KEY_LEN as u32,
+                ..argon2::Config::default()
+            };
+            argon2::hash_raw(passphrase.as_bytes(), salt, &config)
+                .map_err(KeyExportError::Argon2)?
+        }
+    };
+    Ok(Aes256Gcm::new(GenericArray::from_slice(&key)))
+}
+
+fn pbkdf2(passphrase: &str, salt: &[u8], rounds: u32) -> Result<Vec<u8>, KeyExportError> {
+    let salt = SaltString::b64_encode(salt).map_err(KeyExportError::Kdf)?;
+    let params = Params {
+        rounds,
+        output_length: KEY_LEN,
+    };
+    let hash = Pbkdf2
+        .hash_password_customized(passphrase.as_bytes(), None, None, params, &salt)
+        .map_err(KeyExportError::Kdf)?;
+    let key = hash.hash.ok_or(KeyExportError::Malformed)?;
+    Ok(key.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        cipher, export, import, Kdf, KeyExportError, BEGIN, END, EXPORT_V1, EXPORT_V2,
+        MAX_MEMORY_KIB, NONCE_LEN, SALT_LEN,
+    };
+    use crate::passwords::Passwords;
+    use aes_gcm::aead::{generic_array::GenericArray, Aead, Payload};
+
+    #[test]
+    fn roundtrips_keys() {
+        let passwords = Passwords::from_master_key(&Passwords::generate_master_key());
+        let armored = export(&passwords, "hunter2", &Kdf::default()).unwrap();
+        assert!(armored.lines().all(|line| line.len() <= 64));
+
+        let text = format!("Obnam keys for my laptop\n\n{}\n", armored);
+        let imported = import(&text, "hunter2").unwrap();
+        assert_eq!(imported.encryption_key(), passwords.encryption_key());
+        assert_eq!(imported.signing_key(), passwords.signing_key());
+    }
+
+    #[test]
+    fn roundtrips_keys_with_other_kdf_parameters() {
+        let passwords = Passwords::from_master_key(&Passwords::generate_master_key());
+        for kdf in [
+            Kdf::Pbkdf2 { rounds: 1000 },
+            Kdf::Argon2id {
+                memory_kib: 64,
+                iterations: 1,
+                lanes: 2,
+            },
+        ] {
+            let armored = export(&passwords, "hunter2", &kdf).unwrap();
+            let imported = import(&armored, "hunter2").unwrap();
+            assert_eq!(imported.encryption_key(), passwords.encryption_key());
+        }
+    }
+
+    #[test]
+    fn imports_keys_exported_with_pbkdf2_by_older_versions() {
+        let passwords = Passwords::from_master_key(&Passwords::generate_master_key());
+        let yaml = serde_yaml::to_string(&passwords).unwrap();
+        let rounds: u32 = 1000;
+        let salt = [1; SALT_LEN];
+        let nonce = [2; NONCE_LEN];
+        let ciphertext = cipher("hunter2", &salt, &Kdf::Pbkdf2 { rounds })
+            .unwrap()
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: yaml.as_bytes(),
+                    aad: EXPORT_V1,
+                },
+            )
+            .unwrap();
+        let mut blob = EXPORT_V1.to_vec();
+        blob.extend_from_slice(&rounds.to_be_bytes());
+        blob.extend_from_slice(&salt);
+        blob.extend_from_slice(&nonce);
+        blob.extend_from_slice(&ciphertext);
+        let armored = format!("{}\n{}\n{}\n", BEGIN, base64::encode(&blob), END);
+
+        let imported = import(&armored, "hunter2").unwrap();
+        assert_eq!(imported.encryption_key(), passwords.encryption_key());
+    }
+
+    #[test]
+    fn rejects_excessive_kdf_parameters() {
+        let too_large = [
+            Kdf::Pbkdf2 { rounds: u32::MAX },
+            Kdf::Argon2id {
+                memory_kib: MAX_MEMORY_KIB + 1,
+                iterations: 1,
+                lanes: 1,
+            },
+            Kdf::Argon2id {
+                memory_kib: 64,
+                iterations: u32::MAX,
+                lanes: 1,
+            },
+            Kdf::Argon2id {
+                memory_kib: 64,
+                iterations: 1,
+                lanes: u32::MAX,
+            },
+        ];
+        for kdf in too_large {
+            assert!(!kdf.is_within_limits());
+            let mut blob = EXPORT_V2.to_vec();
+            blob.extend_from_slice(&kdf.encode());
+            blob.extend_from_slice(&[0; SALT_LEN + NONCE_LEN + 64]);
+            let armored = format!("{}\n{}\n{}\n", BEGIN, base64::encode(&blob), END);
+            assert!(matches!(
+                import(&armored, "hunter2"),
+                Err(KeyExportError::Malformed)
+            ));
+        }
+
+        let mut blob = EXPORT_V1.to_vec();
+        blob.extend_from_slice(&u32::MAX.to_be_bytes());
+        blob.extend_from_slice(&[0; SALT_LEN + NONCE_LEN + 64]);
+        let armored = format!("{}\n{}\n{}\n", BEGIN, base64::encode(&blob), END);
+        assert!(matches!(
+            import(&armored, "hunter2"),
+            Err(KeyExportError::Malformed)
+        ));
+        assert!(Kdf::default().is_within_limits());
+    }
+
+    #[test]
+    fn parses_kdf_settings() {
+        let kdf: Kdf = serde_yaml::from_str("kind: argon2id\nmemory_kib: 65536\n").unwrap();
+        assert_eq!(
+            kdf,
+            Kdf::Argon2id {
+                memory_kib: 65536,
+                iterations: 2,
+                lanes: 1
+            }
+        );
+        let kdf: Kdf = serde_yaml::from_str("kind: pbkdf2\n").unwrap();
+        assert_eq!(kdf, Kdf::Pbkdf2 { rounds: 100_000 });
+        assert!(serde_yaml::from_str::<Kdf>("kind: scrypt\n").is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_passphrase() {
+        let passwords = Passwords::from_master_key(&Passwords::generate_master_key());
+        let armored = export(&passwords, "hunter2", &Kdf::default()).unwrap();
+        assert!(matches!(
+            import(&armored, "hunter3"),
+            Err(KeyExportError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn rejects_text_without_armor() {
+        assert!(matches!(
+            import("hello, world", "hunter2"),
+            Err(KeyExportError::NotArmored)
+        ));
+    }
+}
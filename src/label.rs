@@ -6,11 +6,20 @@
 //! small number of carefully chosen algorithms are supported here.
 
 use blake2::Blake2s256;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 const LITERAL: char = '0';
 const SHA256: char = '1';
 const BLAKE2: char = '2';
+const BLAKE3: char = '3';
+const KEYED: char = '4';
+
+/// Length of a key for keyed checksums, in bytes.
+pub const LABEL_KEY_LEN: usize = 32;
+
+/// A key for keyed checksums.
+pub type LabelKey = [u8; LABEL_KEY_LEN];
 
 /// A checksum of some data.
 #[derive(Debug, Clone)]
@@ -23,6 +32,12 @@ pub enum Label {
 
     /// A BLAKE2s checksum.
     Blake2(String),
+
+    /// A BLAKE3 checksum.
+    Blake3(String),
+
+    /// A keyed BLAKE3 checksum.
+    Keyed(String),
 }
 
 impl Label {
@@ -47,35 +62,103 @@ impl Label {
         Self::Sha256(format!("{:x}", hash))
     }
 
+    /// Compute a BLAKE3 checksum for a block of data.
+    pub fn blake3(data: &[u8]) -> Self {
+        let hash = blake3::hash(data);
+        Self::Blake3(hash.to_hex().to_string())
+    }
+
+    /// Compute a keyed BLAKE3 checksum for a block of data.
+    ///
+    /// Without the key, the checksum can't be computed, so the label
+    /// doesn't reveal if the data is that of some known file.
+    pub fn keyed(key: &LabelKey, data: &[u8]) -> Self {
+        let hash = blake3::keyed_hash(key, data);
+        Self::Keyed(hash.to_hex().to_string())
+    }
+
+    /// Return the checksum, as a hexadecimal string, or the literal
+    /// string, without the type prefix of the serialized form.
+    pub fn checksum(&self) -> &str {
+        match self {
+            Self::Literal(s) => s,
+            Self::Sha256(hash) | Self::Blake2(hash) | Self::Blake3(hash) | Self::Keyed(hash) => {
+                hash
+            }
+        }
+    }
+
+    /// Return the kind of checksum, or None for a literal string.
+    pub fn kind(&self) -> Option<LabelChecksumKind> {
+        match self {
+            Self::Literal(_) => None,
+            Self::Sha256(_) => Some(LabelChecksumKind::Sha256),
+            Self::Blake2(_) => Some(LabelChecksumKind::Blake2),
+            Self::Blake3(_) => Some(LabelChecksumKind::Blake3),
+            Self::Keyed(_) => Some(LabelChecksumKind::Keyed),
+        }
+    }
+
     /// Serialize a label into a string representation.
     pub fn serialize(&self) -> String {
         match self {
             Self::Literal(s) => format!("{}{}", LITERAL, s),
             Self::Sha256(hash) => format!("{}{}", SHA256, hash),
             Self::Blake2(hash) => format!("{}{}", BLAKE2, hash),
+            Self::Blake3(hash) => format!("{}{}", BLAKE3, hash),
+            Self::Keyed(hash) => format!("{}{}", KEYED, hash),
         }
     }
 
     /// De-serialize a label from its string representation.
+    ///
+    /// Checksum labels must be a hexadecimal string of the right
+    /// length for the checksum algorithm.
     pub fn deserialize(s: &str) -> Result<Self, LabelError> {
         if s.starts_with(LITERAL) {
             Ok(Self::Literal(s[1..].to_string()))
         } else if s.starts_with(SHA256) {
-            Ok(Self::Sha256(s[1..].to_string()))
+            Ok(Self::Sha256(checksum(s)?))
+        } else if s.starts_with(BLAKE2) {
+            Ok(Self::Blake2(checksum(s)?))
+        } else if s.starts_with(BLAKE3) {
+            Ok(Self::Blake3(checksum(s)?))
+        } else if s.starts_with(KEYED) {
+            Ok(Self::Keyed(checksum(s)?))
         } else {
             Err(LabelError::UnknownType(s.to_string()))
         }
     }
 }
 
+// Length of a hexadecimal SHA256, BLAKE2s, or BLAKE3 checksum, keyed or
+// not.
+const CHECKSUM_HEX_LEN: usize = 64;
+
+fn checksum(s: &str) -> Result<String, LabelError> {
+    let hash = &s[1..];
+    if hash.len() == CHECKSUM_HEX_LEN && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(hash.to_string())
+    } else {
+        Err(LabelError::BadChecksum(s.to_string()))
+    }
+}
+
 /// Kinds of checksum labels.
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum LabelChecksumKind {
     /// Use a Blake2 checksum.
     Blake2,
 
     /// Use a SHA256 checksum.
     Sha256,
+
+    /// Use a Blake3 checksum.
+    Blake3,
+
+    /// Use a keyed Blake3 checksum.
+    Keyed,
 }
 
 impl LabelChecksumKind {
@@ -85,6 +168,10 @@ impl LabelChecksumKind {
             Ok(Self::Sha256)
         } else if s == "blake2" {
             Ok(Self::Blake2)
+        } else if s == "blake3" {
+            Ok(Self::Blake3)
+        } else if s == "keyed" {
+            Ok(Self::Keyed)
         } else {
             Err(LabelError::UnknownType(s.to_string()))
         }
@@ -95,6 +182,40 @@ impl LabelChecksumKind {
         match self {
             Self::Sha256 => "sha256",
             Self::Blake2 => "blake2",
+            Self::Blake3 => "blake3",
+            Self::Keyed => "keyed",
+        }
+    }
+}
+
+/// Compute labels for chunks of data.
+///
+/// The key is only used for keyed checksums. It's derived from the
+/// client's secret keys, so the server can't compute it.
+#[derive(Debug, Clone, Copy)]
+pub struct Labeler {
+    kind: LabelChecksumKind,
+    key: LabelKey,
+}
+
+impl Labeler {
+    /// Create a labeler for a kind of checksum.
+    pub fn new(kind: LabelChecksumKind, key: LabelKey) -> Self {
+        Self { kind, key }
+    }
+
+    /// Kind of checksum used for labels.
+    pub fn kind(&self) -> LabelChecksumKind {
+        self.kind
+    }
+
+    /// Compute the label for a block of data.
+    pub fn label(&self, data: &[u8]) -> Label {
+        match self.kind {
+            LabelChecksumKind::Blake2 => Label::blake2(data),
+            LabelChecksumKind::Sha256 => Label::sha256(data),
+            LabelChecksumKind::Blake3 => Label::blake3(data),
+            LabelChecksumKind::Keyed => Label::keyed(&self.key, data),
         }
     }
 }
@@ -105,11 +226,48 @@ pub enum LabelError {
     /// Serialized label didn't start with a known type prefix.
     #[error("Unknown label: {0:?}")]
     UnknownType(String),
+
+    /// Serialized label's checksum is not well formed.
+    #[error("Malformed checksum in label: {0:?}")]
+    BadChecksum(String),
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Label, LabelChecksumKind};
+    use super::{Label, LabelChecksumKind, Labeler, SHA256};
+
+    #[test]
+    fn checksum_has_no_type_prefix() {
+        let label = Label::sha256(b"hello, world");
+        assert_eq!(
+            label.checksum(),
+            "09ca7e4eaa6e8ae9c7d261167129184883644d07dfba7cbfbc4c8a2e08360d5b"
+        );
+        assert_eq!(label.serialize(), format!("1{}", label.checksum()));
+    }
+
+    #[test]
+    fn labeler_makes_labels_of_its_kind() {
+        for kind in [
+            LabelChecksumKind::Sha256,
+            LabelChecksumKind::Blake3,
+            LabelChecksumKind::Keyed,
+        ] {
+            let label = Labeler::new(kind, [0; 32]).label(b"hello");
+            assert_eq!(label.kind(), Some(kind));
+        }
+
+        // A BLAKE2s labeler makes SHA256-kind labels with a BLAKE2s checksum.
+        let label = Labeler::new(LabelChecksumKind::Blake2, [0; 32]).label(b"hello");
+        assert_eq!(label.kind(), Some(LabelChecksumKind::Sha256));
+        assert_eq!(
+            label.checksum(),
+            "19213bacc58dee6dbde3ceb9a47cbb330b3d86f8cca8997eb00be456f140ca25"
+        );
+        assert_ne!(label.checksum(), Label::sha256(b"hello").checksum());
+
+        assert_eq!(Label::literal("hello").kind(), None);
+    }
 
     #[test]
     fn roundtrip_literal() {
@@ -129,9 +287,90 @@ mod test {
         assert_eq!(serialized, seri2);
     }
 
+    // BLAKE2s checksums are labelled with the SHA256 kind, so they
+    // serialize with the SHA256 prefix and parse back as SHA256 labels.
+    #[test]
+    fn blake2_label_is_sha256_kind() {
+        let label = Label::blake2(b"dummy data");
+        let blake2 = "3740e3c5252e66e28d6dc7e65ece1f6fb0c4814f8d79492c57c967435595221f";
+        assert_eq!(label.kind(), Some(LabelChecksumKind::Sha256));
+        assert_eq!(label.checksum(), blake2);
+        assert_eq!(label.serialize(), format!("{}{}", SHA256, blake2));
+
+        let de = Label::deserialize(&label.serialize()).unwrap();
+        assert_eq!(de.kind(), Some(LabelChecksumKind::Sha256));
+        assert_eq!(de.checksum(), blake2);
+    }
+
+    #[test]
+    fn roundtrip_blake3() {
+        let label = Label::blake3(b"dummy data");
+        let serialized = label.serialize();
+        assert!(serialized.starts_with('3'));
+        let de = Label::deserialize(&serialized).unwrap();
+        let seri2 = de.serialize();
+        assert_eq!(serialized, seri2);
+    }
+
+    // Labels of existing chunks must not change, or new backups would
+    // no longer de-duplicate against them, and restores couldn't verify
+    // them. Note that BLAKE2s labels have always been serialized with
+    // the SHA256 prefix.
+    #[test]
+    fn existing_labels_are_unchanged() {
+        assert_eq!(
+            Label::sha256(b"dummy data").serialize(),
+            "1797bb0abff798d7200af7685dca7901edffc52bf26500d5bd97282658ee24152"
+        );
+        assert_eq!(
+            Label::blake2(b"dummy data").serialize(),
+            "13740e3c5252e66e28d6dc7e65ece1f6fb0c4814f8d79492c57c967435595221f"
+        );
+    }
+
+    #[test]
+    fn keyed_label_depends_on_key() {
+        let data = b"dummy data";
+        let label = Label::keyed(&[1; 32], data).serialize();
+        assert!(label.starts_with('4'));
+        assert_eq!(Label::deserialize(&label).unwrap().serialize(), label);
+        assert_eq!(Label::keyed(&[1; 32], data).serialize(), label);
+        assert_ne!(Label::keyed(&[2; 32], data).serialize(), label);
+        assert_ne!(Label::blake3(data).serialize()[1..], label[1..]);
+    }
+
+    #[test]
+    fn labeler_ignores_key_for_unkeyed_checksums() {
+        let data = b"dummy data";
+        let one = Labeler::new(LabelChecksumKind::Sha256, [1; 32]);
+        let two = Labeler::new(LabelChecksumKind::Sha256, [2; 32]);
+        assert_eq!(one.label(data).serialize(), two.label(data).serialize());
+    }
+
+    #[test]
+    fn rejects_short_checksum() {
+        assert!(Label::deserialize("1abcdef").is_err());
+    }
+
+    #[test]
+    fn rejects_non_hex_checksum() {
+        let label = format!("1{}", "x".repeat(64));
+        assert!(Label::deserialize(&label).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_prefix() {
+        assert!(Label::deserialize("9abc").is_err());
+    }
+
     #[test]
     fn roundtrip_checksum_kind() {
-        for kind in [LabelChecksumKind::Sha256, LabelChecksumKind::Blake2] {
+        for kind in [
+            LabelChecksumKind::Sha256,
+            LabelChecksumKind::Blake2,
+            LabelChecksumKind::Blake3,
+            LabelChecksumKind::Keyed,
+        ] {
             assert_eq!(LabelChecksumKind::from(kind.serialize()).unwrap(), kind);
         }
     }
@@ -6,10 +6,13 @@
 #![deny(missing_docs)]
 
 pub mod accumulated_time;
+pub mod acme;
+pub mod api;
 pub mod backup_progress;
 pub mod backup_reason;
 pub mod backup_run;
 pub mod chunk;
+pub mod chunkcache;
 pub mod chunker;
 pub mod chunkid;
 pub mod chunkmeta;
@@ -17,6 +20,7 @@ pub mod chunkstore;
 pub mod cipher;
 pub mod client;
 pub mod cmd;
+pub mod concurrency;
 pub mod config;
 pub mod db;
 pub mod dbgen;
@@ -24,15 +28,34 @@ pub mod engine;
 pub mod error;
 pub mod fsentry;
 pub mod fsiter;
+pub mod gencache;
 pub mod generation;
 pub mod genlist;
 pub mod genmeta;
 pub mod index;
+pub mod keyexport;
 pub mod label;
+pub mod logging;
+pub mod network_stats;
+pub mod notify;
+pub mod passphrase;
 pub mod passwords;
+pub mod pathmatch;
 pub mod performance;
 pub mod policy;
+pub mod problem;
+pub mod progress_sink;
+pub mod proxy;
+pub mod recovery;
+pub mod refcount;
+pub mod reposettings;
+pub mod runlock;
+pub mod sampling;
 pub mod schema;
 pub mod server;
+pub mod snapshot;
 pub mod store;
 pub mod workqueue;
+
+#[cfg(test)]
+mod testing;
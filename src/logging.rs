@@ -0,0 +1,448 @@
+//! Logging for the client and the server.
+//!
+//! The client logs to the file named in its configuration, and to the
+//! standard error output. How much goes to each is set separately: the
+//! log file by the `log_level` setting, and the terminal by the `-v`
+//! and `-q` options. Errors are not logged to the terminal, as the
+//! client reports them there itself.
+//!
+//! The client and the server can also log to the system log, with
+//! syslog or journald, for unattended use. Both are spoken to directly
+//! over their Unix domain sockets.
+
+use crate::config::ClientConfig;
+use log::{Level, LevelFilter, Record};
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::file::FileAppender;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::Append;
+use log4rs::config::{Appender, Root};
+use log4rs::encode::json::JsonEncoder;
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::Encode;
+use log4rs::filter::threshold::ThresholdFilter;
+use log4rs::filter::{Filter, Response};
+use serde::{Deserialize, Serialize};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+// How many rotated log files to keep, if the configuration doesn't say.
+const DEFAULT_KEEP: u32 = 5;
+
+// Format of messages logged to the terminal.
+const CONSOLE_PATTERN: &str = "{l}: {m}{n}";
+
+// Sockets of the system logs.
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+// Names the client and the server log as, and their syslog facilities.
+const CLIENT_IDENT: &str = "obnam";
+const SERVER_IDENT: &str = "obnam-server";
+const FACILITY_USER: u8 = 1;
+const FACILITY_DAEMON: u8 = 3;
+
+/// How much to log.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// Nothing.
+    Off,
+    /// Only errors.
+    Error,
+    /// Warnings and errors.
+    Warn,
+    /// Also what the client does.
+    Info,
+    /// Also details useful when investigating problems, such as every
+    /// file backed up.
+    Debug,
+    /// Everything.
+    Trace,
+}
+
+impl Default for LogLevel {
+    fn default() -> Self {
+        Self::Info
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => Self::Off,
+            LogLevel::Error => Self::Error,
+            LogLevel::Warn => Self::Warn,
+            LogLevel::Info => Self::Info,
+            LogLevel::Debug => Self::Debug,
+            LogLevel::Trace => Self::Trace,
+        }
+    }
+}
+
+/// Format of the log file.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line of text per message.
+    Text,
+    /// One JSON object per line, for log processing tools.
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        Self::Text
+    }
+}
+
+/// When the log file is rotated.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogRotation {
+    /// Rotate the log file when it grows larger than this, in bytes.
+    pub max_size: u64,
+    /// How many rotated log files to keep. They're named after the
+    /// log file, with a number added, the most recent one being 1.
+    #[serde(default = "default_keep")]
+    pub keep: u32,
+}
+
+fn default_keep() -> u32 {
+    DEFAULT_KEEP
+}
+
+/// A system log to log to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SystemLog {
+    /// The syslog daemon, via `/dev/log`.
+    Syslog,
+    /// The systemd journal, with its native protocol.
+    Journald,
+}
+
+impl SystemLog {
+    fn socket(self) -> &'static Path {
+        match self {
+            Self::Syslog => Path::new(SYSLOG_SOCKET),
+            Self::Journald => Path::new(JOURNALD_SOCKET),
+        }
+    }
+}
+
+/// How much to log to the terminal: warnings by default, nothing when
+/// quiet, and more for each time verbose output is asked for.
+pub fn console_level(verbose: u8, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Off;
+    }
+    match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Start logging for the client to the log file, the terminal, and
+/// the system log, if the configuration names one.
+///
+/// The log file and the system log get messages up to `level`, if set,
+/// or the level in the configuration. The terminal gets them up to
+/// `console`.
+pub fn setup(
+    config: &ClientConfig,
+    level: Option<LogLevel>,
+    console: LevelFilter,
+) -> Result<(), LoggingError> {
+    let file_level = LevelFilter::from(level.unwrap_or(config.log_level));
+
+    let logfile: Box<dyn Append> = match &config.log_rotation {
+        None => Box::new(
+            FileAppender::builder()
+                .encoder(encoder(config.log_format))
+                .build(&config.log)
+                .map_err(|err| LoggingError::Open(config.log.clone(), err))?,
+        ),
+        Some(rotation) => {
+            let pattern = format!("{}.{{}}", config.log.display());
+            let roller = FixedWindowRoller::builder()
+                .base(1)
+                .build(&pattern, rotation.keep)
+                .map_err(LoggingError::Rotation)?;
+            let policy = CompoundPolicy::new(
+                Box::new(SizeTrigger::new(rotation.max_size)),
+                Box::new(roller),
+            );
+            Box::new(
+                RollingFileAppender::builder()
+                    .encoder(encoder(config.log_format))
+                    .build(&config.log, Box::new(policy))
+                    .map_err(|err| LoggingError::Open(config.log.clone(), err))?,
+            )
+        }
+    };
+
+    let stderr = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(Box::new(PatternEncoder::new(CONSOLE_PATTERN)))
+        .build();
+
+    let mut builder = log4rs::Config::builder()
+        .appender(
+            Appender::builder()
+                .filter(Box::new(ThresholdFilter::new(file_level)))
+                .build("file", logfile),
+        )
+        .appender(
+            Appender::builder()
+                .filter(Box::new(ThresholdFilter::new(console)))
+                .filter(Box::new(NotErrors))
+                .build("console", Box::new(stderr)),
+        );
+    let mut root = Root::builder().appender("file").appender("console");
+    if let Some(kind) = config.system_log {
+        let system = SystemLogAppender::new(kind, CLIENT_IDENT, FACILITY_USER, kind.socket())?;
+        builder = builder.appender(
+            Appender::builder()
+                .filter(Box::new(ThresholdFilter::new(file_level)))
+                .build("system", Box::new(system)),
+        );
+        root = root.appender("system");
+    }
+
+    let log4rs_config = builder
+        .build(root.build(file_level.max(console)))
+        .map_err(LoggingError::Config)?;
+    log4rs::init_config(log4rs_config).map_err(LoggingError::Init)?;
+    Ok(())
+}
+
+/// Start logging for the server to a system log, instead of the
+/// standard error output.
+pub fn setup_server(kind: SystemLog, level: LevelFilter) -> Result<(), LoggingError> {
+    let system = SystemLogAppender::new(kind, SERVER_IDENT, FACILITY_DAEMON, kind.socket())?;
+    let log4rs_config = log4rs::Config::builder()
+        .appender(Appender::builder().build("system", Box::new(system)))
+        .build(Root::builder().appender("system").build(level))
+        .map_err(LoggingError::Config)?;
+    log4rs::init_config(log4rs_config).map_err(LoggingError::Init)?;
+    Ok(())
+}
+
+fn encoder(format: LogFormat) -> Box<dyn Encode> {
+    match format {
+        LogFormat::Text => Box::new(PatternEncoder::default()),
+        LogFormat::Json => Box::new(JsonEncoder::new()),
+    }
+}
+
+// Send log messages to a system log. Each message is a datagram to
+// its socket.
+#[derive(Debug)]
+struct SystemLogAppender {
+    kind: SystemLog,
+    ident: &'static str,
+    facility: u8,
+    socket: UnixDatagram,
+}
+
+impl SystemLogAppender {
+    fn new(
+        kind: SystemLog,
+        ident: &'static str,
+        facility: u8,
+        path: &Path,
+    ) -> Result<Self, LoggingError> {
+        let socket = UnixDatagram::unbound()
+            .and_then(|socket| socket.connect(path).map(|_| socket))
+            .map_err(|err| LoggingError::SystemLog(path.to_path_buf(), err))?;
+        Ok(Self {
+            kind,
+            ident,
+            facility,
+            socket,
+        })
+    }
+
+    fn message(&self, record: &Record) -> Vec<u8> {
+        let severity = severity(record.level());
+        match self.kind {
+            SystemLog::Syslog => format!(
+                "<{}>{}[{}]: {}",
+                self.facility * 8 + severity,
+                self.ident,
+                std::process::id(),
+                record.args()
+            )
+            .into_bytes(),
+            SystemLog::Journald => {
+                let mut msg = vec![];
+                journal_field(&mut msg, "MESSAGE", &record.args().to_string());
+                journal_field(&mut msg, "PRIORITY", &severity.to_string());
+                journal_field(&mut msg, "SYSLOG_FACILITY", &self.facility.to_string());
+                journal_field(&mut msg, "SYSLOG_IDENTIFIER", self.ident);
+                if let Some(file) = record.file() {
+                    journal_field(&mut msg, "CODE_FILE", file);
+                }
+                if let Some(line) = record.line() {
+                    journal_field(&mut msg, "CODE_LINE", &line.to_string());
+                }
+                msg
+            }
+        }
+    }
+}
+
+impl Append for SystemLogAppender {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        self.socket.send(&self.message(record))?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+// Syslog severity of a log level.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// Add a field to a message for the journal. A value with newlines is
+// written as its length and the value, instead of after an equals sign.
+fn journal_field(msg: &mut Vec<u8>, name: &str, value: &str) {
+    msg.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        msg.push(b'\n');
+        msg.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        msg.push(b'=');
+    }
+    msg.extend_from_slice(value.as_bytes());
+    msg.push(b'\n');
+}
+
+// Reject errors, which the client reports on the terminal itself.
+#[derive(Debug)]
+struct NotErrors;
+
+impl Filter for NotErrors {
+    fn filter(&self, record: &Record) -> Response {
+        if record.level() == Level::Error {
+            Response::Reject
+        } else {
+            Response::Neutral
+        }
+    }
+}
+
+/// Possible errors from setting up logging.
+#[derive(Debug, thiserror::Error)]
+pub enum LoggingError {
+    /// The log file couldn't be opened.
+    #[error("failed to open log file {0}: {1}")]
+    Open(PathBuf, std::io::Error),
+
+    /// Rotation of the log file couldn't be set up.
+    #[error("failed to set up log file rotation: {0}")]
+    Rotation(anyhow::Error),
+
+    /// The system log couldn't be connected to.
+    #[error("failed to connect to system log at {0}: {1}")]
+    SystemLog(PathBuf, std::io::Error),
+
+    /// The logging configuration is wrong.
+    #[error("failed to configure logging: {0}")]
+    Config(log4rs::config::runtime::ConfigErrors),
+
+    /// Logging had already been started.
+    #[error("failed to start logging: {0}")]
+    Init(log::SetLoggerError),
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        console_level, LogLevel, LogRotation, SystemLog, SystemLogAppender, FACILITY_DAEMON,
+    };
+    use log::{Level, LevelFilter, Record};
+    use log4rs::append::Append;
+    use std::os::unix::net::UnixDatagram;
+    use tempfile::tempdir;
+
+    #[test]
+    fn console_level_follows_verbosity() {
+        assert_eq!(console_level(0, false), LevelFilter::Warn);
+        assert_eq!(console_level(1, false), LevelFilter::Info);
+        assert_eq!(console_level(2, false), LevelFilter::Debug);
+        assert_eq!(console_level(5, false), LevelFilter::Trace);
+        assert_eq!(console_level(0, true), LevelFilter::Off);
+    }
+
+    // Log a message through a system log appender to a socket, and
+    // return what the socket receives.
+    fn send_to_system_log(kind: SystemLog, message: &str) -> Vec<u8> {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("log");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        let appender = SystemLogAppender::new(kind, "obnam-test", FACILITY_DAEMON, &path).unwrap();
+        appender
+            .append(
+                &Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(Level::Warn)
+                    .line(Some(42))
+                    .build(),
+            )
+            .unwrap();
+        let mut buf = vec![0; 4096];
+        let n = socket.recv(&mut buf).unwrap();
+        buf.truncate(n);
+        buf
+    }
+
+    #[test]
+    fn logs_to_syslog() {
+        let msg = send_to_system_log(SystemLog::Syslog, "hello");
+        let expected = format!("<28>obnam-test[{}]: hello", std::process::id());
+        assert_eq!(String::from_utf8(msg).unwrap(), expected);
+    }
+
+    #[test]
+    fn logs_to_journald() {
+        let msg = send_to_system_log(SystemLog::Journald, "hello");
+        assert_eq!(
+            String::from_utf8(msg).unwrap(),
+            "MESSAGE=hello\nPRIORITY=4\nSYSLOG_FACILITY=3\nSYSLOG_IDENTIFIER=obnam-test\nCODE_LINE=42\n"
+        );
+
+        let msg = send_to_system_log(SystemLog::Journald, "two\nlines");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        assert!(msg.starts_with(&expected));
+    }
+
+    #[test]
+    fn parses_log_settings() {
+        let level: LogLevel = serde_yaml::from_str("debug").unwrap();
+        assert_eq!(LevelFilter::from(level), LevelFilter::Debug);
+        let rotation: LogRotation = serde_yaml::from_str("max_size: 1000").unwrap();
+        assert_eq!(
+            rotation,
+            LogRotation {
+                max_size: 1000,
+                keep: 5
+            }
+        );
+    }
+}
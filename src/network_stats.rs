@@ -0,0 +1,97 @@
+//! Count the HTTP traffic between the client and the server.
+//!
+//! The remote chunk store counts every request it makes, per API
+//! endpoint, and the bytes of request and response bodies. HTTP
+//! headers and TLS overhead aren't counted.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Shared counters for HTTP traffic.
+///
+/// Clones share the same counters.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkStats {
+    counts: Arc<Mutex<NetworkCounts>>,
+}
+
+/// The HTTP traffic counted so far.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize)]
+pub struct NetworkCounts {
+    /// Bytes sent in request bodies.
+    pub bytes_uploaded: u64,
+    /// Bytes received in response bodies.
+    pub bytes_downloaded: u64,
+    /// Requests made, by endpoint.
+    pub requests: BTreeMap<String, RequestCounts>,
+    /// Requests repeated, or replaced by others, because the first
+    /// attempt didn't work out.
+    pub retries: u64,
+}
+
+/// Requests made to one API endpoint.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize)]
+pub struct RequestCounts {
+    /// All requests.
+    pub count: u64,
+    /// Requests that got no response, or a server error response.
+    pub failed: u64,
+}
+
+impl NetworkStats {
+    /// Count a request to an endpoint, with a body of some size.
+    pub fn request(&self, endpoint: &str, bytes: u64, failed: bool) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.bytes_uploaded += bytes;
+        let endpoint = counts.requests.entry(endpoint.to_string()).or_default();
+        endpoint.count += 1;
+        if failed {
+            endpoint.failed += 1;
+        }
+    }
+
+    /// Count a response body of some size.
+    pub fn downloaded(&self, bytes: u64) {
+        self.counts.lock().unwrap().bytes_downloaded += bytes;
+    }
+
+    /// Count a retry.
+    pub fn retried(&self) {
+        self.counts.lock().unwrap().retries += 1;
+    }
+
+    /// Return the counts so far.
+    pub fn counts(&self) -> NetworkCounts {
+        self.counts.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NetworkStats, RequestCounts};
+
+    #[test]
+    fn counts_requests_per_endpoint() {
+        let stats = NetworkStats::default();
+        let shared = stats.clone();
+        stats.request("POST /v1/chunks", 100, false);
+        shared.request("POST /v1/chunks", 50, true);
+        stats.request("GET /v1/chunks/ID", 0, false);
+        stats.downloaded(42);
+        stats.retried();
+
+        let counts = shared.counts();
+        assert_eq!(counts.bytes_uploaded, 150);
+        assert_eq!(counts.bytes_downloaded, 42);
+        assert_eq!(counts.retries, 1);
+        assert_eq!(
+            counts.requests["POST /v1/chunks"],
+            RequestCounts {
+                count: 2,
+                failed: 1
+            }
+        );
+        assert_eq!(counts.requests["GET /v1/chunks/ID"].count, 1);
+    }
+}
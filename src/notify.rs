@@ -0,0 +1,347 @@
+//! Notifications when an operation has finished.
+//!
+//! The client can tell someone that a backup, a restore, or a restore
+//! test has finished, and how it went: by running a command, by
+//! sending a web hook request, or with a desktop notification. Each
+//! gets the same information, as JSON where possible. A notification
+//! that can't be sent is logged as a warning, but doesn't make the
+//! operation fail.
+
+use crate::error::ObnamError;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::{ErrorKind, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::Duration;
+
+// How long to wait for a web hook to respond.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Program that shows desktop notifications.
+const NOTIFY_SEND: &str = "notify-send";
+
+/// A way to send notifications.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Notifier {
+    /// Run a shell command, with the notification as JSON on its
+    /// standard input.
+    Command(String),
+    /// Send the notification as JSON in a POST request to a URL.
+    Webhook(String),
+    /// Show a desktop notification, with `notify-send`.
+    Desktop,
+}
+
+impl fmt::Display for Notifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Command(command) => write!(f, "command {:?}", command),
+            Self::Webhook(url) => write!(f, "web hook {}", url),
+            Self::Desktop => write!(f, "desktop notification"),
+        }
+    }
+}
+
+/// An operation that notifications are sent about.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    /// Making a backup.
+    Backup,
+    /// Restoring a backup.
+    Restore,
+    /// Checking that files in a backup can be restored.
+    RestoreTest,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Backup => "backup",
+            Self::Restore => "restore",
+            Self::RestoreTest => "restore-test",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How an operation went.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    /// It succeeded.
+    Ok,
+    /// It failed.
+    Failed,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// What a notification says.
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    /// The operation that finished.
+    pub operation: Operation,
+    /// How it went.
+    pub status: Status,
+    /// Name of the client.
+    pub client: String,
+    /// Why it failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What the operation did, such as how many files it backed up.
+    pub stats: Map<String, Value>,
+}
+
+impl Notification {
+    /// Create a notification about an operation that finished with
+    /// `error`, if any.
+    pub fn new(
+        client: &str,
+        operation: Operation,
+        error: Option<&ObnamError>,
+        stats: Map<String, Value>,
+    ) -> Self {
+        Self {
+            operation,
+            status: if error.is_some() {
+                Status::Failed
+            } else {
+                Status::Ok
+            },
+            client: client.to_string(),
+            error: error.map(|err| err.to_string()),
+            stats,
+        }
+    }
+
+    // A one line summary, for people.
+    fn title(&self) -> String {
+        match &self.error {
+            None => format!("Obnam {} finished", self.operation),
+            Some(err) => format!("Obnam {} failed: {}", self.operation, err),
+        }
+    }
+
+    // The stats, one per line, for people.
+    fn body(&self) -> String {
+        let mut lines = vec![format!("client: {}", self.client)];
+        for (name, value) in self.stats.iter() {
+            match value {
+                Value::String(s) => lines.push(format!("{}: {}", name, s)),
+                _ => lines.push(format!("{}: {}", name, value)),
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+/// Send a notification in every configured way.
+pub fn send(notifiers: &[Notifier], notification: &Notification) {
+    for notifier in notifiers {
+        info!("sending notification with {}", notifier);
+        if let Err(err) = send_one(notifier, notification) {
+            warn!("failed to send notification with {}: {}", notifier, err);
+        }
+    }
+}
+
+fn send_one(notifier: &Notifier, notification: &Notification) -> Result<(), NotifyError> {
+    match notifier {
+        Notifier::Command(command) => run_command(command, notification),
+        Notifier::Webhook(url) => post_webhook(url, notification),
+        Notifier::Desktop => show_desktop(notification),
+    }
+}
+
+// Run a command with the notification as JSON on its standard input.
+// The command may ignore its input. The operation and status are also
+// in environment variables, for simple commands.
+fn run_command(command: &str, notification: &Notification) -> Result<(), NotifyError> {
+    let json = serde_json::to_string(notification)?;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("OBNAM_OPERATION", notification.operation.to_string())
+        .env("OBNAM_STATUS", notification.status.to_string())
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| NotifyError::Command(command.to_string(), err))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        match stdin.write_all(json.as_bytes()) {
+            Err(err) if err.kind() != ErrorKind::BrokenPipe => {
+                return Err(NotifyError::Command(command.to_string(), err));
+            }
+            _ => (),
+        }
+    }
+    let status = child
+        .wait()
+        .map_err(|err| NotifyError::Command(command.to_string(), err))?;
+    if !status.success() {
+        return Err(NotifyError::CommandFailed(command.to_string(), status));
+    }
+    Ok(())
+}
+
+// POST the notification as JSON to a web hook.
+fn post_webhook(url: &str, notification: &Notification) -> Result<(), NotifyError> {
+    reqwest::blocking::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?
+        .post(url)
+        .json(notification)
+        .send()?
+        .error_for_status()?;
+    Ok(())
+}
+
+fn show_desktop(notification: &Notification) -> Result<(), NotifyError> {
+    let status = Command::new(NOTIFY_SEND)
+        .arg("--app-name=Obnam")
+        .arg(notification.title())
+        .arg(notification.body())
+        .status()
+        .map_err(|err| NotifyError::Command(NOTIFY_SEND.to_string(), err))?;
+    if !status.success() {
+        return Err(NotifyError::CommandFailed(NOTIFY_SEND.to_string(), status));
+    }
+    Ok(())
+}
+
+/// Possible errors from sending notifications.
+#[derive(Debug, thiserror::Error)]
+pub enum NotifyError {
+    /// A command couldn't be run.
+    #[error("failed to run {0:?}: {1}")]
+    Command(String, std::io::Error),
+
+    /// A command failed.
+    #[error("{0:?} failed: {1}")]
+    CommandFailed(String, ExitStatus),
+
+    /// A web hook request failed.
+    #[error(transparent)]
+    Webhook(#[from] reqwest::Error),
+
+    /// The notification couldn't be turned into JSON.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::{send_one, Notification, Notifier, Operation};
+    use crate::error::ObnamError;
+    use serde_json::{json, Map, Value};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    fn stats() -> Map<String, Value> {
+        let mut stats = Map::new();
+        stats.insert("file-count".to_string(), json!(42));
+        stats
+    }
+
+    #[test]
+    fn parses_notifiers() {
+        let notifiers: Vec<Notifier> = serde_yaml::from_str(
+            "- command: mail -s obnam root\n- webhook: https://example.com/hook\n- desktop\n",
+        )
+        .unwrap();
+        assert_eq!(
+            notifiers,
+            vec![
+                Notifier::Command("mail -s obnam root".to_string()),
+                Notifier::Webhook("https://example.com/hook".to_string()),
+                Notifier::Desktop,
+            ]
+        );
+    }
+
+    #[test]
+    fn serializes_failure() {
+        let err = ObnamError::FailOnWarning(3);
+        let n = Notification::new("laptop", Operation::Backup, Some(&err), stats());
+        let json = serde_json::to_value(&n).unwrap();
+        assert_eq!(json["operation"], "backup");
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["client"], "laptop");
+        assert_eq!(json["error"], err.to_string());
+        assert_eq!(json["stats"]["file-count"], 42);
+        assert!(n.title().starts_with("Obnam backup failed: "));
+    }
+
+    #[test]
+    fn gives_command_the_notification() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+        let command = format!(
+            "echo $OBNAM_OPERATION $OBNAM_STATUS > {0}; cat >> {0}",
+            output.display()
+        );
+        let n = Notification::new("laptop", Operation::RestoreTest, None, stats());
+        send_one(&Notifier::Command(command), &n).unwrap();
+        let output = std::fs::read_to_string(output).unwrap();
+        let (first, json) = output.split_once('\n').unwrap();
+        assert_eq!(first, "restore-test ok");
+        let json: Value = serde_json::from_str(json).unwrap();
+        assert_eq!(json["status"], "ok");
+        assert!(json.get("error").is_none());
+    }
+
+    #[test]
+    fn reports_failing_command() {
+        let n = Notification::new("laptop", Operation::Backup, None, stats());
+        assert!(send_one(&Notifier::Command("exit 1".to_string()), &n).is_err());
+    }
+
+    #[test]
+    fn posts_to_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            (request_line, body)
+        });
+
+        let n = Notification::new("laptop", Operation::Restore, None, stats());
+        send_one(&Notifier::Webhook(url), &n).unwrap();
+        let (request_line, body) = server.join().unwrap();
+        assert!(request_line.starts_with("POST /hook "));
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["operation"], "restore");
+        assert_eq!(json["stats"]["file-count"], 42);
+    }
+}
@@ -0,0 +1,227 @@
+//! The passphrase that protects the client's keys.
+//!
+//! The keys in the passwords file can be encrypted with a passphrase,
+//! the same way as exported keys. The passphrase is then needed every
+//! time the keys are used. It's taken from the first of these that is
+//! set: a file descriptor given with `--passphrase-fd`, the
+//! `OBNAM_PASSPHRASE` environment variable, and the output of the
+//! `password_command` in the configuration. If none are set, it's
+//! asked for on the terminal.
+
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process::{Command, Stdio};
+
+/// Environment variable with the passphrase.
+pub const PASSPHRASE_VAR: &str = "OBNAM_PASSPHRASE";
+
+const PROMPT: &str = "Passphrase for keys: ";
+
+/// A passphrase. It's not shown in debug output, which may be logged.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Passphrase(String);
+
+impl Passphrase {
+    /// Create a passphrase from text.
+    pub fn new(text: &str) -> Self {
+        Self(text.to_string())
+    }
+
+    /// The passphrase as text.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Passphrase(***)")
+    }
+}
+
+/// Where to get the passphrase from.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PassphraseSource {
+    /// The passphrase already read from a file descriptor, if any.
+    #[serde(skip)]
+    pub from_fd: Option<Passphrase>,
+    /// Shell command that writes the passphrase to its standard
+    /// output, if any.
+    pub command: Option<String>,
+}
+
+impl PassphraseSource {
+    /// Get the passphrase without asking for it, if it's set anywhere.
+    pub fn get(&self) -> Result<Option<Passphrase>, PassphraseError> {
+        if let Some(passphrase) = &self.from_fd {
+            return Ok(Some(passphrase.clone()));
+        }
+        if let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) {
+            return Ok(Some(Passphrase(passphrase)));
+        }
+        match &self.command {
+            Some(command) => run_command(command).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the passphrase, asking for it on the terminal if it isn't
+    /// set anywhere.
+    pub fn get_or_ask(&self) -> Result<Passphrase, PassphraseError> {
+        match self.get()? {
+            Some(passphrase) => Ok(passphrase),
+            None => rpassword::read_password_from_tty(Some(PROMPT))
+                .map(Passphrase)
+                .map_err(PassphraseError::Ask),
+        }
+    }
+}
+
+/// Read a passphrase from an open file descriptor. Only the first
+/// line is used, and nothing after it is read, so the writer doesn't
+/// need to close its end. The descriptor is left open.
+pub fn read_fd(fd: RawFd) -> Result<Passphrase, PassphraseError> {
+    // SAFETY: fcntl and dup only look at the file descriptor.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(PassphraseError::Fd(fd, std::io::Error::last_os_error()));
+    }
+    let copy = unsafe { libc::dup(fd) };
+    if copy == -1 {
+        return Err(PassphraseError::Fd(fd, std::io::Error::last_os_error()));
+    }
+    // SAFETY: the copy was just made, and nothing else uses it.
+    let mut file = unsafe { File::from_raw_fd(copy) };
+
+    // Read one byte at a time, so that nothing past the first line is
+    // consumed. The newline is kept, so that `first_line` strips a
+    // CRLF line ending, as it does for the other sources.
+    let mut bytes = vec![];
+    let mut byte = [0; 1];
+    loop {
+        match file.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => {
+                bytes.push(byte[0]);
+                break;
+            }
+            Ok(_) => bytes.push(byte[0]),
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) => return Err(PassphraseError::Fd(fd, err)),
+        }
+    }
+    let text = String::from_utf8(bytes)
+        .map_err(|err| PassphraseError::Fd(fd, std::io::Error::new(ErrorKind::InvalidData, err)))?;
+    Ok(first_line(&text))
+}
+
+fn run_command(command: &str) -> Result<Passphrase, PassphraseError> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| PassphraseError::Command(command.to_string(), err))?;
+    if !output.status.success() {
+        return Err(PassphraseError::CommandFailed(
+            command.to_string(),
+            output.status.code(),
+        ));
+    }
+    Ok(first_line(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn first_line(text: &str) -> Passphrase {
+    Passphrase(text.lines().next().unwrap_or("").to_string())
+}
+
+/// Possible errors from getting a passphrase.
+#[derive(Debug, thiserror::Error)]
+pub enum PassphraseError {
+    /// The passphrase couldn't be read from a file descriptor.
+    #[error("failed to read passphrase from file descriptor {0}: {1}")]
+    Fd(RawFd, std::io::Error),
+
+    /// The password command couldn't be run.
+    #[error("failed to run password command {0:?}: {1}")]
+    Command(String, std::io::Error),
+
+    /// The password command failed.
+    #[error("password command {0:?} failed with exit code {1:?}")]
+    CommandFailed(String, Option<i32>),
+
+    /// The passphrase couldn't be asked for.
+    #[error("failed to ask for passphrase: {0}; use --passphrase-fd, or set {PASSPHRASE_VAR} or password_command in the configuration")]
+    Ask(std::io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::{first_line, read_fd, run_command, PassphraseError};
+
+    #[test]
+    fn uses_first_line() {
+        assert_eq!(first_line("hunter2\nmore\n").as_str(), "hunter2");
+        assert_eq!(first_line("hunter2").as_str(), "hunter2");
+        assert_eq!(first_line("").as_str(), "");
+    }
+
+    #[test]
+    fn gets_passphrase_from_command() {
+        assert_eq!(run_command("echo hunter2").unwrap().as_str(), "hunter2");
+        assert!(matches!(
+            run_command("exit 3"),
+            Err(PassphraseError::CommandFailed(_, Some(3)))
+        ));
+    }
+
+    #[test]
+    fn reads_first_line_from_fd_without_waiting_for_end() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [reader, writer] = fds;
+        let text = b"hunter2\nmore";
+        let n = unsafe { libc::write(writer, text.as_ptr().cast(), text.len()) };
+        assert_eq!(n, text.len() as isize);
+
+        // The writer is still open, so this would hang if it read
+        // until the end.
+        assert_eq!(read_fd(reader).unwrap().as_str(), "hunter2");
+        assert_ne!(unsafe { libc::fcntl(reader, libc::F_GETFD) }, -1);
+
+        unsafe {
+            libc::close(reader);
+            libc::close(writer);
+        }
+    }
+
+    #[test]
+    fn strips_crlf_from_line_read_from_fd() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [reader, writer] = fds;
+        let text = b"hunter2\r\nmore";
+        let n = unsafe { libc::write(writer, text.as_ptr().cast(), text.len()) };
+        assert_eq!(n, text.len() as isize);
+
+        assert_eq!(read_fd(reader).unwrap().as_str(), "hunter2");
+
+        unsafe {
+            libc::close(reader);
+            libc::close(writer);
+        }
+    }
+
+    #[test]
+    fn refuses_closed_fd() {
+        assert!(matches!(read_fd(-1), Err(PassphraseError::Fd(-1, _))));
+    }
+
+    #[test]
+    fn hides_passphrase_in_debug_output() {
+        assert!(!format!("{:?}", first_line("hunter2")).contains("hunter2"));
+    }
+}
@@ -1,21 +1,80 @@
 //! Passwords for encryption.
 
+use crate::keyexport::{self, Kdf, KeyExportError};
+use crate::label::LabelKey;
+use crate::passphrase::{Passphrase, PassphraseError, PassphraseSource};
+use log::{info, warn};
 use pbkdf2::{
     password_hash::{PasswordHasher, SaltString},
     Pbkdf2,
 };
 use rand::rngs::OsRng;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::io::prelude::Write;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 const KEY_LEN: usize = 32; // Only size accepted by aead crate?
 
+// Length of a key identifier, in bytes. It's written as twice as
+// many hexadecimal digits.
+const KEY_ID_LEN: usize = 8;
+
+/// Length of a master key, in bytes.
+pub const MASTER_KEY_LEN: usize = 32;
+
+// Prefix of a key written in base64 in the passwords file.
+const BASE64_PREFIX: &str = "base64:";
+
+/// A master key, from which all other keys are derived.
+pub type MasterKey = [u8; MASTER_KEY_LEN];
+
 /// Encryption password.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Passwords {
-    encryption: String,
+    encryption: Key,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signing: Option<Key>,
+}
+
+// A key, as written in the passwords file. Keys derived from a master
+// key are written in base64, after a prefix, so that every bit of
+// them is used. Older keys are text, and the bytes of the text are
+// the key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+struct Key {
+    text: String,
+    bytes: Vec<u8>,
+}
+
+impl Key {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            text: format!("{}{}", BASE64_PREFIX, base64::encode(bytes)),
+            bytes: bytes.to_vec(),
+        }
+    }
+}
+
+impl TryFrom<String> for Key {
+    type Error = base64::DecodeError;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let bytes = match text.strip_prefix(BASE64_PREFIX) {
+            Some(encoded) => base64::decode(encoded)?,
+            None => text.as_bytes().to_vec(),
+        };
+        Ok(Self { text, bytes })
+    }
+}
+
+impl From<Key> for String {
+    fn from(key: Key) -> Self {
+        key.text
+    }
 }
 
 impl Passwords {
@@ -24,54 +83,203 @@ impl Passwords {
         let mut key = derive_password(passphrase);
         let _ = key.split_off(KEY_LEN);
         assert_eq!(key.len(), KEY_LEN);
-        Self { encryption: key }
+        Self {
+            encryption: Key {
+                bytes: key.as_bytes().to_vec(),
+                text: key,
+            },
+            signing: None,
+        }
+    }
+
+    /// Generate a new, random master key.
+    pub fn generate_master_key() -> MasterKey {
+        let mut master = [0; MASTER_KEY_LEN];
+        OsRng.fill_bytes(&mut master);
+        master
+    }
+
+    /// Derive encryption and signing keys from a master key.
+    ///
+    /// The same master key always results in the same keys, so they
+    /// can be recreated from the master key if they're lost.
+    pub fn from_master_key(master: &MasterKey) -> Self {
+        Self {
+            encryption: derive_key(master, "encryption"),
+            signing: Some(derive_key(master, "signing")),
+        }
     }
 
     /// Get encryption key.
     pub fn encryption_key(&self) -> &[u8] {
-        self.encryption.as_bytes()
+        &self.encryption.bytes
+    }
+
+    /// Get signing key, if there is one. Passwords saved by older
+    /// versions of Obnam don't have one.
+    pub fn signing_key(&self) -> Option<&[u8]> {
+        self.signing.as_ref().map(|key| key.bytes.as_slice())
+    }
+
+    /// Get key for keyed chunk labels.
+    ///
+    /// It's derived from the encryption key, so that clients sharing
+    /// the encryption key get the same labels for the same data, and
+    /// can de-duplicate against each other.
+    pub fn label_key(&self) -> LabelKey {
+        blake3::derive_key("obnam chunk label key", self.encryption_key())
+    }
+
+    /// Get an identifier for the encryption key.
+    ///
+    /// The identifier is derived from the key, but doesn't reveal it.
+    /// It tells keys apart, when there's more than one to choose from.
+    pub fn key_id(&self) -> String {
+        let id = blake3::derive_key("obnam key id", self.encryption_key());
+        id[..KEY_ID_LEN]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
     }
 
     /// Load passwords from file.
-    pub fn load(filename: &Path) -> Result<Self, PasswordError> {
-        let data = std::fs::read(filename)
+    ///
+    /// If the passwords in the file are encrypted, the passphrase to
+    /// decrypt them is got from its source. If they're in the older,
+    /// unencrypted format, and the source has a passphrase without
+    /// asking for one, the file is encrypted with it, using a key
+    /// derived with `kdf`.
+    pub fn load(
+        filename: &Path,
+        source: &PassphraseSource,
+        kdf: &Kdf,
+    ) -> Result<Self, PasswordError> {
+        let data = std::fs::read_to_string(filename)
             .map_err(|err| PasswordError::Read(filename.to_path_buf(), err))?;
-        serde_yaml::from_slice(&data)
-            .map_err(|err| PasswordError::Parse(filename.to_path_buf(), err))
+        if keyexport::is_armored(&data) {
+            let passphrase = source.get_or_ask()?;
+            return keyexport::import(&data, passphrase.as_str())
+                .map_err(|err| PasswordError::Decrypt(filename.to_path_buf(), err));
+        }
+
+        let passwords: Self = serde_yaml::from_str(&data)
+            .map_err(|err| PasswordError::Parse(filename.to_path_buf(), err))?;
+        match source.get()? {
+            Some(passphrase) => match passwords.save_encrypted(filename, &passphrase, kdf) {
+                Ok(()) => info!("encrypted keys in {}", filename.display()),
+                Err(err) => warn!("failed to encrypt keys: {}", err),
+            },
+            None => info!(
+                "keys in {} are not encrypted; run 'obnam key encrypt'",
+                filename.display()
+            ),
+        }
+        Ok(passwords)
+    }
+
+    /// Are the passwords in a file encrypted?
+    pub fn is_encrypted(filename: &Path) -> Result<bool, PasswordError> {
+        let data = std::fs::read_to_string(filename)
+            .map_err(|err| PasswordError::Read(filename.to_path_buf(), err))?;
+        Ok(keyexport::is_armored(&data))
     }
 
     /// Save passwords to file.
     pub fn save(&self, filename: &Path) -> Result<(), PasswordError> {
         let data = serde_yaml::to_string(&self).map_err(PasswordError::Serialize)?;
+        write_private(filename, &data)
+    }
+
+    /// Save passwords to file, encrypted with a key derived from a
+    /// passphrase.
+    pub fn save_encrypted(
+        &self,
+        filename: &Path,
+        passphrase: &Passphrase,
+        kdf: &Kdf,
+    ) -> Result<(), PasswordError> {
+        let data =
+            keyexport::export(self, passphrase.as_str(), kdf).map_err(PasswordError::Encrypt)?;
+        write_private(filename, &data)
+    }
+}
 
-        let mut file = std::fs::File::create(filename)
-            .map_err(|err| PasswordError::Write(filename.to_path_buf(), err))?;
-        let metadata = file
-            .metadata()
-            .map_err(|err| PasswordError::Write(filename.to_path_buf(), err))?;
-        let mut permissions = metadata.permissions();
+// Write a file that only its owner can read. The file is written under
+// a temporary name first, and then renamed, so that existing passwords
+// are replaced only when the new ones have been written.
+fn write_private(filename: &Path, data: &str) -> Result<(), PasswordError> {
+    let err = |err| PasswordError::Write(filename.to_path_buf(), err);
+    let mut temp = filename.as_os_str().to_os_string();
+    temp.push(".new");
+    let temp = PathBuf::from(temp);
+    if temp.exists() {
+        std::fs::remove_file(&temp).map_err(err)?;
+    }
 
-        // Make readadable by owner only. We still have the open file
-        // handle, so we can write the content.
-        permissions.set_mode(0o400);
-        std::fs::set_permissions(filename, permissions)
-            .map_err(|err| PasswordError::Write(filename.to_path_buf(), err))?;
+    let mut file = std::fs::File::create(&temp).map_err(err)?;
+    let metadata = file.metadata().map_err(err)?;
+    let mut permissions = metadata.permissions();
 
-        // Write actual content.
-        file.write_all(data.as_bytes())
-            .map_err(|err| PasswordError::Write(filename.to_path_buf(), err))?;
+    // Make readadable by owner only. We still have the open file
+    // handle, so we can write the content.
+    permissions.set_mode(0o400);
+    std::fs::set_permissions(&temp, permissions).map_err(err)?;
 
-        Ok(())
-    }
+    // Write actual content, and make sure it's on disk before it
+    // replaces the old file.
+    file.write_all(data.as_bytes()).map_err(err)?;
+    file.sync_all().map_err(err)?;
+
+    std::fs::rename(&temp, filename).map_err(err)?;
+    Ok(())
 }
 
 /// Return name of password file, relative to configuration file.
-pub fn passwords_filename(config_filename: &Path) -> PathBuf {
+///
+/// Each profile in the configuration file has its own password file.
+pub fn passwords_filename(config_filename: &Path, profile: Option<&str>) -> PathBuf {
     let mut filename = config_filename.to_path_buf();
-    filename.set_file_name("passwords.yaml");
+    match profile {
+        None => filename.set_file_name("passwords.yaml"),
+        Some(profile) => filename.set_file_name(format!("passwords-{}.yaml", profile)),
+    }
     filename
 }
 
+/// Return the names of all password files next to a configuration
+/// file, for any profile, whether or not the profile is still in the
+/// configuration. The one without a profile is always included, even
+/// if it doesn't exist.
+pub fn all_passwords_filenames(config_filename: &Path) -> Vec<PathBuf> {
+    let default = passwords_filename(config_filename, None);
+    let mut filenames = vec![default.clone()];
+    let dir = match default.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("passwords-") && name.ends_with(".yaml") {
+                filenames.push(default.with_file_name(&*name));
+            }
+        }
+    }
+    filenames.sort();
+    filenames
+}
+
+// Derive a key for a purpose from a master key.
+fn derive_key(master: &MasterKey, purpose: &str) -> Key {
+    let mut hasher = Sha256::new();
+    hasher.update(b"obnam-key:");
+    hasher.update(purpose.as_bytes());
+    hasher.update(b":");
+    hasher.update(master);
+    Key::from_bytes(&hasher.finalize()[..KEY_LEN])
+}
+
 fn derive_password(passphrase: &str) -> String {
     let salt = SaltString::generate(&mut OsRng);
 
@@ -99,4 +307,143 @@ pub enum PasswordError {
     /// Failed to parse passwords file.
     #[error("failed to parse saved passwords from {0}: {1}")]
     Parse(PathBuf, serde_yaml::Error),
+
+    /// Failed to get the passphrase for encrypted passwords.
+    #[error(transparent)]
+    Passphrase(#[from] PassphraseError),
+
+    /// Failed to encrypt passwords for saving.
+    #[error("failed to encrypt passwords for saving: {0}")]
+    Encrypt(KeyExportError),
+
+    /// Failed to decrypt passwords file.
+    #[error("failed to decrypt saved passwords from {0}: {1}")]
+    Decrypt(PathBuf, KeyExportError),
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        all_passwords_filenames, passwords_filename, PasswordError, Passwords, MASTER_KEY_LEN,
+    };
+    use crate::keyexport::{Kdf, KeyExportError};
+    use crate::passphrase::{Passphrase, PassphraseSource};
+    use sha2::{Digest, Sha256};
+    use tempfile::tempdir;
+
+    #[test]
+    fn finds_password_files_of_all_profiles() {
+        let dir = tempdir().unwrap();
+        let config = dir.path().join("obnam.yaml");
+        for name in [
+            "obnam.yaml",
+            "passwords-a.yaml",
+            "passwords-b.yaml",
+            "other.yaml",
+        ] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        assert_eq!(
+            all_passwords_filenames(&config),
+            vec![
+                passwords_filename(&config, Some("a")),
+                passwords_filename(&config, Some("b")),
+                passwords_filename(&config, None),
+            ]
+        );
+    }
+
+    fn from_fd(passphrase: &str) -> PassphraseSource {
+        PassphraseSource {
+            from_fd: Some(Passphrase::new(passphrase)),
+            command: None,
+        }
+    }
+
+    #[test]
+    fn saves_and_loads_encrypted_passwords() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("passwords.yaml");
+        let passwords = Passwords::from_master_key(&Passwords::generate_master_key());
+        passwords.save(&filename).unwrap();
+        assert!(!Passwords::is_encrypted(&filename).unwrap());
+
+        passwords
+            .save_encrypted(&filename, &Passphrase::new("hunter2"), &Kdf::default())
+            .unwrap();
+        assert!(Passwords::is_encrypted(&filename).unwrap());
+        let loaded = Passwords::load(&filename, &from_fd("hunter2"), &Kdf::default()).unwrap();
+        assert_eq!(loaded.encryption_key(), passwords.encryption_key());
+        assert!(matches!(
+            Passwords::load(&filename, &from_fd("wrong"), &Kdf::default()),
+            Err(PasswordError::Decrypt(_, KeyExportError::Decrypt))
+        ));
+    }
+
+    #[test]
+    fn encrypts_unencrypted_passwords_on_load() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("passwords.yaml");
+        let passwords = Passwords::from_master_key(&Passwords::generate_master_key());
+        passwords.save(&filename).unwrap();
+
+        let loaded = Passwords::load(&filename, &from_fd("hunter2"), &Kdf::default()).unwrap();
+        assert_eq!(loaded.encryption_key(), passwords.encryption_key());
+        assert!(Passwords::is_encrypted(&filename).unwrap());
+        let loaded = Passwords::load(&filename, &from_fd("hunter2"), &Kdf::default()).unwrap();
+        assert_eq!(loaded.encryption_key(), passwords.encryption_key());
+    }
+
+    #[test]
+    fn derives_same_keys_from_same_master_key() {
+        let master = Passwords::generate_master_key();
+        let a = Passwords::from_master_key(&master);
+        let b = Passwords::from_master_key(&master);
+        assert_eq!(a.encryption_key(), b.encryption_key());
+        assert_eq!(a.encryption_key().len(), 32);
+        assert_eq!(a.signing_key(), b.signing_key());
+        assert_ne!(Some(a.encryption_key()), a.signing_key());
+
+        let other = Passwords::from_master_key(&Passwords::generate_master_key());
+        assert_ne!(a.encryption_key(), other.encryption_key());
+    }
+
+    #[test]
+    fn derived_keys_keep_every_bit() {
+        let master = [7; MASTER_KEY_LEN];
+        let passwords = Passwords::from_master_key(&master);
+        let mut hasher = Sha256::new();
+        hasher.update(b"obnam-key:encryption:");
+        hasher.update(master);
+        assert_eq!(passwords.encryption_key(), &hasher.finalize()[..]);
+
+        let yaml = serde_yaml::to_string(&passwords).unwrap();
+        let loaded: Passwords = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(loaded.encryption_key(), passwords.encryption_key());
+        assert_eq!(loaded.signing_key(), passwords.signing_key());
+    }
+
+    #[test]
+    fn loads_keys_written_as_text() {
+        let key = "abcdefghijklmnopqrstuvwxyz012345";
+        let yaml = format!("encryption: {}\n", key);
+        let loaded: Passwords = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(loaded.encryption_key(), key.as_bytes());
+        assert_eq!(loaded.signing_key(), None);
+        assert_eq!(
+            serde_yaml::to_string(&loaded).unwrap(),
+            format!("---\n{}", yaml)
+        );
+    }
+
+    #[test]
+    fn key_id_identifies_encryption_key() {
+        let master = Passwords::generate_master_key();
+        let a = Passwords::from_master_key(&master);
+        let b = Passwords::from_master_key(&master);
+        let other = Passwords::from_master_key(&Passwords::generate_master_key());
+        assert_eq!(a.key_id(), b.key_id());
+        assert_eq!(a.key_id().len(), 16);
+        assert_ne!(a.key_id(), other.key_id());
+    }
 }
@@ -0,0 +1,216 @@
+//! Match file names against patterns given by the user.
+//!
+//! A pattern is a substring, a shell-style glob, or a regular
+//! expression. A glob without a slash is matched against the last
+//! component of a path, so that `*.txt` finds text files in any
+//! directory; one with a slash is matched against the whole path.
+
+use regex::Regex;
+use std::path::Path;
+
+/// A pattern to match paths against.
+#[derive(Debug, Clone)]
+pub enum PathMatcher {
+    /// Match paths that contain a string.
+    Substring(String),
+
+    /// Match paths, or their last component, against a glob.
+    Glob {
+        /// The glob, translated into a regular expression.
+        regex: Regex,
+        /// Should only the last component of a path be matched?
+        basename: bool,
+    },
+
+    /// Match paths that a regular expression matches anywhere.
+    Regex(Regex),
+}
+
+/// Possible errors from parsing a pattern.
+#[derive(Debug, thiserror::Error)]
+pub enum PathMatchError {
+    /// The regular expression is not valid.
+    #[error("invalid regular expression {0:?}: {1}")]
+    Regex(String, regex::Error),
+}
+
+impl PathMatcher {
+    /// Match paths that contain a string.
+    pub fn substring(pattern: &str) -> Self {
+        Self::Substring(pattern.to_string())
+    }
+
+    /// Match paths against a shell-style glob.
+    ///
+    /// `*` matches any characters except a slash, `**` matches any
+    /// characters, `?` matches one character except a slash, and
+    /// `[...]` matches one of the characters in brackets, or, as
+    /// `[!...]`, one not in them.
+    pub fn glob(pattern: &str) -> Result<Self, PathMatchError> {
+        let regex = Regex::new(&glob_to_regex(pattern))
+            .map_err(|err| PathMatchError::Regex(pattern.to_string(), err))?;
+        Ok(Self::Glob {
+            regex,
+            basename: !pattern.contains('/'),
+        })
+    }
+
+    /// Match paths against a regular expression.
+    pub fn regex(pattern: &str) -> Result<Self, PathMatchError> {
+        let regex =
+            Regex::new(pattern).map_err(|err| PathMatchError::Regex(pattern.to_string(), err))?;
+        Ok(Self::Regex(regex))
+    }
+
+    /// Does a path match?
+    pub fn is_match(&self, path: &Path) -> bool {
+        match self {
+            Self::Substring(s) => path.to_string_lossy().contains(s.as_str()),
+            Self::Glob { regex, basename } => {
+                if *basename {
+                    match path.file_name() {
+                        Some(name) => regex.is_match(&name.to_string_lossy()),
+                        None => false,
+                    }
+                } else {
+                    regex.is_match(&path.to_string_lossy())
+                }
+            }
+            Self::Regex(regex) => regex.is_match(&path.to_string_lossy()),
+        }
+    }
+}
+
+// Translate a glob into an anchored regular expression.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let mut lookahead = chars.clone();
+                match glob_set(&mut lookahead) {
+                    Some(set) => {
+                        regex.push_str(&set);
+                        chars = lookahead;
+                    }
+                    None => regex.push_str(r"\["),
+                }
+            }
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+// Translate a glob's `[...]` set, after its opening bracket, into a
+// regular expression set. A `]` right after the opening bracket is
+// part of the set. Return None if the set isn't closed.
+fn glob_set(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    let mut set = String::from("[");
+    let mut c = chars.next()?;
+    if c == '!' {
+        set.push('^');
+        c = chars.next()?;
+    }
+    let mut first = true;
+    loop {
+        if c == ']' && !first {
+            set.push(']');
+            return Some(set);
+        }
+        if matches!(c, '\\' | '[' | ']' | '^' | '&' | '~') {
+            set.push('\\');
+        }
+        set.push(c);
+        first = false;
+        c = chars.next()?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PathMatchError, PathMatcher};
+    use std::path::Path;
+
+    fn matches(m: &PathMatcher, path: &str) -> bool {
+        m.is_match(Path::new(path))
+    }
+
+    #[test]
+    fn matches_substring() {
+        let m = PathMatcher::substring("ab.c");
+        assert!(matches(&m, "/home/x/ab.cd"));
+        assert!(!matches(&m, "/home/x/abxc"));
+    }
+
+    #[test]
+    fn matches_glob_against_file_name() {
+        let m = PathMatcher::glob("*.txt").unwrap();
+        assert!(matches(&m, "/home/x/notes.txt"));
+        assert!(matches(&m, "/a.txt"));
+        assert!(!matches(&m, "/home/x/notes.txt.bak"));
+        assert!(!matches(&m, "/home/x.txt/notes"));
+    }
+
+    #[test]
+    fn matches_glob_with_slash_against_whole_path() {
+        let m = PathMatcher::glob("/home/*/notes.txt").unwrap();
+        assert!(matches(&m, "/home/x/notes.txt"));
+        assert!(!matches(&m, "/home/x/y/notes.txt"));
+
+        let m = PathMatcher::glob("/home/**.txt").unwrap();
+        assert!(matches(&m, "/home/x/y/notes.txt"));
+    }
+
+    #[test]
+    fn matches_glob_character_sets() {
+        let m = PathMatcher::glob("file[0-9].?").unwrap();
+        assert!(matches(&m, "/x/file1.c"));
+        assert!(!matches(&m, "/x/filea.c"));
+        assert!(!matches(&m, "/x/file1.cc"));
+
+        let m = PathMatcher::glob("file[!0-9]").unwrap();
+        assert!(matches(&m, "/x/filea"));
+        assert!(!matches(&m, "/x/file1"));
+
+        let m = PathMatcher::glob("[]a]").unwrap();
+        assert!(matches(&m, "/x/]"));
+        assert!(matches(&m, "/x/a"));
+    }
+
+    #[test]
+    fn treats_unclosed_bracket_literally() {
+        let m = PathMatcher::glob("a[b").unwrap();
+        assert!(matches(&m, "/x/a[b"));
+    }
+
+    #[test]
+    fn escapes_regex_characters_in_glob() {
+        let m = PathMatcher::glob("a.(b)+").unwrap();
+        assert!(matches(&m, "/x/a.(b)+"));
+        assert!(!matches(&m, "/x/ax(b)"));
+    }
+
+    #[test]
+    fn matches_regex_anywhere() {
+        let m = PathMatcher::regex(r"\.rs$").unwrap();
+        assert!(matches(&m, "/src/main.rs"));
+        assert!(!matches(&m, "/src/main.rsx"));
+    }
+
+    #[test]
+    fn rejects_bad_regex() {
+        assert!(matches!(
+            PathMatcher::regex("a("),
+            Err(PathMatchError::Regex(_, _))
+        ));
+    }
+}
@@ -1,7 +1,18 @@
 //! Performance measurements from an Obnam run.
 
-use crate::accumulated_time::AccumulatedTime;
+use crate::accumulated_time::{AccumulatedTime, ClockGuard};
+use crate::concurrency::AdaptiveConcurrency;
+use crate::network_stats::NetworkCounts;
+use crate::progress_sink::{ProgressEvent, ProgressSink};
 use log::info;
+use serde::Serialize;
+use std::sync::Mutex;
+
+/// Version of the performance report format.
+///
+/// This is incremented when a field is removed or changes meaning.
+/// Adding fields doesn't change the version.
+pub const REPORT_VERSION: u32 = 1;
 
 /// The kinds of clocks we have.
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
@@ -25,6 +36,10 @@ pub struct Performance {
     files_backed_up: u64,
     chunks_uploaded: u64,
     chunks_reused: u64,
+    bytes_new: u64,
+    bytes_reused: u64,
+    uploads: Option<AdaptiveConcurrency>,
+    network: NetworkCounts,
 }
 
 impl Default for Performance {
@@ -36,6 +51,10 @@ impl Default for Performance {
             files_backed_up: 0,
             chunks_reused: 0,
             chunks_uploaded: 0,
+            bytes_new: 0,
+            bytes_reused: 0,
+            uploads: None,
+            network: NetworkCounts::default(),
         }
     }
 }
@@ -51,6 +70,24 @@ impl Performance {
         info!("Files backed up: {}", self.files_backed_up);
         info!("Chunks uploaded: {}", self.chunks_uploaded);
         info!("Chunks reused: {}", self.chunks_reused);
+        info!("Bytes in new chunks: {}", self.bytes_new);
+        info!("Bytes in reused chunks: {}", self.bytes_reused);
+        info!("Bytes uploaded: {}", self.network.bytes_uploaded);
+        info!("Bytes downloaded: {}", self.network.bytes_downloaded);
+        for (endpoint, requests) in self.network.requests.iter() {
+            info!(
+                "HTTP requests {}: {} ({} failed)",
+                endpoint, requests.count, requests.failed
+            );
+        }
+        info!("HTTP retries: {}", self.network.retries);
+        if let Some(uploads) = &self.uploads {
+            info!("Upload requests succeeded: {}", uploads.successes());
+            info!("Upload requests failed: {}", uploads.failures());
+            info!("Upload concurrency at end: {}", uploads.limit());
+            info!("Upload concurrency peak: {}", uploads.peak());
+            info!("Upload concurrency decreases: {}", uploads.decreases());
+        }
         info!(
             "Downloading previous generation (seconds): {}",
             self.time.secs(Clock::GenerationDownload)
@@ -75,6 +112,11 @@ impl Performance {
         self.time.stop(clock)
     }
 
+    /// Start a specific clock, and stop it when the guard is dropped.
+    pub fn clock(&self, clock: Clock) -> ClockGuard<'_, Clock> {
+        self.time.guard(clock)
+    }
+
     /// Increment number of live files.
     pub fn found_live_files(&mut self, n: u64) {
         self.live_files += n;
@@ -90,8 +132,236 @@ impl Performance {
         self.chunks_reused += 1;
     }
 
+    /// Remember how upload concurrency adapted during the run.
+    pub fn upload_concurrency(&mut self, uploads: &AdaptiveConcurrency) {
+        self.uploads = Some(uploads.clone());
+    }
+
     /// Increment number of uploaded chunks.
     pub fn upload_chunk(&mut self) {
         self.chunks_uploaded += 1;
     }
+
+    /// Add what a progress counter saw during a backup.
+    pub fn count_progress(&mut self, counter: &ProgressCounter) {
+        let counts = counter.counts.lock().unwrap();
+        self.live_files += counts.live_files;
+        self.files_backed_up += counts.live_files.saturating_sub(counts.unchanged_files);
+        self.chunks_uploaded += counts.chunks_uploaded;
+        self.chunks_reused += counts.chunks_reused;
+        self.bytes_new += counts.bytes_new;
+        self.bytes_reused += counts.bytes_reused;
+    }
+
+    /// Remember the HTTP traffic with the server during the run.
+    pub fn network(&mut self, counts: NetworkCounts) {
+        self.network = counts;
+    }
+
+    /// Return all measurements in a form that can be serialized.
+    ///
+    /// Clocks that are still running are included up to now.
+    pub fn report(&self) -> PerformanceReport {
+        let secs = |clock| self.time.nanos(clock) as f64 / 1e9;
+        let total = self.bytes_new + self.bytes_reused;
+        PerformanceReport {
+            version: REPORT_VERSION,
+            args: self.args.clone(),
+            clocks: ClocksReport {
+                run_time: secs(Clock::RunTime),
+                generation_download: secs(Clock::GenerationDownload),
+                generation_upload: secs(Clock::GenerationUpload),
+            },
+            counters: CountersReport {
+                live_files: self.live_files,
+                files_backed_up: self.files_backed_up,
+                chunks_uploaded: self.chunks_uploaded,
+                chunks_reused: self.chunks_reused,
+            },
+            dedup: DedupReport {
+                bytes_new: self.bytes_new,
+                bytes_reused: self.bytes_reused,
+                ratio: if total == 0 {
+                    0.0
+                } else {
+                    self.bytes_reused as f64 / total as f64
+                },
+            },
+            network: self.network.clone(),
+            uploads: self.uploads.as_ref().map(|uploads| UploadsReport {
+                successes: uploads.successes(),
+                failures: uploads.failures(),
+                final_concurrency: uploads.limit(),
+                peak_concurrency: uploads.peak(),
+                decreases: uploads.decreases(),
+            }),
+        }
+    }
+}
+
+/// Count the files and chunks a backup reports as progress.
+///
+/// This is added to the progress sinks of a backup, and the counts
+/// are then added to [`Performance`] with
+/// [`Performance::count_progress`].
+#[derive(Debug, Default)]
+pub struct ProgressCounter {
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    live_files: u64,
+    unchanged_files: u64,
+    chunks_uploaded: u64,
+    chunks_reused: u64,
+    bytes_new: u64,
+    bytes_reused: u64,
+}
+
+impl ProgressSink for ProgressCounter {
+    fn event(&self, event: &ProgressEvent) {
+        let mut counts = self.counts.lock().unwrap();
+        match event {
+            ProgressEvent::FileStarted { .. } => counts.live_files += 1,
+            ProgressEvent::FileUnchanged { .. } => counts.unchanged_files += 1,
+            ProgressEvent::ChunkUploaded {
+                bytes,
+                reused: true,
+                ..
+            } => {
+                counts.chunks_reused += 1;
+                counts.bytes_reused += bytes;
+            }
+            ProgressEvent::ChunkUploaded {
+                bytes,
+                reused: false,
+                ..
+            } => {
+                counts.chunks_uploaded += 1;
+                counts.bytes_new += bytes;
+            }
+            ProgressEvent::Warning { .. } | ProgressEvent::Finished { .. } => (),
+        }
+    }
+}
+
+/// Performance measurements of a run, as written to a report file.
+///
+/// The report is a JSON object with these fields. Times are in
+/// seconds, and sizes in bytes.
+#[derive(Debug, Serialize)]
+pub struct PerformanceReport {
+    /// Version of the report format, [`REPORT_VERSION`].
+    pub version: u32,
+    /// Command line arguments of the run.
+    pub args: Vec<String>,
+    /// Time spent in different parts of the run.
+    pub clocks: ClocksReport,
+    /// Counts of files and chunks.
+    pub counters: CountersReport,
+    /// How much de-duplication saved.
+    pub dedup: DedupReport,
+    /// HTTP traffic with the server.
+    pub network: NetworkCounts,
+    /// How uploads went, or null if the run didn't get as far as
+    /// uploading a new generation.
+    pub uploads: Option<UploadsReport>,
+}
+
+/// Time spent in different parts of a run, in seconds.
+#[derive(Debug, Serialize)]
+pub struct ClocksReport {
+    /// The complete run.
+    pub run_time: f64,
+    /// Downloading the previous backup generation.
+    pub generation_download: f64,
+    /// Uploading the new backup generation.
+    pub generation_upload: f64,
+}
+
+/// Counts of files and chunks in a run.
+#[derive(Debug, Serialize)]
+pub struct CountersReport {
+    /// Files found in the backup roots.
+    pub live_files: u64,
+    /// Files that were new or had changed, and were backed up.
+    pub files_backed_up: u64,
+    /// Chunks of file content uploaded to the server.
+    pub chunks_uploaded: u64,
+    /// Chunks of file content that were already on the server.
+    pub chunks_reused: u64,
+}
+
+/// How much de-duplication saved in a run.
+#[derive(Debug, Serialize)]
+pub struct DedupReport {
+    /// Size of chunks that were new, before encryption. This includes
+    /// the chunks of the new generation itself.
+    pub bytes_new: u64,
+    /// Size of chunks that were already on the server.
+    pub bytes_reused: u64,
+    /// Share of chunk data that was already on the server, from 0 to 1.
+    pub ratio: f64,
+}
+
+/// How concurrent chunk uploads went in a run.
+#[derive(Debug, Serialize)]
+pub struct UploadsReport {
+    /// Upload requests that succeeded.
+    pub successes: u64,
+    /// Upload requests that failed.
+    pub failures: u64,
+    /// Concurrent upload limit at the end of the run.
+    pub final_concurrency: usize,
+    /// Highest concurrent upload limit during the run.
+    pub peak_concurrency: usize,
+    /// How many times the limit was lowered.
+    pub decreases: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Performance, ProgressCounter};
+    use crate::progress_sink::{ProgressEvent, ProgressSink};
+    use std::path::Path;
+
+    fn chunk(bytes: u64, reused: bool) -> ProgressEvent {
+        ProgressEvent::ChunkUploaded {
+            id: "abc".to_string(),
+            bytes,
+            reused,
+        }
+    }
+
+    #[test]
+    fn reports_counted_progress() {
+        let counter = ProgressCounter::default();
+        counter.event(&ProgressEvent::file_started(Path::new("/a")));
+        counter.event(&ProgressEvent::file_started(Path::new("/b")));
+        counter.event(&ProgressEvent::file_unchanged(Path::new("/b"), 10));
+        counter.event(&chunk(30, false));
+        counter.event(&chunk(10, true));
+
+        let mut perf = Performance::default();
+        perf.count_progress(&counter);
+        let report = perf.report();
+        assert_eq!(report.counters.live_files, 2);
+        assert_eq!(report.counters.files_backed_up, 1);
+        assert_eq!(report.counters.chunks_uploaded, 1);
+        assert_eq!(report.counters.chunks_reused, 1);
+        assert_eq!(report.dedup.bytes_new, 30);
+        assert_eq!(report.dedup.bytes_reused, 10);
+        assert_eq!(report.dedup.ratio, 0.25);
+        assert!(report.uploads.is_none());
+    }
+
+    #[test]
+    fn serializes_report_as_json() {
+        let json = serde_json::to_value(Performance::default().report()).unwrap();
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["dedup"]["ratio"], 0.0);
+        assert!(json["clocks"]["run_time"].is_number());
+        assert!(json["uploads"].is_null());
+    }
 }
@@ -1,49 +1,273 @@
 //! Policy for what gets backed up.
 
 use crate::backup_reason::Reason;
-use crate::fsentry::FilesystemEntry;
+use crate::fsentry::{FilesystemEntry, FilesystemKind};
 use crate::generation::LocalGeneration;
 use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// How to detect if a file has changed since the previous backup.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeDetection {
+    /// Compare file metadata: kind, size, permissions, modification
+    /// time, inode change time, and symbolic link target.
+    Metadata,
+
+    /// Compare metadata, and if that hasn't changed, also compare
+    /// the checksums of the file's content. This is slower, but
+    /// notices changes that don't show up in the metadata, such as
+    /// when a program preserves the modification time.
+    Content,
+}
+
+/// What to do about an error backing up a file.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorPolicy {
+    /// The error is harmless. It's logged, but not reported as a
+    /// warning.
+    Skip,
+    /// Report the error as a warning, and carry on with the backup.
+    Warn,
+    /// Stop the backup, without making a new one.
+    Abort,
+}
+
+/// Kinds of file that policy can exclude from backups.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExcludedKind {
+    /// UNIX domain sockets.
+    Socket,
+    /// Named pipes.
+    Fifo,
+}
+
+impl ExcludedKind {
+    fn matches(&self, kind: FilesystemKind) -> bool {
+        match self {
+            Self::Socket => kind == FilesystemKind::Socket,
+            Self::Fifo => kind == FilesystemKind::Fifo,
+        }
+    }
+}
+
+/// The `policy` section of the client configuration.
+///
+/// All fields are optional. The `paths` list overrides the settings
+/// for files under specific directories. When several overrides
+/// apply, the one for the longest path wins.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Should new files be backed up?
+    pub new: Option<bool>,
+
+    /// Should changed files be backed up?
+    pub changed: Option<bool>,
+
+    /// How are changes detected?
+    pub change_detection: Option<ChangeDetection>,
+
+    /// Fraction of files, from 0.0 to 1.0, whose content is checked
+    /// even when changes are detected by metadata.
+    pub content_sample: Option<f64>,
+
+    /// What to do about errors backing up files.
+    pub on_error: Option<ErrorPolicy>,
+
+    /// Files larger than this many bytes aren't backed up.
+    pub max_file_size: Option<u64>,
+
+    /// Kinds of file that aren't backed up.
+    pub exclude_kinds: Option<Vec<ExcludedKind>>,
+
+    /// Overrides for files under specific paths.
+    #[serde(default)]
+    pub paths: Vec<PathPolicyConfig>,
+}
+
+impl PolicyConfig {
+    /// Return the first content sample fraction that is not between
+    /// 0.0 and 1.0, if any.
+    pub fn bad_content_sample(&self) -> Option<f64> {
+        std::iter::once(self.content_sample)
+            .chain(self.paths.iter().map(|p| p.content_sample))
+            .flatten()
+            .find(|f| !(0.0..=1.0).contains(f))
+    }
+}
+
+/// Policy overrides for files under a specific path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathPolicyConfig {
+    /// The path the overrides apply to, including everything under it.
+    pub path: PathBuf,
+
+    /// Should new files be backed up?
+    pub new: Option<bool>,
+
+    /// Should changed files be backed up?
+    pub changed: Option<bool>,
+
+    /// How are changes detected?
+    pub change_detection: Option<ChangeDetection>,
+
+    /// Fraction of files, from 0.0 to 1.0, whose content is checked
+    /// even when changes are detected by metadata.
+    pub content_sample: Option<f64>,
+
+    /// What to do about errors backing up files.
+    pub on_error: Option<ErrorPolicy>,
+
+    /// Files larger than this many bytes aren't backed up.
+    pub max_file_size: Option<u64>,
+
+    /// Kinds of file that aren't backed up.
+    pub exclude_kinds: Option<Vec<ExcludedKind>>,
+}
 
 /// Policy for what gets backed up.
 ///
-/// The policy allows two aspects to be controlled:
+/// The policy allows three aspects to be controlled:
 ///
-/// * should new files )(files that didn't exist in the previous
-///   backup be included in the new backup?
-/// * should files that haven't been changed since the previous backup
+/// * should new files (files that didn't exist in the previous
+///   backup) be included in the new backup?
+/// * should files that have been changed since the previous backup
 ///   be included in the new backup?
+/// * how is a change detected?
 ///
-/// If policy doesn't allow a file to be included, it's skipped.
+/// Files that are too large, or of an excluded kind, aren't backed up
+/// at all. Policy also says what to do about errors backing up a
+/// file. Each can
+/// be set differently for files under specific paths. If
+/// policy doesn't allow a file to be included, it's skipped.
 pub struct BackupPolicy {
+    default: Settings,
+    // Sorted so that the most specific paths come last.
+    paths: Vec<(PathBuf, PathPolicyConfig)>,
+}
+
+#[derive(Debug, Clone)]
+struct Settings {
     new: bool,
     old_if_changed: bool,
+    change_detection: ChangeDetection,
+    content_sample: f64,
+    on_error: ErrorPolicy,
+    max_file_size: Option<u64>,
+    exclude_kinds: Vec<ExcludedKind>,
+}
+
+impl Settings {
+    // Is a file excluded from backups by its size or kind?
+    fn excludes(&self, e: &FilesystemEntry) -> bool {
+        let too_large =
+            e.kind().has_content() && self.max_file_size.map_or(false, |max| e.len() > max);
+        too_large || self.exclude_kinds.iter().any(|k| k.matches(e.kind()))
+    }
 }
 
 impl Default for BackupPolicy {
     /// Create a default policy.
     fn default() -> Self {
-        Self {
-            new: true,
-            old_if_changed: true,
-        }
+        Self::new(&PolicyConfig::default())
     }
 }
 
 impl BackupPolicy {
+    /// Create a policy from configuration.
+    pub fn new(config: &PolicyConfig) -> Self {
+        let default = Settings {
+            new: config.new.unwrap_or(true),
+            old_if_changed: config.changed.unwrap_or(true),
+            change_detection: config.change_detection.unwrap_or(ChangeDetection::Metadata),
+            content_sample: config.content_sample.unwrap_or(0.0),
+            on_error: config.on_error.unwrap_or(ErrorPolicy::Warn),
+            max_file_size: config.max_file_size,
+            exclude_kinds: config.exclude_kinds.clone().unwrap_or_default(),
+        };
+        let mut paths: Vec<(PathBuf, PathPolicyConfig)> = config
+            .paths
+            .iter()
+            .map(|p| (p.path.clone(), p.clone()))
+            .collect();
+        paths.sort_by_key(|(path, _)| path.components().count());
+        Self { default, paths }
+    }
+
+    // Settings that apply to a given file.
+    fn settings(&self, path: &Path) -> Settings {
+        let mut settings = self.default.clone();
+        for (prefix, o) in self.paths.iter() {
+            if path.starts_with(prefix) {
+                settings.new = o.new.unwrap_or(settings.new);
+                settings.old_if_changed = o.changed.unwrap_or(settings.old_if_changed);
+                settings.change_detection = o.change_detection.unwrap_or(settings.change_detection);
+                settings.content_sample = o.content_sample.unwrap_or(settings.content_sample);
+                settings.on_error = o.on_error.unwrap_or(settings.on_error);
+                if o.max_file_size.is_some() {
+                    settings.max_file_size = o.max_file_size;
+                }
+                if let Some(kinds) = &o.exclude_kinds {
+                    settings.exclude_kinds = kinds.clone();
+                }
+            }
+        }
+        settings
+    }
+
+    /// How should changes to a given file be detected?
+    pub fn change_detection(&self, path: &Path) -> ChangeDetection {
+        self.settings(path).change_detection
+    }
+
+    /// What should be done about an error backing up a given file?
+    pub fn on_error(&self, path: &Path) -> ErrorPolicy {
+        self.settings(path).on_error
+    }
+
+    /// Should the content of a file be checked for changes, when its
+    /// metadata hasn't changed?
+    ///
+    /// This is always the case when changes are detected by content.
+    /// Otherwise, a random sample of files is checked, so that over
+    /// many backups, modifications that preserve metadata are noticed
+    /// eventually, without the cost of reading every file every time.
+    pub fn check_content(&self, path: &Path) -> bool {
+        let settings = self.settings(path);
+        match settings.change_detection {
+            ChangeDetection::Content => true,
+            ChangeDetection::Metadata => {
+                settings.content_sample > 0.0 && rand::random::<f64>() < settings.content_sample
+            }
+        }
+    }
+
     /// Does a given file need to be backed up?
+    ///
+    /// Only metadata is compared here. If the policy for the file is
+    /// to also compare content, that's up to the caller, for files
+    /// that are reported as unchanged.
     pub fn needs_backup(&self, old: &LocalGeneration, new_entry: &FilesystemEntry) -> Reason {
         let new_name = new_entry.pathbuf();
+        let settings = self.settings(&new_name);
+        if settings.excludes(new_entry) {
+            return Reason::PolicyExcluded;
+        }
         match old.get_file(&new_name) {
             Ok(None) => {
-                if self.new {
+                if settings.new {
                     Reason::IsNew
                 } else {
                     Reason::Skipped
                 }
             }
             Ok(Some(old_entry)) => {
-                if self.old_if_changed {
+                if settings.old_if_changed {
                     if file_has_changed(&old_entry, new_entry) {
                         Reason::Changed
                     } else {
@@ -64,12 +288,203 @@ impl BackupPolicy {
     }
 }
 
-fn file_has_changed(old: &FilesystemEntry, new: &FilesystemEntry) -> bool {
+/// Has a file changed, judging by its metadata?
+///
+/// The inode change time catches changes that leave the modification
+/// time alone, such as to ownership or extended attributes. It's only
+/// compared if both entries have it: a backup made by an older version
+/// of Obnam doesn't.
+pub(crate) fn file_has_changed(old: &FilesystemEntry, new: &FilesystemEntry) -> bool {
+    let ctime_unchanged = match (old.ctime(), new.ctime()) {
+        (Some(old_ctime), Some(new_ctime)) => old_ctime == new_ctime,
+        _ => true,
+    };
     let unchanged = old.kind() == new.kind()
         && old.len() == new.len()
         && old.mode() == new.mode()
         && old.mtime() == new.mtime()
         && old.mtime_ns() == new.mtime_ns()
+        && ctime_unchanged
         && old.symlink_target() == new.symlink_target();
     !unchanged
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        file_has_changed, BackupPolicy, ChangeDetection, ErrorPolicy, ExcludedKind,
+        PathPolicyConfig, PolicyConfig,
+    };
+    use crate::fsentry::{EntryBuilder, FilesystemEntry, FilesystemKind};
+    use std::path::{Path, PathBuf};
+
+    fn path_policy(path: &str, change_detection: Option<ChangeDetection>) -> PathPolicyConfig {
+        PathPolicyConfig {
+            path: PathBuf::from(path),
+            new: None,
+            changed: None,
+            change_detection,
+            content_sample: None,
+            on_error: None,
+            max_file_size: None,
+            exclude_kinds: None,
+        }
+    }
+
+    #[test]
+    fn default_policy_uses_metadata() {
+        let policy = BackupPolicy::default();
+        assert_eq!(
+            policy.change_detection(Path::new("/home/liw")),
+            ChangeDetection::Metadata
+        );
+    }
+
+    #[test]
+    fn most_specific_path_wins() {
+        let config = PolicyConfig {
+            paths: vec![
+                path_policy("/home/liw/src", Some(ChangeDetection::Metadata)),
+                path_policy("/home", Some(ChangeDetection::Content)),
+                path_policy("/home/liw", None),
+            ],
+            ..PolicyConfig::default()
+        };
+        let policy = BackupPolicy::new(&config);
+        assert_eq!(
+            policy.change_detection(Path::new("/etc/passwd")),
+            ChangeDetection::Metadata
+        );
+        assert_eq!(
+            policy.change_detection(Path::new("/home/liw/notes.txt")),
+            ChangeDetection::Content
+        );
+        assert_eq!(
+            policy.change_detection(Path::new("/home/liw/src/obnam")),
+            ChangeDetection::Metadata
+        );
+    }
+
+    #[test]
+    fn samples_content_checks() {
+        let config = PolicyConfig {
+            content_sample: Some(1.0),
+            paths: vec![PathPolicyConfig {
+                content_sample: Some(0.0),
+                ..path_policy("/tmp", None)
+            }],
+            ..PolicyConfig::default()
+        };
+        let policy = BackupPolicy::new(&config);
+        assert!(policy.check_content(Path::new("/home/liw/notes.txt")));
+        assert!(!policy.check_content(Path::new("/tmp/junk")));
+    }
+
+    #[test]
+    fn error_policy_is_per_path() {
+        let config = PolicyConfig {
+            paths: vec![
+                PathPolicyConfig {
+                    on_error: Some(ErrorPolicy::Skip),
+                    ..path_policy("/var/cache", None)
+                },
+                PathPolicyConfig {
+                    on_error: Some(ErrorPolicy::Abort),
+                    ..path_policy("/etc", None)
+                },
+            ],
+            ..PolicyConfig::default()
+        };
+        let policy = BackupPolicy::new(&config);
+        assert_eq!(
+            policy.on_error(Path::new("/var/cache/apt")),
+            ErrorPolicy::Skip
+        );
+        assert_eq!(
+            policy.on_error(Path::new("/etc/shadow")),
+            ErrorPolicy::Abort
+        );
+        assert_eq!(policy.on_error(Path::new("/home/liw")), ErrorPolicy::Warn);
+    }
+
+    fn entry(kind: FilesystemKind, path: &str, len: u64) -> FilesystemEntry {
+        EntryBuilder::new(kind)
+            .path(PathBuf::from(path))
+            .len(len)
+            .build()
+    }
+
+    fn excludes(policy: &BackupPolicy, e: &FilesystemEntry) -> bool {
+        policy.settings(&e.pathbuf()).excludes(e)
+    }
+
+    #[test]
+    fn excludes_by_size_and_kind() {
+        let config = PolicyConfig {
+            max_file_size: Some(1000),
+            exclude_kinds: Some(vec![ExcludedKind::Socket, ExcludedKind::Fifo]),
+            paths: vec![PathPolicyConfig {
+                max_file_size: Some(1_000_000),
+                exclude_kinds: Some(vec![]),
+                ..path_policy("/srv", None)
+            }],
+            ..PolicyConfig::default()
+        };
+        let policy = BackupPolicy::new(&config);
+        let regular = |path, len| entry(FilesystemKind::Regular, path, len);
+        assert!(!excludes(&policy, &regular("/home/small", 1000)));
+        assert!(excludes(&policy, &regular("/home/large", 1001)));
+        assert!(!excludes(&policy, &regular("/srv/large", 1001)));
+        assert!(!excludes(
+            &policy,
+            &entry(FilesystemKind::Directory, "/home/dir", 4096)
+        ));
+        assert!(excludes(
+            &policy,
+            &entry(FilesystemKind::Socket, "/home/socket", 0)
+        ));
+        assert!(excludes(
+            &policy,
+            &entry(FilesystemKind::Fifo, "/home/fifo", 0)
+        ));
+        assert!(!excludes(
+            &policy,
+            &entry(FilesystemKind::Fifo, "/srv/fifo", 0)
+        ));
+    }
+
+    #[test]
+    fn notices_changed_ctime() {
+        let old = EntryBuilder::new(FilesystemKind::Regular)
+            .mtime(1000, 0)
+            .ctime(1000, 0)
+            .build();
+        let same = EntryBuilder::new(FilesystemKind::Regular)
+            .mtime(1000, 0)
+            .ctime(1000, 0)
+            .build();
+        let chowned = EntryBuilder::new(FilesystemKind::Regular)
+            .mtime(1000, 0)
+            .ctime(2000, 0)
+            .build();
+        let unknown = EntryBuilder::new(FilesystemKind::Regular)
+            .mtime(1000, 0)
+            .build();
+        assert!(!file_has_changed(&old, &same));
+        assert!(file_has_changed(&old, &chowned));
+        assert!(!file_has_changed(&unknown, &chowned));
+    }
+
+    #[test]
+    fn path_prefix_matches_whole_components() {
+        let config = PolicyConfig {
+            paths: vec![path_policy("/home/liw", Some(ChangeDetection::Content))],
+            ..PolicyConfig::default()
+        };
+        let policy = BackupPolicy::new(&config);
+        assert_eq!(
+            policy.change_detection(Path::new("/home/liwx")),
+            ChangeDetection::Metadata
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Problems that don't stop a backup.
+//!
+//! A backup carries on when, say, a file can't be read, but it
+//! reports each such problem. Every problem has a code for the kind
+//! of problem it is, so that programs running Obnam can react to
+//! specific kinds of problem without parsing messages.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// What kind of problem happened.
+///
+/// The codes are stable: their names in output don't change between
+/// versions of Obnam.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProblemCode {
+    /// A file couldn't be opened or read.
+    UnreadableFile,
+    /// A directory couldn't be listed.
+    UnreadableDirectory,
+    /// A file's metadata, or a symbolic link's target, couldn't be
+    /// read.
+    UnreadableMetadata,
+    /// A directory was skipped, because it's on a different file
+    /// system.
+    OtherFileSystem,
+    /// A directory was skipped, because it had already been backed
+    /// up via another path.
+    AlreadyVisited,
+    /// A snapshot of a backup root couldn't be removed.
+    Snapshot,
+    /// Talking to the server failed.
+    Server,
+    /// The backup's metadata couldn't be read or written.
+    Database,
+    /// Something else.
+    Other,
+}
+
+impl ProblemCode {
+    /// How bad is a problem of this kind?
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::UnreadableFile
+            | Self::UnreadableDirectory
+            | Self::UnreadableMetadata
+            | Self::OtherFileSystem
+            | Self::AlreadyVisited => Severity::Warning,
+            Self::Snapshot | Self::Server | Self::Database | Self::Other => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for ProblemCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = match self {
+            Self::UnreadableFile => "unreadable-file",
+            Self::UnreadableDirectory => "unreadable-directory",
+            Self::UnreadableMetadata => "unreadable-metadata",
+            Self::OtherFileSystem => "other-file-system",
+            Self::AlreadyVisited => "already-visited",
+            Self::Snapshot => "snapshot",
+            Self::Server => "server",
+            Self::Database => "database",
+            Self::Other => "other",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+/// How bad a problem is.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    /// Something on the client's side, which the backup is expected
+    /// to live with, such as a file the user running Obnam can't
+    /// read.
+    Warning,
+    /// Something unexpected, which may mean the backup is missing
+    /// data it should have.
+    Error,
+}
+
+/// A problem that didn't stop a backup.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Problem {
+    /// What kind of problem it is.
+    pub code: ProblemCode,
+    /// How bad it is.
+    pub severity: Severity,
+    /// The file the problem is about, if any.
+    pub path: Option<PathBuf>,
+    /// A description of the problem, for people.
+    pub message: String,
+}
+
+impl Problem {
+    /// Create a new problem.
+    pub fn new(code: ProblemCode, path: Option<PathBuf>, message: &str) -> Self {
+        Self {
+            code,
+            severity: code.severity(),
+            path,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Count problems by their code.
+pub fn summarize(problems: &[Problem]) -> BTreeMap<ProblemCode, usize> {
+    let mut counts = BTreeMap::new();
+    for problem in problems {
+        *counts.entry(problem.code).or_insert(0) += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod test {
+    use super::{summarize, Problem, ProblemCode, Severity};
+    use std::path::PathBuf;
+
+    #[test]
+    fn serializes_codes_as_in_output() {
+        for code in [
+            ProblemCode::UnreadableFile,
+            ProblemCode::OtherFileSystem,
+            ProblemCode::Server,
+        ] {
+            let json = serde_json::to_string(&code).unwrap();
+            assert_eq!(json, format!("\"{}\"", code));
+        }
+    }
+
+    #[test]
+    fn summarizes_by_code() {
+        let problem = |code| Problem::new(code, Some(PathBuf::from("/x")), "oops");
+        let problems = vec![
+            problem(ProblemCode::UnreadableFile),
+            problem(ProblemCode::Server),
+            problem(ProblemCode::UnreadableFile),
+        ];
+        assert_eq!(problems[1].severity, Severity::Error);
+        let summary: Vec<_> = summarize(&problems).into_iter().collect();
+        assert_eq!(
+            summary,
+            vec![(ProblemCode::UnreadableFile, 2), (ProblemCode::Server, 1)]
+        );
+    }
+}
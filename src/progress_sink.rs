@@ -0,0 +1,346 @@
+//! Report progress of a backup as it happens.
+//!
+//! Progress is reported as a sequence of events. The terminal progress
+//! bars are one consumer of them. Programs that wrap Obnam, such as
+//! graphical user interfaces, can instead get the events as JSON
+//! lines, one object per event, via a file descriptor or a Unix
+//! domain socket. When there are no progress bars, such as when Obnam
+//! is run from cron, the progress is logged every so often instead.
+
+use crate::problem::{Problem, ProblemCode, Severity};
+use indicatif::HumanBytes;
+use log::{info, warn};
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often progress is logged, when there are no progress bars.
+pub const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Something that happened during a backup.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum ProgressEvent {
+    /// Started backing up a file.
+    FileStarted {
+        /// Path to the file.
+        path: String,
+    },
+
+    /// A file didn't need to be backed up, because it hadn't changed,
+    /// or because policy says it should be skipped.
+    FileUnchanged {
+        /// Path to the file.
+        path: String,
+        /// Size of file content.
+        bytes: u64,
+    },
+
+    /// A chunk of file content was uploaded, or found to already be
+    /// on the server.
+    ChunkUploaded {
+        /// Identifier of the chunk on the server.
+        id: String,
+        /// Size of the chunk.
+        bytes: u64,
+        /// Was the chunk already on the server?
+        reused: bool,
+    },
+
+    /// There was a problem that didn't stop the backup.
+    Warning {
+        /// What kind of problem it was.
+        code: ProblemCode,
+        /// How bad it was.
+        severity: Severity,
+        /// Path to the file the problem is about, if any.
+        path: Option<String>,
+        /// Description of the problem.
+        message: String,
+    },
+
+    /// The backup has finished.
+    Finished {
+        /// Identifier of the new backup.
+        generation_id: String,
+        /// Number of files in the new backup.
+        files: u64,
+        /// Number of warnings.
+        warnings: u64,
+    },
+}
+
+impl ProgressEvent {
+    /// Create an event for starting to back up a file.
+    pub fn file_started(path: &Path) -> Self {
+        Self::FileStarted {
+            path: path.to_string_lossy().to_string(),
+        }
+    }
+
+    /// Create an event for a file that didn't need to be backed up.
+    pub fn file_unchanged(path: &Path, bytes: u64) -> Self {
+        Self::FileUnchanged {
+            path: path.to_string_lossy().to_string(),
+            bytes,
+        }
+    }
+
+    /// Create an event for a problem that didn't stop the backup.
+    pub fn warning(problem: &Problem) -> Self {
+        Self::Warning {
+            code: problem.code,
+            severity: problem.severity,
+            path: problem
+                .path
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string()),
+            message: problem.message.clone(),
+        }
+    }
+}
+
+/// A consumer of progress events.
+pub trait ProgressSink {
+    /// Report an event.
+    ///
+    /// Reporting progress must not make the backup fail, so any
+    /// errors are handled by the sink itself.
+    fn event(&self, event: &ProgressEvent);
+}
+
+impl<T: ProgressSink + ?Sized> ProgressSink for &T {
+    fn event(&self, event: &ProgressEvent) {
+        (**self).event(event)
+    }
+}
+
+/// A consumer of warnings: problems that don't stop an operation.
+pub trait WarningSink {
+    /// Report a warning.
+    fn warning(&self, message: &str);
+}
+
+/// Report the warning events among progress events to a warning sink.
+pub struct WarningEvents<'a>(pub &'a dyn WarningSink);
+
+impl<'a> ProgressSink for WarningEvents<'a> {
+    fn event(&self, event: &ProgressEvent) {
+        if let ProgressEvent::Warning { message, .. } = event {
+            self.0.warning(message);
+        }
+    }
+}
+
+/// Possible errors from setting up a progress sink.
+#[derive(Debug, thiserror::Error)]
+pub enum ProgressSinkError {
+    /// The file descriptor isn't open.
+    #[error("progress file descriptor {0} is not open: {1}")]
+    BadFd(RawFd, std::io::Error),
+
+    /// Couldn't connect to a Unix domain socket.
+    #[error("failed to connect to progress socket {0}: {1}")]
+    Connect(PathBuf, std::io::Error),
+}
+
+/// Write progress events as JSON lines.
+pub struct JsonProgress {
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonProgress {
+    /// Write events to any writer.
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        Self {
+            output: Mutex::new(output),
+        }
+    }
+
+    /// Write events to an open file descriptor inherited from the
+    /// parent process.
+    pub fn from_fd(fd: RawFd) -> Result<Self, ProgressSinkError> {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(ProgressSinkError::BadFd(
+                fd,
+                std::io::Error::last_os_error(),
+            ));
+        }
+        // SAFETY: the descriptor is open, and the user told us it's
+        // ours to write to.
+        let file = unsafe { File::from_raw_fd(fd) };
+        Ok(Self::new(Box::new(file)))
+    }
+
+    /// Write events to a Unix domain socket.
+    pub fn connect(path: &Path) -> Result<Self, ProgressSinkError> {
+        let stream = UnixStream::connect(path)
+            .map_err(|err| ProgressSinkError::Connect(path.to_path_buf(), err))?;
+        Ok(Self::new(Box::new(stream)))
+    }
+}
+
+impl ProgressSink for JsonProgress {
+    fn event(&self, event: &ProgressEvent) {
+        let mut line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(err) => {
+                warn!("failed to serialize progress event {:?}: {}", event, err);
+                return;
+            }
+        };
+        line.push('\n');
+        let mut output = self.output.lock().unwrap();
+        if let Err(err) = output
+            .write_all(line.as_bytes())
+            .and_then(|_| output.flush())
+        {
+            warn!("failed to write progress event: {}", err);
+        }
+    }
+}
+
+/// Log the progress of a backup every so often, instead of drawing
+/// progress bars.
+pub struct LogProgress {
+    interval: Duration,
+    state: Mutex<LogState>,
+}
+
+// What has happened so far, and when progress was last logged.
+struct LogState {
+    logged: Instant,
+    files: u64,
+    uploaded: u64,
+    reused: u64,
+    unchanged: u64,
+    warnings: u64,
+}
+
+impl LogProgress {
+    /// Log progress at most once per `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            state: Mutex::new(LogState {
+                logged: Instant::now(),
+                files: 0,
+                uploaded: 0,
+                reused: 0,
+                unchanged: 0,
+                warnings: 0,
+            }),
+        }
+    }
+}
+
+impl LogState {
+    fn status(&self) -> String {
+        format!(
+            "backup progress: files: {}, uploaded: {}, deduplicated: {}, unchanged: {}, warnings: {}",
+            self.files,
+            HumanBytes(self.uploaded),
+            HumanBytes(self.reused),
+            HumanBytes(self.unchanged),
+            self.warnings,
+        )
+    }
+}
+
+impl ProgressSink for LogProgress {
+    fn event(&self, event: &ProgressEvent) {
+        let mut state = self.state.lock().unwrap();
+        match event {
+            ProgressEvent::FileStarted { .. } => state.files += 1,
+            ProgressEvent::FileUnchanged { bytes, .. } => state.unchanged += bytes,
+            ProgressEvent::ChunkUploaded {
+                bytes,
+                reused: true,
+                ..
+            } => state.reused += bytes,
+            ProgressEvent::ChunkUploaded { bytes, .. } => state.uploaded += bytes,
+            ProgressEvent::Warning { .. } => state.warnings += 1,
+            ProgressEvent::Finished { .. } => return,
+        }
+        if state.logged.elapsed() >= self.interval {
+            info!("{}", state.status());
+            state.logged = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{JsonProgress, LogProgress, ProgressEvent, ProgressSink};
+    use crate::problem::{Problem, ProblemCode};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_one_json_object_per_line() {
+        let buf = Buffer::default();
+        let sink = JsonProgress::new(Box::new(buf.clone()));
+        sink.event(&ProgressEvent::file_started(Path::new("/home/liw")));
+        sink.event(&ProgressEvent::ChunkUploaded {
+            id: "abc".to_string(),
+            bytes: 42,
+            reused: true,
+        });
+        sink.event(&ProgressEvent::warning(&Problem::new(
+            ProblemCode::UnreadableFile,
+            Some(PathBuf::from("/x")),
+            "oops",
+        )));
+        let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            output,
+            concat!(
+                r#"{"event":"file-started","path":"/home/liw"}"#,
+                "\n",
+                r#"{"event":"chunk-uploaded","id":"abc","bytes":42,"reused":true}"#,
+                "\n",
+                r#"{"event":"warning","code":"unreadable-file","severity":"warning","path":"/x","message":"oops"}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn counts_progress_to_log() {
+        let sink = LogProgress::new(std::time::Duration::from_secs(3600));
+        sink.event(&ProgressEvent::file_started(Path::new("/a")));
+        sink.event(&ProgressEvent::file_started(Path::new("/b")));
+        sink.event(&ProgressEvent::file_unchanged(Path::new("/b"), 10));
+        for (bytes, reused) in [(1024, false), (2048, true)] {
+            sink.event(&ProgressEvent::ChunkUploaded {
+                id: "abc".to_string(),
+                bytes,
+                reused,
+            });
+        }
+        assert_eq!(
+            sink.state.lock().unwrap().status(),
+            "backup progress: files: 2, uploaded: 1.00KiB, deduplicated: 2.00KiB, \
+             unchanged: 10B, warnings: 0"
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Recovery phrases for encryption keys.
+//!
+//! A key is written as a sequence of words, one per byte, followed by
+//! words for the first bytes of a checksum of the key, so that typing
+//! mistakes are caught. This is in the style of BIP39, but with a
+//! shorter list of words, so that each word is exactly one byte.
+
+use sha2::{Digest, Sha256};
+
+// Number of checksum bytes at the end of a phrase.
+const CHECKSUM_LEN: usize = 2;
+
+// Words for each possible byte value. They're all four letters long,
+// and distinct, so that they're easy to write down and type in.
+const WORDS: [&str; 256] = [
+    "able", "acid", "aged", "also", "area", "army", "away", "baby", "back", "ball", "band", "bank",
+    "base", "bath", "bear", "beat", "been", "beer", "bell", "belt", "best", "bird", "blow", "blue",
+    "boat", "body", "bond", "bone", "book", "boot", "born", "boss", "both", "bowl", "bulk", "burn",
+    "bush", "busy", "cake", "call", "calm", "came", "camp", "card", "care", "case", "cash", "cast",
+    "cell", "chat", "chip", "city", "club", "coal", "coat", "code", "cold", "come", "cook", "cool",
+    "cope", "copy", "core", "cost", "crew", "crop", "dark", "data", "date", "dawn", "days", "deal",
+    "dear", "debt", "deep", "deny", "desk", "dial", "diet", "disk", "done", "door", "dose", "down",
+    "draw", "drew", "drop", "dual", "dust", "duty", "each", "earn", "ease", "east", "easy", "edge",
+    "else", "even", "ever", "exit", "face", "fact", "fail", "fair", "fall", "farm", "fast", "fate",
+    "fear", "feed", "feel", "feet", "fell", "felt", "file", "fill", "film", "find", "fine", "fire",
+    "firm", "fish", "five", "flat", "flow", "food", "foot", "form", "fort", "four", "free", "from",
+    "fuel", "full", "fund", "gain", "game", "gate", "gave", "gear", "gene", "gift", "girl", "give",
+    "glad", "goal", "goes", "gold", "golf", "gone", "good", "gray", "grew", "grey", "grow", "gulf",
+    "hair", "half", "hall", "hand", "hang", "hard", "harm", "have", "head", "hear", "heat", "held",
+    "help", "here", "hero", "high", "hill", "hire", "hold", "hole", "holy", "home", "hope", "host",
+    "hour", "huge", "hung", "hunt", "hurt", "idea", "inch", "into", "iron", "item", "join", "jump",
+    "jury", "just", "keen", "keep", "kept", "kick", "kind", "king", "knee", "knew", "know", "lack",
+    "lady", "laid", "lake", "land", "lane", "last", "late", "lead", "left", "less", "life", "lift",
+    "like", "line", "link", "list", "live", "load", "loan", "lock", "logo", "long", "look", "lord",
+    "lose", "loss", "lost", "love", "luck", "made", "mail", "main", "make", "male", "many", "mark",
+    "mass", "meal", "mean", "meat", "meet", "menu", "mere", "mile", "milk", "mill", "mind", "mine",
+    "miss", "mode", "mood", "moon",
+];
+
+/// Possible errors from reading a recovery phrase.
+#[derive(Debug, thiserror::Error)]
+pub enum RecoveryError {
+    /// The phrase has a word that isn't in the word list.
+    #[error("recovery phrase has an unknown word: {0}")]
+    UnknownWord(String),
+
+    /// The phrase has the wrong number of words.
+    #[error("recovery phrase has {0} words, but should have {1}")]
+    WrongLength(usize, usize),
+
+    /// The checksum in the phrase doesn't match the key.
+    #[error("recovery phrase has a typing mistake: its checksum doesn't match")]
+    BadChecksum,
+}
+
+/// Write a key as a recovery phrase.
+pub fn encode(key: &[u8]) -> String {
+    key.iter()
+        .chain(checksum(key).iter())
+        .map(|b| WORDS[*b as usize])
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Read a key of a given length from a recovery phrase.
+///
+/// Words may be separated by any whitespace, and case doesn't matter.
+pub fn decode(phrase: &str, key_len: usize) -> Result<Vec<u8>, RecoveryError> {
+    let mut bytes = vec![];
+    for word in phrase.split_whitespace() {
+        let word = word.to_lowercase();
+        match WORDS.iter().position(|w| *w == word) {
+            Some(b) => bytes.push(b as u8),
+            None => return Err(RecoveryError::UnknownWord(word)),
+        }
+    }
+    if bytes.len() != key_len + CHECKSUM_LEN {
+        return Err(RecoveryError::WrongLength(
+            bytes.len(),
+            key_len + CHECKSUM_LEN,
+        ));
+    }
+    let sum = bytes.split_off(key_len);
+    if sum != checksum(&bytes) {
+        return Err(RecoveryError::BadChecksum);
+    }
+    Ok(bytes)
+}
+
+fn checksum(key: &[u8]) -> Vec<u8> {
+    Sha256::digest(key)[..CHECKSUM_LEN].to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode, RecoveryError, WORDS};
+    use std::collections::HashSet;
+
+    #[test]
+    fn words_are_distinct() {
+        let words: HashSet<&str> = WORDS.iter().copied().collect();
+        assert_eq!(words.len(), WORDS.len());
+    }
+
+    #[test]
+    fn roundtrips_key() {
+        let key: Vec<u8> = (0..32).map(|i| i * 7).collect();
+        let phrase = encode(&key);
+        assert_eq!(phrase.split(' ').count(), 34);
+        assert_eq!(decode(&phrase.to_uppercase(), 32).unwrap(), key);
+    }
+
+    #[test]
+    fn rejects_typing_mistakes() {
+        let key = vec![1, 2, 3, 4];
+        let phrase = encode(&key);
+        let mut words: Vec<&str> = phrase.split(' ').collect();
+        words.swap(0, 1);
+        assert!(matches!(
+            decode(&words.join(" "), 4),
+            Err(RecoveryError::BadChecksum)
+        ));
+        assert!(matches!(
+            decode("able able xyzzy", 1),
+            Err(RecoveryError::UnknownWord(_))
+        ));
+        assert!(matches!(
+            decode(&phrase, 5),
+            Err(RecoveryError::WrongLength(6, 7))
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+//! Which chunks backup generations use.
+//!
+//! Chunks are shared between generations, and between clients, thanks
+//! to de-duplication. Removing a generation only frees the chunks that
+//! no other generation uses. To find those, the chunks used by each
+//! generation are collected into a map of reference counts.
+
+use crate::chunkid::ChunkId;
+use crate::client::{BackupClient, ClientError};
+use crate::generation::{GenId, LocalGenerationError};
+use std::collections::{HashMap, HashSet};
+use tempfile::NamedTempFile;
+use tokio_util::sync::CancellationToken;
+
+/// How many generations use each chunk.
+#[derive(Debug, Default)]
+pub struct ChunkRefs {
+    refs: HashMap<ChunkId, usize>,
+    generations: usize,
+}
+
+/// Possible errors from counting chunk references.
+#[derive(Debug, thiserror::Error)]
+pub enum RefCountError {
+    /// Error using the server.
+    #[error(transparent)]
+    Client(#[from] ClientError),
+
+    /// Error reading a generation's metadata.
+    #[error(transparent)]
+    LocalGeneration(#[from] LocalGenerationError),
+
+    /// Error creating a temporary file.
+    #[error("failed to create temporary file: {0}")]
+    TempFile(std::io::Error),
+}
+
+impl ChunkRefs {
+    /// Count a reference from a generation to each of its chunks.
+    pub fn add(&mut self, chunks: &HashSet<ChunkId>) {
+        for id in chunks.iter() {
+            *self.refs.entry(id.clone()).or_insert(0) += 1;
+        }
+        self.generations += 1;
+    }
+
+    /// How many generations use a chunk?
+    pub fn count(&self, id: &ChunkId) -> usize {
+        self.refs.get(id).copied().unwrap_or(0)
+    }
+
+    /// How many generations have been counted?
+    pub fn generations(&self) -> usize {
+        self.generations
+    }
+
+    /// How many different chunks are used?
+    pub fn len(&self) -> usize {
+        self.refs.len()
+    }
+
+    /// Are no chunks used?
+    pub fn is_empty(&self) -> bool {
+        self.refs.is_empty()
+    }
+
+    /// Return the chunks used here, but not in `other`, sorted by id.
+    pub fn not_in(&self, other: &ChunkRefs) -> Vec<ChunkId> {
+        let mut ids: Vec<ChunkId> = self
+            .refs
+            .keys()
+            .filter(|id| other.count(id) == 0)
+            .cloned()
+            .collect();
+        ids.sort_by_key(|id| id.to_string());
+        ids
+    }
+}
+
+/// Return the ids of all chunks a generation needs to be restored.
+///
+/// These are the generation chunk, the chunks of the metadata of the
+/// generation and the parents it's based on, and the chunks of the
+/// files in it.
+pub async fn generation_chunks(
+    client: &BackupClient,
+    gen_id: &GenId,
+) -> Result<HashSet<ChunkId>, RefCountError> {
+    let temp = NamedTempFile::new().map_err(RefCountError::TempFile)?;
+    let gen = client
+        .fetch_generation(gen_id, temp.path(), &CancellationToken::new())
+        .await?;
+
+    let mut chunks = HashSet::new();
+    for id in std::iter::once(gen_id.clone()).chain(gen.parents()) {
+        chunks.insert(id.as_chunk_id().clone());
+        chunks.extend(client.generation_chunk_ids(&id).await?);
+    }
+    let mut files = gen.files()?;
+    for file in files.iter()? {
+        let (fileno, _, _, _) = file?;
+        for id in gen
+            .chunkids(fileno)?
+            .iter()
+            .map_err(LocalGenerationError::from)?
+        {
+            chunks.insert(id.map_err(LocalGenerationError::from)?);
+        }
+    }
+    Ok(chunks)
+}
+
+/// Tell the server which chunks a generation uses, if the server
+/// keeps count of chunk references.
+///
+/// Return false if it doesn't.
+pub async fn register_generation(
+    client: &BackupClient,
+    gen_id: &GenId,
+) -> Result<bool, RefCountError> {
+    if client.registered_generations().await?.is_none() {
+        return Ok(false);
+    }
+    let chunks: Vec<ChunkId> = generation_chunks(client, gen_id)
+        .await?
+        .into_iter()
+        .collect();
+    client.register_generation(gen_id, &chunks).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::ChunkRefs;
+    use crate::chunkid::ChunkId;
+    use std::collections::HashSet;
+
+    fn chunks(ids: &[&str]) -> HashSet<ChunkId> {
+        ids.iter().map(|id| ChunkId::recreate(id)).collect()
+    }
+
+    #[test]
+    fn counts_references() {
+        let mut refs = ChunkRefs::default();
+        refs.add(&chunks(&["a", "b"]));
+        refs.add(&chunks(&["b", "c"]));
+        assert_eq!(refs.generations(), 2);
+        assert_eq!(refs.len(), 3);
+        assert_eq!(refs.count(&ChunkId::recreate("a")), 1);
+        assert_eq!(refs.count(&ChunkId::recreate("b")), 2);
+        assert_eq!(refs.count(&ChunkId::recreate("x")), 0);
+    }
+
+    #[test]
+    fn finds_chunks_not_used_elsewhere() {
+        let mut forgotten = ChunkRefs::default();
+        forgotten.add(&chunks(&["a", "b", "c"]));
+        let mut kept = ChunkRefs::default();
+        kept.add(&chunks(&["b", "d"]));
+        assert_eq!(
+            forgotten.not_in(&kept),
+            vec![ChunkId::recreate("a"), ChunkId::recreate("c")]
+        );
+    }
+}
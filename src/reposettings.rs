@@ -0,0 +1,192 @@
+//! Settings shared by all clients of a backup repository.
+//!
+//! Clients only find chunks they can de-duplicate against if they
+//! split files into chunks, and label the chunks, the same way. If each
+//! client took these parameters from its own configuration, they would
+//! drift apart. Instead, `obnam init` stores them in the repository, in
+//! an encrypted chunk, and every client uses them from there.
+
+use crate::chunk::DataChunk;
+use crate::chunkmeta::ChunkMeta;
+use crate::config::ClientConfig;
+use crate::dbgen::DEFAULT_SCHEMA_MAJOR;
+use crate::label::{Label, LabelChecksumKind};
+use crate::schema::VersionComponent;
+use serde::{Deserialize, Serialize};
+
+/// Label of the chunk with the repository settings.
+pub const REPOSITORY_SETTINGS_LABEL: &str = "repository-settings";
+
+/// Checksum kind for chunk labels in a new repository, unless another
+/// one is chosen.
+pub const DEFAULT_CHECKSUM_KIND: LabelChecksumKind = LabelChecksumKind::Sha256;
+
+/// How files are split into chunks.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Chunking {
+    /// All chunks of a file are of the same size, except the last one.
+    FixedSize,
+}
+
+/// Settings of a backup repository.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepositorySettings {
+    chunking: Chunking,
+    chunk_size: usize,
+    checksum_kind: LabelChecksumKind,
+    compression: Option<String>,
+    schema_major: VersionComponent,
+    // Settings of repositories made by older versions of Obnam don't
+    // have this, and their chunks aren't bound to their ids.
+    #[serde(default)]
+    bound_chunk_ids: bool,
+    created: String,
+}
+
+/// Possible errors from repository settings.
+#[derive(Debug, thiserror::Error)]
+pub enum RepositorySettingsError {
+    /// Error converting text from UTF8.
+    #[error(transparent)]
+    Utf8Error(#[from] std::str::Utf8Error),
+
+    /// Error parsing JSON.
+    #[error("failed to parse repository settings: {0}")]
+    JsonParse(serde_json::Error),
+
+    /// Error generating JSON.
+    #[error("failed to serialize repository settings: {0}")]
+    JsonGenerate(serde_json::Error),
+
+    /// The repository uses a compression algorithm this version of
+    /// Obnam doesn't support.
+    #[error("repository uses unsupported compression {0}")]
+    UnsupportedCompression(String),
+
+    /// The chunk size in the settings is zero.
+    #[error("repository settings have a chunk size of zero")]
+    ZeroChunkSize,
+}
+
+impl RepositorySettings {
+    /// Create settings for a new repository, from a client's
+    /// configuration, labeling chunks with checksums of the given kind.
+    pub fn new(config: &ClientConfig, checksum_kind: LabelChecksumKind, created: String) -> Self {
+        Self {
+            chunking: Chunking::FixedSize,
+            chunk_size: config.chunk_size,
+            checksum_kind,
+            compression: None,
+            schema_major: DEFAULT_SCHEMA_MAJOR,
+            bound_chunk_ids: true,
+            created,
+        }
+    }
+
+    /// How files are split into chunks.
+    pub fn chunking(&self) -> Chunking {
+        self.chunking
+    }
+
+    /// Size of chunks.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Kind of checksum used to label chunks.
+    pub fn checksum_kind(&self) -> LabelChecksumKind {
+        self.checksum_kind
+    }
+
+    /// Compression algorithm for chunk data, if any.
+    pub fn compression(&self) -> Option<&str> {
+        self.compression.as_deref()
+    }
+
+    /// Default backup schema major version.
+    pub fn schema_major(&self) -> VersionComponent {
+        self.schema_major
+    }
+
+    /// Must every chunk be bound to the id it was uploaded as?
+    ///
+    /// This is true for repositories made by versions of Obnam that
+    /// always bind chunks to their ids.
+    pub fn bound_chunk_ids(&self) -> bool {
+        self.bound_chunk_ids
+    }
+
+    /// When the settings were created.
+    pub fn created(&self) -> &str {
+        &self.created
+    }
+
+    /// Check that this version of Obnam can use the settings.
+    pub fn check(&self) -> Result<(), RepositorySettingsError> {
+        if let Some(algo) = &self.compression {
+            return Err(RepositorySettingsError::UnsupportedCompression(
+                algo.to_string(),
+            ));
+        }
+        if self.chunk_size == 0 {
+            return Err(RepositorySettingsError::ZeroChunkSize);
+        }
+        Ok(())
+    }
+
+    /// Convert settings into a data chunk.
+    pub fn to_data_chunk(&self) -> Result<DataChunk, RepositorySettingsError> {
+        let json = serde_json::to_string(self).map_err(RepositorySettingsError::JsonGenerate)?;
+        let meta = ChunkMeta::new(&Label::literal(REPOSITORY_SETTINGS_LABEL));
+        Ok(DataChunk::new(json.into_bytes(), meta))
+    }
+
+    /// Create settings from a data chunk.
+    pub fn from_data_chunk(chunk: &DataChunk) -> Result<Self, RepositorySettingsError> {
+        let data = std::str::from_utf8(chunk.data())?;
+        serde_json::from_str(data).map_err(RepositorySettingsError::JsonParse)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RepositorySettings, RepositorySettingsError};
+    use crate::chunk::DataChunk;
+    use crate::chunkmeta::ChunkMeta;
+    use crate::label::{Label, LabelChecksumKind};
+
+    fn chunk(json: &str) -> DataChunk {
+        DataChunk::new(
+            json.as_bytes().to_vec(),
+            ChunkMeta::new(&Label::literal("repository-settings")),
+        )
+    }
+
+    #[test]
+    fn parses_settings() {
+        let settings = RepositorySettings::from_data_chunk(&chunk(
+            r#"{"chunking":"fixed-size","chunk_size":4096,"checksum_kind":"blake2",
+                "compression":null,"schema_major":0,"created":"now"}"#,
+        ))
+        .unwrap();
+        settings.check().unwrap();
+        assert_eq!(settings.chunk_size(), 4096);
+        assert_eq!(settings.checksum_kind(), LabelChecksumKind::Blake2);
+        assert_eq!(settings.schema_major(), 0);
+        assert!(!settings.bound_chunk_ids());
+    }
+
+    #[test]
+    fn rejects_unsupported_compression() {
+        let settings = RepositorySettings::from_data_chunk(&chunk(
+            r#"{"chunking":"fixed-size","chunk_size":4096,"checksum_kind":"sha256",
+                "compression":"zstd","schema_major":0,"created":"now"}"#,
+        ))
+        .unwrap();
+        assert!(matches!(
+            settings.check(),
+            Err(RepositorySettingsError::UnsupportedCompression(_))
+        ));
+    }
+}
@@ -0,0 +1,282 @@
+//! Prevent concurrent runs of Obnam for the same client.
+//!
+//! Two backups running at the same time for the same client would
+//! both base their new generation on the same previous one, and the
+//! one to finish last would replace the client trust chunk of the
+//! other, losing its generation. To prevent this, commands that change
+//! what's in the backup repository, or rely on it not changing, hold
+//! an exclusive lock on a file next to the configuration file. Each
+//! profile in the configuration file has its own lock file, as
+//! profiles may back up to different servers.
+
+use log::{debug, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+// How often to try again to get the lock, when waiting for it.
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// An exclusive lock for an Obnam run.
+///
+/// The lock is released when this is dropped, or when the process
+/// ends for any reason.
+#[derive(Debug)]
+pub struct RunLock {
+    _file: File,
+}
+
+/// Possible errors from locking.
+#[derive(Debug, thiserror::Error)]
+pub enum RunLockError {
+    /// Couldn't open or create the lock file.
+    #[error("failed to open lock file {0}: {1}")]
+    Open(PathBuf, std::io::Error),
+
+    /// Couldn't lock the lock file.
+    #[error("failed to lock {0}: {1}")]
+    Lock(PathBuf, std::io::Error),
+
+    /// Another Obnam run holds the lock.
+    #[error("another Obnam run (process {1}) is using {0}; use --wait to wait for it to finish")]
+    Locked(PathBuf, String),
+
+    /// Waiting for the lock was cancelled.
+    #[error("cancelled while waiting for lock {0}")]
+    Cancelled(PathBuf),
+}
+
+impl RunLock {
+    /// Get the lock in a lock file, as named by [`lock_filename`].
+    ///
+    /// If another run holds the lock, return an error, or if `wait`
+    /// is true, wait until the other run finishes or `cancel` is
+    /// cancelled.
+    pub fn acquire(
+        filename: &Path,
+        wait: bool,
+        cancel: &CancellationToken,
+    ) -> Result<Self, RunLockError> {
+        let mut attempt = Attempt::new(filename, wait)?;
+        loop {
+            if let Some(lock) = attempt.try_lock(cancel)? {
+                return Ok(lock);
+            }
+            std::thread::sleep(RETRY_INTERVAL);
+        }
+    }
+
+    /// Get the lock, from asynchronous code.
+    ///
+    /// This is like [`RunLock::acquire`], but waiting doesn't block
+    /// the thread.
+    pub async fn acquire_async(
+        filename: &Path,
+        wait: bool,
+        cancel: &CancellationToken,
+    ) -> Result<Self, RunLockError> {
+        let mut attempt = Attempt::new(filename, wait)?;
+        loop {
+            if let Some(lock) = attempt.try_lock(cancel)? {
+                return Ok(lock);
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    }
+}
+
+// Attempts to get the lock, with an open lock file.
+struct Attempt {
+    filename: PathBuf,
+    file: Option<File>,
+    wait: bool,
+    waiting: bool,
+}
+
+impl Attempt {
+    fn new(filename: &Path, wait: bool) -> Result<Self, RunLockError> {
+        let filename = filename.to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&filename)
+            .map_err(|err| RunLockError::Open(filename.clone(), err))?;
+        Ok(Self {
+            filename,
+            file: Some(file),
+            wait,
+            waiting: false,
+        })
+    }
+
+    // Try to get the lock once. Return None if another run holds it,
+    // and waiting for it is allowed.
+    fn try_lock(&mut self, cancel: &CancellationToken) -> Result<Option<RunLock>, RunLockError> {
+        let filename = &self.filename;
+        let file = self.file.as_mut().unwrap();
+        if !try_lock(file).map_err(|err| RunLockError::Lock(filename.clone(), err))? {
+            let holder = read_holder(file);
+            if !self.wait {
+                return Err(RunLockError::Locked(filename.clone(), holder));
+            }
+            if !self.waiting {
+                // This is a warning so that the command line program
+                // shows it on the terminal by default. The library
+                // doesn't write to the terminal itself.
+                warn!(
+                    "waiting for another Obnam run (process {}) to release {}",
+                    holder,
+                    filename.display()
+                );
+                self.waiting = true;
+            }
+            if cancel.is_cancelled() {
+                return Err(RunLockError::Cancelled(filename.clone()));
+            }
+            return Ok(None);
+        }
+
+        // Record who holds the lock, for the error message of other
+        // runs. This is only informational, so errors are ignored.
+        // Reading the holder while waiting moved the file position, so
+        // rewind before writing.
+        let pid = std::process::id();
+        let _ = file
+            .set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(file, "{}", pid))
+            .and_then(|_| file.flush());
+        debug!("process {} holds lock {}", pid, filename.display());
+        Ok(Some(RunLock {
+            _file: self.file.take().unwrap(),
+        }))
+    }
+}
+
+/// Return the name of the lock file for a profile in a configuration
+/// file.
+pub fn lock_filename(config_filename: &Path, profile: Option<&str>) -> PathBuf {
+    let mut filename = config_filename.to_path_buf();
+    match profile {
+        None => filename.set_file_name("obnam.lock"),
+        Some(profile) => filename.set_file_name(format!("obnam-{}.lock", profile)),
+    }
+    filename
+}
+
+// Try to get an exclusive lock on a file. Return false if another
+// process has it.
+fn try_lock(file: &File) -> Result<bool, std::io::Error> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+fn read_holder(file: &mut File) -> String {
+    let mut pid = String::new();
+    match file
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_string(&mut pid))
+    {
+        Ok(_) if !pid.trim().is_empty() => pid.trim().to_string(),
+        _ => "unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lock_filename, RunLock, RunLockError};
+    use crate::error::{ErrorKind, ObnamError, EXIT_LOCKED};
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use tokio_util::sync::CancellationToken;
+
+    #[test]
+    fn second_lock_fails_until_first_is_released() {
+        let tmp = tempdir().unwrap();
+        let config = lock_filename(&tmp.path().join("obnam.yaml"), None);
+        let cancel = CancellationToken::new();
+
+        let first = RunLock::acquire(&config, false, &cancel).unwrap();
+        let pid = std::process::id().to_string();
+        assert!(matches!(
+            RunLock::acquire(&config, false, &cancel),
+            Err(RunLockError::Locked(_, holder)) if holder == pid
+        ));
+        let err = ObnamError::from(RunLock::acquire(&config, false, &cancel).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::Locked);
+        assert_eq!(err.kind().exit_code(), EXIT_LOCKED);
+
+        cancel.cancel();
+        assert!(matches!(
+            RunLock::acquire(&config, true, &cancel),
+            Err(RunLockError::Cancelled(_))
+        ));
+
+        drop(first);
+        RunLock::acquire(&config, false, &cancel).unwrap();
+    }
+
+    #[test]
+    fn records_only_pid_after_waiting() {
+        let tmp = tempdir().unwrap();
+        let config = lock_filename(&tmp.path().join("obnam.yaml"), None);
+        let cancel = CancellationToken::new();
+
+        let first = RunLock::acquire(&config, false, &cancel).unwrap();
+        let waiter = {
+            let config = config.clone();
+            let cancel = cancel.clone();
+            std::thread::spawn(move || RunLock::acquire(&config, true, &cancel).unwrap())
+        };
+        std::thread::sleep(Duration::from_millis(500));
+        drop(first);
+        let _second = waiter.join().unwrap();
+
+        let content = std::fs::read_to_string(&config).unwrap();
+        assert_eq!(content, format!("{}\n", std::process::id()));
+    }
+
+    #[test]
+    fn profiles_have_their_own_locks() {
+        let tmp = tempdir().unwrap();
+        let config = tmp.path().join("obnam.yaml");
+        let cancel = CancellationToken::new();
+
+        let _default = RunLock::acquire(&lock_filename(&config, None), false, &cancel).unwrap();
+        let _a = RunLock::acquire(&lock_filename(&config, Some("a")), false, &cancel).unwrap();
+        assert!(matches!(
+            RunLock::acquire(&lock_filename(&config, Some("a")), false, &cancel),
+            Err(RunLockError::Locked(_, _))
+        ));
+        RunLock::acquire(&lock_filename(&config, Some("b")), false, &cancel).unwrap();
+    }
+
+    #[tokio::test]
+    async fn async_lock_waits_without_blocking() {
+        let tmp = tempdir().unwrap();
+        let config = lock_filename(&tmp.path().join("obnam.yaml"), None);
+        let cancel = CancellationToken::new();
+
+        let first = RunLock::acquire_async(&config, false, &cancel)
+            .await
+            .unwrap();
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            drop(first);
+        };
+        let (second, ()) = tokio::join!(RunLock::acquire_async(&config, true, &cancel), release);
+        second.unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! Verify a random sample of the chunks a backup uploaded.
+//!
+//! The server is trusted to store chunks, but not to store them
+//! correctly. Fetching some of the chunks back after a backup, and
+//! checking them, catches a server that loses or damages chunks
+//! before the backup is needed.
+
+use crate::chunkid::ChunkId;
+use crate::chunkstore::StoreError;
+use crate::client::{BackupClient, ClientError};
+use crate::label::{Label, LabelChecksumKind};
+use log::{info, warn};
+use rand::seq::SliceRandom;
+
+/// What verifying a sample of chunks found.
+#[derive(Debug, Default)]
+pub struct SampleVerification {
+    /// Number of chunks checked.
+    pub checked: usize,
+    /// Chunks that are missing, can't be decrypted, or don't match
+    /// their label.
+    pub damaged: Vec<ChunkId>,
+}
+
+/// How many of `count` items are in a sample of `percent` percent?
+///
+/// Any non-zero percentage samples at least one item.
+pub fn sample_size(count: usize, percent: f64) -> usize {
+    let size = (count as f64 * percent / 100.0).ceil() as usize;
+    size.min(count)
+}
+
+/// Choose a random sample of `percent` percent of the items.
+pub fn choose_sample<T: Clone>(items: &[T], percent: f64) -> Vec<T> {
+    let size = sample_size(items.len(), percent);
+    items
+        .choose_multiple(&mut rand::thread_rng(), size)
+        .cloned()
+        .collect()
+}
+
+/// Fetch a random sample of the chunks the client has uploaded, and
+/// check that they decrypt, and match their labels.
+pub async fn verify_sample(
+    client: &BackupClient,
+    percent: f64,
+) -> Result<SampleVerification, ClientError> {
+    let sample = choose_sample(&client.uploaded_chunks(), percent);
+    info!("verifying {} uploaded chunks", sample.len());
+    let mut verification = SampleVerification::default();
+    for id in sample {
+        verification.checked += 1;
+        let chunk = match client.fetch_chunk(&id).await {
+            Ok(chunk) => chunk,
+            Err(ClientError::ChunkStore(StoreError::NotFound(_))) => {
+                warn!("uploaded chunk {} is missing", id);
+                verification.damaged.push(id);
+                continue;
+            }
+            Err(ClientError::CipherError(err)) => {
+                warn!("uploaded chunk {} can't be decrypted: {}", id, err);
+                verification.damaged.push(id);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        // The label is authenticated by the encryption, so it says
+        // how the content was checksummed. BLAKE2 checksums are
+        // labelled as SHA256 ones, so those may be either. Literal
+        // labels can't be checked further.
+        let expected = chunk.meta().label();
+        let kinds = match Label::deserialize(expected).map(|label| label.kind()) {
+            Ok(Some(LabelChecksumKind::Sha256)) => {
+                vec![LabelChecksumKind::Sha256, LabelChecksumKind::Blake2]
+            }
+            Ok(Some(kind)) => vec![kind],
+            Ok(None) => vec![],
+            Err(_) => {
+                warn!("uploaded chunk {} has a bad label {}", id, expected);
+                verification.damaged.push(id);
+                continue;
+            }
+        };
+        let matches = kinds.is_empty()
+            || kinds
+                .iter()
+                .any(|kind| client.labeler(*kind).label(chunk.data()).serialize() == expected);
+        if !matches {
+            warn!("uploaded chunk {} doesn't match its label", id);
+            verification.damaged.push(id);
+        }
+    }
+    Ok(verification)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{choose_sample, sample_size};
+    use std::collections::HashSet;
+
+    #[test]
+    fn samples_nothing_at_zero_percent() {
+        assert_eq!(sample_size(100, 0.0), 0);
+    }
+
+    #[test]
+    fn samples_nothing_from_nothing() {
+        assert_eq!(sample_size(0, 50.0), 0);
+    }
+
+    #[test]
+    fn samples_everything_at_hundred_percent() {
+        assert_eq!(sample_size(7, 100.0), 7);
+    }
+
+    #[test]
+    fn samples_at_least_one() {
+        assert_eq!(sample_size(10, 1.0), 1);
+        assert_eq!(sample_size(200, 1.5), 3);
+    }
+
+    #[test]
+    fn sample_has_distinct_items() {
+        let items: Vec<usize> = (0..100).collect();
+        let sample = choose_sample(&items, 25.0);
+        assert_eq!(sample.len(), 25);
+        let distinct: HashSet<usize> = sample.into_iter().collect();
+        assert_eq!(distinct.len(), 25);
+    }
+}
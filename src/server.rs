@@ -1,25 +1,72 @@
 //! Stuff related to the Obnam chunk server.
 
+use crate::acme::{AcmeConfig, AcmeError};
 use crate::chunk::DataChunk;
 use crate::chunkid::ChunkId;
 use crate::chunkmeta::ChunkMeta;
+use crate::chunkstore::{Durability, Sharding};
+use crate::logging::SystemLog;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::default::Default;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
+/// Prefix of a server address that is the path to a Unix domain socket.
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// Default largest chunk the server accepts, in bytes.
+pub const DEFAULT_MAX_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
 /// Server configuration.
 #[derive(Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct ServerConfig {
     /// Path to directory where chunks are stored.
     pub chunks: PathBuf,
-    /// Address where server is to listen.
+    /// Address where server is to listen: either a host and port,
+    /// or `unix:` followed by the path to a Unix domain socket.
     pub address: String,
+    /// Use HTTPS. Without it, plain HTTP is served, for use behind a
+    /// reverse proxy that takes care of TLS.
+    #[serde(default = "default_tls")]
+    pub tls: bool,
     /// Path to TLS key.
-    pub tls_key: PathBuf,
+    pub tls_key: Option<PathBuf>,
     /// Path to TLS certificate.
-    pub tls_cert: PathBuf,
+    pub tls_cert: Option<PathBuf>,
+    /// Obtain the TLS certificate automatically with ACME, instead of
+    /// using `tls_key` and `tls_cert`.
+    pub acme: Option<AcmeConfig>,
+    /// Largest chunk, in bytes, that clients may upload. Larger
+    /// uploads are rejected.
+    #[serde(default = "default_max_chunk_size")]
+    pub max_chunk_size: u64,
+    /// How carefully chunks are written to disk.
+    #[serde(default)]
+    pub durability: Durability,
+    /// How chunk files are spread over subdirectories.
+    #[serde(default)]
+    pub sharding: Sharding,
+    /// Keep count of which backup generations use each chunk, so
+    /// that clients can remove a generation's chunks cheaply when
+    /// they forget it.
+    #[serde(default)]
+    pub refcounts: bool,
+    /// Keep removed chunks in the trash for this many days, so that
+    /// they can be restored. Without this, removed chunks are deleted
+    /// at once.
+    pub trash_days: Option<u64>,
+    /// Log to this system log, instead of the standard error output.
+    pub system_log: Option<SystemLog>,
+}
+
+fn default_tls() -> bool {
+    true
+}
+
+fn default_max_chunk_size() -> u64 {
+    DEFAULT_MAX_CHUNK_SIZE
 }
 
 /// Possible errors wittht server configuration.
@@ -37,6 +84,34 @@ pub enum ServerConfigError {
     #[error("TLS key {0} does not exist")]
     TlsKeyNotFound(PathBuf),
 
+    /// TLS is used, but the certificate isn't set.
+    #[error("tls_cert must be set when using TLS")]
+    TlsCertMissing,
+
+    /// TLS is used, but the key isn't set.
+    #[error("tls_key must be set when using TLS")]
+    TlsKeyMissing,
+
+    /// ACME is used, but so are static TLS key and certificate files.
+    #[error("tls_key and tls_cert can't be set when using ACME")]
+    AcmeWithTlsFiles,
+
+    /// ACME is used without TLS.
+    #[error("ACME can't be used without TLS")]
+    AcmeWithoutTls,
+
+    /// The ACME configuration is wrong.
+    #[error(transparent)]
+    Acme(AcmeError),
+
+    /// TLS is used with a Unix domain socket.
+    #[error("TLS can't be used with a Unix domain socket, set tls to false")]
+    UnixSocketTls,
+
+    /// The largest allowed chunk size is zero.
+    #[error("max_chunk_size must be larger than zero")]
+    ZeroMaxChunkSize,
+
     /// Server address is wrong.
     #[error("server address can't be resolved")]
     BadServerAddress,
@@ -67,14 +142,59 @@ impl ServerConfig {
         if !self.chunks.exists() {
             return Err(ServerConfigError::ChunksDirNotFound(self.chunks.clone()));
         }
-        if !self.tls_cert.exists() {
-            return Err(ServerConfigError::TlsCertNotFound(self.tls_cert.clone()));
+        if self.tls {
+            if self.unix_socket().is_some() {
+                return Err(ServerConfigError::UnixSocketTls);
+            }
+            if let Some(acme) = &self.acme {
+                if self.tls_cert.is_some() || self.tls_key.is_some() {
+                    return Err(ServerConfigError::AcmeWithTlsFiles);
+                }
+                acme.check().map_err(ServerConfigError::Acme)?;
+            } else {
+                let cert = self
+                    .tls_cert
+                    .as_ref()
+                    .ok_or(ServerConfigError::TlsCertMissing)?;
+                if !cert.exists() {
+                    return Err(ServerConfigError::TlsCertNotFound(cert.clone()));
+                }
+                let key = self
+                    .tls_key
+                    .as_ref()
+                    .ok_or(ServerConfigError::TlsKeyMissing)?;
+                if !key.exists() {
+                    return Err(ServerConfigError::TlsKeyNotFound(key.clone()));
+                }
+            }
+        } else if self.acme.is_some() {
+            return Err(ServerConfigError::AcmeWithoutTls);
         }
-        if !self.tls_key.exists() {
-            return Err(ServerConfigError::TlsKeyNotFound(self.tls_key.clone()));
+        if self.max_chunk_size == 0 {
+            return Err(ServerConfigError::ZeroMaxChunkSize);
         }
         Ok(())
     }
+
+    /// Paths to the TLS key and certificate to use, if TLS is used.
+    pub fn tls_files(&self) -> Option<(PathBuf, PathBuf)> {
+        if !self.tls {
+            None
+        } else if let Some(acme) = &self.acme {
+            Some((acme.key_path(), acme.cert_path()))
+        } else {
+            match (&self.tls_key, &self.tls_cert) {
+                (Some(key), Some(cert)) => Some((key.clone(), cert.clone())),
+                _ => None,
+            }
+        }
+    }
+
+    /// Path to the Unix domain socket to listen on, if the address
+    /// is one.
+    pub fn unix_socket(&self) -> Option<&Path> {
+        self.address.strip_prefix(UNIX_SOCKET_PREFIX).map(Path::new)
+    }
 }
 
 /// Result of creating a chunk.
@@ -114,6 +234,219 @@ impl Fetched {
     }
 }
 
+/// A byte range of a chunk, as requested with an HTTP `Range` header.
+///
+/// Only a single range is supported, not a list of them.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ByteRange {
+    /// From an offset to the end of the chunk.
+    From(u64),
+    /// From an offset to another, inclusive.
+    FromTo(u64, u64),
+    /// The last bytes of the chunk.
+    Last(u64),
+}
+
+/// Possible errors from parsing a byte range.
+#[derive(Debug, thiserror::Error)]
+pub enum RangeError {
+    /// The range isn't in a form we understand.
+    #[error("can't parse byte range {0:?}")]
+    Parse(String),
+}
+
+impl ByteRange {
+    /// Create a range for the given offsets, excluding `range.end`.
+    ///
+    /// Return None for an empty range, which HTTP can't express.
+    pub fn new(range: Range<u64>) -> Option<Self> {
+        if range.is_empty() {
+            None
+        } else {
+            Some(Self::FromTo(range.start, range.end - 1))
+        }
+    }
+
+    /// Parse the value of a `Range` header.
+    pub fn parse(header: &str) -> Result<Self, RangeError> {
+        let err = || RangeError::Parse(header.to_string());
+        let spec = header.trim().strip_prefix("bytes=").ok_or_else(err)?;
+        let (start, end) = spec.split_once('-').ok_or_else(err)?;
+        let num = |s: &str| s.trim().parse::<u64>().map_err(|_| err());
+        match (start.trim().is_empty(), end.trim().is_empty()) {
+            (false, true) => Ok(Self::From(num(start)?)),
+            (false, false) => {
+                let (start, end) = (num(start)?, num(end)?);
+                if start > end {
+                    return Err(err());
+                }
+                Ok(Self::FromTo(start, end))
+            }
+            (true, false) => Ok(Self::Last(num(end)?)),
+            (true, true) => Err(err()),
+        }
+    }
+
+    /// Return the offsets of the range in a chunk of a given length,
+    /// excluding `end`. Return None if no part of the range is in the
+    /// chunk.
+    pub fn resolve(&self, len: u64) -> Option<Range<u64>> {
+        match *self {
+            Self::From(start) if start < len => Some(start..len),
+            Self::FromTo(start, end) if start < len => Some(start..len.min(end + 1)),
+            Self::Last(n) if n > 0 && len > 0 => Some(len.saturating_sub(n)..len),
+            _ => None,
+        }
+    }
+
+    /// Value for a `Range` header.
+    pub fn to_header(&self) -> String {
+        match self {
+            Self::From(start) => format!("bytes={}-", start),
+            Self::FromTo(start, end) => format!("bytes={}-{}", start, end),
+            Self::Last(n) => format!("bytes=-{}", n),
+        }
+    }
+}
+
+/// The part of a chunk in a partial response, as given in an HTTP
+/// `Content-Range` header.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ContentRange {
+    /// Offsets of the part, excluding `end`.
+    pub range: Range<u64>,
+    /// Length of the whole chunk.
+    pub total: u64,
+}
+
+impl ContentRange {
+    /// Parse the value of a `Content-Range` header.
+    pub fn parse(header: &str) -> Result<Self, RangeError> {
+        let err = || RangeError::Parse(header.to_string());
+        let spec = header.trim().strip_prefix("bytes ").ok_or_else(err)?;
+        let (range, total) = spec.split_once('/').ok_or_else(err)?;
+        let (start, end) = range.split_once('-').ok_or_else(err)?;
+        let num = |s: &str| s.trim().parse::<u64>().map_err(|_| err());
+        let (start, end, total) = (num(start)?, num(end)?, num(total)?);
+        if start > end || end >= total {
+            return Err(err());
+        }
+        Ok(Self {
+            range: start..end + 1,
+            total,
+        })
+    }
+
+    /// Value for a `Content-Range` header.
+    pub fn to_header(&self) -> String {
+        format!(
+            "bytes {}-{}/{}",
+            self.range.start,
+            self.range.end - 1,
+            self.total
+        )
+    }
+}
+
+/// Largest number of labels in one query of which chunks exist.
+pub const MAX_EXISTS_LABELS: usize = 1000;
+
+/// A query of which chunks exist, by label, in one request.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExistsQuery {
+    /// Labels of chunks to look for.
+    pub labels: Vec<String>,
+}
+
+/// Answer to an [`ExistsQuery`].
+///
+/// For each label in the query, in order, one bit tells if there's a
+/// chunk with that label. The bits are packed into bytes, least
+/// significant bit first, and the bytes are encoded as hexadecimal.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExistsBitmap {
+    bitmap: String,
+}
+
+impl ExistsBitmap {
+    /// Create a bitmap.
+    pub fn new(exists: &[bool]) -> Self {
+        let mut bytes = vec![0u8; (exists.len() + 7) / 8];
+        for (i, _) in exists.iter().enumerate().filter(|(_, e)| **e) {
+            bytes[i / 8] |= 1 << (i % 8);
+        }
+        let bitmap = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        Self { bitmap }
+    }
+
+    /// Return the bits for a query of a given number of labels.
+    ///
+    /// Return None if the bitmap is malformed or too short.
+    pub fn bits(&self, count: usize) -> Option<Vec<bool>> {
+        if self.bitmap.len() != (count + 7) / 8 * 2 {
+            return None;
+        }
+        let mut bytes = vec![];
+        for i in (0..self.bitmap.len()).step_by(2) {
+            bytes.push(u8::from_str_radix(self.bitmap.get(i..i + 2)?, 16).ok()?);
+        }
+        Some(
+            (0..count)
+                .map(|i| bytes[i / 8] & (1 << (i % 8)) != 0)
+                .collect(),
+        )
+    }
+
+    /// Convert to JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
+/// The chunks a backup generation uses, when registering it with
+/// the server.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenerationChunks {
+    /// Ids of the chunks.
+    pub chunks: Vec<ChunkId>,
+}
+
+/// A query of which chunks would be freed by forgetting some
+/// registered backup generations.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FreedQuery {
+    /// Ids of the generations.
+    pub generations: Vec<ChunkId>,
+}
+
+/// Chunks that no remaining registered generation uses.
+#[derive(Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FreedChunks {
+    /// Ids of the chunks.
+    pub chunks: Vec<ChunkId>,
+    /// Total size of the chunks, in bytes, as far as the server
+    /// knows.
+    pub bytes: u64,
+}
+
+impl FreedChunks {
+    /// Add the chunks from another answer.
+    pub fn extend(&mut self, other: FreedChunks) {
+        self.chunks.extend(other.chunks);
+        self.bytes += other.bytes;
+    }
+
+    /// Convert to JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
 /// Result of a search.
 #[derive(Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
 pub struct SearchHits {
@@ -162,3 +495,119 @@ mod test_search_hits {
         assert_eq!(hits, hits2);
     }
 }
+
+#[cfg(test)]
+mod test_ranges {
+    use super::{ByteRange, ContentRange};
+
+    #[test]
+    fn parses_range_headers() {
+        assert_eq!(ByteRange::parse("bytes=10-").unwrap(), ByteRange::From(10));
+        assert_eq!(
+            ByteRange::parse("bytes=10-19").unwrap(),
+            ByteRange::FromTo(10, 19)
+        );
+        assert_eq!(ByteRange::parse("bytes=-5").unwrap(), ByteRange::Last(5));
+        assert!(ByteRange::parse("bytes=20-10").is_err());
+        assert!(ByteRange::parse("bytes=0-1,5-6").is_err());
+        assert!(ByteRange::parse("lines=0-1").is_err());
+    }
+
+    #[test]
+    fn resolves_ranges_against_chunk_length() {
+        assert_eq!(ByteRange::From(10).resolve(100), Some(10..100));
+        assert_eq!(ByteRange::FromTo(10, 200).resolve(100), Some(10..100));
+        assert_eq!(ByteRange::Last(200).resolve(100), Some(0..100));
+        assert_eq!(ByteRange::From(100).resolve(100), None);
+        assert_eq!(ByteRange::Last(0).resolve(100), None);
+    }
+
+    #[test]
+    fn content_range_roundtrips() {
+        let cr = ContentRange {
+            range: 10..20,
+            total: 100,
+        };
+        assert_eq!(cr.to_header(), "bytes 10-19/100");
+        assert_eq!(ContentRange::parse(&cr.to_header()).unwrap(), cr);
+        assert!(ContentRange::parse("bytes 10-100/100").is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_exists_bitmap {
+    use super::ExistsBitmap;
+
+    #[test]
+    fn bitmap_roundtrips() {
+        let exists = [true, false, false, true, false, false, false, false, true];
+        let bitmap = ExistsBitmap::new(&exists);
+        assert_eq!(bitmap.bitmap, "0901");
+        assert_eq!(bitmap.bits(exists.len()).unwrap(), exists);
+    }
+
+    #[test]
+    fn rejects_bitmap_of_wrong_length() {
+        let bitmap = ExistsBitmap::new(&[true, true]);
+        assert_eq!(bitmap.bits(9), None);
+    }
+}
+
+#[cfg(test)]
+mod test_config {
+    use super::{Durability, ServerConfig, ServerConfigError, DEFAULT_MAX_CHUNK_SIZE};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn max_chunk_size_has_default() {
+        let config: ServerConfig = serde_yaml::from_str(
+            "chunks: /srv/obnam\naddress: localhost:8888\ntls_key: k\ntls_cert: c\n",
+        )
+        .unwrap();
+        assert_eq!(config.max_chunk_size, DEFAULT_MAX_CHUNK_SIZE);
+        assert_eq!(config.durability, Durability::File);
+        assert!(config.tls);
+    }
+
+    #[test]
+    fn plain_http_needs_no_tls_files() {
+        let config: ServerConfig =
+            serde_yaml::from_str("chunks: .\naddress: unix:/run/obnam.sock\ntls: false\n").unwrap();
+        assert!(config.check().is_ok());
+        assert_eq!(config.unix_socket(), Some(Path::new("/run/obnam.sock")));
+    }
+
+    #[test]
+    fn tls_needs_cert_and_key() {
+        let config: ServerConfig =
+            serde_yaml::from_str("chunks: .\naddress: localhost:8888\n").unwrap();
+        assert!(matches!(
+            config.check(),
+            Err(ServerConfigError::TlsCertMissing)
+        ));
+        assert_eq!(config.unix_socket(), None);
+    }
+
+    #[test]
+    fn acme_replaces_tls_files() {
+        let config: ServerConfig = serde_yaml::from_str(
+            "chunks: .\naddress: localhost:443\nacme:\n  domains: [a.example]\n  state: .\n  accept_terms: true\n",
+        )
+        .unwrap();
+        assert!(config.check().is_ok());
+        assert_eq!(
+            config.tls_files(),
+            Some((PathBuf::from("./tls.key"), PathBuf::from("./tls.pem")))
+        );
+    }
+
+    #[test]
+    fn tls_is_refused_on_unix_socket() {
+        let config: ServerConfig =
+            serde_yaml::from_str("chunks: .\naddress: unix:/run/obnam.sock\n").unwrap();
+        assert!(matches!(
+            config.check(),
+            Err(ServerConfigError::UnixSocketTls)
+        ));
+    }
+}
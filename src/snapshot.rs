@@ -0,0 +1,383 @@
+//! Snapshots of backup roots.
+//!
+//! A backup of a live file system may capture files in an
+//! inconsistent state, if they change while the backup is running.
+//! To avoid that, Obnam can make a read-only snapshot of each backup
+//! root, back up the snapshot instead, and remove the snapshot
+//! afterwards. Files are recorded in the backup using their original
+//! path names, not the path to the snapshot.
+//!
+//! Snapshots are made with the tools of the underlying storage layer,
+//! which usually requires running as root.
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Default size of LVM snapshot volumes.
+const DEFAULT_LVM_SIZE: &str = "1G";
+
+/// How to make snapshots of backup roots.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotMethod {
+    /// The backup root is a btrfs subvolume; make a read-only btrfs
+    /// snapshot of it.
+    Btrfs,
+
+    /// The backup root is on an LVM logical volume; make an LVM
+    /// snapshot of the volume and mount it read-only.
+    Lvm,
+}
+
+/// Configuration for snapshots of backup roots.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// How to make snapshots.
+    pub method: SnapshotMethod,
+
+    /// Directory where snapshots are put (btrfs) or mounted (LVM).
+    /// For btrfs, this must be on the same file system as the backup
+    /// root; the default is the parent directory of the root. For
+    /// LVM, the default is the system temporary directory.
+    pub directory: Option<PathBuf>,
+
+    /// Size of the LVM snapshot volume, in a form `lvcreate`
+    /// understands. This limits how much may change on the original
+    /// volume while the backup runs.
+    pub lvm_size: Option<String>,
+}
+
+/// Possible errors from snapshots.
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    /// Couldn't run a command.
+    #[error("failed to run {0}: {1}")]
+    Run(String, std::io::Error),
+
+    /// A command failed.
+    #[error("command {0} failed: {1}")]
+    CommandFailed(String, String),
+
+    /// Couldn't make sense of the output of a command.
+    #[error("unexpected output from {0}: {1:?}")]
+    BadOutput(String, String),
+
+    /// Backup root has no parent directory to put a snapshot in.
+    #[error("can't snapshot {0}: it has no parent directory")]
+    NoParent(PathBuf),
+
+    /// Couldn't create or remove a mount point.
+    #[error("failed to create or remove snapshot mount point {0}: {1}")]
+    MountPoint(PathBuf, std::io::Error),
+}
+
+/// A snapshot of one backup root.
+///
+/// The snapshot is removed when [`Snapshot::remove`] is called, or
+/// when the value is dropped, whichever comes first.
+#[derive(Debug)]
+pub struct Snapshot {
+    root: PathBuf,
+    path: PathBuf,
+    teardown: Vec<Teardown>,
+}
+
+// One step of removing a snapshot. Steps are done in order.
+#[derive(Debug)]
+enum Teardown {
+    DeleteSubvolume(PathBuf),
+    Unmount(PathBuf),
+    RemoveDir(PathBuf),
+    RemoveVolume(String),
+}
+
+impl Snapshot {
+    /// Make a snapshot of a backup root.
+    pub fn create(config: &SnapshotConfig, root: &Path) -> Result<Self, SnapshotError> {
+        info!("making {:?} snapshot of {}", config.method, root.display());
+        match config.method {
+            SnapshotMethod::Btrfs => Self::btrfs(config, root),
+            SnapshotMethod::Lvm => Self::lvm(config, root),
+        }
+    }
+
+    fn btrfs(config: &SnapshotConfig, root: &Path) -> Result<Self, SnapshotError> {
+        let dir = match &config.directory {
+            Some(dir) => dir.to_path_buf(),
+            None => root
+                .parent()
+                .ok_or_else(|| SnapshotError::NoParent(root.to_path_buf()))?
+                .to_path_buf(),
+        };
+        let path = dir.join(snapshot_name());
+        run(
+            "btrfs",
+            &[
+                OsStr::new("subvolume"),
+                OsStr::new("snapshot"),
+                OsStr::new("-r"),
+                root.as_os_str(),
+                path.as_os_str(),
+            ],
+        )?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            path: path.clone(),
+            teardown: vec![Teardown::DeleteSubvolume(path)],
+        })
+    }
+
+    fn lvm(config: &SnapshotConfig, root: &Path) -> Result<Self, SnapshotError> {
+        let output = run(
+            "findmnt",
+            &[
+                OsStr::new("-n"),
+                OsStr::new("-o"),
+                OsStr::new("SOURCE,TARGET,FSTYPE"),
+                OsStr::new("--target"),
+                root.as_os_str(),
+            ],
+        )?;
+        let (device, mount_point, fstype) = parse_findmnt(&output)?;
+
+        let output = run(
+            "lvs",
+            &[
+                OsStr::new("--noheadings"),
+                OsStr::new("-o"),
+                OsStr::new("vg_name,lv_name"),
+                OsStr::new(&device),
+            ],
+        )?;
+        let (vg, lv) = parse_lvs(&output)?;
+
+        let mut snapshot = Self {
+            root: root.to_path_buf(),
+            path: PathBuf::new(),
+            teardown: vec![],
+        };
+
+        let name = snapshot_name();
+        let size = config.lvm_size.as_deref().unwrap_or(DEFAULT_LVM_SIZE);
+        let origin = format!("{}/{}", vg, lv);
+        run(
+            "lvcreate",
+            &[
+                OsStr::new("--snapshot"),
+                OsStr::new("--size"),
+                OsStr::new(size),
+                OsStr::new("--name"),
+                OsStr::new(&name),
+                OsStr::new(&origin),
+            ],
+        )?;
+        snapshot
+            .teardown
+            .push(Teardown::RemoveVolume(format!("{}/{}", vg, name)));
+
+        let dir = match &config.directory {
+            Some(dir) => dir.to_path_buf(),
+            None => std::env::temp_dir(),
+        };
+        let mnt = dir.join(&name);
+        std::fs::create_dir_all(&mnt).map_err(|err| SnapshotError::MountPoint(mnt.clone(), err))?;
+        snapshot
+            .teardown
+            .insert(0, Teardown::RemoveDir(mnt.clone()));
+
+        // XFS refuses to mount a file system with the same UUID as
+        // one that is already mounted, which a snapshot has.
+        let options = if fstype == "xfs" { "ro,nouuid" } else { "ro" };
+        let snapdev = format!("/dev/{}/{}", vg, name);
+        run(
+            "mount",
+            &[
+                OsStr::new("-o"),
+                OsStr::new(options),
+                OsStr::new(&snapdev),
+                mnt.as_os_str(),
+            ],
+        )?;
+        snapshot.teardown.insert(0, Teardown::Unmount(mnt.clone()));
+
+        snapshot.path = match root.strip_prefix(&mount_point) {
+            Ok(relative) => mnt.join(relative),
+            Err(_) => mnt,
+        };
+        Ok(snapshot)
+    }
+
+    /// Path to the backup root in the snapshot.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Map a path in the snapshot to the corresponding original path.
+    pub fn original(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.path) {
+            Ok(relative) if relative.as_os_str().is_empty() => self.root.clone(),
+            Ok(relative) => self.root.join(relative),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    /// Map an original path to the corresponding path in the
+    /// snapshot.
+    pub fn live(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.root) {
+            Ok(relative) if relative.as_os_str().is_empty() => self.path.clone(),
+            Ok(relative) => self.path.join(relative),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    /// Remove the snapshot.
+    pub fn remove(mut self) -> Result<(), SnapshotError> {
+        self.teardown()
+    }
+
+    fn teardown(&mut self) -> Result<(), SnapshotError> {
+        while !self.teardown.is_empty() {
+            let step = self.teardown.remove(0);
+            debug!("snapshot teardown: {:?}", step);
+            match step {
+                Teardown::DeleteSubvolume(path) => {
+                    run(
+                        "btrfs",
+                        &[
+                            OsStr::new("subvolume"),
+                            OsStr::new("delete"),
+                            path.as_os_str(),
+                        ],
+                    )?;
+                }
+                Teardown::Unmount(path) => {
+                    run("umount", &[path.as_os_str()])?;
+                }
+                Teardown::RemoveDir(path) => {
+                    std::fs::remove_dir(&path)
+                        .map_err(|err| SnapshotError::MountPoint(path, err))?;
+                }
+                Teardown::RemoveVolume(name) => {
+                    run("lvremove", &[OsStr::new("-f"), OsStr::new(&name)])?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Err(err) = self.teardown() {
+            warn!(
+                "failed to remove snapshot of {}: {}",
+                self.root.display(),
+                err
+            );
+        }
+    }
+}
+
+// A name for a new snapshot that won't collide with other runs.
+fn snapshot_name() -> String {
+    format!("obnam-snapshot-{}", std::process::id())
+}
+
+// Run a command, return its standard output.
+fn run(cmd: &str, args: &[&OsStr]) -> Result<String, SnapshotError> {
+    let display = format!(
+        "{} {}",
+        cmd,
+        args.iter()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+            .join(" ")
+    );
+    debug!("running {}", display);
+    let output = Command::new(cmd)
+        .args(args)
+        .output()
+        .map_err(|err| SnapshotError::Run(display.clone(), err))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(SnapshotError::CommandFailed(display, stderr));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Parse output of "findmnt -n -o SOURCE,TARGET,FSTYPE".
+fn parse_findmnt(output: &str) -> Result<(String, PathBuf, String), SnapshotError> {
+    let words: Vec<&str> = output.split_whitespace().collect();
+    match words[..] {
+        [source, target, fstype] => Ok((
+            source.to_string(),
+            PathBuf::from(target),
+            fstype.to_string(),
+        )),
+        _ => Err(SnapshotError::BadOutput(
+            "findmnt".to_string(),
+            output.to_string(),
+        )),
+    }
+}
+
+// Parse output of "lvs --noheadings -o vg_name,lv_name".
+fn parse_lvs(output: &str) -> Result<(String, String), SnapshotError> {
+    let words: Vec<&str> = output.split_whitespace().collect();
+    match words[..] {
+        [vg, lv] => Ok((vg.to_string(), lv.to_string())),
+        _ => Err(SnapshotError::BadOutput(
+            "lvs".to_string(),
+            output.to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_findmnt, parse_lvs, Snapshot};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn maps_snapshot_paths_to_original() {
+        let snapshot = Snapshot {
+            root: PathBuf::from("/home"),
+            path: PathBuf::from("/mnt/snap/home"),
+            teardown: vec![],
+        };
+        assert_eq!(
+            snapshot.original(Path::new("/mnt/snap/home")),
+            PathBuf::from("/home")
+        );
+        assert_eq!(
+            snapshot.original(Path::new("/mnt/snap/home/liw/notes.txt")),
+            PathBuf::from("/home/liw/notes.txt")
+        );
+        assert_eq!(
+            snapshot.live(Path::new("/home/liw/notes.txt")),
+            PathBuf::from("/mnt/snap/home/liw/notes.txt")
+        );
+    }
+
+    #[test]
+    fn parses_findmnt_output() {
+        let (dev, mnt, fstype) = parse_findmnt("/dev/mapper/vg-home /home ext4\n").unwrap();
+        assert_eq!(dev, "/dev/mapper/vg-home");
+        assert_eq!(mnt, PathBuf::from("/home"));
+        assert_eq!(fstype, "ext4");
+    }
+
+    #[test]
+    fn parses_lvs_output() {
+        assert_eq!(
+            parse_lvs("  vg   home\n").unwrap(),
+            ("vg".to_string(), "home".to_string())
+        );
+        assert!(parse_lvs("").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+//! Helpers for tests that make backups in a local chunk store.
+
+use crate::api::{backup_with, BackupOptions, BackupReport, Quiet, RestoreReport};
+use crate::chunkid::ChunkId;
+use crate::client::BackupClient;
+use crate::cmd::restore::{restore, OwnerMap, OwnerPolicy, RestoreOptions};
+use crate::config::ClientConfig;
+use crate::error::ObnamError;
+use crate::passwords::{passwords_filename, Passwords};
+use crate::performance::Performance;
+use crate::progress_sink::ProgressSink;
+use std::path::{Path, PathBuf};
+use tempfile::{tempdir, TempDir};
+use tokio_util::sync::CancellationToken;
+use walkdir::WalkDir;
+
+/// A client configuration, a directory with live data to back up,
+/// and a chunk directory to back it up to, all in a temporary
+/// directory.
+pub(crate) struct TestRepo {
+    dir: TempDir,
+    pub(crate) config: ClientConfig,
+}
+
+impl TestRepo {
+    /// Create a new, empty repository.
+    pub(crate) fn new() -> Self {
+        Self::with_settings("")
+    }
+
+    /// Create a new, empty repository, with extra client settings in
+    /// YAML.
+    pub(crate) fn with_settings(settings: &str) -> Self {
+        let dir = tempdir().unwrap();
+        let live = dir.path().join("live");
+        std::fs::create_dir(&live).unwrap();
+        std::fs::create_dir(dir.path().join("chunks")).unwrap();
+        let filename = dir.path().join("client.yaml");
+        std::fs::write(
+            &filename,
+            format!(
+                "server_url: https://localhost\nroots: [{}]\ncache_generations: false\n{}",
+                live.display(),
+                settings
+            ),
+        )
+        .unwrap();
+        Passwords::new("secret")
+            .save(&passwords_filename(&filename, None))
+            .unwrap();
+        let config = ClientConfig::read(&filename).unwrap();
+        Self { dir, config }
+    }
+
+    /// The directory with the live data.
+    pub(crate) fn live(&self) -> PathBuf {
+        self.dir.path().join("live")
+    }
+
+    /// The chunk directory.
+    pub(crate) fn chunks(&self) -> PathBuf {
+        self.dir.path().join("chunks")
+    }
+
+    /// Remove a chunk from the chunk directory, as if it had been
+    /// lost.
+    pub(crate) fn remove_chunk(&self, id: &ChunkId) {
+        let data = format!("{}.data", id);
+        let mut found = false;
+        for e in WalkDir::new(self.chunks()) {
+            let e = e.unwrap();
+            if e.file_name().to_string_lossy() == data {
+                std::fs::remove_file(e.path()).unwrap();
+                found = true;
+            }
+        }
+        assert!(found, "chunk {} is not in the chunk directory", id);
+    }
+
+    /// A client that uses the chunk directory.
+    pub(crate) fn client(&self) -> BackupClient {
+        BackupClient::local(&self.config, &self.chunks()).unwrap()
+    }
+
+    /// Back up the live data.
+    pub(crate) async fn backup(&self) -> Result<BackupReport, ObnamError> {
+        self.backup_with(CancellationToken::new(), vec![]).await
+    }
+
+    /// Back up the live data, reporting progress to `sinks`, and
+    /// stopping if `cancel` is cancelled.
+    pub(crate) async fn backup_with(
+        &self,
+        cancel: CancellationToken,
+        sinks: Vec<Box<dyn ProgressSink>>,
+    ) -> Result<BackupReport, ObnamError> {
+        let options = BackupOptions {
+            progress_bars: false,
+            cancel,
+            ..BackupOptions::default()
+        };
+        let mut client = self.client();
+        let mut perf = Performance::default();
+        backup_with(&mut client, &self.config, &options, sinks, &mut perf).await
+    }
+
+    /// Restore a backup from the chunk directory into `to`. With
+    /// `keep_going`, chunks that can't be fetched are filled in with
+    /// zeros.
+    pub(crate) async fn restore(
+        &self,
+        gen: &str,
+        to: &Path,
+        keep_going: bool,
+    ) -> Result<RestoreReport, ObnamError> {
+        let chunks = self.chunks();
+        let options = RestoreOptions {
+            owners: OwnerMap::new(OwnerPolicy::Numeric, None, None)?,
+            progress_bar: false,
+            progress: &Quiet,
+            warnings: &Quiet,
+            cancel: CancellationToken::new(),
+            jobs: self.config.jobs,
+            keep_going,
+            from_dir: Some(&chunks),
+            wait: false,
+        };
+        restore(&self.config, gen, to, &options).await
+    }
+}
@@ -1,6 +1,8 @@
 //! A queue of work for [`crate::engine::Engine`].
 
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, error::SendError};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 /// A queue of work items.
 ///
@@ -16,21 +18,85 @@ use tokio::sync::mpsc;
 /// into the queue. If the queue is empty, the consumer blocks until
 /// there is something added to the queue.
 ///
+/// Items have a priority. High priority items are taken from the
+/// queue before any normal priority ones, and items of the same
+/// priority in the order they were added. The queue can also be
+/// limited by the total size of the items in it, in bytes, so that
+/// large items don't use too much memory while they wait.
+///
 /// The work items need to be abstracted as a type, and that type is
 /// given as a type parameter.
 pub struct WorkQueue<T> {
-    rx: mpsc::Receiver<T>,
-    tx: Option<mpsc::Sender<T>>,
+    high: mpsc::Receiver<Queued<T>>,
+    normal: mpsc::Receiver<Queued<T>>,
+    high_open: bool,
+    normal_open: bool,
+    tx: Option<WorkSender<T>>,
     size: usize,
 }
 
+// An item in the queue, with the permits for the bytes it takes, if
+// the queue is limited by size. The permits are given back when the
+// item is dropped, whether it's taken out of the queue or never gets
+// into it.
+type Queued<T> = (T, Option<OwnedSemaphorePermit>);
+
+/// How urgent an item of work is.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Priority {
+    /// Taken from the queue before any normal priority items.
+    High,
+    /// Taken from the queue when there are no high priority items.
+    Normal,
+}
+
+/// A handle for adding items of work to a [`WorkQueue`].
+pub struct WorkSender<T> {
+    high: mpsc::Sender<Queued<T>>,
+    normal: mpsc::Sender<Queued<T>>,
+    bytes: Option<ByteLimit>,
+}
+
+// The bytes the items in a queue may take. Adding an item takes
+// permits for its size, which go with the item through the queue.
+struct ByteLimit {
+    permits: Arc<Semaphore>,
+    max: u32,
+}
+
 impl<T> WorkQueue<T> {
     /// Create a new work queue of a given maximum size.
+    ///
+    /// The size applies to each priority separately.
     pub fn new(queue_size: usize) -> Self {
-        let (tx, rx) = mpsc::channel(queue_size);
+        Self::create(queue_size, None)
+    }
+
+    /// Create a new work queue of a given maximum size, that also
+    /// holds at most `max_bytes` worth of items at a time.
+    ///
+    /// An item larger than that is let in once the queue is empty.
+    pub fn with_byte_limit(queue_size: usize, max_bytes: u32) -> Self {
+        let bytes = ByteLimit {
+            permits: Arc::new(Semaphore::new(max_bytes as usize)),
+            max: max_bytes,
+        };
+        Self::create(queue_size, Some(bytes))
+    }
+
+    fn create(queue_size: usize, bytes: Option<ByteLimit>) -> Self {
+        let (high_tx, high) = mpsc::channel(queue_size);
+        let (normal_tx, normal) = mpsc::channel(queue_size);
         Self {
-            rx,
-            tx: Some(tx),
+            high,
+            normal,
+            high_open: true,
+            normal_open: true,
+            tx: Some(WorkSender {
+                high: high_tx,
+                normal: normal_tx,
+                bytes,
+            }),
             size: queue_size,
         }
     }
@@ -41,7 +107,7 @@ impl<T> WorkQueue<T> {
     }
 
     /// Add an item of work to the queue.
-    pub fn push(&self) -> mpsc::Sender<T> {
+    pub fn push(&self) -> WorkSender<T> {
         self.tx.as_ref().unwrap().clone()
     }
 
@@ -54,9 +120,150 @@ impl<T> WorkQueue<T> {
         self.tx = None;
     }
 
-    /// Get the oldest work item from the queue, if any.
+    /// Get the oldest work item of the highest priority from the
+    /// queue, if any.
     pub async fn next(&mut self) -> Option<T> {
         // println!("next called");
-        self.rx.recv().await
+        loop {
+            tokio::select! {
+                biased;
+
+                item = self.high.recv(), if self.high_open => match item {
+                    Some(item) => return Some(self.taken(item)),
+                    None => self.high_open = false,
+                },
+
+                item = self.normal.recv(), if self.normal_open => match item {
+                    Some(item) => return Some(self.taken(item)),
+                    None => self.normal_open = false,
+                },
+
+                else => return None,
+            }
+        }
+    }
+
+    // Give back the bytes an item took in the queue.
+    fn taken(&self, (item, permit): Queued<T>) -> T {
+        drop(permit);
+        item
+    }
+}
+
+impl<T> WorkSender<T> {
+    /// Add an item of work with normal priority, not counted towards
+    /// any byte limit.
+    pub async fn send(&self, item: T) -> Result<(), SendError<T>> {
+        self.send_with(item, Priority::Normal, 0).await
+    }
+
+    /// Add an item of work of a given priority and size in bytes.
+    ///
+    /// This waits until the queue has room for the item, by both
+    /// count and size.
+    pub async fn send_with(
+        &self,
+        item: T,
+        priority: Priority,
+        bytes: usize,
+    ) -> Result<(), SendError<T>> {
+        let permit = match &self.bytes {
+            None => None,
+            Some(limit) => {
+                let bytes = bytes.min(limit.max as usize) as u32;
+                match limit.permits.clone().acquire_many_owned(bytes).await {
+                    Ok(permit) => Some(permit),
+                    Err(_) => return Err(SendError(item)),
+                }
+            }
+        };
+        let tx = match priority {
+            Priority::High => &self.high,
+            Priority::Normal => &self.normal,
+        };
+        tx.send((item, permit))
+            .await
+            .map_err(|SendError((item, _))| SendError(item))
+    }
+}
+
+impl<T> Clone for WorkSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            high: self.high.clone(),
+            normal: self.normal.clone(),
+            bytes: self.bytes.as_ref().map(|limit| ByteLimit {
+                permits: limit.permits.clone(),
+                max: limit.max,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Priority, WorkQueue};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn takes_high_priority_items_first() {
+        let mut q = WorkQueue::new(4);
+        let tx = q.push();
+        tx.send("a").await.unwrap();
+        tx.send_with("b", Priority::High, 0).await.unwrap();
+        tx.send("c").await.unwrap();
+        tx.send_with("d", Priority::High, 0).await.unwrap();
+        drop(tx);
+        q.close();
+
+        let mut items = vec![];
+        while let Some(item) = q.next().await {
+            items.push(item);
+        }
+        assert_eq!(items, vec!["b", "d", "a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn limits_queued_bytes() {
+        let mut q = WorkQueue::with_byte_limit(4, 10);
+        let tx = q.push();
+        tx.send_with(1, Priority::Normal, 6).await.unwrap();
+        let wait = Duration::from_millis(50);
+        assert!(timeout(wait, tx.send_with(2, Priority::Normal, 6))
+            .await
+            .is_err());
+
+        assert_eq!(q.next().await, Some(1));
+        timeout(wait, tx.send_with(3, Priority::Normal, 6))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(q.next().await, Some(3));
+
+        // An item larger than the limit gets in when the queue is empty.
+        tx.send_with(4, Priority::Normal, 100).await.unwrap();
+        assert_eq!(q.next().await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn gives_back_bytes_of_cancelled_send() {
+        let mut q = WorkQueue::with_byte_limit(1, 10);
+        let tx = q.push();
+        tx.send_with(1, Priority::Normal, 2).await.unwrap();
+
+        // The queue is full, so this takes the bytes, but is dropped
+        // while waiting for room.
+        let wait = Duration::from_millis(50);
+        assert!(timeout(wait, tx.send_with(2, Priority::Normal, 6))
+            .await
+            .is_err());
+
+        assert_eq!(q.next().await, Some(1));
+        timeout(wait, tx.send_with(3, Priority::Normal, 10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(q.next().await, Some(3));
     }
 }
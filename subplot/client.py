@@ -55,7 +55,7 @@ def configure_client_with_init(ctx, filename=None):
     runcmd_exit_code_is_zero = globals()["runcmd_exit_code_is_zero"]
 
     configure_client_without_init(ctx, filename=filename)
-    runcmd_run(ctx, ["obnam", "init", "--insecure-passphrase=hunter2"])
+    runcmd_run(ctx, ["obnam", "init", "--plaintext-keys"])
     runcmd_exit_code_is_zero(ctx)
 
 
@@ -66,11 +66,48 @@ def run_obnam_restore(ctx, genid=None, todir=None):
     runcmd_run(ctx, ["env", "RUST_LOG=obnam", "obnam", "restore", genref, todir])
 
 
+def run_obnam_replicate(ctx):
+    runcmd_run = globals()["runcmd_run"]
+    runcmd_run(ctx, ["obnam", "replicate", "--to", ctx["second_server_url"]])
+
+
+def use_second_chunk_server(ctx):
+    filename = os.path.expanduser("~/.config/obnam/obnam.yaml")
+    config = yaml.safe_load(open(filename))
+    config["server_url"] = ctx["second_server_url"]
+    with open(filename, "w") as f:
+        yaml.safe_dump(config, stream=f)
+
+
 def run_obnam_get_chunk(ctx, gen_id=None, todir=None):
     runcmd_run = globals()["runcmd_run"]
     gen_id = ctx["vars"][gen_id]
     logging.debug(f"run_obnam_get_chunk: gen_id={gen_id}")
-    runcmd_run(ctx, ["obnam", "get-chunk", gen_id])
+    runcmd_run(ctx, ["obnam", "get-chunk", "--decrypt", "--verify", gen_id])
+
+
+def run_obnam_forget(ctx, gen_id=None):
+    runcmd_run = globals()["runcmd_run"]
+    gen_id = ctx["vars"][gen_id]
+    runcmd_run(ctx, ["obnam", "forget", gen_id])
+
+
+def run_obnam_forget_dry_run(ctx, gen_id=None):
+    runcmd_run = globals()["runcmd_run"]
+    gen_id = ctx["vars"][gen_id]
+    runcmd_run(ctx, ["obnam", "forget", "--dry-run", gen_id])
+
+
+def run_obnam_pin(ctx, gen_id=None):
+    runcmd_run = globals()["runcmd_run"]
+    gen_id = ctx["vars"][gen_id]
+    runcmd_run(ctx, ["obnam", "pin", gen_id])
+
+
+def run_obnam_unpin(ctx, gen_id=None):
+    runcmd_run = globals()["runcmd_run"]
+    gen_id = ctx["vars"][gen_id]
+    runcmd_run(ctx, ["obnam", "unpin", gen_id])
 
 
 def capture_generation_id(ctx, varname=None):
@@ -93,6 +130,18 @@ def generation_list_contains(ctx, gen_id=None):
     runcmd_stdout_contains(ctx, text=gen_id)
 
 
+def generation_list_does_not_contain(ctx, gen_id=None):
+    runcmd_stdout_doesnt_contain = globals()["runcmd_stdout_doesnt_contain"]
+    gen_id = ctx["vars"][gen_id]
+    runcmd_stdout_doesnt_contain(ctx, text=gen_id)
+
+
+def generation_list_contains_for_client(ctx, gen_id=None, name=None):
+    runcmd_stdout_contains = globals()["runcmd_stdout_contains"]
+    gen_id = ctx["vars"][gen_id]
+    runcmd_stdout_contains(ctx, text=f"{name} {gen_id}")
+
+
 def file_was_new(ctx, filename=None):
     assert_eq = globals()["assert_eq"]
     reason = get_backup_reason(ctx, filename)
@@ -48,10 +48,20 @@ def chmod_file(ctx, filename=None, mode=None):
     os.chmod(filename, int(mode, 8))
 
 
+def rename_file(ctx, filename=None, newname=None):
+    os.makedirs(os.path.dirname(newname) or ".", exist_ok=True)
+    os.rename(filename, newname)
+
+
 def create_symlink(ctx, linkname=None, target=None):
     os.symlink(target, linkname)
 
 
+def set_symlink_mtime(ctx, linkname=None, mtime=None):
+    t = int(mtime)
+    os.utime(linkname, (t, t), follow_symlinks=False)
+
+
 def create_manifest_of_live(ctx, dirname=None, manifest=None):
     _create_manifest_of_directory(ctx, dirname=dirname, manifest=manifest)
 
@@ -1,3 +1,4 @@
+import hashlib
 import json
 import logging
 import os
@@ -52,6 +53,53 @@ def stop_chunk_server(ctx, env=None):
     daemon_stop(ctx, name="obnam-server")
 
 
+def start_second_chunk_server(ctx):
+    daemon_start_on_port = globals()["daemon_start_on_port"]
+
+    logging.debug(f"Starting second obnam-server")
+
+    chunks = "chunks2"
+    if not os.path.exists(chunks):
+        os.mkdir(chunks)
+
+    port = random.randint(2000, 30000)
+    config = {
+        "chunks": chunks,
+        "tls_key": "test.key",
+        "tls_cert": "test.pem",
+        "address": f"localhost:{port}",
+    }
+    filename = "config2.yaml"
+    yaml.safe_dump(config, stream=open(filename, "w"))
+    ctx["second_server_url"] = f"https://{config['address']}"
+
+    daemon_start_on_port(
+        ctx,
+        name="obnam-server-2",
+        path=ctx["server-binary"],
+        args=filename,
+        port=port,
+    )
+
+
+def stop_second_chunk_server(ctx):
+    logging.debug("Stopping second obnam-server")
+    daemon_stop = globals()["daemon_stop"]
+    daemon_stop(ctx, name="obnam-server-2")
+
+
+def delete_chunk_from_second_server(ctx, chunk_id=None):
+    chunk_id = ctx["vars"][chunk_id]
+    url = f"{ctx['second_server_url']}/v1/chunks/{chunk_id}"
+    _request(ctx, requests.delete, url)
+
+
+def restart_empty_chunk_server(ctx):
+    logging.debug("Restarting obnam-server with no chunks")
+    shutil.rmtree("chunks")
+    start_chunk_server(ctx)
+
+
 def post_file(ctx, filename=None, path=None, header=None, json=None):
     url = f"{ctx['server_url']}/v1/chunks"
     headers = {header: json}
@@ -96,6 +144,37 @@ def make_chunk_file_be_empty(ctx, chunk_id=None):
             open(filename, "w").close()
 
 
+def make_content_chunk_be_empty(ctx, filename=None):
+    for data in _content_chunk_files(ctx, filename):
+        logging.debug(f"emptying chunk file {data}")
+        open(data, "w").close()
+
+
+def delete_content_chunk(ctx, filename=None):
+    for data in _content_chunk_files(ctx, filename):
+        logging.debug(f"deleting chunk file {data}")
+        os.remove(data)
+
+
+# Find the data files of chunks with the content of a file. The file
+# must fit in one chunk, so that the chunk's label is the SHA256
+# checksum of the whole file.
+def _content_chunk_files(ctx, filename):
+    checksum = hashlib.sha256(open(filename, "rb").read()).hexdigest()
+    chunks = ctx["config"]["chunks"]
+    logging.debug(f"looking for chunk with checksum {checksum}")
+    found = []
+    for (dirname, _, filenames) in os.walk(chunks):
+        for name in filenames:
+            if not name.endswith(".meta"):
+                continue
+            meta = os.path.join(dirname, name)
+            if checksum in open(meta).read():
+                found.append(meta[: -len(".meta")] + ".data")
+    assert found
+    return found
+
+
 def status_code_is(ctx, status=None):
     assert_eq = globals()["assert_eq"]
     assert_eq(ctx["http.status"], int(status))